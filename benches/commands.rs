@@ -0,0 +1,39 @@
+#![allow(clippy::needless_return)]
+/// Executing GET and SET, parsing and accounting included, without a connection.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use redis_starter_rust::commands;
+use redis_starter_rust::config::Config;
+use redis_starter_rust::protocol::DataType;
+use redis_starter_rust::StateInner;
+
+fn command(args: &[&str]) -> DataType {
+    return DataType::Array {
+        items: args.iter().map(|arg| DataType::from(*arg)).collect(),
+    };
+}
+
+fn get_set(c: &mut Criterion) {
+    let state = StateInner::new(Config::default());
+    let client = state.connect_client().unwrap();
+    let keys: Vec<String> = (0..1000).map(|i| format!("key:{i}")).collect();
+    let mut group = c.benchmark_group("dispatch");
+    let mut next = keys.iter().cycle();
+    group.bench_function("SET", |b| {
+        b.iter_batched(
+            || command(&["SET", next.next().unwrap(), "some value of a decent length"]),
+            |set| commands::dispatch(set, &state, client.id),
+            BatchSize::SmallInput,
+        );
+    });
+    group.bench_function("GET", |b| {
+        b.iter_batched(
+            || command(&["GET", next.next().unwrap()]),
+            |get| commands::dispatch(get, &state, client.id),
+            BatchSize::SmallInput,
+        );
+    });
+    group.finish();
+}
+
+criterion_group!(benches, get_set);
+criterion_main!(benches);
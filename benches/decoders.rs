@@ -0,0 +1,88 @@
+#![allow(clippy::needless_return)]
+/// Decoding a stream of pipelined commands with each decoder.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use redis_starter_rust::decoders::{v1, v2, v3};
+use redis_starter_rust::protocol::DataType;
+use tokio::runtime::Runtime;
+use tokio_stream::StreamExt;
+
+/// Commands in the pipelined stream fed to the decoders.
+const PIPELINE: usize = 10_000;
+
+/// `PIPELINE` SET commands encoded back to back, as sent by a pipelining client.
+fn pipelined_commands() -> Vec<u8> {
+    let mut stream = Vec::new();
+    for i in 0..PIPELINE {
+        let key = format!("key:{i}");
+        let set = DataType::Array {
+            items: vec![
+                DataType::from("SET"),
+                DataType::from(key.as_str()),
+                DataType::from("some value of a decent length"),
+            ],
+        };
+        stream.extend(set.encode().unwrap());
+    }
+    return stream;
+}
+
+fn runtime() -> Runtime {
+    return tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+}
+
+fn decoders(c: &mut Criterion) {
+    let stream = pipelined_commands();
+    let rt = runtime();
+    let mut group = c.benchmark_group("pipelined SET");
+    group.throughput(Throughput::Bytes(stream.len() as u64));
+    group.bench_function("v1", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                let mut reader = &stream[..];
+                // v1 fails on commands split between two reads, so the whole stream
+                // is read at once
+                let mut decoder = v1::Decoder::new(&mut reader).with_buffer_size(stream.len());
+                let mut decoded = 0;
+                while let Ok(commands) = decoder.parse().await {
+                    decoded += commands.len();
+                }
+                assert_eq!(decoded, PIPELINE);
+            })
+        });
+    });
+    group.bench_function("v2", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                let mut reader = &stream[..];
+                let mut decoder = v2::StreamDecoder::new(&mut reader);
+                let mut commands = Box::pin(decoder.as_stream());
+                let mut decoded = 0;
+                while let Some(Ok(_)) = commands.next().await {
+                    decoded += 1;
+                }
+                assert_eq!(decoded, PIPELINE);
+            })
+        });
+    });
+    group.bench_function("v3", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                let mut reader = &stream[..];
+                let mut decoder = v3::ChunkDecoder::new(&mut reader);
+                let mut commands = Box::pin(decoder.as_stream());
+                let mut decoded = 0;
+                while let Some(Ok(_)) = commands.next().await {
+                    decoded += 1;
+                }
+                assert_eq!(decoded, PIPELINE);
+            })
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decoders);
+criterion_main!(benches);
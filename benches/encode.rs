@@ -0,0 +1,33 @@
+#![allow(clippy::needless_return)]
+/// Encoding replies with `DataType::encode`.
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use redis_starter_rust::protocol::DataType;
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("DataType::encode");
+    let array = DataType::Array {
+        items: (0..100_000)
+            .map(|i| DataType::bulk(Bytes::from(format!("element:{i}"))))
+            .collect(),
+    };
+    group.throughput(Throughput::Bytes(array.encoded_len() as u64));
+    group.bench_function("100k bulk strings", |b| b.iter(|| array.encode().unwrap()));
+    let nested = DataType::Array {
+        items: (0..1000)
+            .map(|i| DataType::Array {
+                items: vec![
+                    DataType::Integer { number: i },
+                    DataType::bulk("value"),
+                    DataType::NullBulkString,
+                ],
+            })
+            .collect(),
+    };
+    group.throughput(Throughput::Bytes(nested.encoded_len() as u64));
+    group.bench_function("nested arrays", |b| b.iter(|| nested.encode().unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+#![no_main]
+#![allow(clippy::needless_return)]
+/// Fuzzes `DataType::from_bytes`, the parser of the v1 decoder, with the input as
+/// a buffer of back to back values. It must not panic, nor allocate what a length
+/// prefix announces before reading it (run with `-malloc_limit_mb`), and the values
+/// it accepts must encode to bytes it decodes back to the same encoding.
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use redis_starter_rust::decoders::v1::DataTypeFrom;
+use redis_starter_rust::protocol::DataType;
+
+/// Checks `value` survives encoding it and decoding it again. Encodings are compared
+/// instead of values so NaN Doubles can be checked too.
+fn assert_round_trips(value: &DataType) {
+    let encoded = value.encode().expect("accepted value can't be encoded");
+    let mut bytes = Bytes::from(encoded.clone());
+    let decoded = DataType::from_bytes(&mut bytes).expect("encoded value isn't accepted");
+    assert!(bytes.is_empty(), "encoded value isn't decoded whole");
+    assert_eq!(decoded.encode().unwrap(), encoded);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut bytes = Bytes::copy_from_slice(data);
+    while !bytes.is_empty() {
+        match DataType::from_bytes(&mut bytes) {
+            Ok(value) => assert_round_trips(&value),
+            Err(_) => break,
+        }
+    }
+});
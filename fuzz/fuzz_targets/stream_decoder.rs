@@ -0,0 +1,57 @@
+#![no_main]
+#![allow(clippy::needless_return)]
+/// Fuzzes `StreamDecoder`, the v2 decoder, reading the input in chunks of the size
+/// given by its first byte so values are split between reads. It must not panic,
+/// nor buffer more than its limits allow (run with `-malloc_limit_mb`), and the
+/// values it accepts must encode to bytes it decodes back to the same encoding.
+use libfuzzer_sys::fuzz_target;
+use redis_starter_rust::decoders::v2::{DecoderLimits, StreamDecoder};
+use redis_starter_rust::protocol::DataType;
+use tokio_stream::StreamExt;
+
+fn limits() -> DecoderLimits {
+    return DecoderLimits {
+        max_bulk_len: 64 * 1024,
+        max_multibulk_len: 1024,
+        ..DecoderLimits::default()
+    };
+}
+
+/// Values decoded from `input`, until the first error.
+async fn decode(input: &[u8], buffer_size: usize) -> Vec<DataType> {
+    let mut reader = input;
+    let mut decoder = StreamDecoder::new(&mut reader)
+        .with_limits(limits())
+        .with_buffer_size(buffer_size);
+    let mut values = Box::pin(decoder.as_stream());
+    let mut decoded = Vec::new();
+    while let Some(Ok(value)) = values.next().await {
+        decoded.push(value);
+    }
+    return decoded;
+}
+
+/// Checks `value` survives encoding it and decoding it again. Encodings are compared
+/// instead of values so NaN Doubles can be checked too.
+async fn assert_round_trips(value: &DataType) {
+    let encoded = value.encode().expect("accepted value can't be encoded");
+    let decoded = decode(&encoded, encoded.len().max(1)).await;
+    assert_eq!(decoded.len(), 1, "encoded value isn't decoded whole");
+    assert_eq!(decoded[0].encode().unwrap(), encoded);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (buffer_size, input) = match data.split_first() {
+        Some((size, input)) => (*size as usize % 64 + 1, input),
+        None => return,
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        for value in decode(input, buffer_size).await {
+            assert_round_trips(&value).await;
+        }
+    });
+});
@@ -0,0 +1,88 @@
+#![allow(clippy::needless_return)]
+
+/// Validates an AOF file without loading it, like `redis-check-aof`.
+///
+/// Reads every command in the file, and the RDB preamble if it has one, reporting
+/// the offset where it stops being valid. With `--fix` the file is truncated to its
+/// last whole command (or transaction), discarding everything after it:
+///
+/// ```text
+/// cargo run --bin aof-check -- [--fix] appendonly.aof
+/// ```
+use std::env;
+use std::fs::OpenOptions;
+use std::process;
+
+use redis_starter_rust::check::check_aof;
+
+const USAGE: &str = "usage: aof-check [--fix] <file.aof>";
+
+fn main() {
+    let mut fix = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--fix" => fix = true,
+            _ if path.is_none() => path = Some(arg),
+            _ => {
+                eprintln!("{USAGE}");
+                process::exit(1);
+            }
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => {
+            eprintln!("{USAGE}");
+            process::exit(1);
+        }
+    };
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("can't read {path}: {err}");
+            process::exit(1);
+        }
+    };
+    let report = check_aof(&data);
+    if let Some(rdb) = &report.preamble {
+        println!(
+            "RDB preamble of {} bytes, version {}, {} keys",
+            rdb.len, rdb.version, rdb.keys
+        );
+    }
+    println!(
+        "AOF analyzed: size={}, ok_up_to={}, diff={}, commands={}",
+        data.len(),
+        report.valid_len,
+        data.len() - report.valid_len,
+        report.commands
+    );
+    let err = match report.error {
+        Some(err) => err,
+        None => {
+            println!("AOF is valid");
+            return;
+        }
+    };
+    println!("AOF is invalid: {err}");
+    if !fix {
+        println!("run with --fix to truncate it to the last valid command");
+        process::exit(1);
+    }
+    let truncated = OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_len(report.valid_len as u64));
+    match truncated {
+        Ok(()) => println!(
+            "AOF truncated to {} bytes, {} discarded",
+            report.valid_len,
+            data.len() - report.valid_len
+        ),
+        Err(err) => {
+            eprintln!("can't truncate {path}: {err}");
+            process::exit(1);
+        }
+    }
+}
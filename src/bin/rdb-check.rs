@@ -0,0 +1,61 @@
+#![allow(clippy::needless_return)]
+
+/// Validates an RDB file without loading it, like `redis-check-rdb`.
+///
+/// Reads the whole file, checking the structure of every key and the checksum at the
+/// end, and reports the offset where it stops being valid:
+///
+/// ```text
+/// cargo run --bin rdb-check -- dump.rdb
+/// ```
+use std::env;
+use std::process;
+
+use redis_starter_rust::check::{check_rdb, CheckError};
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: rdb-check <file.rdb>");
+            process::exit(1);
+        }
+    };
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("can't read {path}: {err}");
+            process::exit(1);
+        }
+    };
+    println!("checking RDB file {path} ({} bytes)", data.len());
+    let report = match check_rdb(&data) {
+        Ok(report) => report,
+        Err(err) => {
+            println!("RDB is invalid: {err}");
+            if let CheckError::Truncated(_) = err {
+                println!("the file is shorter than the RDB it holds, was it copied whole?");
+            }
+            process::exit(1);
+        }
+    };
+    println!("RDB version {}", report.version);
+    for (field, value) in &report.aux {
+        println!("aux field {field} = '{value}'");
+    }
+    println!(
+        "{} keys read, {} with an expiration",
+        report.keys, report.expires
+    );
+    match report.checksum {
+        Some(checksum) => println!("checksum {checksum:016x} is valid"),
+        None => println!("no checksum to verify"),
+    }
+    if report.len < data.len() {
+        println!(
+            "{} bytes after the end of the RDB were ignored",
+            data.len() - report.len
+        );
+    }
+    println!("RDB looks OK");
+}
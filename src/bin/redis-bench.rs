@@ -0,0 +1,310 @@
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
+/// Load generator for the server, a small `redis-benchmark`.
+///
+/// Opens `--clients` connections and sends `--requests` commands of each test through
+/// them, `--pipeline` commands at a time, then reports the throughput and the latency
+/// percentiles. The latency of a command is the time its whole pipeline took.
+///
+/// ```text
+/// cargo run --release --bin redis-bench -- --clients 50 --pipeline 16 --tests set,get
+/// ```
+use std::env;
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BytesMut};
+use memchr::memchr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const USAGE: &str = "usage: redis-bench [--host <host>] [--port <port>] [--clients <n>] \
+[--requests <n>] [--pipeline <n>] [--data-size <bytes>] [--keyspace <n>] [--tests <test,...>]
+
+tests: ping, set, get, incr";
+
+#[derive(Debug, Clone)]
+struct Options {
+    host: String,
+    port: u16,
+    clients: usize,
+    requests: usize,
+    pipeline: usize,
+    data_size: usize,
+    /// keys are picked at random from this many, 0 uses a single key
+    keyspace: usize,
+    tests: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        return Options {
+            host: String::from("127.0.0.1"),
+            port: 6379,
+            clients: 50,
+            requests: 100_000,
+            pipeline: 1,
+            data_size: 3,
+            keyspace: 0,
+            tests: vec![
+                String::from("ping"),
+                String::from("set"),
+                String::from("get"),
+                String::from("incr"),
+            ],
+        };
+    }
+}
+
+impl Options {
+    fn from_args(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for {arg}"))?;
+            let number = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid value for {arg}: {value}"))
+            };
+            match arg.as_str() {
+                "-h" | "--host" => options.host = value.clone(),
+                "-p" | "--port" => {
+                    options.port = value
+                        .parse()
+                        .map_err(|_| format!("invalid port: {value}"))?
+                }
+                "-c" | "--clients" => options.clients = number()?.max(1),
+                "-n" | "--requests" => options.requests = number()?,
+                "-P" | "--pipeline" => options.pipeline = number()?.max(1),
+                "-d" | "--data-size" => options.data_size = number()?,
+                "-r" | "--keyspace" => options.keyspace = number()?,
+                "-t" | "--tests" => {
+                    options.tests = value.split(',').map(|t| t.to_lowercase()).collect();
+                    if let Some(test) = options.tests.iter().find(|t| command(t).is_none()) {
+                        return Err(format!("unknown test: {test}"));
+                    }
+                }
+                _ => return Err(format!("unknown option: {arg}")),
+            }
+        }
+        return Ok(options);
+    }
+}
+
+/// Arguments of the command run by `test`, `{key}` replaced by the key of each request.
+fn command(test: &str) -> Option<Vec<&'static str>> {
+    return match test {
+        "ping" => Some(vec!["PING"]),
+        "set" => Some(vec!["SET", "{key}", "{value}"]),
+        "get" => Some(vec!["GET", "{key}"]),
+        "incr" => Some(vec!["INCR", "{counter}"]),
+        _ => None,
+    };
+}
+
+/// xorshift generator picking the keys, no need for anything better.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        return Rng(seed | 1);
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        return self.0;
+    }
+}
+
+/// Encodes a command as a RESP Array of Bulk Strings.
+fn encode(args: &[&str], options: &Options, value: &str, rng: &mut Rng, buf: &mut Vec<u8>) {
+    let key = match options.keyspace {
+        0 => String::from("key"),
+        n => format!("key:{}", rng.next() % n as u64),
+    };
+    buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        let arg = match *arg {
+            "{key}" => key.as_str(),
+            "{counter}" => "counter",
+            "{value}" => value,
+            arg => arg,
+        };
+        buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+}
+
+/// Length of the reply at the start of `buf` and whether it's an error, None if it
+/// isn't complete yet.
+fn reply_len(buf: &[u8]) -> Option<(usize, bool)> {
+    let line_end = memchr(b'\n', buf)? + 1;
+    let number = || -> Option<i64> {
+        let line = std::str::from_utf8(buf.get(1..line_end.saturating_sub(2))?).ok()?;
+        return line.parse().ok();
+    };
+    return match buf[0] {
+        b'$' | b'=' => match number()? {
+            -1 => Some((line_end, false)),
+            len => {
+                let end = line_end + len as usize + 2;
+                (buf.len() >= end).then_some((end, false))
+            }
+        },
+        b'*' | b'~' | b'>' | b'%' => {
+            let mut elements = number()?.max(0) as usize;
+            if buf[0] == b'%' {
+                elements *= 2;
+            }
+            let mut end = line_end;
+            for _ in 0..elements {
+                end += reply_len(&buf[end..])?.0;
+            }
+            Some((end, false))
+        }
+        b'-' | b'!' => Some((line_end, true)),
+        _ => Some((line_end, false)),
+    };
+}
+
+#[derive(Default)]
+struct Results {
+    /// latency of each request in microseconds
+    latencies: Vec<u32>,
+    errors: usize,
+}
+
+/// Sends `requests` commands of `test` through a new connection.
+async fn run_client(
+    options: Options,
+    test: String,
+    requests: usize,
+    seed: u64,
+) -> std::io::Result<Results> {
+    let mut stream = TcpStream::connect((options.host.as_str(), options.port)).await?;
+    stream.set_nodelay(true)?;
+    let args = command(&test).unwrap();
+    let value = "x".repeat(options.data_size);
+    let mut rng = Rng::new(seed);
+    let mut results = Results::default();
+    let mut request = Vec::new();
+    let mut replies = BytesMut::with_capacity(16 * 1024);
+    let mut sent = 0;
+    while sent < requests {
+        let batch = options.pipeline.min(requests - sent);
+        request.clear();
+        for _ in 0..batch {
+            encode(&args, &options, &value, &mut rng, &mut request);
+        }
+        let start = Instant::now();
+        stream.write_all(&request).await?;
+        let mut received = 0;
+        while received < batch {
+            match reply_len(&replies) {
+                Some((len, error)) => {
+                    replies.advance(len);
+                    received += 1;
+                    results.errors += error as usize;
+                }
+                None => {
+                    if stream.read_buf(&mut replies).await? == 0 {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+            }
+        }
+        let latency = start.elapsed().as_micros() as u32;
+        results
+            .latencies
+            .extend(std::iter::repeat_n(latency, batch));
+        sent += batch;
+    }
+    return Ok(results);
+}
+
+fn percentile(sorted: &[u32], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let ix = ((sorted.len() as f64 * p / 100.0).ceil() as usize).clamp(1, sorted.len()) - 1;
+    return sorted[ix] as f64 / 1000.0;
+}
+
+fn report(test: &str, options: &Options, elapsed: Duration, mut results: Results) {
+    results.latencies.sort_unstable();
+    let requests = results.latencies.len();
+    let avg = results.latencies.iter().map(|l| *l as f64).sum::<f64>() / requests.max(1) as f64;
+    println!("====== {} ======", test.to_uppercase());
+    println!(
+        "  {} requests completed in {:.2} seconds",
+        requests,
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "  {} parallel clients, pipeline {}, {} bytes payload",
+        options.clients, options.pipeline, options.data_size
+    );
+    if results.errors > 0 {
+        println!("  {} requests failed", results.errors);
+    }
+    println!(
+        "  throughput: {:.2} requests per second",
+        requests as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "  latency (ms): avg {:.3} p50 {:.3} p95 {:.3} p99 {:.3} max {:.3}",
+        avg / 1000.0,
+        percentile(&results.latencies, 50.0),
+        percentile(&results.latencies, 95.0),
+        percentile(&results.latencies, 99.0),
+        percentile(&results.latencies, 100.0),
+    );
+    println!();
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::from_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            process::exit(1);
+        }
+    };
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    for test in &options.tests {
+        let start = Instant::now();
+        let mut clients = Vec::new();
+        for i in 0..options.clients {
+            // the requests are split evenly, the first clients take the remainder
+            let requests = options.requests / options.clients
+                + (i < options.requests % options.clients) as usize;
+            let client = run_client(options.clone(), test.clone(), requests, seed + i as u64);
+            clients.push(tokio::spawn(client));
+        }
+        let mut results = Results::default();
+        for client in clients {
+            match client.await.unwrap() {
+                Ok(client_results) => {
+                    results.latencies.extend(client_results.latencies);
+                    results.errors += client_results.errors;
+                }
+                Err(err) => {
+                    eprintln!(
+                        "connection to {}:{} failed: {err}",
+                        options.host, options.port
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+        report(test, &options, start.elapsed(), results);
+    }
+}
@@ -0,0 +1,221 @@
+/// Clients blocked by commands waiting for keys to be written, like BLMPOP.
+///
+/// A command with nothing to serve registers the keys it waits on and its client
+/// sleeps until a command writes any of them, then the command is retried, blocking
+/// again if there's still nothing to serve (another client may have been faster).
+/// Blocked clients are also woken up to give up once their connection is closed or
+/// the server shuts down, see `unblock` and `unblock_all`.
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::clients::ClientId;
+use crate::state::State;
+
+/// A client waiting for the keys of a database to be written.
+pub struct Waiter {
+    client: ClientId,
+    db: usize,
+    keys: Vec<Bytes>,
+    notify: Notify,
+    cancelled: AtomicBool,
+}
+
+impl Waiter {
+    /// Waits until one of the keys is written, or the client is unblocked. Writes
+    /// done since the last wait wake it up right away.
+    pub async fn wait(&self) {
+        self.notify.notified().await;
+    }
+
+    /// true if the client gave up waiting, see `BlockedClients::unblock`.
+    pub fn cancelled(&self) -> bool {
+        return self.cancelled.load(Ordering::SeqCst);
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_one();
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    waiters: HashMap<ClientId, Arc<Waiter>>,
+    /// clients whose connection is closing, which can't block anymore
+    closed: HashSet<ClientId>,
+    /// set once the server shuts down, no client can block anymore
+    stopped: bool,
+}
+
+#[derive(Default)]
+pub struct BlockedClients {
+    /// number of blocked clients, so writes don't take the lock while there are none
+    count: AtomicUsize,
+    table: Mutex<Table>,
+}
+
+impl BlockedClients {
+    pub fn new() -> Self {
+        return BlockedClients::default();
+    }
+
+    /// Blocks `client` on `keys` of database `db` until the returned guard is dropped.
+    /// `None` if the client can't block, because it's disconnecting or the server is
+    /// shutting down.
+    pub fn block(
+        self: &Arc<Self>,
+        client: ClientId,
+        db: usize,
+        keys: &[Bytes],
+    ) -> Option<BlockGuard> {
+        let mut table = self.table.lock().unwrap();
+        if table.stopped || table.closed.contains(&client) {
+            return None;
+        }
+        let waiter = Arc::new(Waiter {
+            client: client,
+            db: db,
+            keys: keys.to_vec(),
+            notify: Notify::new(),
+            cancelled: AtomicBool::new(false),
+        });
+        table.waiters.insert(client, waiter.clone());
+        self.count.fetch_add(1, Ordering::SeqCst);
+        return Some(BlockGuard {
+            blocked: self.clone(),
+            waiter: waiter,
+        });
+    }
+
+    /// Number of clients blocked.
+    pub fn waiting(&self) -> usize {
+        return self.count.load(Ordering::SeqCst);
+    }
+
+    /// Wakes up the clients blocked on any of `keys` of database `db`.
+    pub fn signal(&self, db: usize, keys: &[Bytes]) {
+        if self.count.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let table = self.table.lock().unwrap();
+        for waiter in table.waiters.values() {
+            if waiter.db == db && waiter.keys.iter().any(|key| keys.contains(key)) {
+                waiter.notify.notify_one();
+            }
+        }
+    }
+
+    /// Wakes up every client blocked on keys of database `db`, after its keys were
+    /// replaced all at once.
+    pub fn signal_db(&self, db: usize) {
+        if self.count.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let table = self.table.lock().unwrap();
+        for waiter in table.waiters.values().filter(|waiter| waiter.db == db) {
+            waiter.notify.notify_one();
+        }
+    }
+
+    /// Makes `client` give up waiting, now and in any later command, as its
+    /// connection is closing. See `forget`.
+    pub fn unblock(&self, client: ClientId) {
+        let mut table = self.table.lock().unwrap();
+        table.closed.insert(client);
+        if let Some(waiter) = table.waiters.get(&client) {
+            waiter.cancel();
+        }
+    }
+
+    /// Makes every client give up waiting, as the server is shutting down.
+    pub fn unblock_all(&self) {
+        let mut table = self.table.lock().unwrap();
+        table.stopped = true;
+        for waiter in table.waiters.values() {
+            waiter.cancel();
+        }
+    }
+
+    /// Forgets `client` once disconnected.
+    pub fn forget(&self, client: ClientId) {
+        self.table.lock().unwrap().closed.remove(&client);
+    }
+}
+
+/// Keeps a client blocked, see `BlockedClients::block`.
+pub struct BlockGuard {
+    blocked: Arc<BlockedClients>,
+    waiter: Arc<Waiter>,
+}
+
+impl std::ops::Deref for BlockGuard {
+    type Target = Waiter;
+
+    fn deref(&self) -> &Waiter {
+        return &self.waiter;
+    }
+}
+
+impl Drop for BlockGuard {
+    fn drop(&mut self) {
+        let mut table = self.blocked.table.lock().unwrap();
+        table.waiters.remove(&self.waiter.client);
+        self.blocked.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Wakes up the clients blocked on `keys` of the database selected by `client`.
+pub fn signal_keys(state: &State, client: ClientId, keys: &[Bytes]) {
+    if state.blocked.waiting() == 0 {
+        return;
+    }
+    state.blocked.signal(state.selected_db(client), keys);
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::BlockedClients;
+
+    #[tokio::test]
+    async fn test_signal() {
+        let blocked = Arc::new(BlockedClients::new());
+        let keys = [Bytes::from("a"), Bytes::from("b")];
+        let waiter = blocked.block(1, 0, &keys).unwrap();
+        let woken = || async {
+            let wait = tokio::time::timeout(Duration::from_millis(20), waiter.wait());
+            return wait.await.is_ok();
+        };
+
+        blocked.signal(1, &keys);
+        blocked.signal(0, &[Bytes::from("c")]);
+        assert!(!woken().await);
+        // signals sent before waiting aren't lost
+        blocked.signal(0, &[Bytes::from("b")]);
+        assert!(woken().await);
+        blocked.signal_db(0);
+        assert!(woken().await);
+        assert!(!waiter.cancelled());
+
+        blocked.unblock(1);
+        assert!(woken().await);
+        assert!(waiter.cancelled());
+        drop(waiter);
+        assert_eq!(blocked.waiting(), 0);
+        // closing clients can't block again until forgotten
+        assert!(blocked.block(1, 0, &keys).is_none());
+        blocked.forget(1);
+        assert!(blocked.block(1, 0, &keys).is_some());
+
+        blocked.unblock_all();
+        assert!(blocked.block(2, 0, &keys).is_none());
+    }
+}
@@ -0,0 +1,701 @@
+/// Offline checks of persistence files, behind the `rdb-check` and `aof-check` binaries.
+///
+/// They read the formats of Redis itself: RDB files up to version 12, and AOF files,
+/// with or without an RDB preamble. Files are validated without loading them into a
+/// keyspace, and the errors report the offset where the file stops making sense, so
+/// a truncated AOF can be cut back to its last whole command.
+use thiserror::Error;
+
+use crate::lzf;
+
+/// Latest RDB version understood.
+pub const RDB_VERSION: u32 = 12;
+
+/// First RDB version ending with a checksum.
+const RDB_CHECKSUM_VERSION: u32 = 5;
+
+/// Compressed strings can't expand more than this, LZF back references copy at most
+/// 264 bytes for 2 bytes of input.
+const MAX_LZF_RATIO: usize = 133;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CheckError {
+    #[error("unexpected end of file at offset {0}")]
+    Truncated(usize),
+
+    #[error("{1} at offset {0}")]
+    Corrupt(usize, String),
+}
+
+impl CheckError {
+    /// Offset of the first byte that couldn't be read.
+    pub fn offset(&self) -> usize {
+        return match self {
+            CheckError::Truncated(offset) | CheckError::Corrupt(offset, _) => *offset,
+        };
+    }
+}
+
+/// CRC-64/Jones, the checksum of RDB files: reflected, polynomial 0xad93d23594c935a9,
+/// no final xor.
+pub fn crc64(data: &[u8]) -> u64 {
+    static TABLE: std::sync::OnceLock<[u64; 256]> = std::sync::OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = [0u64; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u64;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0x95ac9329ac4bc9b5
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        return table;
+    });
+    let mut crc = 0u64;
+    for byte in data {
+        crc = table[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    return crc;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RdbReport {
+    pub version: u32,
+    /// auxiliary fields, like `redis-ver`
+    pub aux: Vec<(String, String)>,
+    pub keys: usize,
+    /// keys with an expiration
+    pub expires: usize,
+    /// checksum stored at the end of the file, `None` if the file has none or it's
+    /// disabled
+    pub checksum: Option<u64>,
+    /// bytes of the file taken by the RDB
+    pub len: usize,
+}
+
+/// Length of a field, or the encoding of a string stored in a special format.
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+/// Reads the fields of a file, failing with the offset of the field it stopped at.
+/// DUMP payloads, which hold a value the way RDB files do, are read with it too.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        return Reader { data: data, pos: 0 };
+    }
+
+    /// Whether every byte was read.
+    pub fn is_empty(&self) -> bool {
+        return self.pos == self.data.len();
+    }
+
+    fn corrupt(&self, message: impl Into<String>) -> CheckError {
+        return CheckError::Corrupt(self.pos, message.into());
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], CheckError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.data.len());
+        let end = end.ok_or(CheckError::Truncated(self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        return Ok(bytes);
+    }
+
+    pub fn u8(&mut self) -> Result<u8, CheckError> {
+        return Ok(self.bytes(1)?[0]);
+    }
+
+    fn length(&mut self) -> Result<Length, CheckError> {
+        let first = self.u8()?;
+        return match first >> 6 {
+            0 => Ok(Length::Len((first & 0x3f) as u64)),
+            1 => Ok(Length::Len(
+                ((first & 0x3f) as u64) << 8 | self.u8()? as u64,
+            )),
+            2 if first == 0x80 => Ok(Length::Len(u32::from_be_bytes(
+                self.bytes(4)?.try_into().unwrap(),
+            ) as u64)),
+            2 if first == 0x81 => Ok(Length::Len(u64::from_be_bytes(
+                self.bytes(8)?.try_into().unwrap(),
+            ))),
+            2 => Err(CheckError::Corrupt(
+                self.pos - 1,
+                format!("unknown length encoding {first:#04x}"),
+            )),
+            _ => Ok(Length::Encoded(first & 0x3f)),
+        };
+    }
+
+    /// A plain length, as a count of elements.
+    pub fn count(&mut self) -> Result<usize, CheckError> {
+        let start = self.pos;
+        return match self.length()? {
+            Length::Len(len) => usize::try_from(len)
+                .map_err(|_| CheckError::Corrupt(start, "length too big".into())),
+            Length::Encoded(_) => Err(CheckError::Corrupt(
+                start,
+                "unexpected encoded length".into(),
+            )),
+        };
+    }
+
+    /// A string, decompressed if it's stored compressed.
+    pub fn string(&mut self) -> Result<Vec<u8>, CheckError> {
+        let start = self.pos;
+        return match self.length()? {
+            Length::Len(_) => {
+                self.pos = start;
+                let len = self.count()?;
+                Ok(self.bytes(len)?.to_vec())
+            }
+            Length::Encoded(0) => Ok((self.u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(1) => {
+                let n = i16::from_le_bytes(self.bytes(2)?.try_into().unwrap());
+                Ok(n.to_string().into_bytes())
+            }
+            Length::Encoded(2) => {
+                let n = i32::from_le_bytes(self.bytes(4)?.try_into().unwrap());
+                Ok(n.to_string().into_bytes())
+            }
+            Length::Encoded(3) => {
+                let compressed_len = self.count()?;
+                let len = self.count()?;
+                if len / MAX_LZF_RATIO > compressed_len {
+                    return Err(self.corrupt("invalid compressed string length"));
+                }
+                let data_start = self.pos;
+                let compressed = self.bytes(compressed_len)?;
+                lzf::decompress(compressed, len).ok_or_else(|| {
+                    CheckError::Corrupt(data_start, "invalid compressed string".into())
+                })
+            }
+            Length::Encoded(encoding) => Err(CheckError::Corrupt(
+                start,
+                format!("unknown string encoding {encoding}"),
+            )),
+        };
+    }
+
+    fn utf8_string(&mut self) -> Result<String, CheckError> {
+        return Ok(String::from_utf8_lossy(&self.string()?).into_owned());
+    }
+
+    /// A double of the ZSET type, stored as text.
+    fn text_double(&mut self) -> Result<(), CheckError> {
+        let len = self.u8()?;
+        if len >= 253 {
+            // NaN and infinities
+            return Ok(());
+        }
+        let start = self.pos;
+        let text = self.bytes(len as usize)?;
+        let valid = std::str::from_utf8(text).map(str::parse::<f64>);
+        if !matches!(valid, Ok(Ok(_))) {
+            return Err(CheckError::Corrupt(start, "invalid double".into()));
+        }
+        return Ok(());
+    }
+
+    /// A string holding a serialized small collection, whose header is checked.
+    fn blob(&mut self, kind: Blob) -> Result<(), CheckError> {
+        let start = self.pos;
+        let blob = self.string()?;
+        let header = |n: usize| -> Option<usize> {
+            Some(u32::from_le_bytes(blob.get(n..n + 4)?.try_into().ok()?) as usize)
+        };
+        let valid = match kind {
+            // total bytes, then ends with 0xff
+            Blob::Ziplist => header(0) == Some(blob.len()) && blob.last() == Some(&0xff),
+            Blob::Listpack => header(0) == Some(blob.len()) && blob.last() == Some(&0xff),
+            // encoding of the integers, then how many there are
+            Blob::Intset => match (header(0), header(4)) {
+                (Some(encoding @ (2 | 4 | 8)), Some(len)) => blob.len() == 8 + encoding * len,
+                _ => false,
+            },
+            Blob::Zipmap => blob.last() == Some(&0xff),
+        };
+        if !valid {
+            return Err(CheckError::Corrupt(start, format!("invalid {kind:?}")));
+        }
+        return Ok(());
+    }
+
+    fn stream_id(&mut self) -> Result<(), CheckError> {
+        self.count()?;
+        self.count()?;
+        return Ok(());
+    }
+
+    /// The value of a key, of type `value_type`.
+    fn value(&mut self, value_type: u8) -> Result<(), CheckError> {
+        match value_type {
+            // string
+            0 => {
+                self.string()?;
+            }
+            // list and set
+            1 | 2 => {
+                for _ in 0..self.count()? {
+                    self.string()?;
+                }
+            }
+            // sorted set
+            3 => {
+                for _ in 0..self.count()? {
+                    self.string()?;
+                    self.text_double()?;
+                }
+            }
+            // hash
+            4 => {
+                for _ in 0..self.count()? {
+                    self.string()?;
+                    self.string()?;
+                }
+            }
+            // sorted set with binary scores
+            5 => {
+                for _ in 0..self.count()? {
+                    self.string()?;
+                    self.bytes(8)?;
+                }
+            }
+            9 => self.blob(Blob::Zipmap)?,
+            10 | 12 | 13 => self.blob(Blob::Ziplist)?,
+            11 => self.blob(Blob::Intset)?,
+            16 | 17 | 20 => self.blob(Blob::Listpack)?,
+            // quicklist of ziplists
+            14 => {
+                for _ in 0..self.count()? {
+                    self.blob(Blob::Ziplist)?;
+                }
+            }
+            // quicklist of listpacks, or plain nodes holding a single big element
+            18 => {
+                for _ in 0..self.count()? {
+                    let start = self.pos;
+                    match self.count()? {
+                        1 => {
+                            self.string()?;
+                        }
+                        2 => self.blob(Blob::Listpack)?,
+                        container => {
+                            return Err(CheckError::Corrupt(
+                                start,
+                                format!("unknown quicklist container {container}"),
+                            ))
+                        }
+                    }
+                }
+            }
+            15 | 19 | 21 => self.stream(value_type)?,
+            _ => {
+                return Err(CheckError::Corrupt(
+                    self.pos - 1,
+                    format!("unsupported value type {value_type}"),
+                ))
+            }
+        }
+        return Ok(());
+    }
+
+    fn stream(&mut self, value_type: u8) -> Result<(), CheckError> {
+        for _ in 0..self.count()? {
+            let start = self.pos;
+            if self.string()?.len() != 16 {
+                return Err(CheckError::Corrupt(start, "invalid stream node key".into()));
+            }
+            self.blob(Blob::Listpack)?;
+        }
+        // length and last id
+        self.count()?;
+        self.stream_id()?;
+        if value_type >= 19 {
+            // first id, max deleted id and entries added
+            self.stream_id()?;
+            self.stream_id()?;
+            self.count()?;
+        }
+        for _ in 0..self.count()? {
+            // consumer group: name, last id, entries read, pending entries, consumers
+            self.string()?;
+            self.stream_id()?;
+            if value_type >= 19 {
+                self.count()?;
+            }
+            for _ in 0..self.count()? {
+                // id, delivery time and delivery count
+                self.bytes(16 + 8)?;
+                self.count()?;
+            }
+            for _ in 0..self.count()? {
+                // name, seen time, active time, pending ids
+                self.string()?;
+                self.bytes(8)?;
+                if value_type >= 21 {
+                    self.bytes(8)?;
+                }
+                for _ in 0..self.count()? {
+                    self.bytes(16)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Blob {
+    Zipmap,
+    Ziplist,
+    Listpack,
+    Intset,
+}
+
+/// Checks the RDB at the start of `data`, which may be followed by something else,
+/// like the commands of an AOF with an RDB preamble.
+pub fn check_rdb(data: &[u8]) -> Result<RdbReport, CheckError> {
+    let mut reader = Reader::new(data);
+    let header = reader.bytes(9)?;
+    if &header[..5] != b"REDIS" {
+        return Err(CheckError::Corrupt(0, "not an RDB file".into()));
+    }
+    let version = std::str::from_utf8(&header[5..])
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok())
+        .filter(|version| (1..=RDB_VERSION).contains(version))
+        .ok_or_else(|| CheckError::Corrupt(5, "unsupported version".into()))?;
+    let mut report = RdbReport {
+        version: version,
+        ..RdbReport::default()
+    };
+    let mut expires = false;
+    loop {
+        let start = reader.pos;
+        match reader.u8()? {
+            // EOF
+            0xff => break,
+            // SELECTDB
+            0xfe => {
+                reader.count()?;
+            }
+            // EXPIRETIME, in seconds
+            0xfd => {
+                reader.bytes(4)?;
+                expires = true;
+            }
+            // EXPIRETIME_MS
+            0xfc => {
+                reader.bytes(8)?;
+                expires = true;
+            }
+            // RESIZEDB
+            0xfb => {
+                reader.count()?;
+                reader.count()?;
+            }
+            // AUX
+            0xfa => {
+                let field = reader.utf8_string()?;
+                let value = reader.utf8_string()?;
+                report.aux.push((field, value));
+            }
+            // FREQ
+            0xf9 => {
+                reader.u8()?;
+            }
+            // IDLE
+            0xf8 => {
+                reader.count()?;
+            }
+            // FUNCTION2
+            0xf5 => {
+                reader.string()?;
+            }
+            // SLOT_INFO: slot, slot size and expires slot size
+            0xf4 => {
+                reader.count()?;
+                reader.count()?;
+                reader.count()?;
+            }
+            0xf6 | 0xf7 => {
+                return Err(CheckError::Corrupt(
+                    start,
+                    "modules are not supported".into(),
+                ))
+            }
+            value_type => {
+                reader.string()?;
+                reader.value(value_type)?;
+                report.keys += 1;
+                report.expires += expires as usize;
+                expires = false;
+            }
+        }
+    }
+    if version >= RDB_CHECKSUM_VERSION {
+        let start = reader.pos;
+        let checksum = u64::from_le_bytes(reader.bytes(8)?.try_into().unwrap());
+        // a zero checksum means it was disabled when the file was written
+        if checksum != 0 {
+            let computed = crc64(&data[..start]);
+            if computed != checksum {
+                return Err(CheckError::Corrupt(
+                    start,
+                    format!(
+                        "checksum mismatch: expected {checksum:016x}, computed {computed:016x}"
+                    ),
+                ));
+            }
+            report.checksum = Some(checksum);
+        }
+    }
+    report.len = reader.pos;
+    return Ok(report);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AofReport {
+    /// the RDB the file starts with, if any
+    pub preamble: Option<RdbReport>,
+    pub commands: usize,
+    /// bytes of the file holding whole commands; everything after is invalid
+    pub valid_len: usize,
+    pub error: Option<CheckError>,
+}
+
+/// Reads a `<prefix><integer>\r\n` line.
+fn aof_line(reader: &mut Reader, prefix: u8) -> Result<usize, CheckError> {
+    let start = reader.pos;
+    let rest = &reader.data[start..];
+    let end = match memchr::memchr(b'\n', rest) {
+        Some(end) => end,
+        None => return Err(CheckError::Truncated(start)),
+    };
+    let line = &rest[..end];
+    let number = line
+        .strip_prefix(&[prefix])
+        .and_then(|line| line.strip_suffix(b"\r"))
+        .and_then(|n| std::str::from_utf8(n).ok())
+        .and_then(|n| n.parse().ok());
+    let number = number.ok_or_else(|| {
+        let expected = prefix as char;
+        CheckError::Corrupt(start, format!("expected '{expected}' followed by a length"))
+    })?;
+    reader.pos += end + 1;
+    return Ok(number);
+}
+
+/// Reads a command, returning its name.
+fn aof_command(reader: &mut Reader) -> Result<Vec<u8>, CheckError> {
+    let start = reader.pos;
+    let args = aof_line(reader, b'*')?;
+    if args == 0 {
+        return Err(CheckError::Corrupt(start, "empty command".into()));
+    }
+    let mut name = Vec::new();
+    for i in 0..args {
+        let len = aof_line(reader, b'$')?;
+        let arg = reader.bytes(len)?;
+        if i == 0 {
+            name = arg.to_ascii_uppercase();
+        }
+        let crlf_start = reader.pos;
+        if reader.bytes(2)? != b"\r\n" {
+            return Err(CheckError::Corrupt(crlf_start, "expected CRLF".into()));
+        }
+    }
+    return Ok(name);
+}
+
+/// Checks an AOF file, stopping at the first command that can't be read. Commands
+/// cut short at the end of the file, like a MULTI without its EXEC, are reported as
+/// truncated at their start.
+pub fn check_aof(data: &[u8]) -> AofReport {
+    let mut report = AofReport {
+        preamble: None,
+        commands: 0,
+        valid_len: 0,
+        error: None,
+    };
+    if data.starts_with(b"REDIS") {
+        match check_rdb(data) {
+            Ok(rdb) => {
+                report.valid_len = rdb.len;
+                report.preamble = Some(rdb);
+            }
+            Err(err) => {
+                report.error = Some(err);
+                return report;
+            }
+        }
+    }
+    let mut reader = Reader {
+        data: data,
+        pos: report.valid_len,
+    };
+    // start of the MULTI of the transaction being read, and its commands so far
+    let mut multi: Option<(usize, usize)> = None;
+    while reader.pos < data.len() {
+        let start = reader.pos;
+        // annotations, like the timestamps of `aof-timestamp-enabled`
+        if data[start] == b'#' {
+            match memchr::memchr(b'\n', &data[start..]) {
+                Some(end) => reader.pos += end + 1,
+                None => {
+                    report.error = Some(CheckError::Truncated(start));
+                    break;
+                }
+            }
+            if multi.is_none() {
+                report.valid_len = reader.pos;
+            }
+            continue;
+        }
+        let name = match aof_command(&mut reader) {
+            Ok(name) => name,
+            Err(err) => {
+                report.error = Some(match err {
+                    CheckError::Truncated(_) => CheckError::Truncated(start),
+                    err => err,
+                });
+                break;
+            }
+        };
+        match (&name[..], multi) {
+            (b"MULTI", None) => multi = Some((start, 0)),
+            (b"MULTI", Some(_)) => {
+                report.error = Some(CheckError::Corrupt(start, "nested MULTI".into()));
+                break;
+            }
+            (b"EXEC", Some((_, commands))) => {
+                report.commands += commands + 2;
+                multi = None;
+            }
+            (_, Some((multi_start, commands))) => multi = Some((multi_start, commands + 1)),
+            (_, None) => report.commands += 1,
+        }
+        if multi.is_none() {
+            report.valid_len = reader.pos;
+        }
+    }
+    if let (None, Some((start, _))) = (&report.error, multi) {
+        report.error = Some(CheckError::Truncated(start));
+    }
+    return report;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_aof, check_rdb, crc64, CheckError};
+
+    /// An RDB with a string key with an expiration, a list, a compressed string and an
+    /// intset, followed by its checksum.
+    fn rdb() -> Vec<u8> {
+        let mut rdb = b"REDIS0011".to_vec();
+        rdb.extend_from_slice(b"\xfa\x09redis-ver\x057.2.4");
+        rdb.extend_from_slice(b"\xfe\x00\xfb\x04\x01");
+        rdb.extend_from_slice(b"\xfc\x00\x00\x00\x00\x00\x00\x00\x01");
+        rdb.extend_from_slice(b"\x00\x03key\x05value");
+        rdb.extend_from_slice(b"\x01\x04list\x02\x01a\xc0\x07");
+        let compressed = crate::lzf::compress(&[b'x'; 100]).unwrap();
+        rdb.extend_from_slice(b"\x00\x03big\xc3");
+        rdb.push(compressed.len() as u8);
+        // 100 doesn't fit in 6 bits, the length takes 14
+        rdb.extend_from_slice(&[0x40, 100]);
+        rdb.extend_from_slice(&compressed);
+        rdb.extend_from_slice(b"\x0b\x03set\x0a\x02\x00\x00\x00\x01\x00\x00\x00\x07\x00");
+        rdb.push(0xff);
+        let checksum = crc64(&rdb);
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+        return rdb;
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_check_rdb() {
+        let data = rdb();
+        let report = check_rdb(&data).unwrap();
+        assert_eq!(report.version, 11);
+        assert_eq!(
+            report.aux,
+            vec![(String::from("redis-ver"), String::from("7.2.4"))]
+        );
+        assert_eq!((report.keys, report.expires), (4, 1));
+        assert_eq!(report.len, data.len());
+
+        let mut corrupt = data.clone();
+        // a byte of "value" flipped
+        corrupt[46] ^= 1;
+        assert!(matches!(
+            check_rdb(&corrupt),
+            Err(CheckError::Corrupt(offset, _)) if offset == data.len() - 8
+        ));
+        for len in [3, 20, data.len() - 1] {
+            assert!(matches!(
+                check_rdb(&data[..len]),
+                Err(CheckError::Truncated(_))
+            ));
+        }
+        assert!(check_rdb(b"REDIS0099\xff").is_err());
+        assert!(check_rdb(b"RDB").is_err());
+        // a disabled checksum isn't verified
+        let mut unchecked = data[..data.len() - 8].to_vec();
+        unchecked.extend_from_slice(&[0; 8]);
+        assert_eq!(check_rdb(&unchecked).unwrap().checksum, None);
+    }
+
+    #[test]
+    fn test_check_aof() {
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let mut aof = Vec::new();
+        aof.extend_from_slice(set);
+        aof.extend_from_slice(b"#TS:1700000000\r\n");
+        aof.extend_from_slice(b"*1\r\n$5\r\nMULTI\r\n");
+        aof.extend_from_slice(set);
+        aof.extend_from_slice(b"*1\r\n$4\r\nEXEC\r\n");
+        let report = check_aof(&aof);
+        assert_eq!(report.error, None);
+        assert_eq!((report.commands, report.valid_len), (4, aof.len()));
+
+        // a command cut short, the transaction it belongs to is invalid
+        let report = check_aof(&aof[..aof.len() - 3]);
+        assert_eq!(report.error, Some(CheckError::Truncated(aof.len() - 14)));
+        assert_eq!(report.valid_len, set.len() + 16);
+        // a MULTI without EXEC
+        let mut unfinished = aof[..aof.len() - 14].to_vec();
+        let report = check_aof(&unfinished);
+        assert_eq!(report.error, Some(CheckError::Truncated(set.len() + 16)));
+        // garbage in the middle
+        unfinished.extend_from_slice(b"garbage\r\n");
+        let report = check_aof(&unfinished);
+        assert!(matches!(report.error, Some(CheckError::Corrupt(..))));
+
+        // with an RDB preamble
+        let mut preamble = rdb();
+        let rdb_len = preamble.len();
+        preamble.extend_from_slice(set);
+        let report = check_aof(&preamble);
+        assert_eq!(report.error, None);
+        assert_eq!(report.preamble.unwrap().len, rdb_len);
+        assert_eq!(report.commands, 1);
+    }
+}
@@ -0,0 +1,170 @@
+/// Minimal async client for the server, or any server speaking RESP.
+///
+/// Commands are encoded with `DataType::encode_into` and replies decoded with the
+/// parser of `decoders::v3`, which reads every RESP2 and RESP3 type.
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::decoders::v2::DecoderLimits;
+use crate::decoders::v3::decode_command;
+use crate::protocol::DataType;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ClientError {
+    #[error("connection closed by the server")]
+    ConnectionClosed,
+
+    /// The server replied with an error.
+    #[error("{prefix} {message}")]
+    Reply { prefix: String, message: String },
+
+    #[error("unexpected reply: {0:?}")]
+    UnexpectedReply(DataType),
+}
+
+pub struct Client {
+    stream: TcpStream,
+    /// data read from the server not decoded yet
+    buffer: BytesMut,
+    /// commands are encoded here, reusing its memory
+    scratch: BytesMut,
+    limits: DecoderLimits,
+}
+
+impl Client {
+    pub async fn connect(address: impl ToSocketAddrs) -> Result<Client> {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        return Ok(Client {
+            stream: stream,
+            buffer: BytesMut::with_capacity(16 * 1024),
+            scratch: BytesMut::new(),
+            // the replies of the server are trusted, whatever their size
+            limits: DecoderLimits {
+                max_bulk_len: usize::MAX,
+                max_multibulk_len: usize::MAX,
+                ..DecoderLimits::default()
+            },
+        });
+    }
+
+    /// Sends `command` and returns its reply. Error replies are returned as replies.
+    pub async fn send(&mut self, command: DataType) -> Result<DataType> {
+        let mut replies = self.pipeline(&[command]).await?;
+        return Ok(replies.remove(0));
+    }
+
+    /// Sends `commands` with a single write and returns their replies, in order.
+    pub async fn pipeline(&mut self, commands: &[DataType]) -> Result<Vec<DataType>> {
+        self.scratch.clear();
+        for command in commands {
+            command.encode_into(&mut self.scratch)?;
+        }
+        self.stream.write_all(&self.scratch).await?;
+        let mut replies = Vec::with_capacity(commands.len());
+        for _ in commands {
+            replies.push(self.read_reply().await?);
+        }
+        return Ok(replies);
+    }
+
+    /// Reads the next reply, or message pushed by the server.
+    pub async fn read_reply(&mut self) -> Result<DataType> {
+        loop {
+            if let Some(reply) = decode_command(&mut self.buffer, &self.limits)? {
+                return Ok(reply);
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                bail!(ClientError::ConnectionClosed);
+            }
+        }
+    }
+
+    /// Sends a command given as its arguments, failing on error replies.
+    async fn command(&mut self, args: Vec<Bytes>) -> Result<DataType> {
+        let command = DataType::Array {
+            items: args.into_iter().map(DataType::bulk).collect(),
+        };
+        return match self.send(command).await? {
+            DataType::Error { type_, error } => bail!(ClientError::Reply {
+                prefix: type_,
+                message: error,
+            }),
+            reply => Ok(reply),
+        };
+    }
+
+    /// Value of `key`, `None` if it doesn't exist.
+    pub async fn get(&mut self, key: impl Into<Bytes>) -> Result<Option<Bytes>> {
+        let args = vec![Bytes::from("GET"), key.into()];
+        return match self.command(args).await? {
+            DataType::BulkString { string } => Ok(Some(string)),
+            DataType::NullBulkString | DataType::Null => Ok(None),
+            reply => bail!(ClientError::UnexpectedReply(reply)),
+        };
+    }
+
+    pub async fn set(&mut self, key: impl Into<Bytes>, value: impl Into<Bytes>) -> Result<()> {
+        let args = vec![Bytes::from("SET"), key.into(), value.into()];
+        return match self.command(args).await? {
+            DataType::SimpleString { string } if string == "OK" => Ok(()),
+            reply => bail!(ClientError::UnexpectedReply(reply)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{Client, ClientError};
+    use crate::protocol::DataType;
+    use crate::Server;
+
+    fn command(args: &[&str]) -> DataType {
+        return DataType::Array {
+            items: args.iter().map(|arg| DataType::from(*arg)).collect(),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_get_set() {
+        let (address, _server) = Server::spawn_ephemeral().unwrap();
+        let mut client = Client::connect(address).await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), None);
+        client.set("key", "value").await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), Some(Bytes::from("value")));
+        let replies = client
+            .pipeline(&[command(&["PING"]), command(&["GET", "key"])])
+            .await
+            .unwrap();
+        assert_eq!(
+            replies,
+            vec![
+                DataType::SimpleString {
+                    string: String::from("PONG")
+                },
+                DataType::bulk("value")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let (address, server) = Server::spawn_ephemeral().unwrap();
+        let mut client = Client::connect(address).await.unwrap();
+        let reply = client.send(command(&["NOPE"])).await.unwrap();
+        assert!(matches!(reply, DataType::Error { .. }));
+        let err = client.command(vec![Bytes::from("GET")]).await.unwrap_err();
+        match err.downcast_ref() {
+            Some(ClientError::Reply { prefix, .. }) => assert_eq!(prefix, "ERR"),
+            _ => panic!("unexpected error {:?}", err),
+        }
+        server.stop().await;
+        let err = client.read_reply().await.unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ClientError::ConnectionClosed));
+    }
+}
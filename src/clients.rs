@@ -0,0 +1,142 @@
+/// Registry of the connected clients.
+///
+/// Every connection gets a unique id and a channel the rest of the server can
+/// use to send it out-of-band messages (such as key invalidations), which the
+/// connection handler writes to the socket between command replies.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::mpsc;
+
+use crate::protocol::DataType;
+
+pub type ClientId = u64;
+
+/// Options given to CLIENT TRACKING.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackingOptions {
+    /// client receiving the invalidation messages instead of this one
+    pub redirect: Option<ClientId>,
+    /// broadcast mode: get invalidations for every key matching `prefixes`,
+    /// instead of only for the keys read by the client
+    pub bcast: bool,
+    pub prefixes: Vec<String>,
+    /// only track keys read right after CLIENT CACHING yes
+    pub optin: bool,
+    /// track every key read except right after CLIENT CACHING no
+    pub optout: bool,
+    /// don't send invalidations for keys modified by this client
+    pub noloop: bool,
+}
+
+pub struct Client {
+    pub id: ClientId,
+    /// RESP version spoken by the client
+    pub protocol: u8,
+    /// `None` if tracking is disabled
+    pub tracking: Option<TrackingOptions>,
+    /// CLIENT CACHING choice for the next command
+    pub caching: Option<bool>,
+    /// database selected with SELECT
+    pub db: usize,
+    /// name set with CLIENT SETNAME or HELLO SETNAME
+    pub name: Option<String>,
+    /// client library reported with CLIENT SETINFO LIB-NAME
+    pub lib_name: Option<String>,
+    /// client library version reported with CLIENT SETINFO LIB-VER
+    pub lib_ver: Option<String>,
+    /// number of channels and patterns subscribed to, RESP2 clients with any are
+    /// in subscribed mode
+    pub subscriptions: usize,
+    sender: mpsc::UnboundedSender<DataType>,
+}
+
+impl Client {
+    /// Queues an out-of-band message for the client. Messages for clients that
+    /// already disconnected are dropped.
+    pub fn send(&self, message: DataType) {
+        let _ = self.sender.send(message);
+    }
+}
+
+pub struct Clients {
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<ClientId, Client>>,
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        return Clients {
+            next_id: AtomicU64::new(1),
+            clients: Mutex::new(HashMap::new()),
+        };
+    }
+
+    /// Registers a new client, returning its id and the receiving end of its
+    /// out-of-band messages channel.
+    pub fn register(&self) -> (ClientId, mpsc::UnboundedReceiver<DataType>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let client = Client {
+            id: id,
+            protocol: 2,
+            tracking: None,
+            caching: None,
+            db: 0,
+            name: None,
+            lib_name: None,
+            lib_ver: None,
+            subscriptions: 0,
+            sender: sender,
+        };
+        self.clients.lock().unwrap().insert(id, client);
+        return (id, receiver);
+    }
+
+    pub fn unregister(&self, id: ClientId) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    pub fn contains(&self, id: ClientId) -> bool {
+        return self.clients.lock().unwrap().contains_key(&id);
+    }
+
+    /// Runs `f` with every connected client.
+    pub fn for_each(&self, mut f: impl FnMut(&mut Client)) {
+        let mut clients = self.clients.lock().unwrap();
+        for client in clients.values_mut() {
+            f(client);
+        }
+    }
+
+    /// Runs `f` with the client `id`, if it is still connected.
+    pub fn with_client<T>(&self, id: ClientId, f: impl FnOnce(&mut Client) -> T) -> Option<T> {
+        let mut clients = self.clients.lock().unwrap();
+        return clients.get_mut(&id).map(f);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Clients;
+    use crate::protocol::DataType;
+
+    #[test]
+    fn test_register() {
+        let clients = Clients::new();
+        let (first, mut receiver) = clients.register();
+        let (second, _) = clients.register();
+        assert_ne!(first, second);
+
+        clients.with_client(first, |client| client.send(DataType::Integer { number: 1 }));
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(DataType::Integer { number: 1 })
+        ));
+
+        clients.unregister(first);
+        assert!(!clients.contains(first));
+        assert_eq!(clients.with_client(first, |client| client.id), None);
+    }
+}
@@ -0,0 +1,104 @@
+/// Source of the current time of the keyspace.
+///
+/// Expirations and access times are stored as milliseconds since the epoch, so
+/// absolute deadlines like the ones given to EXPIREAT mean the same as in Redis, but
+/// the time passing is measured with a monotonic clock: a wall clock jumping back or
+/// forward (NTP, a user changing the date) doesn't make every key expire at once or
+/// live forever.
+use std::sync::{Arc, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the epoch.
+    fn now(&self) -> usize;
+}
+
+/// Wall clock time when the clock was created, advanced by a monotonic clock.
+pub struct MonotonicClock {
+    epoch_ms: usize,
+    start: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        let since_the_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards");
+        return MonotonicClock {
+            epoch_ms: since_the_epoch.as_millis() as usize,
+            start: Instant::now(),
+        };
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> usize {
+        return self.epoch_ms + self.start.elapsed().as_millis() as usize;
+    }
+}
+
+/// The clock of the server, shared by every keyspace so their times agree.
+pub fn system() -> Arc<dyn Clock> {
+    static SYSTEM: OnceLock<Arc<MonotonicClock>> = OnceLock::new();
+    return SYSTEM
+        .get_or_init(|| Arc::new(MonotonicClock::new()))
+        .clone();
+}
+
+/// A clock that only moves when told to, so tests can expire keys without sleeping.
+#[cfg(test)]
+pub struct MockClock {
+    now: AtomicUsize,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(now: usize) -> Arc<Self> {
+        return Arc::new(MockClock {
+            now: AtomicUsize::new(now),
+        });
+    }
+
+    pub fn advance(&self, ms: usize) {
+        self.now.fetch_add(ms, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, now: usize) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> usize {
+        return self.now.load(Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{system, Clock, MockClock};
+
+    #[test]
+    fn test_system_clock() {
+        let clock = system();
+        let before = clock.now();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(clock.now() >= before + 5);
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(1000);
+        assert_eq!(clock.now(), 1000);
+        clock.advance(500);
+        assert_eq!(clock.now(), 1500);
+        clock.set(10);
+        assert_eq!(clock.now(), 10);
+    }
+}
@@ -0,0 +1,119 @@
+/// RESP codec for `tokio_util`'s framed streams, so connections can be driven with
+/// `Framed<TcpStream, RespCodec>` (or `FramedRead`/`FramedWrite` over their halves)
+/// instead of the decoders' own stream wrappers. Decoding is done by the v3 parser
+/// and encoding by `DataType::encode_into`, so the codec works for both ends of a
+/// connection.
+///
+/// The codec only sees the buffered data, the read timeout of `DecoderLimits` isn't
+/// enforced by it.
+use anyhow::{Error, Result};
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::decoders::v2::DecoderLimits;
+use crate::decoders::v3::decode_command;
+use crate::protocol::DataType;
+
+#[derive(Debug, Default)]
+pub struct RespCodec {
+    limits: DecoderLimits,
+}
+
+impl RespCodec {
+    pub fn new() -> Self {
+        return RespCodec::default();
+    }
+
+    /// Replaces the default limits of the decoder.
+    pub fn with_limits(mut self, limits: DecoderLimits) -> Self {
+        self.limits = limits;
+        return self;
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = DataType;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<DataType>> {
+        return decode_command(src, &self.limits);
+    }
+}
+
+impl Encoder<DataType> for RespCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: DataType, dst: &mut BytesMut) -> Result<()> {
+        return item.encode_into(dst);
+    }
+}
+
+impl Encoder<&DataType> for RespCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: &DataType, dst: &mut BytesMut) -> Result<()> {
+        return item.encode_into(dst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+    use super::RespCodec;
+    use crate::decoders::v2::DecoderLimits;
+    use crate::protocol::DataType;
+
+    fn command(args: &[&str]) -> DataType {
+        return DataType::from(
+            args.iter()
+                .map(|arg| DataType::from(*arg))
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::from(&b"*2\r\n$4\r\nECHO\r\n$5\r\nhel"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"lo\r\n\r\nPING\r\n*1");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(command(&["ECHO", "hello"]))
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(command(&["PING"])));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"*1");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut codec = RespCodec::new().with_limits(DecoderLimits {
+            max_bulk_len: 4,
+            ..Default::default()
+        });
+        let mut buf = BytesMut::from(&b"*1\r\n$5\r\nhello\r\n"[..]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.to_string(), "invalid bulk length");
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(DataType::ok(), &mut buf).unwrap();
+        codec.encode(&DataType::from(3), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"+OK\r\n:3\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_framed_read() {
+        let data: &[u8] = b"*1\r\n$4\r\nPING\r\nECHO hi\r\n";
+        let framed = FramedRead::new(data, RespCodec::new());
+        let commands: Vec<DataType> = framed.map(|c| c.unwrap()).collect().await;
+        assert_eq!(commands, vec![command(&["PING"]), command(&["ECHO", "hi"])]);
+    }
+}
@@ -0,0 +1,1218 @@
+/// Declarative table of the commands supported by the server.
+///
+/// Every entry carries the metadata Redis exposes through COMMAND INFO/DOCS
+/// (arity, flags, key positions and ACL categories) together with the function
+/// that parses the command arguments into its `CommandHandler`.
+///
+/// Besides the built-in commands, embedders can `register` their own commands at
+/// startup, which are then dispatched, reported and renamed like any other.
+use std::sync::RwLock;
+
+use anyhow::Result;
+use thiserror::Error;
+
+use crate::commands::{connection, generic, hash, list, pubsub, server, string, CommandHandler};
+use crate::protocol::DataType;
+
+pub struct CommandSpec {
+    /// lowercase command name
+    pub name: &'static str,
+    /// number of arguments including the command name. A negative arity -N means
+    /// at least N arguments.
+    pub arity: isize,
+    pub flags: &'static [&'static str],
+    /// position of the first key argument, 0 if the command takes no keys
+    pub first_key: isize,
+    /// position of the last key argument, negative values count from the end
+    pub last_key: isize,
+    /// distance between key arguments
+    pub step: isize,
+    pub acl_categories: &'static [&'static str],
+    pub group: &'static str,
+    pub since: &'static str,
+    pub summary: &'static str,
+    pub parse: fn(&[DataType]) -> Result<Box<dyn CommandHandler>>,
+}
+
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    CommandSpec {
+        name: "blmpop",
+        arity: -5,
+        flags: &["write", "blocking", "movablekeys"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@write", "@list", "@slow", "@blocking"],
+        group: "list",
+        since: "7.0.0",
+        summary: "Pops the first element from one of multiple lists. Blocks until an element is available otherwise. Deletes the list if the last element was popped.",
+        parse: list::parse_blmpop,
+    },
+    CommandSpec {
+        name: "client",
+        arity: -2,
+        flags: &["noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@slow", "@connection"],
+        group: "connection",
+        since: "2.4.0",
+        summary: "A container for client connection commands.",
+        parse: connection::parse_client,
+    },
+    CommandSpec {
+        name: "command",
+        arity: -1,
+        flags: &["loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@slow", "@connection"],
+        group: "server",
+        since: "2.8.13",
+        summary: "Returns detailed information about all commands.",
+        parse: server::parse_command_command,
+    },
+    CommandSpec {
+        name: "config",
+        arity: -2,
+        flags: &["admin", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@admin", "@slow", "@dangerous"],
+        group: "server",
+        since: "2.0.0",
+        summary: "Gets or sets configuration parameters.",
+        parse: server::parse_config,
+    },
+    CommandSpec {
+        name: "dbsize",
+        arity: 1,
+        flags: &["readonly", "fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@keyspace", "@read", "@fast"],
+        group: "server",
+        since: "1.0.0",
+        summary: "Returns the number of keys in the database.",
+        parse: server::parse_dbsize,
+    },
+    CommandSpec {
+        name: "debug",
+        arity: -2,
+        flags: &["admin", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@admin", "@slow", "@dangerous"],
+        group: "server",
+        since: "1.0.0",
+        summary: "A container for debugging commands.",
+        parse: server::parse_debug,
+    },
+    CommandSpec {
+        name: "decr",
+        arity: 2,
+        flags: &["write", "denyoom", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@string", "@fast"],
+        group: "string",
+        since: "1.0.0",
+        summary: "Decrements the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+        parse: string::parse_decr,
+    },
+    CommandSpec {
+        name: "decrby",
+        arity: 3,
+        flags: &["write", "denyoom", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@string", "@fast"],
+        group: "string",
+        since: "1.0.0",
+        summary: "Decrements a number from the integer value of a key. Uses 0 as initial value if the key doesn't exist.",
+        parse: string::parse_decrby,
+    },
+    CommandSpec {
+        name: "del",
+        arity: -2,
+        flags: &["write"],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        acl_categories: &["@keyspace", "@write", "@slow"],
+        group: "generic",
+        since: "1.0.0",
+        summary: "Deletes one or more keys.",
+        parse: generic::parse_del,
+    },
+    CommandSpec {
+        name: "dump",
+        arity: 2,
+        flags: &["readonly"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@read", "@slow"],
+        group: "generic",
+        since: "2.6.0",
+        summary: "Returns a serialized representation of the value stored at a key.",
+        parse: generic::parse_dump,
+    },
+    CommandSpec {
+        name: "echo",
+        arity: 2,
+        flags: &["fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@fast", "@connection"],
+        group: "connection",
+        since: "1.0.0",
+        summary: "Returns the given string.",
+        parse: connection::parse_echo,
+    },
+    CommandSpec {
+        name: "exists",
+        arity: -2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        acl_categories: &["@keyspace", "@read", "@fast"],
+        group: "generic",
+        since: "1.0.0",
+        summary: "Determines whether one or more keys exist.",
+        parse: generic::parse_exists,
+    },
+    CommandSpec {
+        name: "expire",
+        arity: -3,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@write", "@fast"],
+        group: "generic",
+        since: "1.0.0",
+        summary: "Sets the expiration time of a key in seconds.",
+        parse: generic::parse_expire,
+    },
+    CommandSpec {
+        name: "expireat",
+        arity: -3,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@write", "@fast"],
+        group: "generic",
+        since: "1.2.0",
+        summary: "Sets the expiration time of a key to a Unix timestamp.",
+        parse: generic::parse_expireat,
+    },
+    CommandSpec {
+        name: "expiretime",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@read", "@fast"],
+        group: "generic",
+        since: "7.0.0",
+        summary: "Returns the expiration time of a key as a Unix timestamp.",
+        parse: generic::parse_expiretime,
+    },
+    CommandSpec {
+        name: "flushall",
+        arity: -1,
+        flags: &["write"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@keyspace", "@write", "@slow", "@dangerous"],
+        group: "server",
+        since: "1.0.0",
+        summary: "Removes all keys from all databases.",
+        parse: server::parse_flushall,
+    },
+    CommandSpec {
+        name: "flushdb",
+        arity: -1,
+        flags: &["write"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@keyspace", "@write", "@slow", "@dangerous"],
+        group: "server",
+        since: "1.0.0",
+        summary: "Remove all keys from the current database.",
+        parse: server::parse_flushdb,
+    },
+    CommandSpec {
+        name: "get",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@string", "@fast"],
+        group: "string",
+        since: "1.0.0",
+        summary: "Returns the string value of a key.",
+        parse: string::parse_get,
+    },
+    CommandSpec {
+        name: "getdel",
+        arity: 2,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@string", "@fast"],
+        group: "string",
+        since: "6.2.0",
+        summary: "Returns the string value of a key after deleting the key.",
+        parse: string::parse_getdel,
+    },
+    CommandSpec {
+        name: "getex",
+        arity: -2,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@string", "@fast"],
+        group: "string",
+        since: "6.2.0",
+        summary: "Returns the string value of a key after setting its expiration time.",
+        parse: string::parse_getex,
+    },
+    CommandSpec {
+        name: "getrange",
+        arity: 4,
+        flags: &["readonly"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@string", "@slow"],
+        group: "string",
+        since: "2.4.0",
+        summary: "Returns a substring of the string stored at a key.",
+        parse: string::parse_getrange,
+    },
+    CommandSpec {
+        name: "getset",
+        arity: 3,
+        flags: &["write", "denyoom", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@string", "@fast"],
+        group: "string",
+        since: "1.0.0",
+        summary: "Returns the previous string value of a key after setting it to a new value.",
+        parse: string::parse_getset,
+    },
+    CommandSpec {
+        name: "hdel",
+        arity: -3,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@hash", "@fast"],
+        group: "hash",
+        since: "2.0.0",
+        summary: "Deletes one or more fields and their values from a hash. Deletes the hash if no fields remain.",
+        parse: hash::parse_hdel,
+    },
+    CommandSpec {
+        name: "hello",
+        arity: -1,
+        flags: &["noscript", "loading", "stale", "fast", "no-auth", "allow-busy"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@fast", "@connection"],
+        group: "connection",
+        since: "6.0.0",
+        summary: "Handshakes with the Redis server.",
+        parse: connection::parse_hello,
+    },
+    CommandSpec {
+        name: "hexists",
+        arity: 3,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@hash", "@fast"],
+        group: "hash",
+        since: "2.0.0",
+        summary: "Determines whether a field exists in a hash.",
+        parse: hash::parse_hexists,
+    },
+    CommandSpec {
+        name: "hget",
+        arity: 3,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@hash", "@fast"],
+        group: "hash",
+        since: "2.0.0",
+        summary: "Returns the value of a field in a hash.",
+        parse: hash::parse_hget,
+    },
+    CommandSpec {
+        name: "hgetall",
+        arity: 2,
+        flags: &["readonly"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@hash", "@slow"],
+        group: "hash",
+        since: "2.0.0",
+        summary: "Returns all fields and values in a hash.",
+        parse: hash::parse_hgetall,
+    },
+    CommandSpec {
+        name: "hkeys",
+        arity: 2,
+        flags: &["readonly"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@hash", "@slow"],
+        group: "hash",
+        since: "2.0.0",
+        summary: "Returns all fields in a hash.",
+        parse: hash::parse_hkeys,
+    },
+    CommandSpec {
+        name: "hlen",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@hash", "@fast"],
+        group: "hash",
+        since: "2.0.0",
+        summary: "Returns the number of fields in a hash.",
+        parse: hash::parse_hlen,
+    },
+    CommandSpec {
+        name: "hmget",
+        arity: -3,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@hash", "@fast"],
+        group: "hash",
+        since: "2.0.0",
+        summary: "Returns the values of all fields in a hash.",
+        parse: hash::parse_hmget,
+    },
+    CommandSpec {
+        name: "hset",
+        arity: -4,
+        flags: &["write", "denyoom", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@hash", "@fast"],
+        group: "hash",
+        since: "2.0.0",
+        summary: "Creates or modifies the value of a field in a hash.",
+        parse: hash::parse_hset,
+    },
+    CommandSpec {
+        name: "hvals",
+        arity: 2,
+        flags: &["readonly"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@hash", "@slow"],
+        group: "hash",
+        since: "2.0.0",
+        summary: "Returns all values in a hash.",
+        parse: hash::parse_hvals,
+    },
+    CommandSpec {
+        name: "incr",
+        arity: 2,
+        flags: &["write", "denyoom", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@string", "@fast"],
+        group: "string",
+        since: "1.0.0",
+        summary: "Increments the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+        parse: string::parse_incr,
+    },
+    CommandSpec {
+        name: "incrby",
+        arity: 3,
+        flags: &["write", "denyoom", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@string", "@fast"],
+        group: "string",
+        since: "1.0.0",
+        summary: "Increments the integer value of a key by a number. Uses 0 as initial value if the key doesn't exist.",
+        parse: string::parse_incrby,
+    },
+    CommandSpec {
+        name: "info",
+        arity: -1,
+        flags: &["loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@slow", "@dangerous"],
+        group: "server",
+        since: "1.0.0",
+        summary: "Returns information and statistics about the server.",
+        parse: server::parse_info,
+    },
+    CommandSpec {
+        name: "keys",
+        arity: 2,
+        flags: &["readonly"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@keyspace", "@read", "@slow", "@dangerous"],
+        group: "generic",
+        since: "1.0.0",
+        summary: "Returns all key names that match a pattern.",
+        parse: generic::parse_keys,
+    },
+    CommandSpec {
+        name: "latency",
+        arity: -2,
+        flags: &["admin", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@admin", "@slow", "@dangerous"],
+        group: "server",
+        since: "2.8.13",
+        summary: "Reports latency spikes observed by the latency monitor.",
+        parse: server::parse_latency,
+    },
+    CommandSpec {
+        name: "lindex",
+        arity: 3,
+        flags: &["readonly"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@list", "@slow"],
+        group: "list",
+        since: "1.0.0",
+        summary: "Returns an element from a list by its index.",
+        parse: list::parse_lindex,
+    },
+    CommandSpec {
+        name: "linsert",
+        arity: 5,
+        flags: &["write", "denyoom"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@list", "@slow"],
+        group: "list",
+        since: "2.2.0",
+        summary: "Inserts an element before or after another element in a list.",
+        parse: list::parse_linsert,
+    },
+    CommandSpec {
+        name: "lmove",
+        arity: 5,
+        flags: &["write", "denyoom"],
+        first_key: 1,
+        last_key: 2,
+        step: 1,
+        acl_categories: &["@write", "@list", "@slow"],
+        group: "list",
+        since: "6.2.0",
+        summary: "Returns an element after popping it from one list and pushing it to another. Deletes the list if the last element was moved.",
+        parse: list::parse_lmove,
+    },
+    CommandSpec {
+        name: "lmpop",
+        arity: -4,
+        flags: &["write", "movablekeys"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@write", "@list", "@slow"],
+        group: "list",
+        since: "7.0.0",
+        summary: "Returns multiple elements from a list after removing them. Deletes the list if the last element was popped.",
+        parse: list::parse_lmpop,
+    },
+    CommandSpec {
+        name: "lpos",
+        arity: -3,
+        flags: &["readonly"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@read", "@list", "@slow"],
+        group: "list",
+        since: "6.0.6",
+        summary: "Returns the index of matching elements in a list.",
+        parse: list::parse_lpos,
+    },
+    CommandSpec {
+        name: "lpush",
+        arity: -3,
+        flags: &["write", "denyoom", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@list", "@fast"],
+        group: "list",
+        since: "1.0.0",
+        summary: "Prepends one or more elements to a list. Creates the key if it doesn't exist.",
+        parse: list::parse_lpush,
+    },
+    CommandSpec {
+        name: "lrem",
+        arity: 4,
+        flags: &["write"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@list", "@slow"],
+        group: "list",
+        since: "1.0.0",
+        summary: "Removes elements from a list.",
+        parse: list::parse_lrem,
+    },
+    CommandSpec {
+        name: "lset",
+        arity: 4,
+        flags: &["write", "denyoom"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@list", "@slow"],
+        group: "list",
+        since: "1.0.0",
+        summary: "Sets the value of an element in a list by its index.",
+        parse: list::parse_lset,
+    },
+    CommandSpec {
+        name: "ltrim",
+        arity: 4,
+        flags: &["write"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@list", "@slow"],
+        group: "list",
+        since: "1.0.0",
+        summary: "Removes elements from both ends a list. Deletes the list if all elements were trimmed.",
+        parse: list::parse_ltrim,
+    },
+    CommandSpec {
+        name: "memory",
+        arity: -2,
+        flags: &["readonly"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@read", "@slow"],
+        group: "server",
+        since: "4.0.0",
+        summary: "Reports memory usage of keys and of the whole dataset.",
+        parse: server::parse_memory,
+    },
+    CommandSpec {
+        name: "mget",
+        arity: -2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        acl_categories: &["@read", "@string", "@fast"],
+        group: "string",
+        since: "1.0.0",
+        summary: "Atomically returns the string values of one or more keys.",
+        parse: string::parse_mget,
+    },
+    CommandSpec {
+        name: "move",
+        arity: 3,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@write", "@fast"],
+        group: "generic",
+        since: "1.0.0",
+        summary: "Moves a key to another database.",
+        parse: generic::parse_move,
+    },
+    CommandSpec {
+        name: "mset",
+        arity: -3,
+        flags: &["write", "denyoom"],
+        first_key: 1,
+        last_key: -1,
+        step: 2,
+        acl_categories: &["@write", "@string", "@slow"],
+        group: "string",
+        since: "1.0.1",
+        summary: "Atomically creates or modifies the string values of one or more keys.",
+        parse: string::parse_mset,
+    },
+    CommandSpec {
+        name: "msetnx",
+        arity: -3,
+        flags: &["write", "denyoom"],
+        first_key: 1,
+        last_key: -1,
+        step: 2,
+        acl_categories: &["@write", "@string", "@slow"],
+        group: "string",
+        since: "1.0.1",
+        summary: "Atomically modifies the string values of one or more keys only when all keys don't exist.",
+        parse: string::parse_msetnx,
+    },
+    CommandSpec {
+        name: "object",
+        arity: -2,
+        flags: &["readonly"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@keyspace", "@read", "@slow"],
+        group: "generic",
+        since: "2.2.3",
+        summary: "Returns the internal details of the value stored at a key.",
+        parse: generic::parse_object,
+    },
+    CommandSpec {
+        name: "persist",
+        arity: 2,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@write", "@fast"],
+        group: "generic",
+        since: "2.2.0",
+        summary: "Removes the expiration time of a key.",
+        parse: generic::parse_persist,
+    },
+    CommandSpec {
+        name: "pexpire",
+        arity: -3,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@write", "@fast"],
+        group: "generic",
+        since: "2.6.0",
+        summary: "Sets the expiration time of a key in milliseconds.",
+        parse: generic::parse_pexpire,
+    },
+    CommandSpec {
+        name: "pexpireat",
+        arity: -3,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@write", "@fast"],
+        group: "generic",
+        since: "2.6.0",
+        summary: "Sets the expiration time of a key to a Unix milliseconds timestamp.",
+        parse: generic::parse_pexpireat,
+    },
+    CommandSpec {
+        name: "pexpiretime",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@read", "@fast"],
+        group: "generic",
+        since: "7.0.0",
+        summary: "Returns the expiration time of a key as a Unix milliseconds timestamp.",
+        parse: generic::parse_pexpiretime,
+    },
+    CommandSpec {
+        name: "ping",
+        arity: -1,
+        flags: &["fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@fast", "@connection"],
+        group: "connection",
+        since: "1.0.0",
+        summary: "Returns the server's liveliness response.",
+        parse: connection::parse_ping,
+    },
+    CommandSpec {
+        name: "psubscribe",
+        arity: -2,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@pubsub", "@slow"],
+        group: "pubsub",
+        since: "2.0.0",
+        summary: "Listens for messages published to channels that match one or more patterns.",
+        parse: pubsub::parse_psubscribe,
+    },
+    CommandSpec {
+        name: "pttl",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@read", "@fast"],
+        group: "generic",
+        since: "2.6.0",
+        summary: "Returns the expiration time in milliseconds of a key.",
+        parse: generic::parse_pttl,
+    },
+    CommandSpec {
+        name: "publish",
+        arity: 3,
+        flags: &["pubsub", "loading", "stale", "fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@pubsub", "@fast"],
+        group: "pubsub",
+        since: "2.0.0",
+        summary: "Posts a message to a channel.",
+        parse: pubsub::parse_publish,
+    },
+    CommandSpec {
+        name: "punsubscribe",
+        arity: -1,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@pubsub", "@slow"],
+        group: "pubsub",
+        since: "2.0.0",
+        summary: "Stops listening to messages published to channels that match one or more patterns.",
+        parse: pubsub::parse_punsubscribe,
+    },
+    CommandSpec {
+        name: "restore",
+        arity: -4,
+        flags: &["write", "denyoom"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@write", "@slow", "@dangerous"],
+        group: "generic",
+        since: "2.6.0",
+        summary: "Creates a key from the serialized representation of a value.",
+        parse: generic::parse_restore,
+    },
+    CommandSpec {
+        name: "rpush",
+        arity: -3,
+        flags: &["write", "denyoom", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@list", "@fast"],
+        group: "list",
+        since: "1.0.0",
+        summary: "Appends one or more elements to a list. Creates the key if it doesn't exist.",
+        parse: list::parse_rpush,
+    },
+    CommandSpec {
+        name: "scan",
+        arity: -2,
+        flags: &["readonly"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@keyspace", "@read", "@slow"],
+        group: "generic",
+        since: "2.8.0",
+        summary: "Iterates over the key names in the database.",
+        parse: generic::parse_scan,
+    },
+    CommandSpec {
+        name: "select",
+        arity: 2,
+        flags: &["loading", "stale", "fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@fast", "@connection"],
+        group: "connection",
+        since: "1.0.0",
+        summary: "Changes the selected database.",
+        parse: connection::parse_select,
+    },
+    CommandSpec {
+        name: "set",
+        arity: -3,
+        flags: &["write", "denyoom"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@string", "@slow"],
+        group: "string",
+        since: "1.0.0",
+        summary: "Sets the string value of a key, ignoring its type.",
+        parse: string::parse_set,
+    },
+    CommandSpec {
+        name: "setrange",
+        arity: 4,
+        flags: &["write", "denyoom"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@string", "@slow"],
+        group: "string",
+        since: "2.2.0",
+        summary: "Overwrites a part of a string value with another by an offset. Creates the key if it doesn't exist.",
+        parse: string::parse_setrange,
+    },
+    CommandSpec {
+        name: "sort",
+        arity: -2,
+        flags: &["write", "denyoom", "movablekeys"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@write", "@set", "@sortedset", "@list", "@slow", "@dangerous"],
+        group: "generic",
+        since: "1.0.0",
+        summary: "Sorts the elements in a list, a set, or a sorted set, optionally storing the result.",
+        parse: generic::parse_sort,
+    },
+    CommandSpec {
+        name: "subscribe",
+        arity: -2,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@pubsub", "@slow"],
+        group: "pubsub",
+        since: "2.0.0",
+        summary: "Listens for messages published to channels.",
+        parse: pubsub::parse_subscribe,
+    },
+    CommandSpec {
+        name: "swapdb",
+        arity: 3,
+        flags: &["write", "fast"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@keyspace", "@write", "@fast", "@dangerous"],
+        group: "server",
+        since: "4.0.0",
+        summary: "Swaps two Redis databases.",
+        parse: server::parse_swapdb,
+    },
+    CommandSpec {
+        name: "touch",
+        arity: -2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        acl_categories: &["@keyspace", "@read", "@fast"],
+        group: "generic",
+        since: "3.2.1",
+        summary: "Returns the number of existing keys out of those specified after updating the time they were last accessed.",
+        parse: generic::parse_touch,
+    },
+    CommandSpec {
+        name: "ttl",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@read", "@fast"],
+        group: "generic",
+        since: "1.0.0",
+        summary: "Returns the expiration time in seconds of a key.",
+        parse: generic::parse_ttl,
+    },
+    CommandSpec {
+        name: "type",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        acl_categories: &["@keyspace", "@read", "@fast"],
+        group: "generic",
+        since: "1.0.0",
+        summary: "Determines the type of value stored at a key.",
+        parse: generic::parse_type,
+    },
+    CommandSpec {
+        name: "unlink",
+        arity: -2,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        acl_categories: &["@keyspace", "@write", "@fast"],
+        group: "generic",
+        since: "4.0.0",
+        summary: "Asynchronously deletes one or more keys.",
+        parse: generic::parse_unlink,
+    },
+    CommandSpec {
+        name: "unsubscribe",
+        arity: -1,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        acl_categories: &["@pubsub", "@slow"],
+        group: "pubsub",
+        since: "2.0.0",
+        summary: "Stops listening to messages posted to channels.",
+        parse: pubsub::parse_unsubscribe,
+    },
+];
+
+/// Commands added with `register`, reported after the built-in ones.
+static REGISTERED: RwLock<Vec<&'static CommandSpec>> = RwLock::new(Vec::new());
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    #[error("command '{0}' already exists")]
+    AlreadyExists(String),
+    #[error("invalid command name '{0}'")]
+    InvalidName(String),
+    #[error("command '{0}' has no arity")]
+    InvalidArity(String),
+}
+
+/// Adds a custom command to the table. Its `parse` function must build handlers
+/// whose `name` is the name of the spec.
+/// Commands should be registered at startup, before clients can invoke them.
+// the server doesn't register custom commands itself, this is for embedders
+#[allow(dead_code)]
+pub fn register(spec: CommandSpec) -> Result<&'static CommandSpec, RegisterError> {
+    if spec.name.is_empty() || spec.name != spec.name.to_lowercase() || spec.name.contains(' ') {
+        return Err(RegisterError::InvalidName(spec.name.to_string()));
+    }
+    if spec.arity == 0 {
+        return Err(RegisterError::InvalidArity(spec.name.to_string()));
+    }
+    let mut registered = REGISTERED.write().unwrap();
+    if lookup_in(
+        COMMAND_TABLE.iter().chain(registered.iter().copied()),
+        spec.name,
+    )
+    .is_some()
+    {
+        return Err(RegisterError::AlreadyExists(spec.name.to_string()));
+    }
+    // specs live as long as the server, like the built-in ones
+    let spec: &'static CommandSpec = Box::leak(Box::new(spec));
+    registered.push(spec);
+    return Ok(spec);
+}
+
+/// Every command, built-in and registered.
+pub fn commands() -> Vec<&'static CommandSpec> {
+    let registered = REGISTERED.read().unwrap();
+    return COMMAND_TABLE
+        .iter()
+        .chain(registered.iter().copied())
+        .collect();
+}
+
+fn lookup_in<'a>(
+    mut specs: impl Iterator<Item = &'a CommandSpec>,
+    name: &str,
+) -> Option<&'a CommandSpec> {
+    return specs.find(|spec| spec.name.eq_ignore_ascii_case(name));
+}
+
+/// Finds the spec of command `name` (case insensitive).
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    if let Some(spec) = lookup_in(COMMAND_TABLE.iter(), name) {
+        return Some(spec);
+    }
+    let registered = REGISTERED.read().unwrap();
+    return lookup_in(registered.iter().copied(), name);
+}
+
+impl CommandSpec {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        return self.flags.contains(&flag);
+    }
+
+    /// Checks `argc` (number of arguments including the command name) against the arity.
+    pub fn arity_matches(&self, argc: usize) -> bool {
+        let argc = argc as isize;
+        return (self.arity > 0 && argc == self.arity) || (self.arity < 0 && argc >= -self.arity);
+    }
+
+    /// Positions of the key arguments in a command invocation of `argc` arguments.
+    pub fn key_positions(&self, argc: usize) -> Vec<usize> {
+        if self.first_key <= 0 {
+            return Vec::new();
+        }
+        let argc = argc as isize;
+        let last = if self.last_key < 0 {
+            argc + self.last_key
+        } else {
+            self.last_key
+        };
+        let mut positions = Vec::new();
+        let mut pos = self.first_key;
+        while pos <= last && pos < argc {
+            positions.push(pos as usize);
+            pos += self.step.max(1);
+        }
+        return positions;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use super::{commands, lookup, register, CommandSpec, RegisterError, COMMAND_TABLE};
+    use crate::clients::ClientId;
+    use crate::commands::{dispatch, CommandHandler};
+    use crate::config::Config;
+    use crate::errors::ReplyError;
+    use crate::protocol::DataType;
+    use crate::state::{State, StateInner};
+
+    #[derive(Debug)]
+    struct Hello {
+        name: String,
+    }
+
+    fn parse_hello(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+        let name = array[1].as_string().unwrap_or_default();
+        return Ok(Box::new(Hello { name: name }));
+    }
+
+    impl CommandHandler for Hello {
+        fn name(&self) -> &'static str {
+            return "x.hello";
+        }
+
+        fn run(&self, _state: &State, _client: ClientId) -> Result<DataType, ReplyError> {
+            return Ok(DataType::bulk(format!("hello {}", self.name)));
+        }
+    }
+
+    fn hello_spec(name: &'static str) -> CommandSpec {
+        return CommandSpec {
+            name: name,
+            arity: 2,
+            flags: &["fast"],
+            first_key: 0,
+            last_key: 0,
+            step: 0,
+            acl_categories: &["@fast"],
+            group: "module",
+            since: "1.0.0",
+            summary: "Greets the given name.",
+            parse: parse_hello,
+        };
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup("GET").unwrap().name, "get");
+        assert_eq!(lookup("gEt").unwrap().name, "get");
+        assert!(lookup("nope").is_none());
+    }
+
+    #[test]
+    fn test_table_is_consistent() {
+        for spec in COMMAND_TABLE {
+            assert_eq!(spec.name, spec.name.to_lowercase());
+            assert_ne!(spec.arity, 0, "{} has no arity", spec.name);
+            if spec.first_key == 0 {
+                assert_eq!(spec.last_key, 0, "{} has keys", spec.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_arity_and_keys() {
+        let get = lookup("get").unwrap();
+        assert!(get.arity_matches(2));
+        assert!(!get.arity_matches(3));
+        assert_eq!(get.key_positions(2), vec![1]);
+
+        let set = lookup("set").unwrap();
+        assert!(set.arity_matches(5));
+        assert!(!set.arity_matches(2));
+        assert_eq!(set.key_positions(5), vec![1]);
+
+        assert!(lookup("ping").unwrap().key_positions(1).is_empty());
+    }
+
+    #[test]
+    fn test_register() {
+        let spec = register(hello_spec("x.hello")).unwrap();
+        assert_eq!(lookup("X.HELLO").unwrap().name, spec.name);
+        assert_eq!(commands().len(), COMMAND_TABLE.len() + 1);
+        assert_eq!(
+            register(hello_spec("x.hello")).err(),
+            Some(RegisterError::AlreadyExists("x.hello".to_string()))
+        );
+        assert_eq!(
+            register(hello_spec("get")).err(),
+            Some(RegisterError::AlreadyExists("get".to_string()))
+        );
+        assert!(register(hello_spec("X.BYE")).is_err());
+
+        let state = StateInner::new(Config::default());
+        let data = DataType::Array {
+            items: vec![DataType::from("x.hello"), DataType::from("world")],
+        };
+        assert_eq!(dispatch(data, &state, 0), DataType::bulk("hello world"));
+        let data = DataType::Array {
+            items: vec![DataType::from("x.hello")],
+        };
+        assert!(matches!(dispatch(data, &state, 0), DataType::Error { .. }));
+    }
+}
@@ -1,34 +1,61 @@
+#[cfg(feature = "otel")]
+use crate::telemetry;
 use crate::{
-    db::{DBValue, Map},
+    blocked,
+    clients::ClientId,
+    command_table::{self, CommandSpec},
+    config::Config,
+    db::{DBValue, MapInner},
+    errors::ReplyError,
+    evict, latency,
     protocol::DataType,
+    state::State,
+    tracking,
 };
 
 use anyhow::{bail, Result};
 use bytes::Bytes;
+use std::fmt::Debug;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
-macro_rules! get_type_or_bad_arguments {
-    ($array:ident, $ix:literal, $goodmatch:pat => $val:ident) => {
-        match $array.get($ix) {
-            $goodmatch => $val,
-            _ => bail!(ParseError::BadArguments),
+macro_rules! get_string_or_bad_args {
+    ($array:ident, $ix:expr) => {
+        match $array.get($ix).and_then(DataType::as_string) {
+            Some(string) => string,
+            None => bail!(ParseError::BadArguments),
         }
     };
 }
 
-macro_rules! get_string_or_bad_args {
-    ($array:ident, $ix:literal) => {
-        get_type_or_bad_arguments!{$array, $ix, Some(DataType::SimpleString { string } | DataType::BulkString { string }) => string}
+macro_rules! get_bytes_or_bad_args {
+    ($array:ident, $ix:expr) => {
+        match $array.get($ix).and_then(DataType::as_bytes) {
+            Some(bytes) => bytes,
+            None => bail!(ParseError::BadArguments),
+        }
     };
 }
 
+pub mod connection;
+pub mod generic;
+pub mod hash;
+pub mod list;
+pub mod pubsub;
+pub mod server;
+pub mod string;
+
 #[derive(Error, Debug)]
 pub enum ParseError {
     #[error("empty array")]
     EmptyArray,
 
-    #[error("unkown command: {0}")]
-    UnkownCommand(String),
+    #[error("unknown command '{0}', with args beginning with: {1}")]
+    UnkownCommand(String, String),
+
+    #[error("wrong number of arguments for '{0}' command")]
+    WrongArity(String),
 
     #[error("invalid first attribute for command. should be SimpleString or BulkString.")]
     InvalidFirstAttribute,
@@ -36,129 +63,697 @@ pub enum ParseError {
     #[error("invalid command datatype. datatype should be array.")]
     InvalidCommandDataType,
 
-    #[error("invalid format for command arguments.")]
+    #[error("syntax error")]
     BadArguments,
 
+    #[error("value is not an integer or out of range")]
+    NotAnInteger,
+
     #[error("Option '{0}' for {1} not supported")]
     UnsupportedOption(String, String),
+
+    #[error("unknown subcommand '{1}'. Try {0} HELP.")]
+    UnknownSubcommand(String, String),
+
+    #[error("{0} options at the same time are not compatible")]
+    IncompatibleOptions(&'static str),
+
+    #[error("invalid expire time in '{0}' command")]
+    InvalidExpireTime(String),
+
+    #[error("invalid cursor")]
+    InvalidCursor,
+
+    #[error("invalid {0} DB index")]
+    InvalidDbIndex(&'static str),
+
+    #[error("{0} should be greater than 0")]
+    NotPositive(&'static str),
+
+    #[error("{0} can't be negative")]
+    Negative(&'static str),
+
+    #[error("RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list")]
+    ZeroRank,
+
+    #[error("timeout is {0}")]
+    InvalidTimeout(&'static str),
+}
+
+impl ParseError {
+    /// Error reply sent to the client when the command can't be parsed.
+    pub fn as_datatype(&self) -> DataType {
+        return DataType::from(ReplyError::Err(self.to_string()));
+    }
+}
+
+/// Formats the arguments of an unknown command the way Redis does: each one quoted
+/// and followed by a space, stopping once the text reaches 128 characters.
+fn format_unknown_args(array: &[DataType]) -> String {
+    let mut args = String::new();
+    for item in array.iter().skip(1) {
+        if args.len() >= 128 {
+            break;
+        }
+        if let Some(string) = item.as_bytes() {
+            let arg: String = String::from_utf8_lossy(&string)
+                .chars()
+                .take(128 - args.len())
+                .collect();
+            args.push_str(&format!("'{}' ", arg));
+        }
+    }
+    return args;
+}
+
+/// Collects `array[from..]` as strings.
+fn get_strings(array: &[DataType], from: usize) -> Result<Vec<String>> {
+    let mut strings = Vec::new();
+    for ix in from..array.len() {
+        strings.push(get_string_or_bad_args!(array, ix));
+    }
+    return Ok(strings);
+}
+
+/// Collects `array[from..]` as raw bytes, for keys and other binary arguments.
+fn get_bytes(array: &[DataType], from: usize) -> Result<Vec<Bytes>> {
+    let mut bytes = Vec::new();
+    for ix in from..array.len() {
+        bytes.push(get_bytes_or_bad_args!(array, ix));
+    }
+    return Ok(bytes);
+}
+
+/// Parses the flag options in `array[from..]` case-insensitively, returning them as
+/// spelled in `allowed`. Fails on the first option that isn't allowed.
+fn parse_options(
+    array: &[DataType],
+    from: usize,
+    command: &str,
+    allowed: &[&'static str],
+) -> Result<Vec<&'static str>> {
+    let mut options = Vec::new();
+    for option in get_strings(array, from)? {
+        match allowed
+            .iter()
+            .find(|name| option.eq_ignore_ascii_case(name))
+        {
+            Some(name) => options.push(*name),
+            None => bail!(ParseError::UnsupportedOption(
+                option,
+                command.to_uppercase()
+            )),
+        }
+    }
+    return Ok(options);
+}
+
+fn parse_yes_no(value: &str, yes: &str, no: &str) -> Result<bool> {
+    if value.eq_ignore_ascii_case(yes) {
+        return Ok(true);
+    }
+    if value.eq_ignore_ascii_case(no) {
+        return Ok(false);
+    }
+    bail!(ParseError::BadArguments);
+}
+
+/// Keys found expired by a command, removed from the keyspace while their shard was
+/// locked and freed by `release` once it isn't.
+#[derive(Default)]
+pub struct ExpiredKeys {
+    keys: Vec<Bytes>,
+    values: Vec<DBValue>,
+}
+
+impl ExpiredKeys {
+    /// Removes `key` from `map` if it expired, as commands do before accessing a key.
+    pub fn remove_if_expired(&mut self, map: &mut MapInner, key: &[u8]) {
+        let expired = match map.get(key) {
+            Some(v) if v.is_expired(map.now()) => map.remove(key),
+            _ => None,
+        };
+        if let Some(expired) = expired {
+            self.keys.push(Bytes::copy_from_slice(key));
+            self.values.push(expired);
+        }
+    }
+
+    /// Frees the values of the expired keys, accounting them and notifying the clients
+    /// tracking them. Must be called without holding the config nor any shard lock.
+    pub fn release(self, state: &State) {
+        if self.keys.is_empty() {
+            return;
+        }
+        let lazy = state.config.read().unwrap().lazyfree_lazy_expire;
+        state.stats.record_expired(self.keys.len());
+        for value in self.values {
+            state.lazyfree.free(value, lazy);
+        }
+        tracking::invalidate_keys(state, &self.keys, None);
+    }
+}
+
+/// A parsed command, ready to be executed.
+///
+/// Commands are grouped in a module per family (named after the group of the
+/// command table), each command with its own handler and `parse_*` function, which
+/// the command table uses to build the handler from the arguments of the command.
+/// Arity, flags and key positions are declared in the command table.
+pub trait CommandHandler: Debug + Send {
+    /// Name of the command in the command table.
+    fn name(&self) -> &'static str;
+
+    /// Runs the command on behalf of `client`.
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError>;
+
+    fn spec(&self) -> &'static CommandSpec {
+        return command_table::lookup(self.name()).expect("command missing from command table");
+    }
+
+    /// Keys a blocking command waits on when it responds NullArray, along with how
+    /// long it waits (`None` for ever). See `try_dispatch`.
+    fn blocking(&self) -> Option<(&[Bytes], Option<Duration>)> {
+        return None;
+    }
+}
+
+/// Parses a command by looking up its name in the command table, after applying
+/// the commands renamed by the configuration.
+/// The number of arguments is validated against the command arity before parsing.
+fn parse_array(array: Vec<DataType>, config: &Config) -> Result<Box<dyn CommandHandler>> {
+    let cmd = array.first().ok_or(ParseError::EmptyArray)?;
+    let name = match cmd.as_string() {
+        Some(name) => name,
+        None => bail!(ParseError::InvalidFirstAttribute),
+    };
+    let spec = match config
+        .resolve_command(&name)
+        .and_then(|name| command_table::lookup(&name))
+    {
+        Some(spec) => spec,
+        None => bail!(ParseError::UnkownCommand(name, format_unknown_args(&array))),
+    };
+    if !spec.arity_matches(array.len()) {
+        bail!(ParseError::WrongArity(spec.name.to_string()));
+    }
+    return (spec.parse)(&array);
+}
+
+/// Executes the command, recording its latency in the latency monitor.
+/// Commands that may grow the dataset first evict keys if `maxmemory` is exceeded,
+/// and are refused with an OOM error if not enough memory can be reclaimed.
+pub fn execute(cmd: &dyn CommandHandler, state: &State, client: ClientId) -> DataType {
+    let start = Instant::now();
+    let response = match evict_if_needed(cmd, state) {
+        Ok(()) => {
+            let response = match cmd.run(state, client) {
+                Ok(response) => response,
+                Err(err) => DataType::from(err),
+            };
+            let failed = matches!(response, DataType::Error { .. });
+            let usec = start.elapsed().as_micros() as u64;
+            state.stats.record_call(cmd.name(), usec, failed);
+            response
+        }
+        Err(evict::EvictionError::OutOfMemory) => {
+            state.stats.record_rejected(cmd.name());
+            DataType::from(ReplyError::OutOfMemory)
+        }
+    };
+    let threshold = state.config.read().unwrap().latency_monitor_threshold;
+    state.latency.add_sample_if_needed(
+        latency::EVENT_COMMAND,
+        start.elapsed().as_millis() as u64,
+        threshold,
+    );
+    return response;
+}
+
+fn evict_if_needed(cmd: &dyn CommandHandler, state: &State) -> Result<(), evict::EvictionError> {
+    let config = state.config.read().unwrap();
+    if config.maxmemory == 0 || !cmd.spec().has_flag("denyoom") {
+        return Ok(());
+    }
+    let mut evicted = Vec::new();
+    let res = evict::perform_evictions(&state.databases, &state.lazyfree, &config, &mut evicted);
+    state.stats.record_evicted(evicted.len());
+    tracking::invalidate_keys(state, &evicted, None);
+    return res;
+}
+
+/// Finds the spec of the command invoked by `data`, if any.
+fn command_spec(data: &DataType, config: &Config) -> Option<&'static CommandSpec> {
+    let name = match data {
+        DataType::Array { items } => items.first()?.as_string()?,
+        _ => return None,
+    };
+    return config
+        .resolve_command(&name)
+        .and_then(|name| command_table::lookup(&name));
 }
 
+/// Key arguments of the invocation of the command in `data`.
+fn command_keys(spec: &CommandSpec, data: &DataType) -> Vec<Bytes> {
+    let items = match data {
+        DataType::Array { items } => items,
+        _ => return Vec::new(),
+    };
+    return spec
+        .key_positions(items.len())
+        .into_iter()
+        .filter_map(|pos| items[pos].as_bytes())
+        .collect();
+}
+
+/// Shard of the keyspace holding every key of the command in `data`, `None` for
+/// commands without keys or with keys in several shards.
+pub fn command_shard(data: &DataType, state: &State) -> Option<usize> {
+    let keys = {
+        let config = state.config.read().unwrap();
+        command_keys(command_spec(data, &config)?, data)
+    };
+    let mut shards = keys.iter().map(|key| state.databases[0].shard_of(key));
+    let first = shards.next()?;
+    return shards.all(|shard| shard == first).then_some(first);
+}
+
+/// Replied instead of the response of a command that panicked.
+const INTERNAL_ERROR: &str = "internal error while executing the command";
+
+/// Parses and executes a command received from `client`, accounting it in the
+/// server statistics and notifying the clients tracking the keys it modifies or
+/// blocked on them. Failures, even a panicking command, are replied to the client as
+/// errors. Blocking commands don't block here, see `try_dispatch`.
+pub fn dispatch(data: DataType, state: &State, client: ClientId) -> DataType {
+    return match try_dispatch(data, state, client) {
+        Ok(response) => response,
+        Err(blocked) => blocked.reply,
+    };
+}
+
+/// A blocking command that had nothing to serve, which can be dispatched again once
+/// its keys are written. See `blocked`.
 #[derive(Debug)]
-pub enum Commands {
-    /// PING responds with PONG
-    PING,
-
-    /// COMMAND responds with an Array of available server features.
-    COMMAND,
-
-    /// ECHO responds with the received message as a BulkString.
-    ECHO { message: String },
-
-    /// SET stores 'value' under 'key' in the in-memory database.
-    /// The value can have a optional 'expiry' (PX option).
-    /// If the key is already set, responds with old value as a BulkString.
-    /// Otherwise responds "OK" as a SimpleString.
-    SET {
-        key: String,
-        value: Bytes,
-        expiry: usize,
-    },
-
-    /// GET returns the value of 'key' in the in-memory database as a BulkString .
-    /// If the key is not set or expired, responds with a NullBulkString.
-    GET { key: String },
-}
-
-impl Commands {
-    pub fn from_vec(array: Vec<DataType>) -> Result<Self> {
-        let cmd = array.get(0).ok_or(ParseError::EmptyArray)?;
-
-        return match cmd {
-            DataType::BulkString { string } | DataType::SimpleString { string } => {
-                match string.to_uppercase().as_str() {
-                    "PING" => Ok(Commands::PING),
-                    "COMMAND" => Ok(Commands::COMMAND),
-                    "ECHO" => {
-                        let message = get_string_or_bad_args!(array, 1);
-                        return Ok(Commands::ECHO {
-                            message: message.clone(),
-                        });
-                    }
-                    "SET" => {
-                        let key = get_string_or_bad_args!(array, 1);
-                        let value = get_string_or_bad_args!(array, 2);
-                        let opt: &String;
-                        let mut msdelay: isize = 0;
-                        if array.len() > 4 {
-                            opt = get_string_or_bad_args!(array, 3);
-                            if !opt.to_uppercase().eq("PX") {
-                                bail!(ParseError::UnsupportedOption(
-                                    opt.clone(),
-                                    "SET".to_string()
-                                ))
-                            }
-                            msdelay = get_string_or_bad_args!(array, 4).parse()?;
-                        }
-                        return Ok(Commands::SET {
-                            key: key.clone(),
-                            value: Bytes::from(value.clone()),
-                            expiry: msdelay as usize,
-                        });
+pub struct Blocked {
+    /// the command, to dispatch it again
+    pub data: DataType,
+    pub keys: Vec<Bytes>,
+    /// how long to wait for the keys, `None` for ever
+    pub timeout: Option<Duration>,
+    /// the reply once the client gives up waiting
+    pub reply: DataType,
+}
+
+/// Executes the command in `data` like `dispatch`, unless it's a blocking command
+/// with nothing to serve, for the caller to block the client.
+pub fn try_dispatch(
+    data: DataType,
+    state: &State,
+    client: ClientId,
+) -> Result<DataType, Box<Blocked>> {
+    // the config lock must be released before executing the command
+    let (spec, keys, retry, parsed) = {
+        let config = state.config.read().unwrap();
+        let spec = command_spec(&data, &config);
+        let keys = spec
+            .map(|spec| command_keys(spec, &data))
+            .unwrap_or_default();
+        let retry = spec
+            .filter(|spec| spec.has_flag("blocking"))
+            .map(|_| data.clone());
+        (spec, keys, retry, parse_command(data, &config))
+    };
+    #[cfg(feature = "otel")]
+    let span = state.telemetry.get().map(|telemetry| {
+        let name = spec.map_or("unknown", |spec| spec.name);
+        telemetry.command_span(name, keys.len(), state.selected_db(client), client)
+    });
+    let mut blocked = None;
+    let response = match parsed {
+        Ok(cmd) if !allowed_when_subscribed(cmd.name()) && in_subscribed_mode(state, client) => {
+            DataType::from(ReplyError::Err(format!(
+                "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                cmd.name()
+            )))
+        }
+        Ok(cmd) => {
+            let executed =
+                panic::catch_unwind(AssertUnwindSafe(|| execute(cmd.as_ref(), state, client)));
+            let response = match executed {
+                Ok(response) => response,
+                Err(_) => {
+                    eprintln!("command panicked: {:?}", cmd);
+                    DataType::from(ReplyError::Err(String::from(INTERNAL_ERROR)))
+                }
+            };
+            match (cmd.blocking(), retry) {
+                (Some((keys, timeout)), Some(data)) if response == DataType::NullArray => {
+                    blocked = Some((data, keys.to_vec(), timeout));
+                }
+                _ if matches!(response, DataType::Error { .. }) => {}
+                _ => {
+                    if cmd.spec().has_flag("readonly") {
+                        tracking::track_keys(state, client, &keys);
                     }
-                    "GET" => {
-                        let key = get_string_or_bad_args!(array, 1);
-                        return Ok(Commands::GET { key: key.clone() });
+                    if cmd.spec().has_flag("write") {
+                        tracking::invalidate_keys(state, &keys, Some(client));
+                        blocked::signal_keys(state, client, &keys);
                     }
-                    _ => bail!(ParseError::UnkownCommand(string.clone())),
                 }
             }
-            _ => bail!(ParseError::InvalidFirstAttribute),
-        };
-    }
-
-    pub fn execute(&self, map: Map) -> Result<DataType> {
-        let response = match self {
-            Commands::PING => DataType::SimpleString {
-                string: "PONG".to_string(),
-            },
-            Commands::COMMAND => DataType::SimpleString {
-                string: "".to_string(),
-            },
-            Commands::ECHO { message } => DataType::BulkString {
-                string: message.clone(),
-            },
-            Commands::SET { key, value, expiry } => {
-                let mut map = map.lock().unwrap();
-                let new_value = DBValue::with_expiration(value.clone(), *expiry);
-                let old_value = map.insert(key.clone(), new_value);
-                let resp = match old_value {
-                    Some(v) if !v.is_expired() => DataType::BulkString {
-                        string: String::from_utf8(v.value.to_vec())?,
-                    },
-                    _ => DataType::SimpleString {
-                        string: String::from("OK"),
-                    },
-                };
-                resp
+            // CLIENT CACHING only applies to the command right after it
+            if cmd.name() != "client" {
+                state.clients.with_client(client, |c| c.caching = None);
             }
-            Commands::GET { key } => {
-                let map = map.lock().unwrap();
-                match map.get(key) {
-                    Some(v) if !v.is_expired() => DataType::BulkString {
-                        string: String::from_utf8(v.value.to_vec())?,
-                    },
-                    _ => DataType::NullBulkString {},
+            response
+        }
+        Err(err) => match err.downcast_ref::<ParseError>() {
+            Some(parse_err) => {
+                match (spec, parse_err) {
+                    (Some(spec), ParseError::WrongArity(_)) => {
+                        state.stats.record_rejected(spec.name)
+                    }
+                    (Some(spec), _) => state.stats.record_failed(spec.name),
+                    (None, _) => {}
                 }
+                parse_err.as_datatype()
             }
-        };
-        return Ok(response);
+            None => DataType::from(ReplyError::Err(err.to_string())),
+        },
+    };
+    if let DataType::Error { type_, .. } = &response {
+        state.stats.record_error(type_);
+    }
+    #[cfg(feature = "otel")]
+    if let Some(span) = span {
+        telemetry::end_command_span(span, &response, blocked.is_some());
     }
+    let protocol = state
+        .clients
+        .with_client(client, |c| c.protocol)
+        .unwrap_or(2);
+    let response = response.for_protocol(protocol);
+    return match blocked {
+        Some((data, keys, timeout)) => Err(Box::new(Blocked {
+            data: data,
+            keys: keys,
+            timeout: timeout,
+            reply: response,
+        })),
+        None => Ok(response),
+    };
 }
 
-pub fn parse_command(data: DataType) -> Result<Commands> {
+/// true if `client` speaks RESP2 and subscribed to a channel or pattern, after
+/// which its messages could be taken for replies to most commands.
+fn in_subscribed_mode(state: &State, client: ClientId) -> bool {
+    return state
+        .clients
+        .with_client(client, |c| c.protocol < 3 && c.subscriptions > 0)
+        .unwrap_or(false);
+}
+
+/// Commands RESP2 clients can run in subscribed mode.
+fn allowed_when_subscribed(name: &str) -> bool {
+    return matches!(
+        name,
+        "subscribe" | "psubscribe" | "unsubscribe" | "punsubscribe" | "ping" | "quit" | "reset"
+    );
+}
+
+pub fn parse_command(data: DataType, config: &Config) -> Result<Box<dyn CommandHandler>> {
     let cmd = match data {
-        DataType::Array { items } => Commands::from_vec(items)?,
+        DataType::Array { items } => parse_array(items, config)?,
         _ => bail!(ParseError::InvalidCommandDataType),
     };
     return Ok(cmd);
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{dispatch, parse_command, parse_options, ParseError};
+    use crate::clients::ClientId;
+    use crate::config::Config;
+    use crate::db::DBValue;
+    use crate::errors::ReplyError;
+    use crate::protocol::DataType;
+    use crate::state::StateInner;
+    use crate::value::{List, Value};
+
+    fn command(args: &[&str]) -> DataType {
+        return DataType::Array {
+            items: args.iter().map(|arg| DataType::from(*arg)).collect(),
+        };
+    }
+
+    fn parse_error(args: &[&str]) -> String {
+        let err = parse_command(command(args), &Config::default()).unwrap_err();
+        return err.downcast_ref::<ParseError>().unwrap().to_string();
+    }
+
+    #[test]
+    fn test_wrong_arity() {
+        assert_eq!(
+            parse_error(&["GET"]),
+            "wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            parse_error(&["get", "a", "b"]),
+            "wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            parse_error(&["SET", "a"]),
+            "wrong number of arguments for 'set' command"
+        );
+        assert!(parse_command(command(&["SET", "a", "b"]), &Config::default()).is_ok());
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        assert_eq!(
+            parse_error(&["FOO", "a", "b"]),
+            "unknown command 'FOO', with args beginning with: 'a' 'b' "
+        );
+        assert_eq!(
+            parse_error(&["foo"]),
+            "unknown command 'foo', with args beginning with: "
+        );
+        let long = "x".repeat(200);
+        let message = parse_error(&["foo", &long, "b"]);
+        assert_eq!(
+            message,
+            format!(
+                "unknown command 'foo', with args beginning with: '{}' ",
+                "x".repeat(128)
+            )
+        );
+    }
+
+    #[test]
+    fn test_renamed_commands() {
+        let mut config = Config::default();
+        config.rename_command("config", "cfg").unwrap();
+        config.rename_command("Latency", "").unwrap();
+        let unknown = |args: &[&str]| {
+            let err = parse_command(command(args), &config).unwrap_err();
+            return matches!(
+                err.downcast_ref::<ParseError>(),
+                Some(ParseError::UnkownCommand(..))
+            );
+        };
+        assert!(unknown(&["CONFIG", "GET", "*"]));
+        assert!(unknown(&["LATENCY", "LATEST"]));
+        assert!(unknown(&["", "LATEST"]));
+        assert!(parse_command(command(&["CFG", "GET", "*"]), &config).is_ok());
+        assert!(parse_command(command(&["GET", "a"]), &config).is_ok());
+    }
+
+    #[test]
+    fn test_nil_reply_by_protocol() {
+        let state = StateInner::new(Config::default());
+        let client = state.connect_client().unwrap();
+        let reply = dispatch(command(&["GET", "missing"]), &state, client.id);
+        assert_eq!(reply, DataType::NullBulkString);
+
+        state.clients.with_client(client.id, |c| c.protocol = 3);
+        let reply = dispatch(command(&["GET", "missing"]), &state, client.id);
+        assert_eq!(reply, DataType::Null);
+        assert_eq!(reply.encode().unwrap(), b"_\r\n");
+    }
+
+    #[test]
+    fn test_structured_replies_by_protocol() {
+        let state = StateInner::new(Config::default());
+        let client = state.connect_client().unwrap();
+        let reply = dispatch(command(&["CONFIG", "GET", "port"]), &state, client.id);
+        assert_eq!(
+            reply.encode().unwrap(),
+            b"*2\r\n$4\r\nport\r\n$4\r\n6379\r\n"
+        );
+
+        state.clients.with_client(client.id, |c| c.protocol = 3);
+        let reply = dispatch(command(&["CONFIG", "GET", "port"]), &state, client.id);
+        assert_eq!(
+            reply.encode().unwrap(),
+            b"%1\r\n$4\r\nport\r\n$4\r\n6379\r\n"
+        );
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let state = StateInner::new(Config::default());
+        let client = state.connect_client().unwrap();
+        let value = Bytes::from_static(b"\x00\xff\r\n\xc3");
+        let set = DataType::Array {
+            items: vec![
+                DataType::bulk("SET"),
+                DataType::bulk("key"),
+                DataType::bulk(value.clone()),
+            ],
+        };
+        dispatch(set, &state, client.id);
+        let reply = dispatch(command(&["GET", "key"]), &state, client.id);
+        assert_eq!(reply, DataType::bulk(value));
+        assert_eq!(reply.encode().unwrap(), b"$5\r\n\x00\xff\r\n\xc3\r\n");
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let state = StateInner::new(Config::default());
+        let client = state.connect_client().unwrap();
+        let list = Value::List(List::Quicklist([Bytes::from("a")].into()));
+        state
+            .keyspace(0)
+            .lock(b"list")
+            .insert(Bytes::from("list"), DBValue::with_expiration(list, 0, 0));
+        let reply = dispatch(command(&["GET", "list"]), &state, client.id);
+        assert_eq!(reply, DataType::from(ReplyError::WrongType));
+
+        // SET replaces values of any type
+        let reply = dispatch(command(&["SET", "list", "v"]), &state, client.id);
+        assert_eq!(reply, DataType::ok());
+        let reply = dispatch(command(&["GET", "list"]), &state, client.id);
+        assert_eq!(reply, DataType::bulk("v"));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let array = match command(&["EXPIRE", "key", "1", "xx", "GT"]) {
+            DataType::Array { items } => items,
+            _ => unreachable!(),
+        };
+        let options = parse_options(&array, 3, "expire", &["NX", "XX", "GT", "LT"]).unwrap();
+        assert_eq!(options, vec!["XX", "GT"]);
+        let err = parse_options(&array, 3, "expire", &["NX", "XX"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>().unwrap().to_string(),
+            "Option 'GT' for EXPIRE not supported"
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        let state = StateInner::new(Config::default());
+        let client = state.connect_client().unwrap();
+        let other = state.connect_client().unwrap();
+        let run = |client: ClientId, args: &[&str]| dispatch(command(args), &state, client);
+        assert_eq!(run(client.id, &["SET", "key", "zero"]), DataType::ok());
+        assert_eq!(run(client.id, &["SELECT", "15"]), DataType::ok());
+        assert_eq!(run(client.id, &["GET", "key"]), DataType::NullBulkString);
+        assert_eq!(run(client.id, &["SET", "key", "fifteen"]), DataType::ok());
+        assert_eq!(run(client.id, &["DBSIZE"]), DataType::Integer { number: 1 });
+        assert_eq!(run(other.id, &["GET", "key"]), DataType::bulk("zero"));
+        assert_eq!(run(client.id, &["SELECT", "0"]), DataType::ok());
+        assert_eq!(run(client.id, &["GET", "key"]), DataType::bulk("zero"));
+        assert_eq!(state.keyspace(other.id).len(), 1);
+        assert_eq!(state.databases[15].len(), 1);
+
+        let info = run(client.id, &["INFO", "keyspace"]);
+        let info = String::from_utf8(info.encode().unwrap()).unwrap();
+        assert!(info.contains("db0:keys=1,expires=0,avg_ttl=0\r\n"));
+        assert!(info.contains("db15:keys=1,expires=0,avg_ttl=0\r\n"));
+
+        assert_eq!(
+            run(client.id, &["SELECT", "16"]),
+            DataType::from(ReplyError::Err("DB index is out of range".to_string()))
+        );
+        assert_eq!(
+            run(client.id, &["SELECT", "-1"]),
+            DataType::from(ReplyError::Err("DB index is out of range".to_string()))
+        );
+        assert_eq!(
+            run(client.id, &["SELECT", "one"]),
+            DataType::from(ReplyError::NotAnInteger)
+        );
+    }
+
+    #[test]
+    fn test_flush_databases() {
+        let state = StateInner::new(Config::default());
+        let client = state.connect_client().unwrap();
+        let run = |args: &[&str]| dispatch(command(args), &state, client.id);
+        run(&["SET", "a", "value"]);
+        run(&["SELECT", "1"]);
+        run(&["SET", "b", "value"]);
+        assert_eq!(run(&["FLUSHDB"]), DataType::ok());
+        assert_eq!(state.databases[0].len(), 1);
+        assert_eq!(state.databases[1].len(), 0);
+        run(&["SET", "b", "value"]);
+        assert_eq!(run(&["FLUSHALL"]), DataType::ok());
+        assert_eq!(state.databases[0].len(), 0);
+        assert_eq!(state.databases[1].len(), 0);
+    }
+
+    #[test]
+    fn test_move_swapdb() {
+        let state = StateInner::new(Config::default());
+        let client = state.connect_client().unwrap();
+        let other = state.connect_client().unwrap();
+        let run = |client: ClientId, args: &[&str]| dispatch(command(args), &state, client);
+        let error = |message: &str| DataType::from(ReplyError::Err(message.to_string()));
+        run(client.id, &["SET", "a", "zero"]);
+        run(client.id, &["SET", "b", "zero"]);
+        run(other.id, &["SELECT", "1"]);
+        run(other.id, &["SET", "b", "one"]);
+
+        assert_eq!(
+            run(client.id, &["MOVE", "a", "1"]),
+            DataType::Integer { number: 1 }
+        );
+        assert_eq!(
+            run(client.id, &["MOVE", "a", "1"]),
+            DataType::Integer { number: 0 }
+        );
+        assert_eq!(
+            run(client.id, &["MOVE", "b", "1"]),
+            DataType::Integer { number: 0 }
+        );
+        assert_eq!(run(other.id, &["GET", "a"]), DataType::bulk("zero"));
+        assert_eq!(run(client.id, &["GET", "b"]), DataType::bulk("zero"));
+        assert_eq!(
+            run(client.id, &["MOVE", "b", "0"]),
+            error("source and destination objects are the same")
+        );
+        assert_eq!(
+            run(client.id, &["MOVE", "b", "16"]),
+            error("DB index is out of range")
+        );
+
+        assert_eq!(run(client.id, &["SWAPDB", "1", "0"]), DataType::ok());
+        assert_eq!(run(client.id, &["GET", "a"]), DataType::bulk("zero"));
+        assert_eq!(run(client.id, &["GET", "b"]), DataType::bulk("one"));
+        assert_eq!(run(other.id, &["GET", "b"]), DataType::bulk("zero"));
+        assert_eq!(run(client.id, &["SWAPDB", "2", "2"]), DataType::ok());
+        assert_eq!(
+            run(client.id, &["SWAPDB", "0", "16"]),
+            error("DB index is out of range")
+        );
+        assert_eq!(
+            run(client.id, &["SWAPDB", "0", "x"]),
+            error("invalid second DB index")
+        );
+    }
+}
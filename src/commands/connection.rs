@@ -0,0 +1,396 @@
+/// Commands inspecting and configuring the client connection.
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use super::{get_strings, parse_yes_no, CommandHandler, ParseError};
+use crate::clients::{ClientId, TrackingOptions};
+use crate::errors::ReplyError;
+use crate::protocol::DataType;
+use crate::state::State;
+use crate::tracking;
+
+/// PING responds with PONG, or with the Array ["pong", ""] to RESP2 clients in
+/// subscribed mode, so it can't be taken for a message.
+#[derive(Debug)]
+pub struct Ping;
+
+pub fn parse_ping(_array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return Ok(Box::new(Ping));
+}
+
+impl CommandHandler for Ping {
+    fn name(&self) -> &'static str {
+        return "ping";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let subscribed = state
+            .clients
+            .with_client(client, |c| c.protocol < 3 && c.subscriptions > 0);
+        if subscribed == Some(true) {
+            return Ok(DataType::Array {
+                items: vec![DataType::bulk("pong"), DataType::bulk("")],
+            });
+        }
+        return Ok(DataType::SimpleString {
+            string: "PONG".to_string(),
+        });
+    }
+}
+
+/// ECHO responds with the received message as a BulkString.
+#[derive(Debug)]
+pub struct Echo {
+    message: Bytes,
+}
+
+pub fn parse_echo(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let message = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(Echo { message: message }));
+}
+
+impl CommandHandler for Echo {
+    fn name(&self) -> &'static str {
+        return "echo";
+    }
+
+    fn run(&self, _state: &State, _client: ClientId) -> Result<DataType, ReplyError> {
+        return Ok(DataType::bulk(self.message.clone()));
+    }
+}
+
+/// SELECT changes the database the commands of the connection work on, responds "OK".
+#[derive(Debug)]
+pub struct Select {
+    index: i64,
+}
+
+pub fn parse_select(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let index = get_string_or_bad_args!(array, 1)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    return Ok(Box::new(Select { index: index }));
+}
+
+impl CommandHandler for Select {
+    fn name(&self) -> &'static str {
+        return "select";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        if self.index < 0 || self.index as usize >= state.databases.len() {
+            return Err(ReplyError::Err("DB index is out of range".to_string()));
+        }
+        state
+            .clients
+            .with_client(client, |c| c.db = self.index as usize);
+        return Ok(DataType::ok());
+    }
+}
+
+/// HELLO [protover [AUTH username password] [SETNAME clientname]] switches the
+/// connection to RESP `protover` (2 or 3) and responds with a Map describing the server
+/// and the connection. Without `protover` the protocol isn't changed. There are no
+/// users besides `default`, which accepts any password.
+#[derive(Debug)]
+pub struct Hello {
+    protocol: Option<i64>,
+    auth: Option<(String, String)>,
+    name: Option<String>,
+}
+
+pub fn parse_hello(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let args = get_strings(array, 1)?;
+    let mut args = args.into_iter();
+    let protocol = match args.next() {
+        Some(protocol) => Some(protocol.parse().map_err(|_| ParseError::NotAnInteger)?),
+        None => None,
+    };
+    let mut hello = Hello {
+        protocol: protocol,
+        auth: None,
+        name: None,
+    };
+    while let Some(option) = args.next() {
+        match option.to_uppercase().as_str() {
+            "AUTH" => {
+                let (user, pass) = match (args.next(), args.next()) {
+                    (Some(user), Some(pass)) => (user, pass),
+                    _ => bail!(ParseError::BadArguments),
+                };
+                hello.auth = Some((user, pass));
+            }
+            "SETNAME" => hello.name = Some(args.next().ok_or(ParseError::BadArguments)?),
+            _ => bail!(ParseError::BadArguments),
+        }
+    }
+    return Ok(Box::new(hello));
+}
+
+impl CommandHandler for Hello {
+    fn name(&self) -> &'static str {
+        return "hello";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        if let Some(protocol) = self.protocol {
+            if !(2..=3).contains(&protocol) {
+                return Err(ReplyError::NoProto);
+            }
+        }
+        if let Some((user, _)) = &self.auth {
+            if user != "default" {
+                return Err(ReplyError::WrongPass);
+            }
+        }
+        if let Some(name) = &self.name {
+            check_client_name(name)?;
+        }
+        let protocol = state.clients.with_client(client, |c| {
+            if let Some(protocol) = self.protocol {
+                c.protocol = protocol as u8;
+            }
+            if let Some(name) = &self.name {
+                c.name = Some(name.clone());
+            }
+            return c.protocol;
+        });
+        let field = |name: &str, value: DataType| (DataType::bulk(name.to_string()), value);
+        return Ok(DataType::Map {
+            items: vec![
+                field("server", DataType::bulk("redis")),
+                field("version", DataType::bulk(env!("CARGO_PKG_VERSION"))),
+                field(
+                    "proto",
+                    DataType::Integer {
+                        number: protocol.unwrap_or(2) as isize,
+                    },
+                ),
+                field(
+                    "id",
+                    DataType::Integer {
+                        number: client as isize,
+                    },
+                ),
+                field("mode", DataType::bulk("standalone")),
+                field("role", DataType::bulk("master")),
+                field("modules", DataType::Array { items: Vec::new() }),
+            ],
+        });
+    }
+}
+
+/// Client names can't have spaces, newlines nor other special characters.
+fn check_client_name(name: &str) -> Result<(), ReplyError> {
+    if name.bytes().any(|b| !(b'!'..=b'~').contains(&b)) {
+        return Err(ReplyError::Err(String::from(
+            "Client names cannot contain spaces, newlines or special characters.",
+        )));
+    }
+    return Ok(());
+}
+
+#[derive(Debug)]
+pub enum ClientSubcommand {
+    Id,
+    Tracking {
+        enabled: bool,
+        options: TrackingOptions,
+    },
+    Caching {
+        enabled: bool,
+    },
+    GetRedir,
+    SetName {
+        name: String,
+    },
+    GetName,
+    SetInfo {
+        lib_name: bool,
+        value: String,
+    },
+    Info,
+}
+
+/// CLIENT inspects and configures the current connection.
+/// - CLIENT ID: id of the connection as an Integer.
+/// - CLIENT TRACKING ON|OFF [REDIRECT id] [PREFIX prefix ...] [BCAST] [OPTIN] [OPTOUT] [NOLOOP]:
+///   enables or disables key invalidation messages, responds "OK".
+/// - CLIENT CACHING YES|NO: whether the keys read by the next command are tracked
+///   in OPTIN/OPTOUT mode, responds "OK".
+/// - CLIENT GETREDIR: id of the client receiving the invalidation messages, 0 if
+///   they aren't redirected and -1 if tracking is disabled.
+/// - CLIENT SETNAME <name>: names the connection, an empty name removes it. Responds "OK".
+/// - CLIENT GETNAME: name of the connection as a BulkString, NullBulkString if unnamed.
+/// - CLIENT SETINFO LIB-NAME|LIB-VER <value>: reports the client library and its
+///   version, as shown by CLIENT INFO. Responds "OK".
+/// - CLIENT INFO: properties of the connection as a BulkString of `field=value`
+///   pairs separated by spaces, ending with a newline.
+#[derive(Debug)]
+pub struct Client {
+    subcommand: ClientSubcommand,
+}
+
+pub fn parse_client(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let sub = get_string_or_bad_args!(array, 1);
+    let args = get_strings(array, 2)?;
+    let subcommand = match sub.to_uppercase().as_str() {
+        "ID" if args.is_empty() => ClientSubcommand::Id,
+        "GETREDIR" if args.is_empty() => ClientSubcommand::GetRedir,
+        "GETNAME" if args.is_empty() => ClientSubcommand::GetName,
+        "INFO" if args.is_empty() => ClientSubcommand::Info,
+        "SETNAME" if args.len() == 1 => ClientSubcommand::SetName {
+            name: args[0].clone(),
+        },
+        "SETINFO" if args.len() == 2 => ClientSubcommand::SetInfo {
+            lib_name: parse_yes_no(&args[0], "lib-name", "lib-ver")?,
+            value: args[1].clone(),
+        },
+        "CACHING" if args.len() == 1 => ClientSubcommand::Caching {
+            enabled: parse_yes_no(&args[0], "yes", "no")?,
+        },
+        "TRACKING" if !args.is_empty() => {
+            let enabled = parse_yes_no(&args[0], "on", "off")?;
+            let mut options = TrackingOptions::default();
+            let mut args = args[1..].iter();
+            while let Some(option) = args.next() {
+                match option.to_uppercase().as_str() {
+                    "REDIRECT" => {
+                        let id = args.next().ok_or(ParseError::BadArguments)?;
+                        options.redirect = Some(id.parse().map_err(|_| ParseError::NotAnInteger)?);
+                    }
+                    "PREFIX" => {
+                        let prefix = args.next().ok_or(ParseError::BadArguments)?;
+                        options.prefixes.push(prefix.clone());
+                    }
+                    "BCAST" => options.bcast = true,
+                    "OPTIN" => options.optin = true,
+                    "OPTOUT" => options.optout = true,
+                    "NOLOOP" => options.noloop = true,
+                    _ => bail!(ParseError::BadArguments),
+                }
+            }
+            ClientSubcommand::Tracking { enabled, options }
+        }
+        "ID" | "GETREDIR" | "CACHING" | "TRACKING" | "GETNAME" | "INFO" | "SETNAME" | "SETINFO" => {
+            bail!(ParseError::BadArguments)
+        }
+        _ => bail!(ParseError::UnknownSubcommand(
+            "CLIENT".to_string(),
+            sub.clone()
+        )),
+    };
+    return Ok(Box::new(Client { subcommand }));
+}
+
+impl CommandHandler for Client {
+    fn name(&self) -> &'static str {
+        return "client";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let error = |message: &str| Err(ReplyError::Err(message.to_string()));
+        let ok = DataType::ok();
+        let response = match &self.subcommand {
+            ClientSubcommand::Id => DataType::Integer {
+                number: client as isize,
+            },
+            ClientSubcommand::Tracking { enabled: false, .. } => {
+                tracking::set_tracking(state, client, None);
+                ok
+            }
+            ClientSubcommand::Tracking {
+                enabled: true,
+                options,
+            } => {
+                if !options.bcast && !options.prefixes.is_empty() {
+                    return error("PREFIX option requires BCAST mode to be enabled");
+                }
+                if options.optin && options.optout {
+                    return error("You can't use OPTIN and OPTOUT at the same time");
+                }
+                if options.bcast && (options.optin || options.optout) {
+                    return error("OPTIN and OPTOUT are not compatible with BCAST");
+                }
+                if let Some(redirect) = options.redirect {
+                    if !state.clients.contains(redirect) {
+                        return error("The client ID you want redirect to does not exist");
+                    }
+                }
+                tracking::set_tracking(state, client, Some(options.clone()));
+                ok
+            }
+            ClientSubcommand::Caching { enabled } => {
+                let res = state.clients.with_client(client, |c| {
+                    let options = match &c.tracking {
+                        Some(options) if options.optin || options.optout => options,
+                        _ => return Err("CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled"),
+                    };
+                    if *enabled && !options.optin {
+                        return Err("CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.");
+                    }
+                    if !*enabled && !options.optout {
+                        return Err("CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.");
+                    }
+                    c.caching = Some(*enabled);
+                    return Ok(());
+                });
+                match res {
+                    Some(Err(message)) => return error(message),
+                    _ => ok,
+                }
+            }
+            ClientSubcommand::GetRedir => {
+                let redirect = state.clients.with_client(client, |c| match &c.tracking {
+                    Some(options) => options.redirect.unwrap_or(0) as isize,
+                    None => -1,
+                });
+                DataType::Integer {
+                    number: redirect.unwrap_or(-1),
+                }
+            }
+            ClientSubcommand::SetName { name } => {
+                check_client_name(name)?;
+                state.clients.with_client(client, |c| {
+                    c.name = Some(name.clone()).filter(|name| !name.is_empty());
+                });
+                ok
+            }
+            ClientSubcommand::GetName => {
+                match state.clients.with_client(client, |c| c.name.clone()) {
+                    Some(Some(name)) => DataType::bulk(name),
+                    _ => DataType::NullBulkString,
+                }
+            }
+            ClientSubcommand::SetInfo { lib_name, value } => {
+                if value.bytes().any(|b| !(b'!'..=b'~').contains(&b)) {
+                    return error(&format!(
+                        "{} cannot contain spaces, newlines or special characters.",
+                        if *lib_name { "lib-name" } else { "lib-ver" }
+                    ));
+                }
+                state.clients.with_client(client, |c| match lib_name {
+                    true => c.lib_name = Some(value.clone()),
+                    false => c.lib_ver = Some(value.clone()),
+                });
+                ok
+            }
+            ClientSubcommand::Info => {
+                let info = state.clients.with_client(client, |c| {
+                    let field = |value: &Option<String>| value.clone().unwrap_or_default();
+                    format!(
+                        "id={} name={} db={} resp={} lib-name={} lib-ver={}\n",
+                        c.id,
+                        field(&c.name),
+                        c.db,
+                        c.protocol,
+                        field(&c.lib_name),
+                        field(&c.lib_ver)
+                    )
+                });
+                DataType::bulk(info.unwrap_or_default())
+            }
+        };
+        return Ok(response);
+    }
+}
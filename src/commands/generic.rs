@@ -0,0 +1,1720 @@
+/// Commands operating on keys of any type.
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use super::{get_bytes, parse_options, CommandHandler, ExpiredKeys, ParseError};
+use crate::blocked;
+use crate::clients::ClientId;
+use crate::config::Config;
+use crate::db::{DBValue, ShardGuards};
+use crate::dump;
+use crate::errors::ReplyError;
+use crate::glob;
+use crate::protocol::DataType;
+use crate::state::State;
+use crate::tracking;
+use crate::value::{List, Value};
+
+/// Entries visited by KEYS each time it locks a shard.
+const KEYS_BATCH: usize = 1024;
+
+/// DEL and UNLINK remove the given keys, responding with the number of keys removed
+/// as an Integer. The values removed by DEL are freed in the background with
+/// `lazyfree-lazy-user-del`, the ones removed by UNLINK always.
+#[derive(Debug)]
+pub struct Del {
+    name: &'static str,
+    keys: Vec<Bytes>,
+    /// None follows `lazyfree-lazy-user-del`
+    lazy: Option<bool>,
+}
+
+pub fn parse_del(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_del_generic(array, "del", None);
+}
+
+pub fn parse_unlink(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_del_generic(array, "unlink", Some(true));
+}
+
+fn parse_del_generic(
+    array: &[DataType],
+    name: &'static str,
+    lazy: Option<bool>,
+) -> Result<Box<dyn CommandHandler>> {
+    let keys = get_bytes(array, 1)?;
+    return Ok(Box::new(Del {
+        name: name,
+        keys: keys,
+        lazy: lazy,
+    }));
+}
+
+impl CommandHandler for Del {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let mut removed: Vec<DBValue> = Vec::new();
+        {
+            let mut maps = keyspace.lock_keys(&self.keys);
+            for key in &self.keys {
+                let map = maps.get(key);
+                expired.remove_if_expired(map, key);
+                removed.extend(map.remove(key));
+            }
+        }
+        expired.release(state);
+        let response = DataType::Integer {
+            number: removed.len() as isize,
+        };
+        let lazy = self
+            .lazy
+            .unwrap_or_else(|| state.config.read().unwrap().lazyfree_lazy_user_del);
+        for value in removed {
+            state.lazyfree.free(value, lazy);
+        }
+        return Ok(response);
+    }
+}
+
+/// EXISTS responds with the number of the given keys that exist as an Integer. Keys
+/// given more than once are counted every time.
+#[derive(Debug)]
+pub struct Exists {
+    keys: Vec<Bytes>,
+}
+
+pub fn parse_exists(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let keys = get_bytes(array, 1)?;
+    return Ok(Box::new(Exists { keys: keys }));
+}
+
+impl CommandHandler for Exists {
+    fn name(&self) -> &'static str {
+        return "exists";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let mut found = 0;
+        {
+            let mut maps = keyspace.lock_keys(&self.keys);
+            for key in &self.keys {
+                let map = maps.get(key);
+                expired.remove_if_expired(map, key);
+                let exists = map.get(key).is_some();
+                state.stats.record_keyspace_lookup(exists);
+                found += exists as isize;
+            }
+        }
+        expired.release(state);
+        return Ok(DataType::Integer { number: found });
+    }
+}
+
+/// TOUCH marks the given keys as accessed, for the eviction policies, responding
+/// with the number of them that exist as an Integer.
+#[derive(Debug)]
+pub struct Touch {
+    keys: Vec<Bytes>,
+}
+
+pub fn parse_touch(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let keys = get_bytes(array, 1)?;
+    return Ok(Box::new(Touch { keys: keys }));
+}
+
+impl CommandHandler for Touch {
+    fn name(&self) -> &'static str {
+        return "touch";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let mut found = 0;
+        {
+            let config = state.config.read().unwrap();
+            let mut maps = keyspace.lock_keys(&self.keys);
+            for key in &self.keys {
+                let map = maps.get(key);
+                expired.remove_if_expired(map, key);
+                let exists = map.lookup(key, &config).is_some();
+                state.stats.record_keyspace_lookup(exists);
+                found += exists as isize;
+            }
+        }
+        expired.release(state);
+        return Ok(DataType::Integer { number: found });
+    }
+}
+
+/// TYPE responds with the type of the value stored at 'key' as a SimpleString:
+/// string, list, set, zset, hash or stream, and none if the key doesn't exist.
+#[derive(Debug)]
+pub struct Type {
+    key: Bytes,
+}
+
+pub fn parse_type(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(Type { key: key }));
+}
+
+impl CommandHandler for Type {
+    fn name(&self) -> &'static str {
+        return "type";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let type_name = {
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            map.get(&self.key).map(|v| v.value.type_name())
+        };
+        expired.release(state);
+        state.stats.record_keyspace_lookup(type_name.is_some());
+        return Ok(DataType::SimpleString {
+            string: type_name.unwrap_or("none").to_string(),
+        });
+    }
+}
+
+/// EXPIRE and PEXPIRE set a timeout on 'key', in seconds and milliseconds, after
+/// which it is deleted. EXPIREAT and PEXPIREAT set the unix time at which it is
+/// deleted instead. Timeouts in the past delete the key right away.
+/// The NX, XX, GT and LT options only set the timeout if the key has no timeout,
+/// has one, or the new one is greater or less than the current one. Keys without a
+/// timeout count as having an infinite one.
+/// Responds with 1 as an Integer if the timeout was set, 0 if the key doesn't exist
+/// or the options prevented it.
+#[derive(Debug)]
+pub struct Expire {
+    name: &'static str,
+    key: Bytes,
+    timeout: i64,
+    /// milliseconds per unit of `timeout`
+    unit: i64,
+    /// `timeout` is a unix time rather than relative to now
+    absolute: bool,
+    conditions: ExpireConditions,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ExpireConditions {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+}
+
+impl ExpireConditions {
+    /// Whether `expiration` may replace `current`, 0 being no expiration.
+    fn allow(&self, current: usize, expiration: usize) -> bool {
+        let volatile = current != 0;
+        if (self.nx && volatile) || (self.xx && !volatile) {
+            return false;
+        }
+        if self.gt && (!volatile || expiration <= current) {
+            return false;
+        }
+        if self.lt && volatile && expiration >= current {
+            return false;
+        }
+        return true;
+    }
+}
+
+pub fn parse_expire(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_expire_generic(array, "expire", 1000, false);
+}
+
+pub fn parse_pexpire(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_expire_generic(array, "pexpire", 1, false);
+}
+
+pub fn parse_expireat(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_expire_generic(array, "expireat", 1000, true);
+}
+
+pub fn parse_pexpireat(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_expire_generic(array, "pexpireat", 1, true);
+}
+
+fn parse_expire_generic(
+    array: &[DataType],
+    name: &'static str,
+    unit: i64,
+    absolute: bool,
+) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let timeout = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    let options = parse_options(array, 3, name, &["NX", "XX", "GT", "LT"])?;
+    let conditions = ExpireConditions {
+        nx: options.contains(&"NX"),
+        xx: options.contains(&"XX"),
+        gt: options.contains(&"GT"),
+        lt: options.contains(&"LT"),
+    };
+    if conditions.nx && (conditions.xx || conditions.gt || conditions.lt) {
+        bail!(ParseError::IncompatibleOptions("NX and XX, GT or LT"));
+    }
+    if conditions.gt && conditions.lt {
+        bail!(ParseError::IncompatibleOptions("GT and LT"));
+    }
+    return Ok(Box::new(Expire {
+        name: name,
+        key: key,
+        timeout: timeout,
+        unit: unit,
+        absolute: absolute,
+        conditions: conditions,
+    }));
+}
+
+impl CommandHandler for Expire {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let base = match self.absolute {
+            true => 0,
+            false => keyspace.now() as i64,
+        };
+        let expiration = self
+            .timeout
+            .checked_mul(self.unit)
+            .and_then(|timeout| timeout.checked_add(base))
+            .ok_or_else(|| {
+                ReplyError::Err(format!("invalid expire time in '{}' command", self.name))
+            })?;
+        let mut expired = ExpiredKeys::default();
+        // 0 means no expiration, keys expiring in the past are removed as expired
+        let expiration = expiration.max(1) as usize;
+        let set = {
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            let set = match map.get(&self.key) {
+                Some(v) if self.conditions.allow(v.expiration, expiration) => {
+                    map.set_expiration(&self.key, expiration)
+                }
+                _ => false,
+            };
+            expired.remove_if_expired(&mut map, &self.key);
+            set
+        };
+        expired.release(state);
+        return Ok(DataType::Integer {
+            number: set as isize,
+        });
+    }
+}
+
+/// TTL and PTTL respond with the time left until 'key' expires as an Integer, in
+/// seconds and milliseconds. EXPIRETIME and PEXPIRETIME respond with the unix time
+/// at which it expires instead. Responds with -1 if the key has no expiration and -2
+/// if it doesn't exist.
+#[derive(Debug)]
+pub struct Ttl {
+    name: &'static str,
+    key: Bytes,
+    milliseconds: bool,
+    absolute: bool,
+}
+
+pub fn parse_ttl(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_ttl_generic(array, "ttl", false, false);
+}
+
+pub fn parse_pttl(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_ttl_generic(array, "pttl", true, false);
+}
+
+pub fn parse_expiretime(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_ttl_generic(array, "expiretime", false, true);
+}
+
+pub fn parse_pexpiretime(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_ttl_generic(array, "pexpiretime", true, true);
+}
+
+fn parse_ttl_generic(
+    array: &[DataType],
+    name: &'static str,
+    milliseconds: bool,
+    absolute: bool,
+) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(Ttl {
+        name: name,
+        key: key,
+        milliseconds: milliseconds,
+        absolute: absolute,
+    }));
+}
+
+impl CommandHandler for Ttl {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let ttl = {
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            let now = map.now();
+            map.get(&self.key).map(|v| match self.absolute {
+                true => Some(v.expiration).filter(|_| v.is_volatile()),
+                false => v.ttl(now),
+            })
+        };
+        expired.release(state);
+        state.stats.record_keyspace_lookup(ttl.is_some());
+        let number = match ttl {
+            None => -2,
+            Some(None) => -1,
+            Some(Some(ttl)) if self.milliseconds => ttl as isize,
+            // rounded like Redis does
+            Some(Some(ttl)) => ((ttl + 500) / 1000) as isize,
+        };
+        return Ok(DataType::Integer { number: number });
+    }
+}
+
+/// PERSIST removes the expiration of 'key'. Responds with 1 as an Integer if the
+/// expiration was removed, 0 if the key doesn't exist or has no expiration.
+#[derive(Debug)]
+pub struct Persist {
+    key: Bytes,
+}
+
+pub fn parse_persist(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(Persist { key: key }));
+}
+
+impl CommandHandler for Persist {
+    fn name(&self) -> &'static str {
+        return "persist";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let persisted = {
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            match map.get(&self.key) {
+                Some(v) if v.is_volatile() => map.set_expiration(&self.key, 0),
+                _ => false,
+            }
+        };
+        expired.release(state);
+        return Ok(DataType::Integer {
+            number: persisted as isize,
+        });
+    }
+}
+
+/// KEYS responds with an Array of the keys matching the glob-style 'pattern'.
+/// The keyspace is visited in batches, locking a shard only while going through
+/// each batch, so the server keeps serving other clients meanwhile. Keys added or
+/// removed while KEYS runs may or may not be returned.
+#[derive(Debug)]
+pub struct Keys {
+    pattern: Bytes,
+}
+
+pub fn parse_keys(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let pattern = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(Keys { pattern: pattern }));
+}
+
+impl CommandHandler for Keys {
+    fn name(&self) -> &'static str {
+        return "keys";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        // keys moved around between batches can be visited twice
+        let mut keys = HashSet::new();
+        let mut cursor = 0;
+        let now = keyspace.now();
+        loop {
+            cursor = keyspace.scan(cursor, KEYS_BATCH, |key, value| {
+                if !value.is_expired(now) && glob::matches(&self.pattern, key, false) {
+                    keys.insert(key.clone());
+                }
+            });
+            if cursor == 0 {
+                break;
+            }
+        }
+        return Ok(DataType::Array {
+            items: keys.into_iter().map(DataType::bulk).collect(),
+        });
+    }
+}
+
+/// SCAN visits about COUNT keys (10 by default) starting at 'cursor', responding
+/// with an Array holding the cursor to continue from as a BulkString, 0 once the
+/// whole keyspace was visited, and an Array of the keys visited matching the
+/// glob-style MATCH pattern (every key by default).
+/// Keys present during the whole iteration are returned at least once, keys added
+/// or removed meanwhile may or may not be, and keys may be returned more than once.
+#[derive(Debug)]
+pub struct Scan {
+    cursor: u64,
+    pattern: Option<Bytes>,
+    count: usize,
+}
+
+pub fn parse_scan(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let cursor = match get_string_or_bad_args!(array, 1).parse() {
+        Ok(cursor) => cursor,
+        Err(_) => bail!(ParseError::InvalidCursor),
+    };
+    let mut scan = Scan {
+        cursor: cursor,
+        pattern: None,
+        count: 10,
+    };
+    let mut ix = 2;
+    while ix < array.len() {
+        let option = get_string_or_bad_args!(array, ix).to_uppercase();
+        match option.as_str() {
+            "MATCH" => scan.pattern = Some(get_bytes_or_bad_args!(array, ix + 1)),
+            "COUNT" => {
+                scan.count = get_string_or_bad_args!(array, ix + 1)
+                    .parse()
+                    .map_err(|_| ParseError::NotAnInteger)?;
+                if scan.count < 1 {
+                    bail!(ParseError::BadArguments);
+                }
+            }
+            _ => bail!(ParseError::BadArguments),
+        }
+        ix += 2;
+    }
+    return Ok(Box::new(scan));
+}
+
+impl CommandHandler for Scan {
+    fn name(&self) -> &'static str {
+        return "scan";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut keys = Vec::new();
+        let now = keyspace.now();
+        let cursor = keyspace.scan(self.cursor, self.count, |key, value| {
+            let matches = match &self.pattern {
+                Some(pattern) => glob::matches(pattern, key, false),
+                None => true,
+            };
+            if matches && !value.is_expired(now) {
+                keys.push(DataType::bulk(key.clone()));
+            }
+        });
+        return Ok(DataType::Array {
+            items: vec![
+                DataType::bulk(cursor.to_string()),
+                DataType::Array { items: keys },
+            ],
+        });
+    }
+}
+
+/// DUMP serializes the value stored at 'key' in a format RESTORE loads, responds
+/// with it as a BulkString, NullBulkString if the key doesn't exist.
+#[derive(Debug)]
+pub struct Dump {
+    key: Bytes,
+}
+
+pub fn parse_dump(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(Dump { key: key }));
+}
+
+impl CommandHandler for Dump {
+    fn name(&self) -> &'static str {
+        return "dump";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let payload = {
+            let config = state.config.read().unwrap();
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            map.lookup(&self.key, &config)
+                .map(|value| dump::dump(&value.value))
+        };
+        expired.release(state);
+        state.stats.record_keyspace_lookup(payload.is_some());
+        return Ok(match payload {
+            Some(payload) => DataType::bulk(payload),
+            None => DataType::NullBulkString,
+        });
+    }
+}
+
+/// RESTORE stores at 'key' the value serialized by DUMP, expiring 'ttl' ms later, or
+/// at the unix time 'ttl' in ms with ABSTTL, never if 0. Fails if the key exists,
+/// unless REPLACE is given. Responds "OK".
+#[derive(Debug)]
+pub struct Restore {
+    key: Bytes,
+    ttl: i64,
+    payload: Bytes,
+    replace: bool,
+    absolute: bool,
+}
+
+pub fn parse_restore(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let ttl = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    let payload = get_bytes_or_bad_args!(array, 3);
+    let options = parse_options(array, 4, "restore", &["REPLACE", "ABSTTL"])?;
+    return Ok(Box::new(Restore {
+        key: key,
+        ttl: ttl,
+        payload: payload,
+        replace: options.contains(&"REPLACE"),
+        absolute: options.contains(&"ABSTTL"),
+    }));
+}
+
+impl CommandHandler for Restore {
+    fn name(&self) -> &'static str {
+        return "restore";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        if self.ttl < 0 {
+            return Err(ReplyError::Err(
+                "Invalid TTL value, must be >= 0".to_string(),
+            ));
+        }
+        let (value, lazy) = {
+            let config = state.config.read().unwrap();
+            let value = dump::restore(&self.payload, &config)
+                .map_err(|err| ReplyError::Err(err.to_string()))?;
+            (value, config.lazyfree_lazy_server_del)
+        };
+        let keyspace = state.keyspace(client);
+        let now = keyspace.now();
+        let expiration = match self.absolute {
+            true => self.ttl as usize,
+            false if self.ttl == 0 => 0,
+            false => now.saturating_add(self.ttl as usize),
+        };
+        let mut expired = ExpiredKeys::default();
+        let res = {
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            if !self.replace && map.get(&self.key).is_some() {
+                Err(ReplyError::BusyKey)
+            } else if expiration != 0 && expiration <= now {
+                // restored already expired, like Redis it only deletes the key
+                Ok(map.remove(&self.key))
+            } else {
+                let value = DBValue {
+                    expiration: expiration,
+                    ..DBValue::with_expiration(value, 0, now)
+                };
+                Ok(map.insert(self.key.clone(), value))
+            }
+        };
+        expired.release(state);
+        if let Some(old_value) = res? {
+            state.lazyfree.free(old_value, lazy);
+        }
+        return Ok(DataType::ok());
+    }
+}
+
+/// SORT responds with the elements of the list, set or sorted set stored at 'key',
+/// sorted as numbers, or as strings with ALPHA, in ascending order, or descending
+/// with DESC. Elements that compare equal are ordered as strings.
+/// - BY <pattern>: sorts by the values of the keys named after the elements, with
+///   the first '*' of the pattern replaced by the element, or of the field of a hash
+///   with 'pattern->field'. Missing keys count as 0, or sort first with ALPHA.
+///   Patterns without '*' skip sorting.
+/// - LIMIT <offset> <count>: only the 'count' elements after the first 'offset',
+///   every one after it if 'count' is negative.
+/// - GET <pattern>: responds with the values of the keys named after the elements
+///   instead, NullBulkString for missing ones, and '#' for the element itself. Can be
+///   given several times, responding with the values of every pattern in turn.
+/// - STORE <destination>: stores the reply as a list at 'destination' instead, with
+///   empty strings for missing values, and responds with its length as an Integer.
+///   'destination' is deleted if there are no elements.
+///
+/// The keys the patterns refer to are only known while sorting, so with patterns
+/// every shard is locked.
+#[derive(Debug)]
+pub struct Sort {
+    key: Bytes,
+    by: Option<Bytes>,
+    limit: Option<(i64, i64)>,
+    get: Vec<Bytes>,
+    desc: bool,
+    alpha: bool,
+    store: Option<Bytes>,
+}
+
+pub fn parse_sort(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let mut sort = Sort {
+        key: get_bytes_or_bad_args!(array, 1),
+        by: None,
+        limit: None,
+        get: Vec::new(),
+        desc: false,
+        alpha: false,
+        store: None,
+    };
+    let mut ix = 2;
+    while ix < array.len() {
+        let option = get_string_or_bad_args!(array, ix).to_uppercase();
+        let args = array.len() - ix - 1;
+        match option.as_str() {
+            "ASC" => sort.desc = false,
+            "DESC" => sort.desc = true,
+            "ALPHA" => sort.alpha = true,
+            "LIMIT" if args >= 2 => {
+                let offset = get_string_or_bad_args!(array, ix + 1)
+                    .parse()
+                    .map_err(|_| ParseError::NotAnInteger)?;
+                let count = get_string_or_bad_args!(array, ix + 2)
+                    .parse()
+                    .map_err(|_| ParseError::NotAnInteger)?;
+                sort.limit = Some((offset, count));
+                ix += 2;
+            }
+            "BY" if args >= 1 => {
+                sort.by = Some(get_bytes_or_bad_args!(array, ix + 1));
+                ix += 1;
+            }
+            "GET" if args >= 1 => {
+                sort.get.push(get_bytes_or_bad_args!(array, ix + 1));
+                ix += 1;
+            }
+            "STORE" if args >= 1 => {
+                sort.store = Some(get_bytes_or_bad_args!(array, ix + 1));
+                ix += 1;
+            }
+            _ => bail!(ParseError::BadArguments),
+        }
+        ix += 1;
+    }
+    return Ok(Box::new(sort));
+}
+
+impl Sort {
+    /// Whether the elements are sorted, a BY pattern without '*' skips it.
+    fn sorting(&self) -> bool {
+        return self.by.as_ref().is_none_or(|by| by.contains(&b'*'));
+    }
+
+    /// Whether the patterns refer to other keys.
+    fn uses_patterns(&self) -> bool {
+        let by = self.by.as_ref().is_some_and(|by| by.contains(&b'*'));
+        return by || self.get.iter().any(|pattern| pattern != "#");
+    }
+
+    /// The elements of 'key', sorted and limited.
+    fn elements(
+        &self,
+        maps: &mut ShardGuards,
+        config: &Config,
+        expired: &mut ExpiredKeys,
+    ) -> Result<Vec<Bytes>, ReplyError> {
+        let map = maps.get(&self.key);
+        expired.remove_if_expired(map, &self.key);
+        let mut elements: Vec<Bytes> = match map.lookup(&self.key, config).map(|v| &v.value) {
+            None => Vec::new(),
+            Some(Value::List(list)) => list.iter().map(Bytes::copy_from_slice).collect(),
+            Some(Value::Set(set)) => set.iter().map(Bytes::copy_from_slice).collect(),
+            Some(Value::ZSet(zset)) => zset
+                .iter()
+                .map(|(member, _)| Bytes::copy_from_slice(member))
+                .collect(),
+            Some(_) => return Err(ReplyError::WrongType),
+        };
+        if self.sorting() {
+            let weights: Vec<Option<Bytes>> = match &self.by {
+                Some(by) => elements
+                    .iter()
+                    .map(|element| lookup_pattern(maps, by, element))
+                    .collect(),
+                None => elements.iter().cloned().map(Some).collect(),
+            };
+            if self.alpha {
+                let mut sorted: Vec<_> = elements.into_iter().zip(weights).collect();
+                sorted.sort_by(|(a, a_weight), (b, b_weight)| {
+                    a_weight.cmp(b_weight).then_with(|| a.cmp(b))
+                });
+                elements = sorted.into_iter().map(|(element, _)| element).collect();
+            } else {
+                let mut scored = Vec::with_capacity(elements.len());
+                for (element, weight) in elements.into_iter().zip(weights) {
+                    let score = match &weight {
+                        Some(weight) => parse_score(weight).ok_or_else(|| {
+                            ReplyError::Err(
+                                "One or more scores can't be converted into double".to_string(),
+                            )
+                        })?,
+                        None => 0.0,
+                    };
+                    scored.push((element, score));
+                }
+                scored.sort_by(|(a, a_score), (b, b_score)| {
+                    a_score.total_cmp(b_score).then_with(|| a.cmp(b))
+                });
+                elements = scored.into_iter().map(|(element, _)| element).collect();
+            }
+            if self.desc {
+                elements.reverse();
+            }
+        }
+        if let Some((offset, count)) = self.limit {
+            let start = (offset.max(0) as usize).min(elements.len());
+            let end = match count < 0 {
+                true => elements.len(),
+                false => start.saturating_add(count as usize).min(elements.len()),
+            };
+            elements = elements.drain(start..end).collect();
+        }
+        return Ok(elements);
+    }
+}
+
+/// Value of the key named after `pattern` with its first '*' replaced by `element`,
+/// or of the field after '->' if it's a hash. `None` if there's no such string or
+/// field, or the pattern has no '*'. The pattern '#' is the element itself.
+fn lookup_pattern(maps: &mut ShardGuards, pattern: &[u8], element: &[u8]) -> Option<Bytes> {
+    if pattern == b"#" {
+        return Some(Bytes::copy_from_slice(element));
+    }
+    let star = pattern.iter().position(|b| *b == b'*')?;
+    let (key_pattern, field) = match pattern[star..].windows(2).position(|w| w == b"->") {
+        Some(arrow) if star + arrow + 2 < pattern.len() => {
+            (&pattern[..star + arrow], Some(&pattern[star + arrow + 2..]))
+        }
+        _ => (pattern, None),
+    };
+    let key = [&key_pattern[..star], element, &key_pattern[star + 1..]].concat();
+    let map = maps.get(&key);
+    let value = map.get(&key).filter(|v| !v.is_expired(map.now()))?;
+    return match (&value.value, field) {
+        (Value::Str(string), None) => Some(string.to_bytes()),
+        (Value::Hash(hash), Some(field)) => hash.get(field).map(Bytes::copy_from_slice),
+        _ => None,
+    };
+}
+
+/// Parses a number to sort by like strtod does, rejecting NaN.
+fn parse_score(string: &[u8]) -> Option<f64> {
+    let score: f64 = std::str::from_utf8(string).ok()?.trim().parse().ok()?;
+    return (!score.is_nan()).then_some(score);
+}
+
+impl CommandHandler for Sort {
+    fn name(&self) -> &'static str {
+        return "sort";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let mut replaced = None;
+        let mut keys = vec![self.key.clone()];
+        keys.extend(self.store.clone());
+        let res = {
+            let config = state.config.read().unwrap();
+            let mut maps = match self.uses_patterns() {
+                true => keyspace.lock_every_key(),
+                false => keyspace.lock_keys(&keys),
+            };
+            self.elements(&mut maps, &config, &mut expired)
+                .map(|elements| {
+                    let mut rows = Vec::new();
+                    for element in elements {
+                        match self.get.is_empty() {
+                            true => rows.push(Some(element)),
+                            false => rows.extend(
+                                self.get
+                                    .iter()
+                                    .map(|pattern| lookup_pattern(&mut maps, pattern, &element)),
+                            ),
+                        }
+                    }
+                    let destination = match &self.store {
+                        Some(destination) => destination,
+                        None => return rows,
+                    };
+                    let map = maps.get(destination);
+                    replaced = map.remove(destination);
+                    if !rows.is_empty() {
+                        let limits = config.list_listpack_limits();
+                        let mut list = List::new();
+                        for row in &rows {
+                            list.push_back(row.clone().unwrap_or_default(), &limits);
+                        }
+                        let value = DBValue::with_expiration(Value::List(list), 0, map.now());
+                        map.insert(destination.clone(), value);
+                    }
+                    return rows;
+                })
+        };
+        expired.release(state);
+        let rows = res?;
+        if let Some(destination) = &self.store {
+            tracking::invalidate_keys(state, std::slice::from_ref(destination), Some(client));
+            blocked::signal_keys(state, client, std::slice::from_ref(destination));
+            if let Some(replaced) = replaced {
+                let lazy = state.config.read().unwrap().lazyfree_lazy_server_del;
+                state.lazyfree.free(replaced, lazy);
+            }
+            return Ok(DataType::Integer {
+                number: rows.len() as isize,
+            });
+        }
+        return Ok(DataType::Array {
+            items: rows
+                .into_iter()
+                .map(|row| match row {
+                    Some(row) => DataType::bulk(row),
+                    None => DataType::NullBulkString,
+                })
+                .collect(),
+        });
+    }
+}
+
+/// MOVE moves a key to another database, responds 1 if moved and 0 if the key
+/// doesn't exist or already exists in the other database.
+#[derive(Debug)]
+pub struct Move {
+    key: Bytes,
+    db: i64,
+}
+
+pub fn parse_move(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let db = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    return Ok(Box::new(Move { key: key, db: db }));
+}
+
+impl CommandHandler for Move {
+    fn name(&self) -> &'static str {
+        return "move";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        if self.db < 0 || self.db as usize >= state.databases.len() {
+            return Err(ReplyError::Err("DB index is out of range".to_string()));
+        }
+        let (source, target) = (state.selected_db(client), self.db as usize);
+        if source == target {
+            return Err(ReplyError::Err(
+                "source and destination objects are the same".to_string(),
+            ));
+        }
+        let mut expired = ExpiredKeys::default();
+        let moved = {
+            // the shard of the lowest database is locked first
+            let mut lowest = state.databases[source.min(target)].lock(&self.key);
+            let mut highest = state.databases[source.max(target)].lock(&self.key);
+            let (from, to) = match source < target {
+                true => (&mut lowest, &mut highest),
+                false => (&mut highest, &mut lowest),
+            };
+            expired.remove_if_expired(from, &self.key);
+            expired.remove_if_expired(to, &self.key);
+            match to.get(&self.key) {
+                Some(_) => false,
+                None => match from.remove(&self.key) {
+                    Some(value) => {
+                        to.insert(self.key.clone(), value);
+                        true
+                    }
+                    None => false,
+                },
+            }
+        };
+        expired.release(state);
+        if moved {
+            // the target database isn't the one signaled on dispatch
+            state
+                .blocked
+                .signal(target, std::slice::from_ref(&self.key));
+        }
+        return Ok(DataType::Integer {
+            number: moved as isize,
+        });
+    }
+}
+
+#[derive(Debug)]
+pub enum ObjectSubcommand {
+    Encoding,
+    Refcount,
+    IdleTime,
+    Freq,
+}
+
+/// OBJECT inspects the internals of the value stored at a key, without marking it
+/// as accessed. Every subcommand responds NullBulkString if the key doesn't exist.
+/// - OBJECT ENCODING <key>: internal representation of the value as a BulkString.
+/// - OBJECT REFCOUNT <key>: references to the value as an Integer, always 1 as
+///   values aren't shared.
+/// - OBJECT IDLETIME <key>: seconds since the value was last accessed as an Integer.
+///   Not available with the LFU policies.
+/// - OBJECT FREQ <key>: logarithmic access frequency counter as an Integer.
+///   Only available with the LFU policies.
+#[derive(Debug)]
+pub struct Object {
+    subcommand: ObjectSubcommand,
+    key: Bytes,
+}
+
+pub fn parse_object(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let sub = get_string_or_bad_args!(array, 1);
+    let subcommand = match sub.to_uppercase().as_str() {
+        "ENCODING" => ObjectSubcommand::Encoding,
+        "REFCOUNT" => ObjectSubcommand::Refcount,
+        "IDLETIME" => ObjectSubcommand::IdleTime,
+        "FREQ" => ObjectSubcommand::Freq,
+        _ => bail!(ParseError::UnknownSubcommand(
+            "OBJECT".to_string(),
+            sub.clone()
+        )),
+    };
+    if array.len() != 3 {
+        bail!(ParseError::BadArguments);
+    }
+    let key = get_bytes_or_bad_args!(array, 2);
+    return Ok(Box::new(Object {
+        subcommand: subcommand,
+        key: key,
+    }));
+}
+
+impl CommandHandler for Object {
+    fn name(&self) -> &'static str {
+        return "object";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let config = state.config.read().unwrap();
+        match self.subcommand {
+            ObjectSubcommand::IdleTime if config.maxmemory_policy.is_lfu() => {
+                return Err(ReplyError::Err(String::from("An LRU maxmemory policy is selected, idle time not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.")));
+            }
+            ObjectSubcommand::Freq if !config.maxmemory_policy.is_lfu() => {
+                return Err(ReplyError::Err(String::from("An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.")));
+            }
+            _ => {}
+        }
+        let map = keyspace.lock(&self.key);
+        let now = map.now();
+        let value = match map.get(&self.key) {
+            Some(v) if !v.is_expired(now) => v,
+            _ => return Ok(DataType::NullBulkString),
+        };
+        let response = match self.subcommand {
+            ObjectSubcommand::Encoding => DataType::bulk(value.value.encoding()),
+            ObjectSubcommand::Refcount => DataType::Integer { number: 1 },
+            ObjectSubcommand::IdleTime => DataType::Integer {
+                number: (now.saturating_sub(value.lru) / 1000) as isize,
+            },
+            ObjectSubcommand::Freq => DataType::Integer {
+                number: value.lfu_frequency(config.lfu_decay_time, now) as isize,
+            },
+        };
+        return Ok(response);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+
+    use super::{
+        parse_del, parse_dump, parse_exists, parse_expire, parse_expireat, parse_expiretime,
+        parse_keys, parse_object, parse_persist, parse_pexpire, parse_pexpireat, parse_pexpiretime,
+        parse_pttl, parse_restore, parse_scan, parse_sort, parse_touch, parse_ttl, parse_type,
+        parse_unlink, ParseError,
+    };
+    use crate::clock::MockClock;
+    use crate::config::Config;
+    use crate::db::DBValue;
+    use crate::errors::ReplyError;
+    use crate::protocol::DataType;
+    use crate::state::StateInner;
+    use crate::value::{Hash, List, Value};
+
+    #[test]
+    fn test_keys() {
+        let state = StateInner::new(Config::default());
+        let now = state.keyspace(0).now();
+        for i in 0..3000 {
+            let key = format!("user:{i}");
+            let value = DBValue::with_expiration(Bytes::from("v"), 0, now);
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value);
+        }
+        let expired = DBValue {
+            expiration: 1,
+            ..DBValue::with_expiration(Bytes::from("v"), 0, now)
+        };
+        state
+            .keyspace(0)
+            .lock(b"user:expired")
+            .insert(Bytes::from("user:expired"), expired);
+        state.keyspace(0).lock(b"other").insert(
+            Bytes::from("other"),
+            DBValue::with_expiration(Bytes::from("v"), 0, now),
+        );
+
+        let keys = |pattern: &str| {
+            let keys = parse_keys(&[DataType::from("KEYS"), DataType::from(pattern)]).unwrap();
+            let mut names: Vec<String> = match keys.run(&state, 0) {
+                Ok(DataType::Array { items }) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        DataType::BulkString { string } => {
+                            String::from_utf8(string.to_vec()).unwrap()
+                        }
+                        other => panic!("unexpected item {:?}", other),
+                    })
+                    .collect(),
+                other => panic!("unexpected reply {:?}", other),
+            };
+            names.sort();
+            return names;
+        };
+        assert_eq!(keys("user:*").len(), 3000);
+        assert_eq!(
+            keys("user:1?"),
+            (10..20).map(|i| format!("user:{i}")).collect::<Vec<_>>()
+        );
+        assert_eq!(keys("*"), {
+            let mut all = keys("user:*");
+            all.push(String::from("other"));
+            all.sort();
+            all
+        });
+        assert!(keys("nothing*").is_empty());
+    }
+
+    fn args(args: &[&str]) -> Vec<DataType> {
+        return args.iter().map(|arg| DataType::from(*arg)).collect();
+    }
+
+    #[test]
+    fn test_scan() {
+        let state = StateInner::new(Config::default());
+        let now = state.keyspace(0).now();
+        for i in 0..1000 {
+            let key = format!("key:{i}");
+            let value = DBValue::with_expiration(Bytes::from("v"), 0, now);
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value);
+        }
+        let scan = |command: &[&str]| {
+            let reply = parse_scan(&args(command)).unwrap().run(&state, 0);
+            return match reply {
+                Ok(DataType::Array { items }) => match &items[..] {
+                    [DataType::BulkString { string }, DataType::Array { items }] => {
+                        let cursor = std::str::from_utf8(string).unwrap().to_string();
+                        let keys: Vec<String> =
+                            items.iter().map(|item| item.as_string().unwrap()).collect();
+                        (cursor, keys)
+                    }
+                    other => panic!("unexpected reply {:?}", other),
+                },
+                other => panic!("unexpected reply {:?}", other),
+            };
+        };
+
+        // keys removed or added meanwhile don't break the iteration
+        let mut seen = HashSet::new();
+        let mut cursor = String::from("0");
+        let mut calls = 0;
+        loop {
+            let (next, keys) = scan(&["SCAN", &cursor, "COUNT", "50"]);
+            seen.extend(keys);
+            calls += 1;
+            let key = format!("key:{calls}");
+            parse_del(&args(&["DEL", &key]))
+                .unwrap()
+                .run(&state, 0)
+                .unwrap();
+            let key = format!("new:{calls}");
+            let value = DBValue::with_expiration(Bytes::from("v"), 0, now);
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value);
+            cursor = next;
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert!(calls > 10);
+        for i in calls + 1..1000 {
+            assert!(seen.contains(&format!("key:{i}")));
+        }
+
+        let mut matched = HashSet::new();
+        let mut cursor = String::from("0");
+        loop {
+            let (next, keys) = scan(&["SCAN", &cursor, "MATCH", "key:99?", "COUNT", "100"]);
+            matched.extend(keys);
+            cursor = next;
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(matched.len(), 10);
+
+        let error = |command: &[&str]| {
+            let err = parse_scan(&args(command)).unwrap_err();
+            return err.downcast_ref::<ParseError>().unwrap().to_string();
+        };
+        assert_eq!(error(&["SCAN", "-1"]), "invalid cursor");
+        assert_eq!(error(&["SCAN", "0", "COUNT", "0"]), "syntax error");
+        assert_eq!(error(&["SCAN", "0", "MATCH"]), "syntax error");
+        assert_eq!(error(&["SCAN", "0", "TYPE", "string"]), "syntax error");
+    }
+
+    #[test]
+    fn test_del_exists_type() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let now = state.keyspace(0).now();
+        for (key, expiry) in [("a", 0), ("b", 0), ("volatile", 1000)] {
+            let value = DBValue::with_expiration(Bytes::from("v"), expiry, now);
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value);
+        }
+        let value = DBValue::with_expiration(Value::List(List::new()), 0, now);
+        state
+            .keyspace(0)
+            .lock(b"list")
+            .insert(Bytes::from("list"), value);
+
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "DEL" => parse_del(&args(command)),
+                "EXISTS" => parse_exists(&args(command)),
+                _ => parse_type(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0).unwrap();
+        };
+        let integer = |number| DataType::Integer { number };
+        let simple = |string: &str| DataType::SimpleString {
+            string: String::from(string),
+        };
+        // keys given twice are counted twice
+        assert_eq!(
+            run(&["EXISTS", "a", "a", "missing", "volatile"]),
+            integer(3)
+        );
+        assert_eq!(run(&["TYPE", "a"]), simple("string"));
+        assert_eq!(run(&["TYPE", "list"]), simple("list"));
+        assert_eq!(run(&["TYPE", "missing"]), simple("none"));
+
+        clock.advance(1000);
+        assert_eq!(run(&["EXISTS", "volatile"]), integer(0));
+        assert_eq!(run(&["TYPE", "volatile"]), simple("none"));
+        // but deleted once
+        assert_eq!(run(&["DEL", "a", "a", "list", "volatile"]), integer(2));
+        assert_eq!(run(&["EXISTS", "a", "list", "b"]), integer(1));
+        assert_eq!(run(&["DEL", "a"]), integer(0));
+    }
+
+    #[test]
+    fn test_expire_ttl_persist() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let now = state.keyspace(0).now();
+        for key in ["a", "b"] {
+            let value = DBValue::with_expiration(Bytes::from("v"), 0, now);
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value);
+        }
+
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "EXPIRE" => parse_expire(&args(command)),
+                "PEXPIRE" => parse_pexpire(&args(command)),
+                "TTL" => parse_ttl(&args(command)),
+                "PTTL" => parse_pttl(&args(command)),
+                _ => parse_persist(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0);
+        };
+        let integer = |number| Ok(DataType::Integer { number });
+        assert_eq!(run(&["TTL", "missing"]), integer(-2));
+        assert_eq!(run(&["PTTL", "a"]), integer(-1));
+        assert_eq!(run(&["PERSIST", "a"]), integer(0));
+        assert_eq!(run(&["EXPIRE", "missing", "10"]), integer(0));
+
+        assert_eq!(run(&["EXPIRE", "a", "10"]), integer(1));
+        clock.advance(1400);
+        assert_eq!(run(&["TTL", "a"]), integer(9));
+        clock.advance(200);
+        assert_eq!(run(&["TTL", "a"]), integer(8));
+        assert_eq!(run(&["PTTL", "a"]), integer(8400));
+        assert_eq!(run(&["PERSIST", "a"]), integer(1));
+        assert_eq!(run(&["TTL", "a"]), integer(-1));
+        assert_eq!(state.keyspace(0).lock(b"a").volatile_len(), 0);
+
+        assert_eq!(run(&["PEXPIRE", "a", "100"]), integer(1));
+        clock.advance(100);
+        assert_eq!(run(&["PTTL", "a"]), integer(-2));
+        assert_eq!(run(&["PERSIST", "a"]), integer(0));
+
+        // timeouts in the past delete the key
+        assert_eq!(run(&["EXPIRE", "b", "-1"]), integer(1));
+        assert!(state.keyspace(0).lock(b"b").get(b"b").is_none());
+
+        assert!(parse_expire(&args(&["EXPIRE", "a", "soon"])).is_err());
+        assert_eq!(
+            run(&["EXPIRE", "a", &i64::MAX.to_string()]),
+            Err(ReplyError::Err(String::from(
+                "invalid expire time in 'expire' command"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_expire_options() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let now = state.keyspace(0).now();
+        let value = DBValue::with_expiration(Bytes::from("v"), 0, now);
+        state.keyspace(0).lock(b"a").insert(Bytes::from("a"), value);
+
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "PEXPIRE" => parse_pexpire(&args(command)),
+                _ => parse_pttl(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0).unwrap();
+        };
+        let integer = |number| DataType::Integer { number };
+        // keys without a timeout have an infinite one
+        assert_eq!(run(&["PEXPIRE", "a", "100", "XX"]), integer(0));
+        assert_eq!(run(&["PEXPIRE", "a", "100", "gt"]), integer(0));
+        assert_eq!(run(&["PEXPIRE", "a", "100", "NX"]), integer(1));
+        assert_eq!(run(&["PEXPIRE", "a", "200", "NX"]), integer(0));
+        assert_eq!(run(&["PEXPIRE", "a", "50", "GT"]), integer(0));
+        assert_eq!(run(&["PEXPIRE", "a", "200", "XX", "GT"]), integer(1));
+        assert_eq!(run(&["PEXPIRE", "a", "300", "LT"]), integer(0));
+        assert_eq!(run(&["PEXPIRE", "a", "150", "LT"]), integer(1));
+        assert_eq!(run(&["PTTL", "a"]), integer(150));
+        assert_eq!(run(&["PEXPIRE", "missing", "100", "LT"]), integer(0));
+
+        let error = |command: &[&str]| {
+            let err = parse_pexpire(&args(command)).unwrap_err();
+            return err.downcast_ref::<ParseError>().unwrap().to_string();
+        };
+        assert_eq!(
+            error(&["PEXPIRE", "a", "1", "NX", "GT"]),
+            "NX and XX, GT or LT options at the same time are not compatible"
+        );
+        assert_eq!(
+            error(&["PEXPIRE", "a", "1", "GT", "LT"]),
+            "GT and LT options at the same time are not compatible"
+        );
+        assert_eq!(
+            error(&["PEXPIRE", "a", "1", "KEEPTTL"]),
+            "Option 'KEEPTTL' for PEXPIRE not supported"
+        );
+    }
+
+    #[test]
+    fn test_absolute_expirations() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let now = state.keyspace(0).now();
+        for key in ["a", "b"] {
+            let value = DBValue::with_expiration(Bytes::from("v"), 0, now);
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value);
+        }
+
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "EXPIREAT" => parse_expireat(&args(command)),
+                "PEXPIREAT" => parse_pexpireat(&args(command)),
+                "EXPIRETIME" => parse_expiretime(&args(command)),
+                _ => parse_pexpiretime(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0).unwrap();
+        };
+        let integer = |number| DataType::Integer { number };
+        assert_eq!(run(&["EXPIRETIME", "missing"]), integer(-2));
+        assert_eq!(run(&["PEXPIRETIME", "a"]), integer(-1));
+        assert_eq!(run(&["EXPIREAT", "missing", "2000"]), integer(0));
+
+        assert_eq!(run(&["EXPIREAT", "a", "2000"]), integer(1));
+        assert_eq!(run(&["EXPIRETIME", "a"]), integer(2000));
+        assert_eq!(run(&["PEXPIRETIME", "a"]), integer(2_000_000));
+        assert_eq!(run(&["PEXPIREAT", "a", "1500000"]), integer(1));
+        assert_eq!(run(&["PEXPIRETIME", "a"]), integer(1_500_000));
+        clock.set(1_500_000);
+        assert_eq!(run(&["PEXPIRETIME", "a"]), integer(-2));
+
+        // timestamps in the past delete the key
+        assert_eq!(run(&["EXPIREAT", "b", "999"]), integer(1));
+        assert!(state.keyspace(0).lock(b"b").get(b"b").is_none());
+    }
+
+    #[test]
+    fn test_unlink_touch() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let big = || {
+            let items = (0..1000).map(|i| Bytes::from(i.to_string())).collect();
+            return DBValue::with_expiration(Value::List(List::Quicklist(items)), 0, 0);
+        };
+        for key in ["a", "b", "c"] {
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), big());
+        }
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "DEL" => parse_del(&args(command)),
+                "UNLINK" => parse_unlink(&args(command)),
+                _ => parse_touch(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0).unwrap();
+        };
+        let integer = |number| DataType::Integer { number };
+
+        clock.advance(5000);
+        assert_eq!(run(&["TOUCH", "a", "a", "missing"]), integer(2));
+        let lru = |key: &str| {
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .get(key.as_bytes())
+                .unwrap()
+                .lru
+        };
+        assert_eq!(lru("a"), 1_005_000);
+        assert_eq!(lru("b"), 0);
+
+        // DEL frees the values right away unless lazyfree-lazy-user-del is set
+        assert_eq!(run(&["DEL", "a"]), integer(1));
+        assert_eq!(state.lazyfree.freed(), 0);
+        assert_eq!(run(&["UNLINK", "b", "c", "missing"]), integer(2));
+        assert!(state.keyspace(0).is_empty());
+        let start = Instant::now();
+        while state.lazyfree.freed() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5), "values not freed");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_object() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let now = state.keyspace(0).now();
+        for (key, value) in [("int", "12"), ("short", "value")] {
+            let value = DBValue::with_expiration(Bytes::from(value), 0, now);
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value);
+        }
+        let value = DBValue::with_expiration(Value::List(List::new()), 0, now);
+        state
+            .keyspace(0)
+            .lock(b"list")
+            .insert(Bytes::from("list"), value);
+        let run = |command: &[&str]| parse_object(&args(command)).unwrap().run(&state, 0);
+
+        assert_eq!(
+            run(&["OBJECT", "encoding", "int"]),
+            Ok(DataType::bulk("int"))
+        );
+        assert_eq!(
+            run(&["OBJECT", "ENCODING", "short"]),
+            Ok(DataType::bulk("embstr"))
+        );
+        assert_eq!(
+            run(&["OBJECT", "ENCODING", "list"]),
+            Ok(DataType::bulk("listpack"))
+        );
+        assert_eq!(
+            run(&["OBJECT", "REFCOUNT", "list"]),
+            Ok(DataType::Integer { number: 1 })
+        );
+        assert_eq!(
+            run(&["OBJECT", "ENCODING", "missing"]),
+            Ok(DataType::NullBulkString)
+        );
+
+        clock.advance(7500);
+        assert_eq!(
+            run(&["OBJECT", "IDLETIME", "int"]),
+            Ok(DataType::Integer { number: 7 })
+        );
+        // inspecting a key doesn't count as an access
+        assert_eq!(
+            run(&["OBJECT", "IDLETIME", "int"]),
+            Ok(DataType::Integer { number: 7 })
+        );
+        assert!(run(&["OBJECT", "FREQ", "int"]).is_err());
+        state
+            .config
+            .write()
+            .unwrap()
+            .set("maxmemory-policy", "allkeys-lfu")
+            .unwrap();
+        assert!(run(&["OBJECT", "IDLETIME", "int"]).is_err());
+        assert_eq!(
+            run(&["OBJECT", "FREQ", "int"]),
+            Ok(DataType::Integer { number: 5 })
+        );
+
+        let err = parse_object(&args(&["OBJECT", "ENCODING"])).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ParseError>(),
+            Some(ParseError::BadArguments)
+        ));
+        let err = parse_object(&args(&["OBJECT", "SIZE", "int"])).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ParseError>(),
+            Some(ParseError::UnknownSubcommand(..))
+        ));
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let now = state.keyspace(0).now();
+        let value = DBValue::with_expiration(Bytes::from("value"), 0, now);
+        state.keyspace(0).lock(b"a").insert(Bytes::from("a"), value);
+        let run = |command: &[DataType]| {
+            let handler = match command[0].as_string().unwrap().as_str() {
+                "DUMP" => parse_dump(command),
+                _ => parse_restore(command),
+            };
+            return handler.unwrap().run(&state, 0);
+        };
+        let restore = |key: &str, ttl: &str, payload: &DataType, options: &[&str]| {
+            let mut command = args(&["RESTORE", key, ttl]);
+            command.push(payload.clone());
+            command.extend(args(options));
+            return run(&command);
+        };
+        let error = |message: &str| Err(ReplyError::Err(String::from(message)));
+
+        let payload = run(&args(&["DUMP", "a"])).unwrap();
+        assert_eq!(
+            run(&args(&["DUMP", "missing"])),
+            Ok(DataType::NullBulkString)
+        );
+        assert_eq!(restore("b", "0", &payload, &[]), Ok(DataType::ok()));
+        assert_eq!(run(&args(&["DUMP", "b"])), Ok(payload.clone()));
+        assert_eq!(restore("b", "0", &payload, &[]), Err(ReplyError::BusyKey));
+
+        assert_eq!(
+            restore("b", "5000", &payload, &["replace"]),
+            Ok(DataType::ok())
+        );
+        let expiration = |key: &str| {
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .get(key.as_bytes())
+                .map(|v| v.expiration)
+        };
+        assert_eq!(expiration("b"), Some(1_005_000));
+        assert_eq!(
+            restore("c", "2000000", &payload, &["ABSTTL"]),
+            Ok(DataType::ok())
+        );
+        assert_eq!(expiration("c"), Some(2_000_000));
+        // restoring an expired value deletes the key
+        assert_eq!(
+            restore("c", "999999", &payload, &["ABSTTL", "REPLACE"]),
+            Ok(DataType::ok())
+        );
+        assert_eq!(expiration("c"), None);
+
+        assert_eq!(
+            restore("d", "-1", &payload, &[]),
+            error("Invalid TTL value, must be >= 0")
+        );
+        assert_eq!(
+            restore("d", "0", &DataType::from("garbage"), &[]),
+            error("DUMP payload version or checksum are wrong")
+        );
+        assert!(parse_restore(&args(&["RESTORE", "d", "0", "x", "FORCE"])).is_err());
+    }
+
+    #[test]
+    fn test_sort() {
+        let state = StateInner::new(Config::default());
+        let config = Config::default();
+        let insert = |key: &str, value: Value| {
+            let value = DBValue::with_expiration(value, 0, 0);
+            let key = Bytes::copy_from_slice(key.as_bytes());
+            state.keyspace(0).lock(&key).insert(key.clone(), value);
+        };
+        let mut list = List::new();
+        for item in ["3", "10", "1", "2"] {
+            list.push_back(Bytes::from(item), &config.list_listpack_limits());
+        }
+        insert("list", Value::List(list));
+        let mut hash = Hash::new();
+        hash.insert(
+            Bytes::from("name"),
+            Bytes::from("one"),
+            &config.hash_listpack_limits(),
+        );
+        insert("info:1", Value::Hash(hash));
+        for (key, value) in [
+            ("w:1", "30"),
+            ("w:2", "20"),
+            ("w:3", "10"),
+            ("name:2", "two"),
+            ("word", "x"),
+        ] {
+            insert(key, Value::from(Bytes::from(value)));
+        }
+        let run = |command: &[&str]| parse_sort(&args(command)).unwrap().run(&state, 0);
+        let bulks = |items: &[Option<&str>]| {
+            let items = items
+                .iter()
+                .map(|item| match item {
+                    Some(item) => DataType::bulk(item.to_string()),
+                    None => DataType::NullBulkString,
+                })
+                .collect();
+            return Ok(DataType::Array { items });
+        };
+
+        assert_eq!(
+            run(&["SORT", "list"]),
+            bulks(&[Some("1"), Some("2"), Some("3"), Some("10")])
+        );
+        assert_eq!(
+            run(&["SORT", "list", "ALPHA", "DESC"]),
+            bulks(&[Some("3"), Some("2"), Some("10"), Some("1")])
+        );
+        assert_eq!(
+            run(&["SORT", "list", "LIMIT", "1", "2"]),
+            bulks(&[Some("2"), Some("3")])
+        );
+        assert_eq!(run(&["SORT", "list", "LIMIT", "10", "2"]), bulks(&[]));
+        // missing weights count as 0, ties are ordered as strings
+        assert_eq!(
+            run(&["SORT", "list", "BY", "w:*"]),
+            bulks(&[Some("10"), Some("3"), Some("2"), Some("1")])
+        );
+        // patterns without '*' don't sort
+        assert_eq!(
+            run(&["SORT", "list", "BY", "nosort"]),
+            bulks(&[Some("3"), Some("10"), Some("1"), Some("2")])
+        );
+        assert_eq!(
+            run(&[
+                "SORT",
+                "list",
+                "LIMIT",
+                "0",
+                "2",
+                "GET",
+                "#",
+                "GET",
+                "name:*",
+                "GET",
+                "info:*->name"
+            ]),
+            bulks(&[Some("1"), None, Some("one"), Some("2"), Some("two"), None])
+        );
+
+        assert_eq!(
+            run(&["SORT", "list", "GET", "name:*", "STORE", "word"]),
+            Ok(DataType::Integer { number: 4 })
+        );
+        let stored = state
+            .keyspace(0)
+            .lock(b"word")
+            .get(b"word")
+            .unwrap()
+            .value
+            .clone();
+        let items: Vec<&[u8]> = vec![b"", b"two", b"", b""];
+        assert!(stored.as_list().unwrap().iter().eq(items));
+        assert_eq!(
+            run(&["SORT", "missing", "STORE", "word"]),
+            Ok(DataType::Integer { number: 0 })
+        );
+        assert!(state.keyspace(0).lock(b"word").get(b"word").is_none());
+        assert_eq!(run(&["SORT", "missing"]), bulks(&[]));
+
+        assert_eq!(run(&["SORT", "w:1"]), Err(ReplyError::WrongType));
+        let mut words = List::new();
+        words.push_back(Bytes::from("a"), &config.list_listpack_limits());
+        insert("words", Value::List(words));
+        assert_eq!(
+            run(&["SORT", "words"]),
+            Err(ReplyError::Err(String::from(
+                "One or more scores can't be converted into double"
+            )))
+        );
+        assert!(parse_sort(&args(&["SORT", "list", "LIMIT", "1"])).is_err());
+        assert!(parse_sort(&args(&["SORT", "list", "REVERSE"])).is_err());
+    }
+}
@@ -0,0 +1,569 @@
+/// Commands operating on hash values.
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use super::{CommandHandler, ExpiredKeys, ParseError};
+use crate::clients::ClientId;
+use crate::db::DBValue;
+use crate::errors::ReplyError;
+use crate::listpack::ListpackLimits;
+use crate::protocol::DataType;
+use crate::state::State;
+use crate::value::{Hash, Value};
+
+/// Looks up the hash stored at `key` and applies `f` to it, `None` if the key doesn't
+/// exist.
+fn read_hash<R>(
+    state: &State,
+    client: ClientId,
+    key: &[u8],
+    f: impl FnOnce(&Hash) -> R,
+) -> Result<Option<R>, ReplyError> {
+    let keyspace = state.keyspace(client);
+    let mut expired = ExpiredKeys::default();
+    let found = {
+        let config = state.config.read().unwrap();
+        let mut map = keyspace.lock(key);
+        expired.remove_if_expired(&mut map, key);
+        map.lookup(key, &config).map(|v| v.value.as_hash().map(f))
+    };
+    expired.release(state);
+    state.stats.record_keyspace_lookup(found.is_some());
+    return found.transpose();
+}
+
+fn bulk_or_null(value: Option<&[u8]>) -> DataType {
+    return match value {
+        Some(value) => DataType::bulk(Bytes::copy_from_slice(value)),
+        None => DataType::NullBulkString,
+    };
+}
+
+/// HSET sets the 'value' of each 'field' in the hash stored at 'key', creating it if
+/// it doesn't exist. Responds with the number of fields added as an Integer.
+#[derive(Debug)]
+pub struct HSet {
+    key: Bytes,
+    pairs: Vec<(Bytes, Bytes)>,
+}
+
+pub fn parse_hset(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    if array.len() < 4 || !array.len().is_multiple_of(2) {
+        bail!(ParseError::WrongArity("hset".to_string()));
+    }
+    let key = get_bytes_or_bad_args!(array, 1);
+    let mut pairs = Vec::new();
+    for ix in (2..array.len()).step_by(2) {
+        pairs.push((
+            get_bytes_or_bad_args!(array, ix),
+            get_bytes_or_bad_args!(array, ix + 1),
+        ));
+    }
+    return Ok(Box::new(HSet {
+        key: key,
+        pairs: pairs,
+    }));
+}
+
+impl HSet {
+    /// Sets the fields in `hash`, returns how many of them are new.
+    fn set_fields(&self, hash: &mut Hash, limits: &ListpackLimits) -> usize {
+        let mut added = 0;
+        for (field, value) in &self.pairs {
+            if hash.insert(field.clone(), value.clone(), limits) {
+                added += 1;
+            }
+        }
+        return added;
+    }
+}
+
+impl CommandHandler for HSet {
+    fn name(&self) -> &'static str {
+        return "hset";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let res = {
+            let limits = state.config.read().unwrap().hash_listpack_limits();
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            let updated = map.update(&self.key, |v| {
+                return Ok(self.set_fields(v.value.as_hash_mut()?, &limits));
+            });
+            match updated {
+                Some(res) => res,
+                None => {
+                    let mut hash = Hash::new();
+                    let added = self.set_fields(&mut hash, &limits);
+                    let value = DBValue::with_expiration(Value::Hash(hash), 0, map.now());
+                    map.insert(self.key.clone(), value);
+                    Ok(added)
+                }
+            }
+        };
+        expired.release(state);
+        return Ok(DataType::Integer {
+            number: res? as isize,
+        });
+    }
+}
+
+/// HGET responds with the value of 'field' in the hash stored at 'key' as a
+/// BulkString, or NullBulkString if the field or the key don't exist.
+#[derive(Debug)]
+pub struct HGet {
+    key: Bytes,
+    field: Bytes,
+}
+
+pub fn parse_hget(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let field = get_bytes_or_bad_args!(array, 2);
+    return Ok(Box::new(HGet {
+        key: key,
+        field: field,
+    }));
+}
+
+impl CommandHandler for HGet {
+    fn name(&self) -> &'static str {
+        return "hget";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let value = read_hash(state, client, &self.key, |hash| {
+            return bulk_or_null(hash.get(&self.field));
+        })?;
+        return Ok(value.unwrap_or(DataType::NullBulkString));
+    }
+}
+
+/// HMGET responds with an Array of the values of each 'field' in the hash stored at
+/// 'key', with NullBulkString for the fields that don't exist.
+#[derive(Debug)]
+pub struct HMGet {
+    key: Bytes,
+    fields: Vec<Bytes>,
+}
+
+pub fn parse_hmget(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let mut fields = Vec::new();
+    for ix in 2..array.len() {
+        fields.push(get_bytes_or_bad_args!(array, ix));
+    }
+    return Ok(Box::new(HMGet {
+        key: key,
+        fields: fields,
+    }));
+}
+
+impl CommandHandler for HMGet {
+    fn name(&self) -> &'static str {
+        return "hmget";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let values = read_hash(state, client, &self.key, |hash| {
+            return self
+                .fields
+                .iter()
+                .map(|field| bulk_or_null(hash.get(field)))
+                .collect();
+        })?;
+        return Ok(DataType::Array {
+            items: values.unwrap_or_else(|| vec![DataType::NullBulkString; self.fields.len()]),
+        });
+    }
+}
+
+/// HDEL removes each 'field' from the hash stored at 'key', deleting the key if no
+/// field is left. Responds with the number of fields removed as an Integer.
+#[derive(Debug)]
+pub struct HDel {
+    key: Bytes,
+    fields: Vec<Bytes>,
+}
+
+pub fn parse_hdel(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let mut fields = Vec::new();
+    for ix in 2..array.len() {
+        fields.push(get_bytes_or_bad_args!(array, ix));
+    }
+    return Ok(Box::new(HDel {
+        key: key,
+        fields: fields,
+    }));
+}
+
+impl CommandHandler for HDel {
+    fn name(&self) -> &'static str {
+        return "hdel";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let res = {
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            let res = map.update(&self.key, |v| {
+                let hash = v.value.as_hash_mut()?;
+                let removed = self.fields.iter().filter(|f| hash.remove(f)).count();
+                return Ok((removed, hash.is_empty()));
+            });
+            if let Some(Ok((_, true))) = res {
+                // empty hashes don't exist in the keyspace
+                map.remove(&self.key);
+            }
+            res
+        };
+        expired.release(state);
+        let removed = match res {
+            Some(res) => res?.0,
+            None => 0,
+        };
+        return Ok(DataType::Integer {
+            number: removed as isize,
+        });
+    }
+}
+
+/// HGETALL responds with a Map of the fields and values of the hash stored at 'key',
+/// HKEYS with an Array of its fields and HVALS with an Array of its values. They
+/// are empty if the key doesn't exist.
+#[derive(Debug)]
+pub struct HGetAll {
+    name: &'static str,
+    key: Bytes,
+}
+
+pub fn parse_hgetall(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_hgetall_generic(array, "hgetall");
+}
+
+pub fn parse_hkeys(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_hgetall_generic(array, "hkeys");
+}
+
+pub fn parse_hvals(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_hgetall_generic(array, "hvals");
+}
+
+fn parse_hgetall_generic(
+    array: &[DataType],
+    name: &'static str,
+) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(HGetAll {
+        name: name,
+        key: key,
+    }));
+}
+
+impl CommandHandler for HGetAll {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let pairs = read_hash(state, client, &self.key, |hash| {
+            return hash
+                .iter()
+                .map(|(f, v)| (Bytes::copy_from_slice(f), Bytes::copy_from_slice(v)))
+                .collect::<Vec<_>>();
+        })?;
+        let pairs = pairs.unwrap_or_default().into_iter();
+        return Ok(match self.name {
+            "hgetall" => DataType::Map {
+                items: pairs
+                    .map(|(f, v)| (DataType::bulk(f), DataType::bulk(v)))
+                    .collect(),
+            },
+            "hkeys" => DataType::Array {
+                items: pairs.map(|(f, _)| DataType::bulk(f)).collect(),
+            },
+            _ => DataType::Array {
+                items: pairs.map(|(_, v)| DataType::bulk(v)).collect(),
+            },
+        });
+    }
+}
+
+/// HLEN responds with the number of fields in the hash stored at 'key' as an
+/// Integer, 0 if the key doesn't exist.
+#[derive(Debug)]
+pub struct HLen {
+    key: Bytes,
+}
+
+pub fn parse_hlen(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(HLen { key: key }));
+}
+
+impl CommandHandler for HLen {
+    fn name(&self) -> &'static str {
+        return "hlen";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let len = read_hash(state, client, &self.key, Hash::len)?;
+        return Ok(DataType::Integer {
+            number: len.unwrap_or(0) as isize,
+        });
+    }
+}
+
+/// HEXISTS responds with 1 if 'field' exists in the hash stored at 'key' and 0
+/// otherwise, as an Integer.
+#[derive(Debug)]
+pub struct HExists {
+    key: Bytes,
+    field: Bytes,
+}
+
+pub fn parse_hexists(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let field = get_bytes_or_bad_args!(array, 2);
+    return Ok(Box::new(HExists {
+        key: key,
+        field: field,
+    }));
+}
+
+impl CommandHandler for HExists {
+    fn name(&self) -> &'static str {
+        return "hexists";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let exists = read_hash(state, client, &self.key, |hash| hash.contains(&self.field))?;
+        return Ok(DataType::Integer {
+            number: exists.unwrap_or(false) as isize,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{
+        parse_hdel, parse_hexists, parse_hget, parse_hgetall, parse_hkeys, parse_hlen, parse_hmget,
+        parse_hset, parse_hvals,
+    };
+    use crate::commands::ParseError;
+    use crate::config::Config;
+    use crate::db::DBValue;
+    use crate::errors::ReplyError;
+    use crate::protocol::DataType;
+    use crate::state::{State, StateInner};
+
+    fn args(args: &[&str]) -> Vec<DataType> {
+        return args.iter().map(|arg| DataType::from(*arg)).collect();
+    }
+
+    fn bulks(items: &[&str]) -> DataType {
+        let items = items.iter().map(|item| DataType::bulk(item.to_string()));
+        return DataType::Array {
+            items: items.collect(),
+        };
+    }
+
+    /// Creates a state with the hash `h` = {a: 1, b: 2, c: 3} and the string `s`.
+    fn hash_state() -> State {
+        let state = StateInner::new(Config::default());
+        let hset = parse_hset(&args(&["HSET", "h", "a", "1", "b", "2", "c", "3"])).unwrap();
+        assert_eq!(hset.run(&state, 0), Ok(DataType::Integer { number: 3 }));
+        let value = DBValue::with_expiration(Bytes::from("v"), 0, 0);
+        state.keyspace(0).lock(b"s").insert(Bytes::from("s"), value);
+        return state;
+    }
+
+    #[test]
+    fn test_hset() {
+        let state = hash_state();
+        let run = |command: &[&str]| {
+            return parse_hset(&args(command)).unwrap().run(&state, 0);
+        };
+
+        assert_eq!(
+            run(&["HSET", "h", "c", "4", "d", "5"]),
+            Ok(DataType::Integer { number: 1 })
+        );
+        assert_eq!(
+            run(&["HSET", "new", "a", "1"]),
+            Ok(DataType::Integer { number: 1 })
+        );
+        let hget = parse_hget(&args(&["HGET", "h", "c"])).unwrap();
+        assert_eq!(hget.run(&state, 0), Ok(DataType::bulk("4")));
+        assert_eq!(run(&["HSET", "s", "a", "1"]), Err(ReplyError::WrongType));
+    }
+
+    #[test]
+    fn test_hset_arity() {
+        for command in [
+            &["HSET"][..],
+            &["HSET", "h"],
+            &["HSET", "h", "a"],
+            &["HSET", "h", "a", "1", "b"],
+        ] {
+            let err = parse_hset(&args(command)).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ParseError>().unwrap().to_string(),
+                "wrong number of arguments for 'hset' command",
+                "{command:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_hget() {
+        let state = hash_state();
+        let run = |command: &[&str]| {
+            return parse_hget(&args(command)).unwrap().run(&state, 0);
+        };
+
+        assert_eq!(run(&["HGET", "h", "b"]), Ok(DataType::bulk("2")));
+        assert_eq!(run(&["HGET", "h", "x"]), Ok(DataType::NullBulkString));
+        assert_eq!(run(&["HGET", "missing", "a"]), Ok(DataType::NullBulkString));
+        assert_eq!(run(&["HGET", "s", "a"]), Err(ReplyError::WrongType));
+    }
+
+    #[test]
+    fn test_hmget() {
+        let state = hash_state();
+        let run = |command: &[&str]| {
+            return parse_hmget(&args(command)).unwrap().run(&state, 0);
+        };
+
+        assert_eq!(
+            run(&["HMGET", "h", "a", "x", "c"]),
+            Ok(DataType::Array {
+                items: vec![
+                    DataType::bulk("1"),
+                    DataType::NullBulkString,
+                    DataType::bulk("3")
+                ]
+            })
+        );
+        assert_eq!(
+            run(&["HMGET", "missing", "a", "b"]),
+            Ok(DataType::Array {
+                items: vec![DataType::NullBulkString, DataType::NullBulkString]
+            })
+        );
+        assert_eq!(run(&["HMGET", "s", "a"]), Err(ReplyError::WrongType));
+    }
+
+    #[test]
+    fn test_hdel() {
+        let state = hash_state();
+        let run = |command: &[&str]| {
+            return parse_hdel(&args(command)).unwrap().run(&state, 0);
+        };
+
+        assert_eq!(
+            run(&["HDEL", "h", "a", "x"]),
+            Ok(DataType::Integer { number: 1 })
+        );
+        assert_eq!(
+            run(&["HDEL", "h", "a"]),
+            Ok(DataType::Integer { number: 0 })
+        );
+        assert_eq!(
+            run(&["HDEL", "missing", "a"]),
+            Ok(DataType::Integer { number: 0 })
+        );
+        assert_eq!(run(&["HDEL", "s", "a"]), Err(ReplyError::WrongType));
+        assert!(state.keyspace(0).lock(b"h").get(b"h").is_some());
+
+        // removing the last field removes the key
+        assert_eq!(
+            run(&["HDEL", "h", "b", "c"]),
+            Ok(DataType::Integer { number: 2 })
+        );
+        assert!(state.keyspace(0).lock(b"h").get(b"h").is_none());
+        let hlen = parse_hlen(&args(&["HLEN", "h"])).unwrap();
+        assert_eq!(hlen.run(&state, 0), Ok(DataType::Integer { number: 0 }));
+    }
+
+    #[test]
+    fn test_hgetall() {
+        let state = hash_state();
+        let run = |command: &[&str]| {
+            return parse_hgetall(&args(command)).unwrap().run(&state, 0);
+        };
+
+        let map = DataType::Map {
+            items: vec![
+                (DataType::bulk("a"), DataType::bulk("1")),
+                (DataType::bulk("b"), DataType::bulk("2")),
+                (DataType::bulk("c"), DataType::bulk("3")),
+            ],
+        };
+        let reply = run(&["HGETALL", "h"]).unwrap();
+        assert_eq!(reply.clone().for_protocol(3), map);
+        assert_eq!(
+            reply.for_protocol(2),
+            bulks(&["a", "1", "b", "2", "c", "3"])
+        );
+
+        let empty = run(&["HGETALL", "missing"]).unwrap();
+        assert_eq!(
+            empty.clone().for_protocol(3),
+            DataType::Map { items: Vec::new() }
+        );
+        assert_eq!(empty.for_protocol(2), bulks(&[]));
+        assert_eq!(run(&["HGETALL", "s"]), Err(ReplyError::WrongType));
+    }
+
+    #[test]
+    fn test_hkeys_hvals() {
+        let state = hash_state();
+        let hkeys = |key: &str| {
+            return parse_hkeys(&args(&["HKEYS", key])).unwrap().run(&state, 0);
+        };
+        let hvals = |key: &str| {
+            return parse_hvals(&args(&["HVALS", key])).unwrap().run(&state, 0);
+        };
+
+        assert_eq!(hkeys("h"), Ok(bulks(&["a", "b", "c"])));
+        assert_eq!(hvals("h"), Ok(bulks(&["1", "2", "3"])));
+        assert_eq!(hkeys("missing"), Ok(bulks(&[])));
+        assert_eq!(hvals("missing"), Ok(bulks(&[])));
+        assert_eq!(hkeys("s"), Err(ReplyError::WrongType));
+        assert_eq!(hvals("s"), Err(ReplyError::WrongType));
+    }
+
+    #[test]
+    fn test_hlen() {
+        let state = hash_state();
+        let run = |key: &str| {
+            return parse_hlen(&args(&["HLEN", key])).unwrap().run(&state, 0);
+        };
+
+        assert_eq!(run("h"), Ok(DataType::Integer { number: 3 }));
+        assert_eq!(run("missing"), Ok(DataType::Integer { number: 0 }));
+        assert_eq!(run("s"), Err(ReplyError::WrongType));
+    }
+
+    #[test]
+    fn test_hexists() {
+        let state = hash_state();
+        let run = |key: &str, field: &str| {
+            let command = args(&["HEXISTS", key, field]);
+            return parse_hexists(&command).unwrap().run(&state, 0);
+        };
+
+        assert_eq!(run("h", "a"), Ok(DataType::Integer { number: 1 }));
+        assert_eq!(run("h", "x"), Ok(DataType::Integer { number: 0 }));
+        assert_eq!(run("missing", "a"), Ok(DataType::Integer { number: 0 }));
+        assert_eq!(run("s", "a"), Err(ReplyError::WrongType));
+    }
+}
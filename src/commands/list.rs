@@ -0,0 +1,1103 @@
+/// Commands operating on list values.
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use super::{get_bytes, parse_yes_no, CommandHandler, ExpiredKeys, ParseError};
+use crate::clients::ClientId;
+use crate::db::{DBValue, MapInner, ShardGuards};
+use crate::errors::ReplyError;
+use crate::listpack::ListpackLimits;
+use crate::protocol::DataType;
+use crate::state::State;
+use crate::tracking;
+use crate::value::{List, Value};
+
+/// Position of the element at `index` of a list of `len` elements, negative indexes
+/// counting from the end. `None` if it's out of range.
+fn list_index(len: usize, index: i64) -> Option<usize> {
+    let index = match index < 0 {
+        true => index + len as i64,
+        false => index,
+    };
+    return (0..len as i64).contains(&index).then_some(index as usize);
+}
+
+/// LPUSH inserts the given elements at the head of the list stored at 'key', one
+/// after the other, and RPUSH at its tail. The list is created if the key doesn't
+/// exist. Responds with the length of the list as an Integer.
+#[derive(Debug)]
+pub struct Push {
+    name: &'static str,
+    key: Bytes,
+    elements: Vec<Bytes>,
+    left: bool,
+}
+
+pub fn parse_lpush(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_push_generic(array, "lpush", true);
+}
+
+pub fn parse_rpush(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_push_generic(array, "rpush", false);
+}
+
+fn parse_push_generic(
+    array: &[DataType],
+    name: &'static str,
+    left: bool,
+) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let mut elements = Vec::new();
+    for ix in 2..array.len() {
+        elements.push(get_bytes_or_bad_args!(array, ix));
+    }
+    return Ok(Box::new(Push {
+        name: name,
+        key: key,
+        elements: elements,
+        left: left,
+    }));
+}
+
+/// Pushes `item` to the head of `list` if `left`, to its tail otherwise.
+fn push(list: &mut List, item: Bytes, left: bool, limits: &ListpackLimits) {
+    match left {
+        true => list.push_front(item, limits),
+        false => list.push_back(item, limits),
+    }
+}
+
+impl CommandHandler for Push {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let res = {
+            let limits = state.config.read().unwrap().list_listpack_limits();
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            let push_all = |list: &mut List| {
+                for element in &self.elements {
+                    push(list, element.clone(), self.left, &limits);
+                }
+                return list.len();
+            };
+            let updated = map.update(&self.key, |v| {
+                return Ok(push_all(v.value.as_list_mut()?));
+            });
+            match updated {
+                Some(res) => res,
+                None => {
+                    let mut list = List::new();
+                    let len = push_all(&mut list);
+                    let value = DBValue::with_expiration(Value::List(list), 0, map.now());
+                    map.insert(self.key.clone(), value);
+                    Ok(len)
+                }
+            }
+        };
+        expired.release(state);
+        return Ok(DataType::Integer {
+            number: res? as isize,
+        });
+    }
+}
+
+/// LINDEX responds with the element at 'index' of the list stored at 'key' as a
+/// BulkString, negative indexes counting from the end. NullBulkString if the index
+/// is out of range or the key doesn't exist.
+#[derive(Debug)]
+pub struct LIndex {
+    key: Bytes,
+    index: i64,
+}
+
+pub fn parse_lindex(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let index = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    return Ok(Box::new(LIndex {
+        key: key,
+        index: index,
+    }));
+}
+
+impl CommandHandler for LIndex {
+    fn name(&self) -> &'static str {
+        return "lindex";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let found = {
+            let config = state.config.read().unwrap();
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            map.lookup(&self.key, &config).map(|v| {
+                let list = v.value.as_list()?;
+                let element = list_index(list.len(), self.index).and_then(|ix| list.get(ix));
+                return Ok(element.map(Bytes::copy_from_slice));
+            })
+        };
+        expired.release(state);
+        state.stats.record_keyspace_lookup(found.is_some());
+        return match found.transpose()?.flatten() {
+            Some(element) => Ok(DataType::bulk(element)),
+            None => Ok(DataType::NullBulkString),
+        };
+    }
+}
+
+/// LSET replaces the element at 'index' of the list stored at 'key', negative
+/// indexes counting from the end. Responds "OK", or fails if the key doesn't exist
+/// or the index is out of range.
+#[derive(Debug)]
+pub struct LSet {
+    key: Bytes,
+    index: i64,
+    element: Bytes,
+}
+
+pub fn parse_lset(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let index = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    let element = get_bytes_or_bad_args!(array, 3);
+    return Ok(Box::new(LSet {
+        key: key,
+        index: index,
+        element: element,
+    }));
+}
+
+impl CommandHandler for LSet {
+    fn name(&self) -> &'static str {
+        return "lset";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let res = {
+            let limits = state.config.read().unwrap().list_listpack_limits();
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            map.update(&self.key, |v| {
+                let list = v.value.as_list_mut()?;
+                let replaced = list_index(list.len(), self.index)
+                    .is_some_and(|ix| list.set(ix, self.element.clone(), &limits));
+                if !replaced {
+                    return Err(ReplyError::Err("index out of range".to_string()));
+                }
+                return Ok(());
+            })
+        };
+        expired.release(state);
+        match res {
+            Some(res) => res?,
+            None => return Err(ReplyError::Err("no such key".to_string())),
+        }
+        return Ok(DataType::ok());
+    }
+}
+
+/// LREM removes the elements equal to 'element' from the list stored at 'key': the
+/// first 'count' ones if positive, the last 'count' ones if negative, and all of them
+/// if 0. Responds with the number of elements removed as an Integer.
+#[derive(Debug)]
+pub struct LRem {
+    key: Bytes,
+    count: i64,
+    element: Bytes,
+}
+
+pub fn parse_lrem(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let count = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    let element = get_bytes_or_bad_args!(array, 3);
+    return Ok(Box::new(LRem {
+        key: key,
+        count: count,
+        element: element,
+    }));
+}
+
+impl CommandHandler for LRem {
+    fn name(&self) -> &'static str {
+        return "lrem";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let res = {
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            let res = map.update(&self.key, |v| {
+                let list = v.value.as_list_mut()?;
+                let matches = list
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, e)| *e == &self.element[..]);
+                let mut positions: Vec<usize> = matches.map(|(ix, _)| ix).collect();
+                let limit = self.count.unsigned_abs() as usize;
+                if self.count < 0 {
+                    positions.reverse();
+                }
+                if self.count != 0 {
+                    positions.truncate(limit);
+                }
+                positions.sort_unstable();
+                for ix in positions.iter().rev() {
+                    list.remove(*ix);
+                }
+                return Ok((positions.len(), list.is_empty()));
+            });
+            remove_if_empty(&mut map, &self.key, res)
+        };
+        expired.release(state);
+        return Ok(DataType::Integer {
+            number: res?.unwrap_or(0) as isize,
+        });
+    }
+}
+
+/// Removes `key` if the update `res` of its list left it empty, as empty lists
+/// don't exist in the keyspace.
+fn remove_if_empty<T>(
+    map: &mut MapInner,
+    key: &[u8],
+    res: Option<Result<(T, bool), ReplyError>>,
+) -> Result<Option<T>, ReplyError> {
+    return match res {
+        Some(Ok((res, empty))) => {
+            if empty {
+                map.remove(key);
+            }
+            Ok(Some(res))
+        }
+        Some(Err(err)) => Err(err),
+        None => Ok(None),
+    };
+}
+
+/// LTRIM trims the list stored at 'key' to the elements between the 'start' and
+/// 'stop' indexes, both included, negative indexes counting from the end. The key
+/// is deleted if no element is left. Responds "OK".
+#[derive(Debug)]
+pub struct LTrim {
+    key: Bytes,
+    start: i64,
+    stop: i64,
+}
+
+pub fn parse_ltrim(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let start = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    let stop = get_string_or_bad_args!(array, 3)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    return Ok(Box::new(LTrim {
+        key: key,
+        start: start,
+        stop: stop,
+    }));
+}
+
+/// Trims `list` to the elements between `start` and `stop`, both included, like
+/// LTRIM does.
+fn trim(list: &mut List, mut start: i64, mut stop: i64) {
+    let len = list.len() as i64;
+    if start < 0 {
+        start += len;
+    }
+    if stop < 0 {
+        stop += len;
+    }
+    let start = start.max(0);
+    if start > stop || start >= len {
+        list.remove_range(0, len as usize);
+        return;
+    }
+    let stop = stop.min(len - 1);
+    list.remove_range(stop as usize + 1, (len - stop - 1) as usize);
+    list.remove_range(0, start as usize);
+}
+
+impl CommandHandler for LTrim {
+    fn name(&self) -> &'static str {
+        return "ltrim";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let res = {
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            let res = map.update(&self.key, |v| {
+                let list = v.value.as_list_mut()?;
+                trim(list, self.start, self.stop);
+                return Ok(((), list.is_empty()));
+            });
+            remove_if_empty(&mut map, &self.key, res)
+        };
+        expired.release(state);
+        res?;
+        return Ok(DataType::ok());
+    }
+}
+
+/// LINSERT inserts 'element' before or after the first element equal to 'pivot' in
+/// the list stored at 'key'. Responds with the length of the list as an Integer, -1
+/// if 'pivot' wasn't found and 0 if the key doesn't exist.
+#[derive(Debug)]
+pub struct LInsert {
+    key: Bytes,
+    before: bool,
+    pivot: Bytes,
+    element: Bytes,
+}
+
+pub fn parse_linsert(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let before = parse_yes_no(&get_string_or_bad_args!(array, 2), "before", "after")?;
+    let pivot = get_bytes_or_bad_args!(array, 3);
+    let element = get_bytes_or_bad_args!(array, 4);
+    return Ok(Box::new(LInsert {
+        key: key,
+        before: before,
+        pivot: pivot,
+        element: element,
+    }));
+}
+
+impl CommandHandler for LInsert {
+    fn name(&self) -> &'static str {
+        return "linsert";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let res = {
+            let limits = state.config.read().unwrap().list_listpack_limits();
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            map.update(&self.key, |v| {
+                let list = v.value.as_list_mut()?;
+                let pivot = match list.iter().position(|e| e == &self.pivot[..]) {
+                    Some(pivot) => pivot,
+                    None => return Ok(-1),
+                };
+                let ix = if self.before { pivot } else { pivot + 1 };
+                list.insert(ix, self.element.clone(), &limits);
+                return Ok(list.len() as isize);
+            })
+        };
+        expired.release(state);
+        return Ok(DataType::Integer {
+            number: res.transpose()?.unwrap_or(0),
+        });
+    }
+}
+
+/// LPOS responds with the index of the first element equal to 'element' in the list
+/// stored at 'key' as an Integer, or NullBulkString if there is none. RANK skips
+/// the first matches, searching from the end if negative, COUNT responds with an
+/// Array of the indexes of that many matches (all of them if 0) and MAXLEN limits
+/// the elements compared (all of them if 0).
+#[derive(Debug)]
+pub struct LPos {
+    key: Bytes,
+    element: Bytes,
+    rank: i64,
+    count: Option<usize>,
+    maxlen: usize,
+}
+
+pub fn parse_lpos(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let mut lpos = LPos {
+        key: get_bytes_or_bad_args!(array, 1),
+        element: get_bytes_or_bad_args!(array, 2),
+        rank: 1,
+        count: None,
+        maxlen: 0,
+    };
+    for ix in (3..array.len()).step_by(2) {
+        let option = get_string_or_bad_args!(array, ix).to_uppercase();
+        let value: i64 = get_string_or_bad_args!(array, ix + 1)
+            .parse()
+            .map_err(|_| ParseError::NotAnInteger)?;
+        match option.as_str() {
+            "RANK" if value == 0 => bail!(ParseError::ZeroRank),
+            "RANK" => lpos.rank = value,
+            "COUNT" if value < 0 => bail!(ParseError::Negative("COUNT")),
+            "MAXLEN" if value < 0 => bail!(ParseError::Negative("MAXLEN")),
+            "COUNT" => lpos.count = Some(value as usize),
+            "MAXLEN" => lpos.maxlen = value as usize,
+            _ => bail!(ParseError::BadArguments),
+        }
+    }
+    return Ok(Box::new(lpos));
+}
+
+impl LPos {
+    /// Indexes of the matches in `list`, at most `limit` of them unless it's 0.
+    fn positions(&self, list: &List, limit: usize) -> Vec<usize> {
+        let items: Box<dyn Iterator<Item = (usize, &[u8])>> = match self.rank > 0 {
+            true => Box::new(list.iter().enumerate()),
+            false => Box::new(
+                list.iter()
+                    .enumerate()
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev(),
+            ),
+        };
+        let compared = match self.maxlen {
+            0 => list.len(),
+            maxlen => maxlen,
+        };
+        let mut skip = self.rank.unsigned_abs() - 1;
+        let mut positions = Vec::new();
+        for (ix, item) in items.take(compared) {
+            if item != &self.element[..] {
+                continue;
+            }
+            if skip > 0 {
+                skip -= 1;
+                continue;
+            }
+            positions.push(ix);
+            if positions.len() == limit {
+                break;
+            }
+        }
+        return positions;
+    }
+}
+
+impl CommandHandler for LPos {
+    fn name(&self) -> &'static str {
+        return "lpos";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let limit = self.count.unwrap_or(1);
+        let found = {
+            let config = state.config.read().unwrap();
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            map.lookup(&self.key, &config)
+                .map(|v| Ok(self.positions(v.value.as_list()?, limit)))
+        };
+        expired.release(state);
+        state.stats.record_keyspace_lookup(found.is_some());
+        let positions = found.transpose()?.unwrap_or_default();
+        if self.count.is_some() {
+            let items = positions
+                .into_iter()
+                .map(|ix| DataType::Integer {
+                    number: ix as isize,
+                })
+                .collect();
+            return Ok(DataType::Array { items: items });
+        }
+        return match positions.first() {
+            Some(ix) => Ok(DataType::Integer {
+                number: *ix as isize,
+            }),
+            None => Ok(DataType::NullBulkString),
+        };
+    }
+}
+
+/// LMPOP pops up to COUNT elements (1 by default) from the LEFT or RIGHT of the first
+/// non-empty list of 'numkeys' keys. Responds with an Array of the key and the popped
+/// elements, or NullArray if every list is empty. Keys left empty are deleted.
+///
+/// BLMPOP takes a 'timeout' in seconds before 'numkeys': with every list empty the
+/// client blocks until one of them is pushed to, responding NullArray if none was
+/// after 'timeout' (0 waits forever). See `blocked`.
+#[derive(Debug)]
+pub struct MPop {
+    name: &'static str,
+    keys: Vec<Bytes>,
+    left: bool,
+    count: usize,
+    /// how long BLMPOP blocks, `None` to block forever
+    timeout: Option<Duration>,
+}
+
+pub fn parse_lmpop(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_mpop_generic(array, "lmpop", 1, None);
+}
+
+pub fn parse_blmpop(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let timeout: f64 = get_string_or_bad_args!(array, 1)
+        .parse()
+        .map_err(|_| ParseError::InvalidTimeout("not a float or out of range"))?;
+    if !timeout.is_finite() {
+        bail!(ParseError::InvalidTimeout("not a float or out of range"));
+    }
+    if timeout < 0.0 {
+        bail!(ParseError::InvalidTimeout("negative"));
+    }
+    let timeout = match Duration::try_from_secs_f64(timeout) {
+        Ok(timeout) if timeout.is_zero() => None,
+        Ok(timeout) => Some(timeout),
+        Err(_) => bail!(ParseError::InvalidTimeout("out of range")),
+    };
+    return parse_mpop_generic(array, "blmpop", 2, timeout);
+}
+
+/// Parses the arguments of LMPOP, starting at `from` with 'numkeys'.
+fn parse_mpop_generic(
+    array: &[DataType],
+    name: &'static str,
+    from: usize,
+    timeout: Option<Duration>,
+) -> Result<Box<dyn CommandHandler>> {
+    let numkeys: i64 = get_string_or_bad_args!(array, from)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    if numkeys <= 0 {
+        bail!(ParseError::NotPositive("numkeys"));
+    }
+    let direction = from + 1 + numkeys as usize;
+    if direction >= array.len() {
+        bail!(ParseError::BadArguments);
+    }
+    let keys = get_bytes(&array[..direction], from + 1)?;
+    let left = parse_yes_no(&get_string_or_bad_args!(array, direction), "left", "right")?;
+    let count = match &array[direction + 1..] {
+        [] => 1,
+        [option, count]
+            if option
+                .as_string()
+                .is_some_and(|o| o.eq_ignore_ascii_case("count")) =>
+        {
+            let count: i64 = count
+                .as_string()
+                .and_then(|count| count.parse().ok())
+                .ok_or(ParseError::NotAnInteger)?;
+            if count <= 0 {
+                bail!(ParseError::NotPositive("count"));
+            }
+            count as usize
+        }
+        _ => bail!(ParseError::BadArguments),
+    };
+    return Ok(Box::new(MPop {
+        name: name,
+        keys: keys,
+        left: left,
+        count: count,
+        timeout: timeout,
+    }));
+}
+
+impl MPop {
+    /// Pops from the list stored at `key`, `None` if the key doesn't exist.
+    fn pop(
+        &self,
+        state: &State,
+        client: ClientId,
+        key: &Bytes,
+    ) -> Result<Option<Vec<Bytes>>, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let res = {
+            let mut map = keyspace.lock(key);
+            expired.remove_if_expired(&mut map, key);
+            let res = map.update(key, |v| {
+                let list = v.value.as_list_mut()?;
+                let mut popped = Vec::new();
+                while popped.len() < self.count {
+                    let item = match self.left {
+                        true => list.pop_front(),
+                        false => list.pop_back(),
+                    };
+                    match item {
+                        Some(item) => popped.push(item),
+                        None => break,
+                    }
+                }
+                return Ok((popped, list.is_empty()));
+            });
+            remove_if_empty(&mut map, key, res)
+        };
+        expired.release(state);
+        return res;
+    }
+}
+
+impl CommandHandler for MPop {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        for key in &self.keys {
+            let popped = match self.pop(state, client, key)? {
+                Some(popped) => popped,
+                None => continue,
+            };
+            // the keys aren't in the spec, so they aren't invalidated on dispatch
+            tracking::invalidate_keys(state, std::slice::from_ref(key), Some(client));
+            let items = popped.into_iter().map(DataType::bulk).collect();
+            return Ok(DataType::Array {
+                items: vec![
+                    DataType::bulk(key.clone()),
+                    DataType::Array { items: items },
+                ],
+            });
+        }
+        return Ok(DataType::NullArray);
+    }
+
+    fn blocking(&self) -> Option<(&[Bytes], Option<Duration>)> {
+        return match self.name {
+            "blmpop" => Some((&self.keys, self.timeout)),
+            _ => None,
+        };
+    }
+}
+
+/// LMOVE pops an element from the LEFT or RIGHT of the list stored at 'source' and
+/// pushes it to the LEFT or RIGHT of the list stored at 'destination', which is
+/// created if it doesn't exist. Responds with the element as a BulkString, or
+/// NullBulkString if the source doesn't exist. The source is deleted if left empty.
+#[derive(Debug)]
+pub struct LMove {
+    source: Bytes,
+    destination: Bytes,
+    from_left: bool,
+    to_left: bool,
+}
+
+pub fn parse_lmove(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let source = get_bytes_or_bad_args!(array, 1);
+    let destination = get_bytes_or_bad_args!(array, 2);
+    let from_left = parse_yes_no(&get_string_or_bad_args!(array, 3), "left", "right")?;
+    let to_left = parse_yes_no(&get_string_or_bad_args!(array, 4), "left", "right")?;
+    return Ok(Box::new(LMove {
+        source: source,
+        destination: destination,
+        from_left: from_left,
+        to_left: to_left,
+    }));
+}
+
+impl LMove {
+    /// Moves the element with the shards of both keys locked.
+    fn move_locked(
+        &self,
+        maps: &mut ShardGuards,
+        limits: &ListpackLimits,
+    ) -> Result<Option<Bytes>, ReplyError> {
+        // nothing is popped unless the element can be pushed
+        if let Some(v) = maps.get(&self.destination).get(&self.destination) {
+            v.value.as_list()?;
+        }
+        let map = maps.get(&self.source);
+        let res = map.update(&self.source, |v| {
+            let list = v.value.as_list_mut()?;
+            let item = match self.from_left {
+                true => list.pop_front(),
+                false => list.pop_back(),
+            };
+            return Ok((item, list.is_empty()));
+        });
+        let item = match remove_if_empty(map, &self.source, res)?.flatten() {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        let map = maps.get(&self.destination);
+        let pushed = map.update(&self.destination, |v| {
+            push(v.value.as_list_mut()?, item.clone(), self.to_left, limits);
+            return Ok(());
+        });
+        if pushed.is_none() {
+            let mut list = List::new();
+            push(&mut list, item.clone(), self.to_left, limits);
+            let value = DBValue::with_expiration(Value::List(list), 0, map.now());
+            map.insert(self.destination.clone(), value);
+        }
+        return pushed.unwrap_or(Ok(())).map(|()| Some(item));
+    }
+}
+
+impl CommandHandler for LMove {
+    fn name(&self) -> &'static str {
+        return "lmove";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keys = [&self.source, &self.destination];
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let res = {
+            let limits = state.config.read().unwrap().list_listpack_limits();
+            let mut maps = keyspace.lock_keys(&keys);
+            for key in keys {
+                expired.remove_if_expired(maps.get(key), key);
+            }
+            self.move_locked(&mut maps, &limits)
+        };
+        expired.release(state);
+        return Ok(match res? {
+            Some(item) => DataType::bulk(item),
+            None => DataType::NullBulkString,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::{
+        list_index, parse_blmpop, parse_lindex, parse_linsert, parse_lmove, parse_lmpop,
+        parse_lpos, parse_lpush, parse_lrem, parse_lset, parse_ltrim, parse_rpush, trim,
+    };
+    use crate::config::Config;
+    use crate::db::DBValue;
+    use crate::errors::ReplyError;
+    use crate::protocol::DataType;
+    use crate::state::StateInner;
+    use crate::value::{List, Value};
+
+    fn args(args: &[&str]) -> Vec<DataType> {
+        return args.iter().map(|arg| DataType::from(*arg)).collect();
+    }
+
+    fn list(items: &[&str]) -> List {
+        let limits = Config::default().list_listpack_limits();
+        let mut list = List::new();
+        for item in items {
+            list.push_back(Bytes::copy_from_slice(item.as_bytes()), &limits);
+        }
+        return list;
+    }
+
+    fn items(list: &List) -> Vec<String> {
+        return list
+            .iter()
+            .map(|item| String::from_utf8_lossy(item).into_owned())
+            .collect();
+    }
+
+    #[test]
+    fn test_list_index() {
+        assert_eq!(list_index(3, 0), Some(0));
+        assert_eq!(list_index(3, 2), Some(2));
+        assert_eq!(list_index(3, 3), None);
+        assert_eq!(list_index(3, -1), Some(2));
+        assert_eq!(list_index(3, -3), Some(0));
+        assert_eq!(list_index(3, -4), None);
+        assert_eq!(list_index(0, 0), None);
+    }
+
+    #[test]
+    fn test_trim() {
+        let tests: &[(i64, i64, &[&str])] = &[
+            (0, -1, &["a", "b", "c", "d"]),
+            (1, 2, &["b", "c"]),
+            (-2, 100, &["c", "d"]),
+            (-100, 0, &["a"]),
+            (2, 1, &[]),
+            (4, 10, &[]),
+            (-1, -2, &[]),
+        ];
+        for (start, stop, expected) in tests {
+            let mut trimmed = list(&["a", "b", "c", "d"]);
+            trim(&mut trimmed, *start, *stop);
+            assert_eq!(items(&trimmed), *expected, "{start} {stop}");
+        }
+    }
+
+    #[test]
+    fn test_list_editing() {
+        let state = StateInner::new(Config::default());
+        let value = DBValue::with_expiration(Value::List(list(&["a", "b", "a", "c", "a"])), 0, 0);
+        state.keyspace(0).lock(b"l").insert(Bytes::from("l"), value);
+        let value = DBValue::with_expiration(Bytes::from("v"), 0, 0);
+        state.keyspace(0).lock(b"s").insert(Bytes::from("s"), value);
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "LINDEX" => parse_lindex(&args(command)),
+                "LSET" => parse_lset(&args(command)),
+                "LREM" => parse_lrem(&args(command)),
+                "LTRIM" => parse_ltrim(&args(command)),
+                _ => parse_linsert(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0);
+        };
+        let contents = || {
+            let map = state.keyspace(0).lock(b"l");
+            return map.get(b"l").map(|v| items(v.value.as_list().unwrap()));
+        };
+        let integer = |number| Ok(DataType::Integer { number });
+        let error = |message: &str| Err(ReplyError::Err(String::from(message)));
+
+        assert_eq!(run(&["LINDEX", "l", "1"]), Ok(DataType::bulk("b")));
+        assert_eq!(run(&["LINDEX", "l", "-2"]), Ok(DataType::bulk("c")));
+        assert_eq!(run(&["LINDEX", "l", "5"]), Ok(DataType::NullBulkString));
+        assert_eq!(
+            run(&["LINDEX", "missing", "0"]),
+            Ok(DataType::NullBulkString)
+        );
+        assert_eq!(run(&["LINDEX", "s", "0"]), Err(ReplyError::WrongType));
+
+        assert_eq!(run(&["LSET", "l", "-1", "z"]), Ok(DataType::ok()));
+        assert_eq!(run(&["LSET", "l", "5", "z"]), error("index out of range"));
+        assert_eq!(run(&["LSET", "missing", "0", "z"]), error("no such key"));
+        assert_eq!(contents().unwrap(), ["a", "b", "a", "c", "z"]);
+
+        assert_eq!(run(&["LINSERT", "l", "BEFORE", "c", "x"]), integer(6));
+        assert_eq!(run(&["LINSERT", "l", "after", "z", "y"]), integer(7));
+        assert_eq!(run(&["LINSERT", "l", "AFTER", "missing", "y"]), integer(-1));
+        assert_eq!(run(&["LINSERT", "missing", "AFTER", "a", "y"]), integer(0));
+        assert!(parse_linsert(&args(&["LINSERT", "l", "AROUND", "a", "y"])).is_err());
+        assert_eq!(contents().unwrap(), ["a", "b", "a", "x", "c", "z", "y"]);
+
+        assert_eq!(run(&["LREM", "l", "-1", "a"]), integer(1));
+        assert_eq!(contents().unwrap(), ["a", "b", "x", "c", "z", "y"]);
+        run(&["LINSERT", "l", "AFTER", "x", "a"]).unwrap();
+        run(&["LINSERT", "l", "AFTER", "z", "a"]).unwrap();
+        assert_eq!(run(&["LREM", "l", "2", "a"]), integer(2));
+        assert_eq!(contents().unwrap(), ["b", "x", "c", "z", "a", "y"]);
+        run(&["LINSERT", "l", "BEFORE", "b", "a"]).unwrap();
+        assert_eq!(run(&["LREM", "l", "0", "a"]), integer(2));
+        assert_eq!(run(&["LREM", "missing", "0", "a"]), integer(0));
+
+        assert_eq!(run(&["LTRIM", "l", "1", "-2"]), Ok(DataType::ok()));
+        assert_eq!(contents().unwrap(), ["x", "c", "z"]);
+        assert_eq!(run(&["LTRIM", "missing", "0", "1"]), Ok(DataType::ok()));
+        // empty lists are removed
+        assert_eq!(run(&["LTRIM", "l", "5", "10"]), Ok(DataType::ok()));
+        assert_eq!(contents(), None);
+    }
+
+    #[test]
+    fn test_lpos() {
+        let state = StateInner::new(Config::default());
+        let value = DBValue::with_expiration(Value::List(list(&["a", "b", "c", "b", "b"])), 0, 0);
+        state.keyspace(0).lock(b"l").insert(Bytes::from("l"), value);
+        let run = |command: &[&str]| parse_lpos(&args(command)).unwrap().run(&state, 0);
+        let integer = |number| DataType::Integer { number };
+        let integers = |numbers: &[isize]| DataType::Array {
+            items: numbers.iter().map(|number| integer(*number)).collect(),
+        };
+
+        assert_eq!(run(&["LPOS", "l", "b"]), Ok(integer(1)));
+        assert_eq!(run(&["LPOS", "l", "x"]), Ok(DataType::NullBulkString));
+        assert_eq!(run(&["LPOS", "missing", "b"]), Ok(DataType::NullBulkString));
+        assert_eq!(run(&["LPOS", "l", "b", "RANK", "2"]), Ok(integer(3)));
+        assert_eq!(run(&["LPOS", "l", "b", "RANK", "-1"]), Ok(integer(4)));
+        assert_eq!(
+            run(&["LPOS", "l", "b", "RANK", "4"]),
+            Ok(DataType::NullBulkString)
+        );
+        assert_eq!(
+            run(&["LPOS", "l", "b", "COUNT", "0"]),
+            Ok(integers(&[1, 3, 4]))
+        );
+        assert_eq!(
+            run(&["LPOS", "l", "b", "COUNT", "2"]),
+            Ok(integers(&[1, 3]))
+        );
+        assert_eq!(
+            run(&["LPOS", "l", "b", "RANK", "-2", "COUNT", "0"]),
+            Ok(integers(&[3, 1]))
+        );
+        assert_eq!(
+            run(&["LPOS", "l", "b", "COUNT", "0", "MAXLEN", "4"]),
+            Ok(integers(&[1, 3]))
+        );
+        assert_eq!(run(&["LPOS", "l", "x", "COUNT", "0"]), Ok(integers(&[])));
+        assert_eq!(
+            run(&["LPOS", "missing", "x", "COUNT", "0"]),
+            Ok(integers(&[]))
+        );
+
+        for invalid in [
+            &["LPOS", "l", "b", "RANK", "0"][..],
+            &["LPOS", "l", "b", "COUNT", "-1"],
+            &["LPOS", "l", "b", "MAXLEN", "-1"],
+            &["LPOS", "l", "b", "COUNT"],
+            &["LPOS", "l", "b", "FIRST", "1"],
+        ] {
+            assert!(parse_lpos(&args(invalid)).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_mpop() {
+        let state = StateInner::new(Config::default());
+        let value = DBValue::with_expiration(Value::List(list(&["a", "b", "c"])), 0, 0);
+        state.keyspace(0).lock(b"l").insert(Bytes::from("l"), value);
+        let value = DBValue::with_expiration(Bytes::from("v"), 0, 0);
+        state.keyspace(0).lock(b"s").insert(Bytes::from("s"), value);
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "LMPOP" => parse_lmpop(&args(command)),
+                _ => parse_blmpop(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0);
+        };
+        let popped = |key: &str, items: &[&str]| {
+            let items = items.iter().map(|item| DataType::bulk(item.to_string()));
+            return Ok(DataType::Array {
+                items: vec![
+                    DataType::bulk(key.to_string()),
+                    DataType::Array {
+                        items: items.collect(),
+                    },
+                ],
+            });
+        };
+
+        assert_eq!(
+            run(&["LMPOP", "2", "missing", "l", "LEFT"]),
+            popped("l", &["a"])
+        );
+        assert_eq!(
+            run(&["BLMPOP", "0.5", "1", "l", "right", "COUNT", "5"]),
+            popped("l", &["c", "b"])
+        );
+        // empty lists are removed
+        assert!(state.keyspace(0).lock(b"l").get(b"l").is_none());
+        assert_eq!(run(&["LMPOP", "1", "l", "LEFT"]), Ok(DataType::NullArray));
+        assert_eq!(
+            run(&["LMPOP", "1", "s", "LEFT"]),
+            Err(ReplyError::WrongType)
+        );
+
+        for invalid in [
+            &["LMPOP", "0", "l", "LEFT"][..],
+            &["LMPOP", "2", "l", "LEFT"],
+            &["LMPOP", "1", "l", "UP"],
+            &["LMPOP", "1", "l", "LEFT", "COUNT", "0"],
+            &["LMPOP", "1", "l", "LEFT", "COUNT"],
+            &["LMPOP", "1", "l", "LEFT", "COUNT", "1", "COUNT", "1"],
+        ] {
+            assert!(parse_lmpop(&args(invalid)).is_err(), "{invalid:?}");
+        }
+        for timeout in ["-1", "soon", "inf", "1e300"] {
+            let invalid = args(&["BLMPOP", timeout, "1", "l", "LEFT"]);
+            assert!(parse_blmpop(&invalid).is_err(), "{timeout}");
+        }
+    }
+
+    #[test]
+    fn test_blmpop_blocks() {
+        let keys = vec![Bytes::from("a"), Bytes::from("b")];
+        let blocking = |command: &[&str]| {
+            let handler = match command[0] {
+                "LMPOP" => parse_lmpop(&args(command)),
+                _ => parse_blmpop(&args(command)),
+            };
+            let handler = handler.unwrap();
+            return handler
+                .blocking()
+                .map(|(keys, timeout)| (keys.to_vec(), timeout));
+        };
+
+        // blocking itself is up to the caller, see `commands::try_dispatch`
+        assert_eq!(
+            blocking(&["BLMPOP", "0", "2", "a", "b", "LEFT"]),
+            Some((keys.clone(), None))
+        );
+        assert_eq!(
+            blocking(&["BLMPOP", "0.25", "2", "a", "b", "LEFT"]),
+            Some((keys.clone(), Some(Duration::from_millis(250))))
+        );
+        assert_eq!(blocking(&["LMPOP", "2", "a", "b", "LEFT"]), None);
+    }
+
+    #[test]
+    fn test_push_and_lmove() {
+        let state = StateInner::new(Config::default());
+        let value = DBValue::with_expiration(Bytes::from("v"), 0, 0);
+        state.keyspace(0).lock(b"s").insert(Bytes::from("s"), value);
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "LPUSH" => parse_lpush(&args(command)),
+                "RPUSH" => parse_rpush(&args(command)),
+                _ => parse_lmove(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0);
+        };
+        let items = |key: &str| -> Vec<Bytes> {
+            let map = state.keyspace(0).lock(key.as_bytes());
+            return match map.get(key.as_bytes()) {
+                Some(v) => v
+                    .value
+                    .as_list()
+                    .unwrap()
+                    .iter()
+                    .map(Bytes::copy_from_slice)
+                    .collect(),
+                None => Vec::new(),
+            };
+        };
+
+        assert_eq!(
+            run(&["RPUSH", "l", "b", "c"]),
+            Ok(DataType::Integer { number: 2 })
+        );
+        assert_eq!(
+            run(&["LPUSH", "l", "a", "z"]),
+            Ok(DataType::Integer { number: 4 })
+        );
+        assert_eq!(items("l"), vec!["z", "a", "b", "c"]);
+        assert_eq!(run(&["LPUSH", "s", "a"]), Err(ReplyError::WrongType));
+
+        assert_eq!(
+            run(&["LMOVE", "l", "m", "LEFT", "RIGHT"]),
+            Ok(DataType::bulk("z"))
+        );
+        assert_eq!(
+            run(&["LMOVE", "l", "l", "RIGHT", "LEFT"]),
+            Ok(DataType::bulk("c"))
+        );
+        assert_eq!(items("l"), vec!["c", "a", "b"]);
+        // nothing is popped if the destination isn't a list
+        assert_eq!(
+            run(&["LMOVE", "l", "s", "LEFT", "LEFT"]),
+            Err(ReplyError::WrongType)
+        );
+        assert_eq!(items("l").len(), 3);
+        assert_eq!(
+            run(&["LMOVE", "missing", "m", "LEFT", "LEFT"]),
+            Ok(DataType::NullBulkString)
+        );
+        assert_eq!(
+            run(&["LMOVE", "m", "n", "LEFT", "LEFT"]),
+            Ok(DataType::bulk("z"))
+        );
+        // the emptied source is removed
+        assert!(state.keyspace(0).lock(b"m").get(b"m").is_none());
+        assert_eq!(items("n"), vec!["z"]);
+        assert!(parse_lmove(&args(&["LMOVE", "a", "b", "UP", "LEFT"])).is_err());
+    }
+}
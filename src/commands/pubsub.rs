@@ -0,0 +1,285 @@
+/// Commands publishing messages and managing the subscriptions of the client.
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use super::{get_strings, CommandHandler, ParseError};
+use crate::clients::ClientId;
+use crate::errors::ReplyError;
+use crate::protocol::DataType;
+use crate::pubsub;
+use crate::state::State;
+
+/// Confirmation of a (un)subscription, sent for each channel.
+fn confirmation(kind: &str, channel: Option<&str>, count: usize) -> DataType {
+    let channel = match channel {
+        Some(channel) => DataType::bulk(channel.to_string()),
+        None => DataType::Null,
+    };
+    return DataType::Push {
+        items: vec![
+            DataType::bulk(kind.to_string()),
+            channel,
+            DataType::Integer {
+                number: count as isize,
+            },
+        ],
+    };
+}
+
+/// Replies every confirmation in `replies`: commands have a single reply, so all
+/// but the last are sent as out-of-band messages, which are written before it.
+fn reply_each(state: &State, client: ClientId, mut replies: Vec<DataType>) -> DataType {
+    let last = replies.pop().expect("at least one reply");
+    state.clients.with_client(client, |c| {
+        for reply in replies {
+            c.send(reply.for_protocol(c.protocol));
+        }
+    });
+    return last;
+}
+
+/// SUBSCRIBE subscribes the client to the given channels, PSUBSCRIBE to the channels
+/// matching the given patterns. Responds a `subscribe` (or `psubscribe`) confirmation
+/// with the number of subscriptions of the client for each of them.
+#[derive(Debug)]
+pub struct Subscribe {
+    name: &'static str,
+    channels: Vec<String>,
+    pattern: bool,
+}
+
+pub fn parse_subscribe(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_subscribe_generic(array, "subscribe", false);
+}
+
+pub fn parse_psubscribe(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_subscribe_generic(array, "psubscribe", true);
+}
+
+fn parse_subscribe_generic(
+    array: &[DataType],
+    name: &'static str,
+    pattern: bool,
+) -> Result<Box<dyn CommandHandler>> {
+    let channels = get_strings(array, 1)?;
+    if channels.is_empty() {
+        bail!(ParseError::WrongArity(name.to_string()));
+    }
+    return Ok(Box::new(Subscribe {
+        name: name,
+        channels: channels,
+        pattern: pattern,
+    }));
+}
+
+impl CommandHandler for Subscribe {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let replies = {
+            let mut pubsub = state.pubsub.lock().unwrap();
+            let mut replies = Vec::new();
+            for channel in &self.channels {
+                let count = pubsub.subscribe(client, channel, self.pattern);
+                replies.push(confirmation(self.name, Some(channel), count));
+            }
+            let count = pubsub.count(client);
+            state
+                .clients
+                .with_client(client, |c| c.subscriptions = count);
+            replies
+        };
+        return Ok(reply_each(state, client, replies));
+    }
+}
+
+/// UNSUBSCRIBE unsubscribes the client from the given channels, or from all of them
+/// if none is given, PUNSUBSCRIBE from patterns. Responds an `unsubscribe` (or
+/// `punsubscribe`) confirmation with the number of subscriptions left for each
+/// of them, a single one with a Null channel if there were none.
+#[derive(Debug)]
+pub struct Unsubscribe {
+    name: &'static str,
+    channels: Vec<String>,
+    pattern: bool,
+}
+
+pub fn parse_unsubscribe(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_unsubscribe_generic(array, "unsubscribe", false);
+}
+
+pub fn parse_punsubscribe(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_unsubscribe_generic(array, "punsubscribe", true);
+}
+
+fn parse_unsubscribe_generic(
+    array: &[DataType],
+    name: &'static str,
+    pattern: bool,
+) -> Result<Box<dyn CommandHandler>> {
+    return Ok(Box::new(Unsubscribe {
+        name: name,
+        channels: get_strings(array, 1)?,
+        pattern: pattern,
+    }));
+}
+
+impl CommandHandler for Unsubscribe {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let replies = {
+            let mut pubsub = state.pubsub.lock().unwrap();
+            let channels = match self.channels.is_empty() {
+                true => pubsub.subscribed(client, self.pattern),
+                false => self.channels.clone(),
+            };
+            let mut replies = Vec::new();
+            for channel in &channels {
+                let count = pubsub.unsubscribe(client, channel, self.pattern);
+                replies.push(confirmation(self.name, Some(channel), count));
+            }
+            let count = pubsub.count(client);
+            if replies.is_empty() {
+                replies.push(confirmation(self.name, None, count));
+            }
+            state
+                .clients
+                .with_client(client, |c| c.subscriptions = count);
+            replies
+        };
+        return Ok(reply_each(state, client, replies));
+    }
+}
+
+/// PUBLISH sends 'message' to the clients subscribed to 'channel', responds with the
+/// number of clients that received it as an Integer.
+#[derive(Debug)]
+pub struct Publish {
+    channel: String,
+    message: Bytes,
+}
+
+pub fn parse_publish(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let channel = get_string_or_bad_args!(array, 1);
+    let message = get_bytes_or_bad_args!(array, 2);
+    return Ok(Box::new(Publish {
+        channel: channel,
+        message: message,
+    }));
+}
+
+impl CommandHandler for Publish {
+    fn name(&self) -> &'static str {
+        return "publish";
+    }
+
+    fn run(&self, state: &State, _client: ClientId) -> Result<DataType, ReplyError> {
+        let receivers = pubsub::publish(state, &self.channel, self.message.clone());
+        return Ok(DataType::Integer {
+            number: receivers as isize,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_psubscribe, parse_publish, parse_subscribe, parse_unsubscribe};
+    use crate::config::Config;
+    use crate::protocol::DataType;
+    use crate::state::StateInner;
+
+    fn args(args: &[&str]) -> Vec<DataType> {
+        return args.iter().map(|arg| DataType::from(*arg)).collect();
+    }
+
+    fn confirmation(kind: &str, channel: Option<&str>, count: isize) -> DataType {
+        let channel = match channel {
+            Some(channel) => DataType::bulk(channel.to_string()),
+            None => DataType::Null,
+        };
+        return DataType::Push {
+            items: vec![
+                DataType::bulk(kind.to_string()),
+                channel,
+                DataType::Integer { number: count },
+            ],
+        };
+    }
+
+    #[test]
+    fn test_subscribe_unsubscribe() {
+        let state = StateInner::new(Config::default());
+        let mut client = state.connect_client().unwrap();
+        let id = client.id;
+        let run = |command: &[&str]| {
+            let parse = match command[0] {
+                "SUBSCRIBE" => parse_subscribe,
+                "PSUBSCRIBE" => parse_psubscribe,
+                _ => parse_unsubscribe,
+            };
+            return parse(&args(command)).unwrap().run(&state, id);
+        };
+        let subscriptions = || state.clients.with_client(id, |c| c.subscriptions);
+
+        // every confirmation but the last is sent as a message
+        assert_eq!(
+            run(&["SUBSCRIBE", "a", "b"]),
+            Ok(confirmation("subscribe", Some("b"), 2))
+        );
+        let message = client.messages.try_recv().unwrap();
+        assert_eq!(
+            message,
+            confirmation("subscribe", Some("a"), 1).for_protocol(2)
+        );
+        assert_eq!(
+            run(&["PSUBSCRIBE", "c*"]),
+            Ok(confirmation("psubscribe", Some("c*"), 3))
+        );
+        assert_eq!(subscriptions(), Some(3));
+
+        assert_eq!(
+            run(&["UNSUBSCRIBE", "a", "x"]),
+            Ok(confirmation("unsubscribe", Some("x"), 2))
+        );
+        assert!(client.messages.try_recv().is_ok());
+        assert_eq!(
+            run(&["UNSUBSCRIBE"]),
+            Ok(confirmation("unsubscribe", Some("b"), 1))
+        );
+        assert_eq!(
+            run(&["UNSUBSCRIBE"]),
+            Ok(confirmation("unsubscribe", None, 1))
+        );
+        assert_eq!(subscriptions(), Some(1));
+        assert!(parse_subscribe(&args(&["SUBSCRIBE"])).is_err());
+    }
+
+    #[test]
+    fn test_publish() {
+        let state = StateInner::new(Config::default());
+        let mut subscriber = state.connect_client().unwrap();
+        let subscribe = parse_subscribe(&args(&["SUBSCRIBE", "news"])).unwrap();
+        subscribe.run(&state, subscriber.id).unwrap();
+
+        let publish = parse_publish(&args(&["PUBLISH", "news", "hi"])).unwrap();
+        assert_eq!(publish.run(&state, 0), Ok(DataType::Integer { number: 1 }));
+        let message = subscriber.messages.try_recv().unwrap();
+        assert_eq!(
+            message,
+            DataType::Array {
+                items: vec![
+                    DataType::bulk("message"),
+                    DataType::bulk("news"),
+                    DataType::bulk("hi")
+                ]
+            }
+        );
+        let publish = parse_publish(&args(&["PUBLISH", "other", "hi"])).unwrap();
+        assert_eq!(publish.run(&state, 0), Ok(DataType::Integer { number: 0 }));
+    }
+}
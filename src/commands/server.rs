@@ -0,0 +1,880 @@
+/// Commands reporting on and configuring the server.
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use super::{get_strings, parse_yes_no, CommandHandler, ParseError};
+use crate::clients::ClientId;
+use crate::command_table::{self, CommandSpec};
+use crate::config;
+use crate::db::{self, Keyspace, KeyspaceStats, MemoryStats};
+use crate::errors::ReplyError;
+use crate::export;
+use crate::glob;
+use crate::protocol::DataType;
+use crate::state::State;
+use crate::tracking;
+
+#[derive(Debug)]
+pub enum CommandSubcommand {
+    All,
+    Count,
+    List,
+    Info { names: Vec<String> },
+    Docs { names: Vec<String> },
+    GetKeys { args: Vec<String> },
+}
+
+/// COMMAND reports metadata from the command table.
+/// - COMMAND / COMMAND INFO [name ...]: Array with the details of every (or the given) command.
+/// - COMMAND COUNT: number of commands as an Integer.
+/// - COMMAND LIST: Array with the name of every command.
+/// - COMMAND DOCS [name ...]: Map of names to their documentation.
+/// - COMMAND GETKEYS <command> [arg ...]: Array with the keys of the given invocation.
+#[derive(Debug)]
+pub struct Command {
+    subcommand: CommandSubcommand,
+}
+
+pub fn parse_command_command(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    if array.len() == 1 {
+        return Ok(Box::new(Command {
+            subcommand: CommandSubcommand::All,
+        }));
+    }
+    let sub = get_string_or_bad_args!(array, 1);
+    let args = get_strings(array, 2)?;
+    let subcommand = match sub.to_uppercase().as_str() {
+        "COUNT" => CommandSubcommand::Count,
+        "LIST" => CommandSubcommand::List,
+        "INFO" => CommandSubcommand::Info { names: args },
+        "DOCS" => CommandSubcommand::Docs { names: args },
+        "GETKEYS" if !args.is_empty() => CommandSubcommand::GetKeys { args },
+        "GETKEYS" => bail!(ParseError::BadArguments),
+        _ => bail!(ParseError::UnknownSubcommand(
+            "COMMAND".to_string(),
+            sub.clone()
+        )),
+    };
+    return Ok(Box::new(Command { subcommand }));
+}
+
+impl CommandHandler for Command {
+    fn name(&self) -> &'static str {
+        return "command";
+    }
+
+    fn run(&self, _state: &State, _client: ClientId) -> Result<DataType, ReplyError> {
+        let error = |error: &str| ReplyError::Err(error.to_string());
+        let response = match &self.subcommand {
+            CommandSubcommand::All => DataType::Array {
+                items: command_table::commands()
+                    .into_iter()
+                    .map(command_info)
+                    .collect(),
+            },
+            CommandSubcommand::Count => DataType::Integer {
+                number: command_table::commands().len() as isize,
+            },
+            CommandSubcommand::List => DataType::Array {
+                items: command_table::commands()
+                    .into_iter()
+                    .map(|spec| DataType::bulk(spec.name))
+                    .collect(),
+            },
+            CommandSubcommand::Info { names } if names.is_empty() => DataType::Array {
+                items: command_table::commands()
+                    .into_iter()
+                    .map(command_info)
+                    .collect(),
+            },
+            CommandSubcommand::Info { names } => DataType::Array {
+                items: names
+                    .iter()
+                    .map(|name| match command_table::lookup(name) {
+                        Some(spec) => command_info(spec),
+                        None => DataType::NullBulkString,
+                    })
+                    .collect(),
+            },
+            CommandSubcommand::Docs { names } => {
+                let specs: Vec<&CommandSpec> = if names.is_empty() {
+                    command_table::commands()
+                } else {
+                    names
+                        .iter()
+                        .filter_map(|name| command_table::lookup(name))
+                        .collect()
+                };
+                let mut items = Vec::new();
+                for spec in specs {
+                    items.push((DataType::bulk(spec.name), command_docs(spec)));
+                }
+                DataType::Map { items }
+            }
+            CommandSubcommand::GetKeys { args } => match command_table::lookup(&args[0]) {
+                None => return Err(error("Invalid command specified")),
+                Some(spec) if !spec.arity_matches(args.len()) => {
+                    return Err(error("Invalid number of arguments specified for command"))
+                }
+                Some(spec) => {
+                    let positions = spec.key_positions(args.len());
+                    if positions.is_empty() {
+                        return Err(error("The command has no key arguments"));
+                    }
+                    DataType::Array {
+                        items: positions
+                            .into_iter()
+                            .map(|pos| DataType::bulk(args[pos].clone()))
+                            .collect(),
+                    }
+                }
+            },
+        };
+        return Ok(response);
+    }
+}
+
+fn command_info(spec: &CommandSpec) -> DataType {
+    let strings = |items: &[&str]| DataType::Array {
+        items: items
+            .iter()
+            .map(|item| DataType::SimpleString {
+                string: item.to_string(),
+            })
+            .collect(),
+    };
+    return DataType::Array {
+        items: vec![
+            DataType::bulk(spec.name),
+            DataType::Integer { number: spec.arity },
+            strings(spec.flags),
+            DataType::Integer {
+                number: spec.first_key,
+            },
+            DataType::Integer {
+                number: spec.last_key,
+            },
+            DataType::Integer { number: spec.step },
+            strings(spec.acl_categories),
+            // tips, key specifications and subcommands
+            DataType::Array { items: vec![] },
+            DataType::Array { items: vec![] },
+            DataType::Array { items: vec![] },
+        ],
+    };
+}
+
+fn command_docs(spec: &CommandSpec) -> DataType {
+    let mut items = Vec::new();
+    for (field, value) in [
+        ("summary", spec.summary),
+        ("since", spec.since),
+        ("group", spec.group),
+    ] {
+        items.push((DataType::bulk(field), DataType::bulk(value)));
+    }
+    return DataType::Map { items };
+}
+
+#[derive(Debug)]
+pub enum LatencySubcommand {
+    Latest,
+    History { event: String },
+    Reset { events: Vec<String> },
+    Doctor,
+}
+
+/// LATENCY reports the latency spikes recorded by the latency monitor.
+/// - LATENCY LATEST: Array of [event, timestamp, latest ms, max ms] for every event.
+/// - LATENCY HISTORY <event>: Array of [timestamp, latency ms] samples.
+/// - LATENCY RESET [event ...]: resets the given events (or all) and responds
+///   with the number of events reset.
+/// - LATENCY DOCTOR: human readable analysis as a VerbatimString.
+#[derive(Debug)]
+pub struct Latency {
+    subcommand: LatencySubcommand,
+}
+
+pub fn parse_latency(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let sub = get_string_or_bad_args!(array, 1);
+    let subcommand = match sub.to_uppercase().as_str() {
+        "LATEST" => LatencySubcommand::Latest,
+        "HISTORY" => LatencySubcommand::History {
+            event: get_string_or_bad_args!(array, 2),
+        },
+        "RESET" => LatencySubcommand::Reset {
+            events: get_strings(array, 2)?,
+        },
+        "DOCTOR" => LatencySubcommand::Doctor,
+        _ => bail!(ParseError::UnknownSubcommand(
+            "LATENCY".to_string(),
+            sub.clone()
+        )),
+    };
+    return Ok(Box::new(Latency { subcommand }));
+}
+
+impl CommandHandler for Latency {
+    fn name(&self) -> &'static str {
+        return "latency";
+    }
+
+    fn run(&self, state: &State, _client: ClientId) -> Result<DataType, ReplyError> {
+        let integer = |n: u64| DataType::Integer { number: n as isize };
+        let response = match &self.subcommand {
+            LatencySubcommand::Latest => DataType::Array {
+                items: state
+                    .latency
+                    .latest()
+                    .into_iter()
+                    .map(|latest| DataType::Array {
+                        items: vec![
+                            DataType::bulk(latest.event),
+                            integer(latest.time),
+                            integer(latest.latest),
+                            integer(latest.max),
+                        ],
+                    })
+                    .collect(),
+            },
+            LatencySubcommand::History { event } => DataType::Array {
+                items: state
+                    .latency
+                    .history(event)
+                    .into_iter()
+                    .map(|sample| DataType::Array {
+                        items: vec![integer(sample.time), integer(sample.latency)],
+                    })
+                    .collect(),
+            },
+            LatencySubcommand::Reset { events } => integer(state.latency.reset(events) as u64),
+            LatencySubcommand::Doctor => {
+                let threshold = state.config.read().unwrap().latency_monitor_threshold;
+                DataType::VerbatimString {
+                    format: String::from("txt"),
+                    string: state.latency.doctor(threshold),
+                }
+            }
+        };
+        return Ok(response);
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigSubcommand {
+    Get { patterns: Vec<String> },
+    Set { pairs: Vec<(String, String)> },
+}
+
+/// CONFIG reads and changes the server configuration at runtime.
+/// - CONFIG GET <pattern> [pattern ...]: Map of matching names to values (a flat Array
+///   of name/value pairs under RESP2).
+/// - CONFIG SET <name> <value> [name value ...]: responds "OK" as a SimpleString.
+#[derive(Debug)]
+pub struct Config {
+    subcommand: ConfigSubcommand,
+}
+
+pub fn parse_config(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let sub = get_string_or_bad_args!(array, 1);
+    let args = get_strings(array, 2)?;
+    let subcommand = match sub.to_uppercase().as_str() {
+        "GET" if !args.is_empty() => ConfigSubcommand::Get { patterns: args },
+        "SET" if !args.is_empty() && args.len() % 2 == 0 => ConfigSubcommand::Set {
+            pairs: args
+                .chunks(2)
+                .map(|pair| (pair[0].to_lowercase(), pair[1].clone()))
+                .collect(),
+        },
+        "GET" | "SET" => bail!(ParseError::BadArguments),
+        _ => bail!(ParseError::UnknownSubcommand(
+            "CONFIG".to_string(),
+            sub.clone()
+        )),
+    };
+    return Ok(Box::new(Config { subcommand }));
+}
+
+impl CommandHandler for Config {
+    fn name(&self) -> &'static str {
+        return "config";
+    }
+
+    fn run(&self, state: &State, _client: ClientId) -> Result<DataType, ReplyError> {
+        let response = match &self.subcommand {
+            ConfigSubcommand::Get { patterns } => {
+                let config = state.config.read().unwrap();
+                let mut items = Vec::new();
+                for name in config::PARAMETERS {
+                    let found = patterns
+                        .iter()
+                        .any(|p| glob::matches(p.as_bytes(), name.as_bytes(), true));
+                    if let (true, Some(value)) = (found, config.get(name)) {
+                        items.push((DataType::bulk(*name), DataType::bulk(value)));
+                    }
+                }
+                DataType::Map { items }
+            }
+            ConfigSubcommand::Set { pairs } => {
+                // apply on a copy so a failing pair leaves the configuration untouched
+                let mut config = state.config.write().unwrap();
+                let mut updated = config.clone();
+                for (name, value) in pairs {
+                    if let Err(err) = updated.set_at_runtime(name, value) {
+                        return Err(ReplyError::Err(err.to_string()));
+                    }
+                }
+                *config = updated;
+                DataType::ok()
+            }
+        };
+        return Ok(response);
+    }
+}
+
+#[derive(Debug)]
+pub enum DebugSubcommand {
+    Export { path: String },
+    Import { path: String },
+}
+
+/// DEBUG runs commands meant for development and testing.
+/// - DEBUG EXPORT <path>: writes the keys, with their types and expirations, to the
+///   file at `path` as JSON, responds "OK".
+/// - DEBUG IMPORT <path>: loads the keys of a file written by DEBUG EXPORT, replacing
+///   the ones that exist, responds "OK".
+#[derive(Debug)]
+pub struct Debug {
+    subcommand: DebugSubcommand,
+}
+
+pub fn parse_debug(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let sub = get_string_or_bad_args!(array, 1);
+    let subcommand = match (sub.to_uppercase().as_str(), array.len()) {
+        ("EXPORT", 3) => DebugSubcommand::Export {
+            path: get_string_or_bad_args!(array, 2),
+        },
+        ("IMPORT", 3) => DebugSubcommand::Import {
+            path: get_string_or_bad_args!(array, 2),
+        },
+        ("EXPORT" | "IMPORT", _) => bail!(ParseError::BadArguments),
+        _ => bail!(ParseError::UnknownSubcommand(
+            "DEBUG".to_string(),
+            sub.clone()
+        )),
+    };
+    return Ok(Box::new(Debug { subcommand }));
+}
+
+impl CommandHandler for Debug {
+    fn name(&self) -> &'static str {
+        return "debug";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        match &self.subcommand {
+            DebugSubcommand::Export { path } => {
+                std::fs::write(path, export::export(state, client))
+                    .map_err(|err| ReplyError::Err(format!("error writing {path}: {err}")))?;
+            }
+            DebugSubcommand::Import { path } => {
+                let data = std::fs::read(path)
+                    .map_err(|err| ReplyError::Err(format!("error reading {path}: {err}")))?;
+                export::import(state, client, &data)
+                    .map_err(|err| ReplyError::Err(format!("error importing {path}: {err}")))?;
+            }
+        }
+        return Ok(DataType::ok());
+    }
+}
+
+#[derive(Debug)]
+pub enum MemorySubcommand {
+    Usage { key: Bytes, samples: usize },
+    Stats,
+    Doctor,
+}
+
+/// MEMORY reports how much memory the keyspace uses.
+/// - MEMORY USAGE <key> [SAMPLES count]: estimated bytes used by the key and its value
+///   as an Integer, NullBulkString if the key doesn't exist.
+/// - MEMORY STATS: Map of memory statistics names to values.
+/// - MEMORY DOCTOR: human readable analysis as a VerbatimString.
+#[derive(Debug)]
+pub struct Memory {
+    subcommand: MemorySubcommand,
+}
+
+/// Default number of elements inspected by MEMORY USAGE on collections.
+const MEMORY_USAGE_DEFAULT_SAMPLES: usize = 5;
+
+pub fn parse_memory(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let sub = get_string_or_bad_args!(array, 1);
+    let subcommand = match sub.to_uppercase().as_str() {
+        "USAGE" => {
+            let key = get_bytes_or_bad_args!(array, 2);
+            let samples = match array.len() {
+                3 => MEMORY_USAGE_DEFAULT_SAMPLES,
+                5 if get_string_or_bad_args!(array, 3).eq_ignore_ascii_case("SAMPLES") => {
+                    get_string_or_bad_args!(array, 4)
+                        .parse()
+                        .map_err(|_| ParseError::NotAnInteger)?
+                }
+                _ => bail!(ParseError::BadArguments),
+            };
+            MemorySubcommand::Usage { key, samples }
+        }
+        "STATS" => MemorySubcommand::Stats,
+        "DOCTOR" => MemorySubcommand::Doctor,
+        _ => bail!(ParseError::UnknownSubcommand(
+            "MEMORY".to_string(),
+            sub.clone()
+        )),
+    };
+    return Ok(Box::new(Memory { subcommand }));
+}
+
+impl CommandHandler for Memory {
+    fn name(&self) -> &'static str {
+        return "memory";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let response = match &self.subcommand {
+            MemorySubcommand::Usage { key, samples } => {
+                let map = keyspace.lock(key);
+                match map.get(key) {
+                    Some(v) if !v.is_expired(map.now()) => DataType::Integer {
+                        number: db::entry_memory_usage(key, v, *samples) as isize,
+                    },
+                    _ => DataType::NullBulkString,
+                }
+            }
+            MemorySubcommand::Stats => {
+                let stats = MemoryStats::from_maps(keyspace.shards());
+                let fields = [
+                    ("total.allocated", stats.total()),
+                    ("overhead.hashtable.main", stats.overhead_hashtable),
+                    ("overhead.total", stats.overhead_hashtable),
+                    ("keys.count", stats.keys_count),
+                    ("keys.bytes-per-key", stats.bytes_per_key()),
+                    ("dataset.bytes", stats.dataset_bytes),
+                ];
+                let mut items = Vec::new();
+                for (name, value) in fields {
+                    items.push((
+                        DataType::bulk(name),
+                        DataType::Integer {
+                            number: value as isize,
+                        },
+                    ));
+                }
+                items.push((
+                    DataType::bulk("dataset.percentage"),
+                    DataType::Double {
+                        number: (stats.dataset_percentage() * 100.0).round() / 100.0,
+                    },
+                ));
+                DataType::Map { items }
+            }
+            MemorySubcommand::Doctor => DataType::VerbatimString {
+                format: String::from("txt"),
+                string: memory_doctor(&MemoryStats::from_maps(keyspace.shards())),
+            },
+        };
+        return Ok(response);
+    }
+}
+
+/// Builds the human readable report returned by MEMORY DOCTOR.
+fn memory_doctor(stats: &MemoryStats) -> String {
+    // below this size the statistics are dominated by fixed overheads
+    const MIN_DATASET: usize = 1024 * 1024 * 5;
+    if stats.total() < MIN_DATASET {
+        return String::from(
+            "Hi Sam, this instance is empty or is using very little memory, my issues \
+             detector can't be used in these conditions. Please, leave for your mission \
+             on Earth and fill it with some data. The new Sam and I will be back to our \
+             programming as soon as I finished rebooting.\n",
+        );
+    }
+    if stats.dataset_percentage() < 50.0 {
+        return format!(
+            "Sam, I detected a few issues in this instance memory implants:\n\n\
+             * High overhead: only {:.2}% of the memory is used by the dataset itself, the \
+             rest is spent in the keyspace hash table ({} bytes per key). This usually \
+             happens with a large number of very small keys, or after many keys were \
+             deleted.\n",
+            stats.dataset_percentage(),
+            stats.bytes_per_key(),
+        );
+    }
+    return String::from(
+        "Hi Sam, I can't find any memory issue in your instance. I can only account \
+         for what occurs on this base.\n",
+    );
+}
+
+/// INFO reports server information and statistics as a BulkString, made of
+/// `# Section` headers followed by `field:value` lines.
+/// Sections can be picked by name, with "default" (or no arguments), "all" and
+/// "everything" selecting groups of sections.
+#[derive(Debug)]
+pub struct Info {
+    sections: Vec<String>,
+}
+
+pub fn parse_info(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let sections = get_strings(array, 1)?
+        .iter()
+        .map(|section| section.to_lowercase())
+        .collect();
+    return Ok(Box::new(Info { sections }));
+}
+
+impl CommandHandler for Info {
+    fn name(&self) -> &'static str {
+        return "info";
+    }
+
+    fn run(&self, state: &State, _client: ClientId) -> Result<DataType, ReplyError> {
+        let selected = |name: &str, default: bool| {
+            if self.sections.is_empty() {
+                return default;
+            }
+            return self.sections.iter().any(|section| match section.as_str() {
+                "default" => default,
+                "all" | "everything" => true,
+                section => section == name,
+            });
+        };
+        let mut reports = Vec::new();
+        for (name, default) in INFO_SECTIONS {
+            if !selected(name, *default) {
+                continue;
+            }
+            let mut lines = vec![format!("# {}{}", name[..1].to_uppercase(), &name[1..])];
+            lines.extend(info_section(state, name));
+            reports.push(lines.join("\r\n") + "\r\n");
+        }
+        let response = DataType::bulk(reports.join("\r\n"));
+        return Ok(response);
+    }
+}
+
+/// INFO sections, in the order they are reported, and whether they are part of
+/// the default sections.
+const INFO_SECTIONS: &[(&str, bool)] = &[
+    ("memory", true),
+    ("stats", true),
+    ("commandstats", false),
+    ("errorstats", true),
+    ("latencystats", false),
+    ("keyspace", true),
+];
+
+fn info_section(state: &State, section: &str) -> Vec<String> {
+    return match section {
+        "memory" => {
+            let used_memory = state.databases[0].used_memory();
+            let config = state.config.read().unwrap();
+            vec![
+                format!("used_memory:{}", used_memory),
+                format!("used_memory_human:{}", bytes_to_human(used_memory)),
+                format!("maxmemory:{}", config.maxmemory),
+                format!(
+                    "maxmemory_human:{}",
+                    bytes_to_human(config.maxmemory as usize)
+                ),
+                format!("maxmemory_policy:{}", config.maxmemory_policy.name()),
+                format!("lazyfree_pending_objects:{}", state.lazyfree.pending()),
+                format!("lazyfreed_objects:{}", state.lazyfree.freed()),
+            ]
+        }
+        "stats" => {
+            let mut lines = state.stats.stats();
+            let tracked = state.tracking.lock().unwrap().len();
+            lines.push(format!("tracking_total_keys:{}", tracked));
+            lines
+        }
+        "commandstats" => state.stats.commandstats(),
+        "errorstats" => state.stats.errorstats(),
+        "latencystats" => state.stats.latencystats(),
+        "keyspace" => {
+            let mut lines = Vec::new();
+            for (db, keyspace) in state.databases.iter().enumerate() {
+                let stats = KeyspaceStats::from_maps(keyspace.shards());
+                if stats.keys == 0 {
+                    continue;
+                }
+                lines.push(format!(
+                    "db{}:keys={},expires={},avg_ttl={}",
+                    db, stats.keys, stats.expires, stats.avg_ttl
+                ));
+            }
+            lines
+        }
+        _ => Vec::new(),
+    };
+}
+
+/// Formats a number of bytes with the largest unit under it, like Redis does.
+fn bytes_to_human(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    return format!("{:.2}{}", value, UNITS[unit]);
+}
+
+/// DBSIZE responds with the number of keys as an Integer.
+#[derive(Debug)]
+pub struct DbSize {}
+
+pub fn parse_dbsize(_array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return Ok(Box::new(DbSize {}));
+}
+
+impl CommandHandler for DbSize {
+    fn name(&self) -> &'static str {
+        return "dbsize";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        return Ok(DataType::Integer {
+            number: keyspace.len() as isize,
+        });
+    }
+}
+
+/// FLUSHDB removes every key of the selected database and FLUSHALL the ones of
+/// every database, both respond "OK". With the ASYNC option the
+/// keys are freed in the background, and with SYNC before responding. Otherwise
+/// `lazyfree-lazy-user-flush` decides.
+#[derive(Debug)]
+pub struct Flush {
+    name: &'static str,
+    /// whether every database is flushed
+    all: bool,
+    /// None follows `lazyfree-lazy-user-flush`
+    lazy: Option<bool>,
+}
+
+pub fn parse_flushdb(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_flush_generic(array, "flushdb", false);
+}
+
+pub fn parse_flushall(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_flush_generic(array, "flushall", true);
+}
+
+fn parse_flush_generic(
+    array: &[DataType],
+    name: &'static str,
+    all: bool,
+) -> Result<Box<dyn CommandHandler>> {
+    let lazy = match array.len() {
+        1 => None,
+        2 => Some(parse_yes_no(
+            &get_string_or_bad_args!(array, 1),
+            "async",
+            "sync",
+        )?),
+        _ => bail!(ParseError::BadArguments),
+    };
+    return Ok(Box::new(Flush {
+        name: name,
+        all: all,
+        lazy: lazy,
+    }));
+}
+
+impl CommandHandler for Flush {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let lazy = self
+            .lazy
+            .unwrap_or_else(|| state.config.read().unwrap().lazyfree_lazy_user_flush);
+        let entries = match self.all {
+            true => state.databases.iter().flat_map(Keyspace::flush).collect(),
+            false => state.keyspace(client).flush(),
+        };
+        tracking::invalidate_all(state);
+        // dropped here unless freed in the background
+        state.lazyfree.free_entries(entries, lazy);
+        return Ok(DataType::ok());
+    }
+}
+
+/// SWAPDB exchanges the keys of two databases, so the clients that selected one see
+/// the keys of the other, responds "OK".
+#[derive(Debug)]
+pub struct SwapDb {
+    first: i64,
+    second: i64,
+}
+
+pub fn parse_swapdb(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let first = get_string_or_bad_args!(array, 1)
+        .parse()
+        .map_err(|_| ParseError::InvalidDbIndex("first"))?;
+    let second = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::InvalidDbIndex("second"))?;
+    return Ok(Box::new(SwapDb {
+        first: first,
+        second: second,
+    }));
+}
+
+impl CommandHandler for SwapDb {
+    fn name(&self) -> &'static str {
+        return "swapdb";
+    }
+
+    fn run(&self, state: &State, _client: ClientId) -> Result<DataType, ReplyError> {
+        let databases = state.databases.len() as i64;
+        if !(0..databases).contains(&self.first) || !(0..databases).contains(&self.second) {
+            return Err(ReplyError::Err("DB index is out of range".to_string()));
+        }
+        let (first, second) = (self.first.min(self.second), self.first.max(self.second));
+        if first == second {
+            return Ok(DataType::ok());
+        }
+        state.databases[first as usize].swap(&state.databases[second as usize]);
+        // the keys now hold the values of the other database
+        tracking::invalidate_all(state);
+        state.blocked.signal_db(first as usize);
+        state.blocked.signal_db(second as usize);
+        return Ok(DataType::ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{
+        bytes_to_human, parse_dbsize, parse_debug, parse_flushall, parse_flushdb, parse_info,
+    };
+    use crate::config::Config;
+    use crate::db::DBValue;
+    use crate::protocol::DataType;
+    use crate::state::StateInner;
+
+    #[test]
+    fn test_bytes_to_human() {
+        assert_eq!(bytes_to_human(0), "0B");
+        assert_eq!(bytes_to_human(1023), "1023B");
+        assert_eq!(bytes_to_human(1536), "1.50K");
+        assert_eq!(bytes_to_human(5 * 1024 * 1024), "5.00M");
+    }
+
+    #[test]
+    fn test_info_memory() {
+        let state = StateInner::new(Config::default());
+        let key = String::from("key");
+        let value = DBValue::with_expiration(bytes::Bytes::from("value"), 0, 0);
+        state
+            .keyspace(0)
+            .lock(key.as_bytes())
+            .insert(Bytes::from(key), value);
+        let info = parse_info(&[DataType::from("INFO"), DataType::from("memory")]).unwrap();
+        let report = match info.run(&state, 0).unwrap() {
+            DataType::BulkString { string } => String::from_utf8(string.to_vec()).unwrap(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        let used_memory = format!("used_memory:{}\r\n", state.keyspace(0).used_memory());
+        assert!(report.starts_with("# Memory\r\n"));
+        assert!(report.contains(&used_memory));
+        assert!(report.contains("maxmemory_policy:noeviction\r\n"));
+    }
+
+    #[test]
+    fn test_debug_export_import() {
+        let path = std::env::temp_dir().join(format!("redis-export-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let debug = |sub: &str| {
+            parse_debug(&[
+                DataType::from("DEBUG"),
+                DataType::from(sub),
+                DataType::from(path),
+            ])
+            .unwrap()
+        };
+        let state = StateInner::new(Config::default());
+        let key = String::from("key");
+        let value = DBValue::with_expiration(bytes::Bytes::from("value"), 0, 0);
+        state
+            .keyspace(0)
+            .lock(key.as_bytes())
+            .insert(Bytes::from(key.clone()), value);
+        assert_eq!(debug("EXPORT").run(&state, 0), Ok(DataType::ok()));
+
+        let copy = StateInner::new(Config::default());
+        assert_eq!(debug("IMPORT").run(&copy, 0), Ok(DataType::ok()));
+        let value = copy
+            .keyspace(0)
+            .lock(key.as_bytes())
+            .get(key.as_bytes())
+            .map(|v| v.value.clone());
+        assert_eq!(value, Some(bytes::Bytes::from("value").into()));
+
+        std::fs::write(path, "[{}]").unwrap();
+        assert!(debug("IMPORT").run(&copy, 0).is_err());
+        std::fs::remove_file(path).unwrap();
+        assert!(debug("IMPORT").run(&copy, 0).is_err());
+        assert!(parse_debug(&[DataType::from("DEBUG"), DataType::from("EXPORT")]).is_err());
+    }
+
+    #[test]
+    fn test_dbsize_flush() {
+        let state = StateInner::new(Config::default());
+        let fill = || {
+            for i in 0..100 {
+                let key = format!("key:{i}");
+                let value = DBValue::with_expiration(bytes::Bytes::from("value"), 0, 0);
+                state
+                    .keyspace(0)
+                    .lock(key.as_bytes())
+                    .insert(Bytes::from(key), value);
+            }
+        };
+        let dbsize = parse_dbsize(&[DataType::from("DBSIZE")]).unwrap();
+        let size = |number| Ok(DataType::Integer { number });
+        assert_eq!(dbsize.run(&state, 0), size(0));
+        fill();
+        assert_eq!(dbsize.run(&state, 0), size(100));
+
+        let flushdb = parse_flushdb(&[DataType::from("FLUSHDB")]).unwrap();
+        assert_eq!(flushdb.run(&state, 0), Ok(DataType::ok()));
+        assert_eq!(dbsize.run(&state, 0), size(0));
+        assert_eq!(state.lazyfree.freed(), 0);
+
+        fill();
+        let flushall = parse_flushall(&[DataType::from("FLUSHALL"), DataType::from("async")]);
+        assert_eq!(flushall.unwrap().run(&state, 0), Ok(DataType::ok()));
+        assert_eq!(dbsize.run(&state, 0), size(0));
+        assert_eq!(state.keyspace(0).used_memory(), 0);
+        let start = std::time::Instant::now();
+        while state.lazyfree.freed() < 100 {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let flush = |arg: &str| parse_flushall(&[DataType::from("FLUSHALL"), DataType::from(arg)]);
+        assert!(flush("SYNC").is_ok());
+        assert!(flush("LATER").is_err());
+    }
+}
@@ -0,0 +1,1217 @@
+/// Commands operating on string values.
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+
+use super::{get_bytes, CommandHandler, ExpiredKeys, ParseError};
+use crate::clients::ClientId;
+use crate::db::{DBValue, MapInner};
+use crate::errors::ReplyError;
+use crate::protocol::DataType;
+use crate::state::State;
+use crate::value::{Str, Value};
+
+/// Expiration given to a key by SET and GETEX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expiration {
+    /// keeps the current expiration of the key
+    Keep,
+    /// removes the expiration of the key
+    Persist,
+    /// milliseconds from now
+    In(i64),
+    /// unix time in milliseconds
+    At(i64),
+}
+
+impl Expiration {
+    /// Expiration timestamp (ms) given `current`, 0 being no expiration. `None` if
+    /// it's too far in the future to be represented.
+    fn timestamp(&self, current: usize, now: usize) -> Option<usize> {
+        return match self {
+            Expiration::Keep => Some(current),
+            Expiration::Persist => Some(0),
+            Expiration::In(ms) => (now as i64).checked_add(*ms).map(|at| at as usize),
+            Expiration::At(ms) => Some(*ms as usize),
+        };
+    }
+}
+
+/// Replied when the expiration of a key would overflow.
+fn invalid_expire_time(name: &str) -> ReplyError {
+    return ReplyError::Err(ParseError::InvalidExpireTime(name.to_string()).to_string());
+}
+
+/// Parses the EX, PX, EXAT and PXAT options, `option` being one of them and `arg`
+/// the argument following it.
+fn parse_expiration(option: &str, arg: Option<&DataType>, name: &str) -> Result<Expiration> {
+    let time: i64 = match arg.and_then(DataType::as_string) {
+        Some(time) => time.parse().map_err(|_| ParseError::NotAnInteger)?,
+        None => bail!(ParseError::BadArguments),
+    };
+    let unit = match option {
+        "EX" | "EXAT" => 1000,
+        _ => 1,
+    };
+    let ms = match time.checked_mul(unit) {
+        Some(ms) if time > 0 => ms,
+        _ => bail!(ParseError::InvalidExpireTime(name.to_string())),
+    };
+    return match option {
+        "EX" | "PX" => Ok(Expiration::In(ms)),
+        _ => Ok(Expiration::At(ms)),
+    };
+}
+
+/// SET stores 'value' under 'key' in the in-memory database, replacing any value of
+/// any type stored there.
+/// The EX, PX, EXAT and PXAT options set the expiration of the key like EXPIRE and
+/// friends. Otherwise its expiration is removed, unless the KEEPTTL option is given.
+/// The NX and XX options only set the key if it doesn't exist or if it exists.
+/// Responds "OK" as a SimpleString if the key was set, and a NullBulkString if the
+/// NX or XX options prevented it. With the GET option, responds with the old string
+/// value as a BulkString instead, or a NullBulkString if there was none.
+/// Values longer than `string-compression-threshold` are stored compressed.
+/// GETSET is SET with the GET option.
+#[derive(Debug)]
+pub struct Set {
+    name: &'static str,
+    key: Bytes,
+    value: Bytes,
+    expiration: Expiration,
+    nx: bool,
+    xx: bool,
+    get: bool,
+}
+
+pub fn parse_set(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let value = get_bytes_or_bad_args!(array, 2);
+    let mut set = Set {
+        name: "set",
+        key: key,
+        value: value,
+        expiration: Expiration::Persist,
+        nx: false,
+        xx: false,
+        get: false,
+    };
+    let mut ix = 3;
+    while ix < array.len() {
+        let option = get_string_or_bad_args!(array, ix).to_uppercase();
+        let no_expiration = set.expiration == Expiration::Persist;
+        match option.as_str() {
+            "NX" if !set.xx => set.nx = true,
+            "XX" if !set.nx => set.xx = true,
+            "GET" => set.get = true,
+            "KEEPTTL" if no_expiration => set.expiration = Expiration::Keep,
+            "EX" | "PX" | "EXAT" | "PXAT" if no_expiration => {
+                set.expiration = parse_expiration(&option, array.get(ix + 1), "set")?;
+                ix += 1;
+            }
+            _ => bail!(ParseError::BadArguments),
+        }
+        ix += 1;
+    }
+    return Ok(Box::new(set));
+}
+
+pub fn parse_getset(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let value = get_bytes_or_bad_args!(array, 2);
+    return Ok(Box::new(Set {
+        name: "getset",
+        key: key,
+        value: value,
+        expiration: Expiration::Persist,
+        nx: false,
+        xx: false,
+        get: true,
+    }));
+}
+
+impl Set {
+    /// Stores `string` in `map` if the options allow it, returning the old string if
+    /// the GET option was given and whether it was stored. The value replaced is left
+    /// in `replaced` to be freed once the lock is released.
+    fn store(
+        &self,
+        map: &mut MapInner,
+        string: Str,
+        replaced: &mut Option<DBValue>,
+    ) -> Result<(Option<Bytes>, bool), ReplyError> {
+        let now = map.now();
+        let (old_string, current) = match map.get(&self.key) {
+            // SET overwrites keys of any type, but GET only returns strings
+            Some(old) if self.get => (Some(old.value.as_str()?.to_bytes()), Some(old.expiration)),
+            Some(old) => (None, Some(old.expiration)),
+            None => (None, None),
+        };
+        let expiration = self
+            .expiration
+            .timestamp(current.unwrap_or(0), now)
+            .ok_or_else(|| invalid_expire_time(self.name))?;
+        if (self.nx && current.is_some()) || (self.xx && current.is_none()) {
+            return Ok((old_string, false));
+        }
+        let mut value = DBValue::with_expiration(Value::Str(string), 0, now);
+        value.expiration = expiration;
+        *replaced = map.insert(self.key.clone(), value);
+        return Ok((old_string, true));
+    }
+}
+
+impl CommandHandler for Set {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let (lazy, threshold) = {
+            let config = state.config.read().unwrap();
+            (
+                config.lazyfree_lazy_server_del,
+                config.string_compression_threshold,
+            )
+        };
+        // compressed before taking the lock, it's the slow part
+        let string = Str::from(self.value.clone()).compress(threshold);
+        let mut expired = ExpiredKeys::default();
+        let mut replaced = None;
+        let result = {
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            self.store(&mut map, string, &mut replaced)
+        };
+        expired.release(state);
+        if let Some(replaced) = replaced {
+            state.lazyfree.free(replaced, lazy);
+        }
+        let response = match result? {
+            (Some(old_string), _) => DataType::bulk(old_string),
+            (None, true) if !self.get => DataType::ok(),
+            (None, _) => DataType::NullBulkString,
+        };
+        return Ok(response);
+    }
+}
+
+/// GET returns the value of 'key' in the in-memory database as a BulkString .
+/// If the key is not set or expired, responds with a NullBulkString.
+/// The reply shares the stored bytes instead of copying them, so reading big values
+/// costs the same as reading small ones until they are written to the connection.
+/// Compressed values are the exception, they are decompressed on every read.
+#[derive(Debug)]
+pub struct Get {
+    key: Bytes,
+}
+
+pub fn parse_get(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(Get { key: key }));
+}
+
+impl CommandHandler for Get {
+    fn name(&self) -> &'static str {
+        return "get";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let key = &self.key;
+        let mut expired = ExpiredKeys::default();
+        let found = {
+            let config = state.config.read().unwrap();
+            let mut map = keyspace.lock(key);
+            // expired keys are removed lazily when accessed
+            expired.remove_if_expired(&mut map, key);
+            map.lookup(key, &config)
+                .map(|v| v.value.as_str().map(Str::to_bytes))
+        };
+        expired.release(state);
+        state.stats.record_keyspace_lookup(found.is_some());
+        let response = match found {
+            Some(string) => DataType::bulk(string?),
+            None => DataType::NullBulkString {},
+        };
+        return Ok(response);
+    }
+}
+
+/// INCR, DECR, INCRBY and DECRBY add to or subtract from the integer stored in 'key',
+/// responding with the new value as an Integer. Missing keys count as 0.
+/// Fails if the value isn't a string representing a 64 bit signed integer.
+#[derive(Debug)]
+pub struct IncrBy {
+    name: &'static str,
+    key: Bytes,
+    by: i64,
+    decrement: bool,
+}
+
+pub fn parse_incr(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(IncrBy {
+        name: "incr",
+        key: key,
+        by: 1,
+        decrement: false,
+    }));
+}
+
+pub fn parse_decr(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(IncrBy {
+        name: "decr",
+        key: key,
+        by: 1,
+        decrement: true,
+    }));
+}
+
+pub fn parse_incrby(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_incrby_generic(array, "incrby", false);
+}
+
+pub fn parse_decrby(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_incrby_generic(array, "decrby", true);
+}
+
+fn parse_incrby_generic(
+    array: &[DataType],
+    name: &'static str,
+    decrement: bool,
+) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let by = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    return Ok(Box::new(IncrBy {
+        name: name,
+        key: key,
+        by: by,
+        decrement: decrement,
+    }));
+}
+
+impl CommandHandler for IncrBy {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let by = match self.decrement {
+            true => self
+                .by
+                .checked_neg()
+                .ok_or_else(|| ReplyError::Err("decrement would overflow".to_string()))?,
+            false => self.by,
+        };
+        let mut expired = ExpiredKeys::default();
+        let result = {
+            let config = state.config.read().unwrap();
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            // the value is read and written under the same lock
+            match map.lookup(&self.key, &config) {
+                Some(_) => map
+                    .update(&self.key, |v| v.value.as_str_mut()?.incr_by(by))
+                    .unwrap(),
+                None => {
+                    let value = DBValue::with_expiration(Value::Str(Str::Int(by)), 0, map.now());
+                    map.insert(self.key.clone(), value);
+                    Ok(by)
+                }
+            }
+        };
+        expired.release(state);
+        return Ok(DataType::Integer {
+            number: result? as isize,
+        });
+    }
+}
+
+/// GETDEL responds with the string value of 'key' as a BulkString and deletes the
+/// key, or with a NullBulkString if it doesn't exist.
+#[derive(Debug)]
+pub struct GetDel {
+    key: Bytes,
+}
+
+pub fn parse_getdel(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    return Ok(Box::new(GetDel { key: key }));
+}
+
+impl CommandHandler for GetDel {
+    fn name(&self) -> &'static str {
+        return "getdel";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let key = &self.key;
+        let mut expired = ExpiredKeys::default();
+        let mut removed = None;
+        let found = {
+            let mut map = keyspace.lock(key);
+            expired.remove_if_expired(&mut map, key);
+            let found = map.get(key).map(|v| v.value.as_str().map(Str::to_bytes));
+            // only strings are deleted
+            if let Some(Ok(_)) = found {
+                removed = map.remove(key);
+            }
+            found
+        };
+        expired.release(state);
+        state.stats.record_keyspace_lookup(found.is_some());
+        if let Some(removed) = removed {
+            let lazy = state.config.read().unwrap().lazyfree_lazy_server_del;
+            state.lazyfree.free(removed, lazy);
+        }
+        let response = match found {
+            Some(string) => DataType::bulk(string?),
+            None => DataType::NullBulkString {},
+        };
+        return Ok(response);
+    }
+}
+
+/// GETEX responds with the string value of 'key' as a BulkString, or with a
+/// NullBulkString if it doesn't exist, and changes its expiration like SET with the
+/// EX, PX, EXAT, PXAT options. The PERSIST option removes its expiration. Without
+/// options, the expiration is kept.
+#[derive(Debug)]
+pub struct GetEx {
+    key: Bytes,
+    expiration: Expiration,
+}
+
+pub fn parse_getex(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let mut expiration = Expiration::Keep;
+    let mut ix = 2;
+    while ix < array.len() {
+        let option = get_string_or_bad_args!(array, ix).to_uppercase();
+        let no_expiration = expiration == Expiration::Keep;
+        match option.as_str() {
+            "PERSIST" if no_expiration => expiration = Expiration::Persist,
+            "EX" | "PX" | "EXAT" | "PXAT" if no_expiration => {
+                expiration = parse_expiration(&option, array.get(ix + 1), "getex")?;
+                ix += 1;
+            }
+            _ => bail!(ParseError::BadArguments),
+        }
+        ix += 1;
+    }
+    return Ok(Box::new(GetEx {
+        key: key,
+        expiration: expiration,
+    }));
+}
+
+impl CommandHandler for GetEx {
+    fn name(&self) -> &'static str {
+        return "getex";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let key = &self.key;
+        let mut expired = ExpiredKeys::default();
+        let found = {
+            let config = state.config.read().unwrap();
+            let mut map = keyspace.lock(key);
+            expired.remove_if_expired(&mut map, key);
+            let found = map
+                .lookup(key, &config)
+                .map(|v| (v.value.as_str().map(Str::to_bytes), v.expiration));
+            match found {
+                Some((Ok(string), current)) => {
+                    match self.expiration.timestamp(current, map.now()) {
+                        Some(expiration) => {
+                            map.set_expiration(key, expiration);
+                            // keys expiring in the past are removed as expired
+                            expired.remove_if_expired(&mut map, key);
+                            Some(Ok(string))
+                        }
+                        None => Some(Err(invalid_expire_time("getex"))),
+                    }
+                }
+                found => found.map(|(string, _)| string),
+            }
+        };
+        expired.release(state);
+        state.stats.record_keyspace_lookup(found.is_some());
+        let response = match found {
+            Some(string) => DataType::bulk(string?),
+            None => DataType::NullBulkString {},
+        };
+        return Ok(response);
+    }
+}
+
+/// MGET responds with the values of the given keys as an Array of BulkStrings, with
+/// NullBulkStrings for the keys that don't exist or don't hold strings.
+#[derive(Debug)]
+pub struct MGet {
+    keys: Vec<Bytes>,
+}
+
+pub fn parse_mget(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let keys = get_bytes(array, 1)?;
+    return Ok(Box::new(MGet { keys: keys }));
+}
+
+impl CommandHandler for MGet {
+    fn name(&self) -> &'static str {
+        return "mget";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let mut expired = ExpiredKeys::default();
+        let mut found = Vec::with_capacity(self.keys.len());
+        {
+            let config = state.config.read().unwrap();
+            let mut maps = keyspace.lock_keys(&self.keys);
+            for key in &self.keys {
+                let map = maps.get(key);
+                expired.remove_if_expired(map, key);
+                found.push(
+                    map.lookup(key, &config)
+                        .map(|v| v.value.as_str().ok().map(Str::to_bytes)),
+                );
+            }
+        }
+        expired.release(state);
+        let items = found
+            .into_iter()
+            .map(|string| {
+                state.stats.record_keyspace_lookup(string.is_some());
+                return match string.flatten() {
+                    Some(string) => DataType::bulk(string),
+                    None => DataType::NullBulkString,
+                };
+            })
+            .collect();
+        return Ok(DataType::Array { items: items });
+    }
+}
+
+/// MSET stores each 'value' under its 'key', replacing existing values and their
+/// expirations, and responds "OK" as a SimpleString. MSETNX only stores them if
+/// none of the keys exist, responding with 1 as an Integer if they were stored and 0
+/// otherwise. Either way every key is set or none is, no client sees only some.
+#[derive(Debug)]
+pub struct MSet {
+    name: &'static str,
+    keys: Vec<Bytes>,
+    values: Vec<Bytes>,
+    only_new: bool,
+}
+
+pub fn parse_mset(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_mset_generic(array, "mset", false);
+}
+
+pub fn parse_msetnx(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    return parse_mset_generic(array, "msetnx", true);
+}
+
+fn parse_mset_generic(
+    array: &[DataType],
+    name: &'static str,
+    only_new: bool,
+) -> Result<Box<dyn CommandHandler>> {
+    if array.len() < 3 || array.len().is_multiple_of(2) {
+        bail!(ParseError::WrongArity(name.to_string()));
+    }
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for ix in (1..array.len()).step_by(2) {
+        keys.push(get_bytes_or_bad_args!(array, ix));
+        values.push(get_bytes_or_bad_args!(array, ix + 1));
+    }
+    return Ok(Box::new(MSet {
+        name: name,
+        keys: keys,
+        values: values,
+        only_new: only_new,
+    }));
+}
+
+impl CommandHandler for MSet {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let (lazy, threshold) = {
+            let config = state.config.read().unwrap();
+            (
+                config.lazyfree_lazy_server_del,
+                config.string_compression_threshold,
+            )
+        };
+        let strings: Vec<Str> = self
+            .values
+            .iter()
+            .map(|value| Str::from(value.clone()).compress(threshold))
+            .collect();
+        let mut expired = ExpiredKeys::default();
+        let mut old_values = Vec::new();
+        let stored = {
+            let mut maps = keyspace.lock_keys(&self.keys);
+            for key in &self.keys {
+                expired.remove_if_expired(maps.get(key), key);
+            }
+            let exists = self.keys.iter().any(|key| maps.get(key).get(key).is_some());
+            if self.only_new && exists {
+                false
+            } else {
+                for (key, string) in self.keys.iter().zip(strings) {
+                    let map = maps.get(key);
+                    let value = DBValue::with_expiration(Value::Str(string), 0, map.now());
+                    old_values.extend(map.insert(key.clone(), value));
+                }
+                true
+            }
+        };
+        expired.release(state);
+        for value in old_values {
+            state.lazyfree.free(value, lazy);
+        }
+        return match self.only_new {
+            true => Ok(DataType::Integer {
+                number: stored as isize,
+            }),
+            false => Ok(DataType::ok()),
+        };
+    }
+}
+
+/// GETRANGE responds with the bytes of the string at 'key' between the 'start' and
+/// 'end' offsets, both included, as a BulkString. Negative offsets count from the
+/// end of the string. Missing keys are empty strings.
+#[derive(Debug)]
+pub struct GetRange {
+    key: Bytes,
+    start: i64,
+    end: i64,
+}
+
+pub fn parse_getrange(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let start = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    let end = get_string_or_bad_args!(array, 3)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    return Ok(Box::new(GetRange {
+        key: key,
+        start: start,
+        end: end,
+    }));
+}
+
+/// Byte range of a string of `len` bytes selected by the GETRANGE offsets.
+fn string_range(len: usize, mut start: i64, mut end: i64) -> Option<std::ops::Range<usize>> {
+    let len = len as i64;
+    if start < 0 && end < 0 && start > end {
+        return None;
+    }
+    if start < 0 {
+        start += len;
+    }
+    if end < 0 {
+        end += len;
+    }
+    let start = start.max(0);
+    let end = end.max(0).min(len - 1);
+    if len == 0 || start > end {
+        return None;
+    }
+    return Some(start as usize..end as usize + 1);
+}
+
+impl CommandHandler for GetRange {
+    fn name(&self) -> &'static str {
+        return "getrange";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        let key = &self.key;
+        let mut expired = ExpiredKeys::default();
+        let found = {
+            let config = state.config.read().unwrap();
+            let mut map = keyspace.lock(key);
+            expired.remove_if_expired(&mut map, key);
+            map.lookup(key, &config)
+                .map(|v| v.value.as_str().map(Str::to_bytes))
+        };
+        expired.release(state);
+        state.stats.record_keyspace_lookup(found.is_some());
+        let string = found.unwrap_or(Ok(Bytes::new()))?;
+        let response = match string_range(string.len(), self.start, self.end) {
+            Some(range) => DataType::bulk(string.slice(range)),
+            None => DataType::bulk(Bytes::new()),
+        };
+        return Ok(response);
+    }
+}
+
+/// SETRANGE overwrites the string at 'key' with 'value' starting at 'offset',
+/// padding it with zero bytes if it is shorter than 'offset'. Missing keys are
+/// empty strings. Responds with the length of the string after it was modified as
+/// an Integer.
+#[derive(Debug)]
+pub struct SetRange {
+    key: Bytes,
+    offset: i64,
+    value: Bytes,
+}
+
+pub fn parse_setrange(array: &[DataType]) -> Result<Box<dyn CommandHandler>> {
+    let key = get_bytes_or_bad_args!(array, 1);
+    let offset = get_string_or_bad_args!(array, 2)
+        .parse()
+        .map_err(|_| ParseError::NotAnInteger)?;
+    let value = get_bytes_or_bad_args!(array, 3);
+    return Ok(Box::new(SetRange {
+        key: key,
+        offset: offset,
+        value: value,
+    }));
+}
+
+/// Writes `value` into `string` at `offset`, padding it with zeroes.
+fn set_range(string: &[u8], offset: usize, value: &[u8]) -> Bytes {
+    let mut buffer = BytesMut::from(string);
+    if buffer.len() < offset + value.len() {
+        buffer.resize(offset + value.len(), 0);
+    }
+    buffer[offset..offset + value.len()].copy_from_slice(value);
+    return buffer.freeze();
+}
+
+impl CommandHandler for SetRange {
+    fn name(&self) -> &'static str {
+        return "setrange";
+    }
+
+    fn run(&self, state: &State, client: ClientId) -> Result<DataType, ReplyError> {
+        let keyspace = state.keyspace(client);
+        if self.offset < 0 {
+            return Err(ReplyError::Err("offset is out of range".to_string()));
+        }
+        let offset = self.offset as usize;
+        let max_len = state.config.read().unwrap().proto_max_bulk_len;
+        if !self.value.is_empty() && offset.saturating_add(self.value.len()) > max_len {
+            return Err(ReplyError::Err(
+                "string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+            ));
+        }
+        let mut expired = ExpiredKeys::default();
+        let result = {
+            let mut map = keyspace.lock(&self.key);
+            expired.remove_if_expired(&mut map, &self.key);
+            let result = map.update(&self.key, |v| {
+                let string = v.value.as_str_mut()?;
+                if !self.value.is_empty() {
+                    let raw = string.raw_mut();
+                    *raw = set_range(raw, offset, &self.value);
+                }
+                return Ok(string.len());
+            });
+            match result {
+                Some(result) => result,
+                // empty values don't create the key
+                None if self.value.is_empty() => Ok(0),
+                None => {
+                    let string = Str::from(set_range(&[], offset, &self.value));
+                    let len = string.len();
+                    let value = DBValue::with_expiration(Value::Str(string), 0, map.now());
+                    map.insert(self.key.clone(), value);
+                    Ok(len)
+                }
+            }
+        };
+        expired.release(state);
+        return Ok(DataType::Integer {
+            number: result? as isize,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{
+        parse_decr, parse_decrby, parse_get, parse_getdel, parse_getex, parse_getrange,
+        parse_getset, parse_incr, parse_incrby, parse_mget, parse_mset, parse_msetnx, parse_set,
+        parse_setrange, ParseError,
+    };
+    use crate::clock::MockClock;
+    use crate::config::Config;
+    use crate::db::DBValue;
+    use crate::errors::ReplyError;
+    use crate::protocol::DataType;
+    use crate::state::StateInner;
+    use crate::value::{List, Str, Value};
+
+    fn args(args: &[&str]) -> Vec<DataType> {
+        return args.iter().map(|arg| DataType::from(*arg)).collect();
+    }
+
+    #[test]
+    fn test_set_get() {
+        let state = StateInner::new(Config::default());
+        let get = parse_get(&args(&["GET", "key"])).unwrap();
+        assert_eq!(get.run(&state, 0), Ok(DataType::NullBulkString));
+
+        let set = parse_set(&args(&["SET", "key", "1"])).unwrap();
+        assert_eq!(set.run(&state, 0), Ok(DataType::ok()));
+        assert_eq!(get.run(&state, 0), Ok(DataType::bulk("1")));
+        let set = parse_set(&args(&["SET", "key", "2"])).unwrap();
+        assert_eq!(set.run(&state, 0), Ok(DataType::ok()));
+        assert_eq!(get.run(&state, 0), Ok(DataType::bulk("2")));
+    }
+
+    #[test]
+    fn test_binary_keys() {
+        let state = StateInner::new(Config::default());
+        let key = DataType::bulk(Bytes::from_static(b"\xff\x00k"));
+        let set = [DataType::from("SET"), key.clone(), DataType::from("v")];
+        assert_eq!(parse_set(&set).unwrap().run(&state, 0), Ok(DataType::ok()));
+        let get = parse_get(&[DataType::from("GET"), key]).unwrap();
+        assert_eq!(get.run(&state, 0), Ok(DataType::bulk("v")));
+        // not mistaken for another key that isn't UTF-8 either
+        let other = DataType::bulk(Bytes::from_static(b"\xfe\x00k"));
+        let get = parse_get(&[DataType::from("GET"), other]).unwrap();
+        assert_eq!(get.run(&state, 0), Ok(DataType::NullBulkString));
+    }
+
+    #[test]
+    fn test_set_options() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let run = |command: &[&str]| {
+            return parse_set(&args(command)).unwrap().run(&state, 0);
+        };
+        let expiration = |key: &str| {
+            return state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .get(key.as_bytes())
+                .map(|v| v.expiration);
+        };
+        let ok = Ok(DataType::ok());
+        let null = Ok(DataType::NullBulkString);
+
+        assert_eq!(run(&["SET", "key", "1", "XX"]), null);
+        assert_eq!(run(&["SET", "key", "1", "NX", "GET"]), null);
+        assert_eq!(run(&["SET", "key", "2", "NX"]), null);
+        assert_eq!(
+            run(&["SET", "key", "2", "NX", "GET"]),
+            Ok(DataType::bulk("1"))
+        );
+        assert_eq!(
+            run(&["SET", "key", "2", "xx", "get"]),
+            Ok(DataType::bulk("1"))
+        );
+        assert_eq!(run(&["SET", "key", "3", "XX"]), ok);
+
+        assert_eq!(run(&["SET", "key", "3", "EX", "10"]), ok);
+        assert_eq!(expiration("key"), Some(1_010_000));
+        assert_eq!(run(&["SET", "key", "3", "KEEPTTL"]), ok);
+        assert_eq!(expiration("key"), Some(1_010_000));
+        assert_eq!(run(&["SET", "key", "3", "PX", "10"]), ok);
+        assert_eq!(expiration("key"), Some(1_000_010));
+        assert_eq!(run(&["SET", "key", "3", "EXAT", "2000"]), ok);
+        assert_eq!(expiration("key"), Some(2_000_000));
+        assert_eq!(run(&["SET", "key", "3", "PXAT", "1500000"]), ok);
+        assert_eq!(expiration("key"), Some(1_500_000));
+        // the expiration is removed otherwise
+        assert_eq!(run(&["SET", "key", "3"]), ok);
+        assert_eq!(expiration("key"), Some(0));
+        // deadlines past the largest timestamp are refused, like non-positive TTLs
+        let invalid = Err(ReplyError::Err(String::from(
+            "invalid expire time in 'set' command",
+        )));
+        let max = i64::MAX.to_string();
+        assert_eq!(run(&["SET", "key", "4", "PX", &max]), invalid);
+        assert_eq!(
+            run(&["SET", "key", "4", "EX", &(i64::MAX / 1000).to_string()]),
+            invalid
+        );
+        assert_eq!(run(&["SET", "key", "4", "NX", "PX", &max]), invalid);
+        assert_eq!(expiration("key"), Some(0));
+
+        // GET only returns strings, nothing is set otherwise
+        let value = DBValue::with_expiration(Value::List(List::new()), 0, state.keyspace(0).now());
+        state
+            .keyspace(0)
+            .lock(b"list")
+            .insert(Bytes::from("list"), value);
+        assert_eq!(
+            run(&["SET", "list", "1", "GET"]),
+            Err(ReplyError::WrongType)
+        );
+        assert_eq!(run(&["SET", "list", "1"]), ok);
+
+        let error = |command: &[&str]| {
+            let err = parse_set(&args(command)).unwrap_err();
+            return err.downcast_ref::<ParseError>().unwrap().to_string();
+        };
+        assert_eq!(error(&["SET", "key", "1", "NX", "XX"]), "syntax error");
+        assert_eq!(
+            error(&["SET", "key", "1", "EX", "1", "PX", "1"]),
+            "syntax error"
+        );
+        assert_eq!(
+            error(&["SET", "key", "1", "KEEPTTL", "EX", "1"]),
+            "syntax error"
+        );
+        assert_eq!(error(&["SET", "key", "1", "EX"]), "syntax error");
+        assert_eq!(error(&["SET", "key", "1", "MAYBE"]), "syntax error");
+        assert_eq!(
+            error(&["SET", "key", "1", "EX", "soon"]),
+            "value is not an integer or out of range"
+        );
+        for time in ["0", "-1", &i64::MAX.to_string()] {
+            assert_eq!(
+                error(&["SET", "key", "1", "EX", time]),
+                "invalid expire time in 'set' command"
+            );
+        }
+    }
+
+    #[test]
+    fn test_getset_getdel_getex() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "GETSET" => parse_getset(&args(command)),
+                "GETDEL" => parse_getdel(&args(command)),
+                "GETEX" => parse_getex(&args(command)),
+                _ => parse_set(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0);
+        };
+        let expiration = |key: &str| {
+            return state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .get(key.as_bytes())
+                .map(|v| v.expiration);
+        };
+        let bulk = |string: &str| Ok(DataType::bulk(string.to_string()));
+        let null = Ok(DataType::NullBulkString);
+
+        assert_eq!(run(&["GETSET", "key", "1"]), null);
+        assert_eq!(run(&["SET", "key", "2", "EX", "10"]), Ok(DataType::ok()));
+        // GETSET removes the expiration like SET
+        assert_eq!(run(&["GETSET", "key", "3"]), bulk("2"));
+        assert_eq!(expiration("key"), Some(0));
+
+        assert_eq!(run(&["GETEX", "missing", "EX", "10"]), null);
+        assert_eq!(run(&["GETEX", "key", "EX", "10"]), bulk("3"));
+        assert_eq!(expiration("key"), Some(1_010_000));
+        assert_eq!(run(&["GETEX", "key"]), bulk("3"));
+        assert_eq!(expiration("key"), Some(1_010_000));
+        assert_eq!(run(&["GETEX", "key", "PXAT", "1500000"]), bulk("3"));
+        assert_eq!(expiration("key"), Some(1_500_000));
+        assert_eq!(run(&["GETEX", "key", "persist"]), bulk("3"));
+        assert_eq!(expiration("key"), Some(0));
+        assert_eq!(
+            run(&["GETEX", "key", "PX", &i64::MAX.to_string()]),
+            Err(ReplyError::Err(String::from(
+                "invalid expire time in 'getex' command"
+            )))
+        );
+        assert_eq!(expiration("key"), Some(0));
+        // timestamps in the past delete the key
+        assert_eq!(run(&["GETEX", "key", "EXAT", "1"]), bulk("3"));
+        assert_eq!(expiration("key"), None);
+
+        run(&["SET", "key", "4"]).unwrap();
+        assert_eq!(run(&["GETDEL", "key"]), bulk("4"));
+        assert_eq!(run(&["GETDEL", "key"]), null);
+
+        // only strings are read
+        let value = DBValue::with_expiration(Value::List(List::new()), 0, state.keyspace(0).now());
+        state
+            .keyspace(0)
+            .lock(b"list")
+            .insert(Bytes::from("list"), value);
+        assert_eq!(run(&["GETSET", "list", "1"]), Err(ReplyError::WrongType));
+        assert_eq!(run(&["GETDEL", "list"]), Err(ReplyError::WrongType));
+        assert_eq!(
+            run(&["GETEX", "list", "EX", "1"]),
+            Err(ReplyError::WrongType)
+        );
+        assert_eq!(expiration("list"), Some(0));
+
+        for command in [
+            &["GETEX", "key", "EX", "1", "PERSIST"][..],
+            &["GETEX", "key", "KEEPTTL"],
+            &["GETEX", "key", "PX"],
+        ] {
+            assert!(parse_getex(&args(command)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_incr_decr() {
+        let state = StateInner::new(Config::default());
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "INCR" => parse_incr(&args(command)),
+                "DECR" => parse_decr(&args(command)),
+                "INCRBY" => parse_incrby(&args(command)),
+                "DECRBY" => parse_decrby(&args(command)),
+                _ => parse_set(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0);
+        };
+        let integer = |number| Ok(DataType::Integer { number });
+        assert_eq!(run(&["INCR", "counter"]), integer(1));
+        assert_eq!(run(&["INCRBY", "counter", "10"]), integer(11));
+        assert_eq!(run(&["DECR", "counter"]), integer(10));
+        assert_eq!(run(&["DECRBY", "counter", "-5"]), integer(15));
+        let get = parse_get(&args(&["GET", "counter"])).unwrap();
+        assert_eq!(get.run(&state, 0), Ok(DataType::bulk("15")));
+
+        run(&["SET", "string", "10"]).unwrap();
+        assert_eq!(run(&["DECRBY", "string", "20"]), integer(-10));
+        run(&["SET", "string", "ten"]).unwrap();
+        assert_eq!(run(&["INCR", "string"]), Err(ReplyError::NotAnInteger));
+        run(&["SET", "string", &i64::MAX.to_string()]).unwrap();
+        assert_eq!(
+            run(&["INCR", "string"]),
+            Err(ReplyError::Err(
+                "increment or decrement would overflow".to_string()
+            ))
+        );
+        assert_eq!(
+            run(&["DECRBY", "counter", &i64::MIN.to_string()]),
+            Err(ReplyError::Err("decrement would overflow".to_string()))
+        );
+        assert!(parse_incrby(&args(&["INCRBY", "counter", "1.5"])).is_err());
+    }
+
+    #[test]
+    fn test_incr_is_atomic() {
+        let state = StateInner::new(Config::default());
+        let threads: Vec<_> = (0..4)
+            .map(|client| {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    let incr = parse_incr(&args(&["INCR", "counter"])).unwrap();
+                    for _ in 0..1000 {
+                        incr.run(&state, client).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let get = parse_get(&args(&["GET", "counter"])).unwrap();
+        assert_eq!(get.run(&state, 0), Ok(DataType::bulk("4000")));
+    }
+
+    #[test]
+    fn test_getrange_setrange() {
+        let state = StateInner::new(Config::default());
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "GETRANGE" => parse_getrange(&args(command)),
+                "SETRANGE" => parse_setrange(&args(command)),
+                _ => parse_set(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0);
+        };
+        let bulk = |string: &str| Ok(DataType::bulk(string.to_string()));
+        let integer = |number| Ok(DataType::Integer { number });
+        run(&["SET", "key", "This is a string"]).unwrap();
+        assert_eq!(run(&["GETRANGE", "key", "0", "3"]), bulk("This"));
+        assert_eq!(run(&["GETRANGE", "key", "-3", "-1"]), bulk("ing"));
+        assert_eq!(
+            run(&["GETRANGE", "key", "0", "-1"]),
+            bulk("This is a string")
+        );
+        assert_eq!(run(&["GETRANGE", "key", "10", "100"]), bulk("string"));
+        assert_eq!(run(&["GETRANGE", "key", "-100", "3"]), bulk("This"));
+        assert_eq!(run(&["GETRANGE", "key", "5", "3"]), bulk(""));
+        assert_eq!(run(&["GETRANGE", "key", "-1", "-5"]), bulk(""));
+        assert_eq!(run(&["GETRANGE", "missing", "0", "-1"]), bulk(""));
+
+        assert_eq!(run(&["SETRANGE", "key", "10", "STRING"]), integer(16));
+        assert_eq!(
+            run(&["GETRANGE", "key", "0", "-1"]),
+            bulk("This is a STRING")
+        );
+        // integers are edited as strings
+        run(&["SET", "number", "1234"]).unwrap();
+        assert_eq!(run(&["SETRANGE", "number", "1", "0"]), integer(4));
+        assert_eq!(run(&["GETRANGE", "number", "0", "-1"]), bulk("1034"));
+
+        // missing keys are padded with zero bytes
+        assert_eq!(run(&["SETRANGE", "missing", "0", ""]), integer(0));
+        assert_eq!(run(&["GETRANGE", "missing", "0", "-1"]), bulk(""));
+        assert_eq!(run(&["SETRANGE", "missing", "3", "\u{ff}"]), integer(5));
+        let get = parse_get(&args(&["GET", "missing"])).unwrap();
+        assert_eq!(
+            get.run(&state, 0),
+            Ok(DataType::bulk(b"\0\0\0\xc3\xbf".to_vec()))
+        );
+
+        assert_eq!(
+            run(&["SETRANGE", "key", "-1", "x"]),
+            Err(ReplyError::Err("offset is out of range".to_string()))
+        );
+        assert_eq!(
+            run(&["SETRANGE", "key", "536870911", "xx"]),
+            Err(ReplyError::Err(
+                "string exceeds maximum allowed size (proto-max-bulk-len)".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_mset_mget() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let run = |command: &[&str]| {
+            let handler = match command[0] {
+                "MGET" => parse_mget(&args(command)),
+                "MSET" => parse_mset(&args(command)),
+                "MSETNX" => parse_msetnx(&args(command)),
+                _ => parse_incr(&args(command)),
+            };
+            return handler.unwrap().run(&state, 0);
+        };
+        let mget = |keys: &[&str]| {
+            let mut command = vec!["MGET"];
+            command.extend(keys);
+            return match run(&command) {
+                Ok(DataType::Array { items }) => items,
+                other => panic!("unexpected reply {:?}", other),
+            };
+        };
+        let null = DataType::NullBulkString;
+        // keys spread over several shards
+        let keys: Vec<String> = (0..32).map(|i| format!("key:{i}")).collect();
+        let mut command = vec!["MSET"];
+        for key in &keys {
+            command.extend([key.as_str(), key.as_str()]);
+        }
+        assert_eq!(run(&command), Ok(DataType::ok()));
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let values = mget(&keys);
+        assert_eq!(values.len(), 32);
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(value, DataType::bulk(key.to_string()));
+        }
+
+        // replaces the values and their expirations
+        let set = parse_set(&args(&["SET", "a", "1", "PX", "100"])).unwrap();
+        set.run(&state, 0).unwrap();
+        assert_eq!(run(&["MSET", "a", "2", "b", "3"]), Ok(DataType::ok()));
+        clock.advance(100);
+        assert_eq!(
+            mget(&["a", "missing", "b"]),
+            vec![DataType::bulk("2"), null.clone(), DataType::bulk("3")]
+        );
+
+        let integer = |number| Ok(DataType::Integer { number });
+        assert_eq!(run(&["MSETNX", "c", "4", "a", "5"]), integer(0));
+        assert_eq!(mget(&["a", "c"]), vec![DataType::bulk("2"), null.clone()]);
+        assert_eq!(run(&["MSETNX", "c", "4", "d", "5"]), integer(1));
+        assert_eq!(
+            mget(&["c", "d"]),
+            vec![DataType::bulk("4"), DataType::bulk("5")]
+        );
+
+        // values that aren't strings are nil
+        let value = DBValue::with_expiration(Value::List(List::new()), 0, state.keyspace(0).now());
+        state
+            .keyspace(0)
+            .lock(b"list")
+            .insert(Bytes::from("list"), value);
+        assert_eq!(mget(&["list", "c"]), vec![null, DataType::bulk("4")]);
+
+        assert!(parse_mset(&args(&["MSET", "a", "1", "b"])).is_err());
+    }
+
+    #[test]
+    fn test_get_expired() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let set = parse_set(&args(&["SET", "key", "1", "PX", "1000"])).unwrap();
+        set.run(&state, 0).unwrap();
+        let get = parse_get(&args(&["GET", "key"])).unwrap();
+        clock.advance(999);
+        assert_eq!(get.run(&state, 0), Ok(DataType::bulk("1")));
+        clock.advance(1);
+        assert_eq!(get.run(&state, 0), Ok(DataType::NullBulkString));
+        // expired keys are removed when accessed
+        assert!(state.keyspace(0).lock(b"key").get(b"key").is_none());
+    }
+
+    #[test]
+    fn test_set_compressed() {
+        let mut config = Config::default();
+        config.set("string-compression-threshold", "1kb").unwrap();
+        let state = StateInner::new(config);
+        let value = "{\"key\": \"value\"}".repeat(1000);
+        let set = parse_set(&args(&["SET", "key", &value])).unwrap();
+        set.run(&state, 0).unwrap();
+        {
+            let map = state.keyspace(0).lock(b"key");
+            let stored = &map.get(b"key").unwrap().value;
+            assert_eq!(stored.encoding(), "lzf");
+            assert!(stored.memory_usage(0) < value.len() / 10);
+        }
+        let get = parse_get(&args(&["GET", "key"])).unwrap();
+        assert_eq!(get.run(&state, 0), Ok(DataType::bulk(value.clone())));
+        // the old value is decompressed when returned by SET
+        let set = parse_set(&args(&["SET", "key", "small", "GET"])).unwrap();
+        assert_eq!(set.run(&state, 0), Ok(DataType::bulk(value)));
+        assert_eq!(
+            state
+                .keyspace(0)
+                .lock(b"key")
+                .get(b"key")
+                .unwrap()
+                .value
+                .encoding(),
+            "embstr"
+        );
+    }
+
+    #[test]
+    fn test_get_doesnt_copy_the_value() {
+        let state = StateInner::new(Config::default());
+        let value = "x".repeat(1024 * 1024);
+        let set = parse_set(&args(&["SET", "key", &value])).unwrap();
+        set.run(&state, 0).unwrap();
+        let stored = match &state.keyspace(0).lock(b"key").get(b"key").unwrap().value {
+            Value::Str(Str::Raw(string)) => string.as_ptr(),
+            other => panic!("unexpected value {:?}", other),
+        };
+        let get = parse_get(&args(&["GET", "key"])).unwrap();
+        match get.run(&state, 0) {
+            Ok(DataType::BulkString { string }) => assert_eq!(string.as_ptr(), stored),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+}
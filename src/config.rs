@@ -0,0 +1,1149 @@
+/// Server configuration.
+///
+/// Parameters use the same names as `redis.conf`. They can be given on the command
+/// line as `--<name> <value>` pairs and, unless noted otherwise, changed at runtime
+/// with CONFIG SET.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use thiserror::Error;
+
+use crate::command_table;
+use crate::db;
+use crate::decoders::v2::{self, DecoderLimits, ErrorRecovery};
+use crate::decoders::READ_BUFFER_SIZE;
+use crate::engine::EngineKind;
+use crate::listpack::ListpackLimits;
+use crate::output::{self, OutputBufferLimits};
+use crate::uring::IoBackend;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("Unknown option or number of arguments for CONFIG SET - '{0}'")]
+    UnknownOption(String),
+
+    #[error("Invalid argument '{1}' for CONFIG SET '{0}'")]
+    InvalidValue(String, String),
+
+    #[error("missing value for '{0}'")]
+    MissingValue(String),
+
+    #[error("CONFIG SET failed (possibly related to argument '{0}') - can't set immutable config")]
+    Immutable(String),
+
+    #[error("No such command in rename-command: '{0}'")]
+    NoSuchCommand(String),
+}
+
+/// Names of every supported parameter, in the order CONFIG GET reports them.
+pub const PARAMETERS: &[&str] = &[
+    "bind",
+    "port",
+    "acceptors",
+    "reuseport",
+    "websocket-port",
+    "http-port",
+    "memcached-port",
+    "protected-mode",
+    "maxclients",
+    "client-output-buffer-limit",
+    "client-write-timeout",
+    "pidfile",
+    "daemonize",
+    "supervised",
+    "tcp-keepalive",
+    "tcp-nodelay",
+    "latency-monitor-threshold",
+    "maxmemory",
+    "maxmemory-policy",
+    "maxmemory-samples",
+    "lfu-log-factor",
+    "lfu-decay-time",
+    "lazyfree-lazy-eviction",
+    "lazyfree-lazy-expire",
+    "lazyfree-lazy-server-del",
+    "lazyfree-lazy-user-del",
+    "lazyfree-lazy-user-flush",
+    "hash-max-listpack-entries",
+    "hash-max-listpack-value",
+    "set-max-listpack-entries",
+    "set-max-listpack-value",
+    "zset-max-listpack-entries",
+    "zset-max-listpack-value",
+    "list-max-listpack-size",
+    "string-compression-threshold",
+    "proto-max-bulk-len",
+    "proto-max-multibulk-len",
+    "proto-max-nesting",
+    "proto-read-timeout",
+    "read-buffer-size",
+    "bulk-spool-threshold",
+    "proto-error-recovery",
+    "keyspace-shards",
+    "databases",
+    "engine",
+    "io-backend",
+    "otel-endpoint",
+];
+
+/// Parameters that can only be given at startup.
+pub const IMMUTABLE_PARAMETERS: &[&str] = &[
+    "bind",
+    "port",
+    "acceptors",
+    "reuseport",
+    "websocket-port",
+    "http-port",
+    "memcached-port",
+    "pidfile",
+    "daemonize",
+    "supervised",
+    "keyspace-shards",
+    "databases",
+    "engine",
+    "io-backend",
+    "otel-endpoint",
+];
+
+/// Sent to clients refused by protected mode before closing their connection.
+pub const PROTECTED_MODE_ERROR: &str = "Redis is running in protected mode because protected mode is enabled and no password is set for the default user. In this mode connections are only accepted from the loopback interface. If you want to connect from external computers to Redis you may adopt one of the following solutions: 1) Just disable protected mode sending the command 'CONFIG SET protected-mode no' from the loopback interface by connecting to Redis from the same host the server is running, however MAKE SURE Redis is not publicly accessible from internet if you do so. 2) Alternatively you can just disable the protected mode by restarting the server with the '--protected-mode no' option. 3) Bind the server to the loopback interface only with '--bind 127.0.0.1'. NOTE: You only need to do one of the above things in order for the server to start accepting connections from the outside.";
+
+/// Sent to clients refused because `maxclients` are already connected.
+pub const MAXCLIENTS_ERROR: &str = "max number of clients reached";
+
+fn parse_bool(value: &str) -> Option<bool> {
+    return match value.to_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    };
+}
+
+fn format_bool(value: bool) -> String {
+    return String::from(if value { "yes" } else { "no" });
+}
+
+/// How the server reports its state to a service manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Supervised {
+    No,
+    Upstart,
+    Systemd,
+    /// systemd if `$NOTIFY_SOCKET` is set
+    Auto,
+}
+
+impl Supervised {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Supervised::No => "no",
+            Supervised::Upstart => "upstart",
+            Supervised::Systemd => "systemd",
+            Supervised::Auto => "auto",
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name.to_lowercase().as_str() {
+            "no" => Some(Supervised::No),
+            "upstart" => Some(Supervised::Upstart),
+            "systemd" => Some(Supervised::Systemd),
+            "auto" => Some(Supervised::Auto),
+            _ => None,
+        };
+    }
+}
+
+/// How keys are chosen for eviction once `maxmemory` is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxmemoryPolicy {
+    /// don't evict keys, refuse commands that may grow the dataset instead
+    NoEviction,
+    /// evict the least recently used keys among all keys
+    AllkeysLru,
+    /// evict the least recently used keys among keys with an expiration
+    VolatileLru,
+    /// evict the least frequently used keys among all keys
+    AllkeysLfu,
+    /// evict the least frequently used keys among keys with an expiration
+    VolatileLfu,
+    /// evict random keys among all keys
+    AllkeysRandom,
+    /// evict random keys among keys with an expiration
+    VolatileRandom,
+    /// evict the keys with the nearest expiration
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllkeysLru => "allkeys-lru",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::AllkeysLfu => "allkeys-lfu",
+            MaxmemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxmemoryPolicy::AllkeysRandom => "allkeys-random",
+            MaxmemoryPolicy::VolatileRandom => "volatile-random",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name.to_lowercase().as_str() {
+            "noeviction" => Some(MaxmemoryPolicy::NoEviction),
+            "allkeys-lru" => Some(MaxmemoryPolicy::AllkeysLru),
+            "volatile-lru" => Some(MaxmemoryPolicy::VolatileLru),
+            "allkeys-lfu" => Some(MaxmemoryPolicy::AllkeysLfu),
+            "volatile-lfu" => Some(MaxmemoryPolicy::VolatileLfu),
+            "allkeys-random" => Some(MaxmemoryPolicy::AllkeysRandom),
+            "volatile-random" => Some(MaxmemoryPolicy::VolatileRandom),
+            "volatile-ttl" => Some(MaxmemoryPolicy::VolatileTtl),
+            _ => None,
+        };
+    }
+
+    /// true if only keys with an expiration can be evicted
+    pub fn is_volatile(&self) -> bool {
+        return matches!(
+            self,
+            MaxmemoryPolicy::VolatileLru
+                | MaxmemoryPolicy::VolatileLfu
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::VolatileTtl
+        );
+    }
+
+    /// true if keys are evicted at random
+    pub fn is_random(&self) -> bool {
+        return matches!(
+            self,
+            MaxmemoryPolicy::AllkeysRandom | MaxmemoryPolicy::VolatileRandom
+        );
+    }
+
+    /// true if keys are evicted based on their access frequency
+    pub fn is_lfu(&self) -> bool {
+        return matches!(
+            self,
+            MaxmemoryPolicy::AllkeysLfu | MaxmemoryPolicy::VolatileLfu
+        );
+    }
+}
+
+/// Parses a memory amount such as `1024`, `100mb` or `1g`.
+/// `k`, `m` and `g` are powers of 1000, `kb`, `mb` and `gb` powers of 1024.
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let units: &[(&str, u64)] = &[
+        ("gb", 1024 * 1024 * 1024),
+        ("mb", 1024 * 1024),
+        ("kb", 1024),
+        ("g", 1000 * 1000 * 1000),
+        ("m", 1000 * 1000),
+        ("k", 1000),
+        ("b", 1),
+    ];
+    for (suffix, multiplier) in units {
+        if let Some(number) = value.strip_suffix(suffix) {
+            return number.parse::<u64>().ok()?.checked_mul(*multiplier);
+        }
+    }
+    return value.parse().ok();
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address the server listens on.
+    pub bind: IpAddr,
+
+    pub port: u16,
+
+    /// Number of tasks accepting connections. Can't be changed at runtime.
+    pub acceptors: usize,
+
+    /// Gives every acceptor its own listening socket bound with SO_REUSEPORT, letting
+    /// the kernel distribute connections between them, instead of sharing a single
+    /// socket. Can't be changed at runtime.
+    pub reuseport: bool,
+
+    /// Port of the WebSocket listener, on the `bind` address, 0 disables it. Can't be
+    /// changed at runtime.
+    pub websocket_port: u16,
+
+    /// Port of the HTTP gateway, on the `bind` address, 0 disables it. Can't be changed
+    /// at runtime.
+    pub http_port: u16,
+
+    /// Port of the listener speaking the memcached text protocol, on the `bind`
+    /// address, 0 disables it. Needs the `memcached` feature, and can't be changed at
+    /// runtime.
+    pub memcached_port: u16,
+
+    /// When enabled and the server listens on a non-loopback address, connections
+    /// from non-loopback clients are refused (there is no authentication yet).
+    pub protected_mode: bool,
+
+    /// Maximum number of simultaneously connected clients.
+    pub maxclients: usize,
+
+    /// Output buffer limits of each class of clients. Only applies to new connections.
+    pub client_output_buffer_limit: OutputBufferLimits,
+
+    /// Seconds a client has to accept the output written to it before it is
+    /// disconnected, 0 waits forever. Only applies to new connections.
+    pub client_write_timeout: u64,
+
+    /// File the pid of the server is written to, none if empty.
+    pub pidfile: String,
+
+    /// Run in the background, detached from the terminal.
+    pub daemonize: bool,
+
+    pub supervised: Supervised,
+
+    /// Seconds between TCP keepalive probes on idle client connections. 0 disables
+    /// keepalive. Only applies to new connections.
+    pub tcp_keepalive: u64,
+
+    /// Disables Nagle's algorithm on client connections, sending replies right away
+    /// instead of coalescing small writes. Only applies to new connections.
+    pub tcp_nodelay: bool,
+
+    /// Commands and other events taking at least this many milliseconds are
+    /// recorded by the latency monitor. 0 disables monitoring.
+    pub latency_monitor_threshold: u64,
+
+    /// Maximum number of bytes used by the dataset before keys are evicted. 0 means no limit.
+    pub maxmemory: u64,
+
+    pub maxmemory_policy: MaxmemoryPolicy,
+
+    /// Number of keys sampled on every eviction round. Higher values approximate
+    /// the eviction policy more accurately at the expense of CPU.
+    pub maxmemory_samples: usize,
+
+    /// How many hits are needed to saturate the LFU access frequency counter.
+    /// Higher values make the counter grow slower.
+    pub lfu_log_factor: u64,
+
+    /// The LFU access frequency counter of a key is decremented by one for every
+    /// `lfu_decay_time` minutes it isn't accessed. 0 disables the decay.
+    pub lfu_decay_time: u64,
+
+    /// Free the values of evicted keys in the background.
+    pub lazyfree_lazy_eviction: bool,
+
+    /// Free the values of expired keys in the background.
+    pub lazyfree_lazy_expire: bool,
+
+    /// Free in the background the values deleted as a side effect of a command, like
+    /// the old value of a key overwritten by SET.
+    pub lazyfree_lazy_server_del: bool,
+
+    /// Free in the background the values deleted by DEL, as UNLINK does.
+    pub lazyfree_lazy_user_del: bool,
+
+    /// Free in the background the keys removed by FLUSHDB and FLUSHALL when neither
+    /// the ASYNC nor the SYNC option is given.
+    pub lazyfree_lazy_user_flush: bool,
+
+    /// Hashes with up to this many fields, none longer than `hash_max_listpack_value`,
+    /// are stored as listpacks.
+    pub hash_max_listpack_entries: usize,
+
+    pub hash_max_listpack_value: usize,
+
+    /// Sets with up to this many members, none longer than `set_max_listpack_value`,
+    /// are stored as listpacks.
+    pub set_max_listpack_entries: usize,
+
+    pub set_max_listpack_value: usize,
+
+    /// Sorted sets with up to this many members, none longer than
+    /// `zset_max_listpack_value`, are stored as listpacks.
+    pub zset_max_listpack_entries: usize,
+
+    pub zset_max_listpack_value: usize,
+
+    /// Lists are stored as listpacks while they have at most this many elements when
+    /// positive, or while they take at most 4kb (-1), 8kb (-2), 16kb (-3), 32kb (-4)
+    /// or 64kb (-5) when negative.
+    pub list_max_listpack_size: isize,
+
+    /// Strings longer than this are stored compressed. 0 disables compression.
+    pub string_compression_threshold: usize,
+
+    /// Commands renamed at startup with `--rename-command <name> <new name>`, mapping
+    /// the lowercase original name to the new one. An empty new name disables the
+    /// command. Can't be changed at runtime.
+    pub renamed_commands: HashMap<String, String>,
+
+    /// Max length of the bulk strings sent by clients. Only applies to new connections.
+    pub proto_max_bulk_len: usize,
+
+    /// Max number of elements of the arrays sent by clients. Only applies to new connections.
+    pub proto_max_multibulk_len: usize,
+
+    /// Max depth of the nested arrays sent by clients. Only applies to new connections.
+    pub proto_max_nesting: usize,
+
+    /// Seconds a client has to finish sending a command once it started, 0 waits
+    /// forever. Only applies to new connections.
+    pub proto_read_timeout: u64,
+
+    /// Size of the reads done on client connections. Only applies to new connections.
+    pub read_buffer_size: usize,
+
+    /// Bulk strings longer than this are spooled to a temporary file while they are
+    /// received, 0 keeps them in memory. Only applies to new connections.
+    pub bulk_spool_threshold: usize,
+
+    /// Whether connections are closed after a protocol error or skip the line it was
+    /// found in. Only applies to new connections using the v2 decoder.
+    pub proto_error_recovery: ErrorRecovery,
+
+    /// Number of shards the keyspace is partitioned in, each one with its own lock.
+    /// Can't be changed at runtime.
+    pub keyspace_shards: usize,
+
+    /// Number of databases selectable with SELECT. Can't be changed at runtime.
+    pub databases: usize,
+
+    /// Whether commands are executed by the connections, locking the shards they
+    /// touch, or sent to a task owning each shard. Can't be changed at runtime.
+    pub engine: EngineKind,
+
+    /// Whether sockets are driven by epoll or, with the `io-uring` feature, by
+    /// io_uring. Can't be changed at runtime.
+    pub io_backend: IoBackend,
+
+    /// OTLP/gRPC collector the spans of the commands are exported to, with the
+    /// `otel` feature. Empty to not export them. Can't be changed at runtime.
+    pub otel_endpoint: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        return Config {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 6379,
+            acceptors: 1,
+            reuseport: false,
+            websocket_port: 0,
+            http_port: 0,
+            memcached_port: 0,
+            protected_mode: true,
+            maxclients: 10000,
+            client_output_buffer_limit: OutputBufferLimits::default(),
+            client_write_timeout: output::CLIENT_WRITE_TIMEOUT.as_secs(),
+            pidfile: String::new(),
+            daemonize: false,
+            supervised: Supervised::No,
+            tcp_keepalive: 300,
+            tcp_nodelay: true,
+            latency_monitor_threshold: 0,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::NoEviction,
+            maxmemory_samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+            lazyfree_lazy_eviction: false,
+            lazyfree_lazy_expire: false,
+            lazyfree_lazy_server_del: false,
+            lazyfree_lazy_user_del: false,
+            lazyfree_lazy_user_flush: false,
+            hash_max_listpack_entries: 128,
+            hash_max_listpack_value: 64,
+            set_max_listpack_entries: 128,
+            set_max_listpack_value: 64,
+            zset_max_listpack_entries: 128,
+            zset_max_listpack_value: 64,
+            list_max_listpack_size: -2,
+            string_compression_threshold: 0,
+            renamed_commands: HashMap::new(),
+            proto_max_bulk_len: v2::PROTO_MAX_BULK_LEN,
+            proto_max_multibulk_len: v2::PROTO_MAX_MULTIBULK_LEN,
+            proto_max_nesting: v2::PROTO_MAX_NESTING,
+            proto_read_timeout: v2::PROTO_READ_TIMEOUT.as_secs(),
+            read_buffer_size: READ_BUFFER_SIZE,
+            bulk_spool_threshold: 0,
+            proto_error_recovery: ErrorRecovery::Close,
+            keyspace_shards: db::KEYSPACE_SHARDS,
+            databases: db::DATABASES,
+            engine: EngineKind::Locks,
+            io_backend: IoBackend::Epoll,
+            otel_endpoint: String::new(),
+        };
+    }
+}
+
+impl Config {
+    /// Builds the configuration from `--<name> <value>` command line arguments.
+    /// `--rename-command` takes two values: the command and its new name.
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut config = Config::default();
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) => name.to_lowercase(),
+                None => bail!(ConfigError::UnknownOption(arg)),
+            };
+            let value = match args.next() {
+                Some(value) => value,
+                None => bail!(ConfigError::MissingValue(name)),
+            };
+            if name == "rename-command" {
+                let new_name = match args.next() {
+                    Some(new_name) => new_name,
+                    None => bail!(ConfigError::MissingValue(name)),
+                };
+                config.rename_command(&value, &new_name)?;
+                continue;
+            }
+            config.set(&name, &value)?;
+        }
+        return Ok(config);
+    }
+
+    /// Renames command `name` to `new_name`, or disables it if `new_name` is empty.
+    pub fn rename_command(&mut self, name: &str, new_name: &str) -> Result<()> {
+        let spec = match command_table::lookup(name) {
+            Some(spec) => spec,
+            None => bail!(ConfigError::NoSuchCommand(name.to_string())),
+        };
+        self.renamed_commands
+            .insert(spec.name.to_string(), new_name.to_lowercase());
+        return Ok(());
+    }
+
+    /// Resolves the name a client used for a command to its name in the command
+    /// table, `None` if the command was renamed or disabled.
+    pub fn resolve_command(&self, name: &str) -> Option<String> {
+        let name = name.to_lowercase();
+        if name.is_empty() {
+            return None;
+        }
+        let renamed = self.renamed_commands.iter().find(|(_, new)| **new == name);
+        if let Some((original, _)) = renamed {
+            return Some(original.clone());
+        }
+        if self.renamed_commands.contains_key(&name) {
+            return None;
+        }
+        return Some(name);
+    }
+
+    /// Returns the current value of parameter `name` formatted as CONFIG GET does.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "bind" => self.bind.to_string(),
+            "port" => self.port.to_string(),
+            "acceptors" => self.acceptors.to_string(),
+            "reuseport" => format_bool(self.reuseport),
+            "websocket-port" => self.websocket_port.to_string(),
+            "http-port" => self.http_port.to_string(),
+            "memcached-port" => self.memcached_port.to_string(),
+            "protected-mode" => format_bool(self.protected_mode),
+            "maxclients" => self.maxclients.to_string(),
+            "client-output-buffer-limit" => self.client_output_buffer_limit.format(),
+            "client-write-timeout" => self.client_write_timeout.to_string(),
+            "pidfile" => self.pidfile.clone(),
+            "daemonize" => format_bool(self.daemonize),
+            "supervised" => self.supervised.name().to_string(),
+            "tcp-keepalive" => self.tcp_keepalive.to_string(),
+            "tcp-nodelay" => format_bool(self.tcp_nodelay),
+            "latency-monitor-threshold" => self.latency_monitor_threshold.to_string(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.name().to_string(),
+            "maxmemory-samples" => self.maxmemory_samples.to_string(),
+            "lfu-log-factor" => self.lfu_log_factor.to_string(),
+            "lfu-decay-time" => self.lfu_decay_time.to_string(),
+            "lazyfree-lazy-eviction" => format_bool(self.lazyfree_lazy_eviction),
+            "lazyfree-lazy-expire" => format_bool(self.lazyfree_lazy_expire),
+            "lazyfree-lazy-server-del" => format_bool(self.lazyfree_lazy_server_del),
+            "lazyfree-lazy-user-del" => format_bool(self.lazyfree_lazy_user_del),
+            "lazyfree-lazy-user-flush" => format_bool(self.lazyfree_lazy_user_flush),
+            "hash-max-listpack-entries" => self.hash_max_listpack_entries.to_string(),
+            "hash-max-listpack-value" => self.hash_max_listpack_value.to_string(),
+            "set-max-listpack-entries" => self.set_max_listpack_entries.to_string(),
+            "set-max-listpack-value" => self.set_max_listpack_value.to_string(),
+            "zset-max-listpack-entries" => self.zset_max_listpack_entries.to_string(),
+            "zset-max-listpack-value" => self.zset_max_listpack_value.to_string(),
+            "list-max-listpack-size" => self.list_max_listpack_size.to_string(),
+            "string-compression-threshold" => self.string_compression_threshold.to_string(),
+            "proto-max-bulk-len" => self.proto_max_bulk_len.to_string(),
+            "proto-max-multibulk-len" => self.proto_max_multibulk_len.to_string(),
+            "proto-max-nesting" => self.proto_max_nesting.to_string(),
+            "proto-read-timeout" => self.proto_read_timeout.to_string(),
+            "read-buffer-size" => self.read_buffer_size.to_string(),
+            "bulk-spool-threshold" => self.bulk_spool_threshold.to_string(),
+            "proto-error-recovery" => self.proto_error_recovery.name().to_string(),
+            "keyspace-shards" => self.keyspace_shards.to_string(),
+            "databases" => self.databases.to_string(),
+            "engine" => self.engine.name().to_string(),
+            "io-backend" => self.io_backend.name().to_string(),
+            "otel-endpoint" => self.otel_endpoint.clone(),
+            _ => return None,
+        };
+        return Some(value);
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let invalid = || ConfigError::InvalidValue(name.to_string(), value.to_string());
+        match name {
+            "bind" => self.bind = value.parse().map_err(|_| invalid())?,
+            "port" => self.port = value.parse().map_err(|_| invalid())?,
+            "acceptors" => match value.parse() {
+                Ok(acceptors) if acceptors > 0 => self.acceptors = acceptors,
+                _ => bail!(invalid()),
+            },
+            "reuseport" => self.reuseport = parse_bool(value).ok_or_else(invalid)?,
+            "websocket-port" => self.websocket_port = value.parse().map_err(|_| invalid())?,
+            "http-port" => self.http_port = value.parse().map_err(|_| invalid())?,
+            "memcached-port" => self.memcached_port = value.parse().map_err(|_| invalid())?,
+            "protected-mode" => self.protected_mode = parse_bool(value).ok_or_else(invalid)?,
+            "maxclients" => match value.parse() {
+                Ok(maxclients) if maxclients > 0 => self.maxclients = maxclients,
+                _ => bail!(invalid()),
+            },
+            "client-output-buffer-limit" => {
+                self.client_output_buffer_limit = self
+                    .client_output_buffer_limit
+                    .parse(value)
+                    .ok_or_else(invalid)?
+            }
+            "client-write-timeout" => {
+                self.client_write_timeout = value.parse().map_err(|_| invalid())?
+            }
+            "pidfile" => self.pidfile = value.to_string(),
+            "daemonize" => self.daemonize = parse_bool(value).ok_or_else(invalid)?,
+            "supervised" => self.supervised = Supervised::from_name(value).ok_or_else(invalid)?,
+            "tcp-keepalive" => self.tcp_keepalive = value.parse().map_err(|_| invalid())?,
+            "tcp-nodelay" => self.tcp_nodelay = parse_bool(value).ok_or_else(invalid)?,
+            "latency-monitor-threshold" => {
+                self.latency_monitor_threshold = value.parse().map_err(|_| invalid())?
+            }
+            "maxmemory" => self.maxmemory = parse_memory(value).ok_or_else(invalid)?,
+            "maxmemory-policy" => {
+                self.maxmemory_policy = MaxmemoryPolicy::from_name(value).ok_or_else(invalid)?
+            }
+            "maxmemory-samples" => match value.parse() {
+                Ok(samples) if (1..=64).contains(&samples) => self.maxmemory_samples = samples,
+                _ => bail!(invalid()),
+            },
+            "lfu-log-factor" => self.lfu_log_factor = value.parse().map_err(|_| invalid())?,
+            "lfu-decay-time" => self.lfu_decay_time = value.parse().map_err(|_| invalid())?,
+            "lazyfree-lazy-eviction" => {
+                self.lazyfree_lazy_eviction = parse_bool(value).ok_or_else(invalid)?
+            }
+            "lazyfree-lazy-expire" => {
+                self.lazyfree_lazy_expire = parse_bool(value).ok_or_else(invalid)?
+            }
+            "lazyfree-lazy-server-del" => {
+                self.lazyfree_lazy_server_del = parse_bool(value).ok_or_else(invalid)?
+            }
+            "lazyfree-lazy-user-del" => {
+                self.lazyfree_lazy_user_del = parse_bool(value).ok_or_else(invalid)?
+            }
+            "lazyfree-lazy-user-flush" => {
+                self.lazyfree_lazy_user_flush = parse_bool(value).ok_or_else(invalid)?
+            }
+            "hash-max-listpack-entries" => {
+                self.hash_max_listpack_entries = value.parse().map_err(|_| invalid())?
+            }
+            "hash-max-listpack-value" => {
+                self.hash_max_listpack_value = parse_memory(value).ok_or_else(invalid)? as usize
+            }
+            "set-max-listpack-entries" => {
+                self.set_max_listpack_entries = value.parse().map_err(|_| invalid())?
+            }
+            "set-max-listpack-value" => {
+                self.set_max_listpack_value = parse_memory(value).ok_or_else(invalid)? as usize
+            }
+            "zset-max-listpack-entries" => {
+                self.zset_max_listpack_entries = value.parse().map_err(|_| invalid())?
+            }
+            "zset-max-listpack-value" => {
+                self.zset_max_listpack_value = parse_memory(value).ok_or_else(invalid)? as usize
+            }
+            "list-max-listpack-size" => match value.parse() {
+                Ok(size) if size != 0 && size >= -5 => self.list_max_listpack_size = size,
+                _ => bail!(invalid()),
+            },
+            "string-compression-threshold" => {
+                self.string_compression_threshold =
+                    parse_memory(value).ok_or_else(invalid)? as usize
+            }
+            "proto-max-bulk-len" => match parse_memory(value) {
+                // at least 1mb, like Redis
+                Some(len) if len >= 1024 * 1024 => self.proto_max_bulk_len = len as usize,
+                _ => bail!(invalid()),
+            },
+            "proto-max-multibulk-len" => match value.parse() {
+                Ok(len) if len > 0 => self.proto_max_multibulk_len = len,
+                _ => bail!(invalid()),
+            },
+            "proto-max-nesting" => match value.parse() {
+                Ok(depth) if depth > 0 => self.proto_max_nesting = depth,
+                _ => bail!(invalid()),
+            },
+            "proto-read-timeout" => {
+                self.proto_read_timeout = value.parse().map_err(|_| invalid())?
+            }
+            "read-buffer-size" => match parse_memory(value) {
+                Some(size) if size >= 1024 => self.read_buffer_size = size as usize,
+                _ => bail!(invalid()),
+            },
+            "bulk-spool-threshold" => {
+                self.bulk_spool_threshold = parse_memory(value).ok_or_else(invalid)? as usize
+            }
+            "proto-error-recovery" => {
+                self.proto_error_recovery = ErrorRecovery::from_name(value).ok_or_else(invalid)?
+            }
+            "keyspace-shards" => match value.parse() {
+                Ok(shards) if shards > 0 => self.keyspace_shards = shards,
+                _ => bail!(invalid()),
+            },
+            "databases" => match value.parse() {
+                Ok(databases) if databases > 0 => self.databases = databases,
+                _ => bail!(invalid()),
+            },
+            "engine" => self.engine = EngineKind::from_name(value).ok_or_else(invalid)?,
+            "io-backend" => self.io_backend = IoBackend::from_name(value).ok_or_else(invalid)?,
+            "otel-endpoint" => self.otel_endpoint = value.to_string(),
+            _ => bail!(ConfigError::UnknownOption(name.to_string())),
+        }
+        return Ok(());
+    }
+
+    /// Limits applied by the decoder to the input of new connections.
+    pub fn decoder_limits(&self) -> DecoderLimits {
+        return DecoderLimits {
+            max_bulk_len: self.proto_max_bulk_len,
+            max_multibulk_len: self.proto_max_multibulk_len,
+            max_nesting: self.proto_max_nesting,
+            read_timeout: match self.proto_read_timeout {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+        };
+    }
+
+    /// Time new connections have to accept the output written to them.
+    pub fn write_timeout(&self) -> Option<Duration> {
+        return match self.client_write_timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
+    }
+
+    /// Length above which the bulk strings of new connections are spooled to disk.
+    pub fn spool_threshold(&self) -> Option<usize> {
+        return match self.bulk_spool_threshold {
+            0 => None,
+            threshold => Some(threshold),
+        };
+    }
+
+    /// Limits of the hashes stored as listpacks.
+    // collection commands aren't implemented yet
+    #[allow(dead_code)]
+    pub fn hash_listpack_limits(&self) -> ListpackLimits {
+        return ListpackLimits {
+            entries: self.hash_max_listpack_entries,
+            value: self.hash_max_listpack_value,
+            ..ListpackLimits::UNLIMITED
+        };
+    }
+
+    /// Limits of the sets stored as listpacks.
+    #[allow(dead_code)]
+    pub fn set_listpack_limits(&self) -> ListpackLimits {
+        return ListpackLimits {
+            entries: self.set_max_listpack_entries,
+            value: self.set_max_listpack_value,
+            ..ListpackLimits::UNLIMITED
+        };
+    }
+
+    /// Limits of the sorted sets stored as listpacks.
+    #[allow(dead_code)]
+    pub fn zset_listpack_limits(&self) -> ListpackLimits {
+        return ListpackLimits {
+            entries: self.zset_max_listpack_entries,
+            value: self.zset_max_listpack_value,
+            ..ListpackLimits::UNLIMITED
+        };
+    }
+
+    /// Limits of the lists stored as listpacks.
+    #[allow(dead_code)]
+    pub fn list_listpack_limits(&self) -> ListpackLimits {
+        if self.list_max_listpack_size > 0 {
+            return ListpackLimits {
+                entries: self.list_max_listpack_size as usize,
+                ..ListpackLimits::UNLIMITED
+            };
+        }
+        let kb = 4 << (-self.list_max_listpack_size - 1);
+        return ListpackLimits {
+            bytes: kb * 1024,
+            ..ListpackLimits::UNLIMITED
+        };
+    }
+
+    /// Like `set`, refusing parameters that can only be given at startup.
+    pub fn set_at_runtime(&mut self, name: &str, value: &str) -> Result<()> {
+        if IMMUTABLE_PARAMETERS.contains(&name) {
+            bail!(ConfigError::Immutable(name.to_string()));
+        }
+        return self.set(name, value);
+    }
+
+    pub fn listen_address(&self) -> SocketAddr {
+        return SocketAddr::new(self.bind, self.port);
+    }
+
+    /// Address of the WebSocket listener, `None` if disabled.
+    pub fn websocket_address(&self) -> Option<SocketAddr> {
+        if self.websocket_port == 0 {
+            return None;
+        }
+        return Some(SocketAddr::new(self.bind, self.websocket_port));
+    }
+
+    /// Address of the HTTP gateway, `None` if disabled.
+    pub fn http_address(&self) -> Option<SocketAddr> {
+        if self.http_port == 0 {
+            return None;
+        }
+        return Some(SocketAddr::new(self.bind, self.http_port));
+    }
+
+    /// Address of the memcached listener, `None` if disabled.
+    pub fn memcached_address(&self) -> Option<SocketAddr> {
+        if self.memcached_port == 0 {
+            return None;
+        }
+        return Some(SocketAddr::new(self.bind, self.memcached_port));
+    }
+
+    /// true if protected mode refuses connections from `peer`.
+    pub fn protected_mode_denies(&self, peer: &SocketAddr) -> bool {
+        return self.protected_mode && !self.bind.is_loopback() && !peer.ip().is_loopback();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{parse_memory, Config, ConfigError, ErrorRecovery, MaxmemoryPolicy};
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        return args
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<String>>()
+            .into_iter();
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = Config::from_args(args(&["--latency-monitor-threshold", "100"])).unwrap();
+        assert_eq!(config.latency_monitor_threshold, 100);
+        assert_eq!(
+            config.get("latency-monitor-threshold"),
+            Some(String::from("100"))
+        );
+    }
+
+    #[test]
+    fn test_from_args_errors() {
+        let err = Config::from_args(args(&["--latency-monitor-threshold"])).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConfigError>(),
+            Some(&ConfigError::MissingValue(String::from(
+                "latency-monitor-threshold"
+            )))
+        );
+        let err = Config::from_args(args(&["--nope", "1"])).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConfigError>(),
+            Some(&ConfigError::UnknownOption(String::from("nope")))
+        );
+        let err = Config::from_args(args(&["--latency-monitor-threshold", "x"])).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConfigError>(),
+            Some(&ConfigError::InvalidValue(
+                String::from("latency-monitor-threshold"),
+                String::from("x")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_protected_mode() {
+        let local = "127.0.0.1:5000".parse().unwrap();
+        let remote = "10.0.0.2:5000".parse().unwrap();
+        let mut config = Config::default();
+        assert!(!config.protected_mode_denies(&remote));
+
+        config.set("bind", "0.0.0.0").unwrap();
+        assert!(config.protected_mode_denies(&remote));
+        assert!(!config.protected_mode_denies(&local));
+
+        config.set_at_runtime("protected-mode", "no").unwrap();
+        assert!(!config.protected_mode_denies(&remote));
+        assert!(config.set("protected-mode", "maybe").is_err());
+
+        let err = config.set_at_runtime("bind", "127.0.0.1").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConfigError>(),
+            Some(&ConfigError::Immutable(String::from("bind")))
+        );
+    }
+
+    #[test]
+    fn test_rename_command() {
+        let config = Config::from_args(args(&[
+            "--rename-command",
+            "CONFIG",
+            "",
+            "--rename-command",
+            "get",
+            "fetch",
+        ]))
+        .unwrap();
+        assert_eq!(config.resolve_command("config"), None);
+        assert_eq!(config.resolve_command("GET"), None);
+        assert_eq!(config.resolve_command("FETCH"), Some(String::from("get")));
+        assert_eq!(config.resolve_command("set"), Some(String::from("set")));
+
+        let err = Config::from_args(args(&["--rename-command", "nope", "x"])).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConfigError>(),
+            Some(&ConfigError::NoSuchCommand(String::from("nope")))
+        );
+        assert!(Config::from_args(args(&["--rename-command", "get"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("100"), Some(100));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("1kb"), Some(1024));
+        assert_eq!(parse_memory("2MB"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_memory("1g"), Some(1000 * 1000 * 1000));
+        assert_eq!(parse_memory("mb"), None);
+        assert_eq!(parse_memory("-1"), None);
+    }
+
+    #[test]
+    fn test_maxmemory_policy() {
+        let mut config = Config::default();
+        config.set("maxmemory-policy", "ALLKEYS-LRU").unwrap();
+        assert_eq!(config.maxmemory_policy, MaxmemoryPolicy::AllkeysLru);
+        assert_eq!(
+            config.get("maxmemory-policy"),
+            Some(String::from("allkeys-lru"))
+        );
+        assert!(config.set("maxmemory-policy", "nope").is_err());
+        config.set("maxmemory-policy", "volatile-ttl").unwrap();
+        assert!(config.maxmemory_policy.is_volatile());
+        assert_eq!(
+            Config::default().maxmemory_policy,
+            MaxmemoryPolicy::NoEviction
+        );
+    }
+
+    #[test]
+    fn test_maxmemory_samples() {
+        let mut config = Config::default();
+        config.set("maxmemory-samples", "10").unwrap();
+        assert_eq!(config.maxmemory_samples, 10);
+        assert!(config.set("maxmemory-samples", "0").is_err());
+        assert!(config.set("maxmemory-samples", "65").is_err());
+    }
+
+    #[test]
+    fn test_proto_limits() {
+        let mut config = Config::default();
+        config.set("proto-max-bulk-len", "1mb").unwrap();
+        config.set("proto-max-nesting", "4").unwrap();
+        assert_eq!(
+            config.get("proto-max-bulk-len"),
+            Some(String::from("1048576"))
+        );
+        assert!(config.set("proto-max-bulk-len", "1kb").is_err());
+        assert!(config.set("proto-max-multibulk-len", "0").is_err());
+        let limits = config.decoder_limits();
+        assert_eq!(limits.max_bulk_len, 1024 * 1024);
+        assert_eq!(limits.max_nesting, 4);
+
+        config.set("proto-read-timeout", "0").unwrap();
+        assert_eq!(config.decoder_limits().read_timeout, None);
+        config.set("proto-read-timeout", "5").unwrap();
+        assert_eq!(
+            config.decoder_limits().read_timeout,
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn test_read_buffer_size() {
+        let mut config = Config::default();
+        config.set("read-buffer-size", "64kb").unwrap();
+        assert_eq!(config.read_buffer_size, 64 * 1024);
+        assert_eq!(config.get("read-buffer-size"), Some(String::from("65536")));
+        assert!(config.set("read-buffer-size", "512").is_err());
+    }
+
+    #[test]
+    fn test_bulk_spool_threshold() {
+        let mut config = Config::default();
+        assert_eq!(config.spool_threshold(), None);
+        config.set("bulk-spool-threshold", "1mb").unwrap();
+        assert_eq!(config.spool_threshold(), Some(1024 * 1024));
+        assert_eq!(
+            config.get("bulk-spool-threshold"),
+            Some(String::from("1048576"))
+        );
+        assert!(config.set("bulk-spool-threshold", "lots").is_err());
+    }
+
+    #[test]
+    fn test_proto_error_recovery() {
+        let mut config = Config::default();
+        assert_eq!(
+            config.get("proto-error-recovery"),
+            Some(String::from("close"))
+        );
+        config.set("proto-error-recovery", "SKIP-LINE").unwrap();
+        assert_eq!(config.proto_error_recovery, ErrorRecovery::SkipLine);
+        assert!(config.set("proto-error-recovery", "ignore").is_err());
+    }
+
+    #[test]
+    fn test_tcp_options() {
+        let mut config = Config::default();
+        config.set("tcp-keepalive", "60").unwrap();
+        config.set("tcp-nodelay", "no").unwrap();
+        assert_eq!(config.tcp_keepalive, 60);
+        assert!(!config.tcp_nodelay);
+        assert_eq!(config.get("tcp-keepalive"), Some(String::from("60")));
+        assert_eq!(config.get("tcp-nodelay"), Some(String::from("no")));
+
+        for (name, value) in [
+            ("tcp-keepalive", "-1"),
+            ("tcp-keepalive", "soon"),
+            ("tcp-keepalive", ""),
+            ("tcp-nodelay", "maybe"),
+            ("tcp-nodelay", "1"),
+        ] {
+            let err = config.set(name, value).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ConfigError>(),
+                Some(&ConfigError::InvalidValue(
+                    String::from(name),
+                    String::from(value)
+                )),
+                "{name} {value}"
+            );
+        }
+        assert_eq!(config.tcp_keepalive, 60);
+        assert!(!config.tcp_nodelay);
+
+        let err = Config::from_args(args(&["--tcp-keepalive", "x"])).unwrap_err();
+        assert!(err.downcast_ref::<ConfigError>().is_some());
+    }
+
+    #[test]
+    fn test_acceptors() {
+        let config = Config::from_args(args(&["--acceptors", "4", "--reuseport", "yes"])).unwrap();
+        assert_eq!(config.acceptors, 4);
+        assert!(config.reuseport);
+        assert!(Config::from_args(args(&["--acceptors", "0"])).is_err());
+        let mut config = Config::default();
+        assert!(config.set_at_runtime("acceptors", "2").is_err());
+        assert!(config.set_at_runtime("reuseport", "yes").is_err());
+    }
+
+    #[test]
+    fn test_websocket_port() {
+        assert_eq!(Config::default().websocket_address(), None);
+        let config = Config::from_args(args(&["--websocket-port", "6380"])).unwrap();
+        assert_eq!(
+            config.websocket_address(),
+            Some("127.0.0.1:6380".parse().unwrap())
+        );
+        assert!(Config::from_args(args(&["--websocket-port", "70000"])).is_err());
+        let mut config = Config::default();
+        assert!(config.set_at_runtime("websocket-port", "6380").is_err());
+    }
+
+    #[test]
+    fn test_http_port() {
+        assert_eq!(Config::default().http_address(), None);
+        let config = Config::from_args(args(&["--http-port", "8080"])).unwrap();
+        assert_eq!(
+            config.http_address(),
+            Some("127.0.0.1:8080".parse().unwrap())
+        );
+        let mut config = Config::default();
+        assert!(config.set_at_runtime("http-port", "8080").is_err());
+    }
+
+    #[test]
+    fn test_memcached_port() {
+        assert_eq!(Config::default().memcached_address(), None);
+        let config = Config::from_args(args(&["--memcached-port", "11211"])).unwrap();
+        assert_eq!(
+            config.memcached_address(),
+            Some("127.0.0.1:11211".parse().unwrap())
+        );
+        let mut config = Config::default();
+        assert!(config.set_at_runtime("memcached-port", "11211").is_err());
+    }
+
+    #[test]
+    fn test_client_output_limits() {
+        let mut config = Config::default();
+        assert_eq!(config.write_timeout(), Some(Duration::from_secs(30)));
+        config.set_at_runtime("client-write-timeout", "0").unwrap();
+        assert_eq!(config.write_timeout(), None);
+        config
+            .set_at_runtime("client-output-buffer-limit", "normal 10mb 1mb 5")
+            .unwrap();
+        assert_eq!(
+            config.client_output_buffer_limit.normal.hard,
+            10 * 1024 * 1024
+        );
+        assert_eq!(
+            config.get("client-output-buffer-limit").unwrap(),
+            "normal 10485760 1048576 5 slave 268435456 67108864 60 pubsub 33554432 8388608 60"
+        );
+        assert!(config
+            .set("client-output-buffer-limit", "normal 1")
+            .is_err());
+    }
+
+    #[test]
+    fn test_listpack_limits() {
+        let mut config = Config::default();
+        assert_eq!(config.list_listpack_limits().bytes, 8 * 1024);
+        config.set("list-max-listpack-size", "-5").unwrap();
+        assert_eq!(config.list_listpack_limits().bytes, 64 * 1024);
+        config.set("list-max-listpack-size", "100").unwrap();
+        assert_eq!(config.list_listpack_limits().entries, 100);
+        assert!(config.set("list-max-listpack-size", "-6").is_err());
+        assert!(config.set("list-max-listpack-size", "0").is_err());
+
+        config.set("hash-max-listpack-value", "1kb").unwrap();
+        assert_eq!(config.hash_listpack_limits().value, 1024);
+        assert_eq!(config.hash_listpack_limits().entries, 128);
+    }
+}
@@ -1,39 +1,1075 @@
+use crate::clock::{self, Clock};
+use crate::config::Config;
+use crate::evict;
+use crate::value::Value;
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::BuildHasher;
+use std::mem::size_of;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
 use bytes::Bytes;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
 
-pub type MapInner = HashMap<String, DBValue>;
-pub type Map = Arc<Mutex<MapInner>>;
+/// Default number of shards of the keyspace.
+pub const KEYSPACE_SHARDS: usize = 16;
+
+/// Default number of databases.
+pub const DATABASES: usize = 16;
+
+/// Longest time the active expiry cycle sleeps between runs.
+pub const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
 
-fn timestamp() -> usize {
-    let start = SystemTime::now();
-    let since_the_epoch = start
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards");
-    return since_the_epoch.as_millis() as usize;
+/// Max keys removed from each shard by a run of the active expiry cycle, so it
+/// doesn't hold a shard's lock for too long when many keys expire at once.
+pub const ACTIVE_EXPIRE_LIMIT: usize = 1000;
+
+/// Minutes since the epoch of the timestamp `now` (ms), the resolution of the LFU
+/// decay clock.
+fn minutes(now: usize) -> usize {
+    return now / 60_000;
 }
 
+/// A value of the keyspace with the metadata shared by every type.
 #[derive(Clone)]
 pub struct DBValue {
-    pub value: Bytes,
+    pub value: Value,
     pub expiration: usize,
+    /// timestamp (ms) of the last time the value was accessed
+    pub lru: usize,
+    /// logarithmic access frequency counter
+    pub lfu_counter: u8,
+    /// time (minutes) the access frequency counter was last decremented
+    pub lfu_decr_time: usize,
+    /// opaque flags of the values stored by memcached clients, 0 for the rest
+    pub flags: u32,
 }
 
 impl DBValue {
-    pub fn with_expiration(value: Bytes, mut expiration: usize) -> Self {
+    /// A value created at `now` expiring `expiration` ms later, never if 0.
+    pub fn with_expiration(value: impl Into<Value>, mut expiration: usize, now: usize) -> Self {
         if expiration > 0 {
-            let now = timestamp();
-            expiration = now + expiration;
+            expiration += now;
         }
         return DBValue {
-            value: value,
+            value: value.into(),
             expiration: expiration,
+            lru: now,
+            lfu_counter: evict::LFU_INIT_VAL,
+            lfu_decr_time: minutes(now),
+            flags: 0,
         };
     }
 
-    pub fn is_expired(&self) -> bool {
-        let now = timestamp();
+    pub fn is_expired(&self, now: usize) -> bool {
         return self.expiration != 0 && self.expiration <= now;
     }
+
+    pub fn is_volatile(&self) -> bool {
+        return self.expiration != 0;
+    }
+
+    /// milliseconds until the value expires, `None` if it has no expiration
+    pub fn ttl(&self, now: usize) -> Option<usize> {
+        if !self.is_volatile() {
+            return None;
+        }
+        return Some(self.expiration.saturating_sub(now));
+    }
+
+    /// milliseconds since the value was last accessed
+    pub fn idle_time(&self, now: usize) -> usize {
+        return now.saturating_sub(self.lru);
+    }
+
+    /// Access frequency counter, decayed by the time elapsed since it was last decremented.
+    pub fn lfu_frequency(&self, decay_time: u64, now: usize) -> u8 {
+        return evict::lfu_decay(
+            self.lfu_counter,
+            minutes(now).saturating_sub(self.lfu_decr_time),
+            decay_time,
+        );
+    }
+
+    /// Updates the access metadata used by the eviction policies.
+    pub fn touch(&mut self, config: &Config, now: usize) {
+        self.lru = now;
+        let counter = self.lfu_frequency(config.lfu_decay_time, now);
+        self.lfu_counter = evict::lfu_log_incr(counter, config.lfu_log_factor);
+        self.lfu_decr_time = minutes(now);
+    }
+}
+
+/// The keyspace.
+///
+/// Entries are stored densely in a Vec with a HashMap indexing their position,
+/// which allows picking random entries in constant time (needed to sample
+/// eviction candidates). The approximate memory used by the entries is kept
+/// up to date on every write, both for the map and in a counter shared with the
+/// other shards of the keyspace, and so is an index of the keys with
+/// an expiration ordered by deadline, which lets expired keys be found without
+/// scanning the whole keyspace.
+pub struct MapInner {
+    index: HashMap<Bytes, usize>,
+    entries: Vec<(Bytes, DBValue)>,
+    used_memory: usize,
+    /// memory used by every shard of the keyspace the map belongs to
+    keyspace_memory: Arc<AtomicUsize>,
+    /// (expiration, key) of every key with an expiration
+    expires: BTreeSet<(usize, Bytes)>,
+    /// clock of the keyspace the map belongs to
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for MapInner {
+    fn default() -> Self {
+        return MapInner {
+            index: HashMap::default(),
+            entries: Vec::default(),
+            used_memory: 0,
+            keyspace_memory: Arc::default(),
+            expires: BTreeSet::default(),
+            clock: clock::system(),
+        };
+    }
+}
+
+impl MapInner {
+    /// A map not belonging to a keyspace.
+    #[cfg(test)]
+    pub fn new() -> Self {
+        return MapInner::default();
+    }
+
+    /// A map not belonging to a keyspace, telling the time with `clock`.
+    #[cfg(test)]
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        return MapInner {
+            clock: clock,
+            ..Default::default()
+        };
+    }
+
+    /// A map accounting its memory in the shared `keyspace_memory` counter too.
+    fn with_keyspace_memory(keyspace_memory: Arc<AtomicUsize>, clock: Arc<dyn Clock>) -> Self {
+        return MapInner {
+            keyspace_memory: keyspace_memory,
+            clock: clock,
+            ..Default::default()
+        };
+    }
+
+    /// Current time (ms) by the clock of the keyspace.
+    pub fn now(&self) -> usize {
+        return self.clock.now();
+    }
+
+    fn add_memory(&mut self, bytes: usize) {
+        self.used_memory += bytes;
+        self.keyspace_memory.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sub_memory(&mut self, bytes: usize) {
+        self.used_memory -= bytes;
+        self.keyspace_memory.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    /// Approximate bytes used by every entry of the keyspace.
+    pub fn used_memory(&self) -> usize {
+        return self.used_memory;
+    }
+
+    /// Number of slots allocated by the index.
+    pub fn capacity(&self) -> usize {
+        return self.index.capacity();
+    }
+
+    /// Returns the entry for `key` without updating its access time.
+    pub fn get(&self, key: &[u8]) -> Option<&DBValue> {
+        return self.index.get(key).map(|ix| &self.entries[*ix].1);
+    }
+
+    /// Returns the entry for `key`, marking it as accessed.
+    pub fn lookup(&mut self, key: &[u8], config: &Config) -> Option<&DBValue> {
+        let ix = *self.index.get(key)?;
+        let now = self.clock.now();
+        let entry = &mut self.entries[ix].1;
+        entry.touch(config, now);
+        return Some(entry);
+    }
+
+    pub fn insert(&mut self, key: Bytes, value: DBValue) -> Option<DBValue> {
+        self.add_memory(entry_memory_usage(&key, &value, 0));
+        if value.is_volatile() {
+            self.expires.insert((value.expiration, key.clone()));
+        }
+        if let Some(&ix) = self.index.get(&key) {
+            let old = std::mem::replace(&mut self.entries[ix].1, value);
+            self.sub_memory(entry_memory_usage(&key, &old, 0));
+            if old.is_volatile() && old.expiration != self.entries[ix].1.expiration {
+                self.expires.remove(&(old.expiration, key));
+            }
+            return Some(old);
+        }
+        self.index.insert(key.clone(), self.entries.len());
+        self.entries.push((key, value));
+        return None;
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<DBValue> {
+        let ix = self.index.remove(key)?;
+        let (key, value) = self.entries.swap_remove(ix);
+        if let Some((moved, _)) = self.entries.get(ix) {
+            self.index.insert(moved.clone(), ix);
+        }
+        self.sub_memory(entry_memory_usage(&key, &value, 0));
+        if value.is_volatile() {
+            self.expires.remove(&(value.expiration, key));
+        }
+        return Some(value);
+    }
+
+    /// Modifies the value of `key` in place, accounting the memory it grows or
+    /// shrinks by. Its expiration must be changed with `set_expiration` instead.
+    /// Returns `None` if the key doesn't exist.
+    pub fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut DBValue) -> R) -> Option<R> {
+        let ix = *self.index.get(key)?;
+        let (key, value) = &mut self.entries[ix];
+        let before = entry_memory_usage(key, value, 0);
+        let expiration = value.expiration;
+        let res = f(value);
+        debug_assert_eq!(value.expiration, expiration, "expiration changed in update");
+        let after = entry_memory_usage(key, value, 0);
+        self.sub_memory(before);
+        self.add_memory(after);
+        return Some(res);
+    }
+
+    /// Sets the expiration timestamp (ms) of `key`, 0 removes it. Returns false if
+    /// the key doesn't exist.
+    pub fn set_expiration(&mut self, key: &[u8], expiration: usize) -> bool {
+        let ix = match self.index.get(key) {
+            Some(ix) => *ix,
+            None => return false,
+        };
+        let (key, value) = &mut self.entries[ix];
+        if value.is_volatile() {
+            self.expires.remove(&(value.expiration, key.clone()));
+        }
+        value.expiration = expiration;
+        if value.is_volatile() {
+            self.expires.insert((expiration, key.clone()));
+        }
+        return true;
+    }
+
+    /// Keys with an expiration and their expiration timestamp (ms), nearest first.
+    pub fn expirations(&self) -> impl Iterator<Item = (usize, &Bytes)> {
+        return self
+            .expires
+            .iter()
+            .map(|(expiration, key)| (*expiration, key));
+    }
+
+    /// Number of keys with an expiration.
+    pub fn volatile_len(&self) -> usize {
+        return self.expires.len();
+    }
+
+    /// Nearest expiration timestamp (ms) of the keys.
+    pub fn next_expiration(&self) -> Option<usize> {
+        return self.expires.first().map(|(expiration, _)| *expiration);
+    }
+
+    /// Removes up to `limit` keys expired at `now`, nearest expiration first, returning
+    /// them with their values.
+    pub fn remove_expired(&mut self, now: usize, limit: usize) -> Vec<(Bytes, DBValue)> {
+        let mut removed = Vec::new();
+        while removed.len() < limit {
+            let key = match self.expires.first() {
+                Some((expiration, key)) if *expiration <= now => key.clone(),
+                _ => break,
+            };
+            let value = self.remove(&key).expect("expiring key not in the map");
+            removed.push((key, value));
+        }
+        return removed;
+    }
+
+    /// Removes every entry, returning them.
+    pub fn clear(&mut self) -> Vec<(Bytes, DBValue)> {
+        self.index.clear();
+        self.expires.clear();
+        self.sub_memory(self.used_memory);
+        return std::mem::take(&mut self.entries);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &DBValue)> {
+        return self.entries.iter().map(|(k, v)| (k, v));
+    }
+
+    /// Visits the entries before position `end` from the last one backwards, up to
+    /// `count` of them, and returns the position of the last one visited.
+    ///
+    /// Removing an entry moves the last one into its place, so going backwards
+    /// means the entries yet to be visited stay in `[0, position)` however the map
+    /// changes between calls. New entries are appended after them.
+    pub fn scan(&self, end: usize, count: usize, mut f: impl FnMut(&Bytes, &DBValue)) -> usize {
+        let end = end.min(self.entries.len());
+        let start = end.saturating_sub(count);
+        for (key, value) in self.entries[start..end].iter().rev() {
+            f(key, value);
+        }
+        return start;
+    }
+
+    /// Returns the entry stored at position `ix % len`, allowing callers to pick
+    /// random entries.
+    pub fn get_index(&self, ix: usize) -> Option<(&Bytes, &DBValue)> {
+        if self.is_empty() {
+            return None;
+        }
+        let (key, value) = &self.entries[ix % self.entries.len()];
+        return Some((key, value));
+    }
+}
+
+/// The keyspace, partitioned in shards each one behind its own lock.
+///
+/// Keys are assigned to a shard by their hash, so commands on different keys rarely
+/// contend for the same lock. Operations spanning several keys lock their shards
+/// with `lock_keys`, and operations on the whole keyspace with `lock_all`; both take
+/// the locks in ascending shard order so they can't deadlock each other.
+pub struct Keyspace {
+    shards: Vec<Mutex<MapInner>>,
+    hasher: RandomState,
+    /// memory used by the entries of every shard, updated by the shards themselves
+    used_memory: Arc<AtomicUsize>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Keyspace {
+    /// A keyspace telling the time with the system clock.
+    #[cfg(test)]
+    pub fn new(shards: usize) -> Self {
+        return Keyspace::with_clock(shards, clock::system());
+    }
+
+    /// A keyspace telling the time with `clock`, to expire keys and track their
+    /// access times.
+    pub fn with_clock(shards: usize, clock: Arc<dyn Clock>) -> Self {
+        let used_memory = Arc::new(AtomicUsize::new(0));
+        return Keyspace {
+            shards: (0..shards.max(1))
+                .map(|_| {
+                    let map = MapInner::with_keyspace_memory(used_memory.clone(), clock.clone());
+                    Mutex::new(map)
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            used_memory: used_memory,
+            clock: clock,
+        };
+    }
+
+    /// A keyspace made of the given maps, in which keys may not live in their shard.
+    /// It uses the clock of the first map.
+    #[cfg(test)]
+    pub fn from_shards(maps: Vec<MapInner>) -> Self {
+        let used_memory = Arc::new(AtomicUsize::new(0));
+        let clock = match maps.first() {
+            Some(map) => map.clock.clone(),
+            None => clock::system(),
+        };
+        let mut shards = Vec::new();
+        for mut map in maps {
+            used_memory.fetch_add(map.used_memory, Ordering::Relaxed);
+            map.keyspace_memory = used_memory.clone();
+            map.clock = clock.clone();
+            shards.push(Mutex::new(map));
+        }
+        return Keyspace {
+            shards: shards,
+            hasher: RandomState::new(),
+            used_memory: used_memory,
+            clock: clock,
+        };
+    }
+
+    /// Current time (ms) by the clock of the keyspace.
+    pub fn now(&self) -> usize {
+        return self.clock.now();
+    }
+
+    #[cfg(test)]
+    pub fn into_shards(self) -> Vec<MapInner> {
+        return self
+            .shards
+            .into_iter()
+            .map(|shard| shard.into_inner().unwrap_or_else(PoisonError::into_inner))
+            .collect();
+    }
+
+    pub fn shard_count(&self) -> usize {
+        return self.shards.len();
+    }
+
+    /// Index of the shard holding `key`.
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        return self.hasher.hash_one(key) as usize % self.shards.len();
+    }
+
+    /// `count` keyspaces for the databases selected with SELECT. They share the
+    /// counter of the memory used, so `used_memory` is the memory used by every
+    /// database as `maxmemory` expects, and the hasher, so a key lives in the same
+    /// shard index whatever its database.
+    pub fn databases(count: usize, shards: usize, clock: Arc<dyn Clock>) -> Vec<Keyspace> {
+        let first = Keyspace::with_clock(shards, clock);
+        let mut databases = Vec::new();
+        for _ in 1..count.max(1) {
+            databases.push(Keyspace {
+                shards: (0..first.shards.len())
+                    .map(|_| {
+                        let map = MapInner::with_keyspace_memory(
+                            first.used_memory.clone(),
+                            first.clock.clone(),
+                        );
+                        Mutex::new(map)
+                    })
+                    .collect(),
+                hasher: first.hasher.clone(),
+                used_memory: first.used_memory.clone(),
+                clock: first.clock.clone(),
+            });
+        }
+        databases.insert(0, first);
+        return databases;
+    }
+
+    /// Locks the shard holding `key`.
+    pub fn lock(&self, key: &[u8]) -> MutexGuard<'_, MapInner> {
+        return self.lock_shard(self.shard_of(key));
+    }
+
+    pub fn lock_shard(&self, ix: usize) -> MutexGuard<'_, MapInner> {
+        // a command panicking while holding the lock doesn't take the shard down with it
+        return self.shards[ix]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+
+    /// Locks the shards holding `keys`, see `ShardGuards::get`.
+    pub fn lock_keys<K: AsRef<[u8]>>(&self, keys: &[K]) -> ShardGuards<'_> {
+        let mut indexes: Vec<usize> = keys.iter().map(|k| self.shard_of(k.as_ref())).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        return ShardGuards {
+            keyspace: self,
+            guards: indexes
+                .into_iter()
+                .map(|ix| (ix, self.lock_shard(ix)))
+                .collect(),
+        };
+    }
+
+    /// Locks every shard, for commands accessing keys only known while running, like
+    /// the ones the patterns of SORT refer to. See `ShardGuards::get`.
+    pub fn lock_every_key(&self) -> ShardGuards<'_> {
+        return ShardGuards {
+            keyspace: self,
+            guards: (0..self.shards.len())
+                .map(|ix| (ix, self.lock_shard(ix)))
+                .collect(),
+        };
+    }
+
+    /// Locks every shard, for operations that need a consistent view of the keyspace.
+    pub fn lock_all(&self) -> Vec<MutexGuard<'_, MapInner>> {
+        return (0..self.shards.len())
+            .map(|ix| self.lock_shard(ix))
+            .collect();
+    }
+
+    /// Locks the shards one at a time, for aggregations that tolerate the keyspace
+    /// changing while they run.
+    pub fn shards(&self) -> impl Iterator<Item = MutexGuard<'_, MapInner>> {
+        return (0..self.shards.len()).map(|ix| self.lock_shard(ix));
+    }
+
+    /// Exchanges the entries of two of the keyspaces built by `databases`, locking
+    /// every shard of both. `self` must be the database with the lowest index, as
+    /// its shards are locked first.
+    pub fn swap(&self, other: &Keyspace) {
+        let mut first = self.lock_all();
+        let mut second = other.lock_all();
+        for (a, b) in first.iter_mut().zip(second.iter_mut()) {
+            std::mem::swap(&mut **a, &mut **b);
+        }
+    }
+
+    /// Removes every entry from every shard at once, returning them.
+    pub fn flush(&self) -> Vec<(Bytes, DBValue)> {
+        let mut entries = Vec::new();
+        for mut shard in self.lock_all() {
+            entries.append(&mut shard.clear());
+        }
+        return entries;
+    }
+
+    /// Visits about `count` entries starting at `cursor`, 0 on the first call, and
+    /// returns the cursor to continue from, 0 once every entry was visited.
+    ///
+    /// Shards are locked one at a time, and only while visiting up to `count` of
+    /// their entries, so iterating the whole keyspace doesn't stall other clients.
+    /// Like SCAN in Redis, entries present during the whole iteration are visited at
+    /// least once, while entries added or removed meanwhile may or may not be, and
+    /// entries can be visited more than once.
+    pub fn scan(&self, cursor: u64, count: usize, mut f: impl FnMut(&Bytes, &DBValue)) -> u64 {
+        // the cursor holds the shard and the position to continue from in it,
+        // 0 meaning from the last entry
+        let shards = self.shards.len() as u64;
+        let mut shard = (cursor % shards) as usize;
+        let mut end = (cursor / shards) as usize;
+        let count = count.max(1);
+        let mut visited = 0;
+        loop {
+            let map = self.lock_shard(shard);
+            let from = if end == 0 {
+                map.len()
+            } else {
+                end.min(map.len())
+            };
+            let start = map.scan(from, count - visited, &mut f);
+            drop(map);
+            visited += from - start;
+            if start > 0 {
+                return start as u64 * shards + shard as u64;
+            }
+            shard += 1;
+            end = 0;
+            if shard == self.shards.len() {
+                return 0;
+            }
+            if visited >= count {
+                return shard as u64;
+            }
+        }
+    }
+
+    /// Number of keys, including the expired ones not removed yet.
+    pub fn len(&self) -> usize {
+        return self.shards().map(|shard| shard.len()).sum();
+    }
+
+    // nothing needs it yet, it's here to go with `len`
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        return self.shards().all(|shard| shard.is_empty());
+    }
+
+    /// Approximate bytes used by every entry of the keyspace, and of the other
+    /// databases sharing its counter, without locking the shards.
+    pub fn used_memory(&self) -> usize {
+        return self.used_memory.load(Ordering::Relaxed);
+    }
+
+    /// Removes the keys expired at `now`, up to `limit` from each shard. The values are
+    /// returned to be freed once the shards are unlocked.
+    pub fn remove_expired(&self, now: usize, limit: usize) -> Vec<(Bytes, DBValue)> {
+        let mut removed = Vec::new();
+        for mut shard in self.shards() {
+            removed.extend(shard.remove_expired(now, limit));
+        }
+        return removed;
+    }
+
+    /// Nearest expiration timestamp (ms) of the keys.
+    pub fn next_expiration(&self) -> Option<usize> {
+        return self
+            .shards()
+            .filter_map(|shard| shard.next_expiration())
+            .min();
+    }
+}
+
+/// Locks held on the shards of a set of keys.
+pub struct ShardGuards<'a> {
+    keyspace: &'a Keyspace,
+    guards: Vec<(usize, MutexGuard<'a, MapInner>)>,
+}
+
+impl ShardGuards<'_> {
+    /// The locked shard holding `key`.
+    ///
+    /// Panics if `key` wasn't one of the keys the locks were taken for.
+    pub fn get(&mut self, key: &[u8]) -> &mut MapInner {
+        let ix = self.keyspace.shard_of(key);
+        let pos = self
+            .guards
+            .binary_search_by_key(&ix, |(ix, _)| *ix)
+            .expect("shard of the key isn't locked");
+        return &mut self.guards[pos].1;
+    }
+}
+
+/// Approximate bytes used by the hash table slot holding an entry, besides the
+/// heap allocations of the key and the value.
+pub const ENTRY_OVERHEAD: usize = size_of::<(Bytes, DBValue)>() + size_of::<(Bytes, usize)>() + 1;
+
+/// Approximate heap bytes owned by a key.
+pub fn key_memory_usage(key: &[u8]) -> usize {
+    return key.len();
+}
+
+impl DBValue {
+    /// Approximate heap bytes owned by the value. `samples` is the number of
+    /// elements inspected to estimate the size of collections (0 means all of them).
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        return self.value.memory_usage(samples);
+    }
+}
+
+/// Approximate bytes used by an entry of the keyspace.
+pub fn entry_memory_usage(key: &[u8], value: &DBValue, samples: usize) -> usize {
+    return ENTRY_OVERHEAD + key_memory_usage(key) + value.memory_usage(samples);
+}
+
+/// Key counts of the keyspace, as reported by the INFO keyspace section.
+#[derive(Debug, PartialEq, Eq)]
+pub struct KeyspaceStats {
+    pub keys: usize,
+    /// keys with an expiration
+    pub expires: usize,
+    /// average time to live (ms) of the keys with an expiration
+    pub avg_ttl: usize,
+}
+
+impl KeyspaceStats {
+    pub fn from_maps(maps: impl Iterator<Item = impl Deref<Target = MapInner>>) -> Self {
+        let mut keys = 0;
+        let mut expires = 0;
+        let mut total_ttl = 0;
+        for map in maps {
+            let now = map.now();
+            keys += map.len();
+            expires += map.volatile_len();
+            total_ttl += map
+                .expirations()
+                .map(|(expiration, _)| expiration.saturating_sub(now))
+                .sum::<usize>();
+        }
+        let avg_ttl = match expires {
+            0 => 0,
+            expires => total_ttl / expires,
+        };
+        return KeyspaceStats {
+            keys: keys,
+            expires: expires,
+            avg_ttl: avg_ttl,
+        };
+    }
+}
+
+/// Aggregated memory usage of the keyspace, as reported by MEMORY STATS.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub keys_count: usize,
+    /// memory used by the hash table itself, including unused slots
+    pub overhead_hashtable: usize,
+    /// heap bytes owned by keys and values
+    pub dataset_bytes: usize,
+}
+
+impl MemoryStats {
+    pub fn from_maps(maps: impl Iterator<Item = impl Deref<Target = MapInner>>) -> Self {
+        let mut stats = MemoryStats {
+            keys_count: 0,
+            overhead_hashtable: 0,
+            dataset_bytes: 0,
+        };
+        for map in maps {
+            stats.keys_count += map.len();
+            stats.overhead_hashtable += map.capacity() * ENTRY_OVERHEAD;
+            stats.dataset_bytes += map.used_memory() - map.len() * ENTRY_OVERHEAD;
+        }
+        return stats;
+    }
+
+    pub fn total(&self) -> usize {
+        return self.overhead_hashtable + self.dataset_bytes;
+    }
+
+    pub fn bytes_per_key(&self) -> usize {
+        if self.keys_count == 0 {
+            return 0;
+        }
+        return self.total() / self.keys_count;
+    }
+
+    /// percentage of the total memory used by the dataset itself
+    pub fn dataset_percentage(&self) -> f64 {
+        if self.total() == 0 {
+            return 0.0;
+        }
+        return self.dataset_bytes as f64 * 100.0 / self.total() as f64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::AssertUnwindSafe;
+
+    use bytes::Bytes;
+
+    use super::{
+        entry_memory_usage, DBValue, Keyspace, KeyspaceStats, MapInner, MemoryStats, ENTRY_OVERHEAD,
+    };
+    use crate::clock::{self, MockClock};
+    use crate::config::Config;
+    use crate::value::Value;
+
+    fn value(v: &'static str) -> DBValue {
+        return DBValue::with_expiration(Bytes::from(v), 0, 0);
+    }
+
+    #[test]
+    fn test_entry_memory_usage() {
+        assert_eq!(
+            entry_memory_usage(b"key", &value("value"), 0),
+            ENTRY_OVERHEAD + 8
+        );
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        let mut map = MapInner::new();
+        assert_eq!(
+            MemoryStats::from_maps([&map].into_iter()).bytes_per_key(),
+            0
+        );
+        map.insert(Bytes::from("a"), value("abc"));
+        map.insert(Bytes::from("bb"), value("x"));
+        let stats = MemoryStats::from_maps([&map].into_iter());
+        assert_eq!(stats.keys_count, 2);
+        assert_eq!(stats.dataset_bytes, 7);
+        assert!(stats.overhead_hashtable >= 2 * ENTRY_OVERHEAD);
+        assert_eq!(stats.total(), stats.overhead_hashtable + 7);
+    }
+
+    #[test]
+    fn test_insert_remove_keeps_index() {
+        let mut map = MapInner::new();
+        map.insert(Bytes::from("a"), value("1"));
+        map.insert(Bytes::from("b"), value("2"));
+        map.insert(Bytes::from("c"), value("3"));
+        assert!(map.insert(Bytes::from("b"), value("22")).is_some());
+        assert_eq!(map.len(), 3);
+
+        // removing the first entry moves the last one into its slot
+        assert_eq!(
+            map.remove(b"a").unwrap().value,
+            Value::from(Bytes::from("1"))
+        );
+        assert!(map.remove(b"a").is_none());
+        assert_eq!(map.get(b"c").unwrap().value, Value::from(Bytes::from("3")));
+        assert_eq!(map.get(b"b").unwrap().value, Value::from(Bytes::from("22")));
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn test_used_memory() {
+        let mut map = MapInner::new();
+        map.insert(Bytes::from("a"), value("x"));
+        let one = map.used_memory();
+        assert_eq!(one, entry_memory_usage(b"a", &value("x"), 0));
+        map.insert(Bytes::from("a"), value("wxyz"));
+        assert_eq!(map.used_memory(), one + 3);
+        // integers are stored without allocating
+        map.insert(Bytes::from("a"), value("1234"));
+        assert_eq!(map.used_memory(), one - 1);
+        map.remove(b"a");
+        assert_eq!(map.used_memory(), 0);
+    }
+
+    #[test]
+    fn test_update_accounts_memory() {
+        let keyspace = Keyspace::new(2);
+        let key = String::from("a");
+        keyspace
+            .lock(key.as_bytes())
+            .insert(Bytes::from(key.clone()), value("x"));
+        let one = keyspace.used_memory();
+        let updated = keyspace.lock(key.as_bytes()).update(key.as_bytes(), |v| {
+            v.value = Value::from(Bytes::from("wxyz"));
+        });
+        assert_eq!(updated, Some(()));
+        assert_eq!(keyspace.used_memory(), one + 3);
+        assert_eq!(keyspace.lock(key.as_bytes()).used_memory(), one + 3);
+        assert_eq!(keyspace.lock(b"b").update(b"b", |_| ()), None);
+        keyspace.lock(key.as_bytes()).remove(key.as_bytes());
+        assert_eq!(keyspace.used_memory(), 0);
+    }
+
+    #[test]
+    fn test_keyspace_stats() {
+        let clock = MockClock::new(1_000_000);
+        let mut map = MapInner::with_clock(clock.clone());
+        map.insert(Bytes::from("a"), value("1"));
+        map.insert(
+            Bytes::from("b"),
+            DBValue::with_expiration(Bytes::from("2"), 10_000, map.now()),
+        );
+        map.insert(
+            Bytes::from("c"),
+            DBValue::with_expiration(Bytes::from("3"), 20_000, map.now()),
+        );
+        let stats = KeyspaceStats::from_maps([&map].into_iter());
+        assert_eq!(stats.keys, 3);
+        assert_eq!(stats.expires, 2);
+        assert_eq!(stats.avg_ttl, 15_000);
+        clock.advance(5_000);
+        let stats = KeyspaceStats::from_maps([&map].into_iter());
+        assert_eq!(stats.avg_ttl, 10_000);
+    }
+
+    #[test]
+    fn test_expiration_follows_the_clock() {
+        let clock = MockClock::new(1_000_000);
+        let keyspace = Keyspace::with_clock(4, clock.clone());
+        let value = DBValue::with_expiration(Bytes::from("v"), 1_000, keyspace.now());
+        keyspace.lock(b"a").insert(Bytes::from("a"), value);
+        let is_expired = || {
+            let map = keyspace.lock(b"a");
+            return map.get(b"a").map(|v| v.is_expired(map.now()));
+        };
+        let ttl = || {
+            let map = keyspace.lock(b"a");
+            return map.get(b"a").and_then(|v| v.ttl(map.now()));
+        };
+        assert_eq!(is_expired(), Some(false));
+        assert_eq!(ttl(), Some(1_000));
+        clock.advance(999);
+        assert_eq!(is_expired(), Some(false));
+        assert_eq!(ttl(), Some(1));
+        assert!(keyspace.remove_expired(keyspace.now(), 10).is_empty());
+        clock.advance(1);
+        assert_eq!(is_expired(), Some(true));
+        assert_eq!(keyspace.remove_expired(keyspace.now(), 10).len(), 1);
+        assert_eq!(is_expired(), None);
+    }
+
+    #[test]
+    fn test_access_time_follows_the_clock() {
+        let clock = MockClock::new(1_000_000);
+        let mut map = MapInner::with_clock(clock.clone());
+        map.insert(Bytes::from("a"), value("1"));
+        map.lookup(b"a", &Config::default());
+        clock.advance(2_000);
+        assert_eq!(map.get(b"a").unwrap().idle_time(map.now()), 2_000);
+        map.lookup(b"a", &Config::default());
+        assert_eq!(map.get(b"a").unwrap().idle_time(map.now()), 0);
+    }
+
+    #[test]
+    fn test_keyspace_shards() {
+        let keyspace = Keyspace::new(4);
+        for i in 0..100 {
+            let key = format!("key:{i}");
+            keyspace
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value("v"));
+        }
+        assert_eq!(keyspace.len(), 100);
+        // keys are spread across the shards
+        assert!(keyspace.shards().all(|shard| !shard.is_empty()));
+        let stats = KeyspaceStats::from_maps(keyspace.shards());
+        assert_eq!(stats.keys, 100);
+        let per_shard: usize = keyspace.shards().map(|s| s.used_memory()).sum();
+        assert_eq!(keyspace.used_memory(), per_shard);
+    }
+
+    #[test]
+    fn test_scan() {
+        let keyspace = Keyspace::new(4);
+        for i in 0..100 {
+            let key = format!("key:{i}");
+            keyspace
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value("v"));
+        }
+        let mut visited = Vec::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            cursor = keyspace.scan(cursor, 7, |key, _| visited.push(key.clone()));
+            calls += 1;
+            // keys present for the whole iteration are still visited when others
+            // are removed or added in between
+            if calls == 3 {
+                for i in 90..100 {
+                    let key = format!("key:{i}");
+                    keyspace.lock(key.as_bytes()).remove(key.as_bytes());
+                }
+                keyspace.lock(b"new").insert(Bytes::from("new"), value("v"));
+            }
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert!(calls >= 90 / 7);
+        for i in 0..90 {
+            assert!(
+                visited.contains(&Bytes::from(format!("key:{i}"))),
+                "key:{i} not visited"
+            );
+        }
+
+        let empty = Keyspace::new(4);
+        assert_eq!(empty.scan(0, 10, |_, _| panic!("no entries")), 0);
+    }
+
+    #[test]
+    fn test_lock_keys() {
+        let keyspace = Keyspace::new(8);
+        let keys = ["a", "b", "c", "a"];
+        let mut guards = keyspace.lock_keys(&keys);
+        for key in keys {
+            guards
+                .get(key.as_bytes())
+                .insert(Bytes::from(key.to_string()), value("v"));
+        }
+        drop(guards);
+        assert_eq!(keyspace.len(), 3);
+        assert!(keyspace.lock(b"b").get(b"b").is_some());
+        assert_eq!(keyspace.lock_all().len(), 8);
+    }
+
+    #[test]
+    fn test_databases() {
+        let databases = Keyspace::databases(3, 4, clock::system());
+        assert_eq!(databases.len(), 3);
+        databases[1].lock(b"a").insert(Bytes::from("a"), value("v"));
+        assert!(databases[0].lock(b"a").get(b"a").is_none());
+        assert_eq!(databases[1].len(), 1);
+        // memory is accounted for every database together
+        assert!(databases[1].used_memory() > 0);
+        assert_eq!(databases[0].used_memory(), databases[1].used_memory());
+        for key in ["a", "b", "c", "d", "e"] {
+            assert_eq!(
+                databases[0].shard_of(key.as_bytes()),
+                databases[2].shard_of(key.as_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn test_swap() {
+        let databases = Keyspace::databases(2, 4, clock::system());
+        databases[0].lock(b"a").insert(Bytes::from("a"), value("0"));
+        let expiring = DBValue::with_expiration(Bytes::from("1"), 1000, databases[1].now());
+        databases[1].lock(b"b").insert(Bytes::from("b"), expiring);
+        let used_memory = databases[0].used_memory();
+        databases[0].swap(&databases[1]);
+        assert!(databases[0].lock(b"a").get(b"a").is_none());
+        assert!(databases[1].lock(b"a").get(b"a").is_some());
+        assert_eq!(databases[0].lock(b"b").volatile_len(), 1);
+        assert_eq!(databases[0].used_memory(), used_memory);
+    }
+
+    #[test]
+    fn test_flush() {
+        let keyspace = Keyspace::new(8);
+        for i in 0..100 {
+            let key = format!("key:{i}");
+            let value = DBValue::with_expiration(Bytes::from("v"), 1000 * (i % 2), 0);
+            keyspace
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value);
+        }
+        assert!(keyspace.used_memory() > 0);
+        assert_eq!(keyspace.flush().len(), 100);
+        assert!(keyspace.is_empty());
+        assert_eq!(keyspace.used_memory(), 0);
+        assert!(keyspace
+            .shards()
+            .all(|shard| shard.next_expiration().is_none()));
+    }
+
+    #[test]
+    fn test_expiration_index() {
+        let mut map = MapInner::new();
+        map.insert(Bytes::from("a"), value("1"));
+        map.insert(
+            Bytes::from("b"),
+            DBValue::with_expiration(Bytes::from("2"), 20_000, map.now()),
+        );
+        map.insert(
+            Bytes::from("c"),
+            DBValue::with_expiration(Bytes::from("3"), 10_000, map.now()),
+        );
+        assert_eq!(map.volatile_len(), 2);
+        let order: Vec<&Bytes> = map.expirations().map(|(_, key)| key).collect();
+        assert_eq!(order, vec!["c", "b"]);
+
+        // overwriting or removing keys updates the index
+        map.insert(Bytes::from("c"), value("3"));
+        assert_eq!(map.volatile_len(), 1);
+        let expiration = map.get(b"b").unwrap().expiration;
+        assert_eq!(map.next_expiration(), Some(expiration));
+        assert!(map.set_expiration(b"a", 1));
+        assert!(!map.set_expiration(b"missing", 1));
+        assert_eq!(map.next_expiration(), Some(1));
+        map.remove(b"a");
+        assert_eq!(map.next_expiration(), Some(expiration));
+        assert!(map.set_expiration(b"b", 0));
+        assert_eq!(map.next_expiration(), None);
+    }
+
+    #[test]
+    fn test_remove_expired() {
+        let keyspace = Keyspace::new(4);
+        for i in 0..10 {
+            let key = format!("key:{i}");
+            keyspace
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key.clone()), value("v"));
+            keyspace
+                .lock(key.as_bytes())
+                .set_expiration(key.as_bytes(), i * 100 + 1);
+        }
+        let mut removed: Vec<Bytes> = keyspace
+            .remove_expired(500, 100)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        removed.sort();
+        assert_eq!(removed, vec!["key:0", "key:1", "key:2", "key:3", "key:4"]);
+        assert_eq!(keyspace.len(), 5);
+        assert_eq!(keyspace.next_expiration(), Some(501));
+        // at most `limit` keys per shard
+        assert!(keyspace.remove_expired(usize::MAX, 1).len() <= 4);
+    }
+
+    #[test]
+    fn test_poisoned_shard_is_usable() {
+        let keyspace = Keyspace::new(1);
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let _shard = keyspace.lock(b"a");
+            panic!("command failed");
+        }));
+        assert!(res.is_err());
+        keyspace.lock(b"a").insert(Bytes::from("a"), value("1"));
+        assert_eq!(keyspace.len(), 1);
+    }
 }
@@ -1,2 +1,62 @@
+#[cfg(test)]
+mod fuzz;
+pub mod inline;
 pub mod v1;
 pub mod v2;
+pub mod v3;
+
+use crate::protocol::DataType;
+
+/// Default size of the reads done by the decoders on the client connection.
+pub const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// true for the empty (`*0`) and null (`*-1`) multibulks, which Redis skips without
+/// replying when clients send them as commands.
+pub fn is_empty_command(packet: &DataType) -> bool {
+    return match packet {
+        DataType::NullArray => true,
+        DataType::Array { items } => items.is_empty(),
+        _ => false,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::BufReader;
+    use tokio_stream::StreamExt;
+
+    use super::is_empty_command;
+    use super::v1::Decoder;
+    use super::v2::StreamDecoder;
+    use crate::protocol::DataType;
+
+    const INPUT: &[u8] = b"*0\r\n*-1\r\n*1\r\n$4\r\nPING\r\n*0\r\n";
+
+    fn ping() -> DataType {
+        return DataType::Array {
+            items: vec![DataType::bulk("PING")],
+        };
+    }
+
+    #[tokio::test]
+    async fn test_empty_commands_are_skipped() {
+        let mut reader = BufReader::new(INPUT);
+        let mut decoder = StreamDecoder::new(&mut reader);
+        let packets: Vec<DataType> = decoder
+            .as_stream()
+            .filter_map(|packet| packet.ok())
+            .filter(|packet| !is_empty_command(packet))
+            .collect()
+            .await;
+        assert_eq!(packets, vec![ping()]);
+
+        let mut reader = INPUT;
+        let packets = Decoder::new(&mut reader).parse().await.unwrap();
+        assert_eq!(packets.len(), 4);
+        let commands: Vec<DataType> = packets
+            .into_iter()
+            .filter(|packet| !is_empty_command(packet))
+            .collect();
+        assert_eq!(commands, vec![ping()]);
+    }
+}
@@ -0,0 +1,131 @@
+/// Fuzzing of the decoders with proptest, a short session run by every `cargo test`
+/// next to the cargo-fuzz targets in `fuzz/`.
+///
+/// The inputs are arbitrary bytes, bytes drawn from the RESP syntax, and encoded
+/// values with some bytes replaced and cut short. For every input, each decoder must
+/// not panic (nor abort allocating what a length prefix announces), and the values
+/// it accepts must encode to bytes that decode back to the same encoding.
+///
+/// Run longer sessions with `PROPTEST_CASES=100000 cargo test --release fuzz`.
+use bytes::{Bytes, BytesMut};
+use proptest::prelude::*;
+use tokio_stream::StreamExt;
+
+use crate::decoders::v1::DataTypeFrom;
+use crate::decoders::v2::{DecoderLimits, StreamDecoder};
+use crate::decoders::v3::{decode_command, ChunkDecoder};
+use crate::protocol::{strategy, DataType};
+
+/// Bytes making up RESP values, so random inputs get past the first type byte.
+const SYNTAX: &[u8] = b"*$+-:,#(_=%~>|!\r\n\r\n-0123456789abcPING ";
+
+fn mutated_encoding() -> impl Strategy<Value = Vec<u8>> {
+    let edits = prop::collection::vec((any::<usize>(), any::<u8>()), 0..4);
+    return (strategy::datatype(), edits, any::<usize>()).prop_map(|(value, edits, cut)| {
+        let mut encoded = value.encode().unwrap();
+        for (pos, byte) in edits {
+            if !encoded.is_empty() {
+                let len = encoded.len();
+                encoded[pos % len] = byte;
+            }
+        }
+        encoded.truncate(cut % (encoded.len() + 1));
+        return encoded;
+    });
+}
+
+fn input() -> impl Strategy<Value = Vec<u8>> {
+    return prop_oneof![
+        prop::collection::vec(any::<u8>(), 0..256),
+        prop::collection::vec(prop::sample::select(SYNTAX), 0..256),
+        mutated_encoding(),
+    ];
+}
+
+/// Checks that an accepted value survives encoding it and decoding it again, with
+/// v3 as it supports every type. Encodings are compared instead of values so NaN
+/// Doubles can be checked too.
+fn assert_idempotent(value: &DataType) {
+    let encoded = value.encode().expect("accepted value can't be encoded");
+    let mut buffer = BytesMut::from(&encoded[..]);
+    let decoded = decode_command(&mut buffer, &DecoderLimits::default())
+        .expect("encoded value isn't accepted")
+        .expect("encoded value is incomplete");
+    assert_eq!(decoded.encode().unwrap(), encoded);
+}
+
+proptest! {
+    #[test]
+    fn fuzz_v1(input in input()) {
+        let mut bytes = Bytes::from(input);
+        while !bytes.is_empty() {
+            match DataType::from_bytes(&mut bytes) {
+                Ok(value) => assert_idempotent(&value),
+                Err(_) => break,
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_v2(input in input(), buffer_size in 1..64usize) {
+        let limits = DecoderLimits {
+            max_bulk_len: 1024,
+            max_multibulk_len: 1024,
+            ..DecoderLimits::default()
+        };
+        runtime().block_on(async {
+            let mut reader = &input[..];
+            let mut decoder = StreamDecoder::new(&mut reader)
+                .with_limits(limits)
+                .with_buffer_size(buffer_size);
+            let mut values = Box::pin(decoder.as_stream());
+            while let Some(Ok(value)) = values.next().await {
+                assert_idempotent(&value);
+            }
+        });
+    }
+
+    #[test]
+    fn fuzz_v3(input in input(), buffer_size in 1..64usize) {
+        let limits = DecoderLimits {
+            max_bulk_len: 1024,
+            max_multibulk_len: 1024,
+            ..DecoderLimits::default()
+        };
+        let mut buffer = BytesMut::from(&input[..]);
+        while let Ok(Some(value)) = decode_command(&mut buffer, &limits) {
+            assert_idempotent(&value);
+        }
+        runtime().block_on(async {
+            let mut reader = &input[..];
+            let mut decoder = ChunkDecoder::new(&mut reader)
+                .with_limits(limits)
+                .with_buffer_size(buffer_size);
+            let mut values = Box::pin(decoder.as_stream());
+            while let Some(Ok(value)) = values.next().await {
+                assert_idempotent(&value);
+            }
+        });
+    }
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    return tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+}
+
+#[test]
+fn test_announced_lengths_arent_allocated() {
+    // lengths far beyond what's in the input must fail, not abort allocating them
+    for input in [
+        &b"*9223372036854775807\r\n:1\r\n"[..],
+        b"%9223372036854775807\r\n",
+        b"%-5\r\n",
+        b"~-3\r\n",
+    ] {
+        let mut bytes = Bytes::from(input);
+        assert!(DataType::from_bytes(&mut bytes).is_err());
+    }
+}
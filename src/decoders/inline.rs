@@ -0,0 +1,191 @@
+/// Inline commands, the protocol used by `telnet`/`nc` sessions: a line of space
+/// separated arguments terminated by CRLF (or just LF), instead of a RESP Array.
+///
+/// Arguments can be quoted following the rules of Redis' `sdssplitargs`:
+/// - `"double quoted"` arguments support the escapes `\n`, `\r`, `\t`, `\b`, `\a`,
+///   `\xHH` (a hex encoded byte) and `\<char>` for any other char.
+/// - `'single quoted'` arguments are taken literally, except for `\'`.
+///
+/// A closing quote must be followed by a space or the end of the line.
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use crate::protocol::DataType;
+
+/// Max length of an inline command, longer lines are rejected.
+pub const INLINE_MAX_SIZE: usize = 1024 * 64;
+
+/// Returns true if `byte` starts an inline command rather than a RESP type.
+pub fn is_inline(byte: u8) -> bool {
+    return !matches!(
+        byte,
+        b'+' | b'-'
+            | b':'
+            | b'$'
+            | b'*'
+            | b'%'
+            | b'~'
+            | b'>'
+            | b'='
+            | b','
+            | b'#'
+            | b'('
+            | b'_'
+            | b'\0'
+    );
+}
+
+/// Parses an inline command line (without the line terminator) as an Array of
+/// Bulk Strings, like the ones sent by regular clients. Returns None for empty lines.
+pub fn parse_line(line: &[u8]) -> Result<Option<DataType>> {
+    let args = split_args(line)?;
+    if args.is_empty() {
+        return Ok(None);
+    }
+    let mut items = Vec::with_capacity(args.len());
+    for arg in args {
+        items.push(DataType::BulkString {
+            string: Bytes::from(arg),
+        });
+    }
+    return Ok(Some(DataType::Array { items }));
+}
+
+/// Splits a line into arguments, handling quotes and escapes.
+pub fn split_args(line: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut p = 0;
+    loop {
+        // NUL separates arguments too, it ends the line in `sdssplitargs`
+        while p < line.len() && (line[p].is_ascii_whitespace() || line[p] == b'\0') {
+            p += 1;
+        }
+        if p == line.len() {
+            return Ok(args);
+        }
+        let mut arg = Vec::new();
+        let mut in_double = false;
+        let mut in_single = false;
+        loop {
+            let c = line.get(p).copied();
+            if in_double {
+                match c {
+                    None => bail!("unbalanced quotes in request"),
+                    Some(b'\\') if is_hex_escape(&line[p..]) => {
+                        arg.push(hex_byte(line[p + 2], line[p + 3]).unwrap());
+                        p += 3;
+                    }
+                    Some(b'\\') if p + 1 < line.len() => {
+                        p += 1;
+                        arg.push(match line[p] {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                    }
+                    Some(b'"') => {
+                        // closing quote must be followed by a space or nothing at all
+                        if p + 1 < line.len() && !line[p + 1].is_ascii_whitespace() {
+                            bail!("unbalanced quotes in request");
+                        }
+                        p += 1;
+                        break;
+                    }
+                    Some(other) => arg.push(other),
+                }
+            } else if in_single {
+                match c {
+                    None => bail!("unbalanced quotes in request"),
+                    Some(b'\\') if line.get(p + 1) == Some(&b'\'') => {
+                        p += 1;
+                        arg.push(b'\'');
+                    }
+                    Some(b'\'') => {
+                        if p + 1 < line.len() && !line[p + 1].is_ascii_whitespace() {
+                            bail!("unbalanced quotes in request");
+                        }
+                        p += 1;
+                        break;
+                    }
+                    Some(other) => arg.push(other),
+                }
+            } else {
+                match c {
+                    None => break,
+                    Some(b' ' | b'\n' | b'\r' | b'\t' | b'\0') => break,
+                    Some(b'"') => in_double = true,
+                    Some(b'\'') => in_single = true,
+                    Some(other) => arg.push(other),
+                }
+            }
+            p += 1;
+        }
+        args.push(arg);
+    }
+}
+
+/// Returns true if `rest` starts with a `\xHH` escape.
+fn is_hex_escape(rest: &[u8]) -> bool {
+    return rest.len() >= 4 && rest[1] == b'x' && hex_byte(rest[2], rest[3]).is_some();
+}
+
+fn hex_byte(high: u8, low: u8) -> Option<u8> {
+    let high = (high as char).to_digit(16)?;
+    let low = (low as char).to_digit(16)?;
+    return Some((high * 16 + low) as u8);
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{parse_line, split_args};
+    use crate::protocol::DataType;
+
+    fn split(line: &str) -> Vec<String> {
+        return split_args(line.as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(|arg| String::from_utf8(arg).unwrap())
+            .collect();
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(split("SET key value"), vec!["SET", "key", "value"]);
+        assert_eq!(split("  PING   "), vec!["PING"]);
+        assert!(split("").is_empty());
+        assert_eq!(
+            split(r#"SET "a key" "line\nbreak\x41""#),
+            vec!["SET", "a key", "line\nbreakA"]
+        );
+        assert_eq!(split(r"ECHO 'it\'s \n'"), vec!["ECHO", r"it's \n"]);
+        assert_eq!(split(r#"ECHO """#), vec!["ECHO", ""]);
+        assert_eq!(split(r#"ECHO "\xZZ""#), vec!["ECHO", "xZZ"]);
+        assert_eq!(split("ECHO\0\0a"), vec!["ECHO", "a"]);
+    }
+
+    #[test]
+    fn test_split_args_unbalanced_quotes() {
+        for line in [r#"ECHO "open"#, r#"ECHO "a"b"#, "ECHO 'open"] {
+            let err = split_args(line.as_bytes()).unwrap_err();
+            assert_eq!(err.to_string(), "unbalanced quotes in request");
+        }
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line(b"   ").unwrap(), None);
+        assert_eq!(
+            parse_line(b"PING").unwrap(),
+            Some(DataType::Array {
+                items: vec![DataType::BulkString {
+                    string: Bytes::from("PING")
+                }]
+            })
+        );
+    }
+}
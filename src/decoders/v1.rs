@@ -1,16 +1,15 @@
 /// First iteration of decoder.
 ///
-/// `Decoder` can only parse inputs of 1024 bytes at a time.
+/// `Decoder` can only parse inputs of up to its buffer size at a time.
 /// Returns an error if an uncompleted input is parsed.
 ///
 /// The `DataTypeFrom` trait is also exported implementing synchronous decoding
 /// of DataTypes using DataType::from_bytes.
+use crate::decoders::{inline, READ_BUFFER_SIZE};
 use crate::protocol::{DataType, SafeRead};
 
-use std::io::Read;
-
-use anyhow::{bail, Result};
-use bytes::{Buf, Bytes};
+use anyhow::{anyhow, bail, Result};
+use bytes::{Bytes, BytesMut};
 use thiserror::Error;
 use tokio::io::AsyncReadExt;
 
@@ -39,8 +38,7 @@ impl DataTypeFrom for DataType {
                 return Ok(DataType::SimpleString { string });
             }
             '-' => {
-                let (type_, error) = decode_error(bytes)?;
-                return Ok(DataType::Error { type_, error });
+                return decode_error(bytes);
             }
             ':' => {
                 let number = decode_integer(bytes)?;
@@ -52,9 +50,29 @@ impl DataTypeFrom for DataType {
                     None => Ok(DataType::NullBulkString),
                 };
             }
+            '=' => {
+                return match decode_bulk_string(bytes)? {
+                    Some(data) => DataType::from_verbatim(String::from_utf8(data.to_vec())?),
+                    None => bail!("invalid verbatim string length"),
+                };
+            }
             '*' => {
-                let items = decode_array(bytes)?;
-                return Ok(DataType::Array { items });
+                return match decode_array(bytes)? {
+                    Some(items) => Ok(DataType::Array { items }),
+                    None => Ok(DataType::NullArray),
+                };
+            }
+            '%' => {
+                let items = decode_map(bytes)?;
+                return Ok(DataType::Map { items });
+            }
+            '~' => {
+                let items = decode_array(bytes)?.ok_or(anyhow!("invalid set length"))?;
+                return Ok(DataType::Set { items });
+            }
+            '>' => {
+                let items = decode_array(bytes)?.ok_or(anyhow!("invalid push length"))?;
+                return Ok(DataType::Push { items });
             }
             '\0' => bail!(ScanError::StreamEnded),
             _ => bail!(ScanError::UnkownDataType(typechar)),
@@ -62,109 +80,133 @@ impl DataTypeFrom for DataType {
     }
 }
 
-/// Reads from Bytes until '\r\n' is found.
-fn read_until_rn(bytes: &mut Bytes, buf: &mut Vec<u8>) -> Result<()> {
-    loop {
-        let mut c = bytes.get_u8_safe()?;
-        if c == b'\r' {
-            let mut next = bytes.get_u8_safe()?;
-            while next == b'\r' {
-                // handle cases like '\r\r\r\n'
-                buf.push(c);
-                c = next;
-                next = bytes.get_u8_safe()?;
-            }
-            if next != b'\n' {
-                buf.push(c);
-                buf.push(next);
-                continue;
-            }
-            break;
-        }
-        buf.push(c);
-    }
-    return Ok(());
-}
-
 fn read_until_rn_string(bytes: &mut Bytes) -> Result<String> {
-    let mut buf = Vec::new();
-    read_until_rn(bytes, &mut buf)?;
-    return Ok(String::from_utf8(buf)?);
-}
-
-fn read_until_rn_integer(bytes: &mut Bytes) -> Result<isize> {
-    return Ok(read_until_rn_string(bytes)?.parse::<isize>()?);
+    return Ok(String::from_utf8(bytes.read_line_crlf()?.to_vec())?);
 }
 
 /// Decoder for DataType::SimpleString
 fn decode_simple_string(bytes: &mut Bytes) -> Result<String> {
-    return read_until_rn_string(bytes);
+    let string = read_until_rn_string(bytes)?;
+    // a lone CR or LF is read as part of the line
+    if string.contains(['\r', '\n']) {
+        bail!("simple strings can't contain CR or LF");
+    }
+    return Ok(string);
 }
 
 /// Decoder for DataType::Error
-/// TODO: pending error types implementation
-fn decode_error(bytes: &mut Bytes) -> Result<(String, String)> {
-    return Ok((String::new(), decode_simple_string(bytes)?));
+fn decode_error(bytes: &mut Bytes) -> Result<DataType> {
+    return Ok(DataType::from_error_line(decode_simple_string(bytes)?));
 }
 
 /// Decoder for DataType::Integer
 fn decode_integer(bytes: &mut Bytes) -> Result<isize> {
-    return read_until_rn_integer(bytes);
+    return bytes.read_integer_line();
 }
 
 /// Decoder for DataType::BulkString and DataType::NullBulkString
-fn decode_bulk_string(bytes: &mut Bytes) -> Result<Option<String>> {
-    let size = read_until_rn_integer(bytes)?;
+fn decode_bulk_string(bytes: &mut Bytes) -> Result<Option<Bytes>> {
+    let size = bytes.read_integer_line()?;
     if size == -1 {
         return Ok(None);
     }
     if size < -1 {
         bail!("invalid bulk string length");
     }
-    let mut data_buf = Vec::with_capacity(size as usize);
-    bytes.reader().read_exact(&mut data_buf)?;
-    for _ in 0..size {
-        data_buf.push(bytes.get_u8_safe()?);
-    }
-    if bytes.get_u8_safe()? != b'\r' || bytes.get_u8_safe()? != b'\n' {
+    let data = bytes.get_slice_safe(size as usize)?;
+    if &bytes.get_slice_safe(2)?[..] != b"\r\n" {
         bail!("invalid string termination");
     }
-    return Ok(Some(String::from_utf8(data_buf)?));
+    return Ok(Some(data));
 }
 
-/// Decoder for DataType::Array
-fn decode_array(bytes: &mut Bytes) -> Result<Vec<DataType>> {
-    let size = read_until_rn_integer(bytes)?;
-    let mut items = Vec::with_capacity(size as usize);
+/// Decoder for DataType::Array, DataType::Set and DataType::Push.
+/// Returns None for a Null Array.
+fn decode_array(bytes: &mut Bytes) -> Result<Option<Vec<DataType>>> {
+    let size = bytes.read_integer_line()?;
+    if size == -1 {
+        return Ok(None);
+    }
+    if size < -1 {
+        bail!("invalid array length");
+    }
+    // don't trust the announced size for preallocating
+    let mut items = Vec::with_capacity((size as usize).min(1024));
     for _ in 0..size {
         let created = DataType::from_bytes(bytes)?;
         items.push(created);
     }
+    return Ok(Some(items));
+}
+
+/// Decoder for DataType::Map
+fn decode_map(bytes: &mut Bytes) -> Result<Vec<(DataType, DataType)>> {
+    let size = bytes.read_integer_line()?;
+    if size < 0 {
+        bail!("invalid map length");
+    }
+    let mut items = Vec::with_capacity((size as usize).min(1024));
+    for _ in 0..size {
+        let field = DataType::from_bytes(bytes)?;
+        let value = DataType::from_bytes(bytes)?;
+        items.push((field, value));
+    }
     return Ok(items);
 }
 
+/// Decoder for inline commands, terminated by '\n' or '\r\n'.
+fn decode_inline(bytes: &mut Bytes) -> Result<Option<DataType>> {
+    let end = match bytes.iter().position(|b| *b == b'\n' || *b == b'\0') {
+        Some(end) if bytes[end] == b'\n' => end,
+        _ => bail!("unterminated inline command"),
+    };
+    let line = bytes.split_to(end + 1);
+    let line = line.strip_suffix(b"\n").unwrap();
+    return inline::parse_line(line.strip_suffix(b"\r").unwrap_or(line));
+}
+
 /// Decode RESP data from a stream
 pub struct Decoder<'a, R> {
     stream: &'a mut R,
+    /// reused between reads once the values parsed from it are dropped
+    buffer: BytesMut,
+    buffer_size: usize,
 }
 
 impl<'a, R: AsyncReadExt + std::marker::Unpin> Decoder<'a, R> {
     pub fn new(stream: &'a mut R) -> Self {
-        return Decoder { stream: stream };
+        return Decoder {
+            stream: stream,
+            buffer: BytesMut::new(),
+            buffer_size: READ_BUFFER_SIZE,
+        };
+    }
+
+    /// Replaces the default size of the reads done by the decoder.
+    pub fn with_buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        return self;
     }
 
     pub async fn parse(&mut self) -> Result<Vec<DataType>> {
-        // NOTE: Only reads 1024 bytes, so bigger inputs will fail.
+        // NOTE: Only reads up to the buffer size, so bigger inputs will fail.
         // This is fixed on `decoders::v2::StreamDecoder`.
-        let mut buf = [0u8; 1024];
-        match self.stream.read(&mut buf).await {
-            Ok(0) => bail!(ScanError::StreamClosed),
-            _ => {}
-        };
+        self.buffer.clear();
+        self.buffer.reserve(self.buffer_size);
+        let mut limited = (&mut *self.stream).take(self.buffer_size as u64);
+        if let Ok(0) = limited.read_buf(&mut self.buffer).await {
+            bail!(ScanError::StreamClosed);
+        }
 
         let mut parsed = Vec::new();
-        let mut bytes = Bytes::from(buf.to_vec());
+        let mut bytes = self.buffer.split().freeze();
         while !bytes.is_empty() {
+            if inline::is_inline(bytes[0]) {
+                if let Some(command) = decode_inline(&mut bytes)? {
+                    parsed.push(command);
+                }
+                continue;
+            }
             let datatype = DataType::from_bytes(&mut bytes);
             match datatype {
                 Ok(t) => parsed.push(t),
@@ -183,49 +225,12 @@ impl<'a, R: AsyncReadExt + std::marker::Unpin> Decoder<'a, R> {
 mod test {
     use bytes::Bytes;
 
-    use super::{read_until_rn, DataType, DataTypeFrom};
-
-    #[test]
-    fn test_read_until_rn_basic() {
-        let tests = &[
-            "Some string",
-            "Some string\nwith new\nlines",
-            "with multiple \r\r",
-        ];
-        for test in tests {
-            let expected = String::from(*test);
-            let mut buf = Vec::new();
-            let mut bytes = Bytes::from(format!("{}\r\n", expected));
-            read_until_rn(&mut bytes, &mut buf).unwrap();
-            assert_eq!(String::from_utf8(buf).unwrap(), expected);
-        }
-    }
+    use tokio::io::BufReader;
 
-    #[test]
-    fn test_read_until_rn_multiple_rn() {
-        let data = String::from("Some string\r\nother string");
-        let mut buf = Vec::new();
-        let mut bytes = Bytes::from(format!("{}\r\n", data));
-        read_until_rn(&mut bytes, &mut buf).unwrap();
-        assert_eq!(String::from_utf8(buf).unwrap(), "Some string");
-        assert_eq!(
-            String::from_utf8(bytes.to_vec()).unwrap(),
-            "other string\r\n"
-        );
-    }
+    use proptest::prelude::*;
 
-    #[test]
-    fn test_read_until_rn_without_delim() {
-        let expected = String::from("not finished");
-        let mut buf = Vec::new();
-        let mut bytes = Bytes::from(expected.clone());
-        match read_until_rn(&mut bytes, &mut buf) {
-            Err(e) => assert_eq!(e.to_string(), "no bytes left"),
-            Ok(..) => assert!(false, "collect should fail"),
-        };
-        // input buffer was still modified
-        assert_eq!(String::from_utf8(buf).unwrap(), expected);
-    }
+    use super::{DataType, DataTypeFrom, Decoder};
+    use crate::protocol::strategy;
 
     #[test]
     fn test_decode_simple_string() {
@@ -237,7 +242,7 @@ mod test {
         let encoded = DataType::encode(&parsed).unwrap();
         assert_eq!(
             String::from_utf8(encoded).unwrap(),
-            String::from(orig),
+            orig,
             "string encoded data differs from original data"
         );
     }
@@ -258,7 +263,7 @@ mod test {
         let encoded = DataType::encode(&parsed).unwrap();
         assert_eq!(
             String::from_utf8(encoded).unwrap(),
-            String::from(orig),
+            orig,
             "string encoded data differs from original data"
         );
     }
@@ -274,7 +279,7 @@ mod test {
             let encoded = DataType::encode(&parsed).unwrap();
             assert_eq!(
                 String::from_utf8(encoded).unwrap(),
-                String::from(orig),
+                orig,
                 "string encoded data differs from original data"
             );
         }
@@ -291,13 +296,13 @@ mod test {
             assert_eq!(
                 parsed,
                 DataType::BulkString {
-                    string: String::from(*test)
+                    string: Bytes::from(String::from(*test))
                 }
             );
             let encoded = DataType::encode(&parsed).unwrap();
             assert_eq!(
                 String::from_utf8(encoded).unwrap(),
-                String::from(orig),
+                orig,
                 "string encoded data differs from original data"
             );
         }
@@ -312,7 +317,7 @@ mod test {
         let encoded = DataType::encode(&parsed).unwrap();
         assert_eq!(
             String::from_utf8(encoded).unwrap(),
-            String::from(orig),
+            orig,
             "string encoded data differs from original data"
         );
     }
@@ -344,7 +349,7 @@ mod test {
                                 error: String::from("World")
                             },
                             DataType::BulkString {
-                                string: String::from("Hello\nWorld")
+                                string: Bytes::from("Hello\nWorld")
                             }
                         ]
                     },
@@ -355,8 +360,115 @@ mod test {
         let encoded = DataType::encode(&parsed).unwrap();
         assert_eq!(
             String::from_utf8(encoded).unwrap(),
-            String::from(orig),
+            orig,
             "array encoded data differs from original data"
         );
     }
+
+    #[test]
+    fn test_decode_map_and_set() {
+        let orig = "%2\r\n+first\r\n:1\r\n$6\r\nsecond\r\n~2\r\n+a\r\n+b\r\n";
+        let mut data = Bytes::from(orig);
+        let parsed = DataType::from_bytes(&mut data).unwrap();
+        assert_eq!(
+            parsed,
+            DataType::Map {
+                items: vec![
+                    (
+                        DataType::SimpleString {
+                            string: String::from("first")
+                        },
+                        DataType::Integer { number: 1 }
+                    ),
+                    (
+                        DataType::BulkString {
+                            string: Bytes::from("second")
+                        },
+                        DataType::Set {
+                            items: vec![
+                                DataType::SimpleString {
+                                    string: String::from("a")
+                                },
+                                DataType::SimpleString {
+                                    string: String::from("b")
+                                },
+                            ]
+                        }
+                    ),
+                ]
+            }
+        );
+        let encoded = DataType::encode(&parsed).unwrap();
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            orig,
+            "map encoded data differs from original data"
+        );
+    }
+
+    #[test]
+    fn test_decode_verbatim_string() {
+        let orig = "=15\r\ntxt:Some string\r\n";
+        let mut data = Bytes::from(orig);
+        let parsed = DataType::from_bytes(&mut data).unwrap();
+        assert_eq!(
+            parsed,
+            DataType::VerbatimString {
+                format: String::from("txt"),
+                string: String::from("Some string"),
+            }
+        );
+        assert_eq!(String::from_utf8(parsed.encode().unwrap()).unwrap(), orig);
+
+        let mut data = Bytes::from("=4\r\ntext\r\n");
+        assert!(DataType::from_bytes(&mut data).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_decode_inline() {
+        let mut reader = BufReader::new("PING\r\n*1\r\n$4\r\nPING\r\nECHO 'a b'\n".as_bytes());
+        let mut decoder = Decoder::new(&mut reader);
+        let parsed = decoder.parse().await.unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0], parsed[1]);
+        assert_eq!(
+            parsed[2],
+            DataType::Array {
+                items: vec![
+                    DataType::BulkString {
+                        string: Bytes::from("ECHO")
+                    },
+                    DataType::BulkString {
+                        string: Bytes::from("a b")
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_null_array() {
+        let orig = "*2\r\n*-1\r\n$-1\r\n";
+        let mut data = Bytes::from(orig);
+        let parsed = DataType::from_bytes(&mut data).unwrap();
+        assert_eq!(
+            parsed,
+            DataType::Array {
+                items: vec![DataType::NullArray, DataType::NullBulkString]
+            }
+        );
+        assert_eq!(String::from_utf8(parsed.encode().unwrap()).unwrap(), orig);
+
+        let mut data = Bytes::from("~-1\r\n");
+        assert!(DataType::from_bytes(&mut data).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(value in strategy::nested(strategy::resp2_scalar())) {
+            let mut data = Bytes::from(value.encode().unwrap());
+            prop_assert_eq!(DataType::from_bytes(&mut data).unwrap(), value);
+            prop_assert!(data.is_empty());
+        }
+    }
 }
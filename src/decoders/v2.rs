@@ -1,15 +1,23 @@
 /// Second iteration of decoder. This one is implemented as a state machine
 /// and async iterator (tokio_stream::Stream), fixing the issue of input limits.
-use std::collections::VecDeque;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::SeekFrom;
 use std::marker::Unpin;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use async_stream::stream;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::time::{self, Instant};
 use tokio_stream::Stream;
 
+use crate::decoders::{inline, READ_BUFFER_SIZE};
 use crate::protocol::{DataType, SafeRead};
 
 #[derive(Error, Debug, PartialEq, Eq)]
@@ -19,76 +27,291 @@ pub enum ParseError {
 
     #[error("closed stream")]
     StreamClosed,
+
+    #[error("timeout reading command")]
+    ReadTimeout,
 }
 
 #[derive(Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 enum State {
     ExpectingDataTypeIdent,
     ExpectingSimpleStringChar,
     ExpectingInteger,
-    ExpectingBulkStringSize,
-    ExpectingBulkStringChar(isize),
+    ExpectingBulkStringSize(Type),
+    ExpectingBulkStringChar(Type, isize),
     ExpectingErrorData,
-    ExpectingArraySize,
+    ExpectingDouble,
+    ExpectingBoolean,
+    ExpectingBigNumber,
+    ExpectingNull,
+    ExpectingInlineChar,
+    ExpectingAggregateSize(Aggregate),
+}
+
+/// Kinds of aggregate types, all of them parsed through the array buffer stack.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Aggregate {
+    Array,
+    Map,
+    Set,
+    Push,
+}
+
+impl Aggregate {
+    /// Number of elements to read for an aggregate of the given size,
+    /// maps are sized by their field-value pairs.
+    pub fn elements(&self, size: isize) -> isize {
+        return match self {
+            Aggregate::Map => size * 2,
+            _ => size,
+        };
+    }
+
+    pub fn as_datatype(&self, mut items: Vec<DataType>) -> DataType {
+        return match self {
+            Aggregate::Array => DataType::Array { items: items },
+            Aggregate::Set => DataType::Set { items: items },
+            Aggregate::Push => DataType::Push { items: items },
+            Aggregate::Map => {
+                let mut pairs = Vec::with_capacity(items.len() / 2);
+                while !items.is_empty() {
+                    let field = items.remove(0);
+                    let value = items.remove(0);
+                    pairs.push((field, value));
+                }
+                DataType::Map { items: pairs }
+            }
+        };
+    }
 }
 
-enum Type {
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Type {
     SimpleString,
     Integer,
     BulkString,
+    VerbatimString,
     NullBulkString,
+    NullArray,
     Error,
+    Double,
+    Boolean,
+    BigNumber,
+    Null,
 }
 
 impl Type {
-    pub fn as_datatype(&self, buf: &Vec<u8>) -> Result<DataType> {
+    pub fn as_datatype(&self, buf: &[u8]) -> Result<DataType> {
         let dt = match self {
             Type::SimpleString => DataType::SimpleString {
-                string: String::from_utf8(buf.clone())?,
+                string: simple_line(buf)?,
             },
             Type::Integer => DataType::Integer {
-                number: String::from_utf8(buf.clone())?.parse()?,
+                number: parse_number(buf).ok_or(anyhow!("invalid integer"))?,
             },
             Type::BulkString => DataType::BulkString {
-                string: String::from_utf8(buf.clone())?,
+                string: Bytes::copy_from_slice(buf),
             },
+            Type::VerbatimString => DataType::from_verbatim(String::from_utf8(buf.to_vec())?)?,
             Type::NullBulkString => DataType::NullBulkString,
-            Type::Error => {
-                let err = String::from_utf8(buf.clone())?;
-                DataType::Error {
-                    type_: String::new(),
-                    error: err,
+            Type::NullArray => DataType::NullArray,
+            Type::Error => DataType::from_error_line(simple_line(buf)?),
+            Type::Double => DataType::Double {
+                number: parse_number(buf).ok_or(anyhow!("invalid double"))?,
+            },
+            Type::Boolean => DataType::Boolean {
+                value: match buf {
+                    b"t" => true,
+                    b"f" => false,
+                    _ => bail!("invalid boolean"),
+                },
+            },
+            Type::BigNumber => {
+                let number = String::from_utf8(buf.to_vec())?;
+                let digits = number.strip_prefix(['-', '+']).unwrap_or(&number);
+                if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    bail!("invalid big number");
+                }
+                DataType::BigNumber { number: number }
+            }
+            Type::Null => {
+                if !buf.is_empty() {
+                    bail!("invalid null");
                 }
+                DataType::Null
             }
         };
         return Ok(dt);
     }
 }
 
+/// Text of a Simple String or Error. Lines are only terminated by CRLF, so they can
+/// hold a lone CR or LF, which is refused as they couldn't be encoded back.
+fn simple_line(buf: &[u8]) -> Result<String> {
+    if buf.iter().any(|b| matches!(b, b'\r' | b'\n')) {
+        bail!("simple strings can't contain CR or LF");
+    }
+    return Ok(String::from_utf8(buf.to_vec())?);
+}
+
+/// Default max length of a bulk string (512mb).
+pub const PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Default max number of elements of an aggregate.
+pub const PROTO_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+/// Default max depth of nested aggregates.
+pub const PROTO_MAX_NESTING: usize = 32;
+
+/// Default time a client has to finish sending a command once it started.
+pub const PROTO_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Caps on the input accepted by `StreamDecoder`, so a hostile client can't exhaust
+/// the memory of the server with huge lengths or deeply nested aggregates, or hold
+/// on to a half parsed command forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecoderLimits {
+    pub max_bulk_len: usize,
+    pub max_multibulk_len: usize,
+    pub max_nesting: usize,
+    /// None waits for the rest of a command forever.
+    pub read_timeout: Option<Duration>,
+}
+
+impl Default for DecoderLimits {
+    fn default() -> Self {
+        return DecoderLimits {
+            max_bulk_len: PROTO_MAX_BULK_LEN,
+            max_multibulk_len: PROTO_MAX_MULTIBULK_LEN,
+            max_nesting: PROTO_MAX_NESTING,
+            read_timeout: Some(PROTO_READ_TIMEOUT),
+        };
+    }
+}
+
+/// What the decoder does after a protocol error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorRecovery {
+    /// End the stream, like Redis closing the connection.
+    Close,
+    /// Drop the partially parsed command and the rest of the line the error was
+    /// found in, and keep parsing from the next line.
+    SkipLine,
+}
+
+impl ErrorRecovery {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            ErrorRecovery::Close => "close",
+            ErrorRecovery::SkipLine => "skip-line",
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name.to_lowercase().as_str() {
+            "close" => Some(ErrorRecovery::Close),
+            "skip-line" => Some(ErrorRecovery::SkipLine),
+            _ => None,
+        };
+    }
+}
+
+/// Size of the writes done to spool files.
+const SPOOL_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Used to give every spool file of the process a different name.
+static SPOOL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Temporary file holding the body of a big bulk string while it's received.
+struct Spool {
+    file: BufWriter<File>,
+    len: usize,
+}
+
+impl Spool {
+    fn create(len: usize) -> Result<Self> {
+        let id = SPOOL_ID.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("redis-bulk-{}-{}", process::id(), id));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // unlinked right away, the data is only reachable through the open file and
+        // goes away with it even if the connection is dropped mid transfer
+        std::fs::remove_file(&path)?;
+        return Ok(Spool {
+            file: BufWriter::with_capacity(SPOOL_BUFFER_SIZE, File::from_std(file)),
+            len: len,
+        });
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).await?;
+        return Ok(());
+    }
+
+    /// Reads the whole body back into a buffer of its exact size.
+    async fn read_back(mut self) -> Result<Bytes> {
+        self.file.flush().await?;
+        let mut file = self.file.into_inner();
+        file.seek(SeekFrom::Start(0)).await?;
+        let mut data = vec![0; self.len];
+        file.read_exact(&mut data).await?;
+        return Ok(Bytes::from(data));
+    }
+}
+
 /// Decode RESP data from an async stream.
 ///
 /// StreamDecoder works as a State Machine that parses the socket data
 /// byte by byte, in order to parse large quanitities of data without
 /// memory issues.
 ///
-/// (note that a single object big engough could stil overflow memory)
+/// A single big object still has to fit in memory, and while a bulk string is received
+/// its body is kept in a growing buffer and then copied into the parsed value. With
+/// `with_spool_threshold` bodies above the threshold are written to a temporary file
+/// instead, and read back into a single allocation of their exact size once complete,
+/// so partially received values don't take memory and complete ones take it once.
 pub struct StreamDecoder<'a, R> {
     stream: &'a mut R,
     pos: usize,
     input_buffer: Bytes,
+    /// reused between reads once the previous input buffer is consumed
+    read_buffer: BytesMut,
+    read_buffer_size: usize,
 
     state: State,
     parsing_buffer: Vec<u8>,
 
+    /// bulk strings longer than this are spooled to disk, None keeps them in memory
+    spool_threshold: Option<usize>,
+    recovery: ErrorRecovery,
+    /// last byte read by the state machine, to know if an error ended a line
+    last_byte: u8,
+    /// file receiving the body of the bulk string being parsed, if spooled
+    spool: Option<Spool>,
+    /// body of the bulk string being parsed, once read back from its spool
+    spooled: Option<Bytes>,
+
     /// stack of array buffers for nested arrays
     array_buffer: Vec<Vec<DataType>>,
     /// stack of array buffer remainders
     array_remainders: Vec<isize>,
+    /// stack of the aggregate kinds being parsed in each array buffer
+    array_kinds: Vec<Aggregate>,
 
     expecting_rn: bool,
 
-    /// Queue of parsed packets
-    parsed: VecDeque<DataType>,
+    limits: DecoderLimits,
+    /// when the first byte of the command being parsed was read
+    command_started: Option<Instant>,
+
+    /// Packet parsed and not yielded yet. Parsing only moves forward once it's taken,
+    /// so a client pipelining faster than its commands are executed isn't read from
+    /// and fills its TCP window instead of our memory.
+    parsed: Option<DataType>,
 }
 
 impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
@@ -97,48 +320,159 @@ impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
             stream: stream,
             state: State::ExpectingDataTypeIdent,
             input_buffer: Bytes::new(),
+            read_buffer: BytesMut::new(),
+            read_buffer_size: READ_BUFFER_SIZE,
             parsing_buffer: Vec::new(),
+            spool_threshold: None,
+            recovery: ErrorRecovery::Close,
+            last_byte: 0,
+            spool: None,
+            spooled: None,
             array_buffer: Vec::new(),
             array_remainders: Vec::new(),
-            parsed: VecDeque::new(),
+            array_kinds: Vec::new(),
+            parsed: None,
             pos: 0,
             expecting_rn: false,
+            limits: DecoderLimits::default(),
+            command_started: None,
         };
     }
 
+    /// Replaces the default limits of the decoder.
+    pub fn with_limits(mut self, limits: DecoderLimits) -> Self {
+        self.limits = limits;
+        return self;
+    }
+
+    /// Replaces the default size of the reads done by the decoder.
+    pub fn with_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size;
+        return self;
+    }
+
+    /// Spools the bodies of bulk strings longer than `threshold` bytes to a temporary
+    /// file while they are received. None (the default) keeps them in memory.
+    pub fn with_spool_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.spool_threshold = threshold;
+        return self;
+    }
+
+    /// Replaces what the decoder does after a protocol error, closing the stream by default.
+    pub fn with_recovery(mut self, recovery: ErrorRecovery) -> Self {
+        self.recovery = recovery;
+        return self;
+    }
+
+    /// Drops any partially parsed data, leaving the decoder ready for a new command.
+    /// Data already read from the stream and not parsed yet is kept.
+    pub fn reset(&mut self) {
+        self.state = State::ExpectingDataTypeIdent;
+        self.parsing_buffer.clear();
+        self.spool = None;
+        self.spooled = None;
+        self.array_buffer.clear();
+        self.array_remainders.clear();
+        self.array_kinds.clear();
+        self.expecting_rn = false;
+        self.command_started = None;
+        self.parsed = None;
+    }
+
     /// converts the parser into an async iterator of parsed objects
+    ///
+    /// Protocol errors are yielded and, depending on the recovery policy, either end the
+    /// stream or are followed by the commands after them. Stream errors (closed stream,
+    /// read timeouts) always end it.
     pub fn as_stream(&'a mut self) -> impl Stream<Item = Result<DataType>> + 'a {
         stream! {
             loop {
-                if self.parsed.len() > 0 {
-                    yield Ok(self.parsed.pop_front().unwrap());
+                if let Some(packet) = self.parsed.take() {
+                    yield Ok(packet);
+                    continue;
+                }
+                if let Err(err) = self.parse_next().await {
+                    let recover = self.recovery == ErrorRecovery::SkipLine
+                        && err.downcast_ref::<ParseError>().is_none();
+                    yield Err(err);
+                    if !recover {
+                        break;
+                    }
+                    self.reset();
+                    self.skip_line().await?;
                 }
-                self.parse_next().await?;
             }
         }
     }
 
+    /// Discards the rest of the line of the last byte read, unless it already ended it.
+    async fn skip_line(&mut self) -> Result<()> {
+        while self.last_byte != b'\n' {
+            self.last_byte = self.get_byte().await?;
+        }
+        return Ok(());
+    }
+
     // get next byte in buffer. if buffer is empty read from stream.
-    // may return None if stream is closed or on read errors.
-    async fn get_byte(&mut self) -> Option<u8> {
+    // fails if stream is closed, on read errors, or if the command being
+    // parsed isn't completed in time.
+    async fn get_byte(&mut self) -> Result<u8> {
         if self.input_buffer.is_empty() {
-            let mut buf = [0u8; 1024];
-            match self.stream.read(&mut buf).await {
-                Ok(0) | Err(_) => return None,
-                Ok(_) => {}
-            };
-            self.input_buffer = Bytes::from(buf.to_vec());
+            self.fill_input_buffer().await?;
         }
-        return self.input_buffer.get_u8_safe().ok();
+        return self.input_buffer.get_u8_safe();
+    }
+
+    /// Reads the next chunk of data from the stream into the input buffer.
+    async fn fill_input_buffer(&mut self) -> Result<()> {
+        // drop the consumed buffer first, so its memory can be reclaimed
+        self.input_buffer = Bytes::new();
+        self.read_buffer.reserve(self.read_buffer_size);
+        let read = self.stream.read_buf(&mut self.read_buffer);
+        let read = match (self.limits.read_timeout, self.command_started) {
+            (Some(timeout), Some(started)) => match time::timeout_at(started + timeout, read).await
+            {
+                Ok(read) => read,
+                Err(_) => bail!(ParseError::ReadTimeout),
+            },
+            _ => read.await,
+        };
+        match read {
+            Ok(0) | Err(_) => bail!(ParseError::StreamClosed),
+            Ok(_) => {}
+        };
+        self.input_buffer = self.read_buffer.split().freeze();
+        return Ok(());
     }
 
     /// parses the next character in the stream, this function moves the
     /// state machine forward.
     async fn parse_next(&mut self) -> Result<()> {
-        let cur = match self.get_byte().await {
-            Some(b) => b,
-            None => bail!(ParseError::StreamClosed),
-        };
+        if let State::ExpectingBulkStringChar(type_, remaining) = self.state {
+            if remaining > 0 {
+                if self.input_buffer.is_empty() {
+                    self.fill_input_buffer().await?;
+                }
+                // take as much of the body as is buffered at once
+                let len = (remaining as usize).min(self.input_buffer.len());
+                let body = self.input_buffer.get_slice_safe(len)?;
+                match &mut self.spool {
+                    Some(spool) => spool.write(&body).await?,
+                    None => self.parsing_buffer.extend_from_slice(&body),
+                }
+                let remaining = remaining - len as isize;
+                if remaining == 0 {
+                    if let Some(spool) = self.spool.take() {
+                        self.spooled = Some(spool.read_back().await?);
+                    }
+                }
+                self.state = State::ExpectingBulkStringChar(type_, remaining);
+                self.pos += len;
+                return Ok(());
+            }
+        }
+        let cur = self.get_byte().await?;
+        self.last_byte = cur;
         match self.state {
             State::ExpectingDataTypeIdent => match self.handle_datatype_ident(cur) {
                 Ok(_) => {}
@@ -149,12 +483,22 @@ impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
             },
             State::ExpectingSimpleStringChar => self.handle_simple_string_char(cur)?,
             State::ExpectingInteger => self.handle_integer(cur)?,
-            State::ExpectingBulkStringSize => self.handle_bulk_string_size(cur)?,
-            State::ExpectingBulkStringChar(remaining) => {
-                self.handle_bulk_string_char(cur, remaining)?
+            State::ExpectingBulkStringSize(type_) => self.handle_bulk_string_size(cur, type_)?,
+            State::ExpectingBulkStringChar(type_, remaining) => {
+                self.handle_bulk_string_char(cur, type_, remaining)?
             }
             State::ExpectingErrorData => self.handle_error_data(cur)?,
-            State::ExpectingArraySize => self.handle_array_size(cur)?,
+            State::ExpectingDouble => self.handle_simple_read(cur, Type::Double)?,
+            State::ExpectingBoolean => self.handle_simple_read(cur, Type::Boolean)?,
+            State::ExpectingBigNumber => self.handle_simple_read(cur, Type::BigNumber)?,
+            State::ExpectingNull => self.handle_simple_read(cur, Type::Null)?,
+            State::ExpectingInlineChar => self.handle_inline_char(cur)?,
+            State::ExpectingAggregateSize(kind) => self.handle_aggregate_size(cur, kind)?,
+        }
+        if self.state == State::ExpectingDataTypeIdent && self.array_buffer.is_empty() {
+            self.command_started = None;
+        } else if self.command_started.is_none() {
+            self.command_started = Some(Instant::now());
         }
         self.pos += 1;
         Ok(())
@@ -164,10 +508,25 @@ impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
         self.state = match byte {
             b'+' => State::ExpectingSimpleStringChar,
             b':' => State::ExpectingInteger,
-            b'$' => State::ExpectingBulkStringSize,
+            b'$' => State::ExpectingBulkStringSize(Type::BulkString),
+            b'=' => State::ExpectingBulkStringSize(Type::VerbatimString),
             b'-' => State::ExpectingErrorData,
-            b'*' => State::ExpectingArraySize,
-            _ => bail!(ParseError::StreamIdle),
+            b',' => State::ExpectingDouble,
+            b'#' => State::ExpectingBoolean,
+            b'(' => State::ExpectingBigNumber,
+            b'_' => State::ExpectingNull,
+            b'*' => State::ExpectingAggregateSize(Aggregate::Array),
+            b'%' => State::ExpectingAggregateSize(Aggregate::Map),
+            b'~' => State::ExpectingAggregateSize(Aggregate::Set),
+            b'>' => State::ExpectingAggregateSize(Aggregate::Push),
+            // inline commands are only valid at the top level
+            b'\r' | b'\n' if self.array_buffer.is_empty() => bail!(ParseError::StreamIdle),
+            _ if self.array_buffer.is_empty() && inline::is_inline(byte) => {
+                self.parsing_buffer.push(byte);
+                State::ExpectingInlineChar
+            }
+            _ if self.array_buffer.is_empty() => bail!("unexpected byte {:?}", byte as char),
+            _ => bail!("expected '$', got '{}'", byte as char),
         };
         return Ok(());
     }
@@ -189,6 +548,24 @@ impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
         Ok(())
     }
 
+    fn handle_inline_char(&mut self, byte: u8) -> Result<()> {
+        if byte != b'\n' {
+            if self.parsing_buffer.len() >= inline::INLINE_MAX_SIZE {
+                bail!("too big inline request");
+            }
+            self.parsing_buffer.push(byte);
+            return Ok(());
+        }
+        let line = self.parsing_buffer.strip_suffix(b"\r");
+        let line = line.unwrap_or(&self.parsing_buffer);
+        if let Some(command) = inline::parse_line(line)? {
+            self.push_parsed(command);
+        }
+        self.parsing_buffer.clear();
+        self.state = State::ExpectingDataTypeIdent;
+        return Ok(());
+    }
+
     fn handle_simple_string_char(&mut self, byte: u8) -> Result<()> {
         return self.handle_simple_read(byte, Type::SimpleString);
     }
@@ -197,41 +574,57 @@ impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
         return self.handle_simple_read(byte, Type::Integer);
     }
 
-    fn handle_bulk_string_size(&mut self, byte: u8) -> Result<()> {
+    fn handle_bulk_string_size(&mut self, byte: u8, type_: Type) -> Result<()> {
         match byte {
             b'\r' => self.expecting_rn = true,
             b'\n' if self.expecting_rn => {
                 self.expecting_rn = false;
-                let size = self.buffer_as_isize()?;
+                let size = self
+                    .buffer_as_isize()
+                    .ok_or(anyhow!("invalid bulk length"))?;
+                if size >= 0 && size as usize > self.limits.max_bulk_len {
+                    bail!("invalid bulk length");
+                }
                 if size >= 0 {
-                    self.state = State::ExpectingBulkStringChar(size);
+                    self.state = State::ExpectingBulkStringChar(type_, size);
                     self.parsing_buffer.clear();
-                } else if size == -1 {
+                    if self.spool_threshold.is_some_and(|t| size as usize > t) {
+                        self.spool = Some(Spool::create(size as usize)?);
+                    }
+                } else if size == -1 && type_ == Type::BulkString {
                     self.commit_buffer(Type::NullBulkString)?;
                     self.state = State::ExpectingDataTypeIdent;
+                } else {
+                    bail!("invalid bulk length");
                 }
             }
-            _ if self.expecting_rn => bail!("error parsing integer"),
+            _ if self.expecting_rn => bail!("invalid bulk length"),
             _ => self.parsing_buffer.push(byte),
         }
         Ok(())
     }
 
-    fn handle_bulk_string_char(&mut self, byte: u8, remaining: isize) -> Result<()> {
+    fn handle_bulk_string_char(&mut self, byte: u8, type_: Type, remaining: isize) -> Result<()> {
         if remaining < 0 && byte != b'\r' {
-            bail!("error parsing bulk string")
+            bail!("invalid bulk string termination")
         }
         match byte {
             b'\r' if remaining == 0 => self.expecting_rn = true,
             b'\n' if self.expecting_rn => {
                 self.expecting_rn = false;
-                self.commit_buffer(Type::BulkString)?;
+                match (self.spooled.take(), type_) {
+                    (Some(body), Type::BulkString) => {
+                        self.commit_value(DataType::BulkString { string: body })
+                    }
+                    (Some(body), _) => self.commit_value(type_.as_datatype(&body)?),
+                    (None, _) => self.commit_buffer(type_)?,
+                }
                 self.state = State::ExpectingDataTypeIdent;
             }
-            _ if self.expecting_rn => bail!("error parsing bulk string"),
+            _ if self.expecting_rn => bail!("invalid bulk string termination"),
             _ => {
                 self.parsing_buffer.push(byte);
-                self.state = State::ExpectingBulkStringChar(remaining - 1);
+                self.state = State::ExpectingBulkStringChar(type_, remaining - 1);
             }
         }
         Ok(())
@@ -241,17 +634,38 @@ impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
         return self.handle_simple_read(byte, Type::Error);
     }
 
-    fn handle_array_size(&mut self, byte: u8) -> Result<()> {
+    fn handle_aggregate_size(&mut self, byte: u8, kind: Aggregate) -> Result<()> {
         match byte {
             b'\r' => self.expecting_rn = true,
             b'\n' if self.expecting_rn => {
                 self.expecting_rn = false;
-                let size = self.buffer_as_isize()?;
-                self.array_buffer.push(Vec::with_capacity(size as usize));
-                self.array_remainders.push(size);
+                let size = self
+                    .buffer_as_isize()
+                    .ok_or(anyhow!("invalid multibulk length"))?;
+                if size == -1 && kind == Aggregate::Array {
+                    self.commit_buffer(Type::NullArray)?;
+                    self.state = State::ExpectingDataTypeIdent;
+                    return Ok(());
+                }
+                if size < 0 || size as usize > self.limits.max_multibulk_len {
+                    bail!("invalid multibulk length");
+                }
+                if self.array_buffer.len() >= self.limits.max_nesting {
+                    bail!("too many nested aggregates");
+                }
+                let elements = kind.elements(size);
+                // don't trust the announced size for preallocating
+                let capacity = (elements as usize).min(1024);
+                self.array_buffer.push(Vec::with_capacity(capacity));
+                self.array_remainders.push(elements);
+                self.array_kinds.push(kind);
                 self.state = State::ExpectingDataTypeIdent;
+                if elements == 0 {
+                    // empty aggregates are complete right away
+                    while let Some(()) = self.commit_array_buffer() {}
+                }
             }
-            _ if self.expecting_rn => bail!("got '\\r' in the middle of array size"),
+            _ if self.expecting_rn => bail!("invalid multibulk length"),
             _ => self.parsing_buffer.push(byte),
         }
         Ok(())
@@ -262,7 +676,14 @@ impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
     /// and empties the buffer.
     fn commit_buffer(&mut self, type_: Type) -> Result<()> {
         let data = type_.as_datatype(&self.parsing_buffer)?;
-        if self.array_buffer.len() > 0 {
+        self.commit_value(data);
+        self.parsing_buffer.clear();
+        return Ok(());
+    }
+
+    /// Adds a parsed value to the aggregate being parsed, or to the parsed objects.
+    fn commit_value(&mut self, data: DataType) {
+        if !self.array_buffer.is_empty() {
             // parsing array, item is pushed to last array in stack
             let mut storage = self.array_buffer.pop().unwrap();
             storage.push(data);
@@ -277,10 +698,14 @@ impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
             }
         } else {
             // outside array, add to parsed
-            self.parsed.push_back(data);
+            self.push_parsed(data);
         }
-        self.parsing_buffer.clear();
-        return Ok(());
+    }
+
+    fn push_parsed(&mut self, packet: DataType) {
+        // a single byte completes at most one packet
+        debug_assert!(self.parsed.is_none());
+        self.parsed = Some(packet);
     }
 
     /// Commits the last array buffer from the stack to either the array above it or
@@ -297,8 +722,9 @@ impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
         assert_eq!(remainder, 0);
 
         let items = self.array_buffer.pop().unwrap();
-        let array = DataType::Array { items: items };
-        if self.array_buffer.len() > 0 {
+        let kind = self.array_kinds.pop().unwrap();
+        let array = kind.as_datatype(items);
+        if !self.array_buffer.is_empty() {
             // nested array done, add to previous array in stack and decrease its remainders by 1.
             let mut parent = self.array_buffer.pop().unwrap();
             parent.push(array);
@@ -308,28 +734,37 @@ impl<'a, R: AsyncReadExt + Unpin> StreamDecoder<'a, R> {
             return Some(()); // Next array could be done so check it by calling again
         } else {
             // last array done, add to parsed
-            self.parsed.push_back(array);
+            self.push_parsed(array);
             return None; // No more arrays in buffer, stop calling../
         }
     }
 
-    fn buffer_as_isize(&mut self) -> Result<isize> {
-        let num = String::from_utf8(self.parsing_buffer.clone())?.parse::<isize>()?;
+    fn buffer_as_isize(&mut self) -> Option<isize> {
+        let num = parse_number(&self.parsing_buffer);
         self.parsing_buffer.clear();
-        return Ok(num);
+        return num;
     }
 }
 
+pub fn parse_number<T: std::str::FromStr>(buf: &[u8]) -> Option<T> {
+    return std::str::from_utf8(buf).ok()?.parse().ok();
+}
+
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
     use anyhow::Result;
-    use tokio::io::BufReader;
+    use bytes::Bytes;
+    use tokio::io::{AsyncWriteExt, BufReader};
 
     use tokio_stream::StreamExt;
 
+    use proptest::prelude::*;
+
     use crate::{
-        decoders::v2::{ParseError, StreamDecoder},
-        protocol::DataType,
+        decoders::v2::{DecoderLimits, ErrorRecovery, ParseError, StreamDecoder},
+        protocol::{strategy, DataType},
     };
 
     macro_rules! test_decode {
@@ -377,7 +812,7 @@ mod test {
                                 error: String::from("World"),
                             },
                             DataType::BulkString {
-                                string: String::from("Hello\nWorld"),
+                                string: Bytes::from("Hello\nWorld"),
                             },
                         ],
                     },
@@ -411,18 +846,104 @@ mod test {
             test_decode!(
                 orig,
                 DataType::BulkString {
-                    string: String::from(*test)
+                    string: Bytes::from(String::from(*test))
                 }
             );
         }
     }
 
+    #[tokio::test]
+    async fn test_decode_binary_bulk_string() {
+        let orig: &[u8] = b"$4\r\n\x00\xff\r\n\r\n";
+        let mut reader = BufReader::new(orig);
+        let mut decoder = StreamDecoder::new(&mut reader);
+        let mut stream = Box::pin(decoder.as_stream());
+        let item = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            item,
+            DataType::BulkString {
+                string: Bytes::from_static(b"\x00\xff\r\n")
+            }
+        );
+        assert_eq!(item.encode().unwrap(), orig);
+    }
+
     #[tokio::test]
     async fn test_decode_null_bulk_string() {
         let orig = "$-1\r\n";
         test_decode!(orig, DataType::NullBulkString);
     }
 
+    #[tokio::test]
+    async fn test_decode_verbatim_string() {
+        let orig = "=20\r\nmkd:# Hello\r\n\r\nWorld\r\n";
+        test_decode!(
+            orig,
+            DataType::VerbatimString {
+                format: String::from("mkd"),
+                string: String::from("# Hello\r\n\r\nWorld"),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_empty_array() {
+        let orig = "*0\r\n";
+        test_decode!(orig, DataType::Array { items: vec![] });
+        let orig = "%0\r\n";
+        test_decode!(orig, DataType::Map { items: vec![] });
+    }
+
+    #[tokio::test]
+    async fn test_decode_nested_empty_arrays() {
+        let orig = "*3\r\n*0\r\n*1\r\n*0\r\n~0\r\n";
+        test_decode!(
+            orig,
+            DataType::Array {
+                items: vec![
+                    DataType::Array { items: vec![] },
+                    DataType::Array {
+                        items: vec![DataType::Array { items: vec![] }]
+                    },
+                    DataType::Set { items: vec![] },
+                ]
+            }
+        );
+
+        // the decoder keeps going after an empty array
+        let mut reader = BufReader::new("*0\r\n:1\r\n".as_bytes());
+        let mut decoder = StreamDecoder::new(&mut reader);
+        let stream = decoder.as_stream();
+        let items: Vec<Result<DataType>> = stream.collect().await;
+        let values: Vec<&DataType> = items.iter().filter_map(|r| r.as_ref().ok()).collect();
+        assert_eq!(
+            values,
+            vec![
+                &DataType::Array { items: vec![] },
+                &DataType::Integer { number: 1 }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_null_array() {
+        let orig = "*-1\r\n";
+        test_decode!(orig, DataType::NullArray);
+        let orig = "*2\r\n*-1\r\n:1\r\n";
+        test_decode!(
+            orig,
+            DataType::Array {
+                items: vec![DataType::NullArray, DataType::Integer { number: 1 }]
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_null() {
+        let orig = "_\r\n";
+        test_decode!(orig, DataType::Null);
+    }
+
     #[tokio::test]
     async fn test_decode_error() {
         let expected = String::from("some error");
@@ -444,15 +965,102 @@ mod test {
         test_decode!(orig, that_array!());
     }
 
+    #[tokio::test]
+    async fn test_decode_small_read_buffer() {
+        let orig = String::from(
+            "*2\r\n*3\r\n:1\r\n:2\r\n:3\r\n*3\r\n+Hello\r\n-World\r\n$11\r\nHello\nWorld\r\n",
+        );
+        let mut reader = orig.as_bytes();
+        let mut decoder = StreamDecoder::new(&mut reader).with_buffer_size(4);
+        let mut stream = Box::pin(decoder.as_stream());
+        assert_eq!(stream.next().await.unwrap().unwrap(), that_array!());
+    }
+
+    #[tokio::test]
+    async fn test_decode_spooled_bulk_strings() {
+        let big: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let mut orig = format!("*3\r\n$3\r\nSET\r\n$2\r\nk1\r\n${}\r\n", big.len()).into_bytes();
+        orig.extend_from_slice(&big);
+        orig.extend_from_slice(b"\r\n=15\r\ntxt:Some string\r\n$3\r\nabc\r\n");
+        let mut reader = orig.as_slice();
+        let mut decoder = StreamDecoder::new(&mut reader)
+            .with_buffer_size(1000)
+            .with_spool_threshold(Some(10));
+        let mut stream = Box::pin(decoder.as_stream());
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            DataType::Array {
+                items: vec![
+                    DataType::bulk("SET"),
+                    DataType::bulk("k1"),
+                    DataType::bulk(big),
+                ]
+            }
+        );
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            DataType::VerbatimString {
+                format: String::from("txt"),
+                string: String::from("Some string"),
+            }
+        );
+        assert_eq!(stream.next().await.unwrap().unwrap(), DataType::bulk("abc"));
+    }
+
+    #[tokio::test]
+    async fn test_error_recovery() {
+        // a bad array header, a bad element inside an array and a bad bulk length
+        let orig = "*x\r\n*1\r\n$4\r\nPING\r\n*2\r\n:1\r\n?oops\r\nECHO hi\r\n$1x\r\n+OK\r\n";
+        let ping = DataType::Array {
+            items: vec![DataType::bulk("PING")],
+        };
+
+        let mut reader = orig.as_bytes();
+        let mut decoder = StreamDecoder::new(&mut reader).with_recovery(ErrorRecovery::SkipLine);
+        let results: Vec<Result<DataType>> = decoder.as_stream().collect().await;
+        let results: Vec<String> = results
+            .into_iter()
+            .map(|r| match r {
+                Ok(value) => format!("{value:?}"),
+                Err(err) => err.to_string(),
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                String::from("invalid multibulk length"),
+                format!("{ping:?}"),
+                String::from("expected '$', got '?'"),
+                format!(
+                    "{:?}",
+                    DataType::Array {
+                        items: vec![DataType::bulk("ECHO"), DataType::bulk("hi")]
+                    }
+                ),
+                String::from("invalid bulk length"),
+                format!("{:?}", DataType::ok()),
+                String::from("closed stream"),
+            ]
+        );
+
+        // closing is the default
+        let mut reader = orig.as_bytes();
+        let mut decoder = StreamDecoder::new(&mut reader);
+        let results: Vec<Result<DataType>> = decoder.as_stream().collect().await;
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+
     #[tokio::test]
     async fn test_all() {
         let expected_err = String::from("some error");
         let expected_simple_string = String::from("hellohello");
         let expected_bulk_string = String::from("hello\nhello");
         let expected_int = -4231232;
-        let orig = String::from(format!(
-            "{}{}{}{}{}{}",
-            "*2\r\n*3\r\n:1\r\n:2\r\n:3\r\n*3\r\n+Hello\r\n-World\r\n$11\r\nHello\nWorld\r\n",
+        let orig = [
+            String::from(
+                "*2\r\n*3\r\n:1\r\n:2\r\n:3\r\n*3\r\n+Hello\r\n-World\r\n$11\r\nHello\nWorld\r\n",
+            ),
             format!("-{expected_err}\r\n"),
             format!(
                 "${}\r\n{}\r\n",
@@ -461,17 +1069,14 @@ mod test {
             ),
             format!(":{expected_int}\r\n"),
             format!("+{expected_simple_string}\r\n"),
-            "$-1\r\n",
-        ));
+            String::from("$-1\r\n"),
+        ]
+        .concat();
         let mut reader = BufReader::new(orig.as_bytes());
         let mut decoder = StreamDecoder::new(&mut reader);
         let stream = decoder.as_stream();
         let item: Vec<Result<DataType>> = stream.collect().await;
-        let values: Vec<&DataType> = item
-            .iter()
-            .filter(|r| r.is_ok())
-            .map(|r| r.as_ref().unwrap())
-            .collect();
+        let values: Vec<&DataType> = item.iter().filter_map(|r| r.as_ref().ok()).collect();
         assert_eq!(
             values,
             vec![
@@ -481,7 +1086,7 @@ mod test {
                     error: expected_err
                 },
                 &DataType::BulkString {
-                    string: expected_bulk_string
+                    string: Bytes::from(expected_bulk_string)
                 },
                 &DataType::Integer {
                     number: expected_int
@@ -493,4 +1098,269 @@ mod test {
             ]
         );
     }
+
+    #[tokio::test]
+    async fn test_decode_map_and_set() {
+        let orig = String::from("%2\r\n+first\r\n:1\r\n+second\r\n~2\r\n:1\r\n*1\r\n:2\r\n");
+        test_decode!(
+            orig,
+            DataType::Map {
+                items: vec![
+                    (
+                        DataType::SimpleString {
+                            string: String::from("first")
+                        },
+                        DataType::Integer { number: 1 }
+                    ),
+                    (
+                        DataType::SimpleString {
+                            string: String::from("second")
+                        },
+                        DataType::Set {
+                            items: vec![
+                                DataType::Integer { number: 1 },
+                                DataType::Array {
+                                    items: vec![DataType::Integer { number: 2 }]
+                                },
+                            ]
+                        }
+                    ),
+                ]
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_push() {
+        let orig = String::from(">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n");
+        test_decode!(
+            orig,
+            DataType::Push {
+                items: vec![
+                    DataType::BulkString {
+                        string: Bytes::from("invalidate")
+                    },
+                    DataType::Array {
+                        items: vec![DataType::BulkString {
+                            string: Bytes::from("key")
+                        }]
+                    },
+                ]
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_inline() {
+        let orig = String::from("SET key \"some value\"\r\n");
+        test_decode!(
+            orig,
+            DataType::Array {
+                items: vec![
+                    DataType::BulkString {
+                        string: Bytes::from("SET")
+                    },
+                    DataType::BulkString {
+                        string: Bytes::from("key")
+                    },
+                    DataType::BulkString {
+                        string: Bytes::from("some value")
+                    },
+                ]
+            }
+        );
+
+        // empty lines are skipped, LF alone terminates the command
+        let orig = String::from("\r\n\nPING\n");
+        test_decode!(
+            orig,
+            DataType::Array {
+                items: vec![DataType::BulkString {
+                    string: Bytes::from("PING")
+                }]
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_double() {
+        let tests = &[("1.23", 1.23), ("10", 10.0), ("-1.5e3", -1500.0)];
+        for (orig, expected) in tests {
+            let orig = format!(",{orig}\r\n");
+            test_decode!(orig, DataType::Double { number: *expected });
+        }
+        let orig = String::from(",inf\r\n");
+        test_decode!(
+            orig,
+            DataType::Double {
+                number: f64::INFINITY
+            }
+        );
+        let orig = String::from(",-inf\r\n");
+        test_decode!(
+            orig,
+            DataType::Double {
+                number: f64::NEG_INFINITY
+            }
+        );
+
+        let mut reader = BufReader::new(",nan\r\n".as_bytes());
+        let mut decoder = StreamDecoder::new(&mut reader);
+        let mut stream = Box::pin(decoder.as_stream());
+        match stream.next().await {
+            Some(Ok(DataType::Double { number })) => assert!(number.is_nan()),
+            other => panic!("expected nan, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_encode_double() {
+        let tests = &[
+            (1.23, ",1.23\r\n"),
+            (10.0, ",10\r\n"),
+            (f64::INFINITY, ",inf\r\n"),
+            (f64::NEG_INFINITY, ",-inf\r\n"),
+            (f64::NAN, ",nan\r\n"),
+        ];
+        for (number, expected) in tests {
+            let encoded = DataType::Double { number: *number }.encode().unwrap();
+            assert_eq!(String::from_utf8(encoded).unwrap(), *expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decode_boolean_and_big_number() {
+        let orig = String::from("#t\r\n");
+        test_decode!(orig, DataType::Boolean { value: true });
+        let orig = String::from("#f\r\n");
+        test_decode!(orig, DataType::Boolean { value: false });
+        let orig = String::from("(-3492890328409238509324850943850943825024385\r\n");
+        test_decode!(
+            orig,
+            DataType::BigNumber {
+                number: String::from("-3492890328409238509324850943850943825024385")
+            }
+        );
+
+        let mut reader = BufReader::new("#x\r\n".as_bytes());
+        let mut decoder = StreamDecoder::new(&mut reader);
+        let mut stream = Box::pin(decoder.as_stream());
+        assert!(stream.next().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() {
+        let tests = [
+            ("$abc\r\nfoo\r\n", "invalid bulk length"),
+            ("$-5\r\n", "invalid bulk length"),
+            ("*2\r\n$3\r\nfoo\r\nx", "expected '$', got 'x'"),
+            ("*x\r\n", "invalid multibulk length"),
+            (":12a\r\n", "invalid integer"),
+            ("$3\r\nfoobar\r\n", "invalid bulk string termination"),
+            ("\0PING\r\n", "unexpected byte '\\0'"),
+        ];
+        for (orig, expected) in tests {
+            let mut reader = BufReader::new(orig.as_bytes());
+            let mut decoder = StreamDecoder::new(&mut reader);
+            let mut stream = Box::pin(decoder.as_stream());
+            let err = stream.next().await.unwrap().unwrap_err();
+            assert_eq!(err.to_string(), expected, "decoding {orig:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decoder_limits() {
+        let limits = DecoderLimits {
+            max_bulk_len: 4,
+            max_multibulk_len: 2,
+            max_nesting: 2,
+            ..Default::default()
+        };
+        let tests = [
+            ("$4\r\nabcd\r\n", None),
+            ("$99999999999\r\n", Some("invalid bulk length")),
+            ("*2\r\n:1\r\n:2\r\n", None),
+            ("*3\r\n", Some("invalid multibulk length")),
+            ("*1\r\n*1\r\n:1\r\n", None),
+            (
+                "*1\r\n*1\r\n*1\r\n:1\r\n",
+                Some("too many nested aggregates"),
+            ),
+        ];
+        for (orig, expected) in tests {
+            let mut reader = BufReader::new(orig.as_bytes());
+            let mut decoder = StreamDecoder::new(&mut reader).with_limits(limits);
+            let mut stream = Box::pin(decoder.as_stream());
+            let parsed = stream.next().await.unwrap();
+            match expected {
+                Some(err) => assert_eq!(parsed.unwrap_err().to_string(), err),
+                None => assert!(parsed.is_ok(), "decoding {orig:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let limits = DecoderLimits {
+            read_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let mut decoder = StreamDecoder::new(&mut server).with_limits(limits);
+        let mut stream = Box::pin(decoder.as_stream());
+
+        // idle clients aren't timed out
+        let idle = tokio::time::timeout(Duration::from_millis(100), stream.next()).await;
+        assert!(idle.is_err());
+
+        client
+            .write_all(b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPI")
+            .await
+            .unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(ParseError::ReadTimeout)));
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            for _ in 0..1000 {
+                client.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+            }
+        });
+        let mut decoder = StreamDecoder::new(&mut server).with_buffer_size(16);
+        let mut stream = Box::pin(decoder.as_stream());
+        assert!(stream.next().await.unwrap().is_ok());
+        // the client is blocked until the pipelined commands are taken
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+        for _ in 1..1000 {
+            assert!(stream.next().await.unwrap().is_ok());
+        }
+        writer.await.unwrap();
+    }
+
+    /// Encodes `value` and decodes it back, reading `buffer_size` bytes at a time.
+    fn round_trip(value: &DataType, buffer_size: usize) -> DataType {
+        let encoded = value.encode().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        return runtime.block_on(async {
+            let mut reader = encoded.as_slice();
+            let mut decoder = StreamDecoder::new(&mut reader).with_buffer_size(buffer_size);
+            let mut stream = Box::pin(decoder.as_stream());
+            return stream.next().await.unwrap().unwrap();
+        });
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(value in strategy::datatype(), buffer_size in 1..64usize) {
+            prop_assert_eq!(round_trip(&value, buffer_size), value);
+        }
+    }
 }
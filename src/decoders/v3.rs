@@ -0,0 +1,573 @@
+/// Third iteration of decoder. Instead of moving a state machine one byte at a time,
+/// this one reads the socket in chunks and parses whole values out of the buffered data.
+///
+/// Parsing is done in two passes over the buffer: a cheap check that finds how many bytes
+/// hold complete commands (scanning for CRLF with memchr and skipping bulk bodies by
+/// their length), and a parse of just those bytes, which are split from the buffer so
+/// bulk strings can be handed out as slices of it instead of copies.
+use std::marker::Unpin;
+
+use anyhow::{anyhow, bail, Result};
+use async_stream::stream;
+use bytes::{Bytes, BytesMut};
+use memchr::memchr;
+use tokio::io::AsyncReadExt;
+use tokio::time::{self, Instant};
+use tokio_stream::Stream;
+
+use crate::decoders::v2::{parse_number, Aggregate, DecoderLimits, ParseError, Type};
+use crate::decoders::{inline, READ_BUFFER_SIZE};
+use crate::protocol::DataType;
+
+/// Decode RESP data from an async stream, a buffered chunk at a time.
+pub struct ChunkDecoder<'a, R> {
+    stream: &'a mut R,
+    /// data read from the stream and not parsed yet
+    buffer: BytesMut,
+    /// size of the chunks read from the stream
+    buffer_size: usize,
+    limits: DecoderLimits,
+    /// when the first byte of the incomplete command in the buffer was read
+    command_started: Option<Instant>,
+}
+
+impl<'a, R: AsyncReadExt + Unpin> ChunkDecoder<'a, R> {
+    pub fn new(stream: &'a mut R) -> Self {
+        return ChunkDecoder {
+            stream: stream,
+            buffer: BytesMut::new(),
+            buffer_size: READ_BUFFER_SIZE,
+            limits: DecoderLimits::default(),
+            command_started: None,
+        };
+    }
+
+    /// Replaces the default limits of the decoder.
+    pub fn with_limits(mut self, limits: DecoderLimits) -> Self {
+        self.limits = limits;
+        return self;
+    }
+
+    /// Replaces the default size of the chunks read from the stream.
+    pub fn with_buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        return self;
+    }
+
+    /// converts the parser into an async iterator of parsed objects
+    ///
+    /// Commands are parsed one at a time as they are asked for, and the stream is only
+    /// read once the buffered ones run out, so a client pipelining faster than its
+    /// commands are executed fills its TCP window instead of our memory.
+    pub fn as_stream(&'a mut self) -> impl Stream<Item = Result<DataType>> + 'a {
+        stream! {
+            loop {
+                if let Some(packet) = decode_command(&mut self.buffer, &self.limits)? {
+                    // anything left starts the next command, timed from the next read
+                    self.command_started = None;
+                    yield Ok(packet);
+                    continue;
+                }
+                if self.buffer.is_empty() {
+                    self.command_started = None;
+                } else if self.command_started.is_none() {
+                    self.command_started = Some(Instant::now());
+                }
+                self.read_chunk().await?;
+            }
+        }
+    }
+
+    /// Reads the next chunk of data from the stream into the buffer.
+    /// Fails if the command in the buffer isn't completed in time.
+    async fn read_chunk(&mut self) -> Result<()> {
+        self.buffer.reserve(self.buffer_size);
+        let read = self.stream.read_buf(&mut self.buffer);
+        let read = match (self.limits.read_timeout, self.command_started) {
+            (Some(timeout), Some(started)) => match time::timeout_at(started + timeout, read).await
+            {
+                Ok(read) => read,
+                Err(_) => bail!(ParseError::ReadTimeout),
+            },
+            _ => read.await,
+        };
+        match read {
+            Ok(0) | Err(_) => bail!(ParseError::StreamClosed),
+            Ok(_) => return Ok(()),
+        }
+    }
+}
+
+/// Takes the first complete command out of `buffer` and parses it, leaving the rest
+/// of the data in place. Returns None if the buffer doesn't hold a complete command yet.
+pub fn decode_command(buffer: &mut BytesMut, limits: &DecoderLimits) -> Result<Option<DataType>> {
+    loop {
+        let mut cursor = Cursor::new(buffer);
+        if !check_command(&mut cursor, limits)? {
+            return Ok(None);
+        }
+        let len = cursor.pos;
+        let chunk = buffer.split_to(len).freeze();
+        // blank data between commands is skipped
+        if let Some(command) = parse_command(&mut Cursor::new(&chunk), &chunk)? {
+            return Ok(Some(command));
+        }
+    }
+}
+
+/// Position over a buffer being parsed.
+struct Cursor<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Cursor<'b> {
+    fn new(buf: &'b [u8]) -> Self {
+        return Cursor { buf: buf, pos: 0 };
+    }
+
+    fn remaining(&self) -> usize {
+        return self.buf.len() - self.pos;
+    }
+
+    fn peek(&self) -> Option<u8> {
+        return self.buf.get(self.pos).copied();
+    }
+
+    /// Returns the data up to the next CRLF and moves past it, or None if the
+    /// line isn't complete yet.
+    fn line(&mut self) -> Option<&'b [u8]> {
+        let buf: &'b [u8] = self.buf;
+        let mut end = self.pos;
+        loop {
+            end += memchr(b'\n', &buf[end..])?;
+            if end > self.pos && buf[end - 1] == b'\r' {
+                let line = &buf[self.pos..end - 1];
+                self.pos = end + 1;
+                return Some(line);
+            }
+            end += 1;
+        }
+    }
+
+    /// Returns the data up to the next LF (stripping a trailing CR) and moves past it,
+    /// or None if the line isn't complete yet.
+    fn inline_line(&mut self) -> Option<&'b [u8]> {
+        let buf: &'b [u8] = self.buf;
+        let end = self.pos + memchr(b'\n', &buf[self.pos..])?;
+        let line = &buf[self.pos..end];
+        self.pos = end + 1;
+        return Some(line.strip_suffix(b"\r").unwrap_or(line));
+    }
+}
+
+/// Types of simple (single line) values by their identifier.
+fn simple_type(ident: u8) -> Option<Type> {
+    return match ident {
+        b'+' => Some(Type::SimpleString),
+        b'-' => Some(Type::Error),
+        b':' => Some(Type::Integer),
+        b',' => Some(Type::Double),
+        b'#' => Some(Type::Boolean),
+        b'(' => Some(Type::BigNumber),
+        b'_' => Some(Type::Null),
+        _ => None,
+    };
+}
+
+fn aggregate_kind(ident: u8) -> Option<Aggregate> {
+    return match ident {
+        b'*' => Some(Aggregate::Array),
+        b'%' => Some(Aggregate::Map),
+        b'~' => Some(Aggregate::Set),
+        b'>' => Some(Aggregate::Push),
+        _ => None,
+    };
+}
+
+/// Parses the length of a bulk or verbatim string, None for null bulk strings.
+fn bulk_len(ident: u8, line: &[u8], limits: &DecoderLimits) -> Result<Option<usize>> {
+    let size: isize = parse_number(line).ok_or(anyhow!("invalid bulk length"))?;
+    if size == -1 && ident == b'$' {
+        return Ok(None);
+    }
+    if size < 0 || size as usize > limits.max_bulk_len {
+        bail!("invalid bulk length");
+    }
+    return Ok(Some(size as usize));
+}
+
+/// Parses the number of elements of an aggregate, None for null arrays.
+fn aggregate_len(kind: Aggregate, line: &[u8], limits: &DecoderLimits) -> Result<Option<usize>> {
+    let size: isize = parse_number(line).ok_or(anyhow!("invalid multibulk length"))?;
+    if size == -1 && kind == Aggregate::Array {
+        return Ok(None);
+    }
+    if size < 0 || size as usize > limits.max_multibulk_len {
+        bail!("invalid multibulk length");
+    }
+    return Ok(Some(kind.elements(size) as usize));
+}
+
+/// Checks if the buffer holds a complete command at the cursor (or blank data between
+/// commands), moving the cursor past it. Returns false if more data is needed.
+fn check_command(cursor: &mut Cursor, limits: &DecoderLimits) -> Result<bool> {
+    let ident = match cursor.peek() {
+        Some(ident) => ident,
+        None => return Ok(false),
+    };
+    if matches!(ident, b'\r' | b'\n') {
+        cursor.pos += 1;
+        return Ok(true);
+    }
+    if ident == b'\0' {
+        bail!("unexpected byte {:?}", ident as char);
+    }
+    if !inline::is_inline(ident) {
+        return check_value(cursor, limits, 0);
+    }
+    match cursor.inline_line() {
+        Some(line) if line.len() > inline::INLINE_MAX_SIZE => bail!("too big inline request"),
+        Some(_) => return Ok(true),
+        None if cursor.remaining() > inline::INLINE_MAX_SIZE => bail!("too big inline request"),
+        None => return Ok(false),
+    }
+}
+
+/// Checks if the buffer holds a complete value at the cursor, `depth` being the
+/// number of aggregates it's nested in.
+fn check_value(cursor: &mut Cursor, limits: &DecoderLimits, depth: usize) -> Result<bool> {
+    let ident = match cursor.peek() {
+        Some(ident) => ident,
+        None => return Ok(false),
+    };
+    let kind = aggregate_kind(ident);
+    if kind.is_none() && simple_type(ident).is_none() && !matches!(ident, b'$' | b'=') {
+        bail!("expected '$', got '{}'", ident as char);
+    }
+    cursor.pos += 1;
+    let line = match cursor.line() {
+        Some(line) => line,
+        None => return Ok(false),
+    };
+    if let Some(kind) = kind {
+        let elements = match aggregate_len(kind, line, limits)? {
+            Some(elements) => elements,
+            None => return Ok(true),
+        };
+        if depth >= limits.max_nesting {
+            bail!("too many nested aggregates");
+        }
+        for _ in 0..elements {
+            if !check_value(cursor, limits, depth + 1)? {
+                return Ok(false);
+            }
+        }
+    } else if matches!(ident, b'$' | b'=') {
+        let len = match bulk_len(ident, line, limits)? {
+            Some(len) => len,
+            None => return Ok(true),
+        };
+        if cursor.remaining() < len + 2 {
+            return Ok(false);
+        }
+        if &cursor.buf[cursor.pos + len..cursor.pos + len + 2] != b"\r\n" {
+            bail!("invalid bulk string termination");
+        }
+        cursor.pos += len + 2;
+    }
+    return Ok(true);
+}
+
+/// Parses the command at the cursor out of `chunk`, which must have passed
+/// `check_command`. Returns None for blank data between commands.
+fn parse_command(cursor: &mut Cursor, chunk: &Bytes) -> Result<Option<DataType>> {
+    let ident = cursor.peek().ok_or(anyhow!("incomplete command"))?;
+    if matches!(ident, b'\r' | b'\n') {
+        cursor.pos += 1;
+        return Ok(None);
+    }
+    if !inline::is_inline(ident) {
+        return Ok(Some(parse_value(cursor, chunk)?));
+    }
+    let line = cursor.inline_line().ok_or(anyhow!("incomplete command"))?;
+    return inline::parse_line(line);
+}
+
+/// Parses the value at the cursor out of `chunk`, lengths and limits were already
+/// validated by `check_value`.
+fn parse_value(cursor: &mut Cursor, chunk: &Bytes) -> Result<DataType> {
+    let ident = cursor.peek().ok_or(anyhow!("incomplete value"))?;
+    cursor.pos += 1;
+    let line = cursor.line().ok_or(anyhow!("incomplete value"))?;
+    if let Some(type_) = simple_type(ident) {
+        return type_.as_datatype(line);
+    }
+    if let Some(kind) = aggregate_kind(ident) {
+        let size: isize = parse_number(line).ok_or(anyhow!("invalid multibulk length"))?;
+        if size == -1 {
+            return Ok(DataType::NullArray);
+        }
+        let elements = kind.elements(size) as usize;
+        let mut items = Vec::with_capacity(elements.min(1024));
+        for _ in 0..elements {
+            items.push(parse_value(cursor, chunk)?);
+        }
+        return Ok(kind.as_datatype(items));
+    }
+    let size: isize = parse_number(line).ok_or(anyhow!("invalid bulk length"))?;
+    if size == -1 {
+        return Ok(DataType::NullBulkString);
+    }
+    let len = size as usize;
+    let body = chunk.slice(cursor.pos..cursor.pos + len);
+    cursor.pos += len + 2;
+    if ident == b'=' {
+        return Type::VerbatimString.as_datatype(&body);
+    }
+    return Ok(DataType::BulkString { string: body });
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use bytes::Bytes;
+    use tokio::io::AsyncWriteExt;
+    use tokio_stream::StreamExt;
+
+    use proptest::prelude::*;
+
+    use crate::{
+        decoders::v2::{DecoderLimits, ParseError},
+        decoders::v3::ChunkDecoder,
+        protocol::{strategy, DataType},
+    };
+
+    const ALL_TYPES: &str = concat!(
+        "*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$11\r\nHello\nWorld\r\n",
+        "+OK\r\n-ERR some error\r\n:-42\r\n$-1\r\n*-1\r\n*0\r\n",
+        ",1.5\r\n#t\r\n(12345678901234567890\r\n_\r\n=7\r\ntxt:abc\r\n",
+        "%1\r\n+field\r\n:1\r\n~1\r\n:2\r\n>1\r\n:3\r\n",
+        "\r\nPING\n",
+    );
+
+    fn all_types() -> Vec<DataType> {
+        return vec![
+            DataType::Array {
+                items: vec![
+                    DataType::BulkString {
+                        string: Bytes::from("SET"),
+                    },
+                    DataType::BulkString {
+                        string: Bytes::from("key"),
+                    },
+                    DataType::BulkString {
+                        string: Bytes::from("Hello\nWorld"),
+                    },
+                ],
+            },
+            DataType::SimpleString {
+                string: String::from("OK"),
+            },
+            DataType::Error {
+                type_: String::from("ERR"),
+                error: String::from("some error"),
+            },
+            DataType::Integer { number: -42 },
+            DataType::NullBulkString,
+            DataType::NullArray,
+            DataType::Array { items: vec![] },
+            DataType::Double { number: 1.5 },
+            DataType::Boolean { value: true },
+            DataType::BigNumber {
+                number: String::from("12345678901234567890"),
+            },
+            DataType::Null,
+            DataType::VerbatimString {
+                format: String::from("txt"),
+                string: String::from("abc"),
+            },
+            DataType::Map {
+                items: vec![(
+                    DataType::SimpleString {
+                        string: String::from("field"),
+                    },
+                    DataType::Integer { number: 1 },
+                )],
+            },
+            DataType::Set {
+                items: vec![DataType::Integer { number: 2 }],
+            },
+            DataType::Push {
+                items: vec![DataType::Integer { number: 3 }],
+            },
+            DataType::Array {
+                items: vec![DataType::BulkString {
+                    string: Bytes::from("PING"),
+                }],
+            },
+        ];
+    }
+
+    /// Decodes everything in `data`, checking that the stream ends once it's consumed.
+    async fn decode_all(data: &[u8]) -> Vec<DataType> {
+        let mut reader = data;
+        let mut decoder = ChunkDecoder::new(&mut reader);
+        let mut results: Vec<Result<DataType>> = decoder.as_stream().collect().await;
+        let end = results.pop().unwrap().unwrap_err();
+        assert!(matches!(end.downcast_ref(), Some(ParseError::StreamClosed)));
+        return results.into_iter().map(|v| v.unwrap()).collect();
+    }
+
+    #[tokio::test]
+    async fn test_decode_all_types() {
+        assert_eq!(decode_all(ALL_TYPES.as_bytes()).await, all_types());
+    }
+
+    #[tokio::test]
+    async fn test_decode_split_reads() {
+        // a tiny pipe makes every value arrive split across several reads
+        let (mut client, mut server) = tokio::io::duplex(3);
+        tokio::spawn(async move {
+            client.write_all(ALL_TYPES.as_bytes()).await.unwrap();
+        });
+        let mut decoder = ChunkDecoder::new(&mut server);
+        let values: Vec<DataType> = decoder
+            .as_stream()
+            .take(all_types().len())
+            .map(|v| v.unwrap())
+            .collect()
+            .await;
+        assert_eq!(values, all_types());
+    }
+
+    #[tokio::test]
+    async fn test_decode_binary_bulk_string() {
+        let values = decode_all(b"$4\r\n\x00\xff\r\n\r\n").await;
+        assert_eq!(
+            values,
+            vec![DataType::BulkString {
+                string: Bytes::from_static(b"\x00\xff\r\n")
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() {
+        let tests = [
+            ("$abc\r\nfoo\r\n", "invalid bulk length"),
+            ("$-5\r\n", "invalid bulk length"),
+            ("*2\r\n$3\r\nfoo\r\nx", "expected '$', got 'x'"),
+            ("*x\r\n", "invalid multibulk length"),
+            (":12a\r\n", "invalid integer"),
+            ("$3\r\nfoobar\r\n", "invalid bulk string termination"),
+            ("ECHO \"open\r\n", "unbalanced quotes in request"),
+            ("\0PING\r\n", "unexpected byte '\\0'"),
+        ];
+        for (orig, expected) in tests {
+            let mut reader = orig.as_bytes();
+            let mut decoder = ChunkDecoder::new(&mut reader);
+            let mut stream = Box::pin(decoder.as_stream());
+            let err = stream.next().await.unwrap().unwrap_err();
+            assert_eq!(err.to_string(), expected, "decoding {orig:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decoder_limits() {
+        let limits = DecoderLimits {
+            max_bulk_len: 4,
+            max_multibulk_len: 2,
+            max_nesting: 2,
+            ..Default::default()
+        };
+        let tests = [
+            ("$4\r\nabcd\r\n", None),
+            ("$99999999999\r\n", Some("invalid bulk length")),
+            ("*2\r\n:1\r\n:2\r\n", None),
+            ("*3\r\n", Some("invalid multibulk length")),
+            ("*1\r\n*1\r\n:1\r\n", None),
+            (
+                "*1\r\n*1\r\n*1\r\n:1\r\n",
+                Some("too many nested aggregates"),
+            ),
+        ];
+        for (orig, expected) in tests {
+            let mut reader = orig.as_bytes();
+            let mut decoder = ChunkDecoder::new(&mut reader).with_limits(limits);
+            let mut stream = Box::pin(decoder.as_stream());
+            let parsed = stream.next().await.unwrap();
+            match expected {
+                Some(err) => assert_eq!(parsed.unwrap_err().to_string(), err),
+                None => assert!(parsed.is_ok(), "decoding {orig:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let limits = DecoderLimits {
+            read_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let mut decoder = ChunkDecoder::new(&mut server).with_limits(limits);
+        let mut stream = Box::pin(decoder.as_stream());
+
+        // idle clients aren't timed out
+        let idle = tokio::time::timeout(Duration::from_millis(100), stream.next()).await;
+        assert!(idle.is_err());
+
+        client
+            .write_all(b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPI")
+            .await
+            .unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(ParseError::ReadTimeout)));
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            for _ in 0..1000 {
+                client.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+            }
+        });
+        let mut decoder = ChunkDecoder::new(&mut server).with_buffer_size(16);
+        let mut stream = Box::pin(decoder.as_stream());
+        assert!(stream.next().await.unwrap().is_ok());
+        // the client is blocked until the pipelined commands are taken
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+        for _ in 1..1000 {
+            assert!(stream.next().await.unwrap().is_ok());
+        }
+        writer.await.unwrap();
+    }
+
+    /// Encodes `value` and decodes it back, reading `buffer_size` bytes at a time.
+    fn round_trip(value: &DataType, buffer_size: usize) -> DataType {
+        let encoded = value.encode().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        return runtime.block_on(async {
+            let mut reader = encoded.as_slice();
+            let mut decoder = ChunkDecoder::new(&mut reader).with_buffer_size(buffer_size);
+            let mut stream = Box::pin(decoder.as_stream());
+            return stream.next().await.unwrap().unwrap();
+        });
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(value in strategy::datatype(), buffer_size in 1..64usize) {
+            prop_assert_eq!(round_trip(&value, buffer_size), value);
+        }
+    }
+}
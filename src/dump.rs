@@ -0,0 +1,320 @@
+/// Serialization of values for DUMP and RESTORE.
+///
+/// Payloads are laid out like the ones of Redis: the type and the value as stored in
+/// RDB files, then the RDB version as 2 little endian bytes and the CRC-64 of
+/// everything before it as 8 little endian bytes. Values use the plain RDB encodings
+/// rather than the listpack ones: strings (type 0), lists (1), sets (2), hashes (4)
+/// and sorted sets with binary scores (5), which Redis still loads. Streams have no
+/// plain encoding, so they use a type of their own and can only be moved between
+/// instances of this server.
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use crate::check::{self, CheckError, Reader, RDB_VERSION};
+use crate::config::Config;
+use crate::value::{Hash, List, Set, Str, Stream, StreamId, Value, ZSet};
+
+const STRING_TYPE: u8 = 0;
+const LIST_TYPE: u8 = 1;
+const SET_TYPE: u8 = 2;
+const HASH_TYPE: u8 = 4;
+const ZSET_TYPE: u8 = 5;
+/// Past the types of Redis, which end at 21.
+const STREAM_TYPE: u8 = 200;
+
+/// Bytes after the value: the RDB version and the checksum.
+const FOOTER_LEN: usize = 10;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RestoreError {
+    #[error("DUMP payload version or checksum are wrong")]
+    Checksum,
+
+    #[error("Bad data format")]
+    BadFormat,
+}
+
+impl From<CheckError> for RestoreError {
+    fn from(_: CheckError) -> Self {
+        return RestoreError::BadFormat;
+    }
+}
+
+/// Serializes `value` into a payload RESTORE can load.
+pub fn dump(value: &Value) -> Bytes {
+    let mut out = Vec::new();
+    match value {
+        Value::Str(string) => {
+            out.push(STRING_TYPE);
+            write_string(&string.to_bytes(), &mut out);
+        }
+        Value::List(list) => {
+            out.push(LIST_TYPE);
+            write_length(list.len(), &mut out);
+            for item in list.iter() {
+                write_string(item, &mut out);
+            }
+        }
+        Value::Set(set) => {
+            out.push(SET_TYPE);
+            write_length(set.len(), &mut out);
+            for member in set.iter() {
+                write_string(member, &mut out);
+            }
+        }
+        Value::Hash(hash) => {
+            out.push(HASH_TYPE);
+            write_length(hash.len(), &mut out);
+            for (field, value) in hash.iter() {
+                write_string(field, &mut out);
+                write_string(value, &mut out);
+            }
+        }
+        Value::ZSet(zset) => {
+            out.push(ZSET_TYPE);
+            write_length(zset.len(), &mut out);
+            for (member, score) in zset.iter() {
+                write_string(member, &mut out);
+                out.extend(score.to_le_bytes());
+            }
+        }
+        Value::Stream(stream) => {
+            out.push(STREAM_TYPE);
+            write_stream_id(stream.last_id, &mut out);
+            write_length(stream.len(), &mut out);
+            for (id, fields) in &stream.entries {
+                write_stream_id(*id, &mut out);
+                write_length(fields.len(), &mut out);
+                for (field, value) in fields {
+                    write_string(field, &mut out);
+                    write_string(value, &mut out);
+                }
+            }
+        }
+    }
+    out.extend((RDB_VERSION as u16).to_le_bytes());
+    let checksum = check::crc64(&out);
+    out.extend(checksum.to_le_bytes());
+    return Bytes::from(out);
+}
+
+/// RDB length encoding: 6, 14, 32 or 64 bits, the first two bits telling which.
+fn write_length(len: usize, out: &mut Vec<u8>) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.extend([0x40 | (len >> 8) as u8, len as u8]);
+    } else if let Ok(len) = u32::try_from(len) {
+        out.push(0x80);
+        out.extend(len.to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend((len as u64).to_be_bytes());
+    }
+}
+
+fn write_string(string: &[u8], out: &mut Vec<u8>) {
+    write_length(string.len(), out);
+    out.extend_from_slice(string);
+}
+
+fn write_stream_id(id: StreamId, out: &mut Vec<u8>) {
+    out.extend(id.ms.to_be_bytes());
+    out.extend(id.seq.to_be_bytes());
+}
+
+/// Loads the value serialized in `payload`, encoded as `config` asks for values of
+/// its size.
+pub fn restore(payload: &[u8], config: &Config) -> Result<Value, RestoreError> {
+    if payload.len() < FOOTER_LEN {
+        return Err(RestoreError::Checksum);
+    }
+    let (data, footer) = payload.split_at(payload.len() - FOOTER_LEN);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    let checksum = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    let expected = check::crc64(&payload[..payload.len() - 8]);
+    if version as u32 > RDB_VERSION || checksum != expected {
+        return Err(RestoreError::Checksum);
+    }
+
+    let mut reader = Reader::new(data);
+    let value = match reader.u8()? {
+        STRING_TYPE => {
+            let string = Str::from(Bytes::from(reader.string()?));
+            Value::Str(string.compress(config.string_compression_threshold))
+        }
+        LIST_TYPE => {
+            let limits = config.list_listpack_limits();
+            let mut list = List::new();
+            for _ in 0..reader.count()? {
+                list.push_back(Bytes::from(reader.string()?), &limits);
+            }
+            Value::List(list)
+        }
+        SET_TYPE => {
+            let limits = config.set_listpack_limits();
+            let mut set = Set::new();
+            for _ in 0..reader.count()? {
+                if !set.insert(Bytes::from(reader.string()?), &limits) {
+                    return Err(RestoreError::BadFormat);
+                }
+            }
+            Value::Set(set)
+        }
+        HASH_TYPE => {
+            let limits = config.hash_listpack_limits();
+            let mut hash = Hash::new();
+            for _ in 0..reader.count()? {
+                let field = Bytes::from(reader.string()?);
+                let value = Bytes::from(reader.string()?);
+                if !hash.insert(field, value, &limits) {
+                    return Err(RestoreError::BadFormat);
+                }
+            }
+            Value::Hash(hash)
+        }
+        ZSET_TYPE => {
+            let limits = config.zset_listpack_limits();
+            let mut zset = ZSet::new();
+            for _ in 0..reader.count()? {
+                let member = Bytes::from(reader.string()?);
+                let score = f64::from_le_bytes(reader.bytes(8)?.try_into().unwrap());
+                if score.is_nan() || !zset.insert(member, score, &limits) {
+                    return Err(RestoreError::BadFormat);
+                }
+            }
+            Value::ZSet(zset)
+        }
+        STREAM_TYPE => {
+            let last_id = read_stream_id(&mut reader)?;
+            let mut entries = BTreeMap::new();
+            for _ in 0..reader.count()? {
+                let id = read_stream_id(&mut reader)?;
+                let mut fields = Vec::new();
+                for _ in 0..reader.count()? {
+                    fields.push((Bytes::from(reader.string()?), Bytes::from(reader.string()?)));
+                }
+                if id > last_id || entries.insert(id, fields).is_some() {
+                    return Err(RestoreError::BadFormat);
+                }
+            }
+            Value::Stream(Stream {
+                entries: entries,
+                last_id: last_id,
+            })
+        }
+        _ => return Err(RestoreError::BadFormat),
+    };
+    // empty collections don't exist in the keyspace
+    let empty = value.len() == 0 && !matches!(value, Value::Str(_) | Value::Stream(_));
+    if !reader.is_empty() || empty {
+        return Err(RestoreError::BadFormat);
+    }
+    return Ok(value);
+}
+
+fn read_stream_id(reader: &mut Reader) -> Result<StreamId, RestoreError> {
+    let ms = u64::from_be_bytes(reader.bytes(8)?.try_into().unwrap());
+    let seq = u64::from_be_bytes(reader.bytes(8)?.try_into().unwrap());
+    return Ok(StreamId { ms: ms, seq: seq });
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{dump, restore, write_length, RestoreError};
+    use crate::check::Reader;
+    use crate::config::Config;
+    use crate::value::{Hash, List, Set, Stream, StreamId, Value, ZSet};
+
+    #[test]
+    fn test_write_length() {
+        for len in [
+            0,
+            63,
+            64,
+            16383,
+            16384,
+            u32::MAX as usize,
+            u32::MAX as usize + 1,
+        ] {
+            let mut out = Vec::new();
+            write_length(len, &mut out);
+            assert_eq!(Reader::new(&out).count(), Ok(len));
+        }
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let config = Config::default();
+        let mut list = List::new();
+        let mut set = Set::new();
+        let mut hash = Hash::new();
+        let mut zset = ZSet::new();
+        for i in 0..200 {
+            let item = Bytes::from(format!("item:{i}"));
+            list.push_back(item.clone(), &config.list_listpack_limits());
+            set.insert(item.clone(), &config.set_listpack_limits());
+            hash.insert(
+                item.clone(),
+                Bytes::from("v"),
+                &config.hash_listpack_limits(),
+            );
+            zset.insert(item, i as f64 / 3.0, &config.zset_listpack_limits());
+        }
+        let mut stream = Stream::new();
+        stream.last_id = StreamId { ms: 10, seq: 2 };
+        stream.entries.insert(
+            StreamId { ms: 10, seq: 1 },
+            vec![(Bytes::from("field"), Bytes::from("value"))],
+        );
+        let values = [
+            Value::from(Bytes::from("string")),
+            Value::from(Bytes::from("-42")),
+            Value::from(Bytes::from(vec![b'x'; 100_000])),
+            Value::List(list),
+            Value::Set(set),
+            Value::Hash(hash),
+            Value::ZSet(zset),
+            Value::Stream(stream),
+        ];
+        for value in values {
+            let payload = dump(&value);
+            assert_eq!(restore(&payload, &config), Ok(value.clone()));
+        }
+    }
+
+    #[test]
+    fn test_restore_errors() {
+        let config = Config::default();
+        let payload = dump(&Value::from(Bytes::from("value")));
+        let mut corrupted = payload.to_vec();
+        corrupted[3] ^= 1;
+        assert_eq!(restore(&corrupted, &config), Err(RestoreError::Checksum));
+        assert_eq!(
+            restore(&payload[..payload.len() - 1], &config),
+            Err(RestoreError::Checksum)
+        );
+        assert_eq!(restore(b"short", &config), Err(RestoreError::Checksum));
+
+        // valid checksums around invalid values
+        let with_footer = |data: &[u8]| {
+            let mut out = data.to_vec();
+            out.extend(12u16.to_le_bytes());
+            out.extend(crate::check::crc64(&out).to_le_bytes());
+            return out;
+        };
+        for data in [&b"\x00\x05val"[..], b"\x02\x00", b"\x63\x00", b"\x00\x01aa"] {
+            let payload = with_footer(data);
+            assert_eq!(restore(&payload, &config), Err(RestoreError::BadFormat));
+        }
+        let payload = with_footer(b"\x02\x02\x01a\x01a");
+        assert_eq!(restore(&payload, &config), Err(RestoreError::BadFormat));
+        let mut newer = payload.clone();
+        newer[payload.len() - 10] = 13;
+        assert_eq!(restore(&newer, &config), Err(RestoreError::Checksum));
+    }
+}
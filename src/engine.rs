@@ -0,0 +1,367 @@
+/// Command execution engines.
+///
+/// With the `locks` engine every connection executes its commands itself, locking
+/// the shards of the keyspace they touch. With the `actors` engine each shard is
+/// owned by a task that executes, one at a time, the commands whose keys all live
+/// in its shard: connections send them over the shard's channel and wait for the
+/// reply. Commands on a single shard are then serialized by its task instead of
+/// contending for the lock, which is only taken by other tasks for commands without
+/// keys or spanning several shards (those still run on the connection).
+///
+/// Either way, clients running a blocking command with nothing to serve wait on
+/// their connection, not holding any lock nor shard task, for its keys to be written
+/// and then dispatch the command again.
+use std::sync::Arc;
+
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+use crate::clients::ClientId;
+use crate::commands::{self, Blocked};
+use crate::errors::ReplyError;
+use crate::protocol::DataType;
+use crate::state::State;
+
+/// Commands queued on a shard before connections wait to send more.
+const SHARD_QUEUE_LEN: usize = 1024;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum EngineError {
+    #[error("shard {0} is not running")]
+    ShardStopped(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineKind {
+    Locks,
+    Actors,
+}
+
+impl EngineKind {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            EngineKind::Locks => "locks",
+            EngineKind::Actors => "actors",
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name.to_lowercase().as_str() {
+            "locks" => Some(EngineKind::Locks),
+            "actors" => Some(EngineKind::Actors),
+            _ => None,
+        };
+    }
+}
+
+/// A command sent to a shard task.
+pub struct Job {
+    data: DataType,
+    client: ClientId,
+    reply: oneshot::Sender<Result<DataType, Box<Blocked>>>,
+}
+
+#[derive(Clone)]
+pub enum Engine {
+    Locks,
+    /// channels to the task owning each shard
+    Actors(Arc<Vec<mpsc::Sender<Job>>>),
+}
+
+impl Engine {
+    /// Starts the engine selected by the configuration. The shard tasks of the
+    /// actors engine stop once every clone of the engine is dropped.
+    pub fn start(state: &State) -> Self {
+        let kind = state.config.read().unwrap().engine;
+        return match kind {
+            EngineKind::Locks => Engine::Locks,
+            EngineKind::Actors => {
+                let shards = (0..state.databases[0].shard_count())
+                    .map(|_| {
+                        let (jobs, queue) = mpsc::channel(SHARD_QUEUE_LEN);
+                        tokio::spawn(run_shard(state.clone(), queue));
+                        jobs
+                    })
+                    .collect();
+                Engine::Actors(Arc::new(shards))
+            }
+        };
+    }
+
+    /// Executes the command in `data` received from `client`, see `commands::dispatch`.
+    /// Blocking commands wait until they are served, time out, or the client is
+    /// unblocked, see `blocked`.
+    pub async fn dispatch(&self, data: DataType, state: &State, client: ClientId) -> DataType {
+        let mut blocked = match self.try_dispatch(data, state, client).await {
+            Ok(response) => return response,
+            Err(blocked) => blocked,
+        };
+        let deadline = blocked.timeout.map(|timeout| Instant::now() + timeout);
+        let db = state.selected_db(client);
+        let waiter = match state.blocked.block(client, db, &blocked.keys) {
+            Some(waiter) => waiter,
+            None => return blocked.reply,
+        };
+        loop {
+            // the keys may have been written before blocking, so it's retried first
+            blocked = match self.try_dispatch(blocked.data, state, client).await {
+                Ok(response) => return response,
+                Err(blocked) => blocked,
+            };
+            let woken = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, waiter.wait())
+                    .await
+                    .is_ok(),
+                None => {
+                    waiter.wait().await;
+                    true
+                }
+            };
+            if !woken || waiter.cancelled() {
+                return blocked.reply;
+            }
+        }
+    }
+
+    /// Executes the command in `data` on the shard task owning its keys, if any, see
+    /// `commands::try_dispatch`.
+    async fn try_dispatch(
+        &self,
+        data: DataType,
+        state: &State,
+        client: ClientId,
+    ) -> Result<DataType, Box<Blocked>> {
+        let shards = match self {
+            Engine::Locks => return commands::try_dispatch(data, state, client),
+            Engine::Actors(shards) => shards,
+        };
+        let shard = match commands::command_shard(&data, state) {
+            Some(shard) => shard,
+            None => return commands::try_dispatch(data, state, client),
+        };
+        let (reply, response) = oneshot::channel();
+        let job = Job {
+            data: data,
+            client: client,
+            reply: reply,
+        };
+        if shards[shard].send(job).await.is_err() {
+            return Ok(stopped(shard));
+        }
+        return match response.await {
+            Ok(response) => response,
+            Err(_) => Ok(stopped(shard)),
+        };
+    }
+}
+
+/// Error replied when the task of a shard isn't running.
+fn stopped(shard: usize) -> DataType {
+    let err = EngineError::ShardStopped(shard);
+    return DataType::from(ReplyError::Err(err.to_string()));
+}
+
+/// Executes the commands sent to a shard until every sender is dropped.
+async fn run_shard(state: State, mut queue: mpsc::Receiver<Job>) {
+    while let Some(job) = queue.recv().await {
+        let response = commands::try_dispatch(job.data, &state, job.client);
+        // the connection may have been closed while waiting
+        let _ = job.reply.send(response);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::{Engine, EngineKind};
+    use crate::commands::command_shard;
+    use crate::config::Config;
+    use crate::protocol::DataType;
+    use crate::state::{State, StateInner};
+
+    fn command(args: &[&str]) -> DataType {
+        return DataType::Array {
+            items: args.iter().map(|arg| DataType::from(*arg)).collect(),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_actors_engine() {
+        let state = StateInner::new(Config {
+            engine: EngineKind::Actors,
+            ..Default::default()
+        });
+        let client = state.connect_client().unwrap();
+        let engine = Engine::start(&state);
+        for i in 0..20 {
+            let key = format!("key:{i}");
+            let set = command(&["SET", &key, "value"]);
+            assert!(command_shard(&set, &state).is_some());
+            let reply = engine.dispatch(set, &state, client.id).await;
+            assert_eq!(reply, DataType::ok());
+            let reply = engine
+                .dispatch(command(&["GET", &key]), &state, client.id)
+                .await;
+            assert_eq!(reply, DataType::bulk("value"));
+        }
+        assert_eq!(state.keyspace(0).len(), 20);
+
+        // commands without keys run on the connection
+        let ping = command(&["PING"]);
+        assert_eq!(command_shard(&ping, &state), None);
+        let reply = engine.dispatch(ping, &state, client.id).await;
+        assert_eq!(
+            reply,
+            DataType::SimpleString {
+                string: "PONG".to_string()
+            }
+        );
+    }
+
+    async fn test_blocking(kind: EngineKind) {
+        let state = StateInner::new(Config {
+            engine: kind,
+            ..Default::default()
+        });
+        let engine = Engine::start(&state);
+        let producer = state.connect_client().unwrap();
+        let blmpop = |timeout: &str| command(&["BLMPOP", timeout, "2", "a", "b", "LEFT"]);
+        let popped = |key: &str, item: &str| DataType::Array {
+            items: vec![
+                DataType::bulk(key.to_string()),
+                DataType::Array {
+                    items: vec![DataType::bulk(item.to_string())],
+                },
+            ],
+        };
+        let blocked = || {
+            let state = state.clone();
+            let engine = engine.clone();
+            return tokio::spawn(async move {
+                let client = state.connect_client().unwrap();
+                return engine.dispatch(blmpop("0"), &state, client.id).await;
+            });
+        };
+
+        // served right away when a list isn't empty
+        let push = command(&["RPUSH", "b", "x"]);
+        engine.dispatch(push, &state, producer.id).await;
+        let reply = engine.dispatch(blmpop("0"), &state, producer.id).await;
+        assert_eq!(reply, popped("b", "x"));
+
+        // woken up by every command pushing to the lists
+        let push = command(&["RPUSH", "source", "z"]);
+        engine.dispatch(push, &state, producer.id).await;
+        for (push, key, item) in [
+            (&["LPUSH", "a", "y"][..], "a", "y"),
+            (&["RPUSH", "b", "w"], "b", "w"),
+            (&["LMOVE", "source", "a", "LEFT", "LEFT"], "a", "z"),
+        ] {
+            let waiting = blocked();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(!waiting.is_finished());
+            engine.dispatch(command(push), &state, producer.id).await;
+            assert_eq!(waiting.await.unwrap(), popped(key, item));
+        }
+        let waiting = blocked();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // writes to other keys don't serve it
+        let set = command(&["SET", "c", "v"]);
+        engine.dispatch(set, &state, producer.id).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        let push = command(&["RPUSH", "a", "v"]);
+        engine.dispatch(push, &state, producer.id).await;
+        assert_eq!(waiting.await.unwrap(), popped("a", "v"));
+
+        // times out with a NullArray
+        let start = std::time::Instant::now();
+        let reply = engine.dispatch(blmpop("0.1"), &state, producer.id).await;
+        assert_eq!(reply, DataType::NullArray);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(state.blocked.waiting(), 0);
+
+        // and gives up when unblocked
+        let waiting = blocked();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state.blocked.unblock_all();
+        assert_eq!(waiting.await.unwrap(), DataType::NullArray);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_with_locks_engine() {
+        test_blocking(EngineKind::Locks).await;
+    }
+
+    #[tokio::test]
+    async fn test_blocking_with_actors_engine() {
+        test_blocking(EngineKind::Actors).await;
+    }
+
+    /// Runs a mix of commands touching every lock of the state from a client.
+    async fn stress_client(state: State, engine: Engine, seed: usize) {
+        let client = state.connect_client().unwrap();
+        let tracking = command(&["CLIENT", "TRACKING", "ON"]);
+        engine.dispatch(tracking, &state, client.id).await;
+        for i in 0..500 {
+            let key = format!("key:{}", (seed * 7 + i) % 64);
+            let args: &[&str] = match i % 8 {
+                0..=2 => &["SET", &key, "value"],
+                3..=4 => &["GET", &key],
+                5 => &["MEMORY", "USAGE", &key],
+                6 => &["INFO", "keyspace", "clients"],
+                _ => match seed % 2 {
+                    0 => &["CONFIG", "SET", "maxmemory-policy", "allkeys-lru"],
+                    _ => &["MEMORY", "STATS"],
+                },
+            };
+            engine.dispatch(command(args), &state, client.id).await;
+            if i % 50 == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+
+    fn stress(kind: EngineKind) {
+        let (done, finished) = mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(4)
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let state = StateInner::new(Config {
+                    engine: kind,
+                    keyspace_shards: 4,
+                    // small enough to keep evicting keys
+                    maxmemory: 8 * 1024,
+                    ..Default::default()
+                });
+                let engine = Engine::start(&state);
+                let clients: Vec<_> = (0..16)
+                    .map(|seed| tokio::spawn(stress_client(state.clone(), engine.clone(), seed)))
+                    .collect();
+                for client in clients {
+                    client.await.unwrap();
+                }
+            });
+            done.send(()).unwrap();
+        });
+        // a deadlock would block the executor threads, so it's detected from outside
+        let res = finished.recv_timeout(Duration::from_secs(60));
+        assert!(res.is_ok(), "{} engine deadlocked", kind.name());
+    }
+
+    #[test]
+    fn test_no_deadlocks_with_locks_engine() {
+        stress(EngineKind::Locks);
+    }
+
+    #[test]
+    fn test_no_deadlocks_with_actors_engine() {
+        stress(EngineKind::Actors);
+    }
+}
@@ -0,0 +1,119 @@
+/// Error replies sent to clients.
+///
+/// Every error reply starts with an Error Prefix telling its kind (see `DataType::Error`),
+/// `ReplyError` keeps the prefix and the message of the standard ones together so
+/// commands can't send malformed errors.
+use thiserror::Error;
+
+use crate::protocol::DataType;
+
+// not every kind of error is sent by the commands implemented so far
+#[allow(dead_code)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ReplyError {
+    /// Generic error.
+    #[error("{0}")]
+    Err(String),
+
+    #[error("Operation against a key holding the wrong kind of value")]
+    WrongType,
+
+    #[error("Authentication required.")]
+    NoAuth,
+
+    /// The server is busy running a script or module command.
+    #[error("{0}")]
+    Busy(String),
+
+    /// The key belongs to a hash slot served by another node.
+    #[error("{slot} {address}")]
+    Moved { slot: u16, address: String },
+
+    #[error("value is out of range")]
+    OutOfRange,
+
+    #[error("value is not an integer or out of range")]
+    NotAnInteger,
+
+    #[error("command not allowed when used memory > 'maxmemory'.")]
+    OutOfMemory,
+
+    /// The connection was refused.
+    #[error("{0}")]
+    Denied(String),
+
+    #[error("Target key name already exists.")]
+    BusyKey,
+
+    #[error("unsupported protocol version")]
+    NoProto,
+
+    #[error("invalid username-password pair or user is disabled.")]
+    WrongPass,
+}
+
+impl ReplyError {
+    /// Error Prefix of the reply.
+    pub fn prefix(&self) -> &'static str {
+        return match self {
+            ReplyError::Err(_) | ReplyError::OutOfRange | ReplyError::NotAnInteger => "ERR",
+            ReplyError::WrongType => "WRONGTYPE",
+            ReplyError::NoAuth => "NOAUTH",
+            ReplyError::Busy(_) => "BUSY",
+            ReplyError::Moved { .. } => "MOVED",
+            ReplyError::OutOfMemory => "OOM",
+            ReplyError::Denied(_) => "DENIED",
+            ReplyError::BusyKey => "BUSYKEY",
+            ReplyError::NoProto => "NOPROTO",
+            ReplyError::WrongPass => "WRONGPASS",
+        };
+    }
+}
+
+impl From<ReplyError> for DataType {
+    fn from(err: ReplyError) -> Self {
+        return DataType::Error {
+            type_: err.prefix().to_string(),
+            error: err.to_string(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReplyError;
+    use crate::protocol::DataType;
+
+    #[test]
+    fn test_reply_errors() {
+        let tests = [
+            (
+                ReplyError::Err(String::from("syntax error")),
+                "-ERR syntax error\r\n",
+            ),
+            (
+                ReplyError::WrongType,
+                "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+            ),
+            (
+                ReplyError::Moved {
+                    slot: 3999,
+                    address: String::from("127.0.0.1:6381"),
+                },
+                "-MOVED 3999 127.0.0.1:6381\r\n",
+            ),
+            (
+                ReplyError::OutOfMemory,
+                "-OOM command not allowed when used memory > 'maxmemory'.\r\n",
+            ),
+            (
+                ReplyError::BusyKey,
+                "-BUSYKEY Target key name already exists.\r\n",
+            ),
+        ];
+        for (err, expected) in tests {
+            let encoded = DataType::from(err).encode().unwrap();
+            assert_eq!(String::from_utf8(encoded).unwrap(), expected);
+        }
+    }
+}
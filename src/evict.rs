@@ -0,0 +1,382 @@
+/// Key eviction once the dataset grows past `maxmemory`.
+///
+/// Like Redis, eviction is approximated by sampling: each round picks a few random
+/// keys among the candidates of the configured policy and evicts the best one
+/// (the one idle for the longest time with the LRU policies, the least frequently
+/// accessed one with the LFU policies, the one closest to expire with volatile-ttl),
+/// until the memory used by the databases is back under the limit. With the
+/// noeviction policy no key is ever evicted. Keys are sampled from one shard of a
+/// database at a time, starting from a random one.
+///
+/// Access frequency is tracked with a Morris counter: an 8 bit logarithmic counter
+/// incremented with a probability that decreases as it grows (see `lfu_log_incr`),
+/// and decremented over time when the key isn't accessed (see `lfu_decay`).
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use crate::config::{Config, MaxmemoryPolicy};
+use crate::db::{DBValue, Keyspace, MapInner};
+use crate::lazyfree::LazyFree;
+
+/// Rounds of sampling attempted before falling back to scanning every key with an
+/// expiration looking for a candidate (only relevant for volatile policies, where
+/// most sampled keys may have no expiration).
+const MAX_SAMPLING_ROUNDS: usize = 16;
+
+/// Initial value of the access frequency counter, so new keys have a chance to
+/// accumulate hits before being evicted.
+pub const LFU_INIT_VAL: u8 = 5;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum EvictionError {
+    #[error("command not allowed when used memory > 'maxmemory'.")]
+    OutOfMemory,
+}
+
+/// Returns a random number, using the random keys std generates for every `RandomState`.
+pub fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_usize(0);
+    return hasher.finish();
+}
+
+/// Logarithmically increments the access frequency counter: the higher the counter
+/// and `log_factor`, the lower the chance of incrementing it.
+pub fn lfu_log_incr(counter: u8, log_factor: u64) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let r = random() as f64 / u64::MAX as f64;
+    let baseval = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    let p = 1.0 / (baseval * log_factor as f64 + 1.0);
+    if r < p {
+        return counter + 1;
+    }
+    return counter;
+}
+
+/// Decrements the access frequency counter by one for every `decay_time` minutes
+/// elapsed since it was last decremented.
+pub fn lfu_decay(counter: u8, elapsed_minutes: usize, decay_time: u64) -> u8 {
+    if decay_time == 0 {
+        return counter;
+    }
+    let periods = elapsed_minutes as u64 / decay_time;
+    return counter.saturating_sub(periods.min(u8::MAX as u64) as u8);
+}
+
+/// Evicts keys until the databases use at most `config.maxmemory` bytes (they
+/// share the memory counter). Evicted keys are appended to `evicted` and their
+/// values freed with `lazyfree`. Returns an error if there are no keys left to evict.
+pub fn perform_evictions(
+    databases: &[Keyspace],
+    lazyfree: &LazyFree,
+    config: &Config,
+    evicted: &mut Vec<Bytes>,
+) -> Result<(), EvictionError> {
+    let maxmemory = config.maxmemory as usize;
+    if maxmemory == 0 {
+        return Ok(());
+    }
+    while databases
+        .iter()
+        .map(Keyspace::used_memory)
+        .max()
+        .unwrap_or(0)
+        > maxmemory
+    {
+        if !databases
+            .iter()
+            .any(|keyspace| evict_one(keyspace, lazyfree, config, evicted))
+        {
+            return Err(EvictionError::OutOfMemory);
+        }
+    }
+    return Ok(());
+}
+
+/// Evicts a key from the first shard with a candidate, starting from a random one.
+/// Returns false if no shard has candidates.
+fn evict_one(
+    keyspace: &Keyspace,
+    lazyfree: &LazyFree,
+    config: &Config,
+    evicted: &mut Vec<Bytes>,
+) -> bool {
+    let shards = keyspace.shard_count();
+    let start = random() as usize;
+    for i in 0..shards {
+        let mut map = keyspace.lock_shard((start + i) % shards);
+        if let Some(key) = select_candidate(&map, config) {
+            let value = map.remove(&key);
+            drop(map);
+            if let Some(value) = value {
+                lazyfree.free(value, config.lazyfree_lazy_eviction);
+            }
+            evicted.push(key);
+            return true;
+        }
+    }
+    return false;
+}
+
+/// Scores how good of an eviction candidate a value is, higher is better.
+fn eviction_score(value: &DBValue, config: &Config, now: usize) -> usize {
+    let policy = config.maxmemory_policy;
+    if policy.is_lfu() {
+        return (u8::MAX - value.lfu_frequency(config.lfu_decay_time, now)) as usize;
+    }
+    if policy.is_random() {
+        return random() as usize;
+    }
+    if policy == MaxmemoryPolicy::VolatileTtl {
+        return usize::MAX - value.expiration;
+    }
+    return value.idle_time(now);
+}
+
+fn select_candidate(map: &MapInner, config: &Config) -> Option<Bytes> {
+    let policy = config.maxmemory_policy;
+    if policy == MaxmemoryPolicy::NoEviction {
+        return None;
+    }
+    let samples = config.maxmemory_samples;
+    let now = map.now();
+    // (key, score) of the best candidate found so far
+    let mut best: Option<(&Bytes, usize)> = None;
+    for _ in 0..MAX_SAMPLING_ROUNDS {
+        // small keyspaces are inspected entirely
+        let full_scan = map.len() <= samples;
+        for i in 0..samples.min(map.len()) {
+            let ix = if full_scan { i } else { random() as usize };
+            let (key, value) = map.get_index(ix)?;
+            if policy.is_volatile() && !value.is_volatile() {
+                continue;
+            }
+            let score = eviction_score(value, config, now);
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((key, score));
+            }
+        }
+        if best.is_some() {
+            break;
+        }
+    }
+    if best.is_none() && policy.is_volatile() {
+        best = map
+            .expirations()
+            .filter_map(|(_, key)| Some((key, eviction_score(map.get(key)?, config, now))))
+            .max_by_key(|(_, score)| *score);
+    }
+    return best.map(|(key, _)| key.clone());
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{lfu_decay, lfu_log_incr, perform_evictions, EvictionError, LFU_INIT_VAL};
+    use crate::config::{Config, MaxmemoryPolicy};
+    use crate::db::{entry_memory_usage, DBValue, Keyspace, MapInner};
+    use crate::lazyfree::LazyFree;
+
+    fn config(maxmemory: usize, policy: MaxmemoryPolicy) -> Config {
+        return Config {
+            maxmemory: maxmemory as u64,
+            maxmemory_policy: policy,
+            ..Default::default()
+        };
+    }
+
+    /// Evicts keys from a keyspace with `map` as its only shard, returning how many
+    /// were evicted.
+    fn evict(map: &mut MapInner, config: &Config) -> Result<usize, EvictionError> {
+        let keyspace = Keyspace::from_shards(vec![std::mem::take(map)]);
+        let mut evicted = Vec::new();
+        let res = perform_evictions(
+            std::slice::from_ref(&keyspace),
+            &LazyFree::new(),
+            config,
+            &mut evicted,
+        );
+        *map = keyspace.into_shards().pop().unwrap();
+        res?;
+        return Ok(evicted.len());
+    }
+
+    fn fill(map: &mut MapInner, count: usize, expiration: usize) {
+        for i in 0..count {
+            let value = DBValue::with_expiration(Bytes::from("value"), expiration, map.now());
+            map.insert(Bytes::from(format!("key:{expiration}:{i}")), value);
+        }
+    }
+
+    #[test]
+    fn test_no_limit() {
+        let mut map = MapInner::new();
+        fill(&mut map, 10, 0);
+        let evicted = evict(&mut map, &config(0, MaxmemoryPolicy::AllkeysLru));
+        assert_eq!(evicted, Ok(0));
+        assert_eq!(map.len(), 10);
+    }
+
+    #[test]
+    fn test_allkeys_lru_evicts_until_under_limit() {
+        let mut map = MapInner::new();
+        fill(&mut map, 100, 0);
+        let limit = map.used_memory() / 2;
+        let evicted = evict(&mut map, &config(limit, MaxmemoryPolicy::AllkeysLru));
+        assert!(evicted.unwrap() >= 50);
+        assert!(map.used_memory() <= limit);
+    }
+
+    #[test]
+    fn test_allkeys_lru_prefers_idle_keys() {
+        let mut map = MapInner::new();
+        let mut old = DBValue::with_expiration(Bytes::from("value"), 0, map.now());
+        old.lru -= 100_000;
+        map.insert(Bytes::from("old"), old);
+        fill(&mut map, 3, 0);
+        let limit = map.used_memory() - 1;
+        // with so few keys every key is sampled
+        evict(&mut map, &config(limit, MaxmemoryPolicy::AllkeysLru)).unwrap();
+        assert!(map.get(b"old").is_none());
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn test_volatile_lru_only_evicts_volatile_keys() {
+        let mut map = MapInner::new();
+        fill(&mut map, 50, 0);
+        fill(&mut map, 1, 100_000);
+        let volatile = map.iter().find(|(_, v)| v.is_volatile()).unwrap();
+        let size = entry_memory_usage(volatile.0, volatile.1, 0);
+        let limit = map.used_memory() - size;
+        let evicted = evict(&mut map, &config(limit, MaxmemoryPolicy::VolatileLru));
+        assert_eq!(evicted, Ok(1));
+        assert!(map.iter().all(|(_, v)| !v.is_volatile()));
+
+        // nothing left to evict
+        let evicted = evict(&mut map, &config(1, MaxmemoryPolicy::VolatileLru));
+        assert_eq!(evicted, Err(EvictionError::OutOfMemory));
+    }
+
+    #[test]
+    fn test_lfu_log_incr() {
+        // below the initial value the counter is always incremented
+        assert_eq!(lfu_log_incr(0, 10), 1);
+        assert_eq!(lfu_log_incr(u8::MAX, 0), u8::MAX);
+
+        let mut counter = LFU_INIT_VAL;
+        for _ in 0..1000 {
+            counter = lfu_log_incr(counter, 10);
+        }
+        // ~1000 hits with the default log factor get to a counter around 18
+        assert!(counter > 10 && counter < 30, "counter {counter}");
+    }
+
+    #[test]
+    fn test_lfu_decay() {
+        assert_eq!(lfu_decay(10, 0, 1), 10);
+        assert_eq!(lfu_decay(10, 3, 1), 7);
+        assert_eq!(lfu_decay(10, 3, 2), 9);
+        assert_eq!(lfu_decay(10, 300, 1), 0);
+        assert_eq!(lfu_decay(10, 300, 0), 10);
+    }
+
+    #[test]
+    fn test_allkeys_lfu_prefers_unfrequent_keys() {
+        let mut map = MapInner::new();
+        fill(&mut map, 3, 0);
+        let mut rare = DBValue::with_expiration(Bytes::from("value"), 0, map.now());
+        rare.lfu_counter = 0;
+        map.insert(Bytes::from("rare"), rare);
+        let limit = map.used_memory() - 1;
+        evict(&mut map, &config(limit, MaxmemoryPolicy::AllkeysLfu)).unwrap();
+        assert!(map.get(b"rare").is_none());
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn test_noeviction() {
+        let mut map = MapInner::new();
+        fill(&mut map, 10, 0);
+        let evicted = evict(&mut map, &config(1, MaxmemoryPolicy::NoEviction));
+        assert_eq!(evicted, Err(EvictionError::OutOfMemory));
+        assert_eq!(map.len(), 10);
+    }
+
+    #[test]
+    fn test_volatile_ttl_prefers_nearest_expiration() {
+        let mut map = MapInner::new();
+        fill(&mut map, 2, 0);
+        fill(&mut map, 1, 100_000);
+        fill(&mut map, 1, 1_000);
+        let limit = map.used_memory() - 1;
+        evict(&mut map, &config(limit, MaxmemoryPolicy::VolatileTtl)).unwrap();
+        assert!(map.get(b"key:1000:0").is_none());
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn test_random_policies() {
+        let mut map = MapInner::new();
+        fill(&mut map, 50, 0);
+        fill(&mut map, 50, 100_000);
+        let limit = map.used_memory() / 2;
+        let evicted = evict(&mut map, &config(limit, MaxmemoryPolicy::VolatileRandom));
+        assert_eq!(evicted, Ok(50));
+        assert!(map.iter().all(|(_, v)| !v.is_volatile()));
+
+        let evicted = evict(&mut map, &config(limit / 2, MaxmemoryPolicy::AllkeysRandom));
+        assert_eq!(evicted, Ok(25));
+    }
+
+    fn sharded(count: usize, volatile: &str) -> Keyspace {
+        let keyspace = Keyspace::new(4);
+        for i in 0..count {
+            let key = format!("key:{i}");
+            let expiration = if key == volatile { 100_000 } else { 0 };
+            let value = DBValue::with_expiration(Bytes::from("value"), expiration, keyspace.now());
+            keyspace
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key), value);
+        }
+        return keyspace;
+    }
+
+    #[test]
+    fn test_evicts_across_shards() {
+        let keyspace = sharded(100, "");
+        let lazyfree = LazyFree::new();
+        let mut evicted = Vec::new();
+        let limit = keyspace.used_memory() / 2;
+        let config_lru = config(limit, MaxmemoryPolicy::AllkeysLru);
+        perform_evictions(
+            std::slice::from_ref(&keyspace),
+            &lazyfree,
+            &config_lru,
+            &mut evicted,
+        )
+        .unwrap();
+        assert!(keyspace.used_memory() <= limit);
+        assert_eq!(keyspace.len(), 100 - evicted.len());
+
+        // the only volatile key is found whatever shard it is in
+        let keyspace = sharded(100, "key:42");
+        let mut evicted = Vec::new();
+        let limit = keyspace.used_memory() - 1;
+        let config_volatile = config(limit, MaxmemoryPolicy::VolatileLru);
+        perform_evictions(
+            std::slice::from_ref(&keyspace),
+            &lazyfree,
+            &config_volatile,
+            &mut evicted,
+        )
+        .unwrap();
+        assert_eq!(evicted, vec![Bytes::from("key:42")]);
+    }
+}
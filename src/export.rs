@@ -0,0 +1,443 @@
+/// Export of the keyspace to JSON and import back, for DEBUG EXPORT and DEBUG IMPORT.
+///
+/// The export is an array with an object per key, sorted by key and written one per
+/// line so datasets can be diffed:
+///
+/// ```text
+/// [
+/// {"key":"counter","type":"string","value":"42","expire_at":1700000000000},
+/// {"key":"queue","type":"list","value":["a","b"]},
+/// {"key":"user:1","type":"hash","value":[["name","ann"]]},
+/// {"key":"tags","type":"set","value":["x","y"]},
+/// {"key":"ranks","type":"zset","value":[["ann",1.5],["bob","inf"]]},
+/// {"key":"log","type":"stream","value":{"last_id":"5-0","entries":[["5-0",[["f","v"]]]]}}
+/// ]
+/// ```
+///
+/// `expire_at` is the Unix time (ms) the key expires at, and `flags` the flags of
+/// values stored by memcached clients, both left out when unset. Keys and strings that
+/// aren't UTF-8 are written as arrays of bytes, and infinite scores as "inf" and "-inf".
+use std::fmt::Write;
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use crate::clients::ClientId;
+use crate::db::DBValue;
+use crate::json::{self, Json, JsonError};
+use crate::state::State;
+use crate::tracking;
+use crate::value::{Hash, List, Set, Str, Stream, StreamId, Value, ZSet};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ImportError {
+    #[error(transparent)]
+    Json(#[from] JsonError),
+
+    #[error("expected an array of keys")]
+    NotAnArray,
+
+    #[error("key #{0}: {1}")]
+    InvalidEntry(usize, &'static str),
+}
+
+/// The keys of the database selected by `client` that haven't expired, as JSON.
+pub fn export(state: &State, client: ClientId) -> String {
+    let mut entries = Vec::new();
+    for map in state.keyspace(client).shards() {
+        let now = map.now();
+        for (key, value) in map.iter() {
+            if !value.is_expired(now) {
+                let mut entry = String::new();
+                write_entry(key, value, &mut entry);
+                entries.push((key.clone(), entry));
+            }
+        }
+    }
+    entries.sort_unstable();
+    let mut out = String::from("[\n");
+    for (i, (_, entry)) in entries.iter().enumerate() {
+        out.push_str(entry);
+        out.push_str(if i + 1 < entries.len() { ",\n" } else { "\n" });
+    }
+    out.push_str("]\n");
+    return out;
+}
+
+fn write_entry(key: &[u8], value: &DBValue, out: &mut String) {
+    out.push_str("{\"key\":");
+    json::write_bytes(key, out);
+    write!(out, ",\"type\":\"{}\",\"value\":", value.value.type_name()).unwrap();
+    match &value.value {
+        Value::Str(string) => json::write_bytes(&string.to_bytes(), out),
+        Value::List(list) => write_array(list.iter(), out, json::write_bytes),
+        Value::Set(set) => write_array(set.iter(), out, json::write_bytes),
+        Value::Hash(hash) => write_array(hash.iter(), out, write_pair),
+        Value::ZSet(zset) => write_array(zset.iter(), out, |(member, score), out| {
+            out.push('[');
+            json::write_bytes(member, out);
+            out.push(',');
+            write_score(score, out);
+            out.push(']');
+        }),
+        Value::Stream(stream) => {
+            out.push_str("{\"last_id\":");
+            write_stream_id(stream.last_id, out);
+            out.push_str(",\"entries\":");
+            write_array(stream.entries.iter(), out, |(id, fields), out| {
+                out.push('[');
+                write_stream_id(*id, out);
+                out.push(',');
+                write_array(fields.iter(), out, |(field, value), out| {
+                    write_pair((field, value), out)
+                });
+                out.push(']');
+            });
+            out.push('}');
+        }
+    }
+    if value.is_volatile() {
+        write!(out, ",\"expire_at\":{}", value.expiration).unwrap();
+    }
+    if value.flags != 0 {
+        write!(out, ",\"flags\":{}", value.flags).unwrap();
+    }
+    out.push('}');
+}
+
+fn write_array<T>(
+    items: impl Iterator<Item = T>,
+    out: &mut String,
+    mut write_item: impl FnMut(T, &mut String),
+) {
+    out.push('[');
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_item(item, out);
+    }
+    out.push(']');
+}
+
+fn write_pair((field, value): (&[u8], &[u8]), out: &mut String) {
+    out.push('[');
+    json::write_bytes(field, out);
+    out.push(',');
+    json::write_bytes(value, out);
+    out.push(']');
+}
+
+fn write_score(score: f64, out: &mut String) {
+    // JSON has no infinities
+    if score == f64::INFINITY {
+        out.push_str("\"inf\"");
+    } else if score == f64::NEG_INFINITY {
+        out.push_str("\"-inf\"");
+    } else {
+        write!(out, "{score:?}").unwrap();
+    }
+}
+
+fn write_stream_id(id: StreamId, out: &mut String) {
+    write!(out, "\"{}-{}\"", id.ms, id.seq).unwrap();
+}
+
+/// Loads the keys of an export into the database selected by `client`, replacing
+/// the ones that already exist. Keys that have expired since the export are
+/// skipped. Nothing is loaded if any key is invalid. Returns the number of keys loaded.
+pub fn import(state: &State, client: ClientId, data: &[u8]) -> Result<usize, ImportError> {
+    let entries = match json::parse(data)? {
+        Json::Array(entries) => entries,
+        _ => return Err(ImportError::NotAnArray),
+    };
+    let keyspace = state.keyspace(client);
+    let now = keyspace.now();
+    let (values, lazy) = {
+        let config = state.config.read().unwrap();
+        let mut values = Vec::with_capacity(entries.len());
+        for (i, entry) in entries.iter().enumerate() {
+            let invalid = |reason| ImportError::InvalidEntry(i, reason);
+            let key = entry
+                .get("key")
+                .and_then(Json::as_bytes)
+                .ok_or(invalid("missing key"))?;
+            let type_ = entry
+                .get("type")
+                .and_then(Json::as_str)
+                .ok_or(invalid("missing type"))?;
+            let value = entry.get("value").ok_or(invalid("missing value"))?;
+            let value = match type_ {
+                "string" => {
+                    let string = value.as_bytes().ok_or(invalid("invalid string"))?;
+                    Value::Str(Str::from(string).compress(config.string_compression_threshold))
+                }
+                "list" => {
+                    let limits = config.list_listpack_limits();
+                    let mut list = List::new();
+                    for item in byte_strings(value).ok_or(invalid("invalid list"))? {
+                        list.push_back(item, &limits);
+                    }
+                    Value::List(list)
+                }
+                "set" => {
+                    let limits = config.set_listpack_limits();
+                    let mut set = Set::new();
+                    for member in byte_strings(value).ok_or(invalid("invalid set"))? {
+                        set.insert(member, &limits);
+                    }
+                    Value::Set(set)
+                }
+                "hash" => {
+                    let limits = config.hash_listpack_limits();
+                    let mut hash = Hash::new();
+                    for (field, value) in pairs(value).ok_or(invalid("invalid hash"))? {
+                        hash.insert(field, value, &limits);
+                    }
+                    Value::Hash(hash)
+                }
+                "zset" => {
+                    let limits = config.zset_listpack_limits();
+                    let mut zset = ZSet::new();
+                    for (member, score) in scores(value).ok_or(invalid("invalid zset"))? {
+                        zset.insert(member, score, &limits);
+                    }
+                    Value::ZSet(zset)
+                }
+                "stream" => Value::Stream(stream(value).ok_or(invalid("invalid stream"))?),
+                _ => return Err(invalid("unknown type")),
+            };
+            // empty values don't exist in the keyspace
+            if value.len() == 0 && !matches!(value, Value::Str(_) | Value::Stream(_)) {
+                return Err(invalid("empty value"));
+            }
+            let expiration = match entry.get("expire_at") {
+                Some(Json::Number(n)) => n.parse().map_err(|_| invalid("invalid expire_at"))?,
+                Some(_) => return Err(invalid("invalid expire_at")),
+                None => 0,
+            };
+            let flags = match entry.get("flags") {
+                Some(Json::Number(n)) => n.parse().map_err(|_| invalid("invalid flags"))?,
+                Some(_) => return Err(invalid("invalid flags")),
+                None => 0,
+            };
+            let value = DBValue {
+                expiration: expiration,
+                flags: flags,
+                ..DBValue::with_expiration(value, 0, now)
+            };
+            if !value.is_expired(now) {
+                values.push((key, value));
+            }
+        }
+        (values, config.lazyfree_lazy_server_del)
+    };
+    let loaded = values.len();
+    let mut keys = Vec::with_capacity(loaded);
+    for (key, value) in values {
+        let old_value = keyspace.lock(&key).insert(key.clone(), value);
+        if let Some(old_value) = old_value {
+            state.lazyfree.free(old_value, lazy);
+        }
+        keys.push(key);
+    }
+    tracking::invalidate_keys(state, &keys, None);
+    return Ok(loaded);
+}
+
+fn byte_strings(value: &Json) -> Option<Vec<Bytes>> {
+    return value.as_array()?.iter().map(Json::as_bytes).collect();
+}
+
+fn pairs(value: &Json) -> Option<Vec<(Bytes, Bytes)>> {
+    return value
+        .as_array()?
+        .iter()
+        .map(|pair| match pair.as_array()? {
+            [field, value] => Some((field.as_bytes()?, value.as_bytes()?)),
+            _ => None,
+        })
+        .collect();
+}
+
+fn scores(value: &Json) -> Option<Vec<(Bytes, f64)>> {
+    return value
+        .as_array()?
+        .iter()
+        .map(|pair| match pair.as_array()? {
+            [member, Json::Number(score)] => Some((member.as_bytes()?, score.parse().ok()?)),
+            [member, Json::String(score)] => {
+                let score = match score.as_str() {
+                    "inf" | "+inf" => f64::INFINITY,
+                    "-inf" => f64::NEG_INFINITY,
+                    _ => return None,
+                };
+                Some((member.as_bytes()?, score))
+            }
+            _ => None,
+        })
+        .collect();
+}
+
+fn stream_id(value: &Json) -> Option<StreamId> {
+    let (ms, seq) = value.as_str()?.split_once('-')?;
+    return Some(StreamId {
+        ms: ms.parse().ok()?,
+        seq: seq.parse().ok()?,
+    });
+}
+
+fn stream(value: &Json) -> Option<Stream> {
+    let mut stream = Stream::new();
+    stream.last_id = stream_id(value.get("last_id")?)?;
+    for entry in value.get("entries")?.as_array()? {
+        let (id, fields) = match entry.as_array()? {
+            [id, fields] => (stream_id(id)?, pairs(fields)?),
+            _ => return None,
+        };
+        if id > stream.last_id {
+            return None;
+        }
+        stream.entries.insert(id, fields);
+    }
+    return Some(stream);
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{export, import, ImportError};
+    use crate::clock::MockClock;
+    use crate::config::Config;
+    use crate::db::DBValue;
+    use crate::json::JsonError;
+    use crate::state::StateInner;
+    use crate::value::{Hash, List, Set, Stream, StreamId, Value, ZSet};
+
+    #[test]
+    fn test_export_import() {
+        let clock = MockClock::new(1000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let limits = Config::default().hash_listpack_limits();
+        let mut list = List::new();
+        list.push_back(Bytes::from("a"), &limits);
+        list.push_back(Bytes::from_static(b"\xff"), &limits);
+        let mut hash = Hash::new();
+        hash.insert(Bytes::from("field"), Bytes::from("value"), &limits);
+        let mut set = Set::new();
+        set.insert(Bytes::from("member"), &limits);
+        let mut zset = ZSet::new();
+        zset.insert(Bytes::from("a"), 1.5, &limits);
+        zset.insert(Bytes::from("b"), f64::INFINITY, &limits);
+        let mut stream = Stream::new();
+        stream.last_id = StreamId { ms: 5, seq: 1 };
+        stream.entries.insert(
+            StreamId { ms: 5, seq: 0 },
+            vec![(Bytes::from("f"), Bytes::from("v"))],
+        );
+        let values = [
+            ("string", Value::from(Bytes::from("12")), 0, 0),
+            ("list", Value::List(list), 0, 0),
+            ("hash", Value::Hash(hash), 0, 0),
+            ("set", Value::Set(set), 5000, 0),
+            ("zset", Value::ZSet(zset), 0, 0),
+            ("stream", Value::Stream(stream), 0, 7),
+            ("expired", Value::from(Bytes::from("x")), 1, 0),
+        ];
+        for (key, value, expiration, flags) in values {
+            let value = DBValue {
+                flags: flags,
+                ..DBValue::with_expiration(value, expiration, 1000)
+            };
+            state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .insert(Bytes::from(key.to_string()), value);
+        }
+        clock.advance(1);
+
+        let json = export(&state, 0);
+        let expected = concat!(
+            "[\n",
+            r#"{"key":"hash","type":"hash","value":[["field","value"]]},"#,
+            "\n",
+            r#"{"key":"list","type":"list","value":["a",[255]]},"#,
+            "\n",
+            r#"{"key":"set","type":"set","value":["member"],"expire_at":6000},"#,
+            "\n",
+            r#"{"key":"stream","type":"stream","value":{"last_id":"5-1","entries":[["5-0",[["f","v"]]]]},"flags":7},"#,
+            "\n",
+            r#"{"key":"string","type":"string","value":"12"},"#,
+            "\n",
+            r#"{"key":"zset","type":"zset","value":[["a",1.5],["b","inf"]]}"#,
+            "\n]\n",
+        );
+        assert_eq!(json, expected);
+
+        let copy = StateInner::with_clock(Config::default(), clock.clone());
+        assert_eq!(import(&copy, 0, json.as_bytes()), Ok(6));
+        assert_eq!(export(&copy, 0), json);
+        for key in ["string", "list", "hash", "set", "zset", "stream"] {
+            let original = state
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .get(key.as_bytes())
+                .unwrap()
+                .clone();
+            let imported = copy
+                .keyspace(0)
+                .lock(key.as_bytes())
+                .get(key.as_bytes())
+                .unwrap()
+                .clone();
+            assert_eq!(imported.value, original.value, "{key}");
+            assert_eq!(imported.expiration, original.expiration, "{key}");
+            assert_eq!(imported.flags, original.flags, "{key}");
+        }
+
+        // keys expired since the export are skipped
+        clock.advance(5000);
+        let state = StateInner::with_clock(Config::default(), clock);
+        assert_eq!(import(&state, 0, json.as_bytes()), Ok(5));
+        assert!(state.keyspace(0).lock(b"set").get(b"set").is_none());
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let state = StateInner::new(Config::default());
+        let tests = [
+            ("{}", ImportError::NotAnArray),
+            ("[", ImportError::Json(JsonError("unexpected end of input"))),
+            (
+                r#"[{"type":"string","value":"v"}]"#,
+                ImportError::InvalidEntry(0, "missing key"),
+            ),
+            (
+                r#"[{"key":"k","type":"string","value":"v"},{"key":"l","type":"list","value":[]}]"#,
+                ImportError::InvalidEntry(1, "empty value"),
+            ),
+            (
+                r#"[{"key":"k","type":"nope","value":1}]"#,
+                ImportError::InvalidEntry(0, "unknown type"),
+            ),
+            (
+                r#"[{"key":"k","type":"zset","value":[["a","x"]]}]"#,
+                ImportError::InvalidEntry(0, "invalid zset"),
+            ),
+            (
+                r#"[{"key":"k","type":"string","value":"v","expire_at":-1}]"#,
+                ImportError::InvalidEntry(0, "invalid expire_at"),
+            ),
+            (
+                r#"[{"key":"k","type":"stream","value":{"last_id":"1-0","entries":[["2-0",[]]]}}]"#,
+                ImportError::InvalidEntry(0, "invalid stream"),
+            ),
+        ];
+        for (json, err) in tests {
+            assert_eq!(import(&state, 0, json.as_bytes()), Err(err), "{json}");
+        }
+        // nothing is loaded if any key is invalid
+        assert!(state.keyspace(0).is_empty());
+    }
+}
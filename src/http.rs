@@ -0,0 +1,489 @@
+/// HTTP gateway to the commands, for quick inspection with curl and lightweight
+/// integrations.
+///
+/// `POST /command` runs the command given as a JSON array of its arguments, e.g.
+/// `["SET", "key", "value"]`, and `GET /keys/{key}` runs `GET key`. Replies are
+/// rendered as JSON in the representation of the `serde` feature of `DataType`, e.g.
+/// `{"type": "bulk_string", "string": "value"}`.
+use std::fmt::Write;
+
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+use memchr::memmem;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::json::{self, Json, JsonError};
+use crate::protocol::DataType;
+
+/// Longest request line and headers accepted.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpError {
+    #[error("invalid request: {0}")]
+    InvalidRequest(&'static str),
+
+    #[error("invalid JSON: {0}")]
+    InvalidJson(&'static str),
+
+    #[error("body of {0} bytes is too big")]
+    TooBig(usize),
+
+    #[error("not found")]
+    NotFound,
+
+    #[error("method not allowed")]
+    MethodNotAllowed,
+}
+
+impl From<JsonError> for HttpError {
+    fn from(err: JsonError) -> HttpError {
+        return HttpError::InvalidJson(err.0);
+    }
+}
+
+impl HttpError {
+    pub fn status(&self) -> &'static str {
+        return match self {
+            HttpError::InvalidRequest(_) | HttpError::InvalidJson(_) => "400 Bad Request",
+            HttpError::TooBig(_) => "413 Payload Too Large",
+            HttpError::NotFound => "404 Not Found",
+            HttpError::MethodNotAllowed => "405 Method Not Allowed",
+        };
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Bytes,
+    /// false if the connection must be closed after replying
+    pub keep_alive: bool,
+}
+
+/// Request line and headers of a request, and the length of its body.
+fn parse_head(head: &[u8]) -> Result<(Request, usize), HttpError> {
+    let head = std::str::from_utf8(head).map_err(|_| HttpError::InvalidRequest("not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path, version) = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(method), Some(path), Some(version), None) => (method, path, version),
+        _ => return Err(HttpError::InvalidRequest("malformed request line")),
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(HttpError::InvalidRequest("unsupported version")),
+    };
+    let mut content_length = 0;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+            None => continue,
+        };
+        match name.as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| HttpError::InvalidRequest("invalid content length"))?
+            }
+            "transfer-encoding" => {
+                return Err(HttpError::InvalidRequest(
+                    "transfer encodings not supported",
+                ))
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            _ => {}
+        }
+    }
+    let request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        body: Bytes::new(),
+        keep_alive: keep_alive,
+    };
+    return Ok((request, content_length));
+}
+
+/// Reads the next request, buffering the input in `buffer`. Returns `None` if the
+/// connection was closed between requests.
+///
+/// Cancel safe: everything read is kept in `buffer` until a whole request is there.
+pub async fn read_request<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut BytesMut,
+    max_body_len: usize,
+) -> Result<Option<Request>> {
+    loop {
+        if let Some(end) = memmem::find(buffer, b"\r\n\r\n") {
+            let (mut request, content_length) = parse_head(&buffer[..end])?;
+            if content_length > max_body_len {
+                bail!(HttpError::TooBig(content_length));
+            }
+            if buffer.len() >= end + 4 + content_length {
+                let mut message = buffer.split_to(end + 4 + content_length);
+                request.body = message.split_off(end + 4).freeze();
+                return Ok(Some(request));
+            }
+        } else if buffer.len() >= MAX_HEAD_LEN {
+            bail!(HttpError::InvalidRequest("headers too long"));
+        }
+        if reader.read_buf(buffer).await? == 0 {
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+            bail!(HttpError::InvalidRequest("connection closed mid-request"));
+        }
+    }
+}
+
+/// The command a request runs.
+pub fn route(request: &Request) -> Result<DataType, HttpError> {
+    let args = if request.path == "/command" {
+        if request.method != "POST" {
+            return Err(HttpError::MethodNotAllowed);
+        }
+        parse_arguments(&request.body)?
+    } else if let Some(key) = request.path.strip_prefix("/keys/") {
+        if request.method != "GET" {
+            return Err(HttpError::MethodNotAllowed);
+        }
+        vec![Bytes::from("GET"), percent_decode(key)?]
+    } else {
+        return Err(HttpError::NotFound);
+    };
+    if args.is_empty() {
+        return Err(HttpError::InvalidJson("empty command"));
+    }
+    return Ok(DataType::Array {
+        items: args.into_iter().map(DataType::bulk).collect(),
+    });
+}
+
+fn percent_decode(string: &str) -> Result<Bytes, HttpError> {
+    let invalid = HttpError::InvalidRequest("invalid percent-encoding");
+    let mut bytes = Vec::with_capacity(string.len());
+    let mut iter = string.bytes();
+    while let Some(byte) = iter.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = [iter.next(), iter.next()];
+        let hex = match hex {
+            [Some(high), Some(low)] => [high, low],
+            _ => return Err(invalid),
+        };
+        let hex = std::str::from_utf8(&hex).map_err(|_| invalid)?;
+        bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid)?);
+    }
+    return Ok(Bytes::from(bytes));
+}
+
+/// Arguments of a command given as a JSON array. Arguments can be strings, numbers,
+/// or arrays of bytes for the ones that aren't UTF-8, like Bulk Strings are rendered.
+fn parse_arguments(body: &[u8]) -> Result<Vec<Bytes>, HttpError> {
+    let items = match json::parse(body)? {
+        Json::Array(items) => items,
+        _ => return Err(HttpError::InvalidJson("expected an array of arguments")),
+    };
+    return items
+        .iter()
+        .map(|item| match item {
+            // numbers are passed as written, commands parse them themselves
+            Json::Number(number) => Some(Bytes::copy_from_slice(number.as_bytes())),
+            item => item.as_bytes(),
+        })
+        .map(|arg| {
+            arg.ok_or(HttpError::InvalidJson(
+                "arguments must be strings, numbers or arrays of bytes",
+            ))
+        })
+        .collect();
+}
+
+/// Writes `value` as JSON, in the representation of the `serde` feature.
+pub fn to_json(value: &DataType, out: &mut String) {
+    let tagged = |out: &mut String, type_: &str| {
+        write!(out, "{{\"type\":\"{type_}\"").unwrap();
+    };
+    let items = |out: &mut String, type_: &str, items: &[DataType]| {
+        tagged(out, type_);
+        out.push_str(",\"items\":[");
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            to_json(item, out);
+        }
+        out.push_str("]}");
+    };
+    match value {
+        DataType::SimpleString { string } => {
+            tagged(out, "simple_string");
+            out.push_str(",\"string\":");
+            json::write_string(string, out);
+            out.push('}');
+        }
+        DataType::Error { type_, error } => {
+            tagged(out, "error");
+            out.push_str(",\"prefix\":");
+            json::write_string(type_, out);
+            out.push_str(",\"error\":");
+            json::write_string(error, out);
+            out.push('}');
+        }
+        DataType::Integer { number } => {
+            tagged(out, "integer");
+            write!(out, ",\"number\":{number}}}").unwrap();
+        }
+        DataType::BulkString { string } => {
+            tagged(out, "bulk_string");
+            out.push_str(",\"string\":");
+            json::write_bytes(string, out);
+            out.push('}');
+        }
+        DataType::NullBulkString => {
+            tagged(out, "null_bulk_string");
+            out.push('}');
+        }
+        DataType::Array { items: array } => items(out, "array", array),
+        DataType::NullArray => {
+            tagged(out, "null_array");
+            out.push('}');
+        }
+        DataType::Map { items } => {
+            tagged(out, "map");
+            out.push_str(",\"items\":[");
+            for (i, (key, value)) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push('[');
+                to_json(key, out);
+                out.push(',');
+                to_json(value, out);
+                out.push(']');
+            }
+            out.push_str("]}");
+        }
+        DataType::Set { items: set } => items(out, "set", set),
+        DataType::Double { number } => {
+            tagged(out, "double");
+            // like serde_json, JSON has no infinities nor NaN
+            if number.is_finite() {
+                write!(out, ",\"number\":{number:?}}}").unwrap();
+            } else {
+                out.push_str(",\"number\":null}");
+            }
+        }
+        DataType::Boolean { value } => {
+            tagged(out, "boolean");
+            write!(out, ",\"value\":{value}}}").unwrap();
+        }
+        DataType::BigNumber { number } => {
+            tagged(out, "big_number");
+            out.push_str(",\"number\":");
+            json::write_string(number, out);
+            out.push('}');
+        }
+        DataType::Null => {
+            tagged(out, "null");
+            out.push('}');
+        }
+        DataType::VerbatimString { format, string } => {
+            tagged(out, "verbatim_string");
+            out.push_str(",\"format\":");
+            json::write_string(format, out);
+            out.push_str(",\"string\":");
+            json::write_string(string, out);
+            out.push('}');
+        }
+        DataType::Push { items: push } => items(out, "push", push),
+    }
+}
+
+/// Response with the reply to a command.
+pub fn reply_response(reply: &DataType, keep_alive: bool) -> String {
+    let mut body = String::new();
+    to_json(reply, &mut body);
+    return response("200 OK", "application/json", &body, keep_alive);
+}
+
+/// Response to a request that couldn't be served.
+pub fn error_response(err: &HttpError, keep_alive: bool) -> String {
+    return response(err.status(), "text/plain", &err.to_string(), keep_alive);
+}
+
+fn response(status: &str, content_type: &str, body: &str, keep_alive: bool) -> String {
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    return format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: {connection}\r\n\r\n\
+         {body}",
+        body.len()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{Bytes, BytesMut};
+
+    use super::{read_request, route, to_json, HttpError, Request};
+    use crate::protocol::DataType;
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        return Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: Bytes::copy_from_slice(body.as_bytes()),
+            keep_alive: true,
+        };
+    }
+
+    fn command(args: &[&[u8]]) -> DataType {
+        return DataType::Array {
+            items: args
+                .iter()
+                .map(|arg| DataType::bulk(Bytes::copy_from_slice(arg)))
+                .collect(),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let input = b"POST /command HTTP/1.1\r\nContent-Length: 7\r\n\r\n[\"GET\"]\
+                      GET /keys/a HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut reader = &input[..];
+        let mut buffer = BytesMut::new();
+        let first = read_request(&mut reader, &mut buffer, 1024).await.unwrap();
+        assert_eq!(first, Some(request("POST", "/command", "[\"GET\"]")));
+        let second = read_request(&mut reader, &mut buffer, 1024).await.unwrap();
+        let mut expected = request("GET", "/keys/a", "");
+        expected.keep_alive = false;
+        assert_eq!(second, Some(expected));
+        let end = read_request(&mut reader, &mut buffer, 1024).await.unwrap();
+        assert_eq!(end, None);
+
+        let input = b"POST /command HTTP/1.1\r\nContent-Length: 2048\r\n\r\n";
+        let err = read_request(&mut &input[..], &mut BytesMut::new(), 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&HttpError::TooBig(2048)));
+        let input = b"POST /command HTTP/1.1\r\nContent-Length: 10\r\n\r\n[";
+        let err = read_request(&mut &input[..], &mut BytesMut::new(), 1024).await;
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_route() {
+        let set = request(
+            "POST",
+            "/command",
+            r#" ["SET", "k\"\u00e9\ud83d\ude00", 10 ] "#,
+        );
+        assert_eq!(
+            route(&set),
+            Ok(command(&[b"SET", "k\"é😀".as_bytes(), b"10"]))
+        );
+        let binary = request("POST", "/command", r#"["GET", [255, 0], []]"#);
+        assert_eq!(route(&binary), Ok(command(&[b"GET", b"\xff\x00", b""])));
+        let get = request("GET", "/keys/a%20b%ff", "");
+        assert_eq!(route(&get), Ok(command(&[b"GET", b"a b\xff"])));
+
+        assert_eq!(
+            route(&request("GET", "/command", "")),
+            Err(HttpError::MethodNotAllowed)
+        );
+        assert_eq!(
+            route(&request("GET", "/nope", "")),
+            Err(HttpError::NotFound)
+        );
+        assert!(route(&request("GET", "/keys/%zz", "")).is_err());
+        for body in [
+            "",
+            "[]",
+            "{}",
+            "[\"GET\"",
+            "[\"GET\",]",
+            "[\"a\"] x",
+            "[true]",
+            "[[256]]",
+            "[\"\\x\"]",
+        ] {
+            assert!(route(&request("POST", "/command", body)).is_err(), "{body}");
+        }
+    }
+
+    #[test]
+    fn test_to_json() {
+        let value = DataType::Array {
+            items: vec![
+                DataType::ok(),
+                DataType::bulk("line\n\"quoted\"\x01"),
+                DataType::bulk(&b"\xff\x00"[..]),
+                DataType::error("ERR", "bad"),
+                DataType::Map {
+                    items: vec![(DataType::bulk("field"), DataType::from(1))],
+                },
+                DataType::Double { number: 1.5 },
+                DataType::Double {
+                    number: f64::INFINITY,
+                },
+                DataType::NullBulkString,
+            ],
+        };
+        let mut json = String::new();
+        to_json(&value, &mut json);
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"type":"array","items":["#,
+                r#"{"type":"simple_string","string":"OK"},"#,
+                r#"{"type":"bulk_string","string":"line\n\"quoted\"\u0001"},"#,
+                r#"{"type":"bulk_string","string":[255,0]},"#,
+                r#"{"type":"error","prefix":"ERR","error":"bad"},"#,
+                r#"{"type":"map","items":[[{"type":"bulk_string","string":"field"},{"type":"integer","number":1}]]},"#,
+                r#"{"type":"double","number":1.5},"#,
+                r#"{"type":"double","number":null},"#,
+                r#"{"type":"null_bulk_string"}]}"#,
+            )
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_to_json_matches_serde() {
+        let value = DataType::Array {
+            items: vec![
+                DataType::bulk("value"),
+                DataType::Set {
+                    items: vec![DataType::Boolean { value: true }, DataType::Null],
+                },
+                DataType::BigNumber {
+                    number: String::from("123"),
+                },
+                DataType::VerbatimString {
+                    format: String::from("txt"),
+                    string: String::from("text"),
+                },
+                DataType::Push { items: vec![] },
+                DataType::NullArray,
+            ],
+        };
+        let mut json = String::new();
+        to_json(&value, &mut json);
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, serde_json::to_value(&value).unwrap());
+    }
+}
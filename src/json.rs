@@ -0,0 +1,337 @@
+/// Minimal JSON reader and writer, for the HTTP gateway and the dataset exports.
+///
+/// serde_json is only available to the tests, so JSON is handled here. Numbers are
+/// kept as written, leaving their parsing to whoever reads them, and binary strings
+/// are written as arrays of bytes when they aren't valid UTF-8, like the `serde`
+/// feature of `DataType` does.
+use std::fmt::Write;
+
+use bytes::Bytes;
+use thiserror::Error;
+
+/// Deepest nesting of arrays and objects accepted.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("invalid JSON: {0}")]
+pub struct JsonError(pub &'static str);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// the number as written
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// fields in the order they were written
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// A string, or an array of bytes as binary strings are written.
+    pub fn as_bytes(&self) -> Option<Bytes> {
+        return match self {
+            Json::String(string) => Some(Bytes::copy_from_slice(string.as_bytes())),
+            Json::Array(items) => {
+                let bytes: Option<Vec<u8>> = items
+                    .iter()
+                    .map(|item| match item {
+                        Json::Number(n) => n.parse().ok(),
+                        _ => None,
+                    })
+                    .collect();
+                bytes.map(Bytes::from)
+            }
+            _ => None,
+        };
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        return match self {
+            Json::String(string) => Some(string),
+            _ => None,
+        };
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        return match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        };
+    }
+
+    /// Value of `field`, if this is an object with it.
+    pub fn get(&self, field: &str) -> Option<&Json> {
+        return match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == field)
+                .map(|(_, value)| value),
+            _ => None,
+        };
+    }
+}
+
+pub fn parse(input: &[u8]) -> Result<Json, JsonError> {
+    let mut parser = Parser {
+        input: input,
+        pos: 0,
+    };
+    let value = parser.value(0)?;
+    if parser.peek().is_some() {
+        return Err(JsonError("trailing characters"));
+    }
+    return Ok(value);
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    /// Next character that isn't whitespace, without consuming it.
+    fn peek(&mut self) -> Option<u8> {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.input.get(self.pos) {
+            self.pos += 1;
+        }
+        return self.input.get(self.pos).copied();
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        return Some(c);
+    }
+
+    fn literal(&mut self, literal: &[u8], value: Json) -> Result<Json, JsonError> {
+        if !self.input[self.pos..].starts_with(literal) {
+            return Err(JsonError("unexpected character"));
+        }
+        self.pos += literal.len();
+        return Ok(value);
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(JsonError("nested too deep"));
+        }
+        return match self.peek() {
+            Some(b'"') => {
+                self.pos += 1;
+                Ok(Json::String(self.string()?))
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while let Some(b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E') =
+                    self.input.get(self.pos)
+                {
+                    self.pos += 1;
+                }
+                let number = std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+                if number.parse::<f64>().is_err() {
+                    return Err(JsonError("invalid number"));
+                }
+                Ok(Json::Number(number.to_string()))
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    match self.next() {
+                        Some(b',') => continue,
+                        Some(b']') => return Ok(Json::Array(items)),
+                        _ => return Err(JsonError("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    if self.next() != Some(b'"') {
+                        return Err(JsonError("expected a field name"));
+                    }
+                    let name = self.string()?;
+                    if self.next() != Some(b':') {
+                        return Err(JsonError("expected ':'"));
+                    }
+                    fields.push((name, self.value(depth + 1)?));
+                    match self.next() {
+                        Some(b',') => continue,
+                        Some(b'}') => return Ok(Json::Object(fields)),
+                        _ => return Err(JsonError("expected ',' or '}'")),
+                    }
+                }
+            }
+            Some(b't') => self.literal(b"true", Json::Bool(true)),
+            Some(b'f') => self.literal(b"false", Json::Bool(false)),
+            Some(b'n') => self.literal(b"null", Json::Null),
+            Some(_) => Err(JsonError("unexpected character")),
+            None => Err(JsonError("unexpected end of input")),
+        };
+    }
+
+    /// Rest of a string whose opening quote was consumed.
+    fn string(&mut self) -> Result<String, JsonError> {
+        let unterminated = JsonError("unterminated string");
+        let mut string = Vec::new();
+        loop {
+            let c = *self.input.get(self.pos).ok_or(unterminated)?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.input.get(self.pos).ok_or(unterminated)?;
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\x08',
+                        b'f' => '\x0c',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return Err(JsonError("invalid escape")),
+                    };
+                    string.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                0..=0x1f => return Err(JsonError("control character in string")),
+                c => string.push(c),
+            }
+        }
+        return String::from_utf8(string).map_err(|_| JsonError("not UTF-8"));
+    }
+
+    /// Character of a `\uXXXX` escape whose `\u` was consumed, with the low half
+    /// of surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let invalid = JsonError("invalid unicode escape");
+        let high = self.hex4().ok_or(invalid)?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high).ok_or(invalid);
+        }
+        if self.input.get(self.pos..self.pos + 2) != Some(b"\\u") {
+            return Err(invalid);
+        }
+        self.pos += 2;
+        let low = self.hex4().ok_or(invalid)?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return Err(invalid);
+        }
+        return char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)).ok_or(invalid);
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let hex = std::str::from_utf8(self.input.get(self.pos..self.pos + 4)?).ok()?;
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        self.pos += 4;
+        return Some(value);
+    }
+}
+
+pub fn write_string(string: &str, out: &mut String) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\x08' => out.push_str("\\b"),
+            '\x0c' => out.push_str("\\f"),
+            '\0'..='\x1f' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writes `bytes` as a string if they are valid UTF-8, as an array of bytes otherwise.
+pub fn write_bytes(bytes: &[u8], out: &mut String) {
+    match std::str::from_utf8(bytes) {
+        Ok(string) => write_string(string, out),
+        Err(_) => {
+            out.push('[');
+            for (i, byte) in bytes.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write!(out, "{byte}").unwrap();
+            }
+            out.push(']');
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{parse, write_bytes, Json, JsonError};
+
+    #[test]
+    fn test_parse() {
+        let json = parse(
+            br#" {"a": [1, -2.5e3, "x\"\u00e9\ud83d\ude00"], "b": {}, "c": [true, false, null]} "#,
+        );
+        assert_eq!(
+            json,
+            Ok(Json::Object(vec![
+                (
+                    String::from("a"),
+                    Json::Array(vec![
+                        Json::Number(String::from("1")),
+                        Json::Number(String::from("-2.5e3")),
+                        Json::String(String::from("x\"é😀")),
+                    ])
+                ),
+                (String::from("b"), Json::Object(vec![])),
+                (
+                    String::from("c"),
+                    Json::Array(vec![Json::Bool(true), Json::Bool(false), Json::Null])
+                ),
+            ]))
+        );
+        for input in [
+            "",
+            "[",
+            "[1,]",
+            "{\"a\"}",
+            "[1] x",
+            "tru",
+            "\"\\x\"",
+            "\"\\ud83d\"",
+            "1-",
+            "[\"\n\"]",
+        ] {
+            assert!(parse(input.as_bytes()).is_err(), "{input}");
+        }
+        let deep = "[".repeat(1000);
+        assert_eq!(parse(deep.as_bytes()), Err(JsonError("nested too deep")));
+    }
+
+    #[test]
+    fn test_bytes() {
+        for bytes in [&b"text\n\"\x01"[..], b"\xff\x00", b""] {
+            let mut json = String::new();
+            write_bytes(bytes, &mut json);
+            let parsed = parse(json.as_bytes()).unwrap();
+            assert_eq!(parsed.as_bytes(), Some(Bytes::copy_from_slice(bytes)));
+        }
+        assert_eq!(parse(b"[256]").unwrap().as_bytes(), None);
+    }
+}
@@ -0,0 +1,299 @@
+/// Latency monitor, modeled after Redis' `latency.c`.
+///
+/// Code paths that may stall the server report how long they took through
+/// `LatencyMonitor::add_sample_if_needed`. Samples at or above the configured
+/// `latency-monitor-threshold` are stored per event class in a fixed size time
+/// series (one sample per second, keeping the worst latency seen in that second),
+/// which is what the LATENCY command family reports on.
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of samples kept for each event.
+pub const LATENCY_TS_LEN: usize = 160;
+
+/// Execution of a command, including the time spent waiting for the keyspace lock.
+pub const EVENT_COMMAND: &str = "command";
+/// Removal of expired keys.
+pub const EVENT_EXPIRE_CYCLE: &str = "expire-cycle";
+/// Snapshotting the dataset to disk.
+pub const EVENT_FORK: &str = "fork";
+
+fn unix_time() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencySample {
+    /// unix time in seconds
+    pub time: u64,
+    /// latency in milliseconds
+    pub latency: u64,
+}
+
+struct LatencyTimeSeries {
+    /// index of the next slot to write
+    idx: usize,
+    /// worst latency observed since the last reset
+    max: u64,
+    samples: [Option<LatencySample>; LATENCY_TS_LEN],
+}
+
+impl LatencyTimeSeries {
+    fn new() -> Self {
+        return LatencyTimeSeries {
+            idx: 0,
+            max: 0,
+            samples: [None; LATENCY_TS_LEN],
+        };
+    }
+
+    fn add(&mut self, time: u64, latency: u64) {
+        if latency > self.max {
+            self.max = latency;
+        }
+        // merge samples that fall in the same second, keeping the worst one
+        let prev = (self.idx + LATENCY_TS_LEN - 1) % LATENCY_TS_LEN;
+        if let Some(sample) = self.samples[prev].as_mut() {
+            if sample.time == time {
+                sample.latency = sample.latency.max(latency);
+                return;
+            }
+        }
+        self.samples[self.idx] = Some(LatencySample { time, latency });
+        self.idx = (self.idx + 1) % LATENCY_TS_LEN;
+    }
+
+    fn last(&self) -> Option<LatencySample> {
+        let prev = (self.idx + LATENCY_TS_LEN - 1) % LATENCY_TS_LEN;
+        return self.samples[prev];
+    }
+
+    /// samples ordered from oldest to newest
+    fn history(&self) -> Vec<LatencySample> {
+        return (0..LATENCY_TS_LEN)
+            .filter_map(|i| self.samples[(self.idx + i) % LATENCY_TS_LEN])
+            .collect();
+    }
+}
+
+/// Latest sample of an event, as reported by LATENCY LATEST.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LatencyLatest {
+    pub event: String,
+    pub time: u64,
+    pub latest: u64,
+    pub max: u64,
+}
+
+#[derive(Default)]
+pub struct LatencyMonitor {
+    events: Mutex<BTreeMap<String, LatencyTimeSeries>>,
+}
+
+impl LatencyMonitor {
+    pub fn new() -> Self {
+        return LatencyMonitor::default();
+    }
+
+    /// Records `latency` (milliseconds) for `event` when monitoring is enabled
+    /// (`threshold` > 0) and the latency reaches the threshold.
+    pub fn add_sample_if_needed(&self, event: &str, latency: u64, threshold: u64) {
+        if threshold > 0 && latency >= threshold {
+            self.add_sample(event, latency);
+        }
+    }
+
+    pub fn add_sample(&self, event: &str, latency: u64) {
+        self.add_sample_at(event, unix_time(), latency);
+    }
+
+    fn add_sample_at(&self, event: &str, time: u64, latency: u64) {
+        let mut events = self.events.lock().unwrap();
+        events
+            .entry(event.to_string())
+            .or_insert_with(LatencyTimeSeries::new)
+            .add(time, latency);
+    }
+
+    pub fn latest(&self) -> Vec<LatencyLatest> {
+        let events = self.events.lock().unwrap();
+        return events
+            .iter()
+            .filter_map(|(event, ts)| {
+                ts.last().map(|sample| LatencyLatest {
+                    event: event.clone(),
+                    time: sample.time,
+                    latest: sample.latency,
+                    max: ts.max,
+                })
+            })
+            .collect();
+    }
+
+    pub fn history(&self, event: &str) -> Vec<LatencySample> {
+        let events = self.events.lock().unwrap();
+        return match events.get(event) {
+            Some(ts) => ts.history(),
+            None => Vec::new(),
+        };
+    }
+
+    /// Removes the samples of the given events, or of every event if `events` is empty.
+    /// Returns the number of event time series removed.
+    pub fn reset(&self, events: &[String]) -> usize {
+        let mut all = self.events.lock().unwrap();
+        if events.is_empty() {
+            let count = all.len();
+            all.clear();
+            return count;
+        }
+        return events.iter().filter(|e| all.remove(*e).is_some()).count();
+    }
+
+    /// Builds the human readable report returned by LATENCY DOCTOR.
+    pub fn doctor(&self, threshold: u64) -> String {
+        if threshold == 0 {
+            return String::from(
+                "I'm sorry, Dave, I can't do that. Latency monitoring is disabled in this \
+                 instance. You may use \"CONFIG SET latency-monitor-threshold <milliseconds>.\" \
+                 in order to enable it.\n",
+            );
+        }
+        let events = self.events.lock().unwrap();
+        if events.is_empty() {
+            return String::from(
+                "Dave, no latency spike was observed during the lifetime of this instance, \
+                 not in the slightest bit. I honestly think you ought to sleep tonight.\n",
+            );
+        }
+
+        let mut report = String::from(
+            "Dave, I have observed latency spikes in this instance. \
+             You don't mind talking about it, do you Dave?\n\n",
+        );
+        let mut advise_command = false;
+        let mut advise_expire = false;
+        let mut advise_fork = false;
+        for (i, (event, ts)) in events.iter().enumerate() {
+            let samples = ts.history();
+            if samples.is_empty() {
+                continue;
+            }
+            let count = samples.len() as u64;
+            let avg = samples.iter().map(|s| s.latency).sum::<u64>() / count;
+            let mad = samples.iter().map(|s| s.latency.abs_diff(avg)).sum::<u64>() / count;
+            let period = if count > 1 {
+                (samples[samples.len() - 1].time - samples[0].time) / (count - 1)
+            } else {
+                0
+            };
+            report.push_str(&format!(
+                "{}. {}: {} latency spikes (average {}ms, mean deviation {}ms, period {} sec). Worst all time event {}ms.\n",
+                i + 1,
+                event,
+                count,
+                avg,
+                mad,
+                period,
+                ts.max,
+            ));
+            match event.as_str() {
+                EVENT_COMMAND => advise_command = true,
+                EVENT_EXPIRE_CYCLE => advise_expire = true,
+                EVENT_FORK => advise_fork = true,
+                _ => {}
+            }
+        }
+
+        report.push_str("\nI have a few advices for you:\n\n");
+        if advise_command {
+            report.push_str(
+                "- Every command is executed while holding the keyspace lock, so a slow command \
+                 stalls all the other clients. Check which commands you are running are too slow \
+                 to execute and avoid O(N) commands against big values.\n",
+            );
+        }
+        if advise_expire {
+            report.push_str(
+                "- Deleting expired keys is blocking the server. Avoid setting the same \
+                 expiration time on a large number of keys.\n",
+            );
+        }
+        if advise_fork {
+            report.push_str(
+                "- Persisting the dataset is blocking the server. Consider reducing the \
+                 snapshotting frequency.\n",
+            );
+        }
+        return report;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LatencyMonitor, LatencySample, LATENCY_TS_LEN};
+
+    #[test]
+    fn test_threshold() {
+        let monitor = LatencyMonitor::new();
+        monitor.add_sample_if_needed("command", 50, 0);
+        monitor.add_sample_if_needed("command", 50, 100);
+        assert!(monitor.latest().is_empty());
+        monitor.add_sample_if_needed("command", 100, 100);
+        assert_eq!(monitor.latest().len(), 1);
+    }
+
+    #[test]
+    fn test_same_second_keeps_worst() {
+        let monitor = LatencyMonitor::new();
+        monitor.add_sample_at("command", 10, 5);
+        monitor.add_sample_at("command", 10, 20);
+        monitor.add_sample_at("command", 10, 7);
+        monitor.add_sample_at("command", 11, 3);
+        assert_eq!(
+            monitor.history("command"),
+            vec![
+                LatencySample {
+                    time: 10,
+                    latency: 20
+                },
+                LatencySample {
+                    time: 11,
+                    latency: 3
+                },
+            ]
+        );
+        let latest = monitor.latest();
+        assert_eq!(latest[0].latest, 3);
+        assert_eq!(latest[0].max, 20);
+    }
+
+    #[test]
+    fn test_history_wraps() {
+        let monitor = LatencyMonitor::new();
+        for t in 0..(LATENCY_TS_LEN as u64 + 10) {
+            monitor.add_sample_at("command", t, t);
+        }
+        let history = monitor.history("command");
+        assert_eq!(history.len(), LATENCY_TS_LEN);
+        assert_eq!(history[0].time, 10);
+        assert_eq!(history[LATENCY_TS_LEN - 1].time, LATENCY_TS_LEN as u64 + 9);
+    }
+
+    #[test]
+    fn test_reset() {
+        let monitor = LatencyMonitor::new();
+        monitor.add_sample_at("command", 1, 1);
+        monitor.add_sample_at("fork", 1, 1);
+        assert_eq!(
+            monitor.reset(&[String::from("fork"), String::from("nope")]),
+            1
+        );
+        assert_eq!(monitor.reset(&[]), 1);
+        assert!(monitor.latest().is_empty());
+    }
+}
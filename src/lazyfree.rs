@@ -0,0 +1,193 @@
+/// Lazy freeing of big values.
+///
+/// Dropping a value with many elements takes as long as freeing each one of them,
+/// which stalls every command waiting on the shard when done while holding its lock.
+/// Values removed from the keyspace can instead be handed to `LazyFree`, which frees
+/// the big ones on a background thread. The `lazyfree-lazy-*` parameters select the
+/// ways of removing keys that free values lazily.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use bytes::Bytes;
+
+use crate::db::DBValue;
+use crate::value::{Hash, List, Set, Str, Value, ZSet};
+
+/// Values costing more than this to free are freed in the background.
+pub const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Strings cost one unit to free for each chunk of this many bytes.
+const STRING_FREE_CHUNK: usize = 16 * 1024;
+
+/// Approximate cost of freeing a value, the number of allocations it owns.
+pub fn free_effort(value: &Value) -> usize {
+    return match value {
+        Value::Str(Str::Raw(string)) => 1 + string.len() / STRING_FREE_CHUNK,
+        Value::Str(Str::Compressed { data, .. }) => 1 + data.len() / STRING_FREE_CHUNK,
+        Value::Str(Str::Int(_)) => 1,
+        // listpacks are a single allocation
+        Value::List(List::Listpack(_))
+        | Value::Hash(Hash::Listpack(_))
+        | Value::Set(Set::Listpack(_))
+        | Value::ZSet(ZSet::Listpack(_)) => 1,
+        _ => value.len(),
+    };
+}
+
+#[derive(Default)]
+struct Counters {
+    /// values waiting to be freed
+    pending: AtomicUsize,
+    /// values freed in the background so far
+    freed: AtomicUsize,
+}
+
+/// Values handed to the background thread.
+// the values are only held to be dropped there
+#[allow(dead_code)]
+enum Garbage {
+    Value(DBValue),
+    /// every entry of a flushed database
+    Entries(Vec<(Bytes, DBValue)>),
+}
+
+impl Garbage {
+    fn len(&self) -> usize {
+        return match self {
+            Garbage::Value(_) => 1,
+            Garbage::Entries(entries) => entries.len(),
+        };
+    }
+}
+
+pub struct LazyFree {
+    values: mpsc::Sender<Garbage>,
+    counters: Arc<Counters>,
+}
+
+impl LazyFree {
+    /// Starts the thread freeing the values, which stops once `LazyFree` is dropped.
+    pub fn new() -> Self {
+        let (values, queue) = mpsc::channel::<Garbage>();
+        let counters = Arc::new(Counters::default());
+        let background = counters.clone();
+        thread::Builder::new()
+            .name(String::from("lazyfree"))
+            .spawn(move || {
+                for garbage in queue {
+                    let len = garbage.len();
+                    drop(garbage);
+                    background.pending.fetch_sub(len, Ordering::Relaxed);
+                    background.freed.fetch_add(len, Ordering::Relaxed);
+                }
+            })
+            .expect("failed to start the lazyfree thread");
+        return LazyFree {
+            values: values,
+            counters: counters,
+        };
+    }
+
+    /// Frees a value removed from the keyspace, in the background if `lazy` and it's
+    /// costly to free. Call it after releasing the lock of the shard it was in.
+    pub fn free(&self, value: DBValue, lazy: bool) {
+        if !lazy || free_effort(&value.value) <= LAZYFREE_THRESHOLD {
+            return;
+        }
+        self.send(Garbage::Value(value));
+    }
+
+    /// Frees the entries of a flushed database, in the background if `lazy`.
+    pub fn free_entries(&self, entries: Vec<(Bytes, DBValue)>, lazy: bool) {
+        if !lazy || entries.is_empty() {
+            return;
+        }
+        self.send(Garbage::Entries(entries));
+    }
+
+    fn send(&self, garbage: Garbage) {
+        let len = garbage.len();
+        self.counters.pending.fetch_add(len, Ordering::Relaxed);
+        if let Err(mpsc::SendError(garbage)) = self.values.send(garbage) {
+            self.counters.pending.fetch_sub(len, Ordering::Relaxed);
+            drop(garbage);
+        }
+    }
+
+    /// Number of values waiting to be freed.
+    pub fn pending(&self) -> usize {
+        return self.counters.pending.load(Ordering::Relaxed);
+    }
+
+    /// Number of values freed in the background.
+    pub fn freed(&self) -> usize {
+        return self.counters.freed.load(Ordering::Relaxed);
+    }
+}
+
+impl Default for LazyFree {
+    fn default() -> Self {
+        return LazyFree::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+
+    use super::{free_effort, LazyFree, LAZYFREE_THRESHOLD};
+    use crate::db::DBValue;
+    use crate::value::{List, Value};
+
+    fn list(len: usize) -> Value {
+        let items: VecDeque<Bytes> = (0..len).map(|i| Bytes::from(i.to_string())).collect();
+        return Value::List(List::Quicklist(items));
+    }
+
+    #[test]
+    fn test_free_effort() {
+        assert_eq!(free_effort(&Value::from(Bytes::from("small"))), 1);
+        let big = Value::from(Bytes::from(vec![b'x'; 4 * 1024 * 1024]));
+        assert!(free_effort(&big) > LAZYFREE_THRESHOLD);
+        assert_eq!(free_effort(&list(100)), 100);
+        assert_eq!(free_effort(&Value::List(List::new())), 1);
+    }
+
+    #[test]
+    fn test_free() {
+        let lazyfree = LazyFree::new();
+        lazyfree.free(DBValue::with_expiration(list(10), 0, 0), true);
+        lazyfree.free(DBValue::with_expiration(list(1000), 0, 0), false);
+        assert_eq!(lazyfree.freed(), 0);
+        lazyfree.free(DBValue::with_expiration(list(1000), 0, 0), true);
+        let start = Instant::now();
+        while lazyfree.freed() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5), "value not freed");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(lazyfree.freed(), 1);
+        assert_eq!(lazyfree.pending(), 0);
+
+        let entries = (0..10)
+            .map(|i| {
+                (
+                    Bytes::from(i.to_string()),
+                    DBValue::with_expiration(list(1), 0, 0),
+                )
+            })
+            .collect();
+        lazyfree.free_entries(entries, true);
+        while lazyfree.freed() < 11 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "entries not freed"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(lazyfree.pending(), 0);
+    }
+}
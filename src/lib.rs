@@ -0,0 +1,51 @@
+#![allow(
+    clippy::needless_return,
+    clippy::redundant_field_names,
+    clippy::upper_case_acronyms
+)]
+// the state is guarded by std locks, which must never be held across an await
+#![deny(clippy::await_holding_lock)]
+
+mod blocked;
+pub mod check;
+pub mod client;
+mod clients;
+mod clock;
+mod codec;
+mod command_table;
+pub mod commands;
+pub mod config;
+pub mod db;
+pub mod decoders;
+mod dump;
+mod engine;
+mod errors;
+mod evict;
+mod export;
+mod glob;
+mod http;
+mod json;
+mod latency;
+mod lazyfree;
+mod listpack;
+mod lzf;
+#[cfg(feature = "memcached")]
+mod memcached;
+mod output;
+pub mod process;
+pub mod protocol;
+mod pubsub;
+mod server;
+mod shutdown;
+mod state;
+mod stats;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod tracking;
+mod uring;
+mod value;
+mod websocket;
+
+pub use server::{Server, ShutdownGuard, DEFAULT_DECODER_VERSION};
+pub use shutdown::ShutdownHandle;
+pub use state::{State, StateInner};
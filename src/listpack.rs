@@ -0,0 +1,278 @@
+/// Compact storage for small collections.
+///
+/// A listpack keeps its elements back to back in a single buffer, each one prefixed
+/// by its length as a varint, instead of allocating every element on its own and
+/// indexing them with a hash table. Lookups and updates scan the buffer, so they are
+/// only cheap while the collection is small: collections are stored as listpacks
+/// until they grow past the `*-max-listpack-*` limits of the configuration, and are
+/// then converted to their full representation.
+use std::ops::Range;
+
+/// Limits past which a collection stops being stored as a listpack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListpackLimits {
+    /// max number of entries (elements, field-value pairs or members)
+    pub entries: usize,
+    /// max length of each element
+    pub value: usize,
+    /// max bytes used by the whole listpack
+    pub bytes: usize,
+}
+
+impl ListpackLimits {
+    pub const UNLIMITED: ListpackLimits = ListpackLimits {
+        entries: usize::MAX,
+        value: usize::MAX,
+        bytes: usize::MAX,
+    };
+
+    /// Whether a listpack holding `entries` entries in `bytes` bytes, the longest
+    /// of them `value` long, is within the limits.
+    pub fn allow(&self, entries: usize, value: usize, bytes: usize) -> bool {
+        return entries <= self.entries && value <= self.value && bytes <= self.bytes;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Listpack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+#[allow(dead_code)]
+impl Listpack {
+    pub fn new() -> Self {
+        return Listpack::default();
+    }
+
+    /// Number of elements.
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    /// Bytes used by the encoded elements.
+    pub fn bytes(&self) -> usize {
+        return self.buf.len();
+    }
+
+    /// Heap bytes allocated by the listpack.
+    pub fn memory_usage(&self) -> usize {
+        return self.buf.capacity();
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        return Iter {
+            buf: &self.buf,
+            pos: 0,
+        };
+    }
+
+    pub fn get(&self, ix: usize) -> Option<&[u8]> {
+        return self.iter().nth(ix);
+    }
+
+    /// Position of the first element equal to `element`.
+    pub fn position(&self, element: &[u8]) -> Option<usize> {
+        return self.iter().position(|e| e == element);
+    }
+
+    pub fn push_back(&mut self, element: &[u8]) {
+        encode(&mut self.buf, element);
+        self.len += 1;
+    }
+
+    pub fn push_front(&mut self, element: &[u8]) {
+        self.insert(0, element);
+    }
+
+    /// Inserts `element` before the element at `ix`, or at the end if `ix` is `len`.
+    ///
+    /// Panics if `ix` is greater than `len`.
+    pub fn insert(&mut self, ix: usize, element: &[u8]) {
+        assert!(ix <= self.len, "listpack index out of bounds");
+        let offset = self.offset(ix);
+        let mut encoded = Vec::with_capacity(element.len() + 2);
+        encode(&mut encoded, element);
+        self.buf.splice(offset..offset, encoded);
+        self.len += 1;
+    }
+
+    /// Replaces the element at `ix`.
+    ///
+    /// Panics if `ix` is out of bounds.
+    pub fn replace(&mut self, ix: usize, element: &[u8]) {
+        let range = self.range(ix).expect("listpack index out of bounds");
+        let mut encoded = Vec::with_capacity(element.len() + 2);
+        encode(&mut encoded, element);
+        self.buf.splice(range, encoded);
+    }
+
+    /// Removes the element at `ix`, returning it.
+    pub fn remove(&mut self, ix: usize) -> Option<Vec<u8>> {
+        let range = self.range(ix)?;
+        let element = {
+            let (len, header) = decode_len(&self.buf[range.start..]);
+            let start = range.start + header;
+            self.buf[start..start + len].to_vec()
+        };
+        self.buf.drain(range);
+        self.len -= 1;
+        return Some(element);
+    }
+
+    /// Removes `count` elements starting at `ix`.
+    pub fn remove_range(&mut self, ix: usize, count: usize) {
+        let count = count.min(self.len.saturating_sub(ix));
+        if count == 0 {
+            return;
+        }
+        let start = self.offset(ix);
+        let end = self.offset(ix + count);
+        self.buf.drain(start..end);
+        self.len -= count;
+    }
+
+    /// Offset of the element at `ix`, the end of the buffer if `ix` is `len`.
+    fn offset(&self, ix: usize) -> usize {
+        let mut pos = 0;
+        for _ in 0..ix {
+            let (len, header) = decode_len(&self.buf[pos..]);
+            pos += header + len;
+        }
+        return pos;
+    }
+
+    /// Range of the buffer holding the element at `ix`, length included.
+    fn range(&self, ix: usize) -> Option<Range<usize>> {
+        if ix >= self.len {
+            return None;
+        }
+        let start = self.offset(ix);
+        let (len, header) = decode_len(&self.buf[start..]);
+        return Some(start..start + header + len);
+    }
+}
+
+impl<'a> FromIterator<&'a [u8]> for Listpack {
+    fn from_iter<I: IntoIterator<Item = &'a [u8]>>(iter: I) -> Self {
+        let mut listpack = Listpack::new();
+        for element in iter {
+            listpack.push_back(element);
+        }
+        return listpack;
+    }
+}
+
+pub struct Iter<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+        let (len, header) = decode_len(&self.buf[self.pos..]);
+        let start = self.pos + header;
+        self.pos = start + len;
+        return Some(&self.buf[start..start + len]);
+    }
+}
+
+/// Appends `element` to `buf`, prefixed by its length as a varint.
+fn encode(buf: &mut Vec<u8>, element: &[u8]) {
+    let mut len = element.len();
+    while len >= 0x80 {
+        buf.push((len as u8 & 0x7f) | 0x80);
+        len >>= 7;
+    }
+    buf.push(len as u8);
+    buf.extend_from_slice(element);
+}
+
+/// Decodes the length prefixing the element at the start of `buf`, returning it
+/// along with the size of the prefix.
+fn decode_len(buf: &[u8]) -> (usize, usize) {
+    let mut len = 0;
+    let mut shift = 0;
+    for (i, byte) in buf.iter().enumerate() {
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return (len, i + 1);
+        }
+        shift += 7;
+    }
+    panic!("truncated listpack entry");
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Listpack, ListpackLimits};
+
+    fn elements(listpack: &Listpack) -> Vec<Vec<u8>> {
+        return listpack.iter().map(|e| e.to_vec()).collect();
+    }
+
+    #[test]
+    fn test_push_and_iter() {
+        let mut listpack = Listpack::new();
+        listpack.push_back(b"b");
+        listpack.push_front(b"a");
+        listpack.push_back(b"");
+        let long = vec![b'x'; 300];
+        listpack.push_back(&long);
+        assert_eq!(listpack.len(), 4);
+        assert_eq!(
+            elements(&listpack),
+            vec![b"a".to_vec(), b"b".to_vec(), b"".to_vec(), long.clone()]
+        );
+        // 1 byte lengths except for the long element
+        assert_eq!(listpack.bytes(), 2 + 2 + 1 + 2 + 300);
+        assert_eq!(listpack.get(3), Some(&long[..]));
+        assert_eq!(listpack.get(4), None);
+        assert_eq!(listpack.position(b"b"), Some(1));
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut listpack: Listpack = [&b"a"[..], b"b", b"c", b"d"].into_iter().collect();
+        listpack.insert(2, b"x");
+        listpack.replace(0, b"first");
+        assert_eq!(listpack.remove(1), Some(b"b".to_vec()));
+        assert_eq!(listpack.remove(10), None);
+        assert_eq!(
+            elements(&listpack),
+            vec![
+                b"first".to_vec(),
+                b"x".to_vec(),
+                b"c".to_vec(),
+                b"d".to_vec()
+            ]
+        );
+        listpack.remove_range(1, 2);
+        assert_eq!(elements(&listpack), vec![b"first".to_vec(), b"d".to_vec()]);
+        listpack.remove_range(1, 10);
+        assert_eq!(listpack.len(), 1);
+        assert_eq!(listpack.bytes(), 6);
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = ListpackLimits {
+            entries: 2,
+            value: 4,
+            bytes: usize::MAX,
+        };
+        assert!(limits.allow(2, 4, 100));
+        assert!(!limits.allow(3, 1, 10));
+        assert!(!limits.allow(1, 5, 10));
+        assert!(ListpackLimits::UNLIMITED.allow(usize::MAX, 1, 1));
+    }
+}
@@ -0,0 +1,157 @@
+// LZF compressed data is a sequence of chunks starting with a control byte:
+// - `000LLLLL`: L + 1 literal bytes follow.
+// - `LLLOOOOO OOOOOOOO`: copy L + 2 bytes starting O + 1 bytes before the end of
+//   the output. If L is 7 a byte follows the control byte with the rest of the
+//   length.
+
+/// Max number of literals in a single chunk.
+const MAX_LITERALS: usize = 32;
+
+/// Max distance of a back reference.
+const MAX_OFFSET: usize = 1 << 13;
+
+/// Max length of a back reference.
+const MAX_REF: usize = 2 + 7 + 255;
+
+const HASH_LOG: u32 = 14;
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    return (v.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize;
+}
+
+/// Compresses `input` in the format of liblzf, which Redis uses to compress strings
+/// in RDB files. It compresses worse than lz4 or zstd but is fast and small enough
+/// to live in the tree. Returns `None` if `input` can't be made smaller.
+pub fn compress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len());
+    // position + 1 of the last sequence with each hash, 0 if none yet
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut literals = 0;
+    let mut ip = 0;
+    while ip + 2 < input.len() {
+        let h = hash(&input[ip..]);
+        let candidate = table[h];
+        table[h] = ip + 1;
+        if candidate == 0 || ip - candidate >= MAX_OFFSET {
+            ip += 1;
+            continue;
+        }
+        let reference = candidate - 1;
+        let max = (input.len() - ip).min(MAX_REF);
+        let mut len = 0;
+        while len < max && input[reference + len] == input[ip + len] {
+            len += 1;
+        }
+        if len < 3 {
+            ip += 1;
+            continue;
+        }
+        push_literals(&mut out, &input[literals..ip]);
+        let offset = ip - reference - 1;
+        if len - 2 < 7 {
+            out.push(((len - 2) << 5 | offset >> 8) as u8);
+        } else {
+            out.push((7 << 5 | offset >> 8) as u8);
+            out.push((len - 2 - 7) as u8);
+        }
+        out.push(offset as u8);
+        ip += len;
+        literals = ip;
+        if out.len() >= input.len() {
+            return None;
+        }
+    }
+    push_literals(&mut out, &input[literals..]);
+    if out.len() >= input.len() {
+        return None;
+    }
+    return Some(out);
+}
+
+fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Decompresses `input` into `len` bytes, `None` if it isn't valid compressed data.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < MAX_LITERALS {
+            let literals = input.get(ip..ip + ctrl + 1)?;
+            out.extend_from_slice(literals);
+            ip += ctrl + 1;
+            continue;
+        }
+        let mut ref_len = ctrl >> 5;
+        if ref_len == 7 {
+            ref_len += *input.get(ip)? as usize;
+            ip += 1;
+        }
+        let offset = (ctrl & 0x1f) << 8 | *input.get(ip)? as usize;
+        ip += 1;
+        let start = out.len().checked_sub(offset + 1)?;
+        // the reference can overlap the bytes being copied
+        for i in start..start + ref_len + 2 {
+            out.push(out[i]);
+        }
+    }
+    if out.len() != len {
+        return None;
+    }
+    return Some(out);
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use super::{compress, decompress};
+
+    #[test]
+    fn test_compress() {
+        let input = "{\"name\": \"redis\", \"tags\": [\"a\", \"b\"]}, ".repeat(100);
+        let compressed = compress(input.as_bytes()).unwrap();
+        assert!(compressed.len() < input.len() / 10);
+        assert_eq!(
+            decompress(&compressed, input.len()).unwrap(),
+            input.as_bytes()
+        );
+        // runs are copied from overlapping references
+        let run = vec![b'x'; 1000];
+        let compressed = compress(&run).unwrap();
+        assert_eq!(decompress(&compressed, run.len()).unwrap(), run);
+    }
+
+    #[test]
+    fn test_incompressible() {
+        assert_eq!(compress(b""), None);
+        assert_eq!(compress(b"abcdefgh"), None);
+    }
+
+    #[test]
+    fn test_decompress_invalid() {
+        // reference before the start of the output
+        assert_eq!(decompress(&[0x20, 0x05], 3), None);
+        // truncated literals
+        assert_eq!(decompress(&[0x04, b'a'], 5), None);
+        // wrong length
+        assert_eq!(decompress(&[0x00, b'a'], 2), None);
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(input in proptest::collection::vec(0..4u8, 0..2000)) {
+            if let Some(compressed) = compress(&input) {
+                prop_assert!(compressed.len() < input.len());
+                prop_assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+            }
+        }
+    }
+}
@@ -0,0 +1,527 @@
+/// Memcached text protocol, so memcached clients can be pointed at the server while
+/// migrating away from memcached.
+///
+/// `get`, `set`, `delete`, `incr` and `decr` work on database 0, the one RESP clients
+/// start with: values are strings, and the flags memcached clients store along with them
+/// are kept with the value (0 for values written by other commands). Expiration times
+/// are read like memcached does: seconds from now up to 30 days, unix timestamps
+/// beyond that, and negative ones expire the value right away. CAS isn't supported.
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use memchr::memchr;
+use thiserror::Error;
+
+use crate::commands::ExpiredKeys;
+use crate::db::{DBValue, MapInner};
+use crate::evict;
+use crate::state::State;
+use crate::tracking;
+use crate::value::{Str, Value};
+
+/// Longest command line accepted, values aside.
+const MAX_LINE_LEN: usize = 2048;
+
+/// Longest key accepted by memcached.
+const MAX_KEY_LEN: usize = 250;
+
+/// Expiration times beyond this many seconds are unix timestamps.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MemcachedError {
+    #[error("ERROR")]
+    UnknownCommand,
+
+    #[error("CLIENT_ERROR {0}")]
+    Client(&'static str),
+
+    #[error("SERVER_ERROR {0}")]
+    Server(&'static str),
+}
+
+impl MemcachedError {
+    /// Errors after which the rest of the input can't be parsed, the connection
+    /// is closed after replying them.
+    pub fn is_fatal(&self) -> bool {
+        return matches!(self, MemcachedError::Server(_));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Get {
+        keys: Vec<String>,
+    },
+    Set {
+        key: String,
+        flags: u32,
+        exptime: i64,
+        data: Bytes,
+        noreply: bool,
+    },
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    Incr {
+        key: String,
+        by: u64,
+        decr: bool,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+}
+
+impl Request {
+    fn noreply(&self) -> bool {
+        return match self {
+            Request::Set { noreply, .. }
+            | Request::Delete { noreply, .. }
+            | Request::Incr { noreply, .. } => *noreply,
+            _ => false,
+        };
+    }
+}
+
+fn parse_key(key: &str) -> Result<String, MemcachedError> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(MemcachedError::Client("key too long"));
+    }
+    return Ok(key.to_string());
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str) -> Result<T, MemcachedError> {
+    return arg
+        .parse()
+        .map_err(|_| MemcachedError::Client("bad command line format"));
+}
+
+/// Parses the next request in `buffer`, consuming it. Returns `None` if the request
+/// isn't complete yet. Requests that can't be parsed are consumed too, unless the
+/// error is fatal.
+pub fn parse_request(
+    buffer: &mut BytesMut,
+    max_value_len: usize,
+) -> Result<Option<Request>, MemcachedError> {
+    let line_end = match memchr(b'\n', buffer) {
+        Some(pos) => pos,
+        None if buffer.len() > MAX_LINE_LEN => {
+            return Err(MemcachedError::Server("line too long"));
+        }
+        None => return Ok(None),
+    };
+    let line = match std::str::from_utf8(&buffer[..line_end]) {
+        Ok(line) => line.strip_suffix('\r').unwrap_or(line),
+        Err(_) => {
+            buffer.advance(line_end + 1);
+            return Err(MemcachedError::Client("bad command line format"));
+        }
+    };
+    let args: Vec<&str> = line.split_ascii_whitespace().collect();
+    let noreply = args.last() == Some(&"noreply");
+    let bad_format = MemcachedError::Client("bad command line format");
+    let request = match args.as_slice() {
+        ["get", keys @ ..] if !keys.is_empty() => {
+            let keys: Result<Vec<String>, MemcachedError> =
+                keys.iter().map(|key| parse_key(key)).collect();
+            keys.map(|keys| Request::Get { keys: keys })
+        }
+        ["set", key, flags, exptime, len, rest @ ..] if rest.len() <= noreply as usize => {
+            let len: usize = match parse_number(len) {
+                Ok(len) => len,
+                Err(err) => {
+                    buffer.advance(line_end + 1);
+                    return Err(err);
+                }
+            };
+            if len > max_value_len {
+                // the value can't be skipped without buffering it
+                return Err(MemcachedError::Server("object too large for cache"));
+            }
+            let data_start = line_end + 1;
+            if buffer.len() < data_start + len + 2 {
+                return Ok(None);
+            }
+            let header = (parse_key(key), parse_number(flags), parse_number(exptime));
+            let mut message = buffer.split_to(data_start + len + 2);
+            if &message[data_start + len..] != b"\r\n" {
+                return Err(MemcachedError::Client("bad data chunk"));
+            }
+            message.truncate(data_start + len);
+            let data = message.split_off(data_start).freeze();
+            return match header {
+                (Ok(key), Ok(flags), Ok(exptime)) => Ok(Some(Request::Set {
+                    key: key,
+                    flags: flags,
+                    exptime: exptime,
+                    data: data,
+                    noreply: noreply,
+                })),
+                (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => Err(err),
+            };
+        }
+        ["delete", key, rest @ ..] if rest.len() <= noreply as usize => {
+            parse_key(key).map(|key| Request::Delete {
+                key: key,
+                noreply: noreply,
+            })
+        }
+        [command @ ("incr" | "decr"), key, by, rest @ ..] if rest.len() <= noreply as usize => {
+            match (parse_key(key), parse_number(by)) {
+                (Ok(key), Ok(by)) => Ok(Request::Incr {
+                    key: key,
+                    by: by,
+                    decr: *command == "decr",
+                    noreply: noreply,
+                }),
+                (Err(err), _) | (_, Err(err)) => Err(err),
+            }
+        }
+        ["version"] => Ok(Request::Version),
+        ["quit"] => Ok(Request::Quit),
+        ["get" | "set" | "delete" | "incr" | "decr" | "version" | "quit", ..] => Err(bad_format),
+        _ => Err(MemcachedError::UnknownCommand),
+    };
+    buffer.advance(line_end + 1);
+    return request.map(Some);
+}
+
+/// Executes `request`, writing its reply to `out` unless the client asked for none.
+pub fn execute(request: Request, state: &State, out: &mut BytesMut) {
+    let noreply = request.noreply();
+    let reply = match request {
+        Request::Get { keys } => {
+            for key in keys {
+                if let Some((flags, data)) = get(state, key.as_bytes()) {
+                    out.put_slice(format!("VALUE {key} {flags} {}\r\n", data.len()).as_bytes());
+                    out.put_slice(&data);
+                    out.put_slice(b"\r\n");
+                }
+            }
+            Ok(String::from("END"))
+        }
+        Request::Set {
+            key,
+            flags,
+            exptime,
+            data,
+            ..
+        } => set(state, key, flags, exptime, data).map(|()| String::from("STORED")),
+        Request::Delete { key, .. } => match delete(state, key.as_bytes()) {
+            true => Ok(String::from("DELETED")),
+            false => Ok(String::from("NOT_FOUND")),
+        },
+        Request::Incr { key, by, decr, .. } => match incr(state, key.as_bytes(), by, decr) {
+            Ok(Some(n)) => Ok(n.to_string()),
+            Ok(None) => Ok(String::from("NOT_FOUND")),
+            Err(err) => Err(err),
+        },
+        Request::Version => Ok(format!("VERSION {}", env!("CARGO_PKG_VERSION"))),
+        Request::Quit => return,
+    };
+    if noreply {
+        return;
+    }
+    match reply {
+        Ok(reply) => out.put_slice(reply.as_bytes()),
+        Err(err) => out.put_slice(err.to_string().as_bytes()),
+    }
+    out.put_slice(b"\r\n");
+}
+
+/// Flags and value of `key`, `None` if it doesn't exist or isn't a string.
+fn get(state: &State, key: &[u8]) -> Option<(u32, Bytes)> {
+    let mut expired = ExpiredKeys::default();
+    let found = {
+        let config = state.config.read().unwrap();
+        let mut map = state.databases[0].lock(key);
+        expired.remove_if_expired(&mut map, key);
+        map.lookup(key, &config)
+            .and_then(|v| Some((v.flags, v.value.as_str().ok()?.to_bytes())))
+    };
+    expired.release(state);
+    state.stats.record_keyspace_lookup(found.is_some());
+    return found;
+}
+
+fn set(
+    state: &State,
+    key: String,
+    flags: u32,
+    exptime: i64,
+    data: Bytes,
+) -> Result<(), MemcachedError> {
+    let (lazy, threshold) = {
+        let config = state.config.read().unwrap();
+        let mut evicted = Vec::new();
+        let res =
+            evict::perform_evictions(&state.databases, &state.lazyfree, &config, &mut evicted);
+        state.stats.record_evicted(evicted.len());
+        tracking::invalidate_keys(state, &evicted, None);
+        if res.is_err() {
+            return Err(MemcachedError::Server("out of memory storing object"));
+        }
+        (
+            config.lazyfree_lazy_server_del,
+            config.string_compression_threshold,
+        )
+    };
+    let string = Str::from(data).compress(threshold);
+    let now = state.databases[0].now();
+    let expiration = match exptime {
+        0 => Some(0),
+        1..=MAX_RELATIVE_EXPTIME => Some(exptime as usize * 1000),
+        _ => exptime
+            .checked_mul(1000)
+            .ok_or(MemcachedError::Client("bad command line format"))?
+            .checked_sub(now as i64)
+            .filter(|ms| *ms > 0)
+            .map(|ms| ms as usize),
+    };
+    let key = Bytes::from(key);
+    let old_value = {
+        let mut map = state.databases[0].lock(&key);
+        match expiration {
+            Some(expiration) => {
+                let value = DBValue {
+                    flags: flags,
+                    ..DBValue::with_expiration(Value::Str(string), expiration, now)
+                };
+                map.insert(key.clone(), value)
+            }
+            // storing an expired value deletes the key
+            None => map.remove(&key),
+        }
+    };
+    if let Some(old_value) = old_value {
+        state.lazyfree.free(old_value, lazy);
+    }
+    tracking::invalidate_keys(state, &[key], None);
+    return Ok(());
+}
+
+fn delete(state: &State, key: &[u8]) -> bool {
+    let mut expired = ExpiredKeys::default();
+    let removed = {
+        let mut map = state.databases[0].lock(key);
+        expired.remove_if_expired(&mut map, key);
+        map.remove(key)
+    };
+    expired.release(state);
+    let removed = match removed {
+        Some(removed) => removed,
+        None => return false,
+    };
+    let lazy = state.config.read().unwrap().lazyfree_lazy_user_del;
+    state.lazyfree.free(removed, lazy);
+    tracking::invalidate_keys(state, &[Bytes::copy_from_slice(key)], None);
+    return true;
+}
+
+/// Adds `by` to the unsigned counter in `key`, wrapping around like memcached, or
+/// subtracts it without going below 0. `None` if the key doesn't exist.
+fn incr(state: &State, key: &[u8], by: u64, decr: bool) -> Result<Option<u64>, MemcachedError> {
+    let mut expired = ExpiredKeys::default();
+    let res = {
+        let mut map = state.databases[0].lock(key);
+        expired.remove_if_expired(&mut map, key);
+        incr_locked(&mut map, key, by, decr)
+    };
+    expired.release(state);
+    if let Ok(Some(_)) = res {
+        tracking::invalidate_keys(state, &[Bytes::copy_from_slice(key)], None);
+    }
+    return res;
+}
+
+/// `incr` with the shard of `key` already locked.
+fn incr_locked(
+    map: &mut MapInner,
+    key: &[u8],
+    by: u64,
+    decr: bool,
+) -> Result<Option<u64>, MemcachedError> {
+    let non_numeric = MemcachedError::Client("cannot increment or decrement non-numeric value");
+    let current = match map.get(key) {
+        Some(value) => value.value.as_str().map(Str::to_bytes),
+        None => return Ok(None),
+    };
+    let current: u64 = match current.map(|string| std::str::from_utf8(&string).map(str::parse)) {
+        Ok(Ok(Ok(n))) => n,
+        _ => return Err(non_numeric),
+    };
+    let n = match decr {
+        true => current.saturating_sub(by),
+        false => current.wrapping_add(by),
+    };
+    map.update(key, |value| {
+        value.value = Value::Str(Str::from(Bytes::from(n.to_string())));
+    });
+    return Ok(Some(n));
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{Bytes, BytesMut};
+
+    use super::{execute, parse_request, MemcachedError, Request};
+    use crate::clients::TrackingOptions;
+    use crate::clock::MockClock;
+    use crate::config::Config;
+    use crate::protocol::DataType;
+    use crate::state::{State, StateInner};
+    use crate::tracking;
+
+    fn run(state: &State, input: &str) -> String {
+        let mut buffer = BytesMut::from(input);
+        let mut out = BytesMut::new();
+        loop {
+            match parse_request(&mut buffer, 1024) {
+                Ok(Some(request)) => execute(request, state, &mut out),
+                Ok(None) => break,
+                Err(err) => out.extend_from_slice(format!("{err}\r\n").as_bytes()),
+            }
+        }
+        return String::from_utf8(out.to_vec()).unwrap();
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let mut buffer = BytesMut::from("set key 5 0 5 noreply\r\nhel");
+        assert_eq!(parse_request(&mut buffer, 1024), Ok(None));
+        buffer.extend_from_slice(b"lo\r\nget a b\n");
+        assert_eq!(
+            parse_request(&mut buffer, 1024),
+            Ok(Some(Request::Set {
+                key: String::from("key"),
+                flags: 5,
+                exptime: 0,
+                data: Bytes::from("hello"),
+                noreply: true,
+            }))
+        );
+        assert_eq!(
+            parse_request(&mut buffer, 1024),
+            Ok(Some(Request::Get {
+                keys: vec![String::from("a"), String::from("b")]
+            }))
+        );
+        assert!(buffer.is_empty());
+
+        let mut buffer = BytesMut::from("set key 0 0 2048\r\n");
+        let err = parse_request(&mut buffer, 1024).unwrap_err();
+        assert!(err.is_fatal());
+        let mut buffer = BytesMut::from("set key 0 0 2\r\nabc\r\n");
+        assert_eq!(
+            parse_request(&mut buffer, 1024),
+            Err(MemcachedError::Client("bad data chunk"))
+        );
+        let mut buffer = BytesMut::from("flush_all\r\nincr key x\r\nversion\r\n");
+        assert_eq!(
+            parse_request(&mut buffer, 1024),
+            Err(MemcachedError::UnknownCommand)
+        );
+        assert!(parse_request(&mut buffer, 1024).is_err());
+        assert_eq!(parse_request(&mut buffer, 1024), Ok(Some(Request::Version)));
+    }
+
+    #[test]
+    fn test_commands() {
+        let state = StateInner::new(Config::default());
+        assert_eq!(
+            run(&state, "set key 42 0 5\r\nhello\r\nget key missing\r\n"),
+            "STORED\r\nVALUE key 42 5\r\nhello\r\nEND\r\n"
+        );
+        // values are shared with RESP clients
+        let get = DataType::Array {
+            items: vec![DataType::from("GET"), DataType::from("key")],
+        };
+        assert_eq!(
+            crate::commands::dispatch(get, &state, 0),
+            DataType::bulk("hello")
+        );
+        assert_eq!(
+            run(&state, "incr key 1\r\n"),
+            "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+        );
+        assert_eq!(
+            run(
+                &state,
+                "set n 0 0 2 noreply\r\n10\r\nincr n 5\r\ndecr n 100\r\nincr nope 1\r\n"
+            ),
+            "15\r\n0\r\nNOT_FOUND\r\n"
+        );
+        assert_eq!(
+            run(
+                &state,
+                "set n 0 0 20\r\n18446744073709551615\r\nincr n 2\r\n"
+            ),
+            "STORED\r\n1\r\n"
+        );
+        assert_eq!(
+            run(&state, "delete key\r\ndelete key\r\nget key\r\n"),
+            "DELETED\r\nNOT_FOUND\r\nEND\r\n"
+        );
+        assert_eq!(run(&state, "stats\r\n"), "ERROR\r\n");
+    }
+
+    #[test]
+    fn test_exptime() {
+        let clock = MockClock::new(3_000_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        run(&state, "set relative 0 10 1\r\na\r\n");
+        // beyond 30 days it's a unix timestamp, 3000020 seconds since the epoch
+        run(&state, "set absolute 0 3000020 1\r\nb\r\n");
+        assert_eq!(run(&state, "set past 0 -1 1\r\nc\r\n"), "STORED\r\n");
+        assert_eq!(
+            run(&state, "get relative absolute past\r\n"),
+            "VALUE relative 0 1\r\na\r\nVALUE absolute 0 1\r\nb\r\nEND\r\n"
+        );
+        clock.advance(10_000);
+        assert_eq!(
+            run(&state, "get relative absolute\r\n"),
+            "VALUE absolute 0 1\r\nb\r\nEND\r\n"
+        );
+        clock.advance(10_000);
+        assert_eq!(run(&state, "get absolute\r\n"), "END\r\n");
+
+        assert_eq!(
+            run(&state, "set huge 0 9223372036854775807 1\r\nd\r\n"),
+            "CLIENT_ERROR bad command line format\r\n"
+        );
+        assert_eq!(run(&state, "get huge\r\n"), "END\r\n");
+    }
+
+    #[test]
+    fn test_expired_keys_are_invalidated() {
+        let clock = MockClock::new(1_000_000);
+        let state = StateInner::with_clock(Config::default(), clock.clone());
+        let mut client = state.connect_client().unwrap();
+        state.clients.with_client(client.id, |c| c.protocol = 3);
+        tracking::set_tracking(&state, client.id, Some(TrackingOptions::default()));
+        let keys = [Bytes::from("a"), Bytes::from("b"), Bytes::from("c")];
+        tracking::track_keys(&state, client.id, &keys);
+
+        run(
+            &state,
+            "set a 0 1 1\r\n1\r\nset b 0 1 1\r\n2\r\nset c 0 1 1\r\n3\r\n",
+        );
+        // the writes above invalidated the keys, track them again
+        while client.messages.try_recv().is_ok() {}
+        tracking::track_keys(&state, client.id, &keys);
+        clock.advance(1_000);
+
+        assert_eq!(
+            run(&state, "get a\r\ndelete b\r\nincr c 1\r\n"),
+            "END\r\nNOT_FOUND\r\nNOT_FOUND\r\n"
+        );
+        for key in ["a", "b", "c"] {
+            let message = client.messages.try_recv().unwrap();
+            assert!(format!("{message:?}").contains(key), "{message:?}");
+        }
+        assert!(state
+            .stats
+            .stats()
+            .contains(&String::from("expired_keys:3")));
+    }
+}
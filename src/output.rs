@@ -0,0 +1,307 @@
+/// Output buffer limits and write timeouts of client connections.
+///
+/// Replies are written to the socket as they are produced rather than queued, so the
+/// output buffer of a client is the part of the write in progress (the reply being
+/// sent, or the replies buffered for a pipeline) that the socket didn't accept yet.
+/// It only grows while the client doesn't read fast enough, which is when the limits
+/// are checked: like Redis, clients are disconnected once their output buffer goes
+/// over the hard limit, or stays over the soft limit for the given seconds. Clients
+/// that don't accept any output for the write timeout are disconnected too.
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::io::AsyncWrite;
+use tokio::time::{Instant, Sleep};
+
+use crate::config::parse_memory;
+use crate::state::State;
+
+/// Seconds a client has to accept the output written to it, by default.
+pub const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputError {
+    #[error("client closed for overcoming of output buffer limits")]
+    BufferLimit,
+
+    #[error("client closed for not reading its output in time")]
+    WriteTimeout,
+}
+
+/// Output buffer limits of a class of clients, in bytes. 0 disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OutputBufferLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+/// Output buffer limits of every class of clients, as `client-output-buffer-limit`.
+///
+/// Only normal clients exist so far; the limits of replicas and pub/sub clients are
+/// kept so the parameter reads and writes like the one of Redis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputBufferLimits {
+    pub normal: OutputBufferLimit,
+    pub replica: OutputBufferLimit,
+    pub pubsub: OutputBufferLimit,
+}
+
+impl Default for OutputBufferLimits {
+    fn default() -> Self {
+        return OutputBufferLimits {
+            normal: OutputBufferLimit::default(),
+            replica: OutputBufferLimit {
+                hard: 256 * 1024 * 1024,
+                soft: 64 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            pubsub: OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+        };
+    }
+}
+
+impl OutputBufferLimits {
+    /// Parses `<class> <hard> <soft> <soft seconds>` groups, updating the limits of
+    /// the classes given. Limits take memory units, e.g. `64mb`.
+    pub fn parse(&self, value: &str) -> Option<Self> {
+        let words: Vec<&str> = value.split_whitespace().collect();
+        if words.is_empty() || !words.len().is_multiple_of(4) {
+            return None;
+        }
+        let mut limits = *self;
+        for group in words.chunks(4) {
+            let limit = OutputBufferLimit {
+                hard: parse_memory(group[1])? as usize,
+                soft: parse_memory(group[2])? as usize,
+                soft_seconds: group[3].parse().ok()?,
+            };
+            match group[0].to_lowercase().as_str() {
+                "normal" => limits.normal = limit,
+                "replica" | "slave" => limits.replica = limit,
+                "pubsub" => limits.pubsub = limit,
+                _ => return None,
+            }
+        }
+        return Some(limits);
+    }
+
+    /// The limits as CONFIG GET reports them.
+    pub fn format(&self) -> String {
+        let classes = [
+            ("normal", self.normal),
+            ("slave", self.replica),
+            ("pubsub", self.pubsub),
+        ];
+        return classes
+            .iter()
+            .map(|(class, limit)| {
+                format!(
+                    "{} {} {} {}",
+                    class, limit.hard, limit.soft, limit.soft_seconds
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+    }
+}
+
+/// Writer enforcing the output buffer limits and the write timeout of a client,
+/// failing its writes once the client must be disconnected.
+pub struct LimitedWriter<W> {
+    inner: W,
+    state: State,
+    limit: OutputBufferLimit,
+    /// None waits for the client forever
+    write_timeout: Option<Duration>,
+    /// when the output buffer went over the soft limit
+    soft_limit_since: Option<Instant>,
+    /// when the client stopped accepting output
+    stalled_since: Option<Instant>,
+    /// wakes the writer up when a limit expires while the client isn't reading
+    timer: Pin<Box<Sleep>>,
+}
+
+impl<W> LimitedWriter<W> {
+    pub fn new(
+        inner: W,
+        state: State,
+        limit: OutputBufferLimit,
+        write_timeout: Option<Duration>,
+    ) -> Self {
+        return LimitedWriter {
+            inner: inner,
+            state: state,
+            limit: limit,
+            write_timeout: write_timeout,
+            soft_limit_since: None,
+            stalled_since: None,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+        };
+    }
+
+    fn disconnect(&self, err: OutputError) -> Poll<io::Result<usize>> {
+        println!("{err}");
+        self.state.stats.record_output_disconnection(err);
+        return Poll::Ready(Err(io::Error::other(err)));
+    }
+
+    /// Checks the limits when `pending` bytes couldn't be written.
+    fn poll_stalled(&mut self, cx: &mut Context<'_>, pending: usize) -> Poll<io::Result<usize>> {
+        let now = Instant::now();
+        if self.limit.hard > 0 && pending > self.limit.hard {
+            return self.disconnect(OutputError::BufferLimit);
+        }
+        let mut deadline = None;
+        if self.limit.soft > 0 && pending > self.limit.soft {
+            let since = *self.soft_limit_since.get_or_insert(now);
+            let expires = since + Duration::from_secs(self.limit.soft_seconds);
+            if now >= expires {
+                return self.disconnect(OutputError::BufferLimit);
+            }
+            deadline = Some(expires);
+        }
+        if let Some(timeout) = self.write_timeout {
+            let expires = *self.stalled_since.get_or_insert(now) + timeout;
+            if now >= expires {
+                return self.disconnect(OutputError::WriteTimeout);
+            }
+            deadline = Some(deadline.map_or(expires, |deadline: Instant| deadline.min(expires)));
+        }
+        if let Some(deadline) = deadline {
+            self.timer.as_mut().reset(deadline);
+            if self.timer.as_mut().poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+        return Poll::Pending;
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for LimitedWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        return match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(written)) => {
+                self.stalled_since = None;
+                if buf.len() - written <= self.limit.soft {
+                    self.soft_limit_since = None;
+                }
+                Poll::Ready(Ok(written))
+            }
+            Poll::Pending => self.poll_stalled(cx, buf.len()),
+            res => res,
+        };
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.inner).poll_flush(cx);
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.inner).poll_shutdown(cx);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{LimitedWriter, OutputBufferLimit, OutputBufferLimits, OutputError};
+    use crate::config::Config;
+    use crate::state::{State, StateInner};
+
+    #[test]
+    fn test_parse_limits() {
+        let limits = OutputBufferLimits::default();
+        assert_eq!(
+            limits.format(),
+            "normal 0 0 0 slave 268435456 67108864 60 pubsub 33554432 8388608 60"
+        );
+        let limits = limits.parse("normal 1mb 1kb 10 SLAVE 1 1 1").unwrap();
+        assert_eq!(
+            limits.format(),
+            "normal 1048576 1024 10 slave 1 1 1 pubsub 33554432 8388608 60"
+        );
+        for value in ["", "normal 1 1", "normal 1 1 x", "master 1 1 1"] {
+            assert_eq!(limits.parse(value), None, "{value}");
+        }
+    }
+
+    fn stat(state: &State, name: &str) -> String {
+        return state
+            .stats
+            .stats()
+            .into_iter()
+            .find(|line| line.starts_with(name))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let state = StateInner::new(Config::default());
+
+        // readers keep the writer going
+        let (mut client, server) = tokio::io::duplex(64);
+        let limit = OutputBufferLimit {
+            hard: 1000,
+            soft: 100,
+            soft_seconds: 1,
+        };
+        let mut writer = LimitedWriter::new(server, state.clone(), limit, None);
+        let reader = tokio::spawn(async move {
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output.len()
+        });
+        writer.write_all(&[b'x'; 500]).await.unwrap();
+        drop(writer);
+        assert_eq!(reader.await.unwrap(), 500);
+
+        // over the hard limit
+        let (_client, server) = tokio::io::duplex(64);
+        let mut writer = LimitedWriter::new(server, state.clone(), limit, None);
+        let err = writer.write_all(&[b'x'; 2000]).await.unwrap_err();
+        assert_eq!(
+            err.into_inner().unwrap().to_string(),
+            OutputError::BufferLimit.to_string()
+        );
+
+        // over the soft limit for too long
+        let (_client, server) = tokio::io::duplex(64);
+        let mut writer = LimitedWriter::new(server, state.clone(), limit, None);
+        let started = tokio::time::Instant::now();
+        assert!(writer.write_all(&[b'x'; 500]).await.is_err());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(
+            stat(&state, "client_output_buffer_limit"),
+            "client_output_buffer_limit_disconnections:2"
+        );
+
+        // not reading at all
+        let (_client, server) = tokio::io::duplex(64);
+        let timeout = Some(Duration::from_millis(100));
+        let mut writer =
+            LimitedWriter::new(server, state.clone(), OutputBufferLimit::default(), timeout);
+        let started = tokio::time::Instant::now();
+        assert!(writer.write_all(&[b'x'; 100]).await.is_err());
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            stat(&state, "client_write_timeout"),
+            "client_write_timeout_disconnections:1"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+/// Process management: pidfile, daemonization and service manager notifications.
+use std::env;
+use std::fs;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use crate::config::Supervised;
+
+/// Writes the pid of the server to `path`.
+pub fn write_pidfile(path: &str) -> io::Result<()> {
+    return fs::write(path, format!("{}\n", std::process::id()));
+}
+
+pub fn remove_pidfile(path: &str) {
+    let _ = fs::remove_file(path);
+}
+
+/// Detaches the server from the terminal, continuing in a forked child process
+/// with its standard streams redirected to /dev/null.
+/// Must be called before starting the async runtime, as only the calling
+/// thread survives the fork.
+pub fn daemonize() -> io::Result<()> {
+    // SAFETY: the process is still single threaded, the child can safely keep running
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        _ => std::process::exit(0),
+    }
+    // SAFETY: plain syscalls without memory arguments besides the path literal
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        for stdio in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            libc::dup2(fd, stdio);
+        }
+        if fd > libc::STDERR_FILENO {
+            libc::close(fd);
+        }
+    }
+    return Ok(());
+}
+
+/// Sends `state` (e.g. "READY=1") to systemd through the socket in `$NOTIFY_SOCKET`.
+/// Returns `Ok(false)` if the server isn't run by systemd.
+pub fn sd_notify(state: &str) -> io::Result<bool> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return Ok(false),
+    };
+    send_notification(&path, state)?;
+    return Ok(true);
+}
+
+/// Sends `state` to the notification socket at `path`, `@` prefixed paths being
+/// abstract socket names.
+fn send_notification(path: &str, state: &str) -> io::Result<()> {
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    return Ok(());
+}
+
+/// Tells the service manager the server is ready to accept connections.
+pub fn notify_ready(supervised: Supervised) -> io::Result<()> {
+    match supervised {
+        Supervised::No => {}
+        Supervised::Systemd | Supervised::Auto => {
+            if !sd_notify("READY=1\n")? && supervised == Supervised::Systemd {
+                println!("systemd supervision requested, but NOTIFY_SOCKET not found");
+            }
+        }
+        Supervised::Upstart => {
+            // upstart waits for the process to stop itself when ready
+            // SAFETY: raising a signal on the current process has no memory effects
+            unsafe { libc::raise(libc::SIGSTOP) };
+        }
+    }
+    return Ok(());
+}
+
+/// Tells the service manager the server is shutting down.
+pub fn notify_stopping(supervised: Supervised) {
+    if matches!(supervised, Supervised::Systemd | Supervised::Auto) {
+        let _ = sd_notify("STOPPING=1\n");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use std::os::unix::net::UnixDatagram;
+
+    use super::{remove_pidfile, send_notification, write_pidfile};
+
+    #[test]
+    fn test_pidfile() {
+        let path = std::env::temp_dir().join(format!("redis-test-{}.pid", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_pidfile(path).unwrap();
+        let pid = fs::read_to_string(path).unwrap();
+        assert_eq!(pid.trim().parse::<u32>().unwrap(), std::process::id());
+        remove_pidfile(path);
+        assert!(fs::metadata(path).is_err());
+    }
+
+    #[test]
+    fn test_send_notification() {
+        let name = format!("redis-test-notify-{}", std::process::id());
+        let path = std::env::temp_dir().join(&name);
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        send_notification(path.to_str().unwrap(), "READY=1\n").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\n");
+        let _ = fs::remove_file(&path);
+    }
+}
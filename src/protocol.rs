@@ -1,21 +1,110 @@
 use anyhow::{bail, Result};
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use memchr::memchr;
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+/// Replies up to this size are encoded in a single buffer by `DataType::encode_to`,
+/// bigger ones are streamed to the writer.
+const ENCODE_TO_BUFFERED_LEN: usize = 64 * 1024;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SafeReadError {
+    /// The data needed isn't buffered yet, nothing was consumed so the read can
+    /// be retried once more data is available.
+    #[error("not enough data")]
+    Incomplete,
+
+    #[error("invalid integer")]
+    InvalidInteger,
+}
+
+/// Errors converting a `DataType` into a Rust type.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ConversionError {
+    #[error("unexpected {got} reply, expected {expected}")]
+    UnexpectedType {
+        expected: &'static str,
+        got: &'static str,
+    },
+
+    #[error("reply is not valid UTF-8")]
+    InvalidUtf8,
+
+    #[error("reply is not an integer")]
+    InvalidInteger,
+}
+
+/// Reads from a buffer that may not hold all the data needed yet, failing with
+/// `SafeReadError::Incomplete` instead of panicking.
 pub trait SafeRead {
     fn get_u8_safe(&mut self) -> Result<u8>;
+
+    /// Takes the next `len` bytes.
+    fn get_slice_safe(&mut self, len: usize) -> Result<Bytes>;
+
+    /// Takes the data up to the next CRLF, consuming the CRLF too.
+    fn read_line_crlf(&mut self) -> Result<Bytes>;
+
+    /// Takes a CRLF terminated line holding an integer.
+    fn read_integer_line(&mut self) -> Result<isize>;
 }
 
 impl SafeRead for Bytes {
     fn get_u8_safe(&mut self) -> Result<u8> {
         if self.remaining() == 0 {
-            bail!("no bytes left");
+            bail!(SafeReadError::Incomplete);
         }
         return Ok(self.get_u8());
     }
+
+    fn get_slice_safe(&mut self, len: usize) -> Result<Bytes> {
+        if self.remaining() < len {
+            bail!(SafeReadError::Incomplete);
+        }
+        return Ok(self.split_to(len));
+    }
+
+    fn read_line_crlf(&mut self) -> Result<Bytes> {
+        let mut end = 0;
+        loop {
+            end += match memchr(b'\n', &self[end..]) {
+                Some(ix) => ix,
+                None => bail!(SafeReadError::Incomplete),
+            };
+            // a lone LF is part of the line
+            if end > 0 && self[end - 1] == b'\r' {
+                let line = self.split_to(end - 1);
+                self.advance(2);
+                return Ok(line);
+            }
+            end += 1;
+        }
+    }
+
+    fn read_integer_line(&mut self) -> Result<isize> {
+        let mut peek = self.clone();
+        let line = peek.read_line_crlf()?;
+        let number = std::str::from_utf8(&line)
+            .ok()
+            .and_then(|line| line.parse().ok())
+            .ok_or(SafeReadError::InvalidInteger)?;
+        *self = peek;
+        return Ok(number);
+    }
 }
 
 /// DataType represents the available data types on [RESP](https://redis.io/docs/reference/protocol-spec/#resp-protocol-description)
-#[derive(Debug, Eq, PartialEq)]
+///
+/// With the `serde` feature values can be (de)serialized as objects tagged with their type,
+/// e.g. `{"type": "bulk_string", "string": "value"}`. Bulk Strings that aren't valid UTF-8
+/// are represented as arrays of bytes.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
 pub enum DataType {
     /// Simple Strings are encoded as follows: a plus character, followed by a string that cannot
     /// contain a CR or LF character (no newlines are allowed), and terminated by CRLF (that is "\r\n").
@@ -23,7 +112,7 @@ pub enum DataType {
     /// Simple Strings are used to transmit non binary-safe strings with minimal overhead. For example,
     /// many Redis commands reply with just "OK" on success. The RESP Simple String is encoded with
     /// the following 5 bytes:
-    /// ```
+    /// ```text
     /// "+OK\r\n"
     /// ```
     /// In order to send binary-safe strings, use RESP Bulk Strings instead.
@@ -40,7 +129,7 @@ pub enum DataType {
     /// Error type is the error message itself.
     ///
     /// The basic format is:
-    /// ```
+    /// ```text
     /// "-Error message\r\n"
     /// ```
     /// Error replies are only sent when something goes wrong, for instance if you try to perform an
@@ -49,7 +138,7 @@ pub enum DataType {
     ///
     /// The following are examples of error replies:
     ///
-    /// ```
+    /// ```text
     /// -ERR unknown command 'helloworld'
     /// -WRONGTYPE Operation against a key holding the wrong kind of value
     /// ```
@@ -68,6 +157,7 @@ pub enum DataType {
     /// However, such a feature should not be considered vital as it is rarely useful, and a limited client
     /// implementation may simply return a generic error condition, such as false.
     Error {
+        #[cfg_attr(feature = "serde", serde(rename = "prefix"))]
         type_: String,
         error: String,
     },
@@ -101,18 +191,18 @@ pub enum DataType {
     /// - A final CRLF.
     ///
     /// So the string "hello" is encoded as follows:
-    /// ```
+    /// ```text
     /// "$5\r\nhello\r\n"
     /// ```
     ///
     /// An empty string is encoded as:
-    /// ```
+    /// ```text
     /// "$0\r\n\r\n"
     /// ```
     ///
     /// RESP Bulk Strings can also be used in order to signal non-existence of a value using a special
     /// format to represent a Null value. In this format, the length is -1, and there is no data. Null is represented as:
-    /// ```
+    /// ```text
     /// "$-1\r\n"
     /// ```
     ///
@@ -121,8 +211,11 @@ pub enum DataType {
     /// The client library API should not return an empty string, but a nil object, when the server replies with
     /// a Null Bulk String. For example, a Ruby library should return 'nil' while a C library should return NULL
     /// (or set a special flag in the reply object).
+    ///
+    /// Bulk Strings are binary safe, so their data is kept as raw bytes.
     BulkString {
-        string: String,
+        #[cfg_attr(feature = "serde", serde(with = "bulk_serde"))]
+        string: Bytes,
     },
     NullBulkString,
 
@@ -134,25 +227,25 @@ pub enum DataType {
     /// - An additional RESP type for every element of the Array.
     ///
     /// So an empty Array is just the following:
-    /// ```
+    /// ```text
     /// "*0\r\n"
     /// ```
     ///
     /// While an array of two RESP Bulk Strings "hello" and "world" is encoded as:
-    /// ```
+    /// ```text
     /// "*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n"
     /// ```
     ///
     /// As you can see after the *<count>CRLF part prefixing the array, the other data types composing the array
     /// are just concatenated one after the other. For example, an Array of three integers is encoded as follows:
-    /// ```
+    /// ```text
     /// "*3\r\n:1\r\n:2\r\n:3\r\n"
     /// ```
     ///
     /// Arrays can contain mixed types, so it's not necessary for the elements to be of the same type.
     /// For instance, a list of four integers and a bulk string can be encoded as follows:
     ///
-    /// ```
+    /// ```text
     /// *5\r\n
     /// :1\r\n
     /// :2\r\n
@@ -170,7 +263,7 @@ pub enum DataType {
     /// is used, but for historical reasons we have two formats).
     ///
     /// For instance, when the BLPOP command times out, it returns a Null Array that has a count of -1 as in the following example:
-    /// ```
+    /// ```text
     /// "*-1\r\n"
     /// ```
     ///
@@ -180,7 +273,7 @@ pub enum DataType {
     ///
     /// Nested arrays are possible in RESP. For example a nested array of two arrays is encoded as follows:
     ///
-    /// ```
+    /// ```text
     /// *2\r\n
     /// *3\r\n
     /// :1\r\n
@@ -201,7 +294,7 @@ pub enum DataType {
     /// missing and not empty strings. This can happen with the SORT command when used with the GET pattern option
     /// if the specified key is missing. Example of an Array reply containing a Null element:
     ///
-    /// ```
+    /// ```text
     /// *3\r\n
     /// $5\r\n
     /// hello\r\n
@@ -212,7 +305,7 @@ pub enum DataType {
     ///
     /// The second element is a Null. The client library should return something like this:
     ///
-    /// ```
+    /// ```text
     /// ["hello",nil,"world"]
     /// ```
     ///
@@ -220,55 +313,1000 @@ pub enum DataType {
     Array {
         items: Vec<DataType>,
     },
+    NullArray,
+
+    /// RESP3 Maps are sequences of field-value pairs, used by commands returning dictionaries
+    /// such as CONFIG GET or HGETALL when the connection speaks RESP3.
+    ///
+    /// Maps are encoded like Arrays but with a % byte, followed by the number of field-value
+    /// pairs (not the number of elements), and then every field followed by its value:
+    /// ```text
+    /// %2\r\n
+    /// +first\r\n
+    /// :1\r\n
+    /// +second\r\n
+    /// :2\r\n
+    /// ```
+    /// (The format was split into multiple lines to make it easier to read).
+    ///
+    /// Fields and values can be of any type. The order of the pairs is kept, although
+    /// clients shouldn't rely on it.
+    Map {
+        items: Vec<(DataType, DataType)>,
+    },
+
+    /// RESP3 Sets are unordered collections of unique elements, used by commands such as
+    /// SMEMBERS when the connection speaks RESP3.
+    ///
+    /// Sets are encoded exactly like Arrays but with a ~ byte instead of *:
+    /// ```text
+    /// "~2\r\n+orange\r\n+apple\r\n"
+    /// ```
+    Set {
+        items: Vec<DataType>,
+    },
+
+    /// RESP3 Doubles are floating point numbers, prefixed by a "," byte and terminated by CRLF,
+    /// used by commands such as ZSCORE when the connection speaks RESP3: `",1.23\r\n"`.
+    ///
+    /// The number may also be an integer (`",10\r\n"`), use an exponent (`",1.5e10\r\n"`),
+    /// or be one of the special values `inf`, `-inf` and `nan`.
+    Double {
+        number: f64,
+    },
+
+    /// RESP3 Booleans are encoded as `"#t\r\n"` for true and `"#f\r\n"` for false. With RESP2
+    /// the same replies are sent as the Integers 1 and 0, for example by SISMEMBER.
+    Boolean {
+        value: bool,
+    },
+
+    /// RESP3 Big Numbers are integers outside the range of a signed 64-bit integer, prefixed
+    /// by a "(" byte and terminated by CRLF: `"(3492890328409238509324850943850943825024385\r\n"`.
+    ///
+    /// The digits are kept as a string, as they can be arbitrarily large.
+    BigNumber {
+        number: String,
+    },
+
+    /// RESP3 has a single Null type, replacing the Null Bulk String and Null Array of RESP2:
+    /// ```text
+    /// "_\r\n"
+    /// ```
+    ///
+    /// Commands shouldn't reply with it directly, nil replies are converted from and to the
+    /// RESP2 null types by `DataType::for_protocol`, depending on the protocol of the client.
+    Null,
+
+    /// RESP3 Verbatim Strings are Bulk Strings prefixed by a "=" byte, whose data starts with a
+    /// three characters format followed by a colon: `txt` for plain text or `mkd` for markdown.
+    /// ```text
+    /// "=15\r\ntxt:Some string\r\n"
+    /// ```
+    ///
+    /// They are meant for human readable replies such as LATENCY DOCTOR, which clients can
+    /// display without any escaping. Under RESP2 the string is sent as a Bulk String,
+    /// without the format.
+    VerbatimString {
+        format: String,
+        string: String,
+    },
+
+    /// RESP3 Pushes are out-of-band data the server sends without the client asking for it,
+    /// like Pub/Sub messages or client side caching invalidations. They are encoded like Arrays
+    /// but with a > byte, and their first element is a string with the kind of push:
+    /// ```text
+    /// ">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n"
+    /// ```
+    ///
+    /// Having its own type allows clients to tell pushes apart from the replies to their
+    /// commands, even when they are interleaved. Under RESP2 pushes are sent as Arrays.
+    Push {
+        items: Vec<DataType>,
+    },
 }
 
 impl DataType {
     pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode_buf(&mut buf)?;
+        return Ok(buf);
+    }
+
+    /// Appends the encoded value to `buf`. Meant to be used with a buffer reused
+    /// between replies, instead of allocating a new one each time like `encode`.
+    pub fn encode_into(&self, buf: &mut BytesMut) -> Result<()> {
+        buf.reserve(self.encoded_len());
+        return self.encode_buf(buf);
+    }
+
+    fn encode_buf(&self, buf: &mut impl BufMut) -> Result<()> {
         match self {
-            DataType::Integer { number } => encode_integer(*number),
-            DataType::SimpleString { string } => encode_simple_string(string),
-            DataType::BulkString { string } => encode_bulk_string(string),
-            DataType::NullBulkString => encode_null_string(),
-            DataType::Error { type_, error } => encode_error(type_, error),
-            DataType::Array { items } => encode_array(items),
+            DataType::Integer { number } => encode_integer(buf, *number),
+            DataType::SimpleString { string } => encode_simple_string(buf, string),
+            DataType::BulkString { string } => encode_bulk_string(buf, string),
+            DataType::NullBulkString => encode_line(buf, b'$', b"-1"),
+            DataType::Error { type_, error } => encode_error(buf, type_, error),
+            DataType::Array { items } => encode_aggregate(buf, b'*', items),
+            DataType::NullArray => encode_line(buf, b'*', b"-1"),
+            DataType::Map { items } => encode_map(buf, items),
+            DataType::Set { items } => encode_aggregate(buf, b'~', items),
+            DataType::Double { number } => encode_double(buf, *number),
+            DataType::Boolean { value } => encode_boolean(buf, *value),
+            DataType::BigNumber { number } => encode_line(buf, b'(', number.as_bytes()),
+            DataType::Null => encode_line(buf, b'_', b""),
+            DataType::VerbatimString { format, string } => {
+                encode_verbatim_string(buf, format, string)
+            }
+            DataType::Push { items } => encode_aggregate(buf, b'>', items),
+        }
+    }
+
+    /// Encodes the value directly into `writer`. Unlike `encode`, aggregates and bulk
+    /// strings are written piece by piece instead of being copied into a single buffer
+    /// first, so big replies don't need an intermediate allocation of their whole size.
+    /// Pair it with a buffered writer to avoid a write per element.
+    ///
+    /// Small replies and the pieces of big ones are encoded into `scratch`, reuse it
+    /// between calls to avoid allocating.
+    pub async fn encode_to<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        scratch: &mut BytesMut,
+    ) -> Result<()> {
+        scratch.clear();
+        if self.encoded_len() <= ENCODE_TO_BUFFERED_LEN {
+            self.encode_into(scratch)?;
+            writer.write_all(scratch).await?;
+            return Ok(());
+        }
+        // explicit stack of the values left to write, async fns can't recurse
+        let mut pending = vec![self];
+        while let Some(value) = pending.pop() {
+            scratch.clear();
+            match value {
+                DataType::Array { items } | DataType::Set { items } | DataType::Push { items } => {
+                    encode_header(scratch, value.prefix(), items.len());
+                    pending.extend(items.iter().rev());
+                }
+                DataType::Map { items } => {
+                    encode_header(scratch, b'%', items.len());
+                    for (field, value) in items.iter().rev() {
+                        pending.push(value);
+                        pending.push(field);
+                    }
+                }
+                DataType::BulkString { string } => {
+                    encode_header(scratch, b'$', string.len());
+                    writer.write_all(scratch).await?;
+                    writer.write_all(string).await?;
+                    scratch.clear();
+                    scratch.put_slice(b"\r\n");
+                }
+                _ => value.encode_into(scratch)?,
+            }
+            writer.write_all(scratch).await?;
+        }
+        return Ok(());
+    }
+
+    /// Returns the number of bytes `encode` produces for the value.
+    pub fn encoded_len(&self) -> usize {
+        return match self {
+            DataType::Integer { number } => number.to_string().len() + 3,
+            DataType::SimpleString { string } => string.len() + 3,
+            DataType::BulkString { string } => header_len(string.len()) + string.len() + 2,
+            DataType::NullBulkString | DataType::NullArray => 5,
+            DataType::Error { type_, error } if type_.is_empty() => error.len() + 3,
+            DataType::Error { type_, error } => type_.len() + error.len() + 4,
+            DataType::Array { items } | DataType::Set { items } | DataType::Push { items } => {
+                header_len(items.len()) + items.iter().map(|i| i.encoded_len()).sum::<usize>()
+            }
+            DataType::Map { items } => {
+                let pairs = items.iter().map(|(f, v)| f.encoded_len() + v.encoded_len());
+                header_len(items.len()) + pairs.sum::<usize>()
+            }
+            DataType::Double { number } => format_double(*number).len() + 3,
+            DataType::Boolean { .. } => 4,
+            DataType::BigNumber { number } => number.len() + 3,
+            DataType::Null => 3,
+            DataType::VerbatimString { string, .. } => {
+                header_len(string.len() + 4) + string.len() + 6
+            }
+        };
+    }
+
+    /// Type byte of the aggregates that share the Array encoding.
+    fn prefix(&self) -> u8 {
+        return match self {
+            DataType::Set { .. } => b'~',
+            DataType::Push { .. } => b'>',
+            _ => b'*',
+        };
+    }
+
+    /// The `+OK` reply.
+    pub fn ok() -> DataType {
+        return DataType::SimpleString {
+            string: String::from("OK"),
+        };
+    }
+
+    pub fn bulk(string: impl Into<Bytes>) -> DataType {
+        return DataType::BulkString {
+            string: string.into(),
+        };
+    }
+
+    /// An Error with the given Error Prefix (e.g. `ERR`, `WRONGTYPE`). Prefer `ReplyError`
+    /// for the standard errors.
+    pub fn error(kind: &str, msg: impl Into<String>) -> DataType {
+        return DataType::Error {
+            type_: kind.to_string(),
+            error: msg.into(),
+        };
+    }
+
+    /// Name of the type, used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        return match self {
+            DataType::Integer { .. } => "integer",
+            DataType::SimpleString { .. } => "simple string",
+            DataType::BulkString { .. } => "bulk string",
+            DataType::NullBulkString | DataType::NullArray | DataType::Null => "null",
+            DataType::Error { .. } => "error",
+            DataType::Array { .. } => "array",
+            DataType::Map { .. } => "map",
+            DataType::Set { .. } => "set",
+            DataType::Double { .. } => "double",
+            DataType::Boolean { .. } => "boolean",
+            DataType::BigNumber { .. } => "big number",
+            DataType::VerbatimString { .. } => "verbatim string",
+            DataType::Push { .. } => "push",
+        };
+    }
+
+    /// Returns the contents of a Simple String or Bulk String, if valid UTF-8. Only
+    /// for arguments that are text, like options and numbers: keys and values are
+    /// binary, see `as_bytes`.
+    pub fn as_string(&self) -> Option<String> {
+        return match self {
+            DataType::SimpleString { string } => Some(string.clone()),
+            DataType::BulkString { string } => String::from_utf8(string.to_vec()).ok(),
+            _ => None,
+        };
+    }
+
+    /// Returns the contents of a Simple String or Bulk String as raw bytes.
+    pub fn as_bytes(&self) -> Option<Bytes> {
+        return match self {
+            DataType::SimpleString { string } => Some(Bytes::from(string.clone())),
+            DataType::BulkString { string } => Some(string.clone()),
+            _ => None,
+        };
+    }
+
+    /// Builds a VerbatimString from the data of a `=` frame, with the format prefix
+    /// (e.g. `txt:Some string`).
+    pub fn from_verbatim(data: String) -> Result<DataType> {
+        match data.split_once(':') {
+            Some((format, string)) if format.len() == 3 => {
+                return Ok(DataType::VerbatimString {
+                    format: format.to_string(),
+                    string: string.to_string(),
+                });
+            }
+            _ => bail!("invalid verbatim string format"),
+        }
+    }
+
+    /// Builds an Error from the data of a `-` frame, splitting its Error Prefix (a first
+    /// uppercase word like ERR or WRONGTYPE) into `type_`. Errors without a prefix are
+    /// left with an empty `type_`.
+    pub fn from_error_line(line: String) -> DataType {
+        if let Some((prefix, error)) = line.split_once(' ') {
+            let is_prefix = prefix.starts_with(|c: char| c.is_ascii_uppercase())
+                && prefix
+                    .bytes()
+                    .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_');
+            if is_prefix {
+                return DataType::Error {
+                    type_: prefix.to_string(),
+                    error: error.to_string(),
+                };
+            }
+        }
+        return DataType::Error {
+            type_: String::new(),
+            error: line,
+        };
+    }
+
+    /// Shapes a reply for a client speaking the given protocol version.
+    ///
+    /// Commands reply with the richest types available, which are sent as is to RESP3
+    /// clients (besides converting RESP2 nil replies to Null). For RESP2 clients they are
+    /// downgraded to their RESP2 equivalents: Maps are flattened into Arrays of alternating
+    /// fields and values, Sets and Pushes become Arrays, Booleans become the Integers 1 and 0,
+    /// and Doubles, Big Numbers and Verbatim Strings become Bulk Strings.
+    pub fn for_protocol(self, protocol: u8) -> DataType {
+        if protocol >= 3 {
+            return match self {
+                DataType::NullBulkString | DataType::NullArray => DataType::Null,
+                DataType::Array { items } => DataType::Array {
+                    items: shape_items(items, protocol),
+                },
+                DataType::Set { items } => DataType::Set {
+                    items: shape_items(items, protocol),
+                },
+                DataType::Push { items } => DataType::Push {
+                    items: shape_items(items, protocol),
+                },
+                DataType::Map { items } => DataType::Map {
+                    items: items
+                        .into_iter()
+                        .map(|(field, value)| {
+                            (field.for_protocol(protocol), value.for_protocol(protocol))
+                        })
+                        .collect(),
+                },
+                other => other,
+            };
+        }
+        return match self {
+            DataType::Null => DataType::NullBulkString,
+            DataType::VerbatimString { string, .. } => DataType::BulkString {
+                string: Bytes::from(string),
+            },
+            DataType::Double { number } => DataType::BulkString {
+                string: Bytes::from(format_double(number)),
+            },
+            DataType::BigNumber { number } => DataType::BulkString {
+                string: Bytes::from(number),
+            },
+            DataType::Boolean { value } => DataType::Integer {
+                number: value as isize,
+            },
+            DataType::Array { items } | DataType::Set { items } | DataType::Push { items } => {
+                DataType::Array {
+                    items: shape_items(items, protocol),
+                }
+            }
+            DataType::Map { items } => DataType::Array {
+                items: items
+                    .into_iter()
+                    .flat_map(|(field, value)| [field, value])
+                    .map(|item| item.for_protocol(protocol))
+                    .collect(),
+            },
+            other => other,
+        };
+    }
+}
+
+impl From<i64> for DataType {
+    fn from(number: i64) -> Self {
+        return DataType::Integer {
+            number: number as isize,
+        };
+    }
+}
+
+/// Strings are converted to Bulk Strings, which are binary safe, use
+/// `DataType::SimpleString` explicitly for status replies.
+impl From<&str> for DataType {
+    fn from(string: &str) -> Self {
+        return DataType::bulk(Bytes::copy_from_slice(string.as_bytes()));
+    }
+}
+
+impl From<String> for DataType {
+    fn from(string: String) -> Self {
+        return DataType::bulk(string);
+    }
+}
+
+impl From<Bytes> for DataType {
+    fn from(string: Bytes) -> Self {
+        return DataType::bulk(string);
+    }
+}
+
+impl From<Vec<DataType>> for DataType {
+    fn from(items: Vec<DataType>) -> Self {
+        return DataType::Array { items: items };
+    }
+}
+
+impl<T: Into<DataType>> From<Option<T>> for DataType {
+    /// None is converted to the RESP2 nil Bulk String.
+    fn from(value: Option<T>) -> Self {
+        return match value {
+            Some(value) => value.into(),
+            None => DataType::NullBulkString,
+        };
+    }
+}
+
+impl TryFrom<DataType> for Bytes {
+    type Error = ConversionError;
+
+    fn try_from(value: DataType) -> Result<Self, Self::Error> {
+        return match value {
+            DataType::BulkString { string } => Ok(string),
+            DataType::SimpleString { string } => Ok(Bytes::from(string)),
+            DataType::VerbatimString { string, .. } => Ok(Bytes::from(string)),
+            other => Err(ConversionError::UnexpectedType {
+                expected: "string",
+                got: other.type_name(),
+            }),
+        };
+    }
+}
+
+impl TryFrom<DataType> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: DataType) -> Result<Self, Self::Error> {
+        let bytes = Bytes::try_from(value)?;
+        return String::from_utf8(bytes.to_vec()).map_err(|_| ConversionError::InvalidUtf8);
+    }
+}
+
+/// Integers, and strings holding an integer like the ones sent as command arguments.
+impl TryFrom<DataType> for i64 {
+    type Error = ConversionError;
+
+    fn try_from(value: DataType) -> Result<Self, Self::Error> {
+        return match value {
+            DataType::Integer { number } => Ok(number as i64),
+            DataType::BulkString { string } => std::str::from_utf8(&string)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(ConversionError::InvalidInteger),
+            DataType::SimpleString { string } => {
+                string.parse().map_err(|_| ConversionError::InvalidInteger)
+            }
+            other => Err(ConversionError::UnexpectedType {
+                expected: "integer",
+                got: other.type_name(),
+            }),
+        };
+    }
+}
+
+impl TryFrom<DataType> for Vec<DataType> {
+    type Error = ConversionError;
+
+    fn try_from(value: DataType) -> Result<Self, Self::Error> {
+        return match value {
+            DataType::Array { items } | DataType::Set { items } | DataType::Push { items } => {
+                Ok(items)
+            }
+            other => Err(ConversionError::UnexpectedType {
+                expected: "array",
+                got: other.type_name(),
+            }),
+        };
+    }
+}
+
+/// Bulk Strings are serialized as strings when they are valid UTF-8, to keep them readable,
+/// and as sequences of bytes otherwise.
+#[cfg(feature = "serde")]
+mod bulk_serde {
+    use std::fmt;
+
+    use bytes::Bytes;
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(string: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        return match std::str::from_utf8(string) {
+            Ok(string) => serializer.serialize_str(string),
+            Err(_) => serializer.collect_seq(string.iter()),
+        };
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        return deserializer.deserialize_any(BulkVisitor);
+    }
+
+    struct BulkVisitor;
+
+    impl<'de> Visitor<'de> for BulkVisitor {
+        type Value = Bytes;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            return f.write_str("a string or a sequence of bytes");
+        }
+
+        fn visit_str<E: de::Error>(self, string: &str) -> Result<Bytes, E> {
+            return Ok(Bytes::copy_from_slice(string.as_bytes()));
         }
+
+        fn visit_string<E: de::Error>(self, string: String) -> Result<Bytes, E> {
+            return Ok(Bytes::from(string));
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Bytes, E> {
+            return Ok(Bytes::copy_from_slice(bytes));
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Bytes, E> {
+            return Ok(Bytes::from(bytes));
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element::<u8>()? {
+                bytes.push(byte);
+            }
+            return Ok(Bytes::from(bytes));
+        }
+    }
+}
+
+fn shape_items(items: Vec<DataType>, protocol: u8) -> Vec<DataType> {
+    return items
+        .into_iter()
+        .map(|item| item.for_protocol(protocol))
+        .collect();
+}
+
+/// Length of a type byte followed by `len` and CRLF.
+fn header_len(len: usize) -> usize {
+    return len.checked_ilog10().unwrap_or(0) as usize + 1 + 3;
+}
+
+/// Writes a type byte followed by `len` and CRLF.
+fn encode_header(buf: &mut impl BufMut, prefix: u8, len: usize) {
+    buf.put_u8(prefix);
+    encode_decimal(buf, len as u64);
+    buf.put_slice(b"\r\n");
+}
+
+/// Writes `number` in decimal without allocating a temporary string.
+fn encode_decimal(buf: &mut impl BufMut, mut number: u64) {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (number % 10) as u8;
+        number /= 10;
+        if number == 0 {
+            break;
+        }
+    }
+    buf.put_slice(&digits[start..]);
+}
+
+fn encode_integer(buf: &mut impl BufMut, number: isize) -> Result<()> {
+    buf.put_u8(b':');
+    if number < 0 {
+        buf.put_u8(b'-');
     }
+    encode_decimal(buf, number.unsigned_abs() as u64);
+    buf.put_slice(b"\r\n");
+    return Ok(());
 }
 
-fn encode_integer(number: isize) -> Result<Vec<u8>> {
-    let formatted = format!(":{number}\r\n");
-    return Ok(formatted.as_bytes().to_vec());
+fn format_double(number: f64) -> String {
+    if number.is_nan() {
+        return String::from("nan");
+    }
+    // infinities are formatted as `inf` and `-inf`
+    return number.to_string();
+}
+
+fn encode_double(buf: &mut impl BufMut, number: f64) -> Result<()> {
+    return encode_line(buf, b',', format_double(number).as_bytes());
+}
+
+fn encode_boolean(buf: &mut impl BufMut, value: bool) -> Result<()> {
+    buf.put_slice(if value { b"#t\r\n" } else { b"#f\r\n" });
+    return Ok(());
 }
 
-fn encode_simple_string(string: &String) -> Result<Vec<u8>> {
-    // TODO: Check string does not contain '\r\n'
-    let formatted = format!("+{string}\r\n");
-    return Ok(formatted.as_bytes().to_vec());
+/// Writes a type byte followed by `line` and CRLF.
+fn encode_line(buf: &mut impl BufMut, prefix: u8, line: &[u8]) -> Result<()> {
+    buf.put_u8(prefix);
+    buf.put_slice(line);
+    buf.put_slice(b"\r\n");
+    return Ok(());
 }
 
-fn encode_bulk_string(string: &String) -> Result<Vec<u8>> {
-    let formatted = format!("${}\r\n{}\r\n", string.len(), string);
-    return Ok(formatted.as_bytes().to_vec());
+fn encode_simple_string(buf: &mut impl BufMut, string: &String) -> Result<()> {
+    // a line break would end the string early, letting its contents be read as
+    // more replies by the client
+    if string.contains(['\r', '\n']) {
+        bail!("simple strings can't contain CR or LF");
+    }
+    return encode_line(buf, b'+', string.as_bytes());
 }
 
-fn encode_null_string() -> Result<Vec<u8>> {
-    return Ok("$-1\r\n".as_bytes().to_vec());
+fn encode_bulk_string(buf: &mut impl BufMut, string: &Bytes) -> Result<()> {
+    encode_header(buf, b'$', string.len());
+    buf.put_slice(string);
+    buf.put_slice(b"\r\n");
+    return Ok(());
 }
 
-fn encode_error(type_: &String, string: &String) -> Result<Vec<u8>> {
-    let mut ftype = type_.clone();
-    if !type_.eq("") {
-        ftype = format!("{type_} ")
+fn encode_verbatim_string(buf: &mut impl BufMut, format: &String, string: &String) -> Result<()> {
+    if format.len() != 3 {
+        bail!("verbatim string format must be 3 characters long");
     }
-    let formatted = format!("-{ftype}{string}\r\n");
-    return Ok(formatted.as_bytes().to_vec());
+    encode_header(buf, b'=', string.len() + 4);
+    buf.put_slice(format.as_bytes());
+    buf.put_u8(b':');
+    buf.put_slice(string.as_bytes());
+    buf.put_slice(b"\r\n");
+    return Ok(());
 }
 
-fn encode_array(items: &Vec<DataType>) -> Result<Vec<u8>> {
-    let mut buf = format!("*{}\r\n", items.len()).as_bytes().to_vec();
+fn encode_error(buf: &mut impl BufMut, type_: &String, string: &String) -> Result<()> {
+    buf.put_u8(b'-');
+    if !type_.is_empty() {
+        buf.put_slice(type_.as_bytes());
+        buf.put_u8(b' ');
+    }
+    buf.put_slice(string.as_bytes());
+    buf.put_slice(b"\r\n");
+    return Ok(());
+}
+
+/// Encodes the elements of an Array, Set or Push, prefixed by their number.
+fn encode_aggregate(buf: &mut impl BufMut, prefix: u8, items: &[DataType]) -> Result<()> {
+    encode_header(buf, prefix, items.len());
     for item in items {
-        let mut item_data = DataType::encode(&item)?;
-        buf.append(&mut item_data);
+        item.encode_buf(buf)?;
+    }
+    return Ok(());
+}
+
+fn encode_map(buf: &mut impl BufMut, items: &[(DataType, DataType)]) -> Result<()> {
+    encode_header(buf, b'%', items.len());
+    for (field, value) in items {
+        field.encode_buf(buf)?;
+        value.encode_buf(buf)?;
+    }
+    return Ok(());
+}
+
+/// proptest strategies generating random values, for round-trip tests of the decoders.
+///
+/// Errors are built with `DataType::from_error_line` so their prefix is split the way
+/// decoders split it, and Doubles are never NaN, which isn't equal to itself.
+#[cfg(test)]
+pub mod strategy {
+    use bytes::Bytes;
+    use proptest::prelude::*;
+
+    use super::DataType;
+
+    /// Values without nesting supported by every decoder.
+    pub fn resp2_scalar() -> BoxedStrategy<DataType> {
+        return prop_oneof![
+            "[^\r\n]*".prop_map(|string| DataType::SimpleString { string }),
+            "[^\r\n]*".prop_map(DataType::from_error_line),
+            any::<isize>().prop_map(|number| DataType::Integer { number }),
+            prop::collection::vec(any::<u8>(), 0..64)
+                .prop_map(|string| DataType::bulk(Bytes::from(string))),
+            Just(DataType::NullBulkString),
+            Just(DataType::NullArray),
+            ("[a-z]{3}", any::<String>())
+                .prop_map(|(format, string)| { DataType::VerbatimString { format, string } }),
+        ]
+        .boxed();
+    }
+
+    /// Values without nesting, including the RESP3 ones.
+    pub fn scalar() -> BoxedStrategy<DataType> {
+        return prop_oneof![
+            4 => resp2_scalar(),
+            1 => any::<f64>()
+                .prop_filter("NaN isn't equal to itself", |n| !n.is_nan())
+                .prop_map(|number| DataType::Double { number }),
+            1 => any::<bool>().prop_map(|value| DataType::Boolean { value }),
+            1 => "[-+]?[0-9]{1,40}".prop_map(|number| DataType::BigNumber { number }),
+            1 => Just(DataType::Null),
+        ]
+        .boxed();
+    }
+
+    /// Values nesting aggregates of the given scalars.
+    pub fn nested(scalar: BoxedStrategy<DataType>) -> BoxedStrategy<DataType> {
+        return scalar
+            .prop_recursive(4, 64, 8, |inner| {
+                let items = prop::collection::vec(inner.clone(), 0..8);
+                prop_oneof![
+                    items.clone().prop_map(|items| DataType::Array { items }),
+                    items.clone().prop_map(|items| DataType::Set { items }),
+                    items.prop_map(|items| DataType::Push { items }),
+                    prop::collection::vec((inner.clone(), inner), 0..4)
+                        .prop_map(|items| DataType::Map { items }),
+                ]
+            })
+            .boxed();
+    }
+
+    /// Any value.
+    pub fn datatype() -> BoxedStrategy<DataType> {
+        return nested(scalar());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{Bytes, BytesMut};
+
+    use super::{ConversionError, DataType, SafeRead, SafeReadError};
+
+    fn bulk(string: &str) -> DataType {
+        return DataType::BulkString {
+            string: Bytes::from(string.to_string()),
+        };
+    }
+
+    #[test]
+    fn test_read_line_crlf() {
+        let tests = &[
+            "Some string",
+            "Some string\nwith new\nlines",
+            "with multiple \r\r",
+        ];
+        for test in tests {
+            let mut bytes = Bytes::from(format!("{test}\r\n"));
+            assert_eq!(bytes.read_line_crlf().unwrap(), test.as_bytes());
+            assert!(bytes.is_empty());
+        }
+
+        let mut bytes = Bytes::from("Some string\r\nother string\r\n");
+        assert_eq!(bytes.read_line_crlf().unwrap(), "Some string");
+        assert_eq!(bytes, "other string\r\n");
+    }
+
+    #[test]
+    fn test_safe_read_incomplete() {
+        let mut bytes = Bytes::from("not finished");
+        let err = bytes.read_line_crlf().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&SafeReadError::Incomplete));
+        // nothing was consumed, so the read can be retried
+        assert_eq!(bytes, "not finished");
+
+        let err = bytes.get_slice_safe(20).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&SafeReadError::Incomplete));
+        assert_eq!(bytes.get_slice_safe(3).unwrap(), "not");
+        assert_eq!(bytes, " finished");
+    }
+
+    #[test]
+    fn test_read_integer_line() {
+        let mut bytes = Bytes::from("-123\r\n12a\r\n42");
+        assert_eq!(bytes.read_integer_line().unwrap(), -123);
+        let err = bytes.read_integer_line().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&SafeReadError::InvalidInteger));
+        bytes.read_line_crlf().unwrap();
+        let err = bytes.read_integer_line().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&SafeReadError::Incomplete));
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        let values = vec![
+            DataType::Integer { number: -1234 },
+            DataType::SimpleString {
+                string: String::from("OK"),
+            },
+            bulk(""),
+            bulk("hello world"),
+            DataType::NullBulkString,
+            DataType::NullArray,
+            DataType::Error {
+                type_: String::from("ERR"),
+                error: String::from("some error"),
+            },
+            DataType::Error {
+                type_: String::new(),
+                error: String::from("some error"),
+            },
+            DataType::Double { number: 1.5 },
+            DataType::Double { number: f64::NAN },
+            DataType::Boolean { value: false },
+            DataType::BigNumber {
+                number: String::from("-123456789012345678901234567890"),
+            },
+            DataType::Null,
+            DataType::VerbatimString {
+                format: String::from("txt"),
+                string: String::from("some text"),
+            },
+            DataType::Map {
+                items: vec![(bulk("field"), DataType::Integer { number: 10 })],
+            },
+            DataType::Set {
+                items: (0..10).map(|_| bulk("a")).collect(),
+            },
+        ];
+        for value in values {
+            assert_eq!(
+                value.encoded_len(),
+                value.encode().unwrap().len(),
+                "{value:?}"
+            );
+        }
+        let array = DataType::Array {
+            items: (0..1000).map(|_| bulk("x")).collect(),
+        };
+        assert_eq!(array.encoded_len(), array.encode().unwrap().len());
+    }
+
+    #[test]
+    fn test_encode_simple_string_line_breaks() {
+        for string in ["OK\r\n+INJECTED", "a\nb", "a\rb"] {
+            let value = DataType::SimpleString {
+                string: String::from(string),
+            };
+            assert!(value.encode().is_err(), "{string:?}");
+        }
+        let nested = DataType::Array {
+            items: vec![DataType::SimpleString {
+                string: String::from("x\r\n:1"),
+            }],
+        };
+        assert!(nested.encode().is_err());
+        assert!(nested.encode_into(&mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn test_from_error_line() {
+        let tests = [
+            ("ERR unknown command", "ERR", "unknown command"),
+            (
+                "WRONGTYPE Operation against a key",
+                "WRONGTYPE",
+                "Operation against a key",
+            ),
+            ("MOVED 3999 127.0.0.1:6381", "MOVED", "3999 127.0.0.1:6381"),
+            ("some error", "", "some error"),
+            ("Err lowercase prefix", "", "Err lowercase prefix"),
+            ("ERR", "", "ERR"),
+        ];
+        for (line, type_, error) in tests {
+            let parsed = DataType::from_error_line(String::from(line));
+            let expected = DataType::Error {
+                type_: String::from(type_),
+                error: String::from(error),
+            };
+            assert_eq!(parsed, expected);
+            // the prefix is composed back on encode
+            assert_eq!(parsed.encode().unwrap(), format!("-{line}\r\n").as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encode_to() {
+        // big enough to be streamed to the writer
+        let value = DataType::Array {
+            items: vec![
+                DataType::Map {
+                    items: vec![(bulk("field"), bulk(&"v".repeat(70_000)))],
+                },
+                DataType::Push {
+                    items: vec![DataType::Integer { number: 1 }, DataType::Null],
+                },
+                bulk("end"),
+            ],
+        };
+        let mut scratch = BytesMut::new();
+        let mut buf = Vec::new();
+        value.encode_to(&mut buf, &mut scratch).await.unwrap();
+        assert_eq!(buf, value.encode().unwrap());
+
+        let mut buf = Vec::new();
+        bulk("small")
+            .encode_to(&mut buf, &mut scratch)
+            .await
+            .unwrap();
+        assert_eq!(buf, b"$5\r\nsmall\r\n");
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let mut buf = BytesMut::new();
+        DataType::Integer { number: -42 }
+            .encode_into(&mut buf)
+            .unwrap();
+        bulk("hello").encode_into(&mut buf).unwrap();
+        DataType::Integer { number: 0 }
+            .encode_into(&mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], b":-42\r\n$5\r\nhello\r\n:0\r\n");
+
+        let capacity = buf.capacity();
+        buf.clear();
+        bulk("hi").encode_into(&mut buf).unwrap();
+        assert_eq!(&buf[..], b"$2\r\nhi\r\n");
+        assert_eq!(buf.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_constructors() {
+        assert_eq!(DataType::ok().encode().unwrap(), b"+OK\r\n");
+        assert_eq!(DataType::bulk("value"), bulk("value"));
+        assert_eq!(
+            DataType::error("WRONGTYPE", "bad type").encode().unwrap(),
+            b"-WRONGTYPE bad type\r\n"
+        );
+        assert_eq!(DataType::from(-5), DataType::Integer { number: -5 });
+        assert_eq!(DataType::from("value"), bulk("value"));
+        assert_eq!(DataType::from(None::<&str>), DataType::NullBulkString);
+        assert_eq!(
+            DataType::from(vec![DataType::from(1), DataType::from("a")]),
+            DataType::Array {
+                items: vec![DataType::Integer { number: 1 }, bulk("a")]
+            }
+        );
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(String::try_from(bulk("value")), Ok(String::from("value")));
+        assert_eq!(
+            String::try_from(DataType::bulk(&b"\xff"[..])),
+            Err(ConversionError::InvalidUtf8)
+        );
+        assert_eq!(
+            Bytes::try_from(DataType::ok()),
+            Ok(Bytes::from_static(b"OK"))
+        );
+        assert_eq!(i64::try_from(DataType::from(42)), Ok(42));
+        assert_eq!(i64::try_from(bulk("-7")), Ok(-7));
+        assert_eq!(
+            i64::try_from(bulk("seven")),
+            Err(ConversionError::InvalidInteger)
+        );
+        let err = i64::try_from(DataType::NullBulkString).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected null reply, expected integer");
+        assert_eq!(
+            Vec::<DataType>::try_from(DataType::from(vec![bulk("a")])),
+            Ok(vec![bulk("a")])
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let value = DataType::Array {
+            items: vec![
+                DataType::ok(),
+                bulk("value"),
+                DataType::bulk(&b"\xff\x00"[..]),
+                DataType::error("ERR", "bad"),
+                DataType::Map {
+                    items: vec![(bulk("field"), DataType::from(1))],
+                },
+                DataType::NullBulkString,
+            ],
+        };
+        let json = serde_json::to_value(&value).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "array",
+                "items": [
+                    {"type": "simple_string", "string": "OK"},
+                    {"type": "bulk_string", "string": "value"},
+                    {"type": "bulk_string", "string": [255, 0]},
+                    {"type": "error", "prefix": "ERR", "error": "bad"},
+                    {"type": "map", "items": [[
+                        {"type": "bulk_string", "string": "field"},
+                        {"type": "integer", "number": 1},
+                    ]]},
+                    {"type": "null_bulk_string"},
+                ],
+            })
+        );
+        assert_eq!(serde_json::from_value::<DataType>(json).unwrap(), value);
     }
-    return Ok(buf);
 }
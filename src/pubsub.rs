@@ -0,0 +1,247 @@
+/// Publish/subscribe, modeled after Redis' `pubsub.c`.
+///
+/// Clients subscribe to channels, or to glob-style patterns of channel names, and
+/// receive the messages published to them as out-of-band messages: `message` and
+/// `pmessage` Arrays for RESP2 clients, Pushes for RESP3 ones. RESP2 clients with
+/// subscriptions are in subscribed mode, where only the commands managing
+/// subscriptions can be run, so messages can't be confused with replies.
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use bytes::Bytes;
+
+use crate::clients::ClientId;
+use crate::glob;
+use crate::protocol::DataType;
+use crate::state::State;
+
+/// Channels and patterns a client subscribed to.
+#[derive(Debug, Default)]
+struct Subscriptions {
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+}
+
+#[derive(Default)]
+pub struct PubSub {
+    /// clients subscribed to each channel
+    channels: HashMap<String, HashSet<ClientId>>,
+    /// clients subscribed to each pattern
+    patterns: HashMap<String, HashSet<ClientId>>,
+    subscriptions: HashMap<ClientId, Subscriptions>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        return PubSub::default();
+    }
+
+    /// Subscribes `client` to `channel`, or to the channels matching it if `pattern`.
+    /// Returns how many subscriptions the client has now.
+    pub fn subscribe(&mut self, client: ClientId, channel: &str, pattern: bool) -> usize {
+        let table = match pattern {
+            true => &mut self.patterns,
+            false => &mut self.channels,
+        };
+        table.entry(channel.to_string()).or_default().insert(client);
+        let subscriptions = self.subscriptions.entry(client).or_default();
+        match pattern {
+            true => subscriptions.patterns.insert(channel.to_string()),
+            false => subscriptions.channels.insert(channel.to_string()),
+        };
+        return self.count(client);
+    }
+
+    /// Unsubscribes `client` from `channel` (or the pattern). Returns how many
+    /// subscriptions the client has left.
+    pub fn unsubscribe(&mut self, client: ClientId, channel: &str, pattern: bool) -> usize {
+        let table = match pattern {
+            true => &mut self.patterns,
+            false => &mut self.channels,
+        };
+        if let Some(clients) = table.get_mut(channel) {
+            clients.remove(&client);
+            if clients.is_empty() {
+                table.remove(channel);
+            }
+        }
+        if let Some(subscriptions) = self.subscriptions.get_mut(&client) {
+            match pattern {
+                true => subscriptions.patterns.remove(channel),
+                false => subscriptions.channels.remove(channel),
+            };
+            if subscriptions.channels.is_empty() && subscriptions.patterns.is_empty() {
+                self.subscriptions.remove(&client);
+            }
+        }
+        return self.count(client);
+    }
+
+    /// Channels (or patterns) `client` is subscribed to.
+    pub fn subscribed(&self, client: ClientId, pattern: bool) -> Vec<String> {
+        let subscriptions = match self.subscriptions.get(&client) {
+            Some(subscriptions) => subscriptions,
+            None => return Vec::new(),
+        };
+        let channels = match pattern {
+            true => &subscriptions.patterns,
+            false => &subscriptions.channels,
+        };
+        return channels.iter().cloned().collect();
+    }
+
+    /// Number of channels and patterns `client` is subscribed to.
+    pub fn count(&self, client: ClientId) -> usize {
+        return self
+            .subscriptions
+            .get(&client)
+            .map(|s| s.channels.len() + s.patterns.len())
+            .unwrap_or(0);
+    }
+
+    /// true if `client` subscribed to `channel` itself, patterns aside.
+    pub fn is_subscribed(&self, client: ClientId, channel: &str) -> bool {
+        return self
+            .channels
+            .get(channel)
+            .is_some_and(|clients| clients.contains(&client));
+    }
+
+    /// Forgets every subscription of `client`.
+    pub fn remove_client(&mut self, client: ClientId) {
+        let subscriptions = match self.subscriptions.remove(&client) {
+            Some(subscriptions) => subscriptions,
+            None => return,
+        };
+        for (table, channels) in [
+            (&mut self.channels, subscriptions.channels),
+            (&mut self.patterns, subscriptions.patterns),
+        ] {
+            for channel in channels {
+                if let Some(clients) = table.get_mut(&channel) {
+                    clients.remove(&client);
+                    if clients.is_empty() {
+                        table.remove(&channel);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Clients receiving the messages published to `channel`, along with the pattern
+    /// they matched it with. Clients subscribed through several of them are listed
+    /// once for each.
+    fn receivers(&self, channel: &str) -> Vec<(ClientId, Option<String>)> {
+        let mut receivers: Vec<(ClientId, Option<String>)> = Vec::new();
+        if let Some(clients) = self.channels.get(channel) {
+            receivers.extend(clients.iter().map(|client| (*client, None)));
+        }
+        for (pattern, clients) in &self.patterns {
+            if glob::matches(pattern.as_bytes(), channel.as_bytes(), false) {
+                receivers.extend(
+                    clients
+                        .iter()
+                        .map(|client| (*client, Some(pattern.clone()))),
+                );
+            }
+        }
+        return receivers;
+    }
+}
+
+/// Sends `message` to the subscribers of `channel`, returning how many received it.
+pub fn publish(state: &State, channel: &str, message: Bytes) -> usize {
+    let receivers = state.pubsub.lock().unwrap().receivers(channel);
+    for (client, pattern) in &receivers {
+        let items = match pattern {
+            Some(pattern) => vec![
+                DataType::bulk("pmessage"),
+                DataType::bulk(pattern.clone()),
+                DataType::bulk(channel.to_string()),
+                DataType::bulk(message.clone()),
+            ],
+            None => vec![
+                DataType::bulk("message"),
+                DataType::bulk(channel.to_string()),
+                DataType::bulk(message.clone()),
+            ],
+        };
+        state.clients.with_client(*client, |c| {
+            c.send(DataType::Push { items: items }.for_protocol(c.protocol))
+        });
+    }
+    return receivers.len();
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{publish, PubSub};
+    use crate::config::Config;
+    use crate::protocol::DataType;
+    use crate::state::StateInner;
+
+    #[test]
+    fn test_subscriptions() {
+        let mut pubsub = PubSub::new();
+        assert_eq!(pubsub.subscribe(1, "a", false), 1);
+        assert_eq!(pubsub.subscribe(1, "a", false), 1);
+        assert_eq!(pubsub.subscribe(1, "a*", true), 2);
+        assert_eq!(pubsub.subscribe(2, "a", false), 1);
+        assert!(pubsub.is_subscribed(1, "a"));
+        assert!(!pubsub.is_subscribed(1, "ab"));
+        assert_eq!(pubsub.subscribed(1, true), vec!["a*"]);
+        assert_eq!(pubsub.receivers("ab"), vec![(1, Some(String::from("a*")))]);
+
+        assert_eq!(pubsub.unsubscribe(1, "a", false), 1);
+        assert_eq!(pubsub.unsubscribe(1, "nope", false), 1);
+        assert_eq!(pubsub.receivers("a").len(), 2);
+        pubsub.remove_client(1);
+        assert_eq!(pubsub.count(1), 0);
+        assert_eq!(pubsub.receivers("a"), vec![(2, None)]);
+        assert_eq!(pubsub.unsubscribe(2, "a", false), 0);
+        assert!(pubsub.channels.is_empty());
+        assert!(pubsub.subscriptions.is_empty());
+    }
+
+    #[test]
+    fn test_publish() {
+        let state = StateInner::new(Config::default());
+        let mut resp2 = state.connect_client().unwrap();
+        let mut resp3 = state.connect_client().unwrap();
+        state.clients.with_client(resp3.id, |c| c.protocol = 3);
+        {
+            let mut pubsub = state.pubsub.lock().unwrap();
+            pubsub.subscribe(resp2.id, "news", false);
+            pubsub.subscribe(resp3.id, "n*", true);
+        }
+
+        assert_eq!(publish(&state, "news", Bytes::from("hi")), 2);
+        assert_eq!(
+            resp2.messages.try_recv().unwrap(),
+            DataType::Array {
+                items: vec![
+                    DataType::bulk("message"),
+                    DataType::bulk("news"),
+                    DataType::bulk("hi")
+                ]
+            }
+        );
+        assert_eq!(
+            resp3.messages.try_recv().unwrap(),
+            DataType::Push {
+                items: vec![
+                    DataType::bulk("pmessage"),
+                    DataType::bulk("n*"),
+                    DataType::bulk("news"),
+                    DataType::bulk("hi")
+                ]
+            }
+        );
+        assert_eq!(publish(&state, "other", Bytes::from("hi")), 0);
+
+        // subscriptions are dropped with the connection
+        drop(resp2);
+        assert_eq!(publish(&state, "news", Bytes::from("hi")), 1);
+    }
+}
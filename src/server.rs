@@ -0,0 +1,1263 @@
+/// The server: binds the listening sockets, accepts connections and serves them
+/// until shut down, either by a termination signal or through a `ShutdownHandle`.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use redis_starter_rust::config::Config;
+/// use redis_starter_rust::Server;
+///
+/// let server = Server::new(Config::default())
+///     .with_address("127.0.0.1:7000".parse()?)
+///     .with_decoder_version(3);
+/// let shutdown = server.shutdown_handle();
+/// tokio::spawn(async move {
+///     tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+///     shutdown.shutdown();
+/// });
+/// let exit_code = server.run().await?;
+/// # Ok(())
+/// # }
+/// ```
+use crate::clients::ClientId;
+use crate::codec::RespCodec;
+use crate::config::{Config, MAXCLIENTS_ERROR, PROTECTED_MODE_ERROR};
+use crate::db;
+use crate::decoders;
+use crate::decoders::v1::{Decoder, ScanError};
+use crate::decoders::v2::{ParseError, StreamDecoder};
+use crate::decoders::v3::ChunkDecoder;
+use crate::engine::Engine;
+use crate::errors::ReplyError;
+use crate::http::{self, HttpError};
+use crate::latency;
+#[cfg(feature = "memcached")]
+use crate::memcached;
+use crate::output::LimitedWriter;
+use crate::process;
+use crate::protocol::DataType;
+use crate::shutdown::{self, Shutdown, ShutdownHandle, DRAIN_TIMEOUT, EXIT_DRAIN_TIMEOUT, EXIT_OK};
+use crate::state::{ConnectedClient, State, StateInner};
+use crate::stats::{CountedStream, METRICS_SAMPLE_INTERVAL};
+#[cfg(feature = "otel")]
+use crate::telemetry::Telemetry;
+use crate::tracking;
+use crate::uring::{self, IoBackend};
+use crate::websocket::{self, WebSocket};
+
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+use socket2::{SockRef, TcpKeepalive};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+#[cfg(feature = "memcached")]
+use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::codec::FramedRead;
+
+/// Decoder used by the connections unless told otherwise.
+pub const DEFAULT_DECODER_VERSION: u8 = 2;
+
+/// Connections waiting to be accepted on each listening socket.
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// Packets parsed ahead of the command being executed on each connection.
+const PIPELINE_QUEUE_LEN: usize = 128;
+
+pub struct Server {
+    config: Config,
+    decoder_version: u8,
+    shutdown: ShutdownHandle,
+    /// whether SIGINT and SIGTERM shut the server down
+    handle_signals: bool,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        return Server {
+            config: config,
+            decoder_version: DEFAULT_DECODER_VERSION,
+            shutdown: ShutdownHandle::new(),
+            handle_signals: true,
+        };
+    }
+
+    /// Listens on `address` instead of the `bind` and `port` of the configuration.
+    pub fn with_address(mut self, address: SocketAddr) -> Self {
+        self.config.bind = address.ip();
+        self.config.port = address.port();
+        return self;
+    }
+
+    /// Decodes the input of the connections with `decoders::v1` (1), `decoders::v2` (2),
+    /// `decoders::v3` (3) or `decoders::v3` driven by tokio_util (4).
+    pub fn with_decoder_version(mut self, version: u8) -> Self {
+        self.decoder_version = version;
+        return self;
+    }
+
+    /// A handle to shut the server down from another task.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        return self.shutdown.clone();
+    }
+
+    /// Serves connections until the process receives SIGINT or SIGTERM, or the server
+    /// is shut down through a `ShutdownHandle`. Returns the exit code for the process:
+    /// `EXIT_OK` if every connection drained in time.
+    ///
+    /// With the io_uring backend it must run on the runtime of `tokio_uring::start`,
+    /// see `run_blocking`.
+    pub async fn run(self) -> Result<i32> {
+        let listeners = self.bind()?;
+        return Ok(self.serve(listeners).await);
+    }
+
+    /// Like `run`, on a runtime of its own: tokio's multi-threaded one, or the
+    /// single-threaded runtime of tokio-uring with the io_uring backend.
+    pub fn run_blocking(self) -> Result<i32> {
+        #[cfg(feature = "io-uring")]
+        if self.config.io_backend == IoBackend::Uring {
+            return tokio_uring::start(self.run());
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        return runtime.block_on(self.run());
+    }
+
+    /// Starts the server in a new task, returning the address it listens on and a
+    /// guard shutting it down when dropped. Unlike `run` it ignores termination
+    /// signals, they are left to the program embedding it.
+    pub fn spawn(mut self) -> Result<(SocketAddr, ShutdownGuard)> {
+        self.handle_signals = false;
+        let listeners = self.bind()?;
+        let address = listeners[0].0.local_addr()?;
+        let io_backend = self.config.io_backend;
+        let guard = ShutdownGuard {
+            handle: self.shutdown_handle(),
+            server: Some(uring::spawn(io_backend, self.serve(listeners))),
+        };
+        return Ok((address, guard));
+    }
+
+    /// Starts a server with the default configuration on a random port of the
+    /// loopback interface, for tests to run against a real server in-process:
+    ///
+    /// ```no_run
+    /// # async fn example() -> anyhow::Result<()> {
+    /// let (address, _server) = redis_starter_rust::Server::spawn_ephemeral()?;
+    /// let stream = tokio::net::TcpStream::connect(address).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn_ephemeral() -> Result<(SocketAddr, ShutdownGuard)> {
+        let address = SocketAddr::from(([127, 0, 0, 1], 0));
+        return Server::new(Config::default()).with_address(address).spawn();
+    }
+
+    /// Binds the listening sockets, the ones of the acceptors first.
+    fn bind(&self) -> Result<Vec<(Arc<TcpListener>, Protocol)>> {
+        if !(1..=4).contains(&self.decoder_version) {
+            bail!("unknown decoder version {}", self.decoder_version);
+        }
+        #[cfg(not(feature = "io-uring"))]
+        if self.config.io_backend == IoBackend::Uring {
+            bail!("the io_uring backend needs the io-uring feature");
+        }
+        #[cfg(not(feature = "otel"))]
+        if !self.config.otel_endpoint.is_empty() {
+            bail!("exporting spans needs the otel feature");
+        }
+        let resp = Protocol::Resp(self.decoder_version);
+        let mut listeners: Vec<(Arc<TcpListener>, Protocol)> = bind_listeners(&self.config)?
+            .into_iter()
+            .map(|listener| (listener, resp))
+            .collect();
+        if let Some(address) = self.config.websocket_address() {
+            let listener = bind_socket(address, false)?;
+            println!("websocket listener started at {}", listener.local_addr()?);
+            listeners.push((Arc::new(listener), Protocol::WebSocket));
+        }
+        if let Some(address) = self.config.http_address() {
+            let listener = bind_socket(address, false)?;
+            println!("http gateway started at {}", listener.local_addr()?);
+            listeners.push((Arc::new(listener), Protocol::Http));
+        }
+        #[cfg(not(feature = "memcached"))]
+        if self.config.memcached_address().is_some() {
+            bail!("the memcached listener needs the memcached feature");
+        }
+        #[cfg(feature = "memcached")]
+        if let Some(address) = self.config.memcached_address() {
+            let listener = bind_socket(address, false)?;
+            println!("memcached listener started at {}", listener.local_addr()?);
+            listeners.push((Arc::new(listener), Protocol::Memcached));
+        }
+        return Ok(listeners);
+    }
+
+    async fn serve(self, listeners: Vec<(Arc<TcpListener>, Protocol)>) -> i32 {
+        let Server {
+            config,
+            shutdown,
+            handle_signals,
+            ..
+        } = self;
+        match listeners[0].0.local_addr() {
+            Ok(address) => println!(
+                "server started at {} with {} acceptors",
+                address, config.acceptors
+            ),
+            Err(err) => println!("server started at an unknown address: {}", err),
+        }
+        let pidfile = config.pidfile.clone();
+        if !pidfile.is_empty() {
+            if let Err(err) = process::write_pidfile(&pidfile) {
+                println!("failed to write pidfile {}: {}", pidfile, err);
+            }
+        }
+        let supervised = config.supervised;
+        if let Err(err) = process::notify_ready(supervised) {
+            println!("failed to notify readiness: {}", err);
+        }
+        let io_backend = config.io_backend;
+        let state = StateInner::new(config);
+        #[cfg(feature = "otel")]
+        start_telemetry(&state);
+        let engine = Engine::start(&state);
+        let sampler = tokio::spawn(sample_metrics(state.clone()));
+        let expirer = tokio::spawn(active_expire(state.clone()));
+
+        // dropping `notify_shutdown` tells every client task to stop
+        let (notify_shutdown, _) = broadcast::channel::<()>(1);
+        // every client task holds a clone of `shutdown_complete_tx`, the channel
+        // closes once all of them have finished
+        let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+
+        let acceptors: Vec<JoinHandle<()>> = listeners
+            .into_iter()
+            .map(|(listener, protocol)| {
+                let acceptor = accept_connections(
+                    listener,
+                    state.clone(),
+                    engine.clone(),
+                    notify_shutdown.clone(),
+                    shutdown_complete_tx.clone(),
+                    protocol,
+                );
+                uring::spawn(io_backend, acceptor)
+            })
+            .collect();
+        let signal = async {
+            if !handle_signals {
+                return std::future::pending().await;
+            }
+            return shutdown::wait_for_signal().await;
+        };
+        tokio::select! {
+            signal = signal => println!("received {}, shutting down", signal),
+            _ = shutdown.wait() => println!("shutting down"),
+        }
+
+        // stop accepting connections and wait for the running ones to drain
+        process::notify_stopping(supervised);
+        state.blocked.unblock_all();
+        for acceptor in acceptors {
+            // the acceptor drops its listener and its handles on the shutdown channels
+            acceptor.abort();
+            let _ = acceptor.await;
+        }
+        drop(notify_shutdown);
+        drop(shutdown_complete_tx);
+        let code = match tokio::time::timeout(DRAIN_TIMEOUT, shutdown_complete_rx.recv()).await {
+            Ok(_) => EXIT_OK,
+            Err(_) => {
+                println!("timed out waiting for connections to close");
+                EXIT_DRAIN_TIMEOUT
+            }
+        };
+        sampler.abort();
+        expirer.abort();
+        #[cfg(feature = "otel")]
+        stop_telemetry(state).await;
+        if !pidfile.is_empty() {
+            process::remove_pidfile(&pidfile);
+        }
+        println!("server stopped");
+        return code;
+    }
+}
+
+/// Shuts down a server started with `Server::spawn` when dropped.
+pub struct ShutdownGuard {
+    handle: ShutdownHandle,
+    server: Option<JoinHandle<i32>>,
+}
+
+impl ShutdownGuard {
+    /// Shuts the server down and waits for its connections to drain, returning its
+    /// exit code.
+    pub async fn stop(mut self) -> i32 {
+        self.handle.shutdown();
+        let server = self.server.take().expect("server already stopped");
+        return server.await.unwrap_or(EXIT_DRAIN_TIMEOUT);
+    }
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        if self.server.is_some() {
+            self.handle.shutdown();
+        }
+    }
+}
+
+/// What the clients of a listener speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    /// RESP, read with the given decoder version
+    Resp(u8),
+    /// RESP in the frames of WebSocket connections
+    WebSocket,
+    /// commands in HTTP requests, replies rendered as JSON
+    Http,
+    /// the memcached text protocol
+    #[cfg(feature = "memcached")]
+    Memcached,
+}
+
+fn bind_socket(address: SocketAddr, reuseport: bool) -> std::io::Result<TcpListener> {
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    if reuseport {
+        socket.set_reuseport(true)?;
+    }
+    socket.bind(address)?;
+    return socket.listen(LISTEN_BACKLOG);
+}
+
+/// Binds the listening sockets of the acceptors. With `reuseport` every acceptor
+/// gets its own socket, otherwise they all share a single one.
+fn bind_listeners(config: &Config) -> std::io::Result<Vec<Arc<TcpListener>>> {
+    let bind = |address: SocketAddr| bind_socket(address, config.reuseport);
+    let first = Arc::new(bind(config.listen_address())?);
+    if !config.reuseport {
+        return Ok(vec![first; config.acceptors]);
+    }
+    // the rest bind to the port picked for the first one, in case it was 0
+    let address = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..config.acceptors {
+        listeners.push(Arc::new(bind(address)?));
+    }
+    return Ok(listeners);
+}
+
+/// Accepts connections on `listener` and spawns a task handling each one, until aborted.
+async fn accept_connections(
+    listener: Arc<TcpListener>,
+    state: State,
+    engine: Engine,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+    protocol: Protocol,
+) {
+    let io_backend = state.config.read().unwrap().io_backend;
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, peer)) => {
+                if state.config.read().unwrap().protected_mode_denies(&peer) {
+                    let err = ReplyError::Denied(PROTECTED_MODE_ERROR.to_string());
+                    tokio::spawn(refuse_connection(stream, err));
+                    continue;
+                }
+                stream
+            }
+            Err(err) => {
+                println!("error accepting connection: {}", err);
+                continue;
+            }
+        };
+        if let Err(err) = configure_socket(&stream, &state.config.read().unwrap()) {
+            println!("error configuring connection: {}", err);
+        }
+        let client = state.connect_client();
+        state.stats.record_connection(client.is_none());
+        let client = match client {
+            Some(client) => client,
+            None => {
+                let err = ReplyError::Err(MAXCLIENTS_ERROR.to_string());
+                tokio::spawn(refuse_connection(stream, err));
+                continue;
+            }
+        };
+        let state = state.clone();
+        let engine = engine.clone();
+        let shutdown = Shutdown::new(notify_shutdown.subscribe());
+        let done = shutdown_complete_tx.clone();
+        uring::spawn(io_backend, async move {
+            let result = match protocol {
+                Protocol::Resp(1) => {
+                    handle_client_v1(stream, state, engine, client, shutdown).await
+                }
+                #[cfg(feature = "io-uring")]
+                Protocol::Resp(version @ 2..=4) if io_backend == IoBackend::Uring => {
+                    handle_client_uring(stream, state, engine, client, shutdown, version).await
+                }
+                Protocol::Resp(version @ 2..=4) => {
+                    let (rh, wh) = stream.into_split();
+                    handle_client_stream(rh, wh, state, engine, client, shutdown, version).await
+                }
+                Protocol::Resp(version) => panic!("unkown client {}", version),
+                Protocol::WebSocket => {
+                    handle_client_websocket(stream, state, engine, client, shutdown).await
+                }
+                Protocol::Http => handle_client_http(stream, state, engine, client, shutdown).await,
+                #[cfg(feature = "memcached")]
+                Protocol::Memcached => {
+                    handle_client_memcached(stream, state, client, shutdown).await
+                }
+            };
+            if let Err(err) = result {
+                eprintln!("connection error: {err}");
+            }
+            drop(done);
+        });
+    }
+}
+
+/// starts exporting the spans of the commands, if `otel-endpoint` is set
+#[cfg(feature = "otel")]
+fn start_telemetry(state: &State) {
+    let endpoint = state.config.read().unwrap().otel_endpoint.clone();
+    if endpoint.is_empty() {
+        return;
+    }
+    match Telemetry::start(&endpoint) {
+        Ok(telemetry) => {
+            let _ = state.telemetry.set(telemetry);
+            println!("exporting spans to {}", endpoint);
+        }
+        Err(err) => println!("failed to export spans to {}: {}", endpoint, err),
+    }
+}
+
+/// exports the spans left. The exporter runs on the runtime, so it's waited for
+/// on a thread of its own
+#[cfg(feature = "otel")]
+async fn stop_telemetry(state: State) {
+    let stop = tokio::task::spawn_blocking(move || {
+        if let Some(telemetry) = state.telemetry.get() {
+            telemetry.shutdown();
+        }
+    });
+    let _ = stop.await;
+}
+
+/// periodically samples the counters behind the instantaneous metrics of INFO stats
+async fn sample_metrics(state: State) {
+    let mut interval = tokio::time::interval(METRICS_SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.stats.sample_metrics();
+    }
+}
+
+/// removes expired keys in the background, waking up when the nearest one expires
+async fn active_expire(state: State) {
+    loop {
+        let start = Instant::now();
+        let now = state.databases[0].now();
+        let expired: Vec<(Bytes, db::DBValue)> = state
+            .databases
+            .iter()
+            .flat_map(|keyspace| keyspace.remove_expired(now, db::ACTIVE_EXPIRE_LIMIT))
+            .collect();
+        if !expired.is_empty() {
+            let (threshold, lazy) = {
+                let config = state.config.read().unwrap();
+                (
+                    config.latency_monitor_threshold,
+                    config.lazyfree_lazy_expire,
+                )
+            };
+            let (keys, values): (Vec<Bytes>, Vec<db::DBValue>) = expired.into_iter().unzip();
+            for value in values {
+                state.lazyfree.free(value, lazy);
+            }
+            state.stats.record_expired(keys.len());
+            tracking::invalidate_keys(&state, &keys, None);
+            state.latency.add_sample_if_needed(
+                latency::EVENT_EXPIRE_CYCLE,
+                start.elapsed().as_millis() as u64,
+                threshold,
+            );
+        }
+        // keys set to expire sooner while sleeping are removed on the next run
+        let next_expiration = state
+            .databases
+            .iter()
+            .filter_map(|keyspace| keyspace.next_expiration())
+            .min();
+        let wait = match next_expiration {
+            Some(expiration) => Duration::from_millis(
+                expiration.saturating_sub(state.databases[0].now()).max(1) as u64,
+            ),
+            None => db::ACTIVE_EXPIRE_INTERVAL,
+        };
+        tokio::time::sleep(wait.min(db::ACTIVE_EXPIRE_INTERVAL)).await;
+    }
+}
+
+/// applies the TCP options from the configuration to an accepted connection
+fn configure_socket(stream: &TcpStream, config: &Config) -> std::io::Result<()> {
+    stream.set_nodelay(config.tcp_nodelay)?;
+    if config.tcp_keepalive > 0 {
+        // like Redis, send probes every third of the period once the connection is idle
+        let period = Duration::from_secs(config.tcp_keepalive);
+        let keepalive = TcpKeepalive::new()
+            .with_time(period)
+            .with_interval((period / 3).max(Duration::from_secs(1)));
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    return Ok(());
+}
+
+/// replies an error to a connection that won't be served and closes it
+async fn refuse_connection(mut stream: TcpStream, err: ReplyError) {
+    if let Ok(bytes) = DataType::from(err).encode() {
+        let _ = stream.write_all(bytes.as_slice()).await;
+    }
+}
+
+/// Reply sent to clients whose input can't be decoded, right before closing their connection.
+fn protocol_error(state: &State, err: &anyhow::Error) -> DataType {
+    println!("protocol error: {err}");
+    state.stats.record_error("ERR");
+    return DataType::from(ReplyError::Err(format!("Protocol error: {err}")));
+}
+
+/// Writer of the replies to a normal client, disconnecting it if it doesn't read them.
+fn limited_writer<W>(wh: W, state: &State) -> LimitedWriter<CountedStream<W>> {
+    let (limit, write_timeout) = {
+        let config = state.config.read().unwrap();
+        (
+            config.client_output_buffer_limit.normal,
+            config.write_timeout(),
+        )
+    };
+    let wh = CountedStream::new(wh, state.clone());
+    return LimitedWriter::new(wh, state.clone(), limit, write_timeout);
+}
+
+/// handles connection using decoders::v1
+///
+/// The connection isn't read while executing commands, so a client disconnecting
+/// while blocked stays blocked until its command times out or is served.
+async fn handle_client_v1(
+    stream: TcpStream,
+    state: State,
+    engine: Engine,
+    mut client: ConnectedClient,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    println!("accepted new connection");
+    let (rh, wh) = stream.into_split();
+    let mut reader = BufReader::new(CountedStream::new(rh, state.clone()));
+    let mut wh = BufWriter::new(limited_writer(wh, &state));
+    // replies are encoded here, reusing its memory
+    let mut scratch = BytesMut::new();
+    let buffer_size = state.config.read().unwrap().read_buffer_size;
+    let mut decoder = Decoder::new(&mut reader).with_buffer_size(buffer_size);
+    while !shutdown.is_shutdown() {
+        let packets = tokio::select! {
+            res = decoder.parse() => match res {
+                Ok(packets) => packets,
+                Err(err) => match err.downcast_ref() {
+                    Some(ScanError::StreamClosed) => break,
+                    _ => {
+                        let response = protocol_error(&state, &err);
+                        response.encode_to(&mut wh, &mut scratch).await?;
+                        break;
+                    }
+                },
+            },
+            _ = shutdown.recv() => break,
+        };
+        for packet in packets {
+            if decoders::is_empty_command(&packet) {
+                continue;
+            }
+            let response = engine.dispatch(packet, &state, client.id).await;
+            // messages sent while executing the command go before its reply
+            while let Ok(message) = client.messages.try_recv() {
+                message.encode_to(&mut wh, &mut scratch).await?;
+            }
+            response.encode_to(&mut wh, &mut scratch).await?;
+        }
+        // the v1 decoder can't be interrupted mid-command, so other out-of-band
+        // messages are only written after replying to the client
+        while let Ok(message) = client.messages.try_recv() {
+            message.encode_to(&mut wh, &mut scratch).await?;
+        }
+        wh.flush().await?;
+    }
+    wh.flush().await?;
+    println!("done");
+    Ok(())
+}
+
+/// handles connection using the stream based decoders, decoders::v2 or decoders::v3
+async fn handle_client_stream(
+    rh: impl AsyncRead + Unpin,
+    wh: impl AsyncWrite + Unpin,
+    state: State,
+    engine: Engine,
+    client: ConnectedClient,
+    shutdown: Shutdown,
+    decoder_version: u8,
+) -> Result<()> {
+    println!("accepted new connection");
+    let mut rh = CountedStream::new(rh, state.clone());
+    let wh = limited_writer(wh, &state);
+    let (limits, buffer_size, spool_threshold, recovery) = {
+        let config = state.config.read().unwrap();
+        (
+            config.decoder_limits(),
+            config.read_buffer_size,
+            config.spool_threshold(),
+            config.proto_error_recovery,
+        )
+    };
+    if decoder_version == 3 {
+        // v3 buffers the data itself
+        let mut decoder = ChunkDecoder::new(&mut rh)
+            .with_limits(limits)
+            .with_buffer_size(buffer_size);
+        return handle_packets(decoder.as_stream(), wh, state, engine, client, shutdown).await;
+    }
+    if decoder_version == 4 {
+        // v3's parser driven by tokio_util, without read timeouts
+        let codec = RespCodec::new().with_limits(limits);
+        let framed = FramedRead::with_capacity(rh, codec, buffer_size);
+        return handle_packets(framed, wh, state, engine, client, shutdown).await;
+    }
+    let mut reader = BufReader::new(rh);
+    let mut decoder = StreamDecoder::new(&mut reader)
+        .with_limits(limits)
+        .with_buffer_size(buffer_size)
+        .with_spool_threshold(spool_threshold)
+        .with_recovery(recovery);
+    return handle_packets(decoder.as_stream(), wh, state, engine, client, shutdown).await;
+}
+
+/// handles connection using the stream based decoders, with its socket driven by
+/// io_uring: the connection reads and writes the pipe `uring::attach` copies the
+/// socket through
+#[cfg(feature = "io-uring")]
+async fn handle_client_uring(
+    stream: TcpStream,
+    state: State,
+    engine: Engine,
+    client: ConnectedClient,
+    shutdown: Shutdown,
+    decoder_version: u8,
+) -> Result<()> {
+    let (rh, wh) = tokio::io::split(uring::attach(stream)?);
+    return handle_client_stream(rh, wh, state, engine, client, shutdown, decoder_version).await;
+}
+
+/// handles a WebSocket connection, decoding the RESP input with decoders::v3
+async fn handle_client_websocket(
+    stream: TcpStream,
+    state: State,
+    engine: Engine,
+    client: ConnectedClient,
+    shutdown: Shutdown,
+) -> Result<()> {
+    println!("accepted new websocket connection");
+    let (rh, wh) = stream.into_split();
+    let mut rh = CountedStream::new(rh, state.clone());
+    let mut wh = CountedStream::new(wh, state.clone());
+    let (limits, buffer_size) = {
+        let config = state.config.read().unwrap();
+        (config.decoder_limits(), config.read_buffer_size)
+    };
+    let buffered = websocket::handshake(&mut rh, &mut wh).await?;
+    // frames are buffered whole before being decoded, they are limited like bulk strings
+    let max_frame_len = limits.max_bulk_len;
+    let (mut input, mut output, websocket) = WebSocket::start(rh, wh, buffered, max_frame_len);
+    let mut decoder = ChunkDecoder::new(&mut input)
+        .with_limits(limits)
+        .with_buffer_size(buffer_size);
+    let result = handle_packets(
+        decoder.as_stream(),
+        &mut output,
+        state,
+        engine,
+        client,
+        shutdown,
+    )
+    .await;
+    websocket.close(websocket::CLOSE_GOING_AWAY).await;
+    return result;
+}
+
+/// handles a connection to the HTTP gateway, running the command of each request
+async fn handle_client_http(
+    stream: TcpStream,
+    state: State,
+    engine: Engine,
+    client: ConnectedClient,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    println!("accepted new http connection");
+    let (rh, wh) = stream.into_split();
+    let mut rh = CountedStream::new(rh, state.clone());
+    let mut wh = CountedStream::new(wh, state.clone());
+    let max_body_len = state.config.read().unwrap().decoder_limits().max_bulk_len;
+    let mut buffer = BytesMut::new();
+    while !shutdown.is_shutdown() {
+        let request = tokio::select! {
+            res = http::read_request(&mut rh, &mut buffer, max_body_len) => res,
+            _ = shutdown.recv() => break,
+        };
+        let request = match request {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(err) => {
+                if let Some(err) = err.downcast_ref::<HttpError>() {
+                    let response = http::error_response(err, false);
+                    wh.write_all(response.as_bytes()).await?;
+                }
+                return Err(err);
+            }
+        };
+        let response = match http::route(&request) {
+            Ok(command) => {
+                let reply = engine.dispatch(command, &state, client.id).await;
+                http::reply_response(&reply, request.keep_alive)
+            }
+            Err(err) => http::error_response(&err, request.keep_alive),
+        };
+        wh.write_all(response.as_bytes()).await?;
+        if !request.keep_alive {
+            break;
+        }
+    }
+    println!("done");
+    Ok(())
+}
+
+/// handles a connection speaking the memcached text protocol. Its requests operate
+/// on the keyspace directly, on the connection's task, whatever the engine.
+#[cfg(feature = "memcached")]
+async fn handle_client_memcached(
+    stream: TcpStream,
+    state: State,
+    _client: ConnectedClient,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    println!("accepted new memcached connection");
+    let (rh, wh) = stream.into_split();
+    let mut rh = CountedStream::new(rh, state.clone());
+    let mut wh = CountedStream::new(wh, state.clone());
+    let (max_value_len, buffer_size) = {
+        let config = state.config.read().unwrap();
+        (
+            config.decoder_limits().max_bulk_len,
+            config.read_buffer_size,
+        )
+    };
+    let mut input = BytesMut::with_capacity(buffer_size);
+    let mut output = BytesMut::new();
+    while !shutdown.is_shutdown() {
+        loop {
+            match memcached::parse_request(&mut input, max_value_len) {
+                Ok(Some(memcached::Request::Quit)) => {
+                    wh.write_all(&output).await?;
+                    return Ok(());
+                }
+                Ok(Some(request)) => memcached::execute(request, &state, &mut output),
+                Ok(None) => break,
+                Err(err) => {
+                    output.extend_from_slice(format!("{err}\r\n").as_bytes());
+                    if err.is_fatal() {
+                        wh.write_all(&output).await?;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        wh.write_all(&output).await?;
+        output.clear();
+        let read = tokio::select! {
+            res = rh.read_buf(&mut input) => res?,
+            _ = shutdown.recv() => break,
+        };
+        if read == 0 {
+            break;
+        }
+    }
+    println!("done");
+    Ok(())
+}
+
+/// dispatches the packets parsed by a stream based decoder, writing back the responses.
+///
+/// The decoder keeps parsing packets into a bounded queue while the previous ones
+/// are executed and their replies written, both running concurrently on the
+/// connection's task. Replies are buffered and only flushed once the queue is empty,
+/// so the replies to a pipeline of commands are sent with as few writes as possible.
+async fn handle_packets(
+    packets: impl Stream<Item = Result<DataType>>,
+    wh: impl AsyncWrite + Unpin,
+    state: State,
+    engine: Engine,
+    client: ConnectedClient,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let (parsed, queue) = mpsc::channel(PIPELINE_QUEUE_LEN);
+    let reader = read_packets(packets, parsed, state.clone(), client.id);
+    let executor = execute_packets(queue, wh, state, engine, client, shutdown);
+    let ((), result) = tokio::join!(reader, executor);
+    return result;
+}
+
+/// sends the packets parsed by the decoder to `parsed`, until the stream ends or
+/// the receiving end is closed. A command blocking `client` is then unblocked, as
+/// nobody is left to read its reply.
+async fn read_packets(
+    packets: impl Stream<Item = Result<DataType>>,
+    parsed: mpsc::Sender<Result<DataType>>,
+    state: State,
+    client: ClientId,
+) {
+    let mut stream = Box::pin(packets);
+    loop {
+        let packet = tokio::select! {
+            packet = stream.next() => packet,
+            _ = parsed.closed() => break,
+        };
+        let packet = match packet {
+            Some(Ok(packet)) if decoders::is_empty_command(&packet) => continue,
+            Some(packet) => packet,
+            None => break,
+        };
+        if parsed.send(packet).await.is_err() {
+            break;
+        }
+    }
+    state.blocked.unblock(client);
+}
+
+/// executes the packets received from `queue`, writing back the responses
+async fn execute_packets(
+    mut queue: mpsc::Receiver<Result<DataType>>,
+    wh: impl AsyncWrite + Unpin,
+    state: State,
+    engine: Engine,
+    mut client: ConnectedClient,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let mut wh = BufWriter::new(wh);
+    // replies are encoded here, reusing its memory
+    let mut scratch = BytesMut::new();
+    while !shutdown.is_shutdown() {
+        let packet = match queue.try_recv() {
+            Ok(packet) => Some(packet),
+            Err(mpsc::error::TryRecvError::Disconnected) => None,
+            Err(mpsc::error::TryRecvError::Empty) => {
+                // the decoder needs more input, send everything written so far
+                while let Ok(message) = client.messages.try_recv() {
+                    message.encode_to(&mut wh, &mut scratch).await?;
+                }
+                wh.flush().await?;
+                tokio::select! {
+                    packet = queue.recv() => packet,
+                    Some(message) = client.messages.recv() => {
+                        message.encode_to(&mut wh, &mut scratch).await?;
+                        continue;
+                    }
+                    _ = shutdown.recv() => break,
+                }
+            }
+        };
+        let packet = match packet {
+            Some(packet) => packet,
+            None => break,
+        };
+        match packet {
+            Ok(dt) => {
+                let response = engine.dispatch(dt, &state, client.id).await;
+                // messages sent while executing the command, like the confirmations
+                // of all but the last channel of SUBSCRIBE, go before its reply
+                while let Ok(message) = client.messages.try_recv() {
+                    message.encode_to(&mut wh, &mut scratch).await?;
+                }
+                response.encode_to(&mut wh, &mut scratch).await?;
+            }
+            Err(e) => match e.downcast_ref() {
+                Some(ParseError::StreamClosed) => break,
+                _ => {
+                    // the decoder ends the stream unless it can recover from the error
+                    let response = protocol_error(&state, &e);
+                    response.encode_to(&mut wh, &mut scratch).await?;
+                }
+            },
+        }
+    }
+    wh.flush().await?;
+    println!("done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::sync::broadcast;
+
+    use socket2::SockRef;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{
+        accept_connections, bind_listeners, bind_socket, configure_socket, handle_packets,
+        Protocol, PIPELINE_QUEUE_LEN,
+    };
+    use crate::config::Config;
+    use crate::engine::Engine;
+    use crate::protocol::DataType;
+    use crate::shutdown::Shutdown;
+    use crate::state::StateInner;
+    use crate::websocket::{self, OPCODE_BINARY, OPCODE_CLOSE, OPCODE_CONTINUATION};
+
+    /// Records every write done to it.
+    #[derive(Clone, Default)]
+    struct RecordingWriter {
+        writes: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.writes.lock().unwrap().push(buf.to_vec());
+            return Poll::Ready(Ok(buf.len()));
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            return Poll::Ready(Ok(()));
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            return Poll::Ready(Ok(()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_configure_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = || async {
+            let (_client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+            return accepted.unwrap().0;
+        };
+
+        let stream = accept().await;
+        configure_socket(&stream, &Config::default()).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(stream.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(300));
+        assert_eq!(
+            socket.keepalive_interval().unwrap(),
+            Duration::from_secs(100)
+        );
+
+        let mut config = Config::default();
+        config.set("tcp-nodelay", "no").unwrap();
+        config.set("tcp-keepalive", "0").unwrap();
+        let stream = accept().await;
+        configure_socket(&stream, &config).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_replies_are_batched() {
+        let state = StateInner::new(Config::default());
+        let client = state.connect_client().unwrap();
+        let engine = Engine::start(&state);
+        let (_notify, shutdown) = broadcast::channel(1);
+        let ping = DataType::Array {
+            items: vec![DataType::from("PING")],
+        };
+        // unlike tokio_stream::iter, never yields to the runtime between packets
+        let packets = async_stream::stream! {
+            for _ in 0..100 {
+                yield Ok(ping.clone());
+            }
+        };
+        let writer = RecordingWriter::default();
+        let shutdown = Shutdown::new(shutdown);
+        handle_packets(packets, writer.clone(), state, engine, client, shutdown)
+            .await
+            .unwrap();
+        let writes = writer.writes.lock().unwrap();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0], b"+PONG\r\n".repeat(100));
+    }
+
+    #[tokio::test]
+    async fn test_reading_overlaps_writing() {
+        let state = StateInner::new(Config::default());
+        let client = state.connect_client().unwrap();
+        let engine = Engine::start(&state);
+        let (_notify, shutdown) = broadcast::channel(1);
+        let parsed = Arc::new(AtomicUsize::new(0));
+        let counter = parsed.clone();
+        let packets = async_stream::stream! {
+            loop {
+                counter.fetch_add(1, Ordering::SeqCst);
+                yield Ok(DataType::Array { items: vec![DataType::from("PING")] });
+                tokio::task::yield_now().await;
+            }
+        };
+        // nobody reads the replies, so the first flush blocks forever
+        let (wh, _rh) = tokio::io::duplex(8);
+        let shutdown = Shutdown::new(shutdown);
+        let handler = handle_packets(packets, wh, state, engine, client, shutdown);
+        let res = tokio::time::timeout(Duration::from_millis(100), handler).await;
+        assert!(res.is_err());
+        // packets kept being parsed until the queue filled up
+        assert!(parsed.load(Ordering::SeqCst) >= PIPELINE_QUEUE_LEN);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_commands_dont_close_the_connection() {
+        let state = StateInner::new(Config::default());
+        let client = state.connect_client().unwrap();
+        let engine = Engine::start(&state);
+        let (_notify, shutdown) = broadcast::channel(1);
+        let command = |args: &[&str]| DataType::Array {
+            items: args.iter().map(|arg| DataType::from(*arg)).collect(),
+        };
+        let packets = tokio_stream::iter(vec![
+            Ok(command(&["NOPE"])),
+            Ok(command(&["SET", "key", "value", "PX", "soon"])),
+            Ok(DataType::Integer { number: 1 }),
+            Ok(command(&["PING"])),
+        ]);
+        let writer = RecordingWriter::default();
+        let shutdown = Shutdown::new(shutdown);
+        handle_packets(packets, writer.clone(), state, engine, client, shutdown)
+            .await
+            .unwrap();
+        let written = writer.writes.lock().unwrap().concat();
+        let replies = String::from_utf8(written).unwrap();
+        assert_eq!(replies.matches("-ERR").count(), 3, "{replies}");
+        assert!(replies.ends_with("+PONG\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_bind_listeners() {
+        let mut config = Config {
+            port: 0,
+            acceptors: 3,
+            ..Default::default()
+        };
+        let listeners = bind_listeners(&config).unwrap();
+        assert_eq!(listeners.len(), 3);
+        assert!(Arc::ptr_eq(&listeners[0], &listeners[2]));
+
+        config.reuseport = true;
+        let listeners = bind_listeners(&config).unwrap();
+        assert_eq!(listeners.len(), 3);
+        assert!(!Arc::ptr_eq(&listeners[0], &listeners[2]));
+        let address = listeners[0].local_addr().unwrap();
+        assert_eq!(listeners[2].local_addr().unwrap(), address);
+        // every connection is queued on one of the sockets
+        let _client = tokio::net::TcpStream::connect(address).await.unwrap();
+        let mut accepted = 0;
+        for listener in &listeners {
+            let wait = Duration::from_millis(100);
+            if let Ok(res) = tokio::time::timeout(wait, listener.accept()).await {
+                res.unwrap();
+                accepted += 1;
+            }
+        }
+        assert_eq!(accepted, 1);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_connections() {
+        let state = StateInner::new(Config::default());
+        let engine = Engine::start(&state);
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (done, _) = tokio::sync::mpsc::channel(1);
+        let listener = Arc::new(bind_socket("127.0.0.1:0".parse().unwrap(), false).unwrap());
+        let address = listener.local_addr().unwrap();
+        let protocol = Protocol::WebSocket;
+        let acceptor = accept_connections(listener, state, engine, notify_shutdown, done, protocol);
+        tokio::spawn(acceptor);
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let request = "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut input = BytesMut::new();
+        let read = async {
+            while !input.ends_with(b"\r\n\r\n") {
+                stream.read_buf(&mut input).await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap();
+        assert!(input.starts_with(b"HTTP/1.1 101"));
+
+        // commands can be split between frames, and a frame can hold several
+        let mut frames = BytesMut::new();
+        let ping = b"*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n";
+        websocket::encode_frame(OPCODE_BINARY, &ping[..6], Some([1, 2, 3, 4]), &mut frames);
+        frames[0] &= 0x7f;
+        let mask = Some([5, 6, 7, 8]);
+        websocket::encode_frame(OPCODE_CONTINUATION, &ping[6..], mask, &mut frames);
+        stream.write_all(&frames).await.unwrap();
+        let mut output = BytesMut::new();
+        let read = async {
+            while output.len() < 2 + 15 {
+                stream.read_buf(&mut output).await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap();
+        assert_eq!(&output[..], b"\x82\x0f+PONG\r\n$2\r\nhi\r\n");
+
+        let mut close = BytesMut::new();
+        websocket::encode_frame(OPCODE_CLOSE, &[0x03, 0xe8], Some([0; 4]), &mut close);
+        stream.write_all(&close).await.unwrap();
+        let mut output = Vec::new();
+        let read = stream.read_to_end(&mut output);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(output, b"\x88\x02\x03\xe8");
+    }
+
+    #[tokio::test]
+    async fn test_http_gateway() {
+        let state = StateInner::new(Config::default());
+        let engine = Engine::start(&state);
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (done, _) = tokio::sync::mpsc::channel(1);
+        let listener = Arc::new(bind_socket("127.0.0.1:0".parse().unwrap(), false).unwrap());
+        let address = listener.local_addr().unwrap();
+        let protocol = Protocol::Http;
+        let acceptor = accept_connections(listener, state, engine, notify_shutdown, done, protocol);
+        tokio::spawn(acceptor);
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let body = r#"["SET", "a key", "value"]"#;
+        let requests = format!(
+            "POST /command HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}\
+             GET /keys/a%20key HTTP/1.1\r\n\r\n\
+             GET /nope HTTP/1.1\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(requests.as_bytes()).await.unwrap();
+        let mut output = Vec::new();
+        let read = stream.read_to_end(&mut output);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap()
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let responses: Vec<&str> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].starts_with("200 OK"));
+        assert!(responses[0].ends_with(r#"{"type":"simple_string","string":"OK"}"#));
+        assert!(responses[1].ends_with(r#"{"type":"bulk_string","string":"value"}"#));
+        assert!(responses[2].starts_with("404 Not Found"));
+        assert!(responses[2].contains("Connection: close"));
+    }
+
+    #[cfg(not(feature = "memcached"))]
+    #[tokio::test]
+    async fn test_memcached_listener_needs_the_feature() {
+        let mut config = Config::default();
+        config.set("port", "0").unwrap();
+        config.set("memcached-port", "11211").unwrap();
+        let err = super::Server::new(config).run().await.unwrap_err();
+        assert!(err.to_string().contains("memcached feature"), "{err}");
+    }
+
+    #[cfg(not(feature = "io-uring"))]
+    #[tokio::test]
+    async fn test_io_uring_needs_the_feature() {
+        let mut config = Config::default();
+        config.set("port", "0").unwrap();
+        config.set("io-backend", "uring").unwrap();
+        let err = super::Server::new(config).run().await.unwrap_err();
+        assert!(err.to_string().contains("io-uring feature"), "{err}");
+    }
+
+    #[cfg(not(feature = "otel"))]
+    #[tokio::test]
+    async fn test_spans_need_the_feature() {
+        let mut config = Config::default();
+        config.set("port", "0").unwrap();
+        config
+            .set("otel-endpoint", "http://localhost:4317")
+            .unwrap();
+        let err = super::Server::new(config).run().await.unwrap_err();
+        assert!(err.to_string().contains("otel feature"), "{err}");
+    }
+
+    #[cfg(feature = "memcached")]
+    #[tokio::test]
+    async fn test_memcached_connections() {
+        let state = StateInner::new(Config::default());
+        let engine = Engine::start(&state);
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (done, _) = tokio::sync::mpsc::channel(1);
+        let listener = Arc::new(bind_socket("127.0.0.1:0".parse().unwrap(), false).unwrap());
+        let address = listener.local_addr().unwrap();
+        let protocol = Protocol::Memcached;
+        let acceptor = accept_connections(listener, state, engine, notify_shutdown, done, protocol);
+        tokio::spawn(acceptor);
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"set key 1 0 5\r\nhel").await.unwrap();
+        stream
+            .write_all(b"lo\r\nget key\r\nquit\r\n")
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        let read = stream.read_to_end(&mut output);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(output, b"STORED\r\nVALUE key 1 5\r\nhello\r\nEND\r\n");
+    }
+}
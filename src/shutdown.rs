@@ -0,0 +1,124 @@
+/// Graceful shutdown support.
+///
+/// The accept loop owns a `broadcast::Sender<()>`; every client task receives a
+/// `Shutdown` subscribed to it. When a termination signal arrives the sender is
+/// dropped, which wakes every `Shutdown::recv` so connections can stop after the
+/// command in flight and flush their pending writes.
+///
+/// Draining uses the `mpsc` trick: each client task holds a clone of a `Sender`
+/// that is never used to send. Once all tasks are done the channel closes and the
+/// main task's `recv` resolves to `None`.
+use std::sync::Arc;
+
+use tokio::signal;
+use tokio::sync::{broadcast, Notify};
+
+/// Exit code used when the server stops after draining every connection.
+pub const EXIT_OK: i32 = 0;
+
+/// Exit code used when some connections were still running after `DRAIN_TIMEOUT`.
+pub const EXIT_DRAIN_TIMEOUT: i32 = 1;
+
+/// Maximum time to wait for client tasks to finish after a shutdown signal.
+pub const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Listens for the server shutdown notification.
+#[derive(Debug)]
+pub struct Shutdown {
+    /// `true` once the shutdown notification has been received.
+    is_shutdown: bool,
+    notify: broadcast::Receiver<()>,
+}
+
+impl Shutdown {
+    pub fn new(notify: broadcast::Receiver<()>) -> Self {
+        return Shutdown {
+            is_shutdown: false,
+            notify: notify,
+        };
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        return self.is_shutdown;
+    }
+
+    /// Waits for the shutdown notification. Returns immediately if it was already received.
+    pub async fn recv(&mut self) {
+        if self.is_shutdown {
+            return;
+        }
+        // the sender is dropped to signal shutdown, so an error is the expected outcome
+        let _ = self.notify.recv().await;
+        self.is_shutdown = true;
+    }
+}
+
+/// Shuts a `Server` down from outside of it, as a termination signal would.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    notify: Arc<Notify>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        return ShutdownHandle::default();
+    }
+
+    /// Tells the server to shut down. If it isn't running yet, it shuts down as soon
+    /// as it starts.
+    pub fn shutdown(&self) {
+        // the permit is stored until the server waits for it
+        self.notify.notify_one();
+    }
+
+    /// Waits for `shutdown` to be called.
+    pub async fn wait(&self) {
+        self.notify.notified().await;
+    }
+}
+
+/// Resolves when the process receives SIGINT (ctrl-c) or SIGTERM.
+pub async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = signal::ctrl_c() => "SIGINT",
+            _ = sigterm.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install ctrl-c handler");
+        "SIGINT"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::broadcast;
+
+    use super::{Shutdown, ShutdownHandle};
+
+    #[tokio::test]
+    async fn test_recv_after_sender_dropped() {
+        let (tx, rx) = broadcast::channel(1);
+        let mut shutdown = Shutdown::new(rx);
+        assert!(!shutdown.is_shutdown());
+        drop(tx);
+        shutdown.recv().await;
+        assert!(shutdown.is_shutdown());
+        // subsequent calls return immediately
+        shutdown.recv().await;
+    }
+
+    #[tokio::test]
+    async fn test_handle_shutdown_before_waiting() {
+        let handle = ShutdownHandle::new();
+        handle.clone().shutdown();
+        handle.wait().await;
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "otel")]
+use std::sync::OnceLock;
+use std::sync::{Arc, Mutex, RwLock};
+
+use tokio::sync::mpsc;
+
+use crate::blocked::BlockedClients;
+use crate::clients::{ClientId, Clients};
+use crate::clock::{self, Clock};
+use crate::config::Config;
+use crate::db::Keyspace;
+use crate::latency::LatencyMonitor;
+use crate::lazyfree::LazyFree;
+use crate::protocol::DataType;
+use crate::pubsub::PubSub;
+use crate::stats::Stats;
+#[cfg(feature = "otel")]
+use crate::telemetry::Telemetry;
+use crate::tracking::TrackingTable;
+
+/// Server wide state shared by every connection.
+///
+/// The state is guarded by std locks rather than async ones: they are only taken in
+/// synchronous code, never held across an await (clippy's `await_holding_lock` is
+/// denied), and only for short critical sections, so a contended lock stalls an
+/// executor thread for about as long as the command holding it runs. Locks are
+/// never taken while holding one that comes later in this order:
+///
+/// 1. `config`
+/// 2. the shards of `databases`, in ascending order of database and shard
+/// 3. `tracking`
+/// 4. `pubsub`
+/// 5. the clients table of `clients`
+///
+/// The locks inside `latency`, `stats` and `blocked` are never held while taking
+/// another one.
+/// Values are handed to `lazyfree` after releasing the lock of their shard.
+pub struct StateInner {
+    /// keyspace of each database, see `keyspace`
+    pub databases: Vec<Keyspace>,
+    pub config: RwLock<Config>,
+    pub latency: LatencyMonitor,
+    pub stats: Stats,
+    /// number of clients currently connected
+    pub connected_clients: AtomicUsize,
+    pub clients: Clients,
+    pub tracking: Mutex<TrackingTable>,
+    pub pubsub: Mutex<PubSub>,
+    pub blocked: Arc<BlockedClients>,
+    pub lazyfree: LazyFree,
+    /// exports the spans of the commands, once started by the server
+    #[cfg(feature = "otel")]
+    pub telemetry: OnceLock<Telemetry>,
+}
+
+pub type State = Arc<StateInner>;
+
+impl StateInner {
+    pub fn new(config: Config) -> State {
+        return StateInner::with_clock(config, clock::system());
+    }
+
+    /// A state whose keyspace tells the time with `clock`.
+    pub fn with_clock(config: Config, clock: Arc<dyn Clock>) -> State {
+        return Arc::new(StateInner {
+            databases: Keyspace::databases(config.databases, config.keyspace_shards, clock),
+            config: RwLock::new(config),
+            latency: LatencyMonitor::new(),
+            stats: Stats::new(),
+            connected_clients: AtomicUsize::new(0),
+            clients: Clients::new(),
+            tracking: Mutex::new(TrackingTable::new()),
+            pubsub: Mutex::new(PubSub::new()),
+            blocked: Arc::new(BlockedClients::new()),
+            lazyfree: LazyFree::new(),
+            #[cfg(feature = "otel")]
+            telemetry: OnceLock::new(),
+        });
+    }
+
+    /// Keyspace of the database selected by `client`. Database 0 for clients that
+    /// aren't connected, like the ones of the HTTP and memcached listeners.
+    pub fn keyspace(&self, client: ClientId) -> &Keyspace {
+        return &self.databases[self.selected_db(client)];
+    }
+
+    /// Index of the database selected by `client`, see `keyspace`.
+    pub fn selected_db(&self, client: ClientId) -> usize {
+        return self.clients.with_client(client, |c| c.db).unwrap_or(0);
+    }
+
+    /// Registers a new client connection, unless `maxclients` are already connected.
+    /// The client is unregistered when the returned guard is dropped.
+    pub fn connect_client(self: &Arc<Self>) -> Option<ConnectedClient> {
+        let maxclients = self.config.read().unwrap().maxclients;
+        let registered =
+            self.connected_clients
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |clients| {
+                    (clients < maxclients).then_some(clients + 1)
+                });
+        if registered.is_err() {
+            return None;
+        }
+        let (id, messages) = self.clients.register();
+        return Some(ConnectedClient {
+            state: self.clone(),
+            id: id,
+            messages: messages,
+        });
+    }
+}
+
+/// A registered client connection, see `StateInner::connect_client`.
+pub struct ConnectedClient {
+    state: State,
+    pub id: ClientId,
+    /// out-of-band messages to write to the connection
+    pub messages: mpsc::UnboundedReceiver<DataType>,
+}
+
+impl Drop for ConnectedClient {
+    fn drop(&mut self) {
+        self.state.tracking.lock().unwrap().remove_client(self.id);
+        self.state.pubsub.lock().unwrap().remove_client(self.id);
+        self.state.blocked.forget(self.id);
+        self.state.clients.unregister(self.id);
+        self.state.connected_clients.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use super::StateInner;
+    use crate::config::Config;
+
+    #[test]
+    fn test_maxclients() {
+        let state = StateInner::new(Config {
+            maxclients: 2,
+            ..Default::default()
+        });
+        let first = state.connect_client().unwrap();
+        let second = state.connect_client().unwrap();
+        assert!(state.connect_client().is_none());
+        drop(first);
+        assert_eq!(state.connected_clients.load(Ordering::SeqCst), 1);
+        assert!(state.connect_client().is_some());
+        drop(second);
+        assert_eq!(state.connected_clients.load(Ordering::SeqCst), 0);
+    }
+}
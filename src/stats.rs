@@ -0,0 +1,507 @@
+/// Server statistics reported by INFO.
+///
+/// Command statistics are updated by the dispatch layer: every executed command
+/// accounts its call count and duration, commands refused before running (wrong
+/// arity, out of memory) count as rejected and commands replying with an error as
+/// failed. Every error reply is also counted by its prefix (ERR, OOM, ...).
+/// Commands reading keys account whether the key was found in the keyspace.
+///
+/// Instantaneous metrics (ops/sec and network throughput) are computed from
+/// samples of the cumulative counters taken every `METRICS_SAMPLE_INTERVAL`
+/// by `sample_metrics`, averaging the rates observed in the last samples.
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::output::OutputError;
+use crate::state::State;
+
+/// How often the instantaneous metrics are sampled.
+pub const METRICS_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of samples averaged by the instantaneous metrics.
+const METRICS_SAMPLES: usize = 16;
+
+/// Rate of change of a cumulative counter, averaged over the last samples.
+struct InstantaneousMetric {
+    last_time: Instant,
+    last_value: u64,
+    /// rates per second of the last samples
+    samples: [f64; METRICS_SAMPLES],
+    idx: usize,
+}
+
+impl InstantaneousMetric {
+    fn new() -> Self {
+        return InstantaneousMetric {
+            last_time: Instant::now(),
+            last_value: 0,
+            samples: [0.0; METRICS_SAMPLES],
+            idx: 0,
+        };
+    }
+
+    fn sample(&mut self, now: Instant, value: u64) {
+        let elapsed = now.duration_since(self.last_time).as_secs_f64();
+        if elapsed > 0.0 {
+            let rate = value.saturating_sub(self.last_value) as f64 / elapsed;
+            self.samples[self.idx] = rate;
+            self.idx = (self.idx + 1) % METRICS_SAMPLES;
+        }
+        self.last_time = now;
+        self.last_value = value;
+    }
+
+    fn per_second(&self) -> f64 {
+        return self.samples.iter().sum::<f64>() / METRICS_SAMPLES as f64;
+    }
+}
+
+struct InstantaneousMetrics {
+    ops: InstantaneousMetric,
+    net_input: InstantaneousMetric,
+    net_output: InstantaneousMetric,
+}
+
+/// Percentiles of the command latency reported by INFO latencystats.
+pub const LATENCY_PERCENTILES: &[f64] = &[50.0, 99.0, 99.9];
+
+/// Linear sub-buckets per power of two in the latency histogram, which bounds
+/// the error of the reported percentiles to ~6%.
+const SUB_BUCKETS: u64 = 16;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+const HISTOGRAM_LEN: usize = ((64 - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS) as usize;
+
+/// Histogram of command durations in microseconds, with logarithmic buckets.
+struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+fn bucket_index(usec: u64) -> usize {
+    if usec < SUB_BUCKETS {
+        return usec as usize;
+    }
+    let msb = 63 - usec.leading_zeros();
+    let shift = msb - SUB_BUCKET_BITS;
+    let sub = (usec >> shift) & (SUB_BUCKETS - 1);
+    return ((shift as u64 + 1) * SUB_BUCKETS + sub) as usize;
+}
+
+/// Lowest value that falls in bucket `ix`.
+fn bucket_value(ix: usize) -> u64 {
+    let ix = ix as u64;
+    if ix < SUB_BUCKETS {
+        return ix;
+    }
+    let shift = ix / SUB_BUCKETS - 1;
+    let sub = ix % SUB_BUCKETS;
+    return (SUB_BUCKETS + sub) << shift;
+}
+
+impl LatencyHistogram {
+    fn new() -> Self {
+        return LatencyHistogram {
+            counts: vec![0; HISTOGRAM_LEN],
+            total: 0,
+        };
+    }
+
+    fn record(&mut self, usec: u64) {
+        self.counts[bucket_index(usec)] += 1;
+        self.total += 1;
+    }
+
+    /// Approximate value below which `percentile`% of the samples fall.
+    fn percentile(&self, percentile: f64) -> u64 {
+        let target = ((percentile / 100.0) * self.total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (ix, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return bucket_value(ix);
+            }
+        }
+        return 0;
+    }
+}
+
+struct CommandStat {
+    calls: u64,
+    usec: u64,
+    rejected_calls: u64,
+    failed_calls: u64,
+    latency: LatencyHistogram,
+}
+
+impl CommandStat {
+    fn new() -> Self {
+        return CommandStat {
+            calls: 0,
+            usec: 0,
+            rejected_calls: 0,
+            failed_calls: 0,
+            latency: LatencyHistogram::new(),
+        };
+    }
+}
+
+pub struct Stats {
+    commands: Mutex<BTreeMap<&'static str, CommandStat>>,
+    errors: Mutex<BTreeMap<String, u64>>,
+    keyspace_hits: AtomicU64,
+    keyspace_misses: AtomicU64,
+    total_connections_received: AtomicU64,
+    rejected_connections: AtomicU64,
+    total_commands_processed: AtomicU64,
+    total_net_input_bytes: AtomicU64,
+    total_net_output_bytes: AtomicU64,
+    expired_keys: AtomicU64,
+    evicted_keys: AtomicU64,
+    client_output_buffer_limit_disconnections: AtomicU64,
+    client_write_timeout_disconnections: AtomicU64,
+    metrics: Mutex<InstantaneousMetrics>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        return Stats {
+            commands: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
+            total_connections_received: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            total_commands_processed: AtomicU64::new(0),
+            total_net_input_bytes: AtomicU64::new(0),
+            total_net_output_bytes: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            client_output_buffer_limit_disconnections: AtomicU64::new(0),
+            client_write_timeout_disconnections: AtomicU64::new(0),
+            metrics: Mutex::new(InstantaneousMetrics {
+                ops: InstantaneousMetric::new(),
+                net_input: InstantaneousMetric::new(),
+                net_output: InstantaneousMetric::new(),
+            }),
+        };
+    }
+
+    /// Accounts an execution of `command` that took `usec` microseconds.
+    pub fn record_call(&self, command: &'static str, usec: u64, failed: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        let stat = commands.entry(command).or_insert_with(CommandStat::new);
+        self.total_commands_processed
+            .fetch_add(1, Ordering::Relaxed);
+        stat.calls += 1;
+        stat.usec += usec;
+        stat.latency.record(usec);
+        if failed {
+            stat.failed_calls += 1;
+        }
+    }
+
+    /// Accounts a call to `command` refused before it was executed.
+    pub fn record_rejected(&self, command: &'static str) {
+        let mut commands = self.commands.lock().unwrap();
+        let stat = commands.entry(command).or_insert_with(CommandStat::new);
+        stat.rejected_calls += 1;
+    }
+
+    /// Accounts a call to `command` that failed while parsing its arguments.
+    pub fn record_failed(&self, command: &'static str) {
+        let mut commands = self.commands.lock().unwrap();
+        let stat = commands.entry(command).or_insert_with(CommandStat::new);
+        stat.failed_calls += 1;
+    }
+
+    /// Accounts an error reply with the given prefix.
+    pub fn record_error(&self, prefix: &str) {
+        let mut errors = self.errors.lock().unwrap();
+        *errors.entry(prefix.to_string()).or_insert(0) += 1;
+    }
+
+    /// Accounts a read of a key, `hit` if the key was found.
+    pub fn record_keyspace_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.keyspace_hits
+        } else {
+            &self.keyspace_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Accounts a new client connection, `rejected` if it wasn't served.
+    pub fn record_connection(&self, rejected: bool) {
+        self.total_connections_received
+            .fetch_add(1, Ordering::Relaxed);
+        if rejected {
+            self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_net_input(&self, bytes: usize) {
+        self.total_net_input_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_net_output(&self, bytes: usize) {
+        self.total_net_output_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_expired(&self, keys: usize) {
+        self.expired_keys.fetch_add(keys as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_evicted(&self, keys: usize) {
+        self.evicted_keys.fetch_add(keys as u64, Ordering::Relaxed);
+    }
+
+    /// Accounts a client disconnected for not reading its output.
+    pub fn record_output_disconnection(&self, err: OutputError) {
+        let counter = match err {
+            OutputError::BufferLimit => &self.client_output_buffer_limit_disconnections,
+            OutputError::WriteTimeout => &self.client_write_timeout_disconnections,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Samples the cumulative counters backing the instantaneous metrics.
+    pub fn sample_metrics(&self) {
+        let now = Instant::now();
+        let mut metrics = self.metrics.lock().unwrap();
+        let ops = self.total_commands_processed.load(Ordering::Relaxed);
+        metrics.ops.sample(now, ops);
+        let input = self.total_net_input_bytes.load(Ordering::Relaxed);
+        metrics.net_input.sample(now, input);
+        let output = self.total_net_output_bytes.load(Ordering::Relaxed);
+        metrics.net_output.sample(now, output);
+    }
+
+    /// Lines of the INFO stats section.
+    pub fn stats(&self) -> Vec<String> {
+        let metrics = self.metrics.lock().unwrap();
+        let counter = |name: &str, counter: &AtomicU64| {
+            return format!("{}:{}", name, counter.load(Ordering::Relaxed));
+        };
+        return vec![
+            counter(
+                "total_connections_received",
+                &self.total_connections_received,
+            ),
+            counter("total_commands_processed", &self.total_commands_processed),
+            format!(
+                "instantaneous_ops_per_sec:{}",
+                metrics.ops.per_second().round()
+            ),
+            counter("total_net_input_bytes", &self.total_net_input_bytes),
+            counter("total_net_output_bytes", &self.total_net_output_bytes),
+            format!(
+                "instantaneous_input_kbps:{:.2}",
+                metrics.net_input.per_second() / 1024.0
+            ),
+            format!(
+                "instantaneous_output_kbps:{:.2}",
+                metrics.net_output.per_second() / 1024.0
+            ),
+            counter("rejected_connections", &self.rejected_connections),
+            counter("expired_keys", &self.expired_keys),
+            counter("evicted_keys", &self.evicted_keys),
+            counter("keyspace_hits", &self.keyspace_hits),
+            counter("keyspace_misses", &self.keyspace_misses),
+            counter(
+                "client_output_buffer_limit_disconnections",
+                &self.client_output_buffer_limit_disconnections,
+            ),
+            counter(
+                "client_write_timeout_disconnections",
+                &self.client_write_timeout_disconnections,
+            ),
+        ];
+    }
+
+    /// Lines of the INFO commandstats section.
+    pub fn commandstats(&self) -> Vec<String> {
+        let commands = self.commands.lock().unwrap();
+        return commands
+            .iter()
+            .map(|(name, stat)| {
+                let per_call = if stat.calls == 0 {
+                    0.0
+                } else {
+                    stat.usec as f64 / stat.calls as f64
+                };
+                format!(
+                    "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}",
+                    name, stat.calls, stat.usec, per_call, stat.rejected_calls, stat.failed_calls
+                )
+            })
+            .collect();
+    }
+
+    /// Lines of the INFO errorstats section.
+    pub fn errorstats(&self) -> Vec<String> {
+        let errors = self.errors.lock().unwrap();
+        return errors
+            .iter()
+            .map(|(prefix, count)| format!("errorstat_{}:count={}", prefix, count))
+            .collect();
+    }
+
+    /// Lines of the INFO latencystats section.
+    pub fn latencystats(&self) -> Vec<String> {
+        let commands = self.commands.lock().unwrap();
+        return commands
+            .iter()
+            .filter(|(_, stat)| stat.latency.total > 0)
+            .map(|(name, stat)| {
+                let percentiles: Vec<String> = LATENCY_PERCENTILES
+                    .iter()
+                    .map(|p| format!("p{}={:.3}", p, stat.latency.percentile(*p) as f64))
+                    .collect();
+                format!(
+                    "latency_percentiles_usec_{}:{}",
+                    name,
+                    percentiles.join(",")
+                )
+            })
+            .collect();
+    }
+}
+
+/// Wraps a client connection, accounting the bytes read and written in the
+/// network statistics.
+pub struct CountedStream<S> {
+    inner: S,
+    state: State,
+}
+
+impl<S> CountedStream<S> {
+    pub fn new(inner: S, state: State) -> Self {
+        return CountedStream {
+            inner: inner,
+            state: state,
+        };
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.state
+            .stats
+            .record_net_input(buf.filled().len() - before);
+        return res;
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = res {
+            self.state.stats.record_net_output(written);
+        }
+        return res;
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.inner).poll_flush(cx);
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.inner).poll_shutdown(cx);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{bucket_index, bucket_value, InstantaneousMetric, LatencyHistogram, Stats};
+
+    #[test]
+    fn test_buckets() {
+        for usec in [0, 1, 15, 16, 17, 100, 1000, 123_456, u64::MAX] {
+            let value = bucket_value(bucket_index(usec));
+            assert!(value <= usec, "{usec}");
+            assert!(usec - value <= usec / 16, "{usec}");
+        }
+        assert_eq!(bucket_value(bucket_index(31)), 31);
+        assert_eq!(bucket_value(bucket_index(33)), 32);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut histogram = LatencyHistogram::new();
+        for usec in 1..=100 {
+            histogram.record(usec);
+        }
+        assert_eq!(histogram.percentile(50.0), 50);
+        assert_eq!(histogram.percentile(99.0), 96);
+        assert_eq!(histogram.percentile(100.0), 100);
+    }
+
+    #[test]
+    fn test_report() {
+        let stats = Stats::new();
+        stats.record_call("get", 10, false);
+        stats.record_call("get", 20, true);
+        stats.record_rejected("set");
+        stats.record_error("ERR");
+        stats.record_error("ERR");
+        assert_eq!(
+            stats.commandstats(),
+            vec![
+                "cmdstat_get:calls=2,usec=30,usec_per_call=15.00,rejected_calls=0,failed_calls=1",
+                "cmdstat_set:calls=0,usec=0,usec_per_call=0.00,rejected_calls=1,failed_calls=0",
+            ]
+        );
+        assert_eq!(stats.errorstats(), vec!["errorstat_ERR:count=2"]);
+        stats.record_keyspace_lookup(true);
+        stats.record_keyspace_lookup(false);
+        stats.record_keyspace_lookup(false);
+        let report = stats.stats();
+        assert!(report.contains(&String::from("keyspace_hits:1")));
+        assert!(report.contains(&String::from("keyspace_misses:2")));
+        assert!(report.contains(&String::from("total_commands_processed:2")));
+        assert_eq!(
+            stats.latencystats(),
+            vec!["latency_percentiles_usec_get:p50=10.000,p99=20.000,p99.9=20.000"]
+        );
+    }
+
+    #[test]
+    fn test_instantaneous_metric() {
+        let mut metric = InstantaneousMetric::new();
+        let start = metric.last_time;
+        let mut value = 0;
+        for i in 1..=32 {
+            value += 10;
+            metric.sample(start + Duration::from_millis(i * 100), value);
+        }
+        assert!((metric.per_second() - 100.0).abs() < 0.001);
+
+        // no samples are taken without time passing
+        let now = Instant::now();
+        let mut metric = InstantaneousMetric::new();
+        metric.last_time = now;
+        metric.sample(now, 100);
+        assert_eq!(metric.per_second(), 0.0);
+    }
+}
@@ -0,0 +1,187 @@
+/// OpenTelemetry spans for the commands, exported over OTLP.
+///
+/// Built with the `otel` feature and started by `otel-endpoint`, the address of an
+/// OTLP/gRPC collector. Every command executed is a span named after it, with the
+/// number of keys it was called with, the database and client it ran for, and its
+/// outcome: `ok`, `error` (with the prefix of the error reply) or `blocked`, for the
+/// attempts of blocking commands that had nothing to serve yet. Spans are exported
+/// in batches in the background, and flushed when the server stops.
+use anyhow::Result;
+use opentelemetry::trace::{Span as _, SpanKind, Status, Tracer as _, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::export::trace::SpanExporter;
+use opentelemetry_sdk::trace::{Span, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+
+use crate::clients::ClientId;
+use crate::protocol::DataType;
+
+/// Name of the service in the exported spans.
+const SERVICE_NAME: &str = "redis-starter-rust";
+
+pub struct Telemetry {
+    provider: TracerProvider,
+    tracer: Tracer,
+}
+
+impl Telemetry {
+    /// Exports the spans to the OTLP/gRPC collector at `endpoint`, like
+    /// `http://localhost:4317`. The connection is made once spans are exported.
+    pub fn start(endpoint: &str) -> Result<Telemetry> {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)]))
+            .build();
+        return Ok(Telemetry::with_provider(provider));
+    }
+
+    /// Hands the spans to `exporter` as they end.
+    pub fn with_exporter(exporter: impl SpanExporter + 'static) -> Telemetry {
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter)
+            .build();
+        return Telemetry::with_provider(provider);
+    }
+
+    fn with_provider(provider: TracerProvider) -> Telemetry {
+        let tracer = provider.tracer(SERVICE_NAME);
+        return Telemetry {
+            provider: provider,
+            tracer: tracer,
+        };
+    }
+
+    /// Starts the span of a call to `command`, see `end_command_span`.
+    pub fn command_span(&self, command: &str, keys: usize, db: usize, client: ClientId) -> Span {
+        return self
+            .tracer
+            .span_builder(command.to_string())
+            .with_kind(SpanKind::Server)
+            .with_attributes([
+                KeyValue::new("db.system", "redis"),
+                KeyValue::new("redis.command", command.to_string()),
+                KeyValue::new("redis.key_count", keys as i64),
+                KeyValue::new("redis.db", db as i64),
+                KeyValue::new("redis.client_id", client as i64),
+            ])
+            .start(&self.tracer);
+    }
+
+    /// Exports the spans not exported yet and stops exporting. Blocks until done.
+    pub fn shutdown(&self) {
+        if let Err(err) = self.provider.shutdown() {
+            println!("failed to export the remaining spans: {}", err);
+        }
+    }
+}
+
+/// Ends the span of a command with the outcome of `response`, or `blocked` if the
+/// command had nothing to serve and will be retried.
+pub fn end_command_span(mut span: Span, response: &DataType, blocked: bool) {
+    match response {
+        _ if blocked => span.set_attribute(KeyValue::new("redis.outcome", "blocked")),
+        DataType::Error { type_, error } => {
+            span.set_attribute(KeyValue::new("redis.outcome", "error"));
+            span.set_attribute(KeyValue::new("redis.error", type_.clone()));
+            span.set_status(Status::error(error.clone()));
+        }
+        _ => span.set_attribute(KeyValue::new("redis.outcome", "ok")),
+    }
+    span.end();
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+
+    use opentelemetry::trace::Status;
+    use opentelemetry::Value;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+
+    use super::Telemetry;
+    use crate::commands::{dispatch, try_dispatch};
+    use crate::config::Config;
+    use crate::protocol::DataType;
+    use crate::state::StateInner;
+
+    /// Keeps the spans exported.
+    #[derive(Debug, Clone, Default)]
+    struct Collector {
+        spans: Arc<Mutex<Vec<SpanData>>>,
+    }
+
+    impl SpanExporter for Collector {
+        fn export(
+            &mut self,
+            batch: Vec<SpanData>,
+        ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+            self.spans.lock().unwrap().extend(batch);
+            return Box::pin(std::future::ready(Ok(())));
+        }
+    }
+
+    fn command(args: &[&str]) -> DataType {
+        return DataType::Array {
+            items: args.iter().map(|arg| DataType::from(*arg)).collect(),
+        };
+    }
+
+    fn attribute(span: &SpanData, key: &str) -> Option<Value> {
+        return span
+            .attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.clone());
+    }
+
+    #[test]
+    fn test_command_spans() {
+        let state = StateInner::new(Config::default());
+        let collector = Collector::default();
+        assert!(state
+            .telemetry
+            .set(Telemetry::with_exporter(collector.clone()))
+            .is_ok());
+        let client = state.connect_client().unwrap();
+        dispatch(command(&["SELECT", "2"]), &state, client.id);
+        dispatch(command(&["MSET", "a", "1", "b", "2"]), &state, client.id);
+        dispatch(command(&["HGET", "a", "f"]), &state, client.id);
+
+        let spans = collector.spans.lock().unwrap();
+        assert_eq!(spans.len(), 3);
+        let mset = &spans[1];
+        assert_eq!(mset.name, "mset");
+        for (key, value) in [
+            ("redis.command", Value::from("mset")),
+            ("redis.key_count", Value::from(2)),
+            ("redis.db", Value::from(2)),
+            ("redis.client_id", Value::from(client.id as i64)),
+            ("redis.outcome", Value::from("ok")),
+        ] {
+            assert_eq!(attribute(mset, key), Some(value), "{key}");
+        }
+        let hget = &spans[2];
+        assert_eq!(attribute(hget, "redis.outcome"), Some(Value::from("error")));
+        assert_eq!(
+            attribute(hget, "redis.error"),
+            Some(Value::from("WRONGTYPE"))
+        );
+        assert!(matches!(hget.status, Status::Error { .. }));
+        drop(spans);
+
+        let blmpop = command(&["BLMPOP", "0", "1", "l", "LEFT"]);
+        assert!(try_dispatch(blmpop, &state, client.id).is_err());
+        let spans = collector.spans.lock().unwrap();
+        assert_eq!(
+            attribute(&spans[3], "redis.outcome"),
+            Some(Value::from("blocked"))
+        );
+    }
+}
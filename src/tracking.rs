@@ -0,0 +1,372 @@
+/// Server-assisted client-side caching, modeled after Redis' `tracking.c`.
+///
+/// Clients enabling CLIENT TRACKING are told when keys they may have cached are
+/// modified. In the default mode the server remembers which clients read each
+/// key (the tracking table) and notifies them once when the key changes, after
+/// which the key is forgotten until read again. In broadcast mode (BCAST)
+/// clients subscribe to key prefixes instead and are notified about every
+/// modified key matching them, without the server remembering the keys.
+///
+/// Notifications are sent to the client itself when it speaks RESP3, or to
+/// the client given with REDIRECT. RESP3 clients receive them as `invalidate`
+/// pushes, RESP2 clients as `__redis__:invalidate` messages, only once they
+/// subscribed to that channel, as they would read them as replies otherwise.
+use std::collections::{HashMap, HashSet};
+
+use bytes::Bytes;
+
+use crate::clients::{ClientId, TrackingOptions};
+use crate::protocol::DataType;
+use crate::state::State;
+
+/// Channel the invalidation messages are published to for RESP2 clients.
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+#[derive(Default)]
+pub struct TrackingTable {
+    /// clients that read each key
+    keys: HashMap<Bytes, HashSet<ClientId>>,
+    /// clients subscribed to each prefix in broadcast mode
+    prefixes: HashMap<Bytes, HashSet<ClientId>>,
+}
+
+impl TrackingTable {
+    pub fn new() -> Self {
+        return TrackingTable::default();
+    }
+
+    pub fn track_key(&mut self, key: &Bytes, client: ClientId) {
+        self.keys.entry(key.clone()).or_default().insert(client);
+    }
+
+    pub fn subscribe_prefix(&mut self, prefix: &str, client: ClientId) {
+        self.prefixes
+            .entry(Bytes::copy_from_slice(prefix.as_bytes()))
+            .or_default()
+            .insert(client);
+    }
+
+    /// Forgets every key and prefix tracked for `client`.
+    pub fn remove_client(&mut self, client: ClientId) {
+        for table in [&mut self.keys, &mut self.prefixes] {
+            table.retain(|_, clients| {
+                clients.remove(&client);
+                return !clients.is_empty();
+            });
+        }
+    }
+
+    /// Clients to notify about a modification of `key`. Clients tracking the
+    /// key itself are removed from the table, as they are only notified once.
+    pub fn invalidate(&mut self, key: &[u8]) -> HashSet<ClientId> {
+        let mut clients = self.keys.remove(key).unwrap_or_default();
+        for (prefix, subscribers) in &self.prefixes {
+            if key.starts_with(prefix) {
+                clients.extend(subscribers);
+            }
+        }
+        return clients;
+    }
+
+    /// Forgets every key tracked, keeping the prefixes subscribed to.
+    pub fn clear_keys(&mut self) {
+        self.keys.clear();
+    }
+
+    /// Number of keys in the tracking table.
+    pub fn len(&self) -> usize {
+        return self.keys.len();
+    }
+}
+
+/// Enables (or with `None`, disables) tracking for `client`.
+pub fn set_tracking(state: &State, client: ClientId, options: Option<TrackingOptions>) {
+    let mut table = state.tracking.lock().unwrap();
+    table.remove_client(client);
+    if let Some(options) = &options {
+        if options.bcast {
+            if options.prefixes.is_empty() {
+                table.subscribe_prefix("", client);
+            }
+            for prefix in &options.prefixes {
+                table.subscribe_prefix(prefix, client);
+            }
+        }
+    }
+    state.clients.with_client(client, |c| {
+        c.tracking = options;
+        c.caching = None;
+    });
+}
+
+/// Remembers that `client` read `keys`, if its tracking options say so.
+pub fn track_keys(state: &State, client: ClientId, keys: &[Bytes]) {
+    let track = state.clients.with_client(client, |c| match &c.tracking {
+        Some(options) if !options.bcast => {
+            if options.optin {
+                c.caching == Some(true)
+            } else if options.optout {
+                c.caching != Some(false)
+            } else {
+                true
+            }
+        }
+        _ => false,
+    });
+    if track != Some(true) {
+        return;
+    }
+    let mut table = state.tracking.lock().unwrap();
+    for key in keys {
+        table.track_key(key, client);
+    }
+}
+
+/// Invalidation message of `keys`, an Array of the keys or a Null after a flush.
+fn invalidation_message(keys: DataType, protocol: u8) -> DataType {
+    if protocol >= 3 {
+        return DataType::Push {
+            items: vec![DataType::bulk("invalidate"), keys],
+        };
+    }
+    return DataType::Array {
+        items: vec![
+            DataType::bulk("message"),
+            DataType::bulk(INVALIDATE_CHANNEL),
+            keys,
+        ],
+    };
+}
+
+/// Sends the invalidation message of `keys` to `target`, if it can receive it.
+fn send_invalidation(state: &State, target: ClientId, keys: DataType) {
+    let subscribed = state
+        .pubsub
+        .lock()
+        .unwrap()
+        .is_subscribed(target, INVALIDATE_CHANNEL);
+    state.clients.with_client(target, |c| {
+        if c.protocol >= 3 || subscribed {
+            c.send(invalidation_message(keys, c.protocol));
+        }
+    });
+}
+
+/// Notifies the clients tracking `keys` that they were modified. `origin` is the
+/// client that modified them, if any, which isn't notified if it enabled NOLOOP.
+pub fn invalidate_keys(state: &State, keys: &[Bytes], origin: Option<ClientId>) {
+    let mut targets: HashMap<ClientId, Vec<Bytes>> = HashMap::new();
+    {
+        let mut table = state.tracking.lock().unwrap();
+        for key in keys {
+            for client in table.invalidate(key) {
+                targets.entry(client).or_default().push(key.clone());
+            }
+        }
+    }
+    for (client, keys) in targets {
+        let target = state.clients.with_client(client, |c| {
+            let options = c.tracking.as_ref()?;
+            if options.noloop && origin == Some(c.id) {
+                return None;
+            }
+            return match options.redirect {
+                Some(redirect) => Some(redirect),
+                // RESP2 connections can't receive out-of-band messages
+                None if c.protocol >= 3 => Some(c.id),
+                None => None,
+            };
+        });
+        if let Some(Some(target)) = target {
+            let keys = DataType::Array {
+                items: keys.into_iter().map(DataType::bulk).collect(),
+            };
+            send_invalidation(state, target, keys);
+        }
+    }
+}
+
+/// Notifies every tracking client that the whole keyspace was flushed, which is
+/// sent as an invalidation message with a Null instead of the keys.
+pub fn invalidate_all(state: &State) {
+    state.tracking.lock().unwrap().clear_keys();
+    let mut targets = Vec::new();
+    state.clients.for_each(|c| {
+        let options = match &c.tracking {
+            Some(options) => options,
+            None => return,
+        };
+        match options.redirect {
+            Some(redirect) => targets.push(redirect),
+            None if c.protocol >= 3 => targets.push(c.id),
+            None => {}
+        }
+    });
+    targets.sort_unstable();
+    targets.dedup();
+    for target in targets {
+        send_invalidation(state, target, DataType::Null);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use bytes::Bytes;
+
+    use super::{
+        invalidate_all, invalidate_keys, set_tracking, track_keys, TrackingTable,
+        INVALIDATE_CHANNEL,
+    };
+    use crate::clients::{ClientId, TrackingOptions};
+    use crate::config::Config;
+    use crate::protocol::DataType;
+    use crate::state::{State, StateInner};
+
+    /// Subscribes `client` to the invalidation messages, as RESP2 clients must.
+    fn subscribe(state: &State, client: ClientId) {
+        let mut pubsub = state.pubsub.lock().unwrap();
+        pubsub.subscribe(client, INVALIDATE_CHANNEL, false);
+    }
+
+    fn invalidated(message: DataType) -> Vec<String> {
+        let items = match message {
+            DataType::Array { items } => items,
+            DataType::Push { items } => items,
+            _ => panic!("not an array"),
+        };
+        return match items.into_iter().last() {
+            Some(DataType::Array { items }) => items
+                .into_iter()
+                .map(|key| key.as_string().expect("not a key"))
+                .collect(),
+            _ => panic!("no keys"),
+        };
+    }
+
+    #[test]
+    fn test_tracking_table() {
+        let mut table = TrackingTable::new();
+        table.track_key(&Bytes::from("a"), 1);
+        table.track_key(&Bytes::from("a"), 2);
+        table.track_key(&Bytes::from("b"), 2);
+        table.subscribe_prefix("user:", 3);
+        assert_eq!(table.len(), 2);
+
+        assert_eq!(table.invalidate(b"a"), HashSet::from([1, 2]));
+        // clients are notified only once
+        assert!(table.invalidate(b"a").is_empty());
+        assert_eq!(table.invalidate(b"user:1"), HashSet::from([3]));
+        assert_eq!(table.invalidate(b"user:1"), HashSet::from([3]));
+
+        table.remove_client(2);
+        table.remove_client(3);
+        assert!(table.invalidate(b"b").is_empty());
+        assert!(table.invalidate(b"user:1").is_empty());
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn test_invalidation_redirect() {
+        let state = StateInner::new(Config::default());
+        let reader = state.connect_client().unwrap();
+        let mut listener = state.connect_client().unwrap();
+        let options = TrackingOptions {
+            redirect: Some(listener.id),
+            noloop: true,
+            ..Default::default()
+        };
+        set_tracking(&state, reader.id, Some(options));
+        track_keys(&state, reader.id, &[Bytes::from("a"), Bytes::from("b")]);
+
+        // RESP2 clients get nothing until they subscribe to the channel
+        invalidate_keys(&state, &[Bytes::from("b")], None);
+        assert!(listener.messages.try_recv().is_err());
+        subscribe(&state, listener.id);
+        track_keys(&state, reader.id, &[Bytes::from("b")]);
+
+        invalidate_keys(&state, &[Bytes::from("a"), Bytes::from("c")], None);
+        let message = listener.messages.try_recv().unwrap();
+        assert_eq!(invalidated(message), vec!["a"]);
+
+        // keys modified by the tracking client itself are skipped with NOLOOP
+        invalidate_keys(&state, &[Bytes::from("b")], Some(reader.id));
+        assert!(listener.messages.try_recv().is_err());
+
+        // disconnecting forgets the tracked keys
+        track_keys(&state, reader.id, &[Bytes::from("a")]);
+        drop(reader);
+        assert_eq!(state.tracking.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_invalidation_bcast_and_optin() {
+        let state = StateInner::new(Config::default());
+        let bcast = state.connect_client().unwrap();
+        let optin = state.connect_client().unwrap();
+        let mut listener = state.connect_client().unwrap();
+        subscribe(&state, listener.id);
+        let bcast_options = TrackingOptions {
+            redirect: Some(listener.id),
+            bcast: true,
+            prefixes: vec![String::from("user:")],
+            ..Default::default()
+        };
+        set_tracking(&state, bcast.id, Some(bcast_options));
+        invalidate_keys(&state, &[Bytes::from("user:1"), Bytes::from("x")], None);
+        let message = listener.messages.try_recv().unwrap();
+        assert_eq!(invalidated(message), vec!["user:1"]);
+        set_tracking(&state, bcast.id, None);
+
+        let optin_options = TrackingOptions {
+            redirect: Some(listener.id),
+            optin: true,
+            ..Default::default()
+        };
+        set_tracking(&state, optin.id, Some(optin_options));
+        track_keys(&state, optin.id, &[Bytes::from("a")]);
+        assert_eq!(state.tracking.lock().unwrap().len(), 0);
+        state
+            .clients
+            .with_client(optin.id, |c| c.caching = Some(true));
+        track_keys(&state, optin.id, &[Bytes::from("a")]);
+        assert_eq!(state.tracking.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_invalidation_push() {
+        let state = StateInner::new(Config::default());
+        let mut client = state.connect_client().unwrap();
+        state.clients.with_client(client.id, |c| c.protocol = 3);
+        set_tracking(&state, client.id, Some(TrackingOptions::default()));
+        track_keys(&state, client.id, &[Bytes::from("a")]);
+
+        invalidate_keys(&state, &[Bytes::from("a")], None);
+        let message = client.messages.try_recv().unwrap();
+        assert!(matches!(message, DataType::Push { .. }));
+        assert_eq!(invalidated(message), vec!["a"]);
+    }
+
+    #[test]
+    fn test_invalidate_all() {
+        let state = StateInner::new(Config::default());
+        let reader = state.connect_client().unwrap();
+        let mut listener = state.connect_client().unwrap();
+        subscribe(&state, listener.id);
+        let options = TrackingOptions {
+            redirect: Some(listener.id),
+            ..Default::default()
+        };
+        set_tracking(&state, reader.id, Some(options));
+        track_keys(&state, reader.id, &[Bytes::from("a")]);
+
+        invalidate_all(&state);
+        let message = listener.messages.try_recv().unwrap();
+        match message {
+            DataType::Array { items } => assert_eq!(items.last(), Some(&DataType::Null)),
+            message => panic!("unexpected message {:?}", message),
+        }
+        assert_eq!(state.tracking.lock().unwrap().len(), 0);
+    }
+}
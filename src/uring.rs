@@ -0,0 +1,173 @@
+/// I/O backends driving the sockets of the connections.
+///
+/// By default sockets are driven by tokio's runtime, which waits for them to be
+/// ready with epoll and then reads or writes them with syscalls. With the `io-uring`
+/// feature, `io-backend uring` serves the RESP connections with tokio-uring instead,
+/// which submits the reads and writes to an io_uring and reaps their completions.
+///
+/// tokio-uring runs a single-threaded runtime of its own, and its sockets take owned
+/// buffers and can't leave that thread, so connections are served through an
+/// in-memory pipe: two tasks copy what is read from the socket into the pipe and
+/// write to the socket what comes out of it, while the connection reads and writes
+/// the other end like it would the socket. That costs a copy and a task hop for
+/// every read and every write, on top of the I/O itself.
+#[cfg(feature = "io-uring")]
+use std::net::Shutdown;
+#[cfg(feature = "io-uring")]
+use std::rc::Rc;
+
+use std::future::Future;
+
+#[cfg(feature = "io-uring")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+#[cfg(feature = "io-uring")]
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+#[cfg(feature = "io-uring")]
+use tokio_uring::buf::IoBuf;
+
+/// Bytes read from, or written to, a socket at once.
+#[cfg(feature = "io-uring")]
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Bytes buffered by the pipe between a socket and its connection, in each direction.
+#[cfg(feature = "io-uring")]
+const PIPE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoBackend {
+    Epoll,
+    Uring,
+}
+
+impl IoBackend {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            IoBackend::Epoll => "epoll",
+            IoBackend::Uring => "uring",
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name.to_lowercase().as_str() {
+            "epoll" => Some(IoBackend::Epoll),
+            "uring" | "io_uring" => Some(IoBackend::Uring),
+            _ => None,
+        };
+    }
+}
+
+/// Spawns a task of the server. With the io_uring backend it runs on the thread of
+/// the runtime, as the tasks copying between sockets and pipes can only be spawned
+/// from there.
+pub fn spawn<F>(backend: IoBackend, task: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    return match backend {
+        IoBackend::Epoll => tokio::spawn(task),
+        IoBackend::Uring => tokio::task::spawn_local(task),
+    };
+}
+
+/// Hands the socket of `stream` over to io_uring, returning the end of the pipe the
+/// connection is served through. Must be called from a task spawned with `spawn`.
+#[cfg(feature = "io-uring")]
+pub fn attach(stream: TcpStream) -> std::io::Result<DuplexStream> {
+    let socket = Rc::new(tokio_uring::net::TcpStream::from_std(stream.into_std()?));
+    let (connection, pipe) = tokio::io::duplex(PIPE_SIZE);
+    let (output, input) = tokio::io::split(pipe);
+    tokio_uring::spawn(read_socket(socket.clone(), input));
+    tokio_uring::spawn(write_socket(socket, output));
+    return Ok(connection);
+}
+
+/// Copies what the client sends into the pipe, until either of them is closed.
+#[cfg(feature = "io-uring")]
+async fn read_socket(socket: Rc<tokio_uring::net::TcpStream>, mut pipe: WriteHalf<DuplexStream>) {
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        let (result, read) = socket.read(buf).await;
+        buf = read;
+        let n = match result {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if pipe.write_all(&buf[..n]).await.is_err() {
+            break;
+        }
+    }
+    // the connection reads the end of its input
+    let _ = pipe.shutdown().await;
+}
+
+/// Sends the client what the connection writes into the pipe, closing the socket
+/// once the connection is done.
+#[cfg(feature = "io-uring")]
+async fn write_socket(socket: Rc<tokio_uring::net::TcpStream>, mut pipe: ReadHalf<DuplexStream>) {
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        let n = match pipe.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let (result, written) = socket.write_all(buf.slice(..n)).await;
+        buf = written.into_inner();
+        if result.is_err() {
+            break;
+        }
+    }
+    // wakes up `read_socket` if it's still waiting for the client
+    let _ = socket.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod test {
+    use super::IoBackend;
+
+    #[test]
+    fn test_names() {
+        for backend in [IoBackend::Epoll, IoBackend::Uring] {
+            assert_eq!(IoBackend::from_name(backend.name()), Some(backend));
+        }
+        assert_eq!(IoBackend::from_name("io_uring"), Some(IoBackend::Uring));
+        assert_eq!(IoBackend::from_name("kqueue"), None);
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn test_uring_connections() {
+        use std::net::SocketAddr;
+
+        use bytes::Bytes;
+
+        use crate::client::Client;
+        use crate::config::Config;
+        use crate::protocol::DataType;
+        use crate::Server;
+
+        tokio_uring::start(async {
+            let mut config = Config::default();
+            config.set("io-backend", "uring").unwrap();
+            let address = SocketAddr::from(([127, 0, 0, 1], 0));
+            let (address, server) = Server::new(config).with_address(address).spawn().unwrap();
+
+            let mut client = Client::connect(address).await.unwrap();
+            client.set("key", "value").await.unwrap();
+            assert_eq!(client.get("key").await.unwrap(), Some(Bytes::from("value")));
+            // larger than the buffers of the pipe, in both directions
+            let value = "x".repeat(1024 * 1024);
+            client.set("big", value.clone()).await.unwrap();
+            let get = DataType::Array {
+                items: vec![DataType::from("GET"), DataType::from("big")],
+            };
+            let replies = client.pipeline(&vec![get; 10]).await.unwrap();
+            assert_eq!(replies.len(), 10);
+            let big = DataType::bulk(value);
+            assert!(replies.iter().all(|reply| *reply == big));
+            drop(client);
+            assert_eq!(server.stop().await, 0);
+        });
+    }
+}
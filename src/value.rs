@@ -0,0 +1,1057 @@
+/// Values stored in the keyspace.
+///
+/// Every key holds one of the Redis data types. Commands reach the payload through
+/// the `as_*` helpers, which fail with WRONGTYPE when the key holds another type.
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::mem::size_of;
+
+use bytes::Bytes;
+
+use crate::errors::ReplyError;
+use crate::listpack::{Listpack, ListpackLimits};
+use crate::lzf;
+
+/// Longest string stored inline with its object by Redis (`embstr` encoding).
+const EMBSTR_MAX_LEN: usize = 44;
+
+// the collection types are only reachable once their commands are added
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(Str),
+    List(List),
+    Hash(Hash),
+    Set(Set),
+    ZSet(ZSet),
+    Stream(Stream),
+}
+
+impl From<Bytes> for Value {
+    fn from(string: Bytes) -> Self {
+        return Value::Str(Str::from(string));
+    }
+}
+
+#[allow(dead_code)]
+impl Value {
+    /// Name of the type, as reported by TYPE.
+    pub fn type_name(&self) -> &'static str {
+        return match self {
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        };
+    }
+
+    /// Internal representation of the value, as reported by OBJECT ENCODING.
+    pub fn encoding(&self) -> &'static str {
+        return match self {
+            Value::Str(Str::Int(_)) => "int",
+            Value::Str(Str::Raw(string)) if string.len() <= EMBSTR_MAX_LEN => "embstr",
+            Value::Str(Str::Raw(_)) => "raw",
+            Value::Str(Str::Compressed { .. }) => "lzf",
+            Value::List(List::Listpack(_)) => "listpack",
+            Value::List(List::Quicklist(_)) => "quicklist",
+            Value::Hash(Hash::Listpack(_)) => "listpack",
+            Value::Hash(Hash::Table(_)) => "hashtable",
+            Value::Set(Set::Listpack(_)) => "listpack",
+            Value::Set(Set::Table(_)) => "hashtable",
+            Value::ZSet(ZSet::Listpack(_)) => "listpack",
+            Value::ZSet(ZSet::Skiplist(_)) => "skiplist",
+            Value::Stream(_) => "stream",
+        };
+    }
+
+    pub fn as_str(&self) -> Result<&Str, ReplyError> {
+        return match self {
+            Value::Str(string) => Ok(string),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_str_mut(&mut self) -> Result<&mut Str, ReplyError> {
+        return match self {
+            Value::Str(string) => Ok(string),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_list(&self) -> Result<&List, ReplyError> {
+        return match self {
+            Value::List(list) => Ok(list),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_list_mut(&mut self) -> Result<&mut List, ReplyError> {
+        return match self {
+            Value::List(list) => Ok(list),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_hash(&self) -> Result<&Hash, ReplyError> {
+        return match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_hash_mut(&mut self) -> Result<&mut Hash, ReplyError> {
+        return match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_set(&self) -> Result<&Set, ReplyError> {
+        return match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_set_mut(&mut self) -> Result<&mut Set, ReplyError> {
+        return match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_zset(&self) -> Result<&ZSet, ReplyError> {
+        return match self {
+            Value::ZSet(zset) => Ok(zset),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_zset_mut(&mut self) -> Result<&mut ZSet, ReplyError> {
+        return match self {
+            Value::ZSet(zset) => Ok(zset),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_stream(&self) -> Result<&Stream, ReplyError> {
+        return match self {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    pub fn as_stream_mut(&mut self) -> Result<&mut Stream, ReplyError> {
+        return match self {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(ReplyError::WrongType),
+        };
+    }
+
+    /// Number of elements of a collection, 1 for strings.
+    pub fn len(&self) -> usize {
+        return match self {
+            Value::Str(_) => 1,
+            Value::List(list) => list.len(),
+            Value::Hash(hash) => hash.len(),
+            Value::Set(set) => set.len(),
+            Value::ZSet(zset) => zset.len(),
+            Value::Stream(stream) => stream.len(),
+        };
+    }
+
+    /// Approximate heap bytes owned by the value. The size of collections is estimated
+    /// from their first `samples` elements (0 means all of them), listpacks are
+    /// measured exactly.
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        return match self {
+            Value::Str(string) => string.memory_usage(),
+            Value::List(List::Listpack(listpack))
+            | Value::Hash(Hash::Listpack(listpack))
+            | Value::Set(Set::Listpack(listpack))
+            | Value::ZSet(ZSet::Listpack(listpack)) => listpack.memory_usage(),
+            Value::List(List::Quicklist(list)) => {
+                let sizes = list.iter().map(|item| size_of::<Bytes>() + item.len());
+                estimate(sizes, list.len(), samples)
+            }
+            Value::Hash(Hash::Table(hash)) => {
+                let sizes = hash
+                    .iter()
+                    .map(|(field, value)| 2 * size_of::<Bytes>() + field.len() + value.len());
+                estimate(sizes, hash.len(), samples)
+            }
+            Value::Set(Set::Table(set)) => {
+                let sizes = set.iter().map(|member| size_of::<Bytes>() + member.len());
+                estimate(sizes, set.len(), samples)
+            }
+            Value::ZSet(ZSet::Skiplist(zset)) => {
+                // members are kept twice, indexed by name and ordered by score
+                let sizes = zset
+                    .iter()
+                    .map(|(member, _)| 2 * (size_of::<Bytes>() + size_of::<f64>()) + member.len());
+                estimate(sizes, zset.len(), samples)
+            }
+            Value::Stream(stream) => {
+                let sizes = stream.entries.values().map(|fields| {
+                    let data: usize = fields.iter().map(|(f, v)| f.len() + v.len()).sum();
+                    size_of::<StreamId>() + fields.len() * 2 * size_of::<Bytes>() + data
+                });
+                estimate(sizes, stream.len(), samples)
+            }
+        };
+    }
+}
+
+/// Total size of `len` elements, extrapolated from the sizes of the first `samples`.
+fn estimate(sizes: impl Iterator<Item = usize>, len: usize, samples: usize) -> usize {
+    let samples = if samples == 0 { len } else { samples.min(len) };
+    if samples == 0 {
+        return 0;
+    }
+    let sampled: usize = sizes.take(samples).sum();
+    return sampled * len / samples;
+}
+
+/// String value. Strings holding the canonical representation of a 64-bit integer
+/// are stored as the integer itself, which takes no heap memory and lets counters be
+/// incremented without parsing and formatting them every time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Str {
+    Raw(Bytes),
+    Int(i64),
+    /// LZF compressed string, `len` bytes long once decompressed.
+    Compressed {
+        data: Bytes,
+        len: usize,
+    },
+}
+
+impl From<Bytes> for Str {
+    fn from(string: Bytes) -> Self {
+        return match parse_integer(&string) {
+            Some(n) => Str::Int(n),
+            None => Str::Raw(string),
+        };
+    }
+}
+
+impl From<i64> for Str {
+    fn from(n: i64) -> Self {
+        return Str::Int(n);
+    }
+}
+
+#[allow(dead_code)]
+impl Str {
+    pub fn to_bytes(&self) -> Bytes {
+        return match self {
+            Str::Raw(string) => string.clone(),
+            Str::Int(n) => Bytes::from(n.to_string()),
+            Str::Compressed { data, len } => {
+                Bytes::from(lzf::decompress(data, *len).expect("corrupted compressed string"))
+            }
+        };
+    }
+
+    /// Compresses raw strings longer than `threshold`, 0 disables it. Strings that
+    /// don't get smaller are kept as they are.
+    pub fn compress(self, threshold: usize) -> Self {
+        let string = match self {
+            Str::Raw(string) if threshold > 0 && string.len() > threshold => string,
+            _ => return self,
+        };
+        return match lzf::compress(&string) {
+            Some(data) => Str::Compressed {
+                data: Bytes::from(data),
+                len: string.len(),
+            },
+            None => Str::Raw(string),
+        };
+    }
+
+    /// Length of the string, as reported by STRLEN.
+    pub fn len(&self) -> usize {
+        return match self {
+            Str::Raw(string) => string.len(),
+            Str::Int(n) => n.to_string().len(),
+            Str::Compressed { len, .. } => *len,
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// Value of the string as an integer, if it is one.
+    pub fn as_int(&self) -> Result<i64, ReplyError> {
+        return match self {
+            Str::Int(n) => Ok(*n),
+            Str::Raw(string) => parse_integer(string).ok_or(ReplyError::NotAnInteger),
+            // integers are never stored as raw bytes, compressed or not
+            Str::Compressed { .. } => Err(ReplyError::NotAnInteger),
+        };
+    }
+
+    /// Adds `by` to the integer stored in the string, returning the new value.
+    pub fn incr_by(&mut self, by: i64) -> Result<i64, ReplyError> {
+        let n = self
+            .as_int()?
+            .checked_add(by)
+            .ok_or_else(|| ReplyError::Err("increment or decrement would overflow".to_string()))?;
+        *self = Str::Int(n);
+        return Ok(n);
+    }
+
+    /// The string as raw bytes, for commands editing it in place (like APPEND or
+    /// SETRANGE). Integers are formatted and compressed strings decompressed first.
+    pub fn raw_mut(&mut self) -> &mut Bytes {
+        if !matches!(self, Str::Raw(_)) {
+            *self = Str::Raw(self.to_bytes());
+        }
+        return match self {
+            Str::Raw(string) => string,
+            _ => unreachable!("string wasn't converted"),
+        };
+    }
+
+    /// Heap bytes owned by the string, none for integers.
+    pub fn memory_usage(&self) -> usize {
+        return match self {
+            Str::Raw(string) => string.len(),
+            Str::Int(_) => 0,
+            Str::Compressed { data, .. } => data.len(),
+        };
+    }
+}
+
+/// Parses `string` as an integer, only if it's the canonical representation of one
+/// so it can be formatted back exactly.
+fn parse_integer(string: &[u8]) -> Option<i64> {
+    // longest i64 is "-9223372036854775808"
+    if string.is_empty() || string.len() > 20 {
+        return None;
+    }
+    let n: i64 = std::str::from_utf8(string).ok()?.parse().ok()?;
+    if n.to_string().as_bytes() != string {
+        return None;
+    }
+    return Some(n);
+}
+
+/// List, stored as a listpack until it outgrows the limits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum List {
+    Listpack(Listpack),
+    Quicklist(VecDeque<Bytes>),
+}
+
+#[allow(dead_code)]
+impl List {
+    pub fn new() -> Self {
+        return List::Listpack(Listpack::new());
+    }
+
+    pub fn len(&self) -> usize {
+        return match self {
+            List::Listpack(listpack) => listpack.len(),
+            List::Quicklist(list) => list.len(),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        return match self {
+            List::Listpack(listpack) => Box::new(listpack.iter()),
+            List::Quicklist(list) => Box::new(list.iter().map(|item| &item[..])),
+        };
+    }
+
+    pub fn get(&self, ix: usize) -> Option<&[u8]> {
+        return match self {
+            List::Listpack(listpack) => listpack.get(ix),
+            List::Quicklist(list) => list.get(ix).map(|item| &item[..]),
+        };
+    }
+
+    pub fn push_back(&mut self, item: Bytes, limits: &ListpackLimits) {
+        match self.reserve(item.len(), limits) {
+            List::Listpack(listpack) => listpack.push_back(&item),
+            List::Quicklist(list) => list.push_back(item),
+        }
+    }
+
+    pub fn push_front(&mut self, item: Bytes, limits: &ListpackLimits) {
+        match self.reserve(item.len(), limits) {
+            List::Listpack(listpack) => listpack.push_front(&item),
+            List::Quicklist(list) => list.push_front(item),
+        }
+    }
+
+    /// Inserts `item` before the element at `ix`, or at the end if `ix` is the length.
+    pub fn insert(&mut self, ix: usize, item: Bytes, limits: &ListpackLimits) {
+        match self.reserve(item.len(), limits) {
+            List::Listpack(listpack) => listpack.insert(ix, &item),
+            List::Quicklist(list) => list.insert(ix, item),
+        }
+    }
+
+    /// Replaces the element at `ix`, returns false if it's out of range.
+    pub fn set(&mut self, ix: usize, item: Bytes, limits: &ListpackLimits) -> bool {
+        if ix >= self.len() {
+            return false;
+        }
+        if let List::Listpack(listpack) = self {
+            let bytes = listpack.bytes() + item.len();
+            if limits.allow(listpack.len(), item.len(), bytes) {
+                listpack.replace(ix, &item);
+                return true;
+            }
+            self.convert();
+        }
+        if let List::Quicklist(list) = self {
+            list[ix] = item;
+        }
+        return true;
+    }
+
+    pub fn remove(&mut self, ix: usize) -> Option<Bytes> {
+        return match self {
+            List::Listpack(listpack) => listpack.remove(ix).map(Bytes::from),
+            List::Quicklist(list) => list.remove(ix),
+        };
+    }
+
+    /// Removes `count` elements starting at `ix`, clamped to the end of the list.
+    pub fn remove_range(&mut self, ix: usize, count: usize) {
+        match self {
+            List::Listpack(listpack) => listpack.remove_range(ix, count),
+            List::Quicklist(list) => {
+                let ix = ix.min(list.len());
+                let end = ix.saturating_add(count).min(list.len());
+                list.drain(ix..end);
+            }
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<Bytes> {
+        return self.remove(0);
+    }
+
+    pub fn pop_back(&mut self) -> Option<Bytes> {
+        return match self {
+            List::Listpack(listpack) => listpack
+                .remove(listpack.len().checked_sub(1)?)
+                .map(Bytes::from),
+            List::Quicklist(list) => list.pop_back(),
+        };
+    }
+
+    /// Makes room for a new element of `len` bytes, converting the listpack if the
+    /// element doesn't fit in it.
+    fn reserve(&mut self, len: usize, limits: &ListpackLimits) -> &mut Self {
+        if let List::Listpack(listpack) = self {
+            if !limits.allow(listpack.len() + 1, len, listpack.bytes() + len) {
+                self.convert();
+            }
+        }
+        return self;
+    }
+
+    fn convert(&mut self) {
+        if let List::Listpack(listpack) = self {
+            let list = listpack.iter().map(Bytes::copy_from_slice).collect();
+            *self = List::Quicklist(list);
+        }
+    }
+}
+
+/// Hash, stored as a listpack of alternating fields and values until it outgrows
+/// the limits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hash {
+    Listpack(Listpack),
+    Table(HashMap<Bytes, Bytes>),
+}
+
+#[allow(dead_code)]
+impl Hash {
+    pub fn new() -> Self {
+        return Hash::Listpack(Listpack::new());
+    }
+
+    pub fn len(&self) -> usize {
+        return match self {
+            Hash::Listpack(listpack) => listpack.len() / 2,
+            Hash::Table(hash) => hash.len(),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], &[u8])> + '_> {
+        return match self {
+            Hash::Listpack(listpack) => Box::new(pairs(listpack)),
+            Hash::Table(hash) => Box::new(hash.iter().map(|(f, v)| (&f[..], &v[..]))),
+        };
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&[u8]> {
+        return match self {
+            Hash::Listpack(listpack) => pairs(listpack).find(|(f, _)| *f == field).map(|(_, v)| v),
+            Hash::Table(hash) => hash.get(field).map(|value| &value[..]),
+        };
+    }
+
+    pub fn contains(&self, field: &[u8]) -> bool {
+        return self.get(field).is_some();
+    }
+
+    /// Sets the value of `field`, returns true if the field is new.
+    pub fn insert(&mut self, field: Bytes, value: Bytes, limits: &ListpackLimits) -> bool {
+        if let Hash::Listpack(listpack) = self {
+            let pos = pairs(listpack).position(|(f, _)| f == field);
+            let entries = listpack.len() / 2 + pos.is_none() as usize;
+            let bytes = listpack.bytes() + field.len() + value.len();
+            if limits.allow(entries, field.len().max(value.len()), bytes) {
+                match pos {
+                    Some(pos) => listpack.replace(2 * pos + 1, &value),
+                    None => {
+                        listpack.push_back(&field);
+                        listpack.push_back(&value);
+                    }
+                }
+                return pos.is_none();
+            }
+            self.convert();
+        }
+        return match self {
+            Hash::Table(hash) => hash.insert(field, value).is_none(),
+            Hash::Listpack(_) => unreachable!("hash wasn't converted"),
+        };
+    }
+
+    /// Removes `field`, returns true if it was in the hash.
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        return match self {
+            Hash::Listpack(listpack) => {
+                let pos = pairs(listpack).position(|(f, _)| f == field);
+                if let Some(pos) = pos {
+                    listpack.remove_range(2 * pos, 2);
+                }
+                pos.is_some()
+            }
+            Hash::Table(hash) => hash.remove(field).is_some(),
+        };
+    }
+
+    fn convert(&mut self) {
+        if let Hash::Listpack(listpack) = self {
+            let hash = pairs(listpack)
+                .map(|(f, v)| (Bytes::copy_from_slice(f), Bytes::copy_from_slice(v)))
+                .collect();
+            *self = Hash::Table(hash);
+        }
+    }
+}
+
+/// Consecutive pairs of elements of a listpack.
+fn pairs(listpack: &Listpack) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut iter = listpack.iter();
+    return std::iter::from_fn(move || Some((iter.next()?, iter.next()?)));
+}
+
+/// Set, stored as a listpack until it outgrows the limits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Set {
+    Listpack(Listpack),
+    Table(HashSet<Bytes>),
+}
+
+#[allow(dead_code)]
+impl Set {
+    pub fn new() -> Self {
+        return Set::Listpack(Listpack::new());
+    }
+
+    pub fn len(&self) -> usize {
+        return match self {
+            Set::Listpack(listpack) => listpack.len(),
+            Set::Table(set) => set.len(),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        return match self {
+            Set::Listpack(listpack) => Box::new(listpack.iter()),
+            Set::Table(set) => Box::new(set.iter().map(|member| &member[..])),
+        };
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        return match self {
+            Set::Listpack(listpack) => listpack.position(member).is_some(),
+            Set::Table(set) => set.contains(member),
+        };
+    }
+
+    /// Adds `member`, returns true if it's new.
+    pub fn insert(&mut self, member: Bytes, limits: &ListpackLimits) -> bool {
+        if self.contains(&member) {
+            return false;
+        }
+        if let Set::Listpack(listpack) = self {
+            let bytes = listpack.bytes() + member.len();
+            if limits.allow(listpack.len() + 1, member.len(), bytes) {
+                listpack.push_back(&member);
+                return true;
+            }
+            self.convert();
+        }
+        return match self {
+            Set::Table(set) => set.insert(member),
+            Set::Listpack(_) => unreachable!("set wasn't converted"),
+        };
+    }
+
+    /// Removes `member`, returns true if it was in the set.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        return match self {
+            Set::Listpack(listpack) => match listpack.position(member) {
+                Some(pos) => listpack.remove(pos).is_some(),
+                None => false,
+            },
+            Set::Table(set) => set.remove(member),
+        };
+    }
+
+    fn convert(&mut self) {
+        if let Set::Listpack(listpack) = self {
+            let set = listpack.iter().map(Bytes::copy_from_slice).collect();
+            *self = Set::Table(set);
+        }
+    }
+}
+
+/// Score of a sorted set member, ordered with `f64::total_cmp`.
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return self.0.total_cmp(&other.0);
+    }
+}
+
+/// Sorted set, stored until it outgrows the limits as a listpack of alternating
+/// members and scores, ordered by score (then by member).
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZSet {
+    Listpack(Listpack),
+    Skiplist(SortedSet),
+}
+
+#[allow(dead_code)]
+impl ZSet {
+    pub fn new() -> Self {
+        return ZSet::Listpack(Listpack::new());
+    }
+
+    pub fn len(&self) -> usize {
+        return match self {
+            ZSet::Listpack(listpack) => listpack.len() / 2,
+            ZSet::Skiplist(zset) => zset.len(),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        return match self {
+            ZSet::Listpack(listpack) => {
+                scored(listpack).find(|(m, _)| *m == member).map(|(_, s)| s)
+            }
+            ZSet::Skiplist(zset) => zset.score(member),
+        };
+    }
+
+    /// Members ordered by score.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], f64)> + '_> {
+        return match self {
+            ZSet::Listpack(listpack) => Box::new(scored(listpack)),
+            ZSet::Skiplist(zset) => {
+                Box::new(zset.iter().map(|(member, score)| (&member[..], score)))
+            }
+        };
+    }
+
+    /// Adds or updates a member, returns true if it's new.
+    pub fn insert(&mut self, member: Bytes, score: f64, limits: &ListpackLimits) -> bool {
+        if let ZSet::Listpack(listpack) = self {
+            let old = scored(listpack).position(|(m, _)| m == member);
+            let entries = listpack.len() / 2 + old.is_none() as usize;
+            let bytes = listpack.bytes() + member.len();
+            if limits.allow(entries, member.len(), bytes) {
+                if let Some(old) = old {
+                    listpack.remove_range(2 * old, 2);
+                }
+                let key = (Score(score), &member[..]);
+                let pos = scored(listpack)
+                    .position(|(m, s)| (Score(s), m) > key)
+                    .unwrap_or(listpack.len() / 2);
+                listpack.insert(2 * pos, &member);
+                listpack.insert(2 * pos + 1, score.to_string().as_bytes());
+                return old.is_none();
+            }
+            self.convert();
+        }
+        return match self {
+            ZSet::Skiplist(zset) => zset.insert(member, score),
+            ZSet::Listpack(_) => unreachable!("sorted set wasn't converted"),
+        };
+    }
+
+    /// Removes a member, returns true if it was in the set.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        return match self {
+            ZSet::Listpack(listpack) => {
+                let pos = scored(listpack).position(|(m, _)| m == member);
+                if let Some(pos) = pos {
+                    listpack.remove_range(2 * pos, 2);
+                }
+                pos.is_some()
+            }
+            ZSet::Skiplist(zset) => zset.remove(member),
+        };
+    }
+
+    fn convert(&mut self) {
+        if let ZSet::Listpack(listpack) = self {
+            let mut zset = SortedSet::new();
+            for (member, score) in scored(listpack) {
+                zset.insert(Bytes::copy_from_slice(member), score);
+            }
+            *self = ZSet::Skiplist(zset);
+        }
+    }
+}
+
+/// Members and scores of a sorted set stored as a listpack.
+fn scored(listpack: &Listpack) -> impl Iterator<Item = (&[u8], f64)> {
+    return pairs(listpack).map(|(member, score)| {
+        let score = std::str::from_utf8(score).ok().and_then(|s| s.parse().ok());
+        (member, score.expect("invalid score in listpack"))
+    });
+}
+
+/// Sorted set, members indexed by name and ordered by score (then by name).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(Score, Bytes)>,
+}
+
+#[allow(dead_code)]
+impl SortedSet {
+    pub fn new() -> Self {
+        return SortedSet::default();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.scores.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.scores.is_empty();
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        return self.scores.get(member).copied();
+    }
+
+    /// Adds or updates a member, returns true if it's new.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        return old.is_none();
+    }
+
+    /// Removes a member, returns true if it was in the set.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        return match self.scores.remove_entry(member) {
+            Some((member, score)) => self.ordered.remove(&(Score(score), member)),
+            None => false,
+        };
+    }
+
+    /// Members ordered by score.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> {
+        return self.ordered.iter().map(|(score, member)| (member, score.0));
+    }
+}
+
+/// ID of a stream entry, a millisecond timestamp and a sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+/// Append only log of entries, each one a list of field-value pairs.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Vec<(Bytes, Bytes)>>,
+    /// ID of the last entry added, even if it was deleted since
+    pub last_id: StreamId,
+}
+
+#[allow(dead_code)]
+impl Stream {
+    pub fn new() -> Self {
+        return Stream::default();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use bytes::Bytes;
+
+    use super::{Hash, List, Set, Str, Value, ZSet};
+    use crate::errors::ReplyError;
+    use crate::listpack::ListpackLimits;
+
+    const LIMITS: ListpackLimits = ListpackLimits {
+        entries: 2,
+        value: 8,
+        bytes: usize::MAX,
+    };
+
+    #[test]
+    fn test_typed_access() {
+        let mut list = Value::List(List::new());
+        assert_eq!(list.type_name(), "list");
+        assert_eq!(list.as_str(), Err(ReplyError::WrongType));
+        let limits = ListpackLimits::UNLIMITED;
+        list.as_list_mut()
+            .unwrap()
+            .push_back(Bytes::from("a"), &limits);
+        list.as_list_mut()
+            .unwrap()
+            .push_back(Bytes::from("b"), &limits);
+        assert_eq!(list.len(), 2);
+
+        let string = Value::from(Bytes::from("value"));
+        assert_eq!(string.as_str(), Ok(&Str::Raw(Bytes::from("value"))));
+        assert!(string.as_hash().is_err());
+    }
+
+    #[test]
+    fn test_string_encoding() {
+        let encoding = |s: &str| Value::from(Bytes::copy_from_slice(s.as_bytes())).encoding();
+        assert_eq!(encoding("12345"), "int");
+        assert_eq!(encoding("-1"), "int");
+        assert_eq!(encoding("012"), "embstr");
+        assert_eq!(encoding("hello"), "embstr");
+        assert_eq!(encoding(&"x".repeat(45)), "raw");
+        assert_eq!(encoding("9223372036854775807"), "int");
+        assert_eq!(encoding("9223372036854775808"), "embstr");
+        assert_eq!(encoding("+1"), "embstr");
+        assert_eq!(encoding(""), "embstr");
+    }
+
+    #[test]
+    fn test_integer_strings() {
+        let mut string = Str::from(Bytes::from("-42"));
+        assert_eq!(string, Str::Int(-42));
+        assert_eq!(string.to_bytes(), Bytes::from("-42"));
+        assert_eq!(string.len(), 3);
+        assert_eq!(string.memory_usage(), 0);
+        assert_eq!(string.incr_by(50), Ok(8));
+        assert_eq!(string.to_bytes(), Bytes::from("8"));
+        assert!(string.incr_by(i64::MAX).is_err());
+
+        // raw strings holding integers can be incremented too
+        let mut string = Str::Raw(Bytes::from("10"));
+        assert_eq!(string.incr_by(1), Ok(11));
+        assert_eq!(string, Str::Int(11));
+        let mut string = Str::from(Bytes::from("abc"));
+        assert_eq!(string.incr_by(1), Err(ReplyError::NotAnInteger));
+
+        // integers are formatted when edited as bytes
+        let mut string = Str::from(123);
+        string.raw_mut().truncate(2);
+        assert_eq!(string, Str::Raw(Bytes::from("12")));
+    }
+
+    #[test]
+    fn test_compressed_strings() {
+        let json = Bytes::from("{\"id\": 1, \"tags\": [\"a\", \"b\"]}".repeat(50));
+        let mut string = Str::from(json.clone()).compress(1024);
+        assert!(matches!(string, Str::Compressed { .. }));
+        assert_eq!(Value::Str(string.clone()).encoding(), "lzf");
+        assert_eq!(string.to_bytes(), json);
+        assert_eq!(string.len(), json.len());
+        assert!(string.memory_usage() < json.len() / 10);
+        assert_eq!(string.as_int(), Err(ReplyError::NotAnInteger));
+        string.raw_mut().truncate(10);
+        assert_eq!(string, Str::Raw(json.slice(..10)));
+
+        // short, incompressible and integer strings aren't compressed
+        assert_eq!(Str::from(json.clone()).compress(0), Str::Raw(json.clone()));
+        assert_eq!(Str::from(json.clone()).compress(json.len()), Str::Raw(json));
+        let mut seed = 0x2545f491u32;
+        let random: Bytes = (0..2000)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect();
+        assert_eq!(Str::from(random.clone()).compress(16), Str::Raw(random));
+        assert_eq!(Str::from(42).compress(1), Str::Int(42));
+    }
+
+    #[test]
+    fn test_list_conversion() {
+        let mut list = List::new();
+        list.push_back(Bytes::from("b"), &LIMITS);
+        list.push_front(Bytes::from("a"), &LIMITS);
+        assert_eq!(Value::List(list.clone()).encoding(), "listpack");
+        list.push_back(Bytes::from("c"), &LIMITS);
+        assert_eq!(Value::List(list.clone()).encoding(), "quicklist");
+        let items: Vec<&[u8]> = list.iter().collect();
+        assert_eq!(items, vec![&b"a"[..], b"b", b"c"]);
+        assert_eq!(list.pop_back(), Some(Bytes::from("c")));
+        assert_eq!(list.pop_front(), Some(Bytes::from("a")));
+
+        // long elements don't fit either
+        let mut list = List::new();
+        assert!(!list.set(0, Bytes::from("x"), &LIMITS));
+        list.push_back(Bytes::from("a"), &LIMITS);
+        assert!(list.set(0, Bytes::from("long element"), &LIMITS));
+        assert_eq!(list.get(0), Some(&b"long element"[..]));
+        assert!(matches!(list, List::Quicklist(_)));
+    }
+
+    #[test]
+    fn test_hash_conversion() {
+        let mut hash = Hash::new();
+        assert!(hash.insert(Bytes::from("a"), Bytes::from("1"), &LIMITS));
+        assert!(!hash.insert(Bytes::from("a"), Bytes::from("2"), &LIMITS));
+        assert!(hash.insert(Bytes::from("b"), Bytes::from("3"), &LIMITS));
+        assert_eq!(Value::Hash(hash.clone()).encoding(), "listpack");
+        assert_eq!(hash.get(b"a"), Some(&b"2"[..]));
+        assert!(hash.remove(b"a"));
+        assert!(!hash.remove(b"a"));
+        assert!(hash.insert(Bytes::from("c"), Bytes::from("a long value"), &LIMITS));
+        assert_eq!(Value::Hash(hash.clone()).encoding(), "hashtable");
+        assert_eq!(hash.len(), 2);
+        assert_eq!(hash.get(b"b"), Some(&b"3"[..]));
+    }
+
+    #[test]
+    fn test_set_conversion() {
+        let mut set = Set::new();
+        assert!(set.insert(Bytes::from("a"), &LIMITS));
+        assert!(!set.insert(Bytes::from("a"), &LIMITS));
+        assert!(set.insert(Bytes::from("b"), &LIMITS));
+        assert_eq!(Value::Set(set.clone()).encoding(), "listpack");
+        assert!(set.insert(Bytes::from("c"), &LIMITS));
+        assert_eq!(Value::Set(set.clone()).encoding(), "hashtable");
+        assert!(set.contains(b"a") && set.contains(b"c"));
+        assert!(set.remove(b"a"));
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_zset() {
+        for limits in [ListpackLimits::UNLIMITED, LIMITS] {
+            let mut zset = ZSet::new();
+            assert!(zset.insert(Bytes::from("b"), 2.0, &limits));
+            assert!(zset.insert(Bytes::from("a"), 3.0, &limits));
+            assert!(!zset.insert(Bytes::from("a"), 1.0, &limits));
+            assert!(zset.insert(Bytes::from("c"), -0.5, &limits));
+            let members: Vec<_> = zset.iter().map(|(m, s)| (m.to_vec(), s)).collect();
+            assert_eq!(
+                members,
+                vec![
+                    (b"c".to_vec(), -0.5),
+                    (b"a".to_vec(), 1.0),
+                    (b"b".to_vec(), 2.0)
+                ]
+            );
+            assert!(zset.remove(b"a"));
+            assert!(!zset.remove(b"a"));
+            assert_eq!(zset.score(b"b"), Some(2.0));
+            assert_eq!(zset.len(), 2);
+        }
+        let mut zset = ZSet::new();
+        zset.insert(Bytes::from("a"), 1.0, &LIMITS);
+        assert_eq!(Value::ZSet(zset.clone()).encoding(), "listpack");
+        zset.insert(Bytes::from("a member too long"), 1.0, &LIMITS);
+        assert_eq!(Value::ZSet(zset).encoding(), "skiplist");
+    }
+
+    #[test]
+    fn test_memory_usage_sampling() {
+        let list: VecDeque<Bytes> = (0..10).map(|i| Bytes::from(vec![0; i * 2])).collect();
+        let list = Value::List(List::Quicklist(list));
+        let all = list.memory_usage(0);
+        assert_eq!(all, 10 * std::mem::size_of::<Bytes>() + 90);
+        // the first 2 elements are the smallest ones
+        assert!(list.memory_usage(2) < all);
+    }
+
+    #[test]
+    fn test_listpack_memory_usage() {
+        let mut packed = List::new();
+        let mut linked = List::Quicklist(VecDeque::new());
+        for i in 0..100 {
+            let item = Bytes::from(format!("item:{i}"));
+            packed.push_back(item.clone(), &ListpackLimits::UNLIMITED);
+            linked.push_back(item, &ListpackLimits::UNLIMITED);
+        }
+        let packed = Value::List(packed).memory_usage(0);
+        assert!(packed < Value::List(linked).memory_usage(0) / 2);
+    }
+}
@@ -0,0 +1,600 @@
+/// WebSocket transport (RFC 6455) for RESP, so browsers can talk to the server.
+///
+/// The handshake and the framing are implemented here, SHA-1 and base64 included.
+/// Once upgraded, the payload of the data frames the client sends is the RESP input
+/// of the connection, however the commands are split between frames, and the replies
+/// to the commands are sent in binary frames, each one holding whole replies.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use memchr::memmem;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::PollSender;
+
+/// Appended to the key of the client to compute the accept key of the handshake.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest handshake request accepted.
+const MAX_HANDSHAKE_LEN: usize = 8 * 1024;
+
+/// Input received but not decoded yet, per connection.
+const INPUT_BUFFER_LEN: usize = 64 * 1024;
+
+/// Frames queued to be written, per connection.
+const OUTPUT_QUEUE_LEN: usize = 16;
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+/// Status codes of close frames.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum WebSocketError {
+    #[error("invalid handshake: {0}")]
+    InvalidHandshake(&'static str),
+
+    #[error("frames sent by clients must be masked")]
+    Unmasked,
+
+    #[error("reserved bits or opcode used")]
+    Reserved,
+
+    #[error("invalid control frame")]
+    InvalidControlFrame,
+
+    #[error("frame of {0} bytes is too big")]
+    TooBig(u64),
+}
+
+impl WebSocketError {
+    /// Status code of the close frame sent after the error.
+    fn close_code(&self) -> u16 {
+        return match self {
+            WebSocketError::TooBig(_) => CLOSE_TOO_BIG,
+            _ => CLOSE_PROTOCOL_ERROR,
+        };
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (hi, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *hi = hi.wrapping_add(v);
+        }
+    }
+    let mut digest = [0u8; 20];
+    for (i, hi) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&hi.to_be_bytes());
+    }
+    return digest;
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    return out;
+}
+
+/// Value of the Sec-WebSocket-Accept header answering the Sec-WebSocket-Key `key`.
+pub fn accept_key(key: &str) -> String {
+    return base64(&sha1(format!("{key}{GUID}").as_bytes()));
+}
+
+/// Validates the upgrade request at the start of `request` and returns the response
+/// accepting it.
+fn handshake_response(request: &[u8]) -> Result<String, WebSocketError> {
+    let request =
+        std::str::from_utf8(request).map_err(|_| WebSocketError::InvalidHandshake("not UTF-8"))?;
+    let mut lines = request.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    if !request_line.starts_with("GET ") || !request_line.ends_with(" HTTP/1.1") {
+        return Err(WebSocketError::InvalidHandshake("not a GET request"));
+    }
+    let mut key = None;
+    let mut upgrade = false;
+    let mut connection = false;
+    let mut version = false;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+            None => continue,
+        };
+        let contains = |token: &str| {
+            value
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case(token))
+        };
+        match name.as_str() {
+            "upgrade" => upgrade = contains("websocket"),
+            "connection" => connection = contains("upgrade"),
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-key" => key = Some(value),
+            _ => {}
+        }
+    }
+    if !upgrade || !connection {
+        return Err(WebSocketError::InvalidHandshake(
+            "not an upgrade to websocket",
+        ));
+    }
+    if !version {
+        return Err(WebSocketError::InvalidHandshake("unsupported version"));
+    }
+    let key = key.ok_or(WebSocketError::InvalidHandshake("missing key"))?;
+    return Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    ));
+}
+
+/// Reads the upgrade request of the client and accepts it, or replies 400 and fails.
+/// Returns what the client sent after the request.
+pub async fn handshake<R, W>(reader: &mut R, writer: &mut W) -> Result<BytesMut>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = BytesMut::with_capacity(1024);
+    let end = loop {
+        if let Some(pos) = memmem::find(&buffer, b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buffer.len() >= MAX_HANDSHAKE_LEN {
+            bail!(WebSocketError::InvalidHandshake("request too long"));
+        }
+        if reader.read_buf(&mut buffer).await? == 0 {
+            bail!(WebSocketError::InvalidHandshake("connection closed"));
+        }
+    };
+    let request = buffer.split_to(end);
+    match handshake_response(&request) {
+        Ok(response) => writer.write_all(response.as_bytes()).await?,
+        Err(err) => {
+            let response = format!(
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\n\r\n{}",
+                err.to_string().len(),
+                err
+            );
+            writer.write_all(response.as_bytes()).await?;
+            bail!(err);
+        }
+    }
+    writer.flush().await?;
+    return Ok(buffer);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Bytes,
+}
+
+/// Decodes the frame at the start of `buffer`, `None` if it isn't complete yet.
+/// Frames with payloads over `max_len` bytes are refused.
+pub fn decode_frame(
+    buffer: &mut BytesMut,
+    max_len: usize,
+) -> Result<Option<Frame>, WebSocketError> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0f;
+    if buffer[0] & 0x70 != 0 || !matches!(opcode, 0x0..=0x2 | 0x8..=0xA) {
+        return Err(WebSocketError::Reserved);
+    }
+    if buffer[1] & 0x80 == 0 {
+        return Err(WebSocketError::Unmasked);
+    }
+    let (len, header_len) = match buffer[1] & 0x7f {
+        126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+        127 if buffer.len() >= 10 => (u64::from_be_bytes(buffer[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if opcode >= OPCODE_CLOSE && (!fin || len > 125) {
+        return Err(WebSocketError::InvalidControlFrame);
+    }
+    if len > max_len as u64 {
+        return Err(WebSocketError::TooBig(len));
+    }
+    let len = len as usize;
+    if buffer.len() < header_len + 4 + len {
+        return Ok(None);
+    }
+    buffer.advance(header_len);
+    let mask = [buffer[0], buffer[1], buffer[2], buffer[3]];
+    buffer.advance(4);
+    let mut payload = buffer.split_to(len);
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    return Ok(Some(Frame {
+        fin: fin,
+        opcode: opcode,
+        payload: payload.freeze(),
+    }));
+}
+
+/// Encodes a final frame into `out`, masked with `mask` if given, as clients must.
+pub fn encode_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>, out: &mut BytesMut) {
+    out.put_u8(0x80 | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => out.put_u8(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            out.put_u8(mask_bit | 126);
+            out.put_u16(len as u16);
+        }
+        len => {
+            out.put_u8(mask_bit | 127);
+            out.put_u64(len as u64);
+        }
+    }
+    match mask {
+        Some(mask) => {
+            out.put_slice(&mask);
+            out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => out.put_slice(payload),
+    }
+}
+
+fn close_payload(code: u16) -> Bytes {
+    return Bytes::copy_from_slice(&code.to_be_bytes());
+}
+
+/// Writes every frame received from `frames`, until a close frame is written.
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut frames: mpsc::Receiver<(u8, Bytes)>,
+) {
+    let mut buffer = BytesMut::new();
+    while let Some((opcode, payload)) = frames.recv().await {
+        buffer.clear();
+        encode_frame(opcode, &payload, None, &mut buffer);
+        if writer.write_all(&buffer).await.is_err() || writer.flush().await.is_err() {
+            return;
+        }
+        if opcode == OPCODE_CLOSE {
+            let _ = writer.shutdown().await;
+            return;
+        }
+    }
+}
+
+/// Reads the frames of the client, writing the payload of data frames to `input`
+/// and answering control frames, until the connection is closed.
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    mut buffer: BytesMut,
+    mut input: DuplexStream,
+    frames: mpsc::Sender<(u8, Bytes)>,
+    max_len: usize,
+) {
+    loop {
+        let frame = match decode_frame(&mut buffer, max_len) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => match reader.read_buf(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => continue,
+            },
+            Err(err) => {
+                let _ = frames
+                    .send((OPCODE_CLOSE, close_payload(err.close_code())))
+                    .await;
+                return;
+            }
+        };
+        match frame.opcode {
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                let written = input.write_all(&frame.payload).await;
+                // the connection stopped reading its input
+                if written.is_err() {
+                    return;
+                }
+            }
+            OPCODE_PING => {
+                let _ = frames.send((OPCODE_PONG, frame.payload)).await;
+            }
+            OPCODE_CLOSE => {
+                let _ = frames
+                    .send((OPCODE_CLOSE, close_payload(CLOSE_NORMAL)))
+                    .await;
+                return;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Writes the replies of a connection as binary frames: everything written between
+/// two flushes is sent in a single frame.
+pub struct FrameWriter {
+    buffer: BytesMut,
+    frames: PollSender<(u8, Bytes)>,
+}
+
+impl AsyncWrite for FrameWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.buffer.extend_from_slice(buf);
+        return Poll::Ready(Ok(buf.len()));
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.buffer.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        let closed = || io::Error::from(io::ErrorKind::BrokenPipe);
+        match self.frames.poll_reserve(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(closed())),
+            Poll::Ready(Ok(())) => {}
+        }
+        let payload = self.buffer.split().freeze();
+        if self.frames.send_item((OPCODE_BINARY, payload)).is_err() {
+            return Poll::Ready(Err(closed()));
+        }
+        return Poll::Ready(Ok(()));
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return self.poll_flush(cx);
+    }
+}
+
+/// The tasks moving the frames of an upgraded connection.
+pub struct WebSocket {
+    frames: mpsc::Sender<(u8, Bytes)>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl WebSocket {
+    /// Starts moving frames between the connection and the returned RESP input and
+    /// output. `buffered` is the input read after the handshake, and frames with
+    /// payloads over `max_len` bytes close the connection.
+    pub fn start<R, W>(
+        reader: R,
+        writer: W,
+        buffered: BytesMut,
+        max_len: usize,
+    ) -> (DuplexStream, FrameWriter, WebSocket)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (input, pipe) = tokio::io::duplex(INPUT_BUFFER_LEN);
+        let (frames, queue) = mpsc::channel(OUTPUT_QUEUE_LEN);
+        let output = FrameWriter {
+            buffer: BytesMut::new(),
+            frames: PollSender::new(frames.clone()),
+        };
+        let websocket = WebSocket {
+            frames: frames.clone(),
+            reader: tokio::spawn(read_frames(reader, buffered, pipe, frames, max_len)),
+            writer: tokio::spawn(write_frames(writer, queue)),
+        };
+        return (input, output, websocket);
+    }
+
+    /// Sends a close frame, unless one was sent already, and waits for it to be written.
+    pub async fn close(self, code: u16) {
+        self.reader.abort();
+        let _ = self.frames.send((OPCODE_CLOSE, close_payload(code))).await;
+        drop(self.frames);
+        let _ = self.writer.await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{Bytes, BytesMut};
+
+    use super::{
+        accept_key, base64, decode_frame, encode_frame, handshake, handshake_response, sha1, Frame,
+        WebSocketError, OPCODE_BINARY, OPCODE_CONTINUATION, OPCODE_PING,
+    };
+
+    #[test]
+    fn test_sha1() {
+        let hex = |digest: [u8; 20]| -> String {
+            return digest.iter().map(|b| format!("{:02x}", b)).collect();
+        };
+        assert_eq!(
+            hex(sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        let long = "a".repeat(1000);
+        assert_eq!(
+            hex(sha1(long.as_bytes())),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // example of RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_handshake_response() {
+        let request = "GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+                       Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n";
+        let response = handshake_response(request.as_bytes()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let invalid = [
+            "POST / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nSec-WebSocket-Key: a\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: a\r\n\r\n",
+        ];
+        for request in invalid {
+            assert!(handshake_response(request.as_bytes()).is_err(), "{request}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_keeps_the_data_after_the_request() {
+        let request = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                        Sec-WebSocket-Key: a\r\nSec-WebSocket-Version: 13\r\n\r\n\x82";
+        let mut reader = &request[..];
+        let mut response = Vec::new();
+        let buffered = handshake(&mut reader, &mut response).await.unwrap();
+        assert_eq!(&buffered[..], b"\x82");
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+
+        let mut reader = &b"GET / HTTP/1.1\r\n\r\n"[..];
+        let mut response = Vec::new();
+        assert!(handshake(&mut reader, &mut response).await.is_err());
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn test_frames() {
+        for len in [0, 5, 125, 126, 1000, 70_000] {
+            let payload = vec![b'x'; len];
+            let mut buffer = BytesMut::new();
+            encode_frame(OPCODE_BINARY, &payload, Some([1, 2, 3, 4]), &mut buffer);
+            let encoded = buffer.clone();
+            // incomplete frames wait for the rest
+            for i in 0..encoded.len() {
+                let mut partial = BytesMut::from(&encoded[..i]);
+                assert_eq!(decode_frame(&mut partial, usize::MAX), Ok(None));
+            }
+            let frame = decode_frame(&mut buffer, usize::MAX).unwrap().unwrap();
+            assert_eq!(
+                frame,
+                Frame {
+                    fin: true,
+                    opcode: OPCODE_BINARY,
+                    payload: Bytes::from(payload),
+                }
+            );
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        let mut buffer = BytesMut::new();
+        encode_frame(OPCODE_BINARY, b"PING", None, &mut buffer);
+        assert_eq!(
+            decode_frame(&mut buffer, 1024),
+            Err(WebSocketError::Unmasked)
+        );
+
+        let mut buffer = BytesMut::new();
+        encode_frame(OPCODE_BINARY, &[0; 100], Some([0; 4]), &mut buffer);
+        assert_eq!(
+            decode_frame(&mut buffer, 10),
+            Err(WebSocketError::TooBig(100))
+        );
+
+        let mut buffer = BytesMut::new();
+        encode_frame(OPCODE_PING, &[0; 126], Some([0; 4]), &mut buffer);
+        assert_eq!(
+            decode_frame(&mut buffer, 1024),
+            Err(WebSocketError::InvalidControlFrame)
+        );
+
+        let mut buffer = BytesMut::new();
+        encode_frame(0x3, b"", Some([0; 4]), &mut buffer);
+        assert_eq!(
+            decode_frame(&mut buffer, 1024),
+            Err(WebSocketError::Reserved)
+        );
+
+        // fragmented data frames are fine
+        let mut buffer = BytesMut::new();
+        encode_frame(OPCODE_CONTINUATION, b"x", Some([0; 4]), &mut buffer);
+        buffer[0] &= 0x7f;
+        let frame = decode_frame(&mut buffer, 1024).unwrap().unwrap();
+        assert!(!frame.fin);
+    }
+}
@@ -0,0 +1,238 @@
+#![allow(clippy::needless_return)]
+/// Compatibility with the client libraries, checked by talking to the server with
+/// redis-rs: its connection handshake, RESP3, pipelines, the mapping of error replies
+/// to its error kinds, and pub/sub. What other libraries send that redis-rs doesn't,
+/// like COMMAND for cluster routing, is replayed with `client::Client`.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use redis::aio::MultiplexedConnection;
+use redis::{ErrorKind, Value};
+use redis_starter_rust::client::Client;
+use redis_starter_rust::protocol::DataType;
+use redis_starter_rust::Server;
+use tokio_stream::StreamExt;
+
+fn command(args: &[&str]) -> DataType {
+    return DataType::Array {
+        items: args.iter().map(|arg| DataType::from(*arg)).collect(),
+    };
+}
+
+async fn connect(address: SocketAddr, params: &str) -> MultiplexedConnection {
+    let client = redis::Client::open(format!("redis://{address}/{params}")).unwrap();
+    return client.get_multiplexed_async_connection().await.unwrap();
+}
+
+/// redis-rs reports itself with CLIENT SETINFO after connecting, sending HELLO 3
+/// first if asked for RESP3.
+#[tokio::test]
+async fn test_handshake() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let mut resp2 = connect(address, "").await;
+    let info: String = redis::cmd("CLIENT")
+        .arg("INFO")
+        .query_async(&mut resp2)
+        .await
+        .unwrap();
+    assert!(
+        info.contains(" resp=2 lib-name=redis-rs lib-ver=0.27"),
+        "{info}"
+    );
+
+    let mut resp3 = connect(address, "?protocol=resp3").await;
+    let info: String = redis::cmd("CLIENT")
+        .arg("INFO")
+        .query_async(&mut resp3)
+        .await
+        .unwrap();
+    assert!(info.contains(" resp=3 lib-name=redis-rs"), "{info}");
+    let _: () = redis::cmd("HSET")
+        .arg(&["h", "a", "1"])
+        .query_async(&mut resp3)
+        .await
+        .unwrap();
+    let reply: Value = redis::cmd("HGETALL")
+        .arg("h")
+        .query_async(&mut resp3)
+        .await
+        .unwrap();
+    assert!(matches!(reply, Value::Map(_)), "{reply:?}");
+    let reply: Value = redis::cmd("HGETALL")
+        .arg("h")
+        .query_async(&mut resp2)
+        .await
+        .unwrap();
+    assert!(matches!(reply, Value::Array(_)), "{reply:?}");
+}
+
+/// Pipelines are sent with a single write and their replies must come back in
+/// order, however many there are.
+#[tokio::test]
+async fn test_pipelines() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let mut conn = connect(address, "").await;
+    let mut pipe = redis::pipe();
+    for i in 0..1000 {
+        let key = format!("key:{i}");
+        pipe.cmd("SET").arg(&key).arg(i * 7).ignore();
+        pipe.cmd("GET").arg(&key);
+    }
+    let values: Vec<usize> = pipe.query_async(&mut conn).await.unwrap();
+    assert_eq!(values, (0..1000).map(|i| i * 7).collect::<Vec<_>>());
+
+    // the connection is shared by concurrent requests, which are pipelined too
+    let requests = (0..100).map(|i| {
+        let mut conn = conn.clone();
+        async move {
+            let key = format!("key:{i}");
+            let value: usize = redis::cmd("GET").arg(&key).query_async(&mut conn).await?;
+            return redis::RedisResult::Ok(value);
+        }
+    });
+    let mut values = Vec::new();
+    for request in requests.collect::<Vec<_>>() {
+        values.push(tokio::spawn(request));
+    }
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(value.await.unwrap().unwrap(), i * 7);
+    }
+}
+
+/// Clients tell error kinds apart by the prefix of the replies, like the ones of
+/// Redis, and keep using the connection after them.
+#[tokio::test]
+async fn test_error_mapping() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let mut conn = connect(address, "").await;
+    let shared = conn.clone();
+    let query = |args: &[&str]| {
+        let mut conn = shared.clone();
+        let mut cmd = redis::cmd(args[0]);
+        cmd.arg(&args[1..]);
+        async move { cmd.query_async::<Value>(&mut conn).await.unwrap_err() }
+    };
+
+    let err = query(&["NOPE", "a", "b"]).await;
+    assert_eq!(err.kind(), ErrorKind::ResponseError);
+    assert_eq!(
+        err.detail(),
+        Some("unknown command 'NOPE', with args beginning with: 'a' 'b' ")
+    );
+    let err = query(&["GET"]).await;
+    assert_eq!(err.kind(), ErrorKind::ResponseError);
+    assert_eq!(
+        err.detail(),
+        Some("wrong number of arguments for 'get' command")
+    );
+    let err = query(&["SET", "key", "value", "PX", "soon"]).await;
+    assert_eq!(
+        err.detail(),
+        Some("value is not an integer or out of range")
+    );
+
+    let _: () = redis::cmd("SET")
+        .arg(&["s", "v"])
+        .query_async(&mut conn)
+        .await
+        .unwrap();
+    let err = query(&["HGET", "s", "f"]).await;
+    assert_eq!(err.kind(), ErrorKind::ExtensionError);
+    assert_eq!(err.code(), Some("WRONGTYPE"));
+    let err = query(&["HELLO", "4"]).await;
+    assert_eq!(err.code(), Some("NOPROTO"));
+
+    // errors in a pipeline fail it as a whole, not the connection
+    let res: redis::RedisResult<(String, String)> = redis::pipe()
+        .cmd("HGET")
+        .arg(&["s", "f"])
+        .cmd("GET")
+        .arg("s")
+        .query_async(&mut conn)
+        .await;
+    assert_eq!(res.unwrap_err().code(), Some("WRONGTYPE"));
+    let value: String = redis::cmd("GET")
+        .arg("s")
+        .query_async(&mut conn)
+        .await
+        .unwrap();
+    assert_eq!(value, "v");
+}
+
+/// redis-rs subscribes on a dedicated connection and reads the messages as they
+/// are published, whether to a channel or a pattern.
+#[tokio::test]
+async fn test_pubsub() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let client = redis::Client::open(format!("redis://{address}/")).unwrap();
+    let mut pubsub = client.get_async_pubsub().await.unwrap();
+    pubsub.subscribe("news").await.unwrap();
+    pubsub.psubscribe("n*").await.unwrap();
+
+    let mut publisher = connect(address, "").await;
+    let receivers: usize = redis::cmd("PUBLISH")
+        .arg(&["news", "hi"])
+        .query_async(&mut publisher)
+        .await
+        .unwrap();
+    assert_eq!(receivers, 2);
+    let mut messages = HashMap::new();
+    {
+        let mut stream = pubsub.on_message();
+        for _ in 0..2 {
+            let next = tokio::time::timeout(Duration::from_secs(5), stream.next());
+            let message = next.await.unwrap().unwrap();
+            assert_eq!(message.get_channel_name(), "news");
+            let payload: String = message.get_payload().unwrap();
+            messages.insert(message.get_pattern::<Option<String>>().unwrap(), payload);
+        }
+    }
+    assert_eq!(messages.get(&None), Some(&String::from("hi")));
+    assert_eq!(
+        messages.get(&Some(String::from("n*"))),
+        Some(&String::from("hi"))
+    );
+
+    pubsub.unsubscribe("news").await.unwrap();
+    pubsub.punsubscribe("n*").await.unwrap();
+    let receivers: usize = redis::cmd("PUBLISH")
+        .arg(&["news", "hi"])
+        .query_async(&mut publisher)
+        .await
+        .unwrap();
+    assert_eq!(receivers, 0);
+}
+
+/// Cluster clients and ioredis read the key positions of the commands from COMMAND
+/// to route and prefix them.
+#[tokio::test]
+async fn test_command_introspection() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let mut client = Client::connect(address).await.unwrap();
+    let commands = match client.send(command(&["COMMAND"])).await.unwrap() {
+        DataType::Array { items } => items,
+        reply => panic!("unexpected reply {:?}", reply),
+    };
+    let count = client.send(command(&["COMMAND", "COUNT"])).await.unwrap();
+    assert_eq!(
+        count,
+        DataType::Integer {
+            number: commands.len() as isize
+        }
+    );
+    let mut get = None;
+    for info in &commands {
+        let fields = match info {
+            DataType::Array { items } if items.len() == 10 => items,
+            info => panic!("unexpected command info {:?}", info),
+        };
+        assert!(matches!(fields[0], DataType::BulkString { .. }));
+        assert!(matches!(fields[1], DataType::Integer { .. }));
+        if fields[0] == DataType::bulk("get") {
+            get = Some(fields[3..6].to_vec());
+        }
+    }
+    let integer = |number| DataType::Integer { number };
+    assert_eq!(get, Some(vec![integer(1), integer(1), integer(1)]));
+}
@@ -0,0 +1,304 @@
+#![allow(clippy::needless_return)]
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use redis_starter_rust::client::Client;
+use redis_starter_rust::protocol::DataType;
+use redis_starter_rust::Server;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn command(args: &[&str]) -> Vec<u8> {
+    let command = DataType::Array {
+        items: args.iter().map(|arg| DataType::from(*arg)).collect(),
+    };
+    return command.encode().unwrap();
+}
+
+/// Sends `request` and reads replies until `expected` is received.
+async fn assert_replies(stream: &mut TcpStream, request: &[u8], expected: &[u8]) {
+    stream.write_all(request).await.unwrap();
+    let mut replies = vec![0; expected.len()];
+    let read = stream.read_exact(&mut replies);
+    tokio::time::timeout(Duration::from_secs(5), read)
+        .await
+        .expect("timed out waiting for the replies")
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&replies),
+        String::from_utf8_lossy(expected)
+    );
+}
+
+#[tokio::test]
+async fn test_pipelined_commands() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut request = command(&["PING"]);
+    request.extend(command(&["SET", "key", "value"]));
+    request.extend(command(&["GET", "key"]));
+    request.extend(command(&["GET", "missing"]));
+    assert_replies(
+        &mut stream,
+        &request,
+        b"+PONG\r\n+OK\r\n$5\r\nvalue\r\n$-1\r\n",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_servers_dont_share_the_keyspace() {
+    let (first, _first) = Server::spawn_ephemeral().unwrap();
+    let (second, _second) = Server::spawn_ephemeral().unwrap();
+    assert_ne!(first, second);
+    let mut client = Client::connect(first).await.unwrap();
+    client.set("key", "1").await.unwrap();
+    assert_eq!(client.get("key").await.unwrap(), Some(Bytes::from("1")));
+    let mut client = Client::connect(second).await.unwrap();
+    assert_eq!(client.get("key").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_stop() {
+    let (address, server) = Server::spawn_ephemeral().unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    assert_replies(&mut stream, &command(&["PING"]), b"+PONG\r\n").await;
+    drop(stream);
+    assert_eq!(server.stop().await, 0);
+    assert!(TcpStream::connect(address).await.is_err());
+}
+
+#[tokio::test]
+async fn test_hello_switches_to_resp3() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut request = command(&["HSET", "hash", "a", "1"]);
+    request.extend(command(&["HGETALL", "hash"]));
+    assert_replies(&mut stream, &request, b":1\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n").await;
+
+    let mut client = Client::connect(address).await.unwrap();
+    let hello = |protocol: &str| DataType::Array {
+        items: vec![DataType::from("HELLO"), DataType::from(protocol)],
+    };
+    let reply = client.send(hello("3")).await.unwrap();
+    let fields = match reply {
+        DataType::Map { items } => items,
+        reply => panic!("unexpected reply {:?}", reply),
+    };
+    assert!(fields.contains(&(DataType::bulk("proto"), DataType::Integer { number: 3 })));
+    let hgetall = DataType::Array {
+        items: vec![DataType::from("HGETALL"), DataType::from("hash")],
+    };
+    assert_eq!(
+        client.send(hgetall.clone()).await.unwrap(),
+        DataType::Map {
+            items: vec![(DataType::bulk("a"), DataType::bulk("1"))]
+        }
+    );
+
+    let reply = client.send(hello("4")).await.unwrap();
+    assert_eq!(
+        reply,
+        DataType::Error {
+            type_: String::from("NOPROTO"),
+            error: String::from("unsupported protocol version"),
+        }
+    );
+    let auth = |user: &str| DataType::Array {
+        items: ["HELLO", "2", "AUTH", user, "secret", "SETNAME", "app"]
+            .iter()
+            .map(|arg| DataType::from(*arg))
+            .collect(),
+    };
+    assert!(matches!(
+        client.send(auth("bob")).await.unwrap(),
+        DataType::Error { type_, .. } if type_ == "WRONGPASS"
+    ));
+    // the failed HELLO didn't switch protocols
+    assert!(matches!(
+        client.send(hgetall.clone()).await.unwrap(),
+        DataType::Map { .. }
+    ));
+    assert!(matches!(
+        client.send(auth("default")).await.unwrap(),
+        DataType::Array { .. }
+    ));
+    assert!(matches!(
+        client.send(hgetall).await.unwrap(),
+        DataType::Array { .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_subscribe_and_publish() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let mut subscriber = TcpStream::connect(address).await.unwrap();
+    assert_replies(
+        &mut subscriber,
+        &command(&["SUBSCRIBE", "a", "b"]),
+        b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n",
+    )
+    .await;
+    assert_replies(
+        &mut subscriber,
+        &command(&["GET", "a"]),
+        b"-ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n",
+    )
+    .await;
+
+    let mut publisher = Client::connect(address).await.unwrap();
+    let publish = DataType::Array {
+        items: vec![
+            DataType::from("PUBLISH"),
+            DataType::from("b"),
+            DataType::from("hi"),
+        ],
+    };
+    let receivers = publisher.send(publish).await.unwrap();
+    assert_eq!(receivers, DataType::Integer { number: 1 });
+    assert_replies(
+        &mut subscriber,
+        b"",
+        b"*3\r\n$7\r\nmessage\r\n$1\r\nb\r\n$2\r\nhi\r\n",
+    )
+    .await;
+    assert_replies(
+        &mut subscriber,
+        &command(&["PING"]),
+        b"*2\r\n$4\r\npong\r\n$0\r\n\r\n",
+    )
+    .await;
+}
+
+/// RESP2 clients receive the invalidations redirected to them only once subscribed
+/// to `__redis__:invalidate`, so their replies never get out of step.
+#[tokio::test]
+async fn test_tracking_redirect_to_resp2() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let command = |args: &[&str]| DataType::Array {
+        items: args.iter().map(|arg| DataType::from(*arg)).collect(),
+    };
+    let mut listener = Client::connect(address).await.unwrap();
+    let id = match listener.send(command(&["CLIENT", "ID"])).await.unwrap() {
+        DataType::Integer { number } => number.to_string(),
+        reply => panic!("unexpected reply {:?}", reply),
+    };
+    let mut reader = Client::connect(address).await.unwrap();
+    let tracking = command(&["CLIENT", "TRACKING", "ON", "REDIRECT", &id]);
+    assert_eq!(reader.send(tracking).await.unwrap(), DataType::ok());
+    let mut writer = Client::connect(address).await.unwrap();
+
+    // not subscribed: nothing is sent, the replies stay in step
+    reader.get("key").await.unwrap();
+    writer.set("key", "1").await.unwrap();
+    let pong = DataType::SimpleString {
+        string: String::from("PONG"),
+    };
+    assert_eq!(listener.send(command(&["PING"])).await.unwrap(), pong);
+    assert_eq!(listener.get("key").await.unwrap(), Some(Bytes::from("1")));
+
+    let subscribe = command(&["SUBSCRIBE", "__redis__:invalidate"]);
+    assert_eq!(
+        listener.send(subscribe).await.unwrap(),
+        DataType::Array {
+            items: vec![
+                DataType::bulk("subscribe"),
+                DataType::bulk("__redis__:invalidate"),
+                DataType::Integer { number: 1 },
+            ]
+        }
+    );
+    reader.get("key").await.unwrap();
+    writer.set("key", "2").await.unwrap();
+    assert_eq!(
+        listener.read_reply().await.unwrap(),
+        DataType::Array {
+            items: vec![
+                DataType::bulk("message"),
+                DataType::bulk("__redis__:invalidate"),
+                DataType::Array {
+                    items: vec![DataType::bulk("key")]
+                },
+            ]
+        }
+    );
+    assert_eq!(
+        listener.send(command(&["PING"])).await.unwrap(),
+        DataType::Array {
+            items: vec![DataType::bulk("pong"), DataType::bulk("")]
+        }
+    );
+    listener.send(command(&["UNSUBSCRIBE"])).await.unwrap();
+    assert_eq!(listener.get("key").await.unwrap(), Some(Bytes::from("2")));
+}
+
+#[tokio::test]
+async fn test_blmpop_blocks_until_pushed() {
+    let (address, server) = Server::spawn_ephemeral().unwrap();
+    let mut blocked = TcpStream::connect(address).await.unwrap();
+    let mut producer = TcpStream::connect(address).await.unwrap();
+    blocked
+        .write_all(&command(&["BLMPOP", "0", "1", "l", "LEFT"]))
+        .await
+        .unwrap();
+    let mut reply = [0; 1];
+    let read = tokio::time::timeout(Duration::from_millis(200), blocked.read(&mut reply));
+    assert!(read.await.is_err(), "replied without blocking");
+    assert_replies(&mut producer, &command(&["LPUSH", "l", "a"]), b":1\r\n").await;
+    assert_replies(&mut blocked, b"", b"*2\r\n$1\r\nl\r\n*1\r\n$1\r\na\r\n").await;
+
+    // clients that disconnect while blocked don't pop anything
+    let mut gone = TcpStream::connect(address).await.unwrap();
+    gone.write_all(&command(&["BLMPOP", "0", "1", "l", "LEFT"]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(gone);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_replies(&mut producer, &command(&["RPUSH", "l", "b"]), b":1\r\n").await;
+    assert_replies(
+        &mut producer,
+        &command(&["LMPOP", "1", "l", "LEFT"]),
+        b"*2\r\n$1\r\nl\r\n*1\r\n$1\r\nb\r\n",
+    )
+    .await;
+
+    // nor keep the server from shutting down, replying null
+    blocked
+        .write_all(&command(&["BLMPOP", "0", "1", "l", "LEFT"]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(server.stop().await, 0);
+    assert_replies(&mut blocked, b"", b"*-1\r\n").await;
+}
+
+/// Empty and null multibulks are skipped without a reply, like Redis does.
+#[tokio::test]
+async fn test_empty_commands_are_skipped() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut request = b"*0\r\n*-1\r\n".to_vec();
+    request.extend(command(&["PING"]));
+    request.extend(b"*0\r\n");
+    request.extend(command(&["ECHO", "hi"]));
+    assert_replies(&mut stream, &request, b"+PONG\r\n$2\r\nhi\r\n").await;
+}
+
+/// Keys are binary safe, like values.
+#[tokio::test]
+async fn test_binary_keys() {
+    let (address, _server) = Server::spawn_ephemeral().unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut request = b"*3\r\n$3\r\nSET\r\n$1\r\n\xff\r\n$1\r\nv\r\n".to_vec();
+    request.extend(b"*2\r\n$3\r\nGET\r\n$1\r\n\xff\r\n");
+    request.extend(b"*2\r\n$3\r\nGET\r\n$1\r\n\xfe\r\n");
+    request.extend(command(&["KEYS", "*"]));
+    assert_replies(
+        &mut stream,
+        &request,
+        b"+OK\r\n$1\r\nv\r\n$-1\r\n*1\r\n$1\r\n\xff\r\n",
+    )
+    .await;
+}